@@ -6,3 +6,12 @@ REPO_ROOT="$(git rev-parse --show-toplevel)"
 cd "${REPO_ROOT}"
 
 ./build-support/bin/native/cargo clippy --manifest-path="${REPO_ROOT}/src/rust/engine/Cargo.toml" --all
+# The modules behind process_execution's optional features are not built by the command above.
+./build-support/bin/native/cargo clippy \
+  --manifest-path="${REPO_ROOT}/src/rust/engine/process_execution/Cargo.toml" \
+  --features=runner_service
+# Including its tests, which are only built with the feature.
+./build-support/bin/native/cargo clippy \
+  --manifest-path="${REPO_ROOT}/src/rust/engine/process_execution/Cargo.toml" \
+  --features=exec_profiling \
+  --all-targets
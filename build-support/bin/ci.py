@@ -470,9 +470,74 @@ def run_rust_tests() -> None:
     # The osx travis environment has a low file descriptors ulimit, so we avoid running too many
     # tests in parallel.
     command.append("--test-threads=1")
+  # The remote execution tests use an in-memory local store by default, so are run again with an
+  # LMDB store to cover both.
+  lmdb_store_command = [
+    "build-support/bin/native/cargo",
+    "test",
+    "-p",
+    "process_execution",
+    "--tests",
+    "--manifest-path=src/rust/engine/Cargo.toml",
+    "--",
+    "remote::tests",
+    *command[command.index("--") + 1:],
+  ]
+  # process_execution is also tested without its remote-execution feature, which embedders that
+  # never execute remotely disable. The manifest is the crate's own, because cargo applies
+  # --no-default-features to the package of the manifest rather than to the one selected with -p.
+  no_remote_execution_command = [
+    "build-support/bin/native/cargo",
+    "test",
+    "--no-default-features",
+    "--tests",
+    "--manifest-path=src/rust/engine/process_execution/Cargo.toml",
+    *command[command.index("--"):],
+  ]
+  # The tests build grpcio regardless, for their StubCAS and mock execution server, so the crate is
+  # also built alone without the feature, which must not need grpcio.
+  no_remote_execution_build_command = [
+    "build-support/bin/native/cargo",
+    "build",
+    "--no-default-features",
+    "--manifest-path=src/rust/engine/process_execution/Cargo.toml",
+  ]
+  # The modules behind process_execution's optional features are not built by any other crate, so
+  # are tested with their features enabled.
+  runner_service_command = [
+    "build-support/bin/native/cargo",
+    "test",
+    "--features=runner_service",
+    "--tests",
+    "--manifest-path=src/rust/engine/process_execution/Cargo.toml",
+    *command[command.index("--"):],
+  ]
+  exec_profiling_command = [
+    "build-support/bin/native/cargo",
+    "test",
+    "--features=exec_profiling",
+    "--tests",
+    "--manifest-path=src/rust/engine/process_execution/Cargo.toml",
+    *command[command.index("--"):],
+  ]
   with travis_section("RustTests", "Running Rust tests"):
     try:
       subprocess.run(command, env={**os.environ, "RUST_BACKTRACE": "all"}, check=True)
+      subprocess.run(
+        lmdb_store_command,
+        env={**os.environ, "RUST_BACKTRACE": "all", "PANTS_REMOTE_TESTS_LMDB_STORE": "1"},
+        check=True,
+      )
+      subprocess.run(
+        no_remote_execution_command, env={**os.environ, "RUST_BACKTRACE": "all"}, check=True
+      )
+      subprocess.run(no_remote_execution_build_command, check=True)
+      subprocess.run(
+        runner_service_command, env={**os.environ, "RUST_BACKTRACE": "all"}, check=True
+      )
+      subprocess.run(
+        exec_profiling_command, env={**os.environ, "RUST_BACKTRACE": "all"}, check=True
+      )
     except subprocess.CalledProcessError:
       die("Rust test failure.")
 
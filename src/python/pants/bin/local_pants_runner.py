@@ -295,6 +295,16 @@ class LocalPantsRunner(ExceptionSink.AccessGlobalExiterMixin):
     return max_code
 
   def _update_stats(self):
+    try:
+      self._scheduler_session.write_remote_execution_trace()
+    except Exception as e:
+      # A trace is only a diagnostic, so failing to write one does not fail the run.
+      logger.warning('Failed to write the remote execution trace: {}'.format(e))
+    try:
+      self._scheduler_session.write_remote_execution_report()
+    except Exception as e:
+      # Nor does failing to write a report.
+      logger.warning('Failed to write the remote execution report: {}'.format(e))
     metrics = self._scheduler_session.metrics()
     self._run_tracker.pantsd_stats.set_scheduler_metrics(metrics)
     engine_workunits = self._scheduler_session.engine_workunits(metrics)
@@ -6,7 +6,7 @@ import logging
 from pants.engine.fs import Digest
 from pants.engine.platform import PlatformConstraint
 from pants.engine.rules import RootRule, rule
-from pants.util.objects import (Exactly, TypedCollection, datatype, hashable_string_list,
+from pants.util.objects import (Exactly, TypedCollection, datatype, enum, hashable_string_list,
                                 string_optional)
 
 
@@ -18,6 +18,15 @@ _default_timeout_seconds = 15 * 60
 class ProductDescription(datatype([('value', str)])): pass
 
 
+class ExecutionLocality(enum(['any', 'local_only', 'remote_only'])):
+  """Where a process may be executed.
+
+  A process which may only run locally (or only remotely) still runs elsewhere if no CommandRunner
+  which could run it is configured, with a warning, unless
+  `--process-execution-strict-locality` is set.
+  """
+
+
 class ExecuteProcessRequest(datatype([
   ('argv', hashable_string_list),
   ('input_files', Digest),
@@ -28,6 +37,15 @@ class ExecuteProcessRequest(datatype([
   # NB: timeout_seconds covers the whole remote operation including queuing and setup.
   ('timeout_seconds', Exactly(float, int)),
   ('jdk_home', string_optional),
+  # The name of the execution environment (see `--remote-execution-environments`) to execute in
+  # when executed remotely.
+  ('environment', string_optional),
+  # If true, the process's results are shared across versions of pants (see
+  # `--remote-execution-process-cache-namespace`): only set it for processes whose results do not
+  # depend on the version of pants.
+  ('omit_cache_key_gen_version', bool),
+  # The value of an ExecutionLocality.
+  ('execution_locality', str),
 ])):
   """Request for execution with args and snapshots to extract."""
 
@@ -43,6 +61,9 @@ class ExecuteProcessRequest(datatype([
     output_directories=(),
     timeout_seconds=_default_timeout_seconds,
     jdk_home=None,
+    environment=None,
+    omit_cache_key_gen_version=False,
+    execution_locality=ExecutionLocality('any'),
   ):
     if env is None:
       env = ()
@@ -62,6 +83,9 @@ class ExecuteProcessRequest(datatype([
       output_directories=output_directories,
       timeout_seconds=timeout_seconds,
       jdk_home=jdk_home,
+      environment=environment,
+      omit_cache_key_gen_version=omit_cache_key_gen_version,
+      execution_locality=ExecutionLocality(execution_locality).value,
     )
 
 
@@ -117,6 +141,13 @@ class FallibleExecuteProcessResult(datatype([('stdout', bytes),
   Requesting one of these will not raise an exception if the exit code is non-zero."""
 
 
+class RunningProcess(datatype([('process_id', int)])):
+  """A handle to a process which has been started, but which may not have completed.
+
+  Requesting one of these (rather than a FallibleExecuteProcessResult) starts the process in the
+  background. Use `SchedulerSession.poll_running_process` to observe its stdout and result."""
+
+
 class ProcessExecutionFailure(Exception):
   """Used to denote that a process exited, but was unsuccessful in some way.
 
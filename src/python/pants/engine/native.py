@@ -2,6 +2,7 @@
 # Licensed under the Apache License, Version 2.0 (see LICENSE).
 
 import importlib
+import json
 import logging
 import os
 import re
@@ -810,6 +811,7 @@ class Native(Singleton):
                     construct_file_content,
                     construct_files_content,
                     construct_process_result,
+                    construct_running_process,
                     type_address,
                     type_path_globs,
                     type_directory_digest,
@@ -823,6 +825,7 @@ class Native(Singleton):
                     type_link,
                     type_multi_platform_process_request,
                     type_process_result,
+                    type_running_process,
                     type_generator,
                     type_url_to_fetch):
     """Create and return an ExternContext and native Scheduler."""
@@ -841,6 +844,7 @@ class Native(Singleton):
         func(construct_file_content),
         func(construct_files_content),
         func(construct_process_result),
+        func(construct_running_process),
         # Types.
         ti(type_address),
         ti(type_path_globs),
@@ -855,6 +859,7 @@ class Native(Singleton):
         ti(type_link),
         ti(type_multi_platform_process_request),
         ti(type_process_result),
+        ti(type_running_process),
         ti(type_generator),
         ti(type_url_to_fetch),
         ti(str),
@@ -873,18 +878,38 @@ class Native(Singleton):
         self.context.utf8_buf(execution_options.remote_instance_name or ""),
         self.context.utf8_buf(execution_options.remote_ca_certs_path or ""),
         self.context.utf8_buf(execution_options.remote_oauth_bearer_token_path or ""),
+        self.context.utf8_buf(execution_options.remote_proxy or ""),
         execution_options.remote_store_thread_count,
         execution_options.remote_store_chunk_bytes,
         execution_options.remote_store_connection_limit,
+        execution_options.remote_store_max_concurrent_uploads,
         execution_options.remote_store_chunk_upload_timeout_seconds,
         execution_options.remote_store_rpc_retries,
         self.context.utf8_buf_buf(execution_options.remote_execution_extra_platform_properties),
+        execution_options.remote_execution_timeout_excludes_queue,
+        execution_options.remote_execution_max_queue_wait_seconds,
+        self.context.utf8_buf(json.dumps(execution_options.remote_execution_environments)),
+        execution_options.remote_execution_output_spill_threshold_bytes,
         execution_options.process_execution_local_parallelism,
         execution_options.process_execution_remote_parallelism,
         execution_options.process_execution_cleanup_local_dirs,
         execution_options.process_execution_speculation_delay,
         self.context.utf8_buf(execution_options.process_execution_speculation_strategy),
         execution_options.process_execution_use_local_cache,
+        execution_options.intrinsics_url_fetch,
+        execution_options.intrinsics_process_execution,
+        execution_options.remote_execution_persist_inline_output,
+        execution_options.remote_execution_retry_budget,
+        execution_options.remote_execution_retry_budget_refill_interval,
+        execution_options.remote_execution_validate_cache_hit_blobs,
+        execution_options.remote_execution_deterministic_span_ids,
+        self.context.utf8_buf(json.dumps(execution_options.remote_execution_violation_categories)),
+        execution_options.remote_execution_time_budget_seconds,
+        self.context.utf8_buf(execution_options.remote_execution_chrome_trace_path or ""),
+        execution_options.process_execution_strict_locality,
+        self.context.utf8_buf(execution_options.remote_execution_report_path or ""),
+        self.context.utf8_buf(execution_options.remote_execution_record_env_in_report),
+        self.context.utf8_buf_buf(execution_options.remote_execution_record_env_redact_patterns),
       )
     if scheduler_result.is_throw:
       value = self.context.from_value(scheduler_result.throw_handle)
@@ -17,7 +17,7 @@ from pants.engine.fs import (Digest, DirectoriesToMerge, DirectoryToMaterialize,
                              DirectoryWithPrefixToStrip, FileContent, FilesContent,
                              InputFilesContent, PathGlobs, PathGlobsAndRoot, Snapshot, UrlToFetch)
 from pants.engine.isolated_process import (FallibleExecuteProcessResult,
-                                           MultiPlatformExecuteProcessRequest)
+                                           MultiPlatformExecuteProcessRequest, RunningProcess)
 from pants.engine.native import Function, TypeId
 from pants.engine.nodes import Return, Throw
 from pants.engine.objects import Collection
@@ -99,6 +99,7 @@ class Scheduler:
       construct_file_content=FileContent,
       construct_files_content=FilesContent,
       construct_process_result=FallibleExecuteProcessResult,
+      construct_running_process=RunningProcess,
       type_address=Address,
       type_path_globs=PathGlobs,
       type_directory_digest=Digest,
@@ -112,6 +113,7 @@ class Scheduler:
       type_link=Link,
       type_multi_platform_process_request=MultiPlatformExecuteProcessRequest,
       type_process_result=FallibleExecuteProcessResult,
+      type_running_process=RunningProcess,
       type_generator=GeneratorType,
       type_url_to_fetch=UrlToFetch,
     )
@@ -201,7 +203,7 @@ class Scheduler:
         # Otherwise, the Get subject is a "concrete" type, so add a single Get edge.
         add_get_edge(the_get.product, the_get.subject_declared_type)
 
-    self._native.lib.tasks_task_end(self._tasks)
+    self._raise_or_return(self._native.lib.tasks_task_end(self._tasks))
 
   def visualize_graph_to_file(self, session, filename):
     res = self._native.lib.graph_visualize(self._scheduler, session, filename.encode())
@@ -290,6 +292,14 @@ class Scheduler:
   def garbage_collect_store(self):
     self._native.lib.garbage_collect_store(self._scheduler)
 
+  def write_remote_execution_trace(self):
+    res = self._native.lib.write_remote_execution_trace(self._scheduler)
+    self._raise_or_return(res)
+
+  def write_remote_execution_report(self):
+    res = self._native.lib.write_remote_execution_report(self._scheduler)
+    self._raise_or_return(res)
+
   def new_session(self, zipkin_trace_v2, v2_ui=False):
     """Creates a new SchedulerSession for this Scheduler."""
     return SchedulerSession(self, self._native.new_session(
@@ -382,6 +392,13 @@ class SchedulerSession:
     """Returns metrics for this SchedulerSession as a dict of metric name to metric value."""
     return self._scheduler._metrics(self._session)
 
+  def cancel(self):
+    """Cancels the running and future executions of this SchedulerSession, which will fail.
+
+    Work which no other session is waiting for, including process executions, is abandoned.
+    """
+    self._scheduler._native.lib.session_cancel(self._session)
+
   @staticmethod
   def engine_workunits(metrics):
     return metrics.get("engine_workunits")
@@ -534,6 +551,20 @@ class SchedulerSession:
     )
     return self._scheduler._raise_or_return(result)
 
+  def poll_running_process(self, running_process):
+    """Returns the stdout that a RunningProcess has written so far, and its result.
+
+    :param running_process: A RunningProcess which was requested in this session.
+    :returns: A tuple of the stdout bytes written so far, and a FallibleExecuteProcessResult (or
+              None, if the process is still running).
+    """
+    result = self._scheduler._native.lib.running_process_poll(
+      self._scheduler._scheduler,
+      self._session,
+      self._scheduler._to_value(running_process),
+    )
+    return self._scheduler._raise_or_return(result)
+
   def merge_directories(self, directory_digests):
     """Merges any number of directories.
 
@@ -569,3 +600,17 @@ class SchedulerSession:
 
   def garbage_collect_store(self):
     self._scheduler.garbage_collect_store()
+
+  def write_remote_execution_trace(self):
+    """Writes the remote executions since the last call to --remote-execution-chrome-trace-path.
+
+    :raises: An exception if the trace could not be written.
+    """
+    self._scheduler.write_remote_execution_trace()
+
+  def write_remote_execution_report(self):
+    """Writes the report of the remote executions so far to --remote-execution-report-path.
+
+    :raises: An exception if the report could not be written.
+    """
+    self._scheduler.write_remote_execution_report()
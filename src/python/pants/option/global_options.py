@@ -33,6 +33,7 @@ class ExecutionOptions(datatype([
   'remote_store_chunk_upload_timeout_seconds',
   'remote_store_rpc_retries',
   'remote_store_connection_limit',
+  'remote_store_max_concurrent_uploads',
   'process_execution_local_parallelism',
   'process_execution_remote_parallelism',
   'process_execution_cleanup_local_dirs',
@@ -43,7 +44,26 @@ class ExecutionOptions(datatype([
   'remote_instance_name',
   'remote_ca_certs_path',
   'remote_oauth_bearer_token_path',
+  'remote_proxy',
   'remote_execution_extra_platform_properties',
+  'remote_execution_timeout_excludes_queue',
+  'remote_execution_max_queue_wait_seconds',
+  'remote_execution_environments',
+  'remote_execution_output_spill_threshold_bytes',
+  'intrinsics_url_fetch',
+  'intrinsics_process_execution',
+  'remote_execution_persist_inline_output',
+  'remote_execution_retry_budget',
+  'remote_execution_retry_budget_refill_interval',
+  'remote_execution_validate_cache_hit_blobs',
+  'remote_execution_deterministic_span_ids',
+  'remote_execution_violation_categories',
+  'remote_execution_time_budget_seconds',
+  'remote_execution_chrome_trace_path',
+  'process_execution_strict_locality',
+  'remote_execution_report_path',
+  'remote_execution_record_env_in_report',
+  'remote_execution_record_env_redact_patterns',
 ])):
   """A collection of all options related to (remote) execution of processes.
 
@@ -62,6 +82,7 @@ class ExecutionOptions(datatype([
       remote_store_chunk_upload_timeout_seconds=bootstrap_options.remote_store_chunk_upload_timeout_seconds,
       remote_store_rpc_retries=bootstrap_options.remote_store_rpc_retries,
       remote_store_connection_limit=bootstrap_options.remote_store_connection_limit,
+      remote_store_max_concurrent_uploads=bootstrap_options.remote_store_max_concurrent_uploads,
       process_execution_local_parallelism=bootstrap_options.process_execution_local_parallelism,
       process_execution_remote_parallelism=bootstrap_options.process_execution_remote_parallelism,
       process_execution_cleanup_local_dirs=bootstrap_options.process_execution_cleanup_local_dirs,
@@ -72,19 +93,39 @@ class ExecutionOptions(datatype([
       remote_instance_name=bootstrap_options.remote_instance_name,
       remote_ca_certs_path=bootstrap_options.remote_ca_certs_path,
       remote_oauth_bearer_token_path=bootstrap_options.remote_oauth_bearer_token_path,
+      remote_proxy=bootstrap_options.remote_proxy,
       remote_execution_extra_platform_properties=bootstrap_options.remote_execution_extra_platform_properties,
+      remote_execution_timeout_excludes_queue=bootstrap_options.remote_execution_timeout_excludes_queue,
+      remote_execution_max_queue_wait_seconds=bootstrap_options.remote_execution_max_queue_wait_seconds,
+      remote_execution_environments=bootstrap_options.remote_execution_environments,
+      remote_execution_output_spill_threshold_bytes=bootstrap_options.remote_execution_output_spill_threshold_bytes,
+      intrinsics_url_fetch=bootstrap_options.intrinsics_url_fetch,
+      intrinsics_process_execution=bootstrap_options.intrinsics_process_execution,
+      remote_execution_persist_inline_output=bootstrap_options.remote_execution_persist_inline_output,
+      remote_execution_retry_budget=bootstrap_options.remote_execution_retry_budget,
+      remote_execution_retry_budget_refill_interval=bootstrap_options.remote_execution_retry_budget_refill_interval,
+      remote_execution_validate_cache_hit_blobs=bootstrap_options.remote_execution_validate_cache_hit_blobs,
+      remote_execution_deterministic_span_ids=bootstrap_options.remote_execution_deterministic_span_ids,
+      remote_execution_violation_categories=bootstrap_options.remote_execution_violation_categories,
+      remote_execution_time_budget_seconds=bootstrap_options.remote_execution_time_budget_seconds,
+      remote_execution_chrome_trace_path=bootstrap_options.remote_execution_chrome_trace_path,
+      process_execution_strict_locality=bootstrap_options.process_execution_strict_locality,
+      remote_execution_report_path=bootstrap_options.remote_execution_report_path,
+      remote_execution_record_env_in_report=bootstrap_options.remote_execution_record_env_in_report,
+      remote_execution_record_env_redact_patterns=bootstrap_options.remote_execution_record_env_redact_patterns,
     )
 
 
 DEFAULT_EXECUTION_OPTIONS = ExecutionOptions(
     remote_execution=False,
     remote_store_server=[],
-    remote_store_thread_count=1,
+    remote_store_thread_count=0,
     remote_execution_server=None,
     remote_store_chunk_bytes=1024*1024,
     remote_store_chunk_upload_timeout_seconds=60,
     remote_store_rpc_retries=2,
     remote_store_connection_limit=5,
+    remote_store_max_concurrent_uploads=0,
     process_execution_local_parallelism=multiprocessing.cpu_count()*2,
     process_execution_remote_parallelism=128,
     process_execution_cleanup_local_dirs=True,
@@ -95,7 +136,26 @@ DEFAULT_EXECUTION_OPTIONS = ExecutionOptions(
     remote_instance_name=None,
     remote_ca_certs_path=None,
     remote_oauth_bearer_token_path=None,
+    remote_proxy=None,
     remote_execution_extra_platform_properties=[],
+    remote_execution_timeout_excludes_queue=False,
+    remote_execution_max_queue_wait_seconds=10*60,
+    remote_execution_environments={},
+    remote_execution_output_spill_threshold_bytes=0,
+    intrinsics_url_fetch=True,
+    intrinsics_process_execution=True,
+    remote_execution_persist_inline_output=True,
+    remote_execution_retry_budget=0,
+    remote_execution_retry_budget_refill_interval=1.0,
+    remote_execution_validate_cache_hit_blobs=False,
+    remote_execution_deterministic_span_ids=False,
+    remote_execution_violation_categories={},
+    remote_execution_time_budget_seconds=0,
+    remote_execution_chrome_trace_path=None,
+    process_execution_strict_locality=False,
+    remote_execution_report_path=None,
+    remote_execution_record_env_in_report='off',
+    remote_execution_record_env_redact_patterns=[],
   )
 
 
@@ -357,7 +417,8 @@ class GlobalOptionsRegistrar(SubsystemClientMixin, Optionable):
     # TODO: Infer this from remote-store-connection-limit.
     register('--remote-store-thread-count', type=int, advanced=True,
              default=DEFAULT_EXECUTION_OPTIONS.remote_store_thread_count,
-             help='Thread count to use for the pool that interacts with the remote file store.')
+             help='Thread count to use for the pool that interacts with the remote file store and '
+                  'remote execution server. If 0, one thread per core is used, up to 4.')
     register('--remote-execution-server', advanced=True,
              help='host:port of grpc server to use as remote execution scheduler.')
     register('--remote-store-chunk-bytes', type=int, advanced=True,
@@ -372,6 +433,10 @@ class GlobalOptionsRegistrar(SubsystemClientMixin, Optionable):
     register('--remote-store-connection-limit', type=int, advanced=True,
              default=DEFAULT_EXECUTION_OPTIONS.remote_store_connection_limit,
              help='Number of remote stores to concurrently allow connections to.')
+    register('--remote-store-max-concurrent-uploads', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_store_max_concurrent_uploads,
+             help='Number of blobs which process executions may upload to the remote store at '
+                  'once, summed over all processes, or 0 for no limit.')
     register('--remote-execution-process-cache-namespace', advanced=True,
              help="The cache namespace for remote process execution. "
                   "Bump this to invalidate every artifact's remote execution. "
@@ -388,11 +453,99 @@ class GlobalOptionsRegistrar(SubsystemClientMixin, Optionable):
              help='Path to a file containing an oauth token to use for grpc connections to '
                   '--remote-execution-server and --remote-store-server. If not specified, no '
                   'authorization will be performed.')
+    register('--remote-proxy', advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_proxy,
+             help='URL of an HTTP proxy, of the form http://[username:password@]host:port, via '
+                  'which to connect to --remote-execution-server and --remote-store-server. '
+                  'Connections to TLS servers are tunnelled through it, so remain encrypted. If '
+                  'not specified, servers are connected to directly.')
     register('--remote-execution-extra-platform-properties', advanced=True,
              help='Platform properties to set on remote execution requests. '
                   'Format: property=value. Multiple values should be specified as multiple '
                   'occurrences of this flag. Pants itself may add additional platform properties.',
                    type=list, default=[])
+    register('--remote-execution-timeout-excludes-queue', type=bool, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_execution_timeout_excludes_queue,
+             help='Whether the timeouts of remote processes only count the time that they spend '
+                  'executing, rather than the time since they were submitted, if the server '
+                  'reports when their execution starts.')
+    register('--remote-execution-max-queue-wait-seconds', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_execution_max_queue_wait_seconds,
+             help='If --remote-execution-timeout-excludes-queue is set, the longest that a remote '
+                  'process may additionally wait in the queue before it is timed out regardless.')
+    register('--remote-execution-environments', type=dict, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_execution_environments,
+             help='Named environments which remote processes may select to execute in. Each maps '
+                  'to a dict with any of the keys "platform_properties" (a dict of platform '
+                  'properties), "container_image", "instance_name" and "priority".')
+    register('--remote-execution-output-spill-threshold-bytes', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_execution_output_spill_threshold_bytes,
+             help='The stdout and stderr of remote processes which are larger than this many bytes '
+                  'are kept only in the Store, and loaded from it when they are consumed, rather '
+                  'than being held in memory. If 0, outputs are always held in memory.')
+    register('--remote-execution-persist-inline-output', type=bool, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_execution_persist_inline_output,
+             help='Whether the stdout and stderr which remote processes return inline are recorded '
+                  'in the Store. If disabled, they are held only in memory (which saves I/O for '
+                  'e.g. interactive goals), unless the local process cache records them.')
+    register('--remote-execution-retry-budget', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_execution_retry_budget,
+             help='If positive, the most retries of each kind (of executions whose inputs the '
+                  'server reports missing, of polls after cancellations, and of fetches of '
+                  'outputs) which remote processes may make in a burst, across the whole build. '
+                  'The budget refills by one retry of each kind per '
+                  '--remote-execution-retry-budget-refill-interval. If 0, retries are unbounded.')
+    register('--remote-execution-retry-budget-refill-interval', type=float, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_execution_retry_budget_refill_interval,
+             help='The number of seconds per retry of each kind by which the budget of '
+                  '--remote-execution-retry-budget refills.')
+    register('--remote-execution-validate-cache-hit-blobs', type=bool, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_execution_validate_cache_hit_blobs,
+             help='Whether to check that the remote store still has the outputs (including the '
+                  'files of output directories) of results which the remote cache serves, and to '
+                  're-execute processes whose cached results reference missing blobs, rather than '
+                  'failing once the outputs are fetched.')
+    register('--remote-execution-deterministic-span-ids', type=bool, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_execution_deterministic_span_ids,
+             help='Whether to derive the span ids of the workunits which record the remote timings '
+                  'of executions from their parent, name and action, so that the traces of '
+                  'identical builds can be diffed.')
+    register('--remote-execution-violation-categories', type=dict, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_execution_violation_categories,
+             help='The categories of the types of the precondition violations with which the '
+                  'remote execution server fails requests, beyond those which are understood by '
+                  'default (MISSING and INPUT_TOO_LARGE). Maps each violation type to '
+                  '"missing_blob" or "inputs_too_large".')
+    register('--remote-execution-time-budget-seconds', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_execution_time_budget_seconds,
+             help='If positive, the most wall time which remote processes may spend waiting on '
+                  'their operations across the whole build, after which processes which may run '
+                  'locally do so. Processes which are already running remotely finish there.')
+    register('--remote-execution-chrome-trace-path', advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_execution_chrome_trace_path,
+             help='If set, the remote executions of each run, including those which failed, are '
+                  'written to this path at the end of the run as a Chrome trace, which can be '
+                  'loaded by chrome://tracing or Perfetto.')
+    register('--process-execution-strict-locality', type=bool, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.process_execution_strict_locality,
+             help='Whether processes which may only run locally (or only remotely) fail when no '
+                  'runner which could run them is configured, rather than running elsewhere with '
+                  'a warning.')
+    register('--remote-execution-report-path', advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_execution_report_path,
+             help='If set, a JSON report of the remote executions of each run (with the slowest '
+                  'and the failed actions, and any degradations) is written to this path at the '
+                  'end of the run.')
+    register('--remote-execution-record-env-in-report', choices=['off', 'names_only', 'full'],
+             default=DEFAULT_EXECUTION_OPTIONS.remote_execution_record_env_in_report, advanced=True,
+             help='How much of the env of each remote execution (including the env vars which '
+                  'pants injects) is recorded in `--remote-execution-report-path`: `names_only` '
+                  'records the names of the env vars, and `full` their values too, except for '
+                  'those which match `--remote-execution-record-env-redact-patterns`.')
+    register('--remote-execution-record-env-redact-patterns', type=list, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_execution_record_env_redact_patterns,
+             help='Regexes: the values of env vars which match any of them are redacted when the '
+                  'env is fully recorded (see `--remote-execution-record-env-in-report`).')
     register('--process-execution-local-parallelism', type=int, default=DEFAULT_EXECUTION_OPTIONS.process_execution_local_parallelism,
              advanced=True,
              help='Number of concurrent processes that may be executed locally.')
@@ -418,6 +571,15 @@ class GlobalOptionsRegistrar(SubsystemClientMixin, Optionable):
              advanced=True)
     register('--process-execution-use-local-cache', type=bool, default=True, advanced=True,
              help='Whether to keep process executions in a local cache persisted to disk.')
+    register('--intrinsics-url-fetch', type=bool, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.intrinsics_url_fetch,
+             help='Whether rules may fetch URLs into Snapshots. If disabled (e.g. for hermetic '
+                  'builds), rules which would need to fail rule graph validation, rather than '
+                  'touching the network when they run.')
+    register('--intrinsics-process-execution', type=bool, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.intrinsics_process_execution,
+             help='Whether rules may run processes. If disabled, rules which would need to fail '
+                  'rule graph validation.')
 
   @classmethod
   def register_options(cls, register):
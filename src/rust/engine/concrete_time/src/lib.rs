@@ -69,6 +69,18 @@ impl Into<std::time::Duration> for Duration {
   }
 }
 
+///
+/// A protobuf `Timestamp` is converted to the `Duration` since the unix epoch that it represents.
+///
+impl<'a> From<&'a protobuf::well_known_types::Timestamp> for Duration {
+  fn from(timestamp: &'a protobuf::well_known_types::Timestamp) -> Self {
+    Self {
+      secs: timestamp.seconds as u64,
+      nanos: timestamp.nanos as u32,
+    }
+  }
+}
+
 /// A timespan
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
 pub struct TimeSpan {
@@ -95,20 +107,14 @@ impl TimeSpan {
     }
   }
 
-  fn std_duration_from_protobuf_timestamp(
-    t: &protobuf::well_known_types::Timestamp,
-  ) -> std::time::Duration {
-    std::time::Duration::new(t.seconds as u64, t.nanos as u32)
-  }
-
   /// Construct a `TimeSpan` given a start and an end `Timestamp` from protobuf
   pub fn from_start_and_end(
     start: &protobuf::well_known_types::Timestamp,
     end: &protobuf::well_known_types::Timestamp,
     time_span_description: &str,
   ) -> Result<Self, String> {
-    let start = Self::std_duration_from_protobuf_timestamp(start);
-    let end = Self::std_duration_from_protobuf_timestamp(end);
+    let start: std::time::Duration = Duration::from(start).into();
+    let end: std::time::Duration = Duration::from(end).into();
     let time_span = end.checked_sub(start).map(|duration| TimeSpan {
       start: start.into(),
       duration: duration.into(),
@@ -141,6 +147,14 @@ mod tests {
     assert_eq!(concrete.nanos, std.subsec_nanos());
   }
 
+  #[test]
+  fn convert_from_protobuf_timestamp() {
+    let mut timestamp = protobuf::well_known_types::Timestamp::new();
+    timestamp.set_seconds(3);
+    timestamp.set_nanos(141_592_653);
+    assert_eq!(Duration::from(&timestamp), Duration::new(3, 141_592_653));
+  }
+
   #[test]
   fn time_span_since() {
     let start = std::time::SystemTime::now();
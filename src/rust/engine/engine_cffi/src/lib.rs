@@ -41,8 +41,8 @@ mod cffi_externs;
 
 use engine::externs::*;
 use engine::{
-  externs, nodes, Core, ExecutionRequest, Function, Handle, Key, Params, RootResult, Rule,
-  Scheduler, Session, Tasks, TypeId, Types, Value,
+  externs, nodes, Core, ExecutionRequest, Function, Handle, InternedNames, IntrinsicFlags, Key,
+  Params, RootResult, Rule, RuleCost, Scheduler, Session, SnapshotKey, Tasks, TypeId, Types, Value,
 };
 use futures::Future;
 use hashing::Digest;
@@ -181,6 +181,7 @@ pub extern "C" fn scheduler_create(
   construct_file_content: Function,
   construct_files_content: Function,
   construct_process_result: Function,
+  construct_running_process: Function,
   type_address: TypeId,
   type_path_globs: TypeId,
   type_directory_digest: TypeId,
@@ -194,6 +195,7 @@ pub extern "C" fn scheduler_create(
   type_link: TypeId,
   type_multi_platform_process_request: TypeId,
   type_process_result: TypeId,
+  type_running_process: TypeId,
   type_generator: TypeId,
   type_url_to_fetch: TypeId,
   type_string: TypeId,
@@ -209,18 +211,38 @@ pub extern "C" fn scheduler_create(
   remote_instance_name: Buffer,
   remote_root_ca_certs_path_buffer: Buffer,
   remote_oauth_bearer_token_path_buffer: Buffer,
+  remote_proxy_buf: Buffer,
   remote_store_thread_count: u64,
   remote_store_chunk_bytes: u64,
   remote_store_connection_limit: u64,
+  remote_store_max_concurrent_uploads: u64,
   remote_store_chunk_upload_timeout_seconds: u64,
   remote_store_rpc_retries: u64,
   remote_execution_extra_platform_properties_buf: BufferBuffer,
+  remote_execution_timeout_excludes_queue: bool,
+  remote_execution_max_queue_wait_seconds: u64,
+  remote_execution_environments_buf: Buffer,
+  remote_execution_output_spill_threshold_bytes: u64,
   process_execution_local_parallelism: u64,
   process_execution_remote_parallelism: u64,
   process_execution_cleanup_local_dirs: bool,
   process_execution_speculation_delay: f64,
   process_execution_speculation_strategy_buf: Buffer,
   process_execution_use_local_cache: bool,
+  intrinsics_url_fetch: bool,
+  intrinsics_process_execution: bool,
+  remote_execution_persist_inline_output: bool,
+  remote_execution_retry_budget: u64,
+  remote_execution_retry_budget_refill_interval: f64,
+  remote_execution_validate_cache_hit_blobs: bool,
+  remote_execution_deterministic_span_ids: bool,
+  remote_execution_violation_categories_buf: Buffer,
+  remote_execution_time_budget_seconds: u64,
+  remote_execution_chrome_trace_path_buffer: Buffer,
+  process_execution_strict_locality: bool,
+  remote_execution_report_path_buffer: Buffer,
+  remote_execution_record_env_in_report_buf: Buffer,
+  remote_execution_record_env_redact_patterns_buf: BufferBuffer,
 ) -> RawResult {
   let root_type_ids = root_type_ids.to_vec();
   let ignore_patterns = ignore_patterns_buf
@@ -232,6 +254,7 @@ pub extern "C" fn scheduler_create(
     construct_file_content: construct_file_content,
     construct_files_content: construct_files_content,
     construct_process_result: construct_process_result,
+    construct_running_process: construct_running_process,
     address: type_address,
     path_globs: type_path_globs,
     directory_digest: type_directory_digest,
@@ -245,6 +268,7 @@ pub extern "C" fn scheduler_create(
     link: type_link,
     multi_platform_process_request: type_multi_platform_process_request,
     process_result: type_process_result,
+    running_process: type_running_process,
     generator: type_generator,
     url_to_fetch: type_url_to_fetch,
     string: type_string,
@@ -252,7 +276,17 @@ pub extern "C" fn scheduler_create(
   };
   #[allow(clippy::redundant_closure)] // I couldn't find an easy way to remove this closure.
   let mut tasks = with_tasks(tasks_ptr, |tasks| tasks.clone());
-  tasks.intrinsics_set(&types);
+  let intrinsic_flags = IntrinsicFlags {
+    url_fetch: intrinsics_url_fetch,
+    process_execution: intrinsics_process_execution,
+  };
+  if let Err(err) = tasks.intrinsics_set(&types, &intrinsic_flags) {
+    return RawResult {
+      is_throw: true,
+      throw_handle: externs::create_exception(&err.to_string()).into(),
+      raw_pointer: std::ptr::null(),
+    };
+  }
   // Allocate on the heap via `Box` and return a raw pointer to the boxed value.
   let remote_store_servers_vec = remote_store_servers_buf
     .to_strings()
@@ -294,6 +328,39 @@ pub extern "C" fn scheduler_create(
     }
   };
 
+  let remote_execution_chrome_trace_path = {
+    let path = remote_execution_chrome_trace_path_buffer.to_os_string();
+    if path.is_empty() {
+      None
+    } else {
+      Some(PathBuf::from(path))
+    }
+  };
+
+  let remote_execution_report_path = {
+    let path = remote_execution_report_path_buffer.to_os_string();
+    if path.is_empty() {
+      None
+    } else {
+      Some(PathBuf::from(path))
+    }
+  };
+
+  let remote_proxy_string = remote_proxy_buf
+    .to_string()
+    .expect("remote_proxy was not valid UTF8");
+  let remote_execution_environments = remote_execution_environments_buf
+    .to_string()
+    .expect("remote_execution_environments was not valid UTF8");
+  let remote_execution_violation_categories = remote_execution_violation_categories_buf
+    .to_string()
+    .expect("remote_execution_violation_categories was not valid UTF8");
+  let remote_execution_record_env_in_report = remote_execution_record_env_in_report_buf
+    .to_string()
+    .expect("remote_execution_record_env_in_report was not valid UTF8");
+  let remote_execution_record_env_redact_patterns = remote_execution_record_env_redact_patterns_buf
+    .to_strings()
+    .expect("Failed to decode remote_execution_record_env_redact_patterns");
   let process_execution_speculation_strategy = process_execution_speculation_strategy_buf
     .to_string()
     .expect("process_execution_speculation_strategy was not valid UTF8");
@@ -323,12 +390,26 @@ pub extern "C" fn scheduler_create(
     },
     remote_root_ca_certs_path,
     remote_oauth_bearer_token_path,
+    if remote_proxy_string.is_empty() {
+      None
+    } else {
+      Some(remote_proxy_string)
+    },
     remote_store_thread_count as usize,
     remote_store_chunk_bytes as usize,
     Duration::from_secs(remote_store_chunk_upload_timeout_seconds),
     remote_store_rpc_retries as usize,
     remote_store_connection_limit as usize,
+    remote_store_max_concurrent_uploads as usize,
     remote_execution_extra_platform_properties_list,
+    remote_execution_timeout_excludes_queue,
+    Duration::from_secs(remote_execution_max_queue_wait_seconds),
+    remote_execution_environments,
+    if remote_execution_output_spill_threshold_bytes == 0 {
+      None
+    } else {
+      Some(remote_execution_output_spill_threshold_bytes as usize)
+    },
     process_execution_local_parallelism as usize,
     process_execution_remote_parallelism as usize,
     process_execution_cleanup_local_dirs,
@@ -337,6 +418,23 @@ pub extern "C" fn scheduler_create(
     Duration::from_millis((process_execution_speculation_delay * 1000.0).round() as u64),
     process_execution_speculation_strategy,
     process_execution_use_local_cache,
+    remote_execution_persist_inline_output,
+    remote_execution_retry_budget,
+    // convert the interval from float to millisecond resolution, as for the speculation delay.
+    Duration::from_millis((remote_execution_retry_budget_refill_interval * 1000.0).round() as u64),
+    remote_execution_validate_cache_hit_blobs,
+    remote_execution_deterministic_span_ids,
+    remote_execution_violation_categories,
+    if remote_execution_time_budget_seconds == 0 {
+      None
+    } else {
+      Some(Duration::from_secs(remote_execution_time_budget_seconds))
+    },
+    remote_execution_chrome_trace_path,
+    process_execution_strict_locality,
+    remote_execution_report_path,
+    remote_execution_record_env_in_report,
+    remote_execution_record_env_redact_patterns,
   );
 
   match core {
@@ -494,11 +592,147 @@ pub extern "C" fn tasks_add_select(tasks_ptr: *mut Tasks, product: TypeId) {
   })
 }
 
+///
+/// Sets the estimated cost of the task being registered, where 0 is cheap, 1 is moderate, and 2
+/// is expensive.
+///
 #[no_mangle]
-pub extern "C" fn tasks_task_end(tasks_ptr: *mut Tasks) {
+pub extern "C" fn tasks_set_estimated_cost(tasks_ptr: *mut Tasks, cost: u8) {
+  let cost = match cost {
+    0 => RuleCost::Cheap,
+    1 => RuleCost::Moderate,
+    2 => RuleCost::Expensive,
+    _ => panic!("Unknown estimated cost: {}", cost),
+  };
   with_tasks(tasks_ptr, |tasks| {
-    tasks.task_end();
+    tasks.set_estimated_cost(cost);
+  })
+}
+
+#[no_mangle]
+pub extern "C" fn tasks_task_end(tasks_ptr: *mut Tasks) -> PyResult {
+  with_tasks(tasks_ptr, |tasks| {
+    tasks.task_end().map_err(|e| e.to_string()).into()
+  })
+}
+
+#[no_mangle]
+pub extern "C" fn tasks_goal_begin(
+  tasks_ptr: *mut Tasks,
+  func: Function,
+  output_type: TypeId,
+  goal_name: Buffer,
+) {
+  let goal_name = goal_name.to_string().expect("goal_name was not valid UTF8");
+  with_tasks(tasks_ptr, |tasks| {
+    tasks.goal_begin(func, output_type, goal_name);
+  })
+}
+
+#[no_mangle]
+pub extern "C" fn tasks_goal_end(tasks_ptr: *mut Tasks) -> PyResult {
+  with_tasks(tasks_ptr, |tasks| {
+    tasks.goal_end().map_err(|e| e.to_string()).into()
+  })
+}
+
+///
+/// Serializes the rules which are registered in the Tasks (see Tasks::snapshot), returning the
+/// snapshot as bytes. The snapshot is keyed on the given plugins and on the names of the fields of
+/// Types, and each of the types and functions which the rules refer to must be given with a stable
+/// (e.g. module-qualified) name.
+///
+#[no_mangle]
+pub extern "C" fn tasks_snapshot(
+  tasks_ptr: *mut Tasks,
+  plugins_buf: BufferBuffer,
+  key_type_names_buf: BufferBuffer,
+  type_ids: TypeIdBuffer,
+  type_names_buf: BufferBuffer,
+  functions: HandleBuffer,
+  function_names_buf: BufferBuffer,
+) -> PyResult {
+  snapshot_key_and_names(
+    &plugins_buf,
+    &key_type_names_buf,
+    &type_ids,
+    &type_names_buf,
+    &functions,
+    &function_names_buf,
+  )
+  .and_then(|(key, names)| {
+    with_tasks(tasks_ptr, |tasks| {
+      tasks
+        .snapshot(&key, &names)
+        .map(|snapshot| externs::store_bytes(&snapshot))
+    })
+  })
+  .into()
+}
+
+///
+/// Replaces the rules which are registered in the Tasks with those of a snapshot which was taken
+/// by tasks_snapshot, possibly by another process. The arguments are as for tasks_snapshot: the
+/// types and functions of the snapshot are re-interned as those which are given for their names.
+///
+#[no_mangle]
+pub extern "C" fn tasks_restore_snapshot(
+  tasks_ptr: *mut Tasks,
+  snapshot_buf: Buffer,
+  plugins_buf: BufferBuffer,
+  key_type_names_buf: BufferBuffer,
+  type_ids: TypeIdBuffer,
+  type_names_buf: BufferBuffer,
+  functions: HandleBuffer,
+  function_names_buf: BufferBuffer,
+) -> PyResult {
+  snapshot_key_and_names(
+    &plugins_buf,
+    &key_type_names_buf,
+    &type_ids,
+    &type_names_buf,
+    &functions,
+    &function_names_buf,
+  )
+  .and_then(|(key, names)| {
+    let restored = Tasks::from_snapshot(&snapshot_buf.to_bytes(), &key, &names)?;
+    with_tasks(tasks_ptr, |tasks| *tasks = restored);
+    Ok(())
   })
+  .into()
+}
+
+fn snapshot_key_and_names(
+  plugins_buf: &BufferBuffer,
+  key_type_names_buf: &BufferBuffer,
+  type_ids: &TypeIdBuffer,
+  type_names_buf: &BufferBuffer,
+  functions: &HandleBuffer,
+  function_names_buf: &BufferBuffer,
+) -> Result<(SnapshotKey, InternedNames), String> {
+  let strings = |buf: &BufferBuffer, what: &str| {
+    buf
+      .to_strings()
+      .map_err(|e| format!("Failed to decode {} as UTF8: {:?}", what, e))
+  };
+  let plugins = strings(plugins_buf, "plugin names")?;
+  let key_type_names = strings(key_type_names_buf, "the names of Types")?;
+  let key = SnapshotKey::new(
+    plugins.iter().map(String::as_str),
+    key_type_names.iter().map(String::as_str),
+  );
+  let functions = functions
+    .to_vec()
+    .into_iter()
+    .map(|function| Function(externs::key_for(function)));
+  let names = InternedNames::new(
+    type_ids
+      .to_vec()
+      .into_iter()
+      .zip(strings(type_names_buf, "type names")?),
+    functions.zip(strings(function_names_buf, "function names")?),
+  );
+  Ok((key, names))
 }
 
 #[no_mangle]
@@ -617,6 +851,11 @@ pub extern "C" fn session_create(
   })
 }
 
+#[no_mangle]
+pub extern "C" fn session_cancel(session_ptr: *mut Session) {
+  with_session(session_ptr, |session| session.cancel())
+}
+
 #[no_mangle]
 pub extern "C" fn session_destroy(ptr: *mut Session) {
   let _ = unsafe { Box::from_raw(ptr) };
@@ -639,6 +878,20 @@ pub extern "C" fn validator_run(scheduler_ptr: *mut Scheduler) -> PyResult {
   })
 }
 
+///
+/// Returns a JSON string describing the errors (if any) which make the RuleGraph invalid: the same
+/// errors that validator_run renders as text.
+///
+#[no_mangle]
+pub extern "C" fn validator_errors_json(scheduler_ptr: *mut Scheduler) -> PyResult {
+  with_scheduler(scheduler_ptr, |scheduler| {
+    serde_json::to_string(&scheduler.core.rule_graph.errors())
+      .map(|json| externs::store_utf8(&json))
+      .map_err(|e| format!("Failed to serialize rule graph errors: {}", e))
+      .into()
+  })
+}
+
 #[no_mangle]
 pub extern "C" fn rule_graph_visualize(
   scheduler_ptr: *mut Scheduler,
@@ -657,6 +910,39 @@ pub extern "C" fn rule_graph_visualize(
   })
 }
 
+///
+/// Returns the plan of rules that would be used to compute the given product for the given param
+/// types, rendered either as indented text (elided below max_depth, if it is non-zero) or as JSON.
+///
+#[no_mangle]
+pub extern "C" fn rule_graph_explain(
+  scheduler_ptr: *mut Scheduler,
+  param_types: TypeIdBuffer,
+  product_type: TypeId,
+  max_depth: u64,
+  as_json: bool,
+) -> PyResult {
+  with_scheduler(scheduler_ptr, |scheduler| {
+    scheduler
+      .core
+      .rule_graph
+      .plan(param_types.to_vec(), product_type)
+      .map(|plan| {
+        let rendered = if as_json {
+          plan.to_json()
+        } else {
+          plan.render_text(if max_depth == 0 {
+            None
+          } else {
+            Some(max_depth as usize)
+          })
+        };
+        externs::store_utf8(&rendered)
+      })
+      .into()
+  })
+}
+
 #[no_mangle]
 pub extern "C" fn rule_subgraph_visualize(
   scheduler_ptr: *mut Scheduler,
@@ -738,6 +1024,27 @@ pub extern "C" fn garbage_collect_store(scheduler_ptr: *mut Scheduler) {
   });
 }
 
+///
+/// Writes the report of the remote executions so far, if --remote-execution-report-path is set.
+///
+#[no_mangle]
+pub extern "C" fn write_remote_execution_report(scheduler_ptr: *mut Scheduler) -> PyResult {
+  with_scheduler(scheduler_ptr, |scheduler| {
+    scheduler.core.write_remote_execution_report().into()
+  })
+}
+
+///
+/// Writes the remote executions since the last call as a Chrome trace, if
+/// --remote-execution-chrome-trace-path is set.
+///
+#[no_mangle]
+pub extern "C" fn write_remote_execution_trace(scheduler_ptr: *mut Scheduler) -> PyResult {
+  with_scheduler(scheduler_ptr, |scheduler| {
+    scheduler.core.write_remote_execution_trace().into()
+  })
+}
+
 #[no_mangle]
 pub extern "C" fn lease_files_in_graph(scheduler_ptr: *mut Scheduler) {
   with_scheduler(scheduler_ptr, |scheduler| {
@@ -749,6 +1056,29 @@ pub extern "C" fn lease_files_in_graph(scheduler_ptr: *mut Scheduler) {
   });
 }
 
+///
+/// Polls a process which was started by requesting a RunningProcess, returning a tuple of the
+/// stdout that it has written so far and its FallibleExecuteProcessResult (or None, if it is still
+/// running). Raises if the process could not be run.
+///
+#[no_mangle]
+pub extern "C" fn running_process_poll(
+  scheduler_ptr: *mut Scheduler,
+  session_ptr: *mut Session,
+  running_process: Handle,
+) -> PyResult {
+  with_scheduler(scheduler_ptr, |scheduler| {
+    with_session(session_ptr, |session| {
+      let process_id = externs::project_str(&running_process.into(), "process_id");
+      process_id
+        .parse::<u64>()
+        .map_err(|e| format!("Process id {} was not a u64: {:?}", process_id, e))
+        .and_then(|id| nodes::poll_running_process(&scheduler.core, session, id))
+        .into()
+    })
+  })
+}
+
 #[no_mangle]
 pub extern "C" fn match_path_globs(path_globs: Handle, paths_buf: BufferBuffer) -> PyResult {
   let path_globs = match nodes::Snapshot::lift_path_globs(&path_globs.into()) {
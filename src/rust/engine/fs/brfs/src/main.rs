@@ -708,6 +708,7 @@ fn main() {
       args.value_of("remote-instance-name").map(str::to_owned),
       root_ca_certs,
       oauth_bearer_token,
+      None,
       1,
       4 * 1024 * 1024,
       std::time::Duration::from_secs(5 * 60),
@@ -27,8 +27,9 @@
 #![allow(clippy::mutex_atomic)]
 
 mod snapshot;
-pub use crate::snapshot::{OneOffStoreFileByDigest, Snapshot, StoreFileByDigest};
+pub use crate::snapshot::{MergeError, OneOffStoreFileByDigest, Snapshot, StoreFileByDigest};
 
+use async_semaphore::AsyncSemaphore;
 use bazel_protos;
 use boxfuture::{try_future, BoxFuture, Boxable};
 use bytes::Bytes;
@@ -36,11 +37,16 @@ use concrete_time::TimeSpan;
 use dirs;
 use fs::FileContent;
 use futures::{future, Future};
-use hashing::Digest;
+#[cfg(feature = "remote-execution")]
+use grpcio;
+#[cfg(feature = "remote-execution")]
+pub use grpcio::Environment as GrpcEnvironment;
+use hashing::{Digest, Fingerprint};
+use num_cpus;
 use protobuf::Message;
 use serde_derive::Serialize;
 pub use serverset::BackoffConfig;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::OpenOptions;
 use std::io::Write;
 use std::os::unix::fs::OpenOptionsExt;
@@ -58,12 +64,65 @@ const GIGABYTES: usize = 1024 * MEGABYTES;
 // after garbage collection. We almost certainly want to make this configurable.
 pub const DEFAULT_LOCAL_STORE_GC_TARGET_BYTES: usize = 4 * GIGABYTES;
 
+// The minimum interval between progress workunits for an upload to the remote.
+pub const DEFAULT_UPLOAD_PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+// The most completion queues (each of which is polled by a thread) in a default grpc Environment.
+// Beyond a few, more threads only contend with each other.
+pub const MAX_DEFAULT_GRPC_THREADS: usize = 4;
+
+///
+/// One completion queue per core, up to MAX_DEFAULT_GRPC_THREADS.
+///
+pub fn default_grpc_thread_count() -> usize {
+  num_cpus::get().min(MAX_DEFAULT_GRPC_THREADS)
+}
+
+///
+/// A grpc Environment for the channels of Stores and remote CommandRunners, with
+/// default_grpc_thread_count completion queues.
+///
+#[cfg(feature = "remote-execution")]
+pub fn default_grpc_environment() -> Arc<grpcio::Environment> {
+  grpc_environment(default_grpc_thread_count())
+}
+
+///
+/// A grpc Environment with `thread_count` completion queues.
+///
+/// An Environment may be shared by any number of Stores and CommandRunners, each of which holds a
+/// reference to it. When its last reference is dropped, it shuts down and joins its threads, which
+/// panics if that happens on one of them (for example, in a callback of one of its calls). So
+/// whoever shares an Environment should hold a reference to it for as long as it uses anything
+/// which was created with it, and drop that reference last, after those users have been dropped.
+///
+#[cfg(feature = "remote-execution")]
+pub fn grpc_environment(thread_count: usize) -> Arc<grpcio::Environment> {
+  Arc::new(
+    grpcio::EnvBuilder::new()
+      .cq_count(thread_count.max(1))
+      .name_prefix("grpc")
+      .build(),
+  )
+}
+
 mod local;
+mod memory;
+#[cfg(not(feature = "remote-execution"))]
+mod no_remote;
+#[cfg(not(feature = "remote-execution"))]
+use crate::no_remote as remote;
+#[cfg(feature = "remote-execution")]
+mod proxy;
+#[cfg(feature = "remote-execution")]
+pub use crate::proxy::{connect_channel, ChannelArg, ProxyConfig, ProxyScheme};
+#[cfg(feature = "remote-execution")]
 mod remote;
 
 // Summary of the files and directories uploaded with an operation
 // ingested_file_{count, bytes}: Number and combined size of processed files
 // uploaded_file_{count, bytes}: Number and combined size of files uploaded to the remote
+// upload_queue_time: Combined time which the uploaded files waited for the upload limiter
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize)]
 pub struct UploadSummary {
   pub ingested_file_count: usize,
@@ -72,6 +131,146 @@ pub struct UploadSummary {
   pub uploaded_file_bytes: usize,
   #[serde(skip)]
   pub upload_wall_time: Duration,
+  #[serde(skip)]
+  pub upload_queue_time: Duration,
+}
+
+///
+/// How a Store read blobs from its remote: blobs may be read several at a time with BatchReadBlobs
+/// requests, or one at a time with ByteStream reads.
+///
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RemoteReadCounts {
+  pub batch_requests: usize,
+  pub batched_blobs: usize,
+  pub individual_reads: usize,
+}
+
+impl RemoteReadCounts {
+  fn combine(counts: &[RemoteReadCounts]) -> RemoteReadCounts {
+    counts
+      .iter()
+      .fold(RemoteReadCounts::default(), |total, counts| {
+        RemoteReadCounts {
+          batch_requests: total.batch_requests + counts.batch_requests,
+          batched_blobs: total.batched_blobs + counts.batched_blobs,
+          individual_reads: total.individual_reads + counts.individual_reads,
+        }
+      })
+  }
+}
+
+///
+/// The combined size of the blobs which uploads found that they needed to upload, and of those
+/// which they have uploaded so far. They can be read while the uploads are ongoing (see
+/// `Store::with_upload_counts`), for example to report how far an upload got before it timed out.
+/// Cheap to clone: all clones share the counts.
+///
+#[derive(Clone, Debug, Default)]
+pub struct UploadCounts(Arc<Mutex<(usize, usize)>>);
+
+impl UploadCounts {
+  pub fn new() -> UploadCounts {
+    UploadCounts::default()
+  }
+
+  pub fn uploaded_bytes(&self) -> usize {
+    self.0.lock().0
+  }
+
+  pub fn total_bytes(&self) -> usize {
+    self.0.lock().1
+  }
+}
+
+///
+/// Records workunits for the progress of an upload of blobs to the remote: a progress workunit
+/// after a blob is uploaded (at most once per `interval`), and then a final workunit and the parent
+/// "remote input upload" workunit when the upload completes.
+///
+struct UploadProgress {
+  total_count: usize,
+  total_bytes: usize,
+  interval: Duration,
+  start: SystemTime,
+  span_id: String,
+  parent_id: Option<String>,
+  workunit_store: WorkUnitStore,
+  // The count and combined size of the blobs uploaded so far, and when progress was last recorded.
+  state: Mutex<(usize, usize, SystemTime)>,
+}
+
+impl UploadProgress {
+  fn new<'a, I: Iterator<Item = &'a Digest>>(
+    digests: I,
+    interval: Duration,
+    workunit_store: WorkUnitStore,
+  ) -> UploadProgress {
+    let start = SystemTime::now();
+    let (total_count, total_bytes) = digests.fold((0, 0), |(count, bytes), digest| {
+      (count + 1, bytes + digest.1)
+    });
+    UploadProgress {
+      total_count,
+      total_bytes,
+      interval,
+      start,
+      span_id: workunit_store::generate_random_64bit_string(),
+      parent_id: workunit_store::get_parent_id(),
+      workunit_store,
+      state: Mutex::new((0, 0, start)),
+    }
+  }
+
+  fn record_upload(&self, digest: Digest) {
+    let mut state = self.state.lock();
+    state.0 += 1;
+    state.1 += digest.1;
+    let since_last_update = state.2.elapsed().unwrap_or_default();
+    if since_last_update >= self.interval {
+      let name = format!(
+        "remote input upload progress: {}/{} blobs, {}/{} bytes, {} bytes/s",
+        state.0,
+        self.total_count,
+        state.1,
+        self.total_bytes,
+        self.throughput(state.1)
+      );
+      self.add_workunit(name, &state.2);
+      state.2 = SystemTime::now();
+    }
+  }
+
+  fn finish(&self) {
+    let state = self.state.lock();
+    let name = format!(
+      "remote input upload complete: {} blobs, {} bytes, {} bytes/s",
+      state.0,
+      state.1,
+      self.throughput(state.1)
+    );
+    self.add_workunit(name, &state.2);
+    self.workunit_store.add_workunit(workunit_store::WorkUnit {
+      name: "remote input upload".to_string(),
+      time_span: TimeSpan::since(&self.start),
+      span_id: self.span_id.clone(),
+      parent_id: self.parent_id.clone(),
+    });
+  }
+
+  fn throughput(&self, uploaded_bytes: usize) -> u128 {
+    let elapsed_millis = self.start.elapsed().unwrap_or_default().as_millis();
+    (uploaded_bytes as u128) * 1000 / std::cmp::max(elapsed_millis, 1)
+  }
+
+  fn add_workunit(&self, name: String, since: &SystemTime) {
+    self.workunit_store.add_workunit(workunit_store::WorkUnit {
+      name,
+      time_span: TimeSpan::since(since),
+      span_id: workunit_store::generate_random_64bit_string(),
+      parent_id: Some(self.span_id.clone()),
+    });
+  }
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize)]
@@ -148,8 +347,11 @@ enum RootOrParentMetadataBuilder {
 ///
 #[derive(Clone)]
 pub struct Store {
-  local: local::ByteStore,
+  local: LocalStore,
   remote: Option<remote::ByteStore>,
+  upload_progress_interval: Duration,
+  upload_counts: Option<UploadCounts>,
+  upload_limiter: Option<AsyncSemaphore>,
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -170,6 +372,81 @@ pub enum ShrinkBehavior {
   Compact,
 }
 
+///
+/// The local storage of a Store: either LMDB databases on disk, or (for tests and tools which do
+/// not need their blobs to outlive the process) memory.
+///
+#[derive(Clone)]
+enum LocalStore {
+  Lmdb(local::ByteStore),
+  Memory(memory::ByteStore),
+}
+
+impl LocalStore {
+  fn path(&self) -> Option<&Path> {
+    match self {
+      LocalStore::Lmdb(store) => Some(store.path()),
+      LocalStore::Memory(_) => None,
+    }
+  }
+
+  fn entry_type(&self, fingerprint: &Fingerprint) -> Result<Option<EntryType>, String> {
+    match self {
+      LocalStore::Lmdb(store) => store.entry_type(fingerprint),
+      LocalStore::Memory(store) => store.entry_type(fingerprint),
+    }
+  }
+
+  fn lease_all<'a, Ds: Iterator<Item = &'a Digest>>(&self, digests: Ds) -> Result<(), String> {
+    match self {
+      LocalStore::Lmdb(store) => store.lease_all(digests),
+      LocalStore::Memory(store) => store.lease_all(digests),
+    }
+  }
+
+  fn shrink(&self, target_bytes: usize, shrink_behavior: ShrinkBehavior) -> Result<usize, String> {
+    match self {
+      LocalStore::Lmdb(store) => store.shrink(target_bytes, shrink_behavior),
+      LocalStore::Memory(store) => store.shrink(target_bytes, shrink_behavior),
+    }
+  }
+
+  fn store_bytes(
+    &self,
+    entry_type: EntryType,
+    bytes: Bytes,
+    initial_lease: bool,
+  ) -> BoxFuture<Digest, String> {
+    match self {
+      LocalStore::Lmdb(store) => store
+        .store_bytes(entry_type, bytes, initial_lease)
+        .to_boxed(),
+      LocalStore::Memory(store) => store
+        .store_bytes(entry_type, bytes, initial_lease)
+        .to_boxed(),
+    }
+  }
+
+  fn load_bytes_with<T: Send + 'static, F: Fn(Bytes) -> T + Send + Sync + 'static>(
+    &self,
+    entry_type: EntryType,
+    digest: Digest,
+    f: F,
+  ) -> BoxFuture<Option<T>, String> {
+    match self {
+      LocalStore::Lmdb(store) => store.load_bytes_with(entry_type, digest, f),
+      LocalStore::Memory(store) => store.load_bytes_with(entry_type, digest, f),
+    }
+  }
+
+  fn all_digests(&self, entry_type: EntryType) -> Result<Vec<Digest>, String> {
+    match self {
+      LocalStore::Lmdb(store) => store.all_digests(entry_type),
+      LocalStore::Memory(store) => store.all_digests(entry_type),
+    }
+  }
+}
+
 // Note that Store doesn't implement ByteStore because it operates at a higher level of abstraction,
 // considering Directories as a standalone concept, rather than a buffer of bytes.
 // This has the nice property that Directories can be trusted to be valid and canonical.
@@ -183,15 +460,72 @@ impl Store {
     path: P,
   ) -> Result<Store, String> {
     Ok(Store {
-      local: local::ByteStore::new(executor, path)?,
+      local: LocalStore::Lmdb(local::ByteStore::new(executor, path)?),
       remote: None,
+      upload_progress_interval: DEFAULT_UPLOAD_PROGRESS_INTERVAL,
+      upload_counts: None,
+      upload_limiter: None,
+    })
+  }
+
+  ///
+  /// Make a store which only uses local storage, which is kept in memory rather than on disk. It
+  /// is not shared with any other Store (except for clones of this one), and is lost when the
+  /// last of them is dropped.
+  ///
+  pub fn in_memory() -> Store {
+    Store {
+      local: LocalStore::Memory(memory::ByteStore::new()),
+      remote: None,
+      upload_progress_interval: DEFAULT_UPLOAD_PROGRESS_INTERVAL,
+      upload_counts: None,
+      upload_limiter: None,
+    }
+  }
+
+  ///
+  /// As with_remote, but with local storage which is kept in memory (see in_memory).
+  ///
+  #[cfg(feature = "remote-execution")]
+  pub fn in_memory_with_remote(
+    cas_addresses: Vec<String>,
+    instance_name: Option<String>,
+    root_ca_certs: Option<Vec<u8>>,
+    oauth_bearer_token: Option<String>,
+    proxy: Option<ProxyConfig>,
+    thread_count: usize,
+    chunk_size_bytes: usize,
+    upload_timeout: Duration,
+    backoff_config: BackoffConfig,
+    rpc_retries: usize,
+    connection_limit: usize,
+  ) -> Result<Store, String> {
+    Ok(Store {
+      remote: Some(remote::ByteStore::new(
+        cas_addresses,
+        instance_name,
+        root_ca_certs,
+        oauth_bearer_token,
+        proxy,
+        grpc_environment(thread_count),
+        chunk_size_bytes,
+        upload_timeout,
+        backoff_config,
+        rpc_retries,
+        connection_limit,
+      )?),
+      ..Store::in_memory()
     })
   }
 
   ///
   /// Make a store which uses local storage, and if it is missing a value which it tries to load,
-  /// will attempt to back-fill its local storage from a remote CAS.
+  /// will attempt to back-fill its local storage from a remote CAS. If a proxy is given, the CAS is
+  /// connected to via it.
+  ///
+  /// The CAS is connected to in a grpc Environment of its own, with `thread_count` threads.
   ///
+  #[cfg(feature = "remote-execution")]
   pub fn with_remote<P: AsRef<Path>>(
     executor: task_executor::Executor,
     path: P,
@@ -199,30 +533,107 @@ impl Store {
     instance_name: Option<String>,
     root_ca_certs: Option<Vec<u8>>,
     oauth_bearer_token: Option<String>,
+    proxy: Option<ProxyConfig>,
     thread_count: usize,
     chunk_size_bytes: usize,
     upload_timeout: Duration,
     backoff_config: BackoffConfig,
     rpc_retries: usize,
     connection_limit: usize,
+  ) -> Result<Store, String> {
+    Store::with_remote_in_environment(
+      executor,
+      path,
+      cas_addresses,
+      instance_name,
+      root_ca_certs,
+      oauth_bearer_token,
+      proxy,
+      grpc_environment(thread_count),
+      chunk_size_bytes,
+      upload_timeout,
+      backoff_config,
+      rpc_retries,
+      connection_limit,
+    )
+  }
+
+  ///
+  /// As with_remote, but connects to the CAS in the given (possibly shared) grpc Environment. See
+  /// `grpc_environment` for when a shared Environment may be dropped.
+  ///
+  #[cfg(feature = "remote-execution")]
+  pub fn with_remote_in_environment<P: AsRef<Path>>(
+    executor: task_executor::Executor,
+    path: P,
+    cas_addresses: Vec<String>,
+    instance_name: Option<String>,
+    root_ca_certs: Option<Vec<u8>>,
+    oauth_bearer_token: Option<String>,
+    proxy: Option<ProxyConfig>,
+    env: Arc<grpcio::Environment>,
+    chunk_size_bytes: usize,
+    upload_timeout: Duration,
+    backoff_config: BackoffConfig,
+    rpc_retries: usize,
+    connection_limit: usize,
   ) -> Result<Store, String> {
     Ok(Store {
-      local: local::ByteStore::new(executor, path)?,
+      local: LocalStore::Lmdb(local::ByteStore::new(executor, path)?),
       remote: Some(remote::ByteStore::new(
         cas_addresses,
         instance_name,
         root_ca_certs,
         oauth_bearer_token,
-        thread_count,
+        proxy,
+        env,
         chunk_size_bytes,
         upload_timeout,
         backoff_config,
         rpc_retries,
         connection_limit,
       )?),
+      upload_progress_interval: DEFAULT_UPLOAD_PROGRESS_INTERVAL,
+      upload_counts: None,
+      upload_limiter: None,
     })
   }
 
+  ///
+  /// Sets the minimum interval between the progress workunits recorded by
+  /// ensure_remote_has_recursive.
+  ///
+  pub fn with_upload_progress_interval(mut self, upload_progress_interval: Duration) -> Store {
+    self.upload_progress_interval = upload_progress_interval;
+    self
+  }
+
+  ///
+  /// Adds the sizes of the blobs which ensure_remote_has_recursive (and its variants) need to
+  /// upload, and then of each blob as it is uploaded, to the given counts.
+  ///
+  pub fn with_upload_counts(mut self, upload_counts: UploadCounts) -> Store {
+    self.upload_counts = Some(upload_counts);
+    self
+  }
+
+  ///
+  /// Bounds the blobs which ensure_remote_has_recursive (and its variants) upload at once by the
+  /// permits of the given semaphore, which may be shared with other Stores to bound their uploads
+  /// together. The time which blobs wait for a permit is reported in the UploadSummary.
+  ///
+  pub fn with_upload_limiter(mut self, upload_limiter: AsyncSemaphore) -> Store {
+    self.upload_limiter = Some(upload_limiter);
+    self
+  }
+
+  ///
+  /// The directory of the local storage of this Store, or None if it is kept in memory.
+  ///
+  pub fn local_path(&self) -> Option<&Path> {
+    self.local.path()
+  }
+
   // This default is also hard-coded into the Python options code in global_options.py
   pub fn default_path() -> PathBuf {
     match dirs::home_dir() {
@@ -432,6 +843,30 @@ impl Store {
       .to_boxed()
   }
 
+  ///
+  /// Returns those of the given digests which the remote ByteStore does not have. Unlike
+  /// ensure_remote_has_recursive, Directories are not expanded: only the given digests are checked.
+  ///
+  pub fn remote_missing_digests(
+    &self,
+    digests: Vec<Digest>,
+    workunit_store: WorkUnitStore,
+  ) -> BoxFuture<HashSet<Digest>, String> {
+    let remote = if let Some(ref remote) = self.remote {
+      remote
+    } else {
+      return future::err("Cannot check which blobs the remote has without a remote".to_owned())
+        .to_boxed();
+    };
+    if digests.is_empty() {
+      return future::ok(HashSet::new()).to_boxed();
+    }
+    let request = remote.find_missing_blobs_request(digests.iter());
+    remote
+      .list_missing_digests(request, workunit_store)
+      .to_boxed()
+  }
+
   ///
   /// Ensures that the remote ByteStore has a copy of each passed Fingerprint, including any files
   /// contained in any Directories in the list.
@@ -442,6 +877,58 @@ impl Store {
     &self,
     digests: Vec<Digest>,
     workunit_store: WorkUnitStore,
+  ) -> BoxFuture<UploadSummary, String> {
+    self.ensure_remote_has_recursive_with_ephemeral(digests, vec![], workunit_store)
+  }
+
+  ///
+  /// Like ensure_remote_has_recursive, but any blob reachable from one of the ephemeral_digests is
+  /// uploaded without requesting a long lease, so that servers which support differentiated leases
+  /// may expire it sooner. Servers which don't will ignore the distinction. A blob which is also
+  /// reachable from the digests other than via an ephemeral digest is still uploaded with a long
+  /// lease.
+  ///
+  /// The ephemeral_digests are only used to classify blobs: to be uploaded, they must also be (or
+  /// be contained in) one of the digests.
+  ///
+  pub fn ensure_remote_has_recursive_with_ephemeral(
+    &self,
+    digests: Vec<Digest>,
+    ephemeral_digests: Vec<Digest>,
+    workunit_store: WorkUnitStore,
+  ) -> BoxFuture<UploadSummary, String> {
+    let ingested_digests = self.expand_digests(digests.clone(), workunit_store.clone());
+    self.ensure_remote_has_expanded(digests, ingested_digests, ephemeral_digests, workunit_store)
+  }
+
+  ///
+  /// Like ensure_remote_has_recursive_with_ephemeral, but additionally uploads the given changed
+  /// blobs (usually computed by diff_directories) without expanding them: the remote is trusted to
+  /// already have anything which they reference, but which they do not contain.
+  ///
+  pub fn ensure_remote_has_changes_with_ephemeral(
+    &self,
+    digests: Vec<Digest>,
+    changed: HashMap<Digest, EntryType>,
+    ephemeral_digests: Vec<Digest>,
+    workunit_store: WorkUnitStore,
+  ) -> BoxFuture<UploadSummary, String> {
+    let ingested_digests = self
+      .expand_digests(digests.clone(), workunit_store.clone())
+      .map(move |mut ingested_digests| {
+        ingested_digests.extend(changed);
+        ingested_digests
+      })
+      .to_boxed();
+    self.ensure_remote_has_expanded(digests, ingested_digests, ephemeral_digests, workunit_store)
+  }
+
+  fn ensure_remote_has_expanded(
+    &self,
+    digests: Vec<Digest>,
+    ingested_digests: BoxFuture<HashMap<Digest, EntryType>, String>,
+    ephemeral_digests: Vec<Digest>,
+    workunit_store: WorkUnitStore,
   ) -> BoxFuture<UploadSummary, String> {
     let start_time = Instant::now();
 
@@ -451,76 +938,123 @@ impl Store {
       return future::err("Cannot ensure remote has blobs without a remote".to_owned()).to_boxed();
     };
 
-    let mut expanding_futures = Vec::new();
-
-    let mut expanded_digests = HashMap::new();
-    for digest in digests {
-      match self.local.entry_type(&digest.0) {
-        Ok(Some(EntryType::File)) => {
-          expanded_digests.insert(digest, EntryType::File);
-        }
-        Ok(Some(EntryType::Directory)) => {
-          expanding_futures.push(self.expand_directory(digest, workunit_store.clone()));
-        }
-        Ok(None) => {
-          return future::err(format!("Failed to upload digest {:?}: Not found", digest))
-            .to_boxed();
-        }
-        Err(err) => {
-          return future::err(format!("Failed to upload digest {:?}: {:?}", digest, err))
-            .to_boxed();
-        }
-      };
-    }
-
     let local = self.local.clone();
     let remote = remote.clone();
     let remote2 = remote.clone();
     let workunit_store2 = workunit_store.clone();
-    future::join_all(expanding_futures)
-      .map(move |futures| {
-        for mut digests in futures {
-          for (digest, entry_type) in digests.drain() {
-            expanded_digests.insert(digest, entry_type);
-          }
-        }
-        expanded_digests
-      })
-      .and_then(move |ingested_digests| {
+    let upload_progress_interval = self.upload_progress_interval;
+    let upload_counts = self.upload_counts.clone();
+    let upload_limiter = self.upload_limiter.clone();
+    // Walking the digests again is only necessary if some of them are ephemeral.
+    let long_leased = if ephemeral_digests.is_empty() {
+      future::ok(HashSet::new()).to_boxed()
+    } else {
+      self.long_leased_digests(
+        digests,
+        ephemeral_digests.iter().cloned().collect(),
+        workunit_store.clone(),
+      )
+    };
+    ingested_digests
+      .join3(
+        self.expand_digests(ephemeral_digests, workunit_store.clone()),
+        long_leased,
+      )
+      .and_then(move |(ingested_digests, ephemeral_digests, long_leased)| {
+        let short_leased: HashSet<Digest> = ephemeral_digests
+          .keys()
+          .filter(|digest| !long_leased.contains(digest))
+          .cloned()
+          .collect();
         if Store::upload_is_faster_than_checking_whether_to_upload(&ingested_digests) {
-          return future::ok((ingested_digests.keys().cloned().collect(), ingested_digests))
-            .to_boxed();
+          return future::ok((
+            ingested_digests.keys().cloned().collect(),
+            ingested_digests,
+            short_leased,
+          ))
+          .to_boxed();
         }
         let request = remote.find_missing_blobs_request(ingested_digests.keys());
         let f = remote.list_missing_digests(request, workunit_store.clone());
-        f.map(move |digests_to_upload| (digests_to_upload, ingested_digests))
+        f.map(move |digests_to_upload| (digests_to_upload, ingested_digests, short_leased))
           .to_boxed()
       })
-      .and_then(move |(digests_to_upload, ingested_digests)| {
+      .and_then(move |(digests_to_upload, ingested_digests, short_leased)| {
+        let progress = if digests_to_upload.is_empty() {
+          None
+        } else {
+          Some(Arc::new(UploadProgress::new(
+            digests_to_upload.iter(),
+            upload_progress_interval,
+            workunit_store2.clone(),
+          )))
+        };
+        let progress2 = progress.clone();
+        if let Some(ref upload_counts) = upload_counts {
+          upload_counts.0.lock().1 += digests_to_upload
+            .iter()
+            .map(|digest| digest.1)
+            .sum::<usize>();
+        }
         future::join_all(
           digests_to_upload
             .into_iter()
             .map(|digest| {
               let entry_type = ingested_digests[&digest];
+              let short_lease = short_leased.contains(&digest);
+              let local = local.clone();
               let remote = remote2.clone();
               let workunit_store = workunit_store2.clone();
-              local
-                .load_bytes_with(entry_type, digest, move |bytes| {
-                  remote.store_bytes(bytes, workunit_store.clone())
-                })
-                .and_then(move |maybe_future| match maybe_future {
-                  Some(future) => Ok(future),
-                  None => Err(format!("Failed to upload digest {:?}: Not found", digest)),
-                })
+              let progress = progress.clone();
+              let upload_counts = upload_counts.clone();
+              let upload = move || {
+                local
+                  .load_bytes_with(entry_type, digest, move |bytes| {
+                    let progress = progress.clone();
+                    let upload_counts = upload_counts.clone();
+                    remote
+                      .store_bytes(bytes, short_lease, workunit_store.clone())
+                      .inspect(move |digest| {
+                        if let Some(ref progress) = progress {
+                          progress.record_upload(*digest);
+                        }
+                        if let Some(ref upload_counts) = upload_counts {
+                          upload_counts.0.lock().0 += digest.1;
+                        }
+                      })
+                  })
+                  .and_then(move |maybe_future| match maybe_future {
+                    Some(future) => future.to_boxed(),
+                    None => future::err(format!("Failed to upload digest {:?}: Not found", digest))
+                      .to_boxed(),
+                  })
+              };
+              // Each blob holds a permit of the limiter from loading it until it is uploaded.
+              match upload_limiter {
+                Some(ref upload_limiter) => {
+                  let queued_at = Instant::now();
+                  upload_limiter.with_acquired(move || {
+                    let queue_time = queued_at.elapsed();
+                    upload().map(move |digest| (digest, queue_time))
+                  })
+                }
+                None => upload()
+                  .map(|digest| (digest, Duration::default()))
+                  .to_boxed(),
+              }
             })
             .collect::<Vec<_>>(),
         )
-        .and_then(future::join_all)
+        .inspect(move |_| {
+          if let Some(progress) = progress2 {
+            progress.finish();
+          }
+        })
         .map(|uploaded_digests| (uploaded_digests, ingested_digests))
       })
       .map(move |(uploaded_digests, ingested_digests)| {
         let ingested_file_sizes = ingested_digests.iter().map(|(digest, _)| digest.1);
-        let uploaded_file_sizes = uploaded_digests.iter().map(|digest| digest.1);
+        let uploaded_file_sizes = uploaded_digests.iter().map(|(digest, _)| digest.1);
 
         UploadSummary {
           ingested_file_count: ingested_file_sizes.len(),
@@ -528,11 +1062,126 @@ impl Store {
           uploaded_file_count: uploaded_file_sizes.len(),
           uploaded_file_bytes: uploaded_file_sizes.sum(),
           upload_wall_time: start_time.elapsed(),
+          upload_queue_time: uploaded_digests
+            .iter()
+            .map(|(_, queue_time)| *queue_time)
+            .sum(),
         }
       })
       .to_boxed()
   }
 
+  ///
+  /// Expands the given Digests of Files and Directories into the Digests of everything that they
+  /// contain (including themselves).
+  ///
+  ///
+  /// Returns the blobs which are reachable from the given digests other than via one of the
+  /// ephemeral_digests, and so which should hold long leases even if they are also reachable from
+  /// an ephemeral digest.
+  ///
+  fn long_leased_digests(
+    &self,
+    digests: Vec<Digest>,
+    ephemeral_digests: HashSet<Digest>,
+    workunit_store: WorkUnitStore,
+  ) -> BoxFuture<HashSet<Digest>, String> {
+    let mut long_leased = HashSet::new();
+    let mut directories = Vec::new();
+    for digest in digests {
+      if ephemeral_digests.contains(&digest) {
+        continue;
+      }
+      match self.local.entry_type(&digest.0) {
+        Ok(Some(EntryType::Directory)) => directories.push(digest),
+        _ => {
+          long_leased.insert(digest);
+        }
+      }
+    }
+
+    // The children of each reachable directory, from which the blobs which are reachable without
+    // passing through an ephemeral digest are then found.
+    let walks = directories
+      .iter()
+      .map(|digest| {
+        self.walk(
+          *digest,
+          |_, _, digest, directory| {
+            let children = directory
+              .get_files()
+              .iter()
+              .map(|file| file.get_digest())
+              .chain(
+                directory
+                  .get_directories()
+                  .iter()
+                  .map(|dir| dir.get_digest()),
+              )
+              .map(|digest| -> Result<Digest, String> { digest.into() })
+              .collect::<Result<Vec<_>, _>>();
+            future::result(children.map(|children| (digest, children))).to_boxed()
+          },
+          workunit_store.clone(),
+        )
+      })
+      .collect::<Vec<_>>();
+    future::join_all(walks)
+      .map(move |walked| {
+        let children: HashMap<Digest, Vec<Digest>> = walked.into_iter().flatten().collect();
+        let mut pending = directories;
+        while let Some(digest) = pending.pop() {
+          if ephemeral_digests.contains(&digest) || !long_leased.insert(digest) {
+            continue;
+          }
+          if let Some(children) = children.get(&digest) {
+            pending.extend(children.iter().cloned());
+          }
+        }
+        long_leased
+      })
+      .to_boxed()
+  }
+
+  fn expand_digests(
+    &self,
+    digests: Vec<Digest>,
+    workunit_store: WorkUnitStore,
+  ) -> BoxFuture<HashMap<Digest, EntryType>, String> {
+    let mut expanding_futures = Vec::new();
+
+    let mut expanded_digests = HashMap::new();
+    for digest in digests {
+      match self.local.entry_type(&digest.0) {
+        Ok(Some(EntryType::File)) => {
+          expanded_digests.insert(digest, EntryType::File);
+        }
+        Ok(Some(EntryType::Directory)) => {
+          expanding_futures.push(self.expand_directory(digest, workunit_store.clone()));
+        }
+        Ok(None) => {
+          return future::err(format!("Failed to upload digest {:?}: Not found", digest))
+            .to_boxed();
+        }
+        Err(err) => {
+          return future::err(format!("Failed to upload digest {:?}: {:?}", digest, err))
+            .to_boxed();
+        }
+      };
+    }
+
+    future::join_all(expanding_futures)
+      .map(move |futures| {
+        for mut digests in futures {
+          for (digest, entry_type) in digests.drain() {
+            expanded_digests.insert(digest, entry_type);
+          }
+        }
+        expanded_digests
+      })
+      .to_boxed()
+  }
+
   ///
   /// Download a directory from Remote ByteStore recursively to the local one. Called only with the
   /// Digest of a Directory.
@@ -583,6 +1232,151 @@ impl Store {
       .to_boxed()
   }
 
+  ///
+  /// Download the given files from the Remote ByteStore to the local one, skipping those which are
+  /// already local. Files of at most max_batch_bytes are read in BatchReadBlobs requests, each for
+  /// at most max_batch_bytes in total: larger files, and any which a batch does not return (e.g.
+  /// because the server does not implement BatchReadBlobs) are read individually. Fails if any of
+  /// the files is not in the remote.
+  ///
+  pub fn ensure_local_has_files(
+    &self,
+    digests: Vec<Digest>,
+    max_batch_bytes: usize,
+    workunit_store: WorkUnitStore,
+  ) -> BoxFuture<RemoteReadCounts, String> {
+    let remote = match self.remote {
+      Some(ref remote) => remote.clone(),
+      None => return future::ok(RemoteReadCounts::default()).to_boxed(),
+    };
+    let mut wanted = Vec::with_capacity(digests.len());
+    let mut seen = HashSet::with_capacity(digests.len());
+    for digest in digests {
+      if seen.insert(digest) && try_future!(self.local.entry_type(&digest.0)).is_none() {
+        wanted.push(digest);
+      }
+    }
+    let (batchable, oversized): (Vec<_>, Vec<_>) = wanted
+      .into_iter()
+      .partition(|digest| digest.1 <= max_batch_bytes);
+
+    let mut batches: Vec<Vec<Digest>> = vec![];
+    let mut batch_bytes = 0;
+    for digest in batchable {
+      if batches.is_empty() || batch_bytes + digest.1 > max_batch_bytes {
+        batches.push(vec![]);
+        batch_bytes = 0;
+      }
+      batches.last_mut().unwrap().push(digest);
+      batch_bytes += digest.1;
+    }
+
+    let batch_futures = batches
+      .into_iter()
+      .map(|batch| self.load_file_batch(&remote, batch, workunit_store.clone()))
+      .collect::<Vec<_>>();
+    let individual_futures = oversized
+      .into_iter()
+      .map(|digest| {
+        self
+          .load_file_individually(digest, workunit_store.clone())
+          .map(|()| RemoteReadCounts {
+            individual_reads: 1,
+            ..RemoteReadCounts::default()
+          })
+      })
+      .collect::<Vec<_>>();
+    future::join_all(batch_futures)
+      .join(future::join_all(individual_futures))
+      .map(|(batch_counts, individual_counts)| {
+        RemoteReadCounts::combine(&[
+          RemoteReadCounts::combine(&batch_counts),
+          RemoteReadCounts::combine(&individual_counts),
+        ])
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Reads a batch of files with one BatchReadBlobs request, and stores them locally. Files which
+  /// the request does not return (or all of them, if it fails) are read individually.
+  ///
+  fn load_file_batch(
+    &self,
+    remote: &remote::ByteStore,
+    batch: Vec<Digest>,
+    workunit_store: WorkUnitStore,
+  ) -> BoxFuture<RemoteReadCounts, String> {
+    let store = self.clone();
+    remote
+      .load_bytes_batch(batch.clone(), workunit_store.clone())
+      .or_else(|_| Ok::<_, String>(HashMap::new()))
+      .and_then(move |mut blobs| {
+        let batched_blobs = batch
+          .iter()
+          .filter(|digest| blobs.contains_key(digest))
+          .count();
+        let loads = batch
+          .into_iter()
+          .map(|digest| match blobs.remove(&digest) {
+            Some(bytes) => store
+              .local
+              .store_bytes(EntryType::File, bytes, true)
+              .and_then(move |stored_digest| {
+                if digest == stored_digest {
+                  Ok(0)
+                } else {
+                  Err(format!(
+                    "CAS gave wrong digest: expected {:?}, got {:?}",
+                    digest, stored_digest
+                  ))
+                }
+              })
+              .to_boxed(),
+            None => store
+              .load_file_individually(digest, workunit_store.clone())
+              .map(|()| 1)
+              .to_boxed(),
+          })
+          .collect::<Vec<BoxFuture<usize, String>>>();
+        future::join_all(loads).map(move |individual_reads| RemoteReadCounts {
+          batch_requests: 1,
+          batched_blobs,
+          individual_reads: individual_reads.iter().sum(),
+        })
+      })
+      .to_boxed()
+  }
+
+  fn load_file_individually(
+    &self,
+    digest: Digest,
+    workunit_store: WorkUnitStore,
+  ) -> BoxFuture<(), String> {
+    self
+      .load_bytes_with(
+        EntryType::File,
+        digest,
+        |_| Ok(()),
+        |_| Ok(()),
+        workunit_store,
+      )
+      .and_then(move |maybe_file| {
+        maybe_file
+          .map(|((), _metadata)| ())
+          .ok_or_else(|| format!("Could not read file with digest {:?}", digest))
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Returns true if the local store contains a Directory with the given Digest. Does not consult
+  /// the remote store, or check whether the Directory's children are present.
+  ///
+  pub fn has_local_directory(&self, digest: Digest) -> Result<bool, String> {
+    Ok(self.local.entry_type(&digest.0)? == Some(EntryType::Directory))
+  }
+
   pub fn lease_all<'a, Ds: Iterator<Item = &'a Digest>>(&self, digests: Ds) -> Result<(), String> {
     self.local.lease_all(digests)
   }
@@ -627,6 +1421,81 @@ impl Store {
     }
   }
 
+  ///
+  /// Computes the blobs which are reachable from the `current` Directory, but which are not at the
+  /// same path under the `previous` Directory: that is, the Directories which differ, and the Files
+  /// and Directories which they contain that are not in their previous versions.
+  ///
+  /// Only subtrees whose digests differ are walked, so when few files change between two large
+  /// Directories, this is much cheaper than expanding `current`. Both Directories must be
+  /// available, but only the parts of `previous` which differ from `current` are loaded.
+  ///
+  pub fn diff_directories(
+    &self,
+    previous: Digest,
+    current: Digest,
+    workunit_store: WorkUnitStore,
+  ) -> BoxFuture<HashMap<Digest, EntryType>, String> {
+    if previous == current {
+      return future::ok(HashMap::new()).to_boxed();
+    }
+    let store = self.clone();
+    let load = |digest: Digest| {
+      self
+        .load_directory(digest, workunit_store.clone())
+        .and_then(move |maybe_directory| {
+          maybe_directory
+            .map(|(directory, _metadata)| directory)
+            .ok_or_else(|| format!("Failed to diff directory {:?}: Not found", digest))
+        })
+    };
+    load(previous)
+      .join(load(current))
+      .and_then(move |(previous_directory, current_directory)| {
+        let mut changed = HashMap::new();
+        changed.insert(current, EntryType::Directory);
+
+        let previous_files = previous_directory
+          .get_files()
+          .iter()
+          .map(|file| (file.get_name(), file.get_digest()))
+          .collect::<HashMap<_, _>>();
+        for file in current_directory.get_files() {
+          if previous_files.get(file.get_name()) != Some(&file.get_digest()) {
+            changed.insert(try_future!(file.get_digest().into()), EntryType::File);
+          }
+        }
+
+        let previous_directories = previous_directory
+          .get_directories()
+          .iter()
+          .map(|directory| (directory.get_name(), directory.get_digest()))
+          .collect::<HashMap<_, _>>();
+        let mut subtrees = Vec::new();
+        for directory in current_directory.get_directories() {
+          let digest: Digest = try_future!(directory.get_digest().into());
+          match previous_directories.get(directory.get_name()) {
+            Some(&previous) if previous == directory.get_digest() => {}
+            Some(&previous) => {
+              let previous: Digest = try_future!(previous.into());
+              subtrees.push(store.diff_directories(previous, digest, workunit_store.clone()));
+            }
+            None => subtrees.push(store.expand_directory(digest, workunit_store.clone())),
+          }
+        }
+
+        future::join_all(subtrees)
+          .map(move |subtrees| {
+            for subtree in subtrees {
+              changed.extend(subtree);
+            }
+            changed
+          })
+          .to_boxed()
+      })
+      .to_boxed()
+  }
+
   pub fn expand_directory(
     &self,
     digest: Digest,
@@ -949,20 +1818,21 @@ impl Store {
   }
 }
 
-// Only public for testing.
 #[derive(Copy, Clone, Debug, Eq, Hash, PartialEq, Ord, PartialOrd)]
 pub enum EntryType {
   Directory,
   File,
 }
 
-#[cfg(test)]
+// The tests read from and write to a StubCAS, so need a remote.
+#[cfg(all(test, feature = "remote-execution"))]
 mod tests {
   use super::{
-    local, DirectoryMaterializeMetadata, EntryType, FileContent, LoadMetadata, Store,
-    UploadSummary, MEGABYTES,
+    local, DirectoryMaterializeMetadata, EntryType, FileContent, LoadMetadata, RemoteReadCounts,
+    Store, UploadCounts, UploadSummary, MEGABYTES,
   };
 
+  use async_semaphore::AsyncSemaphore;
   use bazel_protos;
   use bytes::Bytes;
   use digest::{Digest as DigestTrait, FixedOutput};
@@ -974,9 +1844,10 @@ mod tests {
   use serverset::BackoffConfig;
   use sha2::Sha256;
   use std;
-  use std::collections::HashMap;
+  use std::collections::{HashMap, HashSet};
   use std::fs::File;
   use std::io::Read;
+  use std::iter;
   use std::os::unix::fs::PermissionsExt;
   use std::path::{Path, PathBuf};
   use std::time::Duration;
@@ -1064,6 +1935,7 @@ mod tests {
       None,
       None,
       None,
+      None,
       1,
       10 * MEGABYTES,
       Duration::from_secs(1),
@@ -1220,6 +2092,95 @@ mod tests {
     );
   }
 
+  #[test]
+  fn ensure_local_has_files_batches_small_files() {
+    let dir = TempDir::new().unwrap();
+
+    // Three batches of two, and one file which is too large for a batch.
+    let small_files = (0..6)
+      .map(|i| TestData::new(&format!("small file {}", i)))
+      .collect::<Vec<_>>();
+    let large_file = TestData::fourty_chars();
+    let max_batch_bytes = 2 * small_files[0].len();
+    let cas = small_files
+      .iter()
+      .chain(iter::once(&large_file))
+      .fold(StubCAS::builder(), |cas, file| cas.file(file))
+      .max_batch_total_size_bytes(max_batch_bytes)
+      .build();
+    let digests = small_files
+      .iter()
+      .chain(iter::once(&large_file))
+      .map(TestData::digest)
+      .collect::<Vec<_>>();
+
+    let store = new_store(dir.path(), cas.address());
+    let counts = block_on(store.ensure_local_has_files(
+      digests.clone(),
+      max_batch_bytes,
+      WorkUnitStore::new(),
+    ))
+    .unwrap();
+    assert_eq!(
+      counts,
+      RemoteReadCounts {
+        batch_requests: 3,
+        batched_blobs: 6,
+        individual_reads: 1,
+      }
+    );
+    assert_eq!(cas.batch_read_request_count(), 3);
+    assert_eq!(cas.read_request_count(), 1);
+
+    // Files which are already local are not read again.
+    let counts =
+      block_on(store.ensure_local_has_files(digests, max_batch_bytes, WorkUnitStore::new()))
+        .unwrap();
+    assert_eq!(counts, RemoteReadCounts::default());
+    assert_eq!(cas.batch_read_request_count(), 3);
+
+    drop(store);
+    for file in small_files.iter().chain(iter::once(&large_file)) {
+      assert_eq!(
+        load_file_bytes(&new_local_store(dir.path()), file.digest()),
+        Ok(Some(file.bytes()))
+      );
+    }
+  }
+
+  #[test]
+  fn ensure_local_has_files_falls_back_to_individual_reads() {
+    let dir = TempDir::new().unwrap();
+
+    let roland = TestData::roland();
+    let catnip = TestData::catnip();
+    // The server rejects every batch, as if the advertised limit were wrong.
+    let cas = StubCAS::builder()
+      .file(&roland)
+      .file(&catnip)
+      .max_batch_total_size_bytes(0)
+      .build();
+
+    let counts = block_on(new_store(dir.path(), cas.address()).ensure_local_has_files(
+      vec![roland.digest(), catnip.digest()],
+      10 * MEGABYTES,
+      WorkUnitStore::new(),
+    ))
+    .unwrap();
+    assert_eq!(
+      counts,
+      RemoteReadCounts {
+        batch_requests: 1,
+        batched_blobs: 0,
+        individual_reads: 2,
+      }
+    );
+    assert_eq!(
+      load_file_bytes(&new_local_store(dir.path()), catnip.digest()),
+      Ok(Some(catnip.bytes()))
+    );
+  }
+
   #[test]
   fn load_file_missing_is_none() {
     let dir = TempDir::new().unwrap();
@@ -1509,6 +2470,80 @@ mod tests {
     );
   }
 
+  #[test]
+  fn diff_directories_includes_only_changed_subtrees() {
+    let dir = TempDir::new().unwrap();
+    let store = new_local_store(dir.path());
+    for directory in &[
+      TestDirectory::containing_roland(),
+      TestDirectory::containing_robin(),
+      TestDirectory::recursive(),
+      TestDirectory::recursive_with_robin(),
+    ] {
+      block_on(store.record_directory(&directory.directory(), false))
+        .expect("Error storing directory locally");
+    }
+
+    let changed = block_on(store.diff_directories(
+      TestDirectory::recursive().digest(),
+      TestDirectory::recursive_with_robin().digest(),
+      WorkUnitStore::new(),
+    ))
+    .unwrap();
+
+    // The unchanged treats file is not included, and nor is the removed roland.
+    assert_eq!(
+      changed,
+      vec![
+        (
+          TestDirectory::recursive_with_robin().digest(),
+          EntryType::Directory
+        ),
+        (
+          TestDirectory::containing_robin().digest(),
+          EntryType::Directory
+        ),
+        (TestData::robin().digest(), EntryType::File),
+      ]
+      .into_iter()
+      .collect::<HashMap<_, _>>()
+    );
+  }
+
+  #[test]
+  fn diff_identical_directories_is_empty() {
+    let dir = TempDir::new().unwrap();
+    let recursive = TestDirectory::recursive();
+
+    let changed = block_on(new_local_store(dir.path()).diff_directories(
+      recursive.digest(),
+      recursive.digest(),
+      WorkUnitStore::new(),
+    ))
+    .unwrap();
+    assert_eq!(changed, HashMap::new());
+  }
+
+  #[test]
+  fn diff_directories_missing_previous() {
+    let dir = TempDir::new().unwrap();
+    let store = new_local_store(dir.path());
+    block_on(store.record_directory(&TestDirectory::recursive_with_robin().directory(), false))
+      .expect("Error storing directory locally");
+
+    let error = block_on(store.diff_directories(
+      TestDirectory::recursive().digest(),
+      TestDirectory::recursive_with_robin().digest(),
+      WorkUnitStore::new(),
+    ))
+    .expect_err("Want error");
+    assert!(
+      error.contains(&format!("{}", TestDirectory::recursive().fingerprint())),
+      "Bad error message: {}",
+      error
+    );
+  }
+
   #[test]
   fn uploads_files() {
     let dir = TempDir::new().unwrap();
@@ -1686,6 +2721,194 @@ mod tests {
     );
   }
 
+  #[test]
+  fn upload_records_progress_workunits() {
+    let dir = TempDir::new().unwrap();
+    let cas = StubCAS::empty();
+
+    let catnip = TestData::catnip();
+    let roland = TestData::roland();
+    let testdir = TestDirectory::containing_roland();
+
+    block_on(new_local_store(dir.path()).record_directory(&testdir.directory(), false))
+      .expect("Error storing directory locally");
+    block_on(new_local_store(dir.path()).store_file_bytes(roland.bytes(), false))
+      .expect("Error storing file locally");
+    block_on(new_local_store(dir.path()).store_file_bytes(catnip.bytes(), false))
+      .expect("Error storing file locally");
+
+    let workunit_store = WorkUnitStore::new();
+    let summary = block_on(
+      new_store(dir.path(), cas.address())
+        .with_upload_progress_interval(Duration::from_millis(0))
+        .ensure_remote_has_recursive(
+          vec![testdir.digest(), catnip.digest()],
+          workunit_store.clone(),
+        ),
+    )
+    .expect("Error uploading directory");
+    assert_eq!(summary.uploaded_file_count, 3);
+
+    let workunits = workunit_store.get_workunits().lock().clone();
+    let parent = workunits
+      .iter()
+      .find(|workunit| workunit.name == "remote input upload")
+      .expect("Want a parent workunit");
+    let children = workunits
+      .iter()
+      .filter(|workunit| workunit.parent_id.as_ref() == Some(&parent.span_id))
+      .map(|workunit| workunit.name.clone())
+      .collect::<Vec<_>>();
+
+    assert!(children
+      .iter()
+      .any(|name| name.starts_with("remote input upload progress: ")));
+    let complete = format!(
+      "remote input upload complete: 3 blobs, {} bytes, ",
+      summary.uploaded_file_bytes
+    );
+    assert_eq!(
+      children
+        .iter()
+        .filter(|name| name.starts_with(&complete))
+        .count(),
+      1
+    );
+  }
+
+  #[test]
+  fn upload_counts_are_shared_with_the_caller() {
+    let dir = TempDir::new().unwrap();
+    let cas = StubCAS::empty();
+
+    let catnip = TestData::catnip();
+    let roland = TestData::roland();
+    let testdir = TestDirectory::containing_roland();
+
+    block_on(new_local_store(dir.path()).record_directory(&testdir.directory(), false))
+      .expect("Error storing directory locally");
+    block_on(new_local_store(dir.path()).store_file_bytes(roland.bytes(), false))
+      .expect("Error storing file locally");
+    block_on(new_local_store(dir.path()).store_file_bytes(catnip.bytes(), false))
+      .expect("Error storing file locally");
+
+    let upload_counts = UploadCounts::new();
+    let summary = block_on(
+      new_store(dir.path(), cas.address())
+        .with_upload_counts(upload_counts.clone())
+        .ensure_remote_has_recursive(
+          vec![testdir.digest(), catnip.digest()],
+          WorkUnitStore::new(),
+        ),
+    )
+    .expect("Error uploading directory");
+    assert_eq!(
+      summary.uploaded_file_bytes,
+      testdir.bytes().len() + roland.len() + catnip.len()
+    );
+    assert_eq!(upload_counts.total_bytes(), summary.uploaded_file_bytes);
+    assert_eq!(upload_counts.uploaded_bytes(), summary.uploaded_file_bytes);
+  }
+
+  #[test]
+  fn upload_limiter_bounds_concurrent_writes() {
+    let dir = TempDir::new().unwrap();
+    let cas = StubCAS::builder()
+      .write_delay(Duration::from_millis(10))
+      .build();
+
+    let catnip = TestData::catnip();
+    let roland = TestData::roland();
+    let testdir = TestDirectory::containing_roland();
+
+    block_on(new_local_store(dir.path()).record_directory(&testdir.directory(), false))
+      .expect("Error storing directory locally");
+    block_on(new_local_store(dir.path()).store_file_bytes(roland.bytes(), false))
+      .expect("Error storing file locally");
+    block_on(new_local_store(dir.path()).store_file_bytes(catnip.bytes(), false))
+      .expect("Error storing file locally");
+
+    let summary = block_on(
+      new_store(dir.path(), cas.address())
+        .with_upload_limiter(AsyncSemaphore::new(1))
+        .ensure_remote_has_recursive(
+          vec![testdir.digest(), catnip.digest()],
+          WorkUnitStore::new(),
+        ),
+    )
+    .expect("Error uploading directory");
+    assert_eq!(summary.uploaded_file_count, 3);
+    assert_eq!(cas.max_concurrent_writes(), 1);
+  }
+
+  #[test]
+  fn uploads_ephemeral_digests_with_short_leases() {
+    let dir = TempDir::new().unwrap();
+    let cas = StubCAS::empty();
+
+    let catnip = TestData::catnip();
+    let roland = TestData::roland();
+    let testdir = TestDirectory::containing_roland();
+
+    block_on(new_local_store(dir.path()).record_directory(&testdir.directory(), false))
+      .expect("Error storing directory locally");
+    block_on(new_local_store(dir.path()).store_file_bytes(roland.bytes(), false))
+      .expect("Error storing file locally");
+    block_on(new_local_store(dir.path()).store_file_bytes(catnip.bytes(), false))
+      .expect("Error storing file locally");
+
+    block_on(
+      new_store(dir.path(), cas.address()).ensure_remote_has_recursive_with_ephemeral(
+        vec![testdir.digest(), catnip.digest()],
+        vec![testdir.digest()],
+        WorkUnitStore::new(),
+      ),
+    )
+    .expect("Error uploading");
+
+    assert_eq!(
+      *cas.write_short_leases.lock(),
+      vec![
+        (testdir.fingerprint(), true),
+        (roland.fingerprint(), true),
+        (catnip.fingerprint(), false),
+      ]
+      .into_iter()
+      .collect::<HashMap<_, _>>()
+    );
+  }
+
+  #[test]
+  fn does_not_shorten_the_leases_of_blobs_which_are_also_reachable_otherwise() {
+    let dir = TempDir::new().unwrap();
+    let cas = StubCAS::empty();
+
+    let roland = TestData::roland();
+    let testdir = TestDirectory::containing_roland();
+
+    block_on(new_local_store(dir.path()).record_directory(&testdir.directory(), false))
+      .expect("Error storing directory locally");
+    block_on(new_local_store(dir.path()).store_file_bytes(roland.bytes(), false))
+      .expect("Error storing file locally");
+
+    // roland is both inside the ephemeral directory, and an upload in its own right.
+    block_on(
+      new_store(dir.path(), cas.address()).ensure_remote_has_recursive_with_ephemeral(
+        vec![testdir.digest(), roland.digest()],
+        vec![testdir.digest()],
+        WorkUnitStore::new(),
+      ),
+    )
+    .expect("Error uploading");
+
+    assert_eq!(
+      *cas.write_short_leases.lock(),
+      vec![(testdir.fingerprint(), true), (roland.fingerprint(), false)]
+        .into_iter()
+        .collect::<HashMap<_, _>>()
+    );
+  }
+
   #[test]
   fn upload_missing_files() {
     let dir = TempDir::new().unwrap();
@@ -1706,6 +2929,40 @@ mod tests {
     );
   }
 
+  #[test]
+  fn remote_missing_digests_does_not_expand_directories() {
+    let dir = TempDir::new().unwrap();
+    let roland = TestData::roland();
+    let catnip = TestData::catnip();
+    let testdir = TestDirectory::containing_roland();
+    let cas = StubCAS::builder().file(&roland).build();
+    let store = new_store(dir.path(), cas.address());
+
+    let missing = block_on(store.remote_missing_digests(
+      vec![roland.digest(), catnip.digest(), testdir.digest()],
+      WorkUnitStore::new(),
+    ))
+    .unwrap();
+    assert_eq!(
+      missing,
+      vec![catnip.digest(), testdir.digest()]
+        .into_iter()
+        .collect::<HashSet<_>>()
+    );
+    assert_eq!(
+      *cas.find_missing_blobs_log.lock(),
+      vec![
+        roland.fingerprint(),
+        catnip.fingerprint(),
+        testdir.fingerprint(),
+      ]
+    );
+    assert_eq!(
+      block_on(store.remote_missing_digests(vec![], WorkUnitStore::new())),
+      Ok(HashSet::new())
+    );
+  }
+
   #[test]
   fn upload_missing_file_in_directory() {
     let dir = TempDir::new().unwrap();
@@ -1781,6 +3038,7 @@ mod tests {
       Some("dark-tower".to_owned()),
       None,
       None,
+      None,
       1,
       10 * MEGABYTES,
       Duration::from_secs(1),
@@ -1811,6 +3069,7 @@ mod tests {
       Some("dark-tower".to_owned()),
       None,
       None,
+      None,
       1,
       10 * MEGABYTES,
       Duration::from_secs(1),
@@ -1857,6 +3116,7 @@ mod tests {
       None,
       None,
       Some("Armory.Key".to_owned()),
+      None,
       1,
       10 * MEGABYTES,
       Duration::from_secs(1),
@@ -1887,6 +3147,7 @@ mod tests {
       None,
       None,
       Some("Armory.Key".to_owned()),
+      None,
       1,
       10 * MEGABYTES,
       Duration::from_secs(1),
@@ -2214,6 +3475,7 @@ mod tests {
         uploaded_file_count: test_data.len(),
         uploaded_file_bytes: test_bytes,
         upload_wall_time: Duration::default(),
+        upload_queue_time: Duration::default(),
       }
     );
   }
@@ -2252,6 +3514,7 @@ mod tests {
         uploaded_file_count: 1,
         uploaded_file_bytes: testroland.digest().1,
         upload_wall_time: Duration::default(),
+        upload_queue_time: Duration::default(),
       }
     );
 
@@ -2274,6 +3537,7 @@ mod tests {
         uploaded_file_count: 2,
         uploaded_file_bytes: testdir.digest().1 + testcatnip.digest().1,
         upload_wall_time: Duration::default(),
+        upload_queue_time: Duration::default(),
       }
     );
   }
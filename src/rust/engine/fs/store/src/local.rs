@@ -11,7 +11,7 @@ use sha2::Sha256;
 use sharded_lmdb::ShardedLmdb;
 use std;
 use std::collections::BinaryHeap;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time;
 
@@ -27,6 +27,7 @@ struct InnerStore {
   file_dbs: Result<Arc<ShardedLmdb>, String>,
   directory_dbs: Result<Arc<ShardedLmdb>, String>,
   executor: task_executor::Executor,
+  root: PathBuf,
 }
 
 impl ByteStore {
@@ -53,10 +54,15 @@ impl ByteStore {
         directory_dbs: ShardedLmdb::new(directories_root.clone(), 5 * GIGABYTES, executor.clone())
           .map(Arc::new),
         executor: executor,
+        root: root.to_owned(),
       }),
     })
   }
 
+  pub fn path(&self) -> &Path {
+    &self.inner.root
+  }
+
   // Note: This performs IO on the calling thread. Hopefully the IO is small enough not to matter.
   pub fn entry_type(&self, fingerprint: &Fingerprint) -> Result<Option<EntryType>, String> {
     if *fingerprint == EMPTY_DIGEST.0 {
@@ -109,7 +115,7 @@ impl ByteStore {
     Ok(())
   }
 
-  fn default_lease_until_secs_since_epoch() -> u64 {
+  pub(crate) fn default_lease_until_secs_since_epoch() -> u64 {
     let now_since_epoch = time::SystemTime::now()
       .duration_since(time::UNIX_EPOCH)
       .expect("Surely you're not before the unix epoch?");
@@ -332,12 +338,12 @@ impl ByteStore {
 }
 
 #[derive(Eq, PartialEq, Ord, PartialOrd)]
-struct AgedFingerprint {
+pub(crate) struct AgedFingerprint {
   // expired_seconds_ago must be the first field for the Ord implementation.
-  expired_seconds_ago: u64,
-  fingerprint: Fingerprint,
-  size_bytes: usize,
-  entry_type: EntryType,
+  pub(crate) expired_seconds_ago: u64,
+  pub(crate) fingerprint: Fingerprint,
+  pub(crate) size_bytes: usize,
+  pub(crate) entry_type: EntryType,
 }
 
 #[cfg(test)]
@@ -0,0 +1,367 @@
+use super::local::{self, AgedFingerprint};
+use super::{EntryType, ShrinkBehavior};
+
+use boxfuture::{BoxFuture, Boxable};
+use bytes::Bytes;
+use digest::{Digest as DigestTrait, FixedOutput};
+use futures::future::{self, Future};
+use hashing::{Digest, Fingerprint, EMPTY_DIGEST};
+use parking_lot::Mutex;
+use sha2::Sha256;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::sync::Arc;
+use std::time;
+
+///
+/// A local store which keeps its blobs in memory, for tests and tools which do not need them to
+/// outlive the process. It behaves like the LMDB-backed local::ByteStore, including for leases and
+/// garbage collection, except that compaction is a no-op.
+///
+#[derive(Clone, Default)]
+pub struct ByteStore {
+  inner: Arc<Mutex<InnerStore>>,
+}
+
+#[derive(Default)]
+struct InnerStore {
+  // As in local::ByteStore, directories are stored separately from files.
+  files: Entries,
+  directories: Entries,
+}
+
+#[derive(Default)]
+struct Entries {
+  // Ordered, so that all_digests lists digests in the same order as LMDB does.
+  bytes: BTreeMap<Fingerprint, Bytes>,
+  // The time (in seconds since the epoch) until which each leased fingerprint is leased.
+  leases: HashMap<Fingerprint, u64>,
+}
+
+impl InnerStore {
+  fn entries(&self, entry_type: EntryType) -> &Entries {
+    match entry_type {
+      EntryType::Directory => &self.directories,
+      EntryType::File => &self.files,
+    }
+  }
+
+  fn entries_mut(&mut self, entry_type: EntryType) -> &mut Entries {
+    match entry_type {
+      EntryType::Directory => &mut self.directories,
+      EntryType::File => &mut self.files,
+    }
+  }
+}
+
+impl ByteStore {
+  pub fn new() -> ByteStore {
+    ByteStore::default()
+  }
+
+  pub fn entry_type(&self, fingerprint: &Fingerprint) -> Result<Option<EntryType>, String> {
+    if *fingerprint == EMPTY_DIGEST.0 {
+      // As in local::ByteStore, the empty digest is a valid Directory.
+      return Ok(Some(EntryType::Directory));
+    }
+    let inner = self.inner.lock();
+    if inner.directories.bytes.contains_key(fingerprint) {
+      Ok(Some(EntryType::Directory))
+    } else if inner.files.bytes.contains_key(fingerprint) {
+      Ok(Some(EntryType::File))
+    } else {
+      Ok(None)
+    }
+  }
+
+  pub fn lease_all<'a, Ds: Iterator<Item = &'a Digest>>(&self, digests: Ds) -> Result<(), String> {
+    let until = local::ByteStore::default_lease_until_secs_since_epoch();
+    let mut inner = self.inner.lock();
+    for digest in digests {
+      inner.files.leases.insert(digest.0, until);
+    }
+    Ok(())
+  }
+
+  ///
+  /// As local::ByteStore::shrink: removes unleased blobs, those whose leases expired longest ago
+  /// first, until the stored blobs are no bigger than target_bytes.
+  ///
+  pub fn shrink(
+    &self,
+    target_bytes: usize,
+    _shrink_behavior: ShrinkBehavior,
+  ) -> Result<usize, String> {
+    let mut inner = self.inner.lock();
+    let now = time::SystemTime::now();
+    let mut used_bytes: usize = 0;
+    let mut fingerprints_by_expired_ago = BinaryHeap::new();
+    for &entry_type in &[EntryType::File, EntryType::Directory] {
+      let entries = inner.entries(entry_type);
+      for (fingerprint, bytes) in &entries.bytes {
+        used_bytes += bytes.len();
+        let lease_until_unix_timestamp = entries.leases.get(fingerprint).cloned().unwrap_or(0);
+        let leased_until = time::UNIX_EPOCH + time::Duration::from_secs(lease_until_unix_timestamp);
+        fingerprints_by_expired_ago.push(AgedFingerprint {
+          expired_seconds_ago: now
+            .duration_since(leased_until)
+            .map(|t| t.as_secs())
+            // 0 indicates unleased.
+            .unwrap_or(0),
+          fingerprint: *fingerprint,
+          size_bytes: bytes.len(),
+          entry_type,
+        });
+      }
+    }
+
+    while used_bytes > target_bytes {
+      let aged_fingerprint = fingerprints_by_expired_ago
+        .pop()
+        .expect("Sum of size of blobs exceeded stored blobs");
+      if aged_fingerprint.expired_seconds_ago == 0 {
+        // Ran out of expired blobs - everything remaining is leased and cannot be collected.
+        return Ok(used_bytes);
+      }
+      let entries = inner.entries_mut(aged_fingerprint.entry_type);
+      entries.bytes.remove(&aged_fingerprint.fingerprint);
+      entries.leases.remove(&aged_fingerprint.fingerprint);
+      used_bytes -= aged_fingerprint.size_bytes;
+    }
+    Ok(used_bytes)
+  }
+
+  pub fn store_bytes(
+    &self,
+    entry_type: EntryType,
+    bytes: Bytes,
+    initial_lease: bool,
+  ) -> impl Future<Item = Digest, Error = String> {
+    let fingerprint = {
+      let mut hasher = Sha256::default();
+      hasher.input(&bytes);
+      Fingerprint::from_bytes_unsafe(hasher.fixed_result().as_slice())
+    };
+    let digest = Digest(fingerprint, bytes.len());
+    let mut inner = self.inner.lock();
+    let entries = inner.entries_mut(entry_type);
+    if initial_lease {
+      entries.leases.insert(
+        fingerprint,
+        local::ByteStore::default_lease_until_secs_since_epoch(),
+      );
+    }
+    entries.bytes.insert(fingerprint, bytes);
+    future::ok(digest)
+  }
+
+  pub fn load_bytes_with<T: Send + 'static, F: Fn(Bytes) -> T + Send + Sync + 'static>(
+    &self,
+    entry_type: EntryType,
+    digest: Digest,
+    f: F,
+  ) -> BoxFuture<Option<T>, String> {
+    if digest == EMPTY_DIGEST {
+      return future::ok(Some(f(Bytes::new()))).to_boxed();
+    }
+
+    let maybe_bytes = self
+      .inner
+      .lock()
+      .entries(entry_type)
+      .bytes
+      .get(&digest.0)
+      .cloned();
+    future::result(match maybe_bytes {
+      Some(ref bytes) if bytes.len() != digest.1 => Err(format!(
+        "Got hash collision reading from store - digest {:?} was requested, but retrieved bytes \
+         with that fingerprint had length {}. Congratulations, you may have broken sha256! \
+         Underlying bytes: {:?}",
+        digest,
+        bytes.len(),
+        bytes
+      )),
+      Some(bytes) => Ok(Some(f(bytes))),
+      None => Ok(None),
+    })
+    .to_boxed()
+  }
+
+  pub fn all_digests(&self, entry_type: EntryType) -> Result<Vec<Digest>, String> {
+    Ok(
+      self
+        .inner
+        .lock()
+        .entries(entry_type)
+        .bytes
+        .iter()
+        .map(|(fingerprint, bytes)| Digest(*fingerprint, bytes.len()))
+        .collect(),
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::local::tests as lmdb_tests;
+  use super::super::tests::block_on;
+  use super::{ByteStore, EntryType, ShrinkBehavior};
+  use bytes::Bytes;
+  use hashing::{Digest, Fingerprint};
+  use tempfile::TempDir;
+  use testutil::data::{TestData, TestDirectory};
+
+  fn load_bytes(
+    store: &ByteStore,
+    entry_type: EntryType,
+    digest: Digest,
+  ) -> Result<Option<Bytes>, String> {
+    block_on(store.load_bytes_with(entry_type, digest, |b| b))
+  }
+
+  #[test]
+  fn roundtrip_file_and_directory() {
+    let store = ByteStore::new();
+    let testdata = TestData::roland();
+    let testdir = TestDirectory::containing_roland();
+
+    assert_eq!(
+      block_on(store.store_bytes(EntryType::File, testdata.bytes(), false)),
+      Ok(testdata.digest())
+    );
+    assert_eq!(
+      block_on(store.store_bytes(EntryType::Directory, testdir.bytes(), false)),
+      Ok(testdir.digest())
+    );
+    assert_eq!(
+      load_bytes(&store, EntryType::File, testdata.digest()),
+      Ok(Some(testdata.bytes()))
+    );
+    assert_eq!(
+      load_bytes(&store, EntryType::Directory, testdir.digest()),
+      Ok(Some(testdir.bytes()))
+    );
+    assert_eq!(
+      store.entry_type(&testdata.fingerprint()),
+      Ok(Some(EntryType::File))
+    );
+    assert_eq!(
+      store.entry_type(&testdir.fingerprint()),
+      Ok(Some(EntryType::Directory))
+    );
+  }
+
+  #[test]
+  fn clones_share_blobs() {
+    let store = ByteStore::new();
+    let testdata = TestData::roland();
+    block_on(
+      store
+        .clone()
+        .store_bytes(EntryType::File, testdata.bytes(), false),
+    )
+    .unwrap();
+    assert_eq!(
+      load_bytes(&store, EntryType::File, testdata.digest()),
+      Ok(Some(testdata.bytes()))
+    );
+  }
+
+  #[test]
+  fn missing_digests_match_lmdb() {
+    let dir = TempDir::new().unwrap();
+    let lmdb = lmdb_tests::new_store(dir.path());
+    let memory = ByteStore::new();
+    let testdata = TestData::roland();
+    let testdir = TestDirectory::containing_roland();
+    // The file is stored, but is not a directory.
+    block_on(lmdb.store_bytes(EntryType::File, testdata.bytes(), false)).unwrap();
+    block_on(memory.store_bytes(EntryType::File, testdata.bytes(), false)).unwrap();
+
+    let digests = vec![
+      testdata.digest(),
+      testdir.digest(),
+      TestData::empty().digest(),
+      TestDirectory::empty().digest(),
+    ];
+    for entry_type in &[EntryType::File, EntryType::Directory] {
+      for digest in &digests {
+        assert_eq!(
+          load_bytes(&memory, *entry_type, *digest),
+          block_on(lmdb.load_bytes_with(*entry_type, *digest, |b| b)),
+          "Differ for {:?} {:?}",
+          entry_type,
+          digest
+        );
+      }
+      assert_eq!(
+        memory.all_digests(*entry_type),
+        lmdb.all_digests(*entry_type)
+      );
+    }
+    for digest in &digests {
+      assert_eq!(memory.entry_type(&digest.0), lmdb.entry_type(&digest.0));
+    }
+  }
+
+  #[test]
+  fn wrong_length_is_a_collision() {
+    let store = ByteStore::new();
+    let testdata = TestData::roland();
+    block_on(store.store_bytes(EntryType::File, testdata.bytes(), false)).unwrap();
+    let error = load_bytes(
+      &store,
+      EntryType::File,
+      Digest(testdata.fingerprint(), testdata.len() + 1),
+    )
+    .expect_err("Want error");
+    assert!(error.contains("hash collision"), "Bad error: {}", error);
+  }
+
+  #[test]
+  fn garbage_collect_keeps_leased_blobs() {
+    let store = ByteStore::new();
+    let testdir = TestDirectory::containing_roland();
+    let fourty_chars = TestData::fourty_chars();
+
+    block_on(store.store_bytes(EntryType::Directory, testdir.bytes(), true))
+      .expect("Error storing");
+    block_on(store.store_bytes(EntryType::File, fourty_chars.bytes(), true))
+      .expect("Error storing");
+    block_on(store.store_bytes(EntryType::File, TestData::roland().bytes(), false))
+      .expect("Error storing");
+
+    assert_eq!(
+      store.shrink(80, ShrinkBehavior::Fast),
+      Ok(testdir.bytes().len() + fourty_chars.len())
+    );
+    assert_eq!(
+      load_bytes(&store, EntryType::File, fourty_chars.digest()),
+      Ok(Some(fourty_chars.bytes()))
+    );
+    assert_eq!(
+      load_bytes(&store, EntryType::Directory, testdir.digest()),
+      Ok(Some(testdir.bytes()))
+    );
+    assert_eq!(
+      load_bytes(&store, EntryType::File, TestData::roland().digest()),
+      Ok(None)
+    );
+  }
+
+  #[test]
+  fn garbage_collect_after_lease_all() {
+    let store = ByteStore::new();
+    let bytes = Bytes::from("0123456789");
+    let digest = Digest(
+      Fingerprint::from_hex_string(
+        "84d89877f0d4041efb6bf91a16f0248f2fd573e6af05c19f96bedb9f882f7882",
+      )
+      .unwrap(),
+      10,
+    );
+    block_on(store.store_bytes(EntryType::File, bytes.clone(), false)).expect("Error storing");
+    store.lease_all(vec![digest].iter()).expect("Error leasing");
+    assert_eq!(store.shrink(0, ShrinkBehavior::Compact), Ok(10));
+    assert_eq!(load_bytes(&store, EntryType::File, digest), Ok(Some(bytes)));
+  }
+}
@@ -1,4 +1,5 @@
 use super::{BackoffConfig, EntryType};
+use crate::proxy::{check_channel_address, connect_channel, ProxyConfig};
 
 use bazel_protos;
 use boxfuture::{BoxFuture, Boxable};
@@ -11,12 +12,17 @@ use hashing::{Digest, Fingerprint};
 use serverset::{Retry, Serverset};
 use sha2::Sha256;
 use std::cmp::min;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use std::time::Duration;
 use uuid;
 use workunit_store::WorkUnitStore;
 
+// The request header which asks a server to store an uploaded blob with a short lease. Servers
+// which do not support differentiated leases ignore it.
+const SHORT_LEASE_HEADER: &str = "x-pants-blob-lease";
+const SHORT_LEASE_HEADER_VALUE: &str = "short";
+
 #[derive(Clone)]
 pub struct ByteStore {
   instance_name: Option<String>,
@@ -26,6 +32,7 @@ pub struct ByteStore {
   env: Arc<grpcio::Environment>,
   serverset: Serverset<grpcio::Channel>,
   authorization_header: Option<String>,
+  proxy: Option<ProxyConfig>,
 }
 
 impl ByteStore {
@@ -34,26 +41,31 @@ impl ByteStore {
     instance_name: Option<String>,
     root_ca_certs: Option<Vec<u8>>,
     oauth_bearer_token: Option<String>,
-    thread_count: usize,
+    proxy: Option<ProxyConfig>,
+    env: Arc<grpcio::Environment>,
     chunk_size_bytes: usize,
     upload_timeout: Duration,
     backoff_config: BackoffConfig,
     rpc_retries: usize,
     connection_limit: usize,
   ) -> Result<ByteStore, String> {
-    let env = Arc::new(grpcio::Environment::new(thread_count));
     let env2 = env.clone();
 
+    // The Serverset connects its channels lazily, when they cannot fail, so their addresses are
+    // checked here.
+    for cas_address in &cas_addresses {
+      check_channel_address(cas_address, root_ca_certs.is_some(), proxy.as_ref())?;
+    }
+    let proxy2 = proxy.clone();
+
     let connect = move |cas_address: &str| {
-      let builder = grpcio::ChannelBuilder::new(env2.clone());
-      if let Some(ref root_ca_certs) = root_ca_certs {
-        let creds = grpcio::ChannelCredentialsBuilder::new()
-          .root_cert(root_ca_certs.clone())
-          .build();
-        builder.secure_connect(cas_address, creds)
-      } else {
-        builder.connect(cas_address)
-      }
+      connect_channel(
+        env2.clone(),
+        cas_address,
+        root_ca_certs.clone(),
+        proxy2.as_ref(),
+      )
+      .expect("The address of the channel was checked when the ByteStore was created")
     };
 
     let serverset = Serverset::new(cas_addresses, connect, connection_limit, backoff_config)?;
@@ -66,6 +78,7 @@ impl ByteStore {
       env,
       serverset,
       authorization_header: oauth_bearer_token.map(|t| format!("Bearer {}", t)),
+      proxy,
     })
   }
 
@@ -78,14 +91,17 @@ impl ByteStore {
     &self,
     f: F,
   ) -> impl Future<Item = Value, Error = String> {
-    Retry(self.serverset.clone()).all_errors_immediately(
-      move |channel| {
-        f(bazel_protos::bytestream_grpc::ByteStreamClient::new(
-          channel,
-        ))
-      },
-      self.rpc_attempts,
-    )
+    let proxy = self.proxy.clone();
+    Retry(self.serverset.clone())
+      .all_errors_immediately(
+        move |channel| {
+          f(bazel_protos::bytestream_grpc::ByteStreamClient::new(
+            channel,
+          ))
+        },
+        self.rpc_attempts,
+      )
+      .map_err(move |error| explain_proxy_error(&proxy, error))
   }
 
   fn with_cas_client<
@@ -101,29 +117,68 @@ impl ByteStore {
     &self,
     f: F,
   ) -> impl Future<Item = Value, Error = String> {
-    Retry(self.serverset.clone()).all_errors_immediately(
-      move |channel| {
-        f(bazel_protos::remote_execution_grpc::ContentAddressableStorageClient::new(channel))
-      },
-      self.rpc_attempts,
-    )
+    let proxy = self.proxy.clone();
+    Retry(self.serverset.clone())
+      .all_errors_immediately(
+        move |channel| {
+          f(bazel_protos::remote_execution_grpc::ContentAddressableStorageClient::new(channel))
+        },
+        self.rpc_attempts,
+      )
+      .map_err(move |error| explain_proxy_error(&proxy, error))
+  }
+
+  ///
+  /// Like with_cas_client, for methods of the ContentAddressableStorage service which its
+  /// generated client does not have (see bazel_protos::batch_read_blobs).
+  ///
+  fn with_grpc_client<
+    Value: Send + 'static,
+    Fut: Future<Item = Value, Error = String>,
+    IntoFut: IntoFuture<Future = Fut, Item = Value, Error = String>,
+    F: Fn(grpcio::Client) -> IntoFut + Send + Sync + Clone + 'static,
+  >(
+    &self,
+    f: F,
+  ) -> impl Future<Item = Value, Error = String> {
+    let proxy = self.proxy.clone();
+    Retry(self.serverset.clone())
+      .all_errors_immediately(
+        move |channel| f(grpcio::Client::new(channel)),
+        self.rpc_attempts,
+      )
+      .map_err(move |error| explain_proxy_error(&proxy, error))
   }
 
   fn call_option(&self) -> grpcio::CallOption {
+    self.call_option_with_headers(&[])
+  }
+
+  fn call_option_with_headers(&self, extra_headers: &[(&str, &str)]) -> grpcio::CallOption {
     let mut call_option = grpcio::CallOption::default();
-    if let Some(ref authorization_header) = self.authorization_header {
-      let mut builder = grpcio::MetadataBuilder::with_capacity(1);
-      builder
-        .add_str("authorization", &authorization_header)
-        .unwrap();
+    if self.authorization_header.is_some() || !extra_headers.is_empty() {
+      let mut builder = grpcio::MetadataBuilder::with_capacity(1 + extra_headers.len());
+      if let Some(ref authorization_header) = self.authorization_header {
+        builder
+          .add_str("authorization", &authorization_header)
+          .unwrap();
+      }
+      for (key, value) in extra_headers {
+        builder.add_str(key, value).unwrap();
+      }
       call_option = call_option.headers(builder.build());
     }
     call_option
   }
 
+  ///
+  /// Uploads the given bytes. If short_lease is true, the server is asked to store them with a
+  /// short lease, because they are not expected to be used again after the current run.
+  ///
   pub fn store_bytes(
     &self,
     bytes: Bytes,
+    short_lease: bool,
     workunit_store: WorkUnitStore,
   ) -> BoxFuture<Digest, String> {
     let start_time = std::time::SystemTime::now();
@@ -142,11 +197,20 @@ impl ByteStore {
     );
     let workunit_name = format!("store_bytes({})", resource_name.clone());
     let workunit_store = workunit_store.clone();
+    let extra_headers = if short_lease {
+      vec![(SHORT_LEASE_HEADER, SHORT_LEASE_HEADER_VALUE)]
+    } else {
+      vec![]
+    };
     let store = self.clone();
     self
       .with_byte_stream_client(move |client| {
         match client
-          .write_opt(store.call_option().timeout(store.upload_timeout))
+          .write_opt(
+            store
+              .call_option_with_headers(&extra_headers)
+              .timeout(store.upload_timeout),
+          )
           .map(|v| (v, client))
         {
           Err(err) => future::err(format!(
@@ -318,6 +382,72 @@ impl ByteStore {
       .to_boxed()
   }
 
+  ///
+  /// Reads the given blobs with a single BatchReadBlobs request, returning those which the server
+  /// returned (blobs which it failed to read individually, e.g. because they are missing, are
+  /// omitted). Callers are responsible for keeping the total size of the blobs within the limit
+  /// which the server advertises in its capabilities.
+  ///
+  pub fn load_bytes_batch(
+    &self,
+    digests: Vec<Digest>,
+    workunit_store: WorkUnitStore,
+  ) -> BoxFuture<HashMap<Digest, Bytes>, String> {
+    let start_time = std::time::SystemTime::now();
+
+    let store = self.clone();
+    let workunit_name = format!(
+      "load_bytes_batch({}, {} blobs)",
+      store.instance_name.clone().unwrap_or_default(),
+      digests.len()
+    );
+    let mut request = bazel_protos::remote_apis_backports::BatchReadBlobsRequest::new();
+    if let Some(ref instance_name) = self.instance_name {
+      request.set_instance_name(instance_name.clone());
+    }
+    for digest in &digests {
+      request.mut_digests().push(digest.into());
+    }
+    self
+      .with_grpc_client(move |client| {
+        client
+          .unary_call(
+            &bazel_protos::batch_read_blobs::BATCH_READ_BLOBS,
+            &request,
+            store.call_option(),
+          )
+          .map_err(|err| {
+            format!(
+              "Error from server in response to batch_read_blobs_request: {:?}",
+              err
+            )
+          })
+          .and_then(|mut response| {
+            let mut blobs = HashMap::new();
+            for mut blob_response in response.take_responses().into_iter() {
+              // Other statuses (e.g. NotFound) are errors reading the individual blob.
+              if blob_response.get_status().get_code() != grpcio::RpcStatusCode::Ok as i32 {
+                continue;
+              }
+              let digest: Result<Digest, String> = blob_response.get_digest().into();
+              blobs.insert(digest?, blob_response.take_data());
+            }
+            Ok(blobs)
+          })
+      })
+      .then(move |future| {
+        let workunit = workunit_store::WorkUnit {
+          name: workunit_name.clone(),
+          time_span: TimeSpan::since(&start_time),
+          span_id: workunit_store::generate_random_64bit_string(),
+          parent_id: workunit_store::get_parent_id(),
+        };
+        workunit_store.add_workunit(workunit);
+        future
+      })
+      .to_boxed()
+  }
+
   ///
   /// Given a collection of Digests (digests),
   /// returns the set of digests from that collection not present in the CAS.
@@ -380,15 +510,24 @@ impl ByteStore {
   }
 }
 
+fn explain_proxy_error(proxy: &Option<ProxyConfig>, error: String) -> String {
+  match *proxy {
+    Some(ref proxy) => proxy.explain_error(error),
+    None => error,
+  }
+}
+
 #[cfg(test)]
 mod tests {
   use super::super::{EntryType, MEGABYTES};
   use super::ByteStore;
+  use crate::proxy::ProxyConfig;
   use bytes::Bytes;
   use hashing::Digest;
   use mock::StubCAS;
   use serverset::BackoffConfig;
   use std::collections::HashSet;
+  use std::sync::Arc;
   use std::time::Duration;
   use testutil::data::{TestData, TestDirectory};
   use workunit_store::WorkUnitStore;
@@ -520,7 +659,7 @@ mod tests {
 
     let store = new_byte_store(&cas);
     assert_eq!(
-      block_on(store.store_bytes(testdata.bytes(), WorkUnitStore::new())),
+      block_on(store.store_bytes(testdata.bytes(), false, WorkUnitStore::new())),
       Ok(testdata.digest())
     );
 
@@ -537,7 +676,8 @@ mod tests {
       None,
       None,
       None,
-      1,
+      None,
+      Arc::new(grpcio::Environment::new(1)),
       10 * 1024,
       Duration::from_secs(5),
       BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10)).unwrap(),
@@ -551,7 +691,7 @@ mod tests {
     let fingerprint = big_file_fingerprint();
 
     assert_eq!(
-      block_on(store.store_bytes(all_the_henries.clone(), WorkUnitStore::new())),
+      block_on(store.store_bytes(all_the_henries.clone(), false, WorkUnitStore::new())),
       Ok(big_file_digest())
     );
 
@@ -579,7 +719,7 @@ mod tests {
 
     let store = new_byte_store(&cas);
     assert_eq!(
-      block_on(store.store_bytes(empty_file.bytes(), WorkUnitStore::new())),
+      block_on(store.store_bytes(empty_file.bytes(), false, WorkUnitStore::new())),
       Ok(empty_file.digest())
     );
 
@@ -595,8 +735,9 @@ mod tests {
     let cas = StubCAS::always_errors();
 
     let store = new_byte_store(&cas);
-    let error = block_on(store.store_bytes(TestData::roland().bytes(), WorkUnitStore::new()))
-      .expect_err("Want error");
+    let error =
+      block_on(store.store_bytes(TestData::roland().bytes(), false, WorkUnitStore::new()))
+        .expect_err("Want error");
     assert!(
       error.contains("Error from server"),
       format!("Bad error message, got: {}", error)
@@ -614,7 +755,8 @@ mod tests {
       None,
       None,
       None,
-      1,
+      None,
+      Arc::new(grpcio::Environment::new(1)),
       10 * 1024 * 1024,
       Duration::from_secs(1),
       BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10)).unwrap(),
@@ -622,8 +764,9 @@ mod tests {
       1,
     )
     .unwrap();
-    let error = block_on(store.store_bytes(TestData::roland().bytes(), WorkUnitStore::new()))
-      .expect_err("Want error");
+    let error =
+      block_on(store.store_bytes(TestData::roland().bytes(), false, WorkUnitStore::new()))
+        .expect_err("Want error");
     assert!(
       error.contains("Error attempting to upload digest"),
       format!("Bad error message, got: {}", error)
@@ -694,7 +837,8 @@ mod tests {
       None,
       None,
       None,
-      1,
+      None,
+      Arc::new(grpcio::Environment::new(1)),
       10 * 1024 * 1024,
       Duration::from_secs(1),
       BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10)).unwrap(),
@@ -717,13 +861,51 @@ mod tests {
     assert_eq!(cas2.read_request_count(), 1);
   }
 
+  #[test]
+  fn unreachable_proxy_is_named_in_errors() {
+    let cas = new_cas(10);
+    // Nothing listens on the port once the listener has been dropped.
+    let proxy_port = std::net::TcpListener::bind("127.0.0.1:0")
+      .unwrap()
+      .local_addr()
+      .unwrap()
+      .port();
+    let proxy = ProxyConfig::parse(&format!("http://127.0.0.1:{}", proxy_port)).unwrap();
+    let store = ByteStore::new(
+      vec![cas.address()],
+      None,
+      None,
+      None,
+      Some(proxy),
+      Arc::new(grpcio::Environment::new(1)),
+      10 * MEGABYTES,
+      Duration::from_secs(1),
+      BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10)).unwrap(),
+      1,
+      1,
+    )
+    .unwrap();
+
+    let error = load_file_bytes(&store, TestData::roland().digest()).unwrap_err();
+    assert!(
+      error.contains(&format!(
+        "connecting via proxy http://127.0.0.1:{}",
+        proxy_port
+      )),
+      "Bad error: {}",
+      error
+    );
+    assert_eq!(cas.read_request_count(), 0);
+  }
+
   fn new_byte_store(cas: &StubCAS) -> ByteStore {
     ByteStore::new(
       vec![cas.address()],
       None,
       None,
       None,
-      1,
+      None,
+      Arc::new(grpcio::Environment::new(1)),
       10 * MEGABYTES,
       Duration::from_secs(1),
       BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10)).unwrap(),
@@ -18,6 +18,30 @@ use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use workunit_store::WorkUnitStore;
 
+///
+/// Why Directories could not be merged (see `Snapshot::merge_directories_checked`).
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum MergeError {
+  // Different files with the given name were present at the same path.
+  DuplicateFile(String),
+  // Any other failure, e.g. a Directory which was missing from the Store.
+  Other(String),
+}
+
+impl fmt::Display for MergeError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      MergeError::DuplicateFile(file_name) => write!(
+        f,
+        "Can only merge Directories with no duplicates, but found duplicate files: {}",
+        file_name
+      ),
+      MergeError::Other(message) => write!(f, "{}", message),
+    }
+  }
+}
+
 #[derive(Eq, Hash, PartialEq)]
 pub struct Snapshot {
   pub digest: Digest,
@@ -273,6 +297,20 @@ impl Snapshot {
     dir_digests: Vec<Digest>,
     workunit_store: WorkUnitStore,
   ) -> BoxFuture<Digest, String> {
+    Self::merge_directories_checked(store, dir_digests, workunit_store)
+      .map_err(|error| error.to_string())
+      .to_boxed()
+  }
+
+  ///
+  /// As merge_directories, but distinguishes Directories which conflict with one another from
+  /// other failures.
+  ///
+  pub fn merge_directories_checked(
+    store: Store,
+    dir_digests: Vec<Digest>,
+    workunit_store: WorkUnitStore,
+  ) -> BoxFuture<Digest, MergeError> {
     if dir_digests.is_empty() {
       return future::ok(EMPTY_DIGEST).to_boxed();
     } else if dir_digests.len() == 1 {
@@ -290,6 +328,7 @@ impl Snapshot {
               .map(|(dir, _metadata)| dir)
               .ok_or_else(|| format!("Digest {:?} did not exist in the Store.", digest))
           })
+          .map_err(MergeError::Other)
       })
       .collect::<Vec<_>>();
     join_all(directories)
@@ -320,11 +359,7 @@ impl Snapshot {
             .group_by(|f| f.get_name().to_owned());
           for (file_name, group) in &groups {
             if group.count() > 1 {
-              return future::err(format!(
-                "Can only merge Directories with no duplicates, but found duplicate files: {}",
-                file_name
-              ))
-              .to_boxed();
+              return future::err(MergeError::DuplicateFile(file_name)).to_boxed();
             }
           }
         }
@@ -352,10 +387,11 @@ impl Snapshot {
               let workunit_store2 = workunit_store2.clone();
               let digests_result = group
                 .map(|d| d.get_digest().into())
-                .collect::<Result<Vec<_>, String>>();
+                .collect::<Result<Vec<_>, String>>()
+                .map_err(MergeError::Other);
               future::done(digests_result)
                 .and_then(move |digests| {
-                  Self::merge_directories(store2.clone(), digests, workunit_store2.clone())
+                  Self::merge_directories_checked(store2.clone(), digests, workunit_store2.clone())
                 })
                 .map(move |merged_digest| {
                   let mut child_dir = bazel_protos::remote_execution::DirectoryNode::new();
@@ -368,7 +404,9 @@ impl Snapshot {
         )
         .and_then(move |child_directories| {
           out_dir.set_directories(protobuf::RepeatedField::from_vec(child_directories));
-          store.record_directory(&out_dir, true)
+          store
+            .record_directory(&out_dir, true)
+            .map_err(MergeError::Other)
         })
         .to_boxed()
       })
@@ -634,7 +672,7 @@ mod tests {
   use testutil::data::TestDirectory;
   use testutil::make_file;
 
-  use super::{OneOffStoreFileByDigest, Snapshot};
+  use super::{MergeError, OneOffStoreFileByDigest, Snapshot};
   use crate::Store;
   use fs::{
     Dir, File, GlobExpansionConjunction, GlobMatching, PathGlobs, PathStat, PosixFS,
@@ -845,7 +883,7 @@ mod tests {
 
     let err = runtime
       .block_on(Snapshot::merge_directories(
-        store,
+        store.clone(),
         vec![containing_roland.digest(), containing_wrong_roland.digest()],
         WorkUnitStore::new(),
       ))
@@ -856,6 +894,15 @@ mod tests {
       "Want error message to contain roland but was: {}",
       err
     );
+
+    assert_eq!(
+      runtime.block_on(Snapshot::merge_directories_checked(
+        store,
+        vec![containing_roland.digest(), containing_wrong_roland.digest()],
+        WorkUnitStore::new(),
+      )),
+      Err(MergeError::DuplicateFile("roland".to_owned()))
+    );
   }
 
   #[test]
@@ -4,8 +4,9 @@ use std::time::{Duration, Instant};
 
 use crate::node::{EntryId, Node, NodeContext, NodeError};
 
-use futures::future::{self, Future};
+use futures::future::{self, Either, Future};
 use futures::sync::oneshot;
+use futures::{Async, Poll};
 use log::{self, trace};
 use parking_lot::Mutex;
 
@@ -124,6 +125,8 @@ pub(crate) enum EntryState<N: Node> {
     waiters: Vec<oneshot::Sender<Result<(N::Item, Generation), N::Error>>>,
     previous_result: Option<EntryResult<N>>,
     dirty: bool,
+    // For cancellable Nodes, sending on this drops the run. See `Waiter`.
+    abort: Option<oneshot::Sender<()>>,
   },
   // A node that has completed, and then possibly been marked dirty. Because marking a node
   // dirty does not eagerly re-execute any logic, it will stay this way until a caller moves it
@@ -166,6 +169,84 @@ impl<N: Node> EntryKey<N> {
   }
 }
 
+///
+/// A request for the value of a running cancellable Node. When the last Waiter for a run goes away
+/// before the Node has completed, the run is aborted and the Node is moved back to NotStarted, so
+/// that it runs again if it is requested later.
+///
+struct Waiter<N: Node> {
+  receiver: oneshot::Receiver<Result<(N::Item, Generation), N::Error>>,
+  state: Arc<Mutex<EntryState<N>>>,
+  run_token: RunToken,
+  node: EntryKey<N>,
+}
+
+impl<N: Node> Future for Waiter<N> {
+  type Item = Result<(N::Item, Generation), N::Error>;
+  type Error = oneshot::Canceled;
+
+  fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+    self.receiver.poll()
+  }
+}
+
+impl<N: Node> Drop for Waiter<N> {
+  fn drop(&mut self) {
+    // Closing the receiver marks our own sender canceled, so that it is not counted as interested.
+    self.receiver.close();
+    if let Ok(Async::Ready(_)) = self.receiver.poll() {
+      // The value has already been sent: the Node completed.
+      return;
+    }
+
+    let mut state = self.state.lock();
+    let should_abort = match *state {
+      EntryState::Running {
+        run_token,
+        ref waiters,
+        ref abort,
+        ..
+      } => {
+        run_token == self.run_token
+          && abort.is_some()
+          && waiters.iter().all(oneshot::Sender::is_canceled)
+      }
+      _ => false,
+    };
+    if !should_abort {
+      return;
+    }
+
+    *state = match mem::replace(&mut *state, EntryState::initial()) {
+      EntryState::Running {
+        run_token,
+        generation,
+        mut previous_result,
+        abort,
+        ..
+      } => {
+        trace!(
+          "Aborting {:?} because it has no remaining waiters.",
+          self.node
+        );
+        if let Some(abort) = abort {
+          let _ = abort.send(());
+        }
+        if let Some(previous_result) = previous_result.as_mut() {
+          previous_result.dirty();
+        }
+        // As in `clear`, a new RunToken causes the aborted run to be discarded if it completes.
+        EntryState::NotStarted {
+          run_token: run_token.next(),
+          generation,
+          previous_result,
+        }
+      }
+      _ => unreachable!("The state was checked to be Running."),
+    };
+  }
+}
+
 ///
 /// An Entry and its adjacencies.
 ///
@@ -232,8 +313,14 @@ impl<N: Node> Entry<N> {
       &EntryKey::Valid(ref n) => {
         let context = context_factory.clone_for(entry_id);
         let node = n.clone();
+        let (abort, abort_receiver) = if n.cancellable() {
+          let (abort, abort_receiver) = oneshot::channel();
+          (Some(abort), Some(abort_receiver))
+        } else {
+          (None, None)
+        };
 
-        context_factory.spawn(future::lazy(move || {
+        let work = future::lazy(move || {
           // If we have previous result generations, compare them to all current dependency
           // generations (which, if they are dirty, will cause recursive cleaning). If they
           // match, we can consider the previous result value to be clean for reuse.
@@ -282,7 +369,26 @@ impl<N: Node> Entry<N> {
                 .to_boxed()
             }
           })
-        }));
+        });
+
+        if let Some(abort_receiver) = abort_receiver {
+          let node = n.clone();
+          context_factory.spawn(work.select2(abort_receiver).then(move |res| match res {
+            Ok(Either::B(_)) => {
+              // Every waiter went away, and the Entry has already moved on: drop the work.
+              trace!("Cancelled {:?} because it had no remaining waiters.", node);
+              future::ok(()).to_boxed()
+            }
+            Err(Either::B((_, work))) => {
+              // The Entry moved on without aborting (because it completed or was cleared): the
+              // work no longer has any effect, but was not cancelled.
+              work.to_boxed()
+            }
+            Ok(Either::A(_)) | Err(Either::A(_)) => future::ok(()).to_boxed(),
+          }));
+        } else {
+          context_factory.spawn(work);
+        }
 
         EntryState::Running {
           waiters: Vec::new(),
@@ -291,6 +397,7 @@ impl<N: Node> Entry<N> {
           generation,
           previous_result,
           dirty: false,
+          abort,
         }
       }
       &EntryKey::Cyclic(_) => EntryState::Completed {
@@ -325,11 +432,25 @@ impl<N: Node> Entry<N> {
       // cases we don't swap the state of the Node.
       match &mut *state {
         &mut EntryState::Running {
-          ref mut waiters, ..
+          ref mut waiters,
+          ref abort,
+          run_token,
+          ..
         } => {
           let (send, recv) = oneshot::channel();
           waiters.push(send);
           trace!("Adding waiter on {:?}", self.node);
+          if abort.is_some() {
+            return Waiter {
+              receiver: recv,
+              state: self.state.clone(),
+              run_token,
+              node: self.node.clone(),
+            }
+            .map_err(|_| N::Error::invalidated())
+            .flatten()
+            .to_boxed();
+          }
           return recv
             .map_err(|_| N::Error::invalidated())
             .flatten()
@@ -1011,7 +1011,8 @@ mod tests {
   use std::time::Duration;
 
   use boxfuture::{BoxFuture, Boxable};
-  use futures::future::{self, Future};
+  use futures::future::{self, Future, Shared};
+  use futures::sync::oneshot;
   use hashing::Digest;
   use parking_lot::Mutex;
 
@@ -1241,6 +1242,54 @@ mod tests {
     );
   }
 
+  #[test]
+  fn cancel_when_no_waiters_remain() {
+    let graph = Arc::new(Graph::new());
+    let context = TContext::new_with_stalls(0, vec![TNode(0)].into_iter().collect(), graph.clone());
+
+    let root = graph.create(TNode(2), &context);
+    assert!(eventually(|| context.runs().contains(&TNode(0))));
+    assert_eq!(context.aborted(), vec![]);
+
+    // Dropping the only request for TNode(2) aborts it, and then transitively its dependencies.
+    drop(root);
+    assert!(eventually(|| context.aborted() == vec![TNode(0)]));
+
+    // The aborted Nodes run again when they are next requested.
+    let _root = graph.create(TNode(2), &context);
+    assert!(eventually(|| context
+      .runs()
+      .iter()
+      .filter(|&node| *node == TNode(0))
+      .count()
+      == 2));
+  }
+
+  #[test]
+  fn no_cancel_while_a_waiter_remains() {
+    let graph = Arc::new(Graph::new());
+    let context = TContext::new_with_stalls(0, vec![TNode(0)].into_iter().collect(), graph.clone());
+
+    let first = graph.create(TNode(2), &context);
+    assert!(eventually(|| context.runs().contains(&TNode(0))));
+    let second = graph.create(TNode(2), &context);
+
+    // The second request is still waiting, so nothing is aborted, and it completes once the stall
+    // is released.
+    drop(first);
+    context.release_stalls();
+    assert_eq!(second.wait(), Ok(vec![T(0, 0), T(1, 0), T(2, 0)]));
+    assert_eq!(context.aborted(), vec![]);
+    assert_eq!(
+      context
+        .runs()
+        .iter()
+        .filter(|&node| *node == TNode(0))
+        .count(),
+      1
+    );
+  }
+
   #[test]
   fn cyclic_failure() {
     // Confirms that an attempt to create a cycle fails.
@@ -1412,6 +1461,23 @@ mod tests {
     fn run(self, context: TContext) -> BoxFuture<Vec<T>, TError> {
       context.ran(self.clone());
       let token = T(self.0, context.id());
+      if context.stalls.contains(&self) {
+        // Completes once the stalls are released: records that it was aborted if it is dropped
+        // before then.
+        let mut guard = StallGuard {
+          node: self,
+          aborted: context.aborted.clone(),
+          completed: false,
+        };
+        return context
+          .release
+          .clone()
+          .then(move |_| {
+            guard.completed = true;
+            Ok::<_, TError>(vec![token])
+          })
+          .to_boxed();
+      }
       if let Some(dep) = context.dependency_of(&self) {
         context.maybe_delay(&self);
         context
@@ -1433,6 +1499,27 @@ mod tests {
     fn cacheable(&self) -> bool {
       true
     }
+
+    fn cancellable(&self) -> bool {
+      true
+    }
+  }
+
+  ///
+  /// Held by the run of a stalled TNode, and records the TNode if the run is dropped before it
+  /// completes.
+  ///
+  struct StallGuard {
+    node: TNode,
+    aborted: Arc<Mutex<Vec<TNode>>>,
+    completed: bool,
+  }
+  impl Drop for StallGuard {
+    fn drop(&mut self) {
+      if !self.completed {
+        self.aborted.lock().push(self.node.clone());
+      }
+    }
   }
 
   impl std::fmt::Display for TNode {
@@ -1502,6 +1589,12 @@ mod tests {
     delays: HashMap<TNode, Duration>,
     graph: Arc<Graph<TNode>>,
     runs: Arc<Mutex<Vec<TNode>>>,
+    // TNodes which do not complete when run until `release_stalls` is called, and the record of
+    // those runs which were aborted.
+    stalls: Arc<HashSet<TNode>>,
+    release: Shared<oneshot::Receiver<()>>,
+    releaser: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+    aborted: Arc<Mutex<Vec<TNode>>>,
     entry_id: Option<EntryId>,
   }
   impl NodeContext for TContext {
@@ -1513,6 +1606,10 @@ mod tests {
         delays: self.delays.clone(),
         graph: self.graph.clone(),
         runs: self.runs.clone(),
+        stalls: self.stalls.clone(),
+        release: self.release.clone(),
+        releaser: self.releaser.clone(),
+        aborted: self.aborted.clone(),
         entry_id: Some(entry_id),
       }
     }
@@ -1534,12 +1631,17 @@ mod tests {
 
   impl TContext {
     fn new(id: usize, graph: Arc<Graph<TNode>>) -> TContext {
+      let (releaser, release) = oneshot::channel();
       TContext {
         id,
         edges: Arc::default(),
         delays: HashMap::default(),
         graph,
         runs: Arc::new(Mutex::new(Vec::new())),
+        stalls: Arc::default(),
+        release: release.shared(),
+        releaser: Arc::new(Mutex::new(Some(releaser))),
+        aborted: Arc::new(Mutex::new(Vec::new())),
         entry_id: None,
       }
     }
@@ -1550,12 +1652,8 @@ mod tests {
       graph: Arc<Graph<TNode>>,
     ) -> TContext {
       TContext {
-        id,
         edges: Arc::new(edges),
-        delays: HashMap::default(),
-        graph,
-        runs: Arc::new(Mutex::new(Vec::new())),
-        entry_id: None,
+        ..TContext::new(id, graph)
       }
     }
 
@@ -1565,12 +1663,15 @@ mod tests {
       graph: Arc<Graph<TNode>>,
     ) -> TContext {
       TContext {
-        id,
-        edges: Arc::default(),
         delays,
-        graph,
-        runs: Arc::new(Mutex::new(Vec::new())),
-        entry_id: None,
+        ..TContext::new(id, graph)
+      }
+    }
+
+    fn new_with_stalls(id: usize, stalls: HashSet<TNode>, graph: Arc<Graph<TNode>>) -> TContext {
+      TContext {
+        stalls: Arc::new(stalls),
+        ..TContext::new(id, graph)
       }
     }
 
@@ -1608,6 +1709,32 @@ mod tests {
     fn runs(&self) -> Vec<TNode> {
       self.runs.lock().clone()
     }
+
+    fn aborted(&self) -> Vec<TNode> {
+      self.aborted.lock().clone()
+    }
+
+    ///
+    /// Completes the runs of the stalled TNodes.
+    ///
+    fn release_stalls(&self) {
+      if let Some(releaser) = self.releaser.lock().take() {
+        let _ = releaser.send(());
+      }
+    }
+  }
+
+  ///
+  /// Polls the condition until it holds, or until a generous timeout has elapsed.
+  ///
+  fn eventually(condition: impl Fn() -> bool) -> bool {
+    for _ in 0..1000 {
+      if condition() {
+        return true;
+      }
+      thread::sleep(Duration::from_millis(10));
+    }
+    false
   }
 
   #[derive(Clone, Debug, Eq, PartialEq)]
@@ -38,6 +38,14 @@ pub trait Node: Clone + Debug + Display + Eq + Hash + Send + 'static {
   /// If the node result is cacheable, return true.
   ///
   fn cacheable(&self) -> bool;
+
+  ///
+  /// If true, a run of the Node is dropped when every request for its value is dropped while it is
+  /// still running, and the Node runs again the next time that it is requested.
+  ///
+  fn cancellable(&self) -> bool {
+    false
+  }
 }
 
 pub trait NodeError: Clone + Debug + Eq + Send {
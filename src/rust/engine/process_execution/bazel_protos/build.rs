@@ -12,10 +12,13 @@ fn main() {
   let build_root = BuildRoot::find().unwrap();
   let thirdpartyprotobuf = build_root.join("3rdparty/protobuf");
   mark_dir_as_rerun_trigger(&thirdpartyprotobuf);
+  // Protos which are defined by pants itself.
+  let localprotobuf = PathBuf::from("protos");
+  mark_dir_as_rerun_trigger(&localprotobuf);
 
   let grpcio_output_dir = PathBuf::from("src/gen");
   replace_if_changed(&grpcio_output_dir, |path| {
-    generate_for_grpcio(&thirdpartyprotobuf, path);
+    generate_for_grpcio(&thirdpartyprotobuf, &localprotobuf, path);
     format(path);
   });
 
@@ -31,9 +34,13 @@ fn main() {
   mark_dir_as_rerun_trigger(&tower_output_dir);
 }
 
-fn generate_for_grpcio(thirdpartyprotobuf: &Path, gen_dir: &Path) {
-  let amended_proto_root =
-    add_rustproto_header(&thirdpartyprotobuf).expect("Error adding proto bytes header");
+fn generate_for_grpcio(thirdpartyprotobuf: &Path, localprotobuf: &Path, gen_dir: &Path) {
+  let amended_proto_root = add_rustproto_header(&[
+    thirdpartyprotobuf.join("bazelbuild_remote-apis"),
+    thirdpartyprotobuf.join("googleapis"),
+    localprotobuf.to_owned(),
+  ])
+  .expect("Error adding proto bytes header");
 
   protoc_grpcio::compile_grpc_protos(
     &[
@@ -44,6 +51,8 @@ fn generate_for_grpcio(thirdpartyprotobuf: &Path, gen_dir: &Path) {
       "google/rpc/status.proto",
       "google/longrunning/operations.proto",
       "google/protobuf/empty.proto",
+      "pants/process_runner/v1/process_runner.proto",
+      "pants/remote_apis_backports/v1/remote_apis_backports.proto",
     ],
     &[
       amended_proto_root.path().to_owned(),
@@ -70,13 +79,12 @@ option (rustproto.carllerche_bytes_for_bytes_all) = true;
 "#;
 
 ///
-/// Copies protos from thirdpartyprotobuf, adds a header to make protoc_grpcio uses Bytes instead
+/// Copies protos from the given roots, adds a header to make protoc_grpcio uses Bytes instead
 /// of Vec<u8>s, and rewrites them into a temporary directory
 ///
-fn add_rustproto_header(thirdpartyprotobuf: &Path) -> Result<tempfile::TempDir, String> {
+fn add_rustproto_header(src_roots: &[PathBuf]) -> Result<tempfile::TempDir, String> {
   let amended_proto_root = tempfile::TempDir::new().unwrap();
-  for f in &["bazelbuild_remote-apis", "googleapis"] {
-    let src_root = thirdpartyprotobuf.join(f);
+  for src_root in src_roots {
     for entry in walkdir::WalkDir::new(&src_root)
       .into_iter()
       .filter_map(|entry| entry.ok())
@@ -85,7 +93,7 @@ fn add_rustproto_header(thirdpartyprotobuf: &Path) -> Result<tempfile::TempDir,
     {
       let dst = amended_proto_root
         .path()
-        .join(entry.path().strip_prefix(&src_root).unwrap());
+        .join(entry.path().strip_prefix(src_root).unwrap());
       std::fs::create_dir_all(dst.parent().unwrap())
         .map_err(|err| format!("Error making dir in temp proto root: {}", err))?;
       let original = std::fs::read_to_string(entry.path())
@@ -153,7 +161,15 @@ fn generate_mod_rs(dir: &Path) -> Result<(), String> {
     .filter_map(|d| d.ok())
     .map(|d| d.file_name().to_string_lossy().into_owned())
     .filter(|name| &name != &"mod.rs" && &name != &".gitignore")
-    .map(|name| format!("pub mod {};", name.trim_end_matches(".rs")))
+    .map(|name| {
+      let name = name.trim_end_matches(".rs");
+      if name.ends_with("_grpc") {
+        // Clients and services need grpcio, which is only a dependency with this feature.
+        format!("#[cfg(feature = \"remote-execution\")]\npub mod {};", name)
+      } else {
+        format!("pub mod {};", name)
+      }
+    })
     .collect::<Vec<_>>();
   pub_mod_stmts.sort();
   let contents = format!(
@@ -0,0 +1,74 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! The BatchReadBlobs method of the ContentAddressableStorage service, and the
+//! max_batch_total_size_bytes field of CacheCapabilities, are newer than the dump of the REAPI in
+//! 3rdparty/protobuf/bazelbuild_remote-apis. Until the dump is updated, the messages of the method
+//! are declared in pants/remote_apis_backports/v1/remote_apis_backports.proto, and the method
+//! itself and the field here, with the names and numbers that they have upstream.
+//!
+
+use crate::remote_apis_backports::{BatchReadBlobsRequest, BatchReadBlobsResponse};
+use crate::remote_execution::CacheCapabilities;
+
+#[cfg(feature = "remote-execution")]
+pub const BATCH_READ_BLOBS: grpcio::Method<BatchReadBlobsRequest, BatchReadBlobsResponse> =
+  grpcio::Method {
+    ty: grpcio::MethodType::Unary,
+    name: "/build.bazel.remote.execution.v2.ContentAddressableStorage/BatchReadBlobs",
+    req_mar: grpcio::Marshaller {
+      ser: grpcio::pb_ser,
+      de: grpcio::pb_de,
+    },
+    resp_mar: grpcio::Marshaller {
+      ser: grpcio::pb_ser,
+      de: grpcio::pb_de,
+    },
+  };
+
+// The field of CacheCapabilities which holds max_batch_total_size_bytes (an int64).
+const MAX_BATCH_TOTAL_SIZE_BYTES_FIELD_NUMBER: u32 = 4;
+
+///
+/// The largest total size of the blobs of a batched request which the server accepts, or 0 if it
+/// does not report a limit.
+///
+pub fn max_batch_total_size_bytes(capabilities: &CacheCapabilities) -> i64 {
+  capabilities
+    .get_unknown_fields()
+    .get(MAX_BATCH_TOTAL_SIZE_BYTES_FIELD_NUMBER)
+    // As for any scalar field, the last value wins.
+    .and_then(|values| values.varint.last())
+    .map(|&value| value as i64)
+    .unwrap_or(0)
+}
+
+pub fn set_max_batch_total_size_bytes(capabilities: &mut CacheCapabilities, bytes: i64) {
+  let unknown_fields = capabilities.mut_unknown_fields();
+  if let Some(ref mut fields) = unknown_fields.fields {
+    fields.remove(&MAX_BATCH_TOTAL_SIZE_BYTES_FIELD_NUMBER);
+  }
+  unknown_fields.add_varint(MAX_BATCH_TOTAL_SIZE_BYTES_FIELD_NUMBER, bytes as u64);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{max_batch_total_size_bytes, set_max_batch_total_size_bytes};
+  use crate::remote_execution::CacheCapabilities;
+  use protobuf::{self, Message};
+
+  #[test]
+  fn max_batch_total_size_bytes_round_trips() {
+    let mut capabilities = CacheCapabilities::new();
+    assert_eq!(max_batch_total_size_bytes(&capabilities), 0);
+
+    set_max_batch_total_size_bytes(&mut capabilities, 1024);
+    set_max_batch_total_size_bytes(&mut capabilities, 4096);
+    assert_eq!(max_batch_total_size_bytes(&capabilities), 4096);
+
+    let parsed: CacheCapabilities =
+      protobuf::parse_from_bytes(&capabilities.write_to_bytes().unwrap()).unwrap();
+    assert_eq!(max_batch_total_size_bytes(&parsed), 4096);
+  }
+}
@@ -12,6 +12,8 @@ pub use crate::gen::*;
 mod gen_for_tower;
 pub use crate::gen_for_tower::*;
 
+pub mod batch_read_blobs;
 mod conversions;
+pub mod pipelining;
 mod verification;
 pub use crate::verification::verify_directory_canonical;
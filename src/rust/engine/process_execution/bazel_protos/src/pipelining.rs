@@ -0,0 +1,96 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! Pipelined execution is a non-standard extension of the REAPI, which some execution frontends
+//! support to avoid opening a stream per Execute call: a single duplex stream on
+//! PIPELINED_EXECUTE carries any number of ExecuteRequests from the client, and the Operations
+//! for all of them from the server.
+//!
+//! Each ExecuteRequest is tagged with a client-generated id, as a string in a field which the
+//! REAPI does not use, and the server tags each Operation that it sends with the id of the request
+//! that it is for. A server which does not implement the method fails the stream with
+//! Unimplemented; one which sends an untagged Operation does not understand the tagging.
+//!
+
+use crate::operations::Operation;
+use crate::remote_execution::ExecuteRequest;
+use protobuf::Message;
+
+#[cfg(feature = "remote-execution")]
+pub const PIPELINED_EXECUTE: grpcio::Method<ExecuteRequest, Operation> = grpcio::Method {
+  ty: grpcio::MethodType::Duplex,
+  name: "/pants.remote_execution.PipelinedExecution/Execute",
+  req_mar: grpcio::Marshaller {
+    ser: grpcio::pb_ser,
+    de: grpcio::pb_de,
+  },
+  resp_mar: grpcio::Marshaller {
+    ser: grpcio::pb_ser,
+    de: grpcio::pb_de,
+  },
+};
+
+// The field of ExecuteRequests and Operations in which the tag is sent. Scheduling hints use 1000.
+pub const PIPELINE_TAG_FIELD_NUMBER: u32 = 1001;
+
+pub fn set_tag<M: Message>(message: &mut M, tag: &str) {
+  message
+    .mut_unknown_fields()
+    .add_length_delimited(PIPELINE_TAG_FIELD_NUMBER, tag.as_bytes().to_vec());
+}
+
+pub fn tag<M: Message>(message: &M) -> Option<String> {
+  message
+    .get_unknown_fields()
+    .get(PIPELINE_TAG_FIELD_NUMBER)
+    .and_then(|values| values.length_delimited.first())
+    .and_then(|bytes| String::from_utf8(bytes.clone()).ok())
+}
+
+///
+/// Returns the message without its tag, so that it compares equal to the untagged original.
+///
+pub fn without_tag<M: Message + Clone>(message: &M) -> M {
+  let mut message = message.clone();
+  let unknown_fields = message.mut_unknown_fields();
+  let now_empty = match unknown_fields.fields {
+    Some(ref mut fields) => {
+      fields.remove(&PIPELINE_TAG_FIELD_NUMBER);
+      fields.is_empty()
+    }
+    None => false,
+  };
+  if now_empty {
+    unknown_fields.fields = None;
+  }
+  message
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{set_tag, tag, without_tag};
+  use crate::remote_execution::ExecuteRequest;
+
+  #[test]
+  fn tags_round_trip() {
+    let mut request = ExecuteRequest::new();
+    request.set_instance_name("main".to_owned());
+    assert_eq!(tag(&request), None);
+
+    let mut tagged = request.clone();
+    set_tag(&mut tagged, "17");
+    assert_eq!(tag(&tagged), Some("17".to_owned()));
+    assert_ne!(tagged, request);
+    assert_eq!(without_tag(&tagged), request);
+  }
+
+  #[test]
+  fn tags_survive_serialization() {
+    let mut request = ExecuteRequest::new();
+    set_tag(&mut request, "17");
+    let bytes = protobuf::Message::write_to_bytes(&request).unwrap();
+    let parsed: ExecuteRequest = protobuf::parse_from_bytes(&bytes).unwrap();
+    assert_eq!(tag(&parsed), Some("17".to_owned()));
+  }
+}
@@ -0,0 +1,468 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! The REAPI Action and Command of requests. These identify requests (and so key caches) however
+//! the requests are run, so unlike the remote module, this does not depend on gRPC and is built
+//! whether or not the `remote-execution` feature is enabled.
+//!
+
+use std::cmp::min;
+use std::collections::BTreeSet;
+use std::io;
+use std::path::PathBuf;
+
+use bazel_protos;
+use digest::{Digest as DigestTrait, FixedOutput};
+use hashing::{Digest, Fingerprint};
+use log::warn;
+use protobuf::{self, Message};
+use sha2::Sha256;
+
+use crate::{
+  scheduling_hints, ExecuteProcessRequest, ExecuteProcessRequestMetadata, ExecutionEnvironment,
+};
+
+// Environment variable which is exclusively used for cache key invalidation.
+// This may be not specified in an ExecuteProcessRequest, and may be populated only by the
+// CommandRunner.
+pub(crate) const CACHE_KEY_GEN_VERSION_ENV_VAR_NAME: &str = "PANTS_CACHE_KEY_GEN_VERSION";
+
+// The prefix of the env vars which carry the epochs of the cache scopes of a request.
+const CACHE_SCOPE_ENV_VAR_PREFIX: &str = "PANTS_CACHE_SCOPE_";
+
+// The platform property in which the container image of an ExecutionEnvironment is sent.
+pub const CONTAINER_IMAGE_PROPERTY: &str = "container-image";
+
+// The canonical form version from which output paths are normalized.
+const NORMALIZED_OUTPUT_PATHS_CANONICAL_FORM_VERSION: u32 = 1;
+
+// The canonical form version from which an empty cache key gen version or cache scope epoch is
+// treated as absent (see `CANONICAL_FORM_COMPATIBILITY_NOTE`).
+const EMPTY_AS_ABSENT_CANONICAL_FORM_VERSION: u32 = 2;
+
+// Well under the ARG_MAX of common Linux and macOS workers, which also covers the environment.
+pub const DEFAULT_ARGV_WARNING_BYTES: usize = 1024 * 1024;
+
+// The number of characters of the longest argument which are included in argv summaries.
+const ARGV_SUMMARY_ARG_CHARS: usize = 100;
+
+// The number of bytes around an invalid byte of a request string which are included in errors.
+const HEX_PREVIEW_BYTES: usize = 16;
+
+///
+/// The Action and Command of a request, and the ExecuteRequest which runs them. The digest of the
+/// Action identifies the request (see `ExecuteProcessRequest::fingerprint`), whether or not it is
+/// executed remotely.
+///
+pub fn make_execute_request(
+  req: &ExecuteProcessRequest,
+  metadata: ExecuteProcessRequestMetadata,
+) -> Result<
+  (
+    bazel_protos::remote_execution::Action,
+    bazel_protos::remote_execution::Command,
+    bazel_protos::remote_execution::ExecuteRequest,
+  ),
+  String,
+> {
+  let ExecuteProcessRequestMetadata {
+    mut instance_name,
+    cache_key_gen_version,
+    cache_scopes,
+    mut platform_properties,
+    argv_warning_bytes,
+    allow_lossy_env,
+    canonical_form_version,
+    mut environments,
+    ..
+  } = metadata;
+
+  let mut priority = None;
+  if let Some(ref name) = req.environment {
+    let ExecutionEnvironment {
+      platform_properties: mut environment_properties,
+      container_image,
+      instance_name: environment_instance_name,
+      priority: environment_priority,
+    } = match environments.remove(name) {
+      Some(environment) => environment,
+      None => {
+        return Err(format!(
+          "Unknown execution environment {:?} for {}. Known environments: {}",
+          name,
+          req.description,
+          if environments.is_empty() {
+            "(none)".to_owned()
+          } else {
+            environments.keys().cloned().collect::<Vec<_>>().join(", ")
+          }
+        ));
+      }
+    };
+    if let Some(container_image) = container_image {
+      environment_properties.push((CONTAINER_IMAGE_PROPERTY.to_owned(), container_image));
+    }
+    // The environment's properties replace those of the metadata with the same names.
+    platform_properties.retain(|(name, _)| {
+      !environment_properties
+        .iter()
+        .any(|(environment_name, _)| environment_name == name)
+    });
+    platform_properties.extend(environment_properties);
+    if environment_instance_name.is_some() {
+      instance_name = environment_instance_name;
+    }
+    priority = environment_priority;
+  }
+
+  let mut command = bazel_protos::remote_execution::Command::new();
+  let argv = req
+    .argv
+    .iter()
+    .enumerate()
+    .map(|(i, arg)| checked_request_string(&format!("argv[{}]", i), arg.as_bytes(), false))
+    .collect::<Result<Vec<_>, _>>()?;
+  command.set_arguments(protobuf::RepeatedField::from_vec(argv));
+  for (i, (name, value)) in req.env.iter().enumerate() {
+    if name.as_str() == CACHE_KEY_GEN_VERSION_ENV_VAR_NAME {
+      return Err(format!(
+        "Cannot set env var with name {} as that is reserved for internal use by pants",
+        CACHE_KEY_GEN_VERSION_ENV_VAR_NAME
+      ));
+    }
+    if name.starts_with(CACHE_SCOPE_ENV_VAR_PREFIX) {
+      return Err(format!(
+        "Cannot set env var with name {} as names starting with {} are reserved for internal use \
+         by pants",
+        name, CACHE_SCOPE_ENV_VAR_PREFIX
+      ));
+    }
+    // The name can only be included in messages once it is known to be valid.
+    let name = checked_request_string(
+      &format!("The name of env var {}", i),
+      name.as_bytes(),
+      allow_lossy_env,
+    )?;
+    let value = checked_request_string(
+      &format!("The value of env var {}", name),
+      value.as_bytes(),
+      allow_lossy_env,
+    )?;
+    let mut env = bazel_protos::remote_execution::Command_EnvironmentVariable::new();
+    env.set_name(name);
+    env.set_value(value);
+    command.mut_environment_variables().push(env);
+  }
+
+  if let Some(warning) = argv_length_warning(&req.description, &req.argv, argv_warning_bytes) {
+    warn!("{}", warning);
+  }
+
+  // An empty cache key gen version or scope epoch is sent as an env var with an empty value in
+  // older forms, but clients do not agree on whether an unset option is empty or absent.
+  let empty_as_absent = canonical_form_version >= EMPTY_AS_ABSENT_CANONICAL_FORM_VERSION;
+  let is_set = |value: &String| !(empty_as_absent && value.is_empty());
+
+  // The name of the env var is reserved (above) even for requests which omit it.
+  if let Some(cache_key_gen_version) = cache_key_gen_version
+    .filter(|_| !req.omit_cache_key_gen_version)
+    .filter(is_set)
+  {
+    let mut env = bazel_protos::remote_execution::Command_EnvironmentVariable::new();
+    env.set_name(CACHE_KEY_GEN_VERSION_ENV_VAR_NAME.to_string());
+    env.set_value(cache_key_gen_version);
+    command.mut_environment_variables().push(env);
+  }
+  // Scopes which the runner does not configure do not affect the Command, so that configuring a
+  // scope for the first time only changes the digests of its requests.
+  for cache_scope_name in &req.cache_scope_names {
+    if !is_valid_cache_scope_name(cache_scope_name) {
+      return Err(format!(
+        "Invalid cache scope name {:?}: names may only contain ASCII letters, digits and \
+         underscores",
+        cache_scope_name
+      ));
+    }
+    if let Some(epoch) = cache_scopes
+      .get(cache_scope_name)
+      .filter(|epoch| is_set(*epoch))
+    {
+      let name = format!("{}{}", CACHE_SCOPE_ENV_VAR_PREFIX, cache_scope_name);
+      let value = checked_request_string(
+        &format!("The epoch of cache scope {}", name),
+        epoch.as_bytes(),
+        false,
+      )?;
+      let mut env = bazel_protos::remote_execution::Command_EnvironmentVariable::new();
+      env.set_name(name);
+      env.set_value(value);
+      command.mut_environment_variables().push(env);
+    }
+  }
+  let normalize_output_paths =
+    canonical_form_version >= NORMALIZED_OUTPUT_PATHS_CANONICAL_FORM_VERSION;
+  let output_files =
+    checked_output_paths("output_files", &req.output_files, normalize_output_paths)?;
+  command.set_output_files(protobuf::RepeatedField::from_vec(output_files));
+  let output_directories = checked_output_paths(
+    "output_directories",
+    &req.output_directories,
+    normalize_output_paths,
+  )?;
+  command.set_output_directories(protobuf::RepeatedField::from_vec(output_directories));
+
+  let mut request_properties = vec![];
+  if req.jdk_home.is_some() {
+    // Ideally, the JDK would be brought along as part of the input directory, but we don't
+    // currently have support for that. Scoot supports this property, and will symlink .jdk to a
+    // system-installed JDK https://github.com/twitter/scoot/pull/391 - we should probably come to
+    // some kind of consensus across tools as to how this should work; RBE appears to work by
+    // allowing you to specify a jdk-version platform property, and it will put a JDK at a
+    // well-known path in the docker container you specify in which to run.
+    request_properties.push(("JDK_SYMLINK".to_owned(), ".jdk".to_owned()));
+  }
+  request_properties.push(("target_platform".to_owned(), req.target_platform.into()));
+  if req.environment.is_some() {
+    // The properties which the request sets itself take precedence over its environment's.
+    platform_properties.retain(|(name, _)| {
+      !request_properties
+        .iter()
+        .any(|(request_name, _)| request_name == name)
+    });
+  }
+  platform_properties.extend(request_properties);
+
+  // The Platform is omitted, rather than sent empty, if it has no properties (although every
+  // request currently has a target_platform property).
+  for (name, value) in platform_properties {
+    command.mut_platform().mut_properties().push({
+      let mut property = bazel_protos::remote_execution::Platform_Property::new();
+      property.set_name(name.clone());
+      property.set_value(value.clone());
+      property
+    });
+  }
+
+  let mut action = bazel_protos::remote_execution::Action::new();
+  action.set_command_digest((&digest(&command)?).into());
+  action.set_input_root_digest((&req.input_files).into());
+
+  let mut execute_request = bazel_protos::remote_execution::ExecuteRequest::new();
+  if let Some(instance_name) = instance_name {
+    execute_request.set_instance_name(instance_name);
+  }
+  execute_request.set_action_digest((&digest(&action)?).into());
+  // NB: We don't set do_not_cache on the Action when forcing a re-run, because it would change
+  // the Action's digest.
+  if req.force_rerun {
+    execute_request.set_skip_cache_lookup(true);
+  }
+  if let Some(ref scheduling_hints) = req.scheduling_hints {
+    scheduling_hints::attach(&mut execute_request, scheduling_hints);
+  }
+  if let Some(priority) = priority {
+    execute_request
+      .mut_execution_policy()
+      .set_priority(priority);
+  }
+
+  Ok((action, command, execute_request))
+}
+
+///
+/// The size of an argv as counted against OS limits: including the NUL terminator of each argument.
+///
+fn argv_bytes(argv: &[String]) -> usize {
+  argv.iter().map(|arg| arg.len() + 1).sum()
+}
+
+///
+/// Summarizes the size of an argv, including its longest argument (truncated), and suggests how to
+/// shrink it.
+///
+pub(crate) fn describe_argv(argv: &[String]) -> String {
+  let longest = argv
+    .iter()
+    .max_by_key(|arg| arg.len())
+    .map_or("", String::as_str);
+  let truncated = if longest.chars().count() > ARGV_SUMMARY_ARG_CHARS {
+    format!(
+      "{}...",
+      longest
+        .chars()
+        .take(ARGV_SUMMARY_ARG_CHARS)
+        .collect::<String>()
+    )
+  } else {
+    longest.to_owned()
+  };
+  format!(
+    "{} arguments totalling {} bytes, the longest of which ({} bytes) is {:?}. Consider passing \
+     the arguments in an args file, rather than on the command line",
+    argv.len(),
+    argv_bytes(argv),
+    longest.len(),
+    truncated
+  )
+}
+
+///
+/// Validates that the output paths of the named field are UTF-8, relative, and do not escape the
+/// input root, and returns them sorted. If normalize is set, redundant `.` components and
+/// separators are removed first, so that equivalent declarations (such as `./a//b` and `a/b`)
+/// serialize identically, and any duplicates which that creates are dropped.
+///
+fn checked_output_paths(
+  field: &str,
+  paths: &BTreeSet<PathBuf>,
+  normalize: bool,
+) -> Result<Vec<String>, String> {
+  let mut checked = paths
+    .iter()
+    .map(|path| {
+      let path_str = path
+        .to_str()
+        .ok_or_else(|| format!("{} contains a non-UTF8 path: {:?}", field, path))?;
+      if path.has_root() {
+        return Err(format!(
+          "{} must be relative to the input root, but {:?} is absolute",
+          field, path_str
+        ));
+      }
+      if path
+        .components()
+        .any(|component| component == std::path::Component::ParentDir)
+      {
+        return Err(format!(
+          "{} may not escape the input root, but {:?} contains a '..' component",
+          field, path_str
+        ));
+      }
+      if normalize {
+        Ok(
+          path
+            .components()
+            .filter_map(|component| match component {
+              std::path::Component::Normal(name) => name.to_str(),
+              _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("/"),
+        )
+      } else {
+        Ok(path_str.to_owned())
+      }
+    })
+    .collect::<Result<Vec<String>, String>>()?;
+  checked.sort();
+  checked.dedup();
+  Ok(checked)
+}
+
+fn is_valid_cache_scope_name(name: &str) -> bool {
+  !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+///
+/// Validates that a string of a request is UTF-8 with no NUL bytes, which would otherwise fail (or
+/// be mangled) when the Command is serialized. Strings which cross the FFI boundary are validated
+/// as the raw bytes that Python sent, before they are Strings. If lossy is set, invalid sequences
+/// are instead replaced with U+FFFD.
+///
+pub fn checked_request_string(what: &str, bytes: &[u8], lossy: bool) -> Result<String, String> {
+  if let Some(offset) = bytes.iter().position(|b| *b == 0) {
+    return Err(format!(
+      "{} contains a NUL byte at offset {}: {}",
+      what,
+      offset,
+      hex_preview(bytes, offset)
+    ));
+  }
+  match std::str::from_utf8(bytes) {
+    Ok(value) => Ok(value.to_owned()),
+    Err(e) => {
+      let message = format!(
+        "{} is not valid UTF-8 at offset {}: {}",
+        what,
+        e.valid_up_to(),
+        hex_preview(bytes, e.valid_up_to())
+      );
+      if lossy {
+        warn!("{}. Replacing invalid sequences with U+FFFD.", message);
+        Ok(String::from_utf8_lossy(bytes).into_owned())
+      } else {
+        Err(message)
+      }
+    }
+  }
+}
+
+///
+/// Renders the bytes around the given offset as hex.
+///
+fn hex_preview(bytes: &[u8], offset: usize) -> String {
+  let start = offset.saturating_sub(HEX_PREVIEW_BYTES / 2);
+  let end = min(bytes.len(), offset + HEX_PREVIEW_BYTES / 2);
+  let hex = bytes[start..end]
+    .iter()
+    .map(|b| format!("{:02x}", b))
+    .collect::<Vec<_>>()
+    .join(" ");
+  format!("bytes {}..{} are [{}]", start, end, hex)
+}
+
+///
+/// A warning, if the argv is larger than warning_bytes.
+///
+pub(crate) fn argv_length_warning(
+  description: &str,
+  argv: &[String],
+  warning_bytes: Option<usize>,
+) -> Option<String> {
+  match warning_bytes {
+    Some(warning_bytes) if argv_bytes(argv) > warning_bytes => Some(format!(
+      "The argv of {} is larger than {} bytes, so remote workers may reject it: it has {}.",
+      description,
+      warning_bytes,
+      describe_argv(argv)
+    )),
+    _ => None,
+  }
+}
+
+///
+/// Hashes and counts the bytes written to it, so that a proto can be digested while it is
+/// serialized, rather than first being serialized into a buffer.
+///
+struct HashingWriter {
+  hasher: Sha256,
+  len: usize,
+}
+
+impl io::Write for HashingWriter {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    self.hasher.input(buf);
+    self.len += buf.len();
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+pub(crate) fn digest(message: &dyn Message) -> Result<Digest, String> {
+  let mut writer = HashingWriter {
+    hasher: Sha256::default(),
+    len: 0,
+  };
+  message
+    .write_to_writer(&mut writer)
+    .map_err(|e| format!("{:?}", e))?;
+
+  Ok(Digest(
+    Fingerprint::from_bytes_unsafe(&writer.hasher.fixed_result()),
+    writer.len,
+  ))
+}
@@ -0,0 +1,312 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! Moves the trailing arguments of an over-long command line into an "argfile": a file in the
+//! input files which many tools (javac, scalac, gcc and clang among them) read further arguments
+//! from when they are passed a flag like `@path`.
+//!
+//! The argfile is named after the digest of its contents, and which arguments are moved depends
+//! only on the request, so a request is always rewritten in the same way, and its Action digest
+//! is stable.
+//!
+
+use std::path::PathBuf;
+
+use boxfuture::{try_future, BoxFuture, Boxable};
+use bytes::Bytes;
+use futures::{future, Future};
+use store::{Snapshot, Store};
+use workunit_store::WorkUnitStore;
+
+use crate::ExecuteProcessRequest;
+
+// The placeholder in an argfile_flag_template which is replaced by the path of the argfile.
+pub const ARGFILE_PATH_PLACEHOLDER: &str = "{path}";
+
+const ARGFILE_NAME_PREFIX: &str = ".pants-argfile-";
+
+// The length of a hex encoded Fingerprint.
+const FINGERPRINT_HEX_LEN: usize = 64;
+
+///
+/// True if the request has both an argfile_threshold and an argfile_flag_template, in which case
+/// `with_argfile` may rewrite it.
+///
+pub fn uses_argfile(req: &ExecuteProcessRequest) -> bool {
+  req.argfile_threshold.is_some() && req.argfile_flag_template.is_some()
+}
+
+///
+/// The size of an argv as the OS counts it against its limit: each argument has a terminator.
+///
+pub fn argv_bytes(argv: &[String]) -> usize {
+  argv.iter().map(|arg| arg.len() + 1).sum()
+}
+
+///
+/// Renders arguments as the contents of an argfile: each on its own line, in double quotes, with
+/// any backslashes and double quotes that they contain escaped.
+///
+pub fn argfile_contents(args: &[String]) -> Bytes {
+  let mut contents = String::new();
+  for arg in args {
+    contents.push('"');
+    for c in arg.chars() {
+      if c == '\\' || c == '"' {
+        contents.push('\\');
+      }
+      contents.push(c);
+    }
+    contents.push_str("\"\n");
+  }
+  Bytes::from(contents)
+}
+
+///
+/// Splits the argv of a request which needs an argfile into the arguments which are kept, and
+/// those which are moved into the argfile. The first argument (the binary) is always kept, and as
+/// many of the following arguments are kept as fit under the threshold along with the flag which
+/// replaces the rest.
+///
+/// Returns None if the request does not need an argfile.
+///
+fn split_argv(req: &ExecuteProcessRequest) -> Result<Option<(&[String], &[String])>, String> {
+  let (threshold, template) = match (req.argfile_threshold, &req.argfile_flag_template) {
+    (Some(threshold), &Some(ref template)) => (threshold, template),
+    _ => return Ok(None),
+  };
+  if !template.contains(ARGFILE_PATH_PLACEHOLDER) {
+    return Err(format!(
+      "Invalid argfile flag template {:?} for {}: it must contain {}",
+      template, req.description, ARGFILE_PATH_PLACEHOLDER
+    ));
+  }
+  if req.argv.len() < 2 || argv_bytes(&req.argv) <= threshold {
+    return Ok(None);
+  }
+
+  // Argfile names all have the same length.
+  let flag_bytes = template
+    .replace(
+      ARGFILE_PATH_PLACEHOLDER,
+      &"x".repeat(ARGFILE_NAME_PREFIX.len() + FINGERPRINT_HEX_LEN),
+    )
+    .len();
+  let mut kept_bytes = argv_bytes(&req.argv[..1]) + flag_bytes + 1;
+  let mut kept = 1;
+  for arg in &req.argv[1..] {
+    kept_bytes += arg.len() + 1;
+    if kept_bytes > threshold {
+      break;
+    }
+    kept += 1;
+  }
+  Ok(Some(req.argv.split_at(kept)))
+}
+
+///
+/// If the argv of the request exceeds its argfile_threshold, and it has an argfile_flag_template,
+/// stores an argfile containing its trailing arguments, and returns the request with the argfile
+/// merged into its input files, and with the trailing arguments replaced by the flag. Otherwise,
+/// returns the request unchanged.
+///
+/// A rewritten request has no argfile_threshold or argfile_flag_template, so is never rewritten
+/// again.
+///
+pub fn with_argfile(
+  store: Store,
+  req: ExecuteProcessRequest,
+  workunit_store: WorkUnitStore,
+) -> BoxFuture<ExecuteProcessRequest, String> {
+  let (kept, moved) = match try_future!(split_argv(&req)) {
+    Some((kept, moved)) => (kept.to_vec(), argfile_contents(moved)),
+    None => return future::ok(req).to_boxed(),
+  };
+  let store2 = store.clone();
+  let store3 = store.clone();
+  store
+    .store_file_bytes(moved, true)
+    .and_then(move |digest| {
+      let name = format!("{}{}", ARGFILE_NAME_PREFIX, digest.0.to_hex());
+      store2
+        .snapshot_of_one_file(PathBuf::from(&name), digest, false)
+        .map(|snapshot| (name, snapshot.digest))
+    })
+    .and_then(move |(name, argfile_digest)| {
+      Snapshot::merge_directories(
+        store3,
+        vec![req.input_files, argfile_digest],
+        workunit_store,
+      )
+      .map(move |input_files| {
+        let flag = req
+          .argfile_flag_template
+          .as_ref()
+          .unwrap()
+          .replace(ARGFILE_PATH_PLACEHOLDER, &name);
+        let mut argv = kept;
+        argv.push(flag);
+        ExecuteProcessRequest {
+          argv,
+          input_files,
+          argfile_threshold: None,
+          argfile_flag_template: None,
+          ..req
+        }
+      })
+    })
+    .to_boxed()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{argfile_contents, argv_bytes, with_argfile};
+  use crate::action::make_execute_request;
+  use crate::tests::execute_process_request;
+  use crate::{EnvRecording, ExecuteProcessRequest, ExecuteProcessRequestMetadata};
+  use bytes::Bytes;
+  use futures::Future;
+  use hashing::Digest;
+  use std::collections::BTreeMap;
+  use std::str;
+  use store::Store;
+  use tempfile::TempDir;
+  use testutil::data::{TestData, TestDirectory};
+  use testutil::owned_string_vec;
+  use workunit_store::WorkUnitStore;
+
+  fn metadata() -> ExecuteProcessRequestMetadata {
+    ExecuteProcessRequestMetadata {
+      instance_name: None,
+      cache_key_gen_version: None,
+      cache_scopes: BTreeMap::new(),
+      platform_properties: vec![],
+      timeout_excludes_queue: false,
+      argv_error_patterns: vec![],
+      argv_warning_bytes: None,
+      allow_lossy_env: false,
+      canonical_form_version: 0,
+      environments: BTreeMap::new(),
+      record_env_in_report: EnvRecording::Off,
+    }
+  }
+
+  // The binary and an output directory fit under the threshold along with an `@{path}` flag, but
+  // the sources do not.
+  fn long_request(template: Option<&str>) -> ExecuteProcessRequest {
+    let mut argv = owned_string_vec(&["/usr/bin/javac", "-d", "out"]);
+    argv.extend((0..20).map(|i| format!("src/org/pantsbuild/File{}.java", i)));
+    ExecuteProcessRequest {
+      argv,
+      input_files: TestDirectory::containing_roland().digest(),
+      argfile_threshold: Some(120),
+      argfile_flag_template: template.map(String::from),
+      ..execute_process_request("javac")
+    }
+  }
+
+  fn store() -> (Store, TempDir) {
+    let store_dir = TempDir::new().unwrap();
+    let executor = task_executor::Executor::new();
+    let store = Store::local_only(executor, store_dir.path()).unwrap();
+    store
+      .record_directory(&TestDirectory::containing_roland().directory(), false)
+      .wait()
+      .unwrap();
+    (store, store_dir)
+  }
+
+  fn rewrite(store: &Store, req: ExecuteProcessRequest) -> ExecuteProcessRequest {
+    with_argfile(store.clone(), req, WorkUnitStore::new())
+      .wait()
+      .unwrap()
+  }
+
+  fn action_digest(req: &ExecuteProcessRequest) -> Digest {
+    let (_, _, execute_request) = make_execute_request(req, metadata()).unwrap();
+    execute_request.get_action_digest().into()
+  }
+
+  #[test]
+  fn contents_are_quoted() {
+    assert_eq!(
+      argfile_contents(&owned_string_vec(&["A.java", "a \"b\\c\""])),
+      Bytes::from("\"A.java\"\n\"a \\\"b\\\\c\\\"\"\n")
+    );
+  }
+
+  #[test]
+  fn long_argv_is_moved_into_an_argfile() {
+    let (store, _store_dir) = store();
+    let req = long_request(Some("@{path}"));
+    let rewritten = rewrite(&store, req.clone());
+
+    let (_, command, _) = make_execute_request(&rewritten, metadata()).unwrap();
+    let contents = argfile_contents(&req.argv[3..]);
+    let argfile = TestData::new(str::from_utf8(&contents).unwrap());
+    let argfile_name = format!(".pants-argfile-{}", argfile.fingerprint().to_hex());
+    assert_eq!(
+      command.get_arguments().to_vec(),
+      vec![
+        "/usr/bin/javac".to_owned(),
+        "-d".to_owned(),
+        "out".to_owned(),
+        format!("@{}", argfile_name),
+      ]
+    );
+    assert!(argv_bytes(&rewritten.argv) <= req.argfile_threshold.unwrap());
+
+    let (directory, _) = store
+      .load_directory(rewritten.input_files, WorkUnitStore::new())
+      .wait()
+      .unwrap()
+      .unwrap();
+    let names = directory
+      .get_files()
+      .iter()
+      .map(|file| file.get_name().to_owned())
+      .collect::<Vec<_>>();
+    assert_eq!(names, vec![argfile_name, "roland".to_owned()]);
+    assert_eq!(
+      store
+        .load_file_bytes_with(argfile.digest(), |bytes| bytes, WorkUnitStore::new())
+        .wait()
+        .unwrap()
+        .map(|(bytes, _)| bytes),
+      Some(contents)
+    );
+  }
+
+  #[test]
+  fn argfiles_are_deterministic() {
+    let (store, _store_dir) = store();
+    let first = rewrite(&store, long_request(Some("@{path}")));
+    let (other_store, _other_store_dir) = self::store();
+    let second = rewrite(&other_store, long_request(Some("@{path}")));
+
+    assert_eq!(first, second);
+    assert_eq!(action_digest(&first), action_digest(&second));
+  }
+
+  #[test]
+  fn argfiles_require_a_template() {
+    let (store, _store_dir) = store();
+    let req = long_request(None);
+    assert_eq!(rewrite(&store, req.clone()), req);
+
+    let short = ExecuteProcessRequest {
+      argfile_threshold: Some(1024),
+      ..long_request(Some("@{path}"))
+    };
+    assert_eq!(rewrite(&store, short.clone()), short);
+
+    let invalid = with_argfile(store, long_request(Some("@argfile")), WorkUnitStore::new()).wait();
+    assert_eq!(
+      invalid,
+      Err(
+        "Invalid argfile flag template \"@argfile\" for javac: it must contain {path}".to_owned()
+      )
+    );
+  }
+}
@@ -0,0 +1,248 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! A small in-memory LRU of the blobs of results (such as their stdout and stderr), which chains of
+//! processes often read many times. Only small blobs are cached, so that a few large ones do not
+//! evict all of the others.
+//!
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use boxfuture::{BoxFuture, Boxable};
+use bytes::Bytes;
+use futures::{future, Future};
+use hashing::Digest;
+use workunit_store::WorkUnitStore;
+
+use crate::metrics;
+
+///
+/// Cheap to clone: all clones share a cache.
+///
+#[derive(Clone)]
+pub struct BlobCache {
+  budget_bytes: usize,
+  max_blob_bytes: usize,
+  state: Arc<Mutex<CacheState>>,
+}
+
+struct CacheState {
+  // By digest, each with the tick at which it was last used.
+  blobs: HashMap<Digest, (Bytes, u64)>,
+  cached_bytes: usize,
+  tick: u64,
+}
+
+impl BlobCache {
+  ///
+  /// A cache of at most `budget_bytes` of blobs, each of which is at most `max_blob_bytes`.
+  ///
+  pub fn new(budget_bytes: usize, max_blob_bytes: usize) -> BlobCache {
+    BlobCache {
+      budget_bytes,
+      max_blob_bytes,
+      state: Arc::new(Mutex::new(CacheState {
+        blobs: HashMap::new(),
+        cached_bytes: 0,
+        tick: 0,
+      })),
+    }
+  }
+
+  ///
+  /// Returns the cached blob, or else calls `load` and caches what it returns. Blobs which are too
+  /// large to cache are always loaded, and are not counted as hits or misses.
+  ///
+  pub fn load<F>(
+    &self,
+    digest: Digest,
+    workunit_store: &WorkUnitStore,
+    load: F,
+  ) -> BoxFuture<Option<Bytes>, String>
+  where
+    F: FnOnce() -> BoxFuture<Option<Bytes>, String>,
+  {
+    if digest.1 > self.max_blob_bytes || digest.1 > self.budget_bytes {
+      return load();
+    }
+    if let Some(bytes) = self.get(digest) {
+      workunit_store.increment_counter(metrics::REMOTE_BLOB_CACHE_HITS, 1);
+      return future::ok(Some(bytes)).to_boxed();
+    }
+    workunit_store.increment_counter(metrics::REMOTE_BLOB_CACHE_MISSES, 1);
+    let cache = self.clone();
+    load()
+      .map(move |maybe_bytes| {
+        if let Some(ref bytes) = maybe_bytes {
+          cache.insert(digest, bytes.clone());
+        }
+        maybe_bytes
+      })
+      .to_boxed()
+  }
+
+  fn get(&self, digest: Digest) -> Option<Bytes> {
+    let mut state = self.state.lock().unwrap();
+    state.tick += 1;
+    let tick = state.tick;
+    state.blobs.get_mut(&digest).map(|(bytes, last_used)| {
+      *last_used = tick;
+      bytes.clone()
+    })
+  }
+
+  fn insert(&self, digest: Digest, bytes: Bytes) {
+    let mut state = self.state.lock().unwrap();
+    if state.blobs.contains_key(&digest) {
+      return;
+    }
+    // Evict the least recently used blobs until the new one fits.
+    while state.cached_bytes + bytes.len() > self.budget_bytes {
+      let oldest = state
+        .blobs
+        .iter()
+        .min_by_key(|(_, (_, last_used))| *last_used)
+        .map(|(digest, _)| *digest);
+      match oldest {
+        Some(oldest) => {
+          let (evicted, _) = state.blobs.remove(&oldest).unwrap();
+          state.cached_bytes -= evicted.len();
+        }
+        None => return,
+      }
+    }
+    state.tick += 1;
+    let tick = state.tick;
+    state.cached_bytes += bytes.len();
+    state.blobs.insert(digest, (bytes, tick));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::sync::Arc;
+
+  use boxfuture::{BoxFuture, Boxable};
+  use bytes::Bytes;
+  use futures::{future, Future};
+  use testutil::data::TestData;
+  use workunit_store::WorkUnitStore;
+
+  use super::BlobCache;
+  use crate::metrics;
+
+  ///
+  /// Loads the given blob, counting the loads.
+  ///
+  fn counting_loader(
+    data: &TestData,
+    loads: &Arc<AtomicUsize>,
+  ) -> impl FnOnce() -> BoxFuture<Option<Bytes>, String> {
+    let bytes = data.bytes();
+    let loads = loads.clone();
+    move || {
+      loads.fetch_add(1, Ordering::SeqCst);
+      future::ok(Some(bytes)).to_boxed()
+    }
+  }
+
+  fn load(
+    cache: &BlobCache,
+    data: &TestData,
+    loads: &Arc<AtomicUsize>,
+    workunit_store: &WorkUnitStore,
+  ) -> Bytes {
+    cache
+      .load(data.digest(), workunit_store, counting_loader(data, loads))
+      .wait()
+      .unwrap()
+      .unwrap()
+  }
+
+  #[test]
+  fn repeated_loads_read_once() {
+    let cache = BlobCache::new(1024, 1024);
+    let loads = Arc::new(AtomicUsize::new(0));
+    let workunit_store = WorkUnitStore::new();
+    let roland = TestData::roland();
+
+    assert_eq!(
+      load(&cache, &roland, &loads, &workunit_store),
+      roland.bytes()
+    );
+    // Clones share the cache.
+    assert_eq!(
+      load(&cache.clone(), &roland, &loads, &workunit_store),
+      roland.bytes()
+    );
+    assert_eq!(loads.load(Ordering::SeqCst), 1);
+
+    let counters = workunit_store.get_counters();
+    assert_eq!(counters[metrics::REMOTE_BLOB_CACHE_HITS], 1);
+    assert_eq!(counters[metrics::REMOTE_BLOB_CACHE_MISSES], 1);
+  }
+
+  #[test]
+  fn large_blobs_bypass_the_cache() {
+    let roland = TestData::roland();
+    let cache = BlobCache::new(1024, roland.len() - 1);
+    let loads = Arc::new(AtomicUsize::new(0));
+    let workunit_store = WorkUnitStore::new();
+
+    load(&cache, &roland, &loads, &workunit_store);
+    load(&cache, &roland, &loads, &workunit_store);
+    assert_eq!(loads.load(Ordering::SeqCst), 2);
+    assert!(workunit_store.get_counters().is_empty());
+  }
+
+  #[test]
+  fn least_recently_used_blobs_are_evicted() {
+    let roland = TestData::roland();
+    let catnip = TestData::catnip();
+    let robin = TestData::robin();
+    // Room for two of the three blobs.
+    let cache = BlobCache::new(roland.len() + catnip.len() + robin.len() - 1, 1024);
+    let loads = Arc::new(AtomicUsize::new(0));
+    let workunit_store = WorkUnitStore::new();
+
+    load(&cache, &roland, &loads, &workunit_store);
+    load(&cache, &catnip, &loads, &workunit_store);
+    // Using roland makes catnip the least recently used.
+    load(&cache, &roland, &loads, &workunit_store);
+    load(&cache, &robin, &loads, &workunit_store);
+    assert_eq!(loads.load(Ordering::SeqCst), 3);
+
+    load(&cache, &roland, &loads, &workunit_store);
+    assert_eq!(loads.load(Ordering::SeqCst), 3);
+    load(&cache, &catnip, &loads, &workunit_store);
+    assert_eq!(loads.load(Ordering::SeqCst), 4);
+  }
+
+  #[test]
+  fn missing_blobs_are_not_cached() {
+    let cache = BlobCache::new(1024, 1024);
+    let loads = Arc::new(AtomicUsize::new(0));
+    let digest = TestData::roland().digest();
+    let missing = || {
+      let loads = loads.clone();
+      move || -> BoxFuture<Option<Bytes>, String> {
+        loads.fetch_add(1, Ordering::SeqCst);
+        future::ok(None).to_boxed()
+      }
+    };
+
+    let workunit_store = WorkUnitStore::new();
+    assert_eq!(
+      cache.load(digest, &workunit_store, missing()).wait(),
+      Ok(None)
+    );
+    assert_eq!(
+      cache.load(digest, &workunit_store, missing()).wait(),
+      Ok(None)
+    );
+    assert_eq!(loads.load(Ordering::SeqCst), 2);
+  }
+}
@@ -1,6 +1,6 @@
 use crate::{
   ExecuteProcessRequest, ExecuteProcessRequestMetadata, FallibleExecuteProcessResult,
-  MultiPlatformExecuteProcessRequest,
+  MultiPlatformExecuteProcessRequest, Platform, ProcessProgress, ProcessResultSource,
 };
 use boxfuture::{BoxFuture, Boxable};
 use bytes::Bytes;
@@ -11,6 +11,8 @@ use log::{debug, warn};
 use protobuf::Message;
 use sha2::Sha256;
 use sharded_lmdb::ShardedLmdb;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::sync::Arc;
 use store::Store;
 use workunit_store::WorkUnitStore;
@@ -27,22 +29,41 @@ impl crate::CommandRunner for CommandRunner {
   fn extract_compatible_request(
     &self,
     req: &MultiPlatformExecuteProcessRequest,
-  ) -> Option<ExecuteProcessRequest> {
+  ) -> Option<Arc<ExecuteProcessRequest>> {
     self.underlying.extract_compatible_request(req)
   }
 
-  // TODO: Maybe record WorkUnits for local cache checks.
+  fn metrics(&self) -> HashMap<&'static str, i64> {
+    self.underlying.metrics()
+  }
+
   fn run(
     &self,
     req: MultiPlatformExecuteProcessRequest,
     workunit_store: WorkUnitStore,
+  ) -> BoxFuture<FallibleExecuteProcessResult, String> {
+    self.run_with_progress(req, ProcessProgress::new(), workunit_store)
+  }
+
+  // TODO: Maybe record WorkUnits for local cache checks.
+  fn run_with_progress(
+    &self,
+    req: MultiPlatformExecuteProcessRequest,
+    progress: ProcessProgress,
+    workunit_store: WorkUnitStore,
   ) -> BoxFuture<FallibleExecuteProcessResult, String> {
     let digest = self.digest(req.clone());
     let key = digest.0;
 
+    // A forced re-run skips the lookup, but its result still replaces any cached result.
+    let lookup = if req.0.values().any(|epr| epr.force_rerun) {
+      futures::future::ok(None).to_boxed()
+    } else {
+      self.lookup(key, workunit_store.clone()).to_boxed()
+    };
+
     let command_runner = self.clone();
-    self
-      .lookup(key, workunit_store.clone())
+    lookup
       .then(move |maybe_result| {
         match maybe_result {
           Ok(Some(result)) => return futures::future::ok(result).to_boxed(),
@@ -56,7 +77,7 @@ impl crate::CommandRunner for CommandRunner {
         }
         command_runner
             .underlying
-            .run(req, workunit_store)
+            .run_with_progress(req, progress, workunit_store)
             .and_then(move |result| {
               command_runner.store(key, &result).then(|store_result| {
                 if let Err(err) = store_result {
@@ -71,17 +92,20 @@ impl crate::CommandRunner for CommandRunner {
   }
 }
 
-impl CommandRunner {
-  fn bytes_to_digest(&self, bytes: &[u8]) -> Digest {
-    let mut hasher = Sha256::default();
-    hasher.input(bytes);
+///
+/// The Digest of the given bytes, as they would be recorded in a Store.
+///
+pub(crate) fn bytes_to_digest(bytes: &[u8]) -> Digest {
+  let mut hasher = Sha256::default();
+  hasher.input(bytes);
 
-    Digest(
-      Fingerprint::from_bytes_unsafe(&hasher.fixed_result()),
-      bytes.len(),
-    )
-  }
+  Digest(
+    Fingerprint::from_bytes_unsafe(&hasher.fixed_result()),
+    bytes.len(),
+  )
+}
 
+impl CommandRunner {
   fn digest(&self, req: MultiPlatformExecuteProcessRequest) -> Digest {
     let mut hashes: Vec<String> = req
       .0
@@ -90,7 +114,7 @@ impl CommandRunner {
       .map(|(_a, _b, er)| er.get_action_digest().get_hash().to_string())
       .collect();
     hashes.sort();
-    self.bytes_to_digest(
+    bytes_to_digest(
       hashes
         .iter()
         .fold(String::new(), |mut acc, hash| {
@@ -118,10 +142,20 @@ impl CommandRunner {
       })
       .and_then(move |maybe_execute_response| {
         if let Some(execute_response) = maybe_execute_response {
+          let platform = Platform::try_from(
+            &execute_response
+              .get_result()
+              .get_execution_metadata()
+              .get_worker()
+              .to_owned(),
+          )
+          .unwrap_or(Platform::None);
           crate::remote::populate_fallible_execution_result(
             file_store,
             execute_response,
             vec![],
+            ProcessResultSource::HitLocalCache,
+            platform,
             workunit_store,
           )
           .map(Some)
@@ -139,8 +173,16 @@ impl CommandRunner {
   ) -> impl Future<Item = (), Error = String> {
     let mut execute_response = bazel_protos::remote_execution::ExecuteResponse::new();
     execute_response.set_cached_result(true);
+    if let Some(ref server_message) = result.server_message {
+      execute_response.set_message(server_message.clone());
+    }
     let action_result = execute_response.mut_result();
     action_result.set_exit_code(result.exit_code);
+    // The cache has no field for the platform, so it is recorded as the worker which ran the
+    // process, from which lookup recovers it.
+    action_result
+      .mut_execution_metadata()
+      .set_worker(String::from(result.platform));
     action_result.mut_output_directories().push({
       let mut directory = bazel_protos::remote_execution::OutputDirectory::new();
       directory.set_path(String::new());
@@ -151,14 +193,10 @@ impl CommandRunner {
     // TODO: Should probably have a configurable lease time which is larger than default.
     // (This isn't super urgent because we don't ever actually GC this store. So also...)
     // TODO: GC the local process execution cache.
-    self
-      .file_store
-      .store_file_bytes(result.stdout.clone(), true)
-      .join(
-        self
-          .file_store
-          .store_file_bytes(result.stderr.clone(), true),
-      )
+    result
+      .stdout
+      .store(&self.file_store)
+      .join(result.stderr.store(&self.file_store))
       .and_then(move |(stdout_digest, stderr_digest)| {
         let action_result = execute_response.mut_result();
         action_result.set_stdout_digest((&stdout_digest).into());
@@ -174,8 +212,15 @@ impl CommandRunner {
 
 #[cfg(test)]
 mod test {
-  use crate::{CommandRunner as CommandRunnerTrait, ExecuteProcessRequestMetadata};
-  use crate::{ExecuteProcessRequest, Platform};
+  use crate::remote::tests::{
+    create_command_runner_for_platform, echo_foo_request, empty_request_metadata,
+  };
+  use crate::speculate::SpeculatingCommandRunner;
+  use crate::{
+    CommandRunner as CommandRunnerTrait, EnvRecording, ExecuteProcessRequestMetadata,
+    FallibleExecuteProcessResult,
+  };
+  use crate::{ExecuteProcessRequest, ExecutionLocality, Platform, ProcessResultSource};
   use hashing::EMPTY_DIGEST;
   use sharded_lmdb::ShardedLmdb;
   use std::collections::{BTreeMap, BTreeSet};
@@ -185,6 +230,7 @@ mod test {
   use std::time::Duration;
   use store::Store;
   use tempfile::TempDir;
+  use testutil::as_bytes;
   use testutil::data::TestData;
   use workunit_store::WorkUnitStore;
 
@@ -226,6 +272,20 @@ mod test {
       description: "bash".to_string(),
       jdk_home: None,
       target_platform: Platform::None,
+      force_rerun: false,
+      expected_output_digest: None,
+      diff_outputs: false,
+      ephemeral_input_digests: vec![],
+      scheduling_hints: None,
+      poll_interval_hint: None,
+      cache_scope_names: BTreeSet::new(),
+      argfile_threshold: None,
+      argfile_flag_template: None,
+      provenance: None,
+      environment: None,
+      omit_cache_key_gen_version: false,
+      expected_output_size: None,
+      execution_locality: ExecutionLocality::Any,
     };
 
     let local_result = runtime.block_on(local.run(request.clone().into(), WorkUnitStore::new()));
@@ -243,7 +303,15 @@ mod test {
       metadata: ExecuteProcessRequestMetadata {
         instance_name: None,
         cache_key_gen_version: None,
+        cache_scopes: BTreeMap::new(),
         platform_properties: vec![],
+        timeout_excludes_queue: false,
+        argv_error_patterns: vec![],
+        argv_warning_bytes: None,
+        allow_lossy_env: false,
+        canonical_form_version: 0,
+        environments: BTreeMap::new(),
+        record_env_in_report: EnvRecording::Off,
       },
     };
 
@@ -251,10 +319,117 @@ mod test {
       runtime.block_on(caching.run(request.clone().into(), WorkUnitStore::new()));
 
     assert_eq!(local_result, uncached_result);
+    assert_eq!(
+      uncached_result.as_ref().map(|result| result.source),
+      Ok(ProcessResultSource::RanLocally)
+    );
 
     std::fs::remove_file(&script_path).unwrap();
-    let cached_result = runtime.block_on(caching.run(request.into(), WorkUnitStore::new()));
+    let cached_result = runtime.block_on(caching.run(request.clone().into(), WorkUnitStore::new()));
+
+    assert_eq!(
+      cached_result.as_ref().map(|result| result.source),
+      Ok(ProcessResultSource::HitLocalCache)
+    );
+    assert_eq!(
+      uncached_result.map(|result| FallibleExecuteProcessResult {
+        source: ProcessResultSource::HitLocalCache,
+        ..result
+      }),
+      cached_result
+    );
+
+    // A forced re-run skips the cache, and so fails now that the script is gone.
+    let forced_result = runtime
+      .block_on(
+        caching.run(
+          ExecuteProcessRequest {
+            force_rerun: true,
+            ..request
+          }
+          .into(),
+          WorkUnitStore::new(),
+        ),
+      )
+      .unwrap();
+    assert_eq!(forced_result.source, ProcessResultSource::RanLocally);
+    assert_ne!(forced_result.exit_code, 0);
+  }
+
+  #[test]
+  fn local_only_requests_are_cached_but_never_run_remotely() {
+    let runtime = task_executor::Executor::new();
+    let work_dir = TempDir::new().unwrap();
+    let store_dir = TempDir::new().unwrap();
+    let store = Store::local_only(runtime.clone(), store_dir.path()).unwrap();
+    let local = crate::local::CommandRunner::new(
+      store.clone(),
+      runtime.clone(),
+      work_dir.path().to_owned(),
+      true,
+    );
+
+    let request = ExecuteProcessRequest {
+      execution_locality: ExecutionLocality::LocalOnly,
+      ..(*echo_foo_request().0[&(Platform::None, Platform::None)]).clone()
+    };
+    let execute_request = crate::remote::make_execute_request(&request, empty_request_metadata())
+      .unwrap()
+      .2;
+    // The mock server has no operations for the request, so fails it if it is ever executed.
+    let mock_server = mock::execution_server::TestServer::new(
+      mock::execution_server::MockExecution::new("gimme-foo".to_owned(), execute_request, vec![]),
+      None,
+    );
+    let cas = mock::StubCAS::empty();
+    let remote = create_command_runner_for_platform(mock_server.address(), &cas, Platform::None);
+
+    let cache_dir = TempDir::new().unwrap();
+    let caching = crate::cache::CommandRunner {
+      underlying: Arc::new(SpeculatingCommandRunner::new(
+        Box::new(remote),
+        Box::new(local),
+        Duration::from_millis(0),
+      )),
+      file_store: store,
+      process_execution_store: ShardedLmdb::new(
+        cache_dir.path().to_owned(),
+        50 * 1024 * 1024,
+        runtime.clone(),
+      )
+      .unwrap(),
+      metadata: empty_request_metadata(),
+    };
+    // The locality is not part of the Action, so the request is cached under the same key as it
+    // would be if it could run anywhere.
+    assert_eq!(
+      caching.digest(request.clone().into()),
+      caching.digest(
+        ExecuteProcessRequest {
+          execution_locality: ExecutionLocality::Any,
+          ..request.clone()
+        }
+        .into()
+      )
+    );
+
+    let uncached_result = runtime
+      .block_on(caching.run(request.clone().into(), WorkUnitStore::new()))
+      .unwrap();
+    assert_eq!(uncached_result.source, ProcessResultSource::RanLocally);
+    assert_eq!(uncached_result.stdout, as_bytes("foo"));
+
+    let cached_result = runtime
+      .block_on(caching.run(request.into(), WorkUnitStore::new()))
+      .unwrap();
+    assert_eq!(cached_result.source, ProcessResultSource::HitLocalCache);
+    assert_eq!(cached_result.stdout, as_bytes("foo"));
 
-    assert_eq!(uncached_result, cached_result);
+    assert!(mock_server
+      .mock_responder
+      .received_messages
+      .lock()
+      .iter()
+      .all(|message| message.message_type != "ExecuteRequest"));
   }
 }
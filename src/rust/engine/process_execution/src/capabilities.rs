@@ -0,0 +1,415 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! Detection of which of the optional features of the REAPI a server supports, via its
+//! Capabilities service.
+//!
+//! Servers silently ignore the fields of requests which they do not understand, so a request which
+//! relies on a feature that the server lacks does not fail: it just behaves differently. Instead,
+//! each feature which a client is about to use is checked against the capabilities which the
+//! server advertises, and the first use of each which is missing is reported, along with what the
+//! client does instead. In strict mode, using a missing feature is an error.
+//!
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use bazel_protos;
+use bazel_protos::remote_execution::{DigestFunction, ServerCapabilities};
+use futures::future::Shared;
+use futures::sync::oneshot;
+use futures::Future;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Feature {
+  // Running actions, rather than only serving their results from the action cache.
+  RemoteExecution,
+  // Digests computed with SHA256, which is the only function that digests are computed with.
+  Sha256Digests,
+  // Sending each ExecuteRequest with a priority.
+  ExecutionPriority,
+}
+
+impl Feature {
+  ///
+  /// Whether the given capabilities include this feature.
+  ///
+  pub fn is_supported_by(self, capabilities: &ServerCapabilities) -> bool {
+    let execution = capabilities.get_execution_capabilities();
+    match self {
+      Feature::RemoteExecution => execution.get_exec_enabled(),
+      Feature::Sha256Digests => execution.get_digest_function() == DigestFunction::SHA256,
+      Feature::ExecutionPriority => !execution
+        .get_execution_priority_capabilities()
+        .get_priorities()
+        .is_empty(),
+    }
+  }
+
+  // The field of ServerCapabilities which advertises this feature.
+  fn capability(self) -> &'static str {
+    match self {
+      Feature::RemoteExecution => "execution_capabilities.exec_enabled",
+      Feature::Sha256Digests => "execution_capabilities.digest_function",
+      Feature::ExecutionPriority => "execution_capabilities.execution_priority_capabilities",
+    }
+  }
+
+  // What a client does instead of using this feature, if the server lacks it.
+  fn fallback(self) -> &'static str {
+    match self {
+      Feature::RemoteExecution => {
+        "relying on the action cache, so requests which miss it will fail"
+      }
+      Feature::Sha256Digests => "sending SHA256 digests regardless",
+      Feature::ExecutionPriority => "sending requests without a priority",
+    }
+  }
+}
+
+impl fmt::Display for Feature {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let name = match self {
+      Feature::RemoteExecution => "remote execution",
+      Feature::Sha256Digests => "SHA256 digests",
+      Feature::ExecutionPriority => "execution priorities",
+    };
+    write!(f, "{}", name)
+  }
+}
+
+///
+/// The capabilities of a server, which are fetched once for each instance name which requests are
+/// made with (as environments may override the instance name, whose capabilities may differ), and
+/// the features which it has been found to lack. Cheap to clone: all clones share their state, so
+/// that each missing feature is reported once however many runners use it.
+///
+#[derive(Clone)]
+pub struct CapabilityDetector {
+  strict: bool,
+  state: Arc<Mutex<DetectorState>>,
+}
+
+#[derive(Default)]
+struct DetectorState {
+  // By instance name, absent until the capabilities have been recorded. None if the server does
+  // not implement the Capabilities service, in which case it is assumed to support every feature.
+  capabilities: HashMap<Option<String>, Option<ServerCapabilities>>,
+  missing: BTreeSet<Feature>,
+  // By instance name, completes when the fetch which is in flight (if any) does.
+  fetches: HashMap<Option<String>, Shared<oneshot::Receiver<()>>>,
+}
+
+///
+/// The part of a caller in the fetch of the capabilities which concurrent first requests share.
+///
+pub enum FetchRole {
+  // The caller must fetch the capabilities and record them, and then drop the Sender, which
+  // completes the fetch for the Waiters. If it is dropped without recording any, the next caller
+  // to join is the Fetcher of a new fetch.
+  Fetcher(oneshot::Sender<()>),
+  // Completes once the fetch which is in flight does, whether or not it recorded capabilities.
+  Waiter(Shared<oneshot::Receiver<()>>),
+}
+
+impl CapabilityDetector {
+  pub fn new(strict: bool) -> CapabilityDetector {
+    CapabilityDetector {
+      strict,
+      state: Arc::new(Mutex::new(DetectorState::default())),
+    }
+  }
+
+  ///
+  /// The request with which to fetch the capabilities of the given instance.
+  ///
+  pub fn request(
+    instance_name: Option<&str>,
+  ) -> bazel_protos::remote_execution::GetCapabilitiesRequest {
+    let mut request = bazel_protos::remote_execution::GetCapabilitiesRequest::new();
+    if let Some(instance_name) = instance_name {
+      request.set_instance_name(instance_name.to_owned());
+    }
+    request
+  }
+
+  pub fn is_known(&self, instance_name: Option<&str>) -> bool {
+    self
+      .state
+      .lock()
+      .unwrap()
+      .capabilities
+      .contains_key(&instance_name.map(str::to_owned))
+  }
+
+  ///
+  /// Joins the fetch of the capabilities of the given instance which is in flight, or starts one,
+  /// so that however many requests are concurrently the first, the capabilities are fetched once.
+  ///
+  pub fn join_fetch(&self, instance_name: Option<&str>) -> FetchRole {
+    let instance_name = instance_name.map(str::to_owned);
+    let mut state = self.state.lock().unwrap();
+    if let Some(fetch) = state.fetches.get(&instance_name) {
+      if fetch.peek().is_none() {
+        return FetchRole::Waiter(fetch.clone());
+      }
+    }
+    let (sender, receiver) = oneshot::channel();
+    state.fetches.insert(instance_name, receiver.shared());
+    FetchRole::Fetcher(sender)
+  }
+
+  ///
+  /// Records the capabilities of the server for the given instance, or None if it does not
+  /// implement the Capabilities service. Returns whether these were the first capabilities to be
+  /// recorded for the instance: later ones (from concurrent fetches) are ignored.
+  ///
+  pub fn record(
+    &self,
+    instance_name: Option<&str>,
+    capabilities: Option<ServerCapabilities>,
+  ) -> bool {
+    let instance_name = instance_name.map(str::to_owned);
+    let mut state = self.state.lock().unwrap();
+    state.fetches.remove(&instance_name);
+    if state.capabilities.contains_key(&instance_name) {
+      return false;
+    }
+    state.capabilities.insert(instance_name, capabilities);
+    true
+  }
+
+  ///
+  /// Whether the server supports the given feature for the given instance. The first time that the
+  /// feature is found to be missing, `warn` is called with a description of it and of the
+  /// fallback. In strict mode, a missing feature is an error instead, every time that it is
+  /// checked.
+  ///
+  /// Features are assumed to be supported until the capabilities have been recorded.
+  ///
+  pub fn check<F: FnOnce(String)>(
+    &self,
+    instance_name: Option<&str>,
+    feature: Feature,
+    warn: F,
+  ) -> Result<bool, String> {
+    let mut state = self.state.lock().unwrap();
+    let supported = match state.capabilities.get(&instance_name.map(str::to_owned)) {
+      Some(Some(capabilities)) => feature.is_supported_by(capabilities),
+      _ => true,
+    };
+    if supported {
+      return Ok(true);
+    }
+    let first = state.missing.insert(feature);
+    if self.strict {
+      return Err(format!(
+        "The remote execution server does not support {} (it does not advertise {}), and \
+         capability detection is strict.",
+        feature,
+        feature.capability()
+      ));
+    }
+    if first {
+      warn(format!(
+        "The remote execution server does not support {} (it does not advertise {}): \
+         falling back to {}.",
+        feature,
+        feature.capability(),
+        feature.fallback()
+      ));
+    }
+    Ok(false)
+  }
+
+  ///
+  /// The largest total size of the blobs of a batched CAS request which the server accepts for the
+  /// given instance, if it has reported one. Servers which report 0 (or whose capabilities are not
+  /// yet known) have no known limit.
+  ///
+  pub fn max_batch_total_size_bytes(&self, instance_name: Option<&str>) -> Option<usize> {
+    match self
+      .state
+      .lock()
+      .unwrap()
+      .capabilities
+      .get(&instance_name.map(str::to_owned))
+    {
+      Some(Some(capabilities)) => {
+        match bazel_protos::batch_read_blobs::max_batch_total_size_bytes(
+          capabilities.get_cache_capabilities(),
+        ) {
+          size if size > 0 => Some(size as usize),
+          _ => None,
+        }
+      }
+      _ => None,
+    }
+  }
+
+  ///
+  /// The number of features which have been found to be missing.
+  ///
+  pub fn missing_count(&self) -> usize {
+    self.state.lock().unwrap().missing.len()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use bazel_protos::remote_execution::{DigestFunction, ServerCapabilities};
+  use futures::Future;
+
+  use super::{CapabilityDetector, Feature, FetchRole};
+
+  fn capabilities_without_priorities() -> ServerCapabilities {
+    let mut capabilities = ServerCapabilities::new();
+    let execution = capabilities.mut_execution_capabilities();
+    execution.set_exec_enabled(true);
+    execution.set_digest_function(DigestFunction::SHA256);
+    capabilities
+  }
+
+  #[test]
+  fn unknown_capabilities_support_everything() {
+    let detector = CapabilityDetector::new(true);
+    assert_eq!(
+      detector.check(None, Feature::ExecutionPriority, |_| panic!(
+        "Should not warn"
+      )),
+      Ok(true)
+    );
+    assert!(detector.record(None, None));
+    assert!(!detector.record(None, Some(capabilities_without_priorities())));
+    assert_eq!(
+      detector.check(None, Feature::ExecutionPriority, |_| panic!(
+        "Should not warn"
+      )),
+      Ok(true)
+    );
+    assert_eq!(detector.missing_count(), 0);
+  }
+
+  #[test]
+  fn concurrent_fetches_are_shared() {
+    let detector = CapabilityDetector::new(false);
+    let fetcher = match detector.join_fetch(None) {
+      FetchRole::Fetcher(fetcher) => fetcher,
+      FetchRole::Waiter(_) => panic!("The first caller should fetch"),
+    };
+    let waiter = match detector.join_fetch(None) {
+      FetchRole::Waiter(waiter) => waiter,
+      FetchRole::Fetcher(_) => panic!("A fetch is already in flight"),
+    };
+    assert!(waiter.peek().is_none());
+
+    detector.record(None, Some(capabilities_without_priorities()));
+    fetcher.send(()).unwrap();
+    assert!(waiter.wait().is_ok());
+    assert!(detector.is_known(None));
+  }
+
+  #[test]
+  fn capabilities_are_per_instance() {
+    let detector = CapabilityDetector::new(false);
+    detector.record(Some("gpus"), Some(capabilities_without_priorities()));
+    assert!(detector.is_known(Some("gpus")));
+    assert!(!detector.is_known(None));
+    match detector.join_fetch(None) {
+      FetchRole::Fetcher(_) => (),
+      FetchRole::Waiter(_) => panic!("The default instance has not been fetched"),
+    }
+
+    assert_eq!(
+      detector.check(None, Feature::ExecutionPriority, |_| panic!(
+        "Should not warn"
+      )),
+      Ok(true)
+    );
+    let mut warnings = vec![];
+    assert_eq!(
+      detector.check(Some("gpus"), Feature::ExecutionPriority, |warning| warnings
+        .push(warning)),
+      Ok(false)
+    );
+    assert_eq!(warnings.len(), 1);
+  }
+
+  #[test]
+  fn abandoned_fetch_is_restarted() {
+    let detector = CapabilityDetector::new(false);
+    let fetcher = match detector.join_fetch(None) {
+      FetchRole::Fetcher(fetcher) => fetcher,
+      FetchRole::Waiter(_) => panic!("The first caller should fetch"),
+    };
+    let waiter = match detector.join_fetch(None) {
+      FetchRole::Waiter(waiter) => waiter,
+      FetchRole::Fetcher(_) => panic!("A fetch is already in flight"),
+    };
+
+    // Dropped without recording any capabilities, which completes the waiters...
+    std::mem::drop(fetcher);
+    assert!(waiter.wait().is_err());
+    assert!(!detector.is_known(None));
+    // ...and lets the next caller fetch again.
+    match detector.join_fetch(None) {
+      FetchRole::Fetcher(_) => (),
+      FetchRole::Waiter(_) => panic!("The abandoned fetch should not be waited for"),
+    }
+  }
+
+  #[test]
+  fn warns_once_per_missing_feature() {
+    let detector = CapabilityDetector::new(false);
+    detector.record(None, Some(capabilities_without_priorities()));
+
+    let mut warnings = vec![];
+    for _ in 0..3 {
+      for feature in &[Feature::RemoteExecution, Feature::Sha256Digests] {
+        assert_eq!(
+          detector.check(None, *feature, |_| panic!("Should not warn")),
+          Ok(true)
+        );
+      }
+      assert_eq!(
+        detector.check(None, Feature::ExecutionPriority, |warning| warnings
+          .push(warning)),
+        Ok(false)
+      );
+    }
+    assert_eq!(
+      warnings,
+      vec![
+        "The remote execution server does not support execution priorities (it does not \
+         advertise execution_capabilities.execution_priority_capabilities): falling back to \
+         sending requests without a priority."
+          .to_owned()
+      ]
+    );
+    assert_eq!(detector.missing_count(), 1);
+  }
+
+  #[test]
+  fn strict_missing_feature_is_an_error() {
+    let detector = CapabilityDetector::new(true);
+    let mut capabilities = capabilities_without_priorities();
+    capabilities
+      .mut_execution_capabilities()
+      .set_digest_function(DigestFunction::SHA1);
+    detector.record(None, Some(capabilities));
+
+    for _ in 0..2 {
+      let error = detector
+        .check(Feature::Sha256Digests, |_| panic!("Should not warn"))
+        .expect_err("Want error");
+      assert!(
+        error.contains("does not support SHA256 digests"),
+        "Unexpected error: {}",
+        error
+      );
+    }
+    assert_eq!(detector.missing_count(), 1);
+  }
+}
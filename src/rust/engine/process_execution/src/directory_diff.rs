@@ -0,0 +1,466 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! Compares two Directory digests file by file, by walking their protos in the Store rather than
+//! by materializing them, so that the outputs of two runs of a process can be diffed cheaply.
+//!
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+
+use bazel_protos::remote_execution::Directory;
+use boxfuture::{BoxFuture, Boxable};
+use futures::future::{self, Loop};
+use futures::{stream, Future, Stream};
+use hashing::{Digest, EMPTY_DIGEST};
+use store::Store;
+use workunit_store::WorkUnitStore;
+
+// The most Directory protos which are loaded at once while comparing two trees.
+const MAX_CONCURRENT_LOADS: usize = 16;
+
+// The most entries which are rendered by DirectoryDiff's Display.
+pub const MAX_REPORTED_ENTRIES: usize = 20;
+
+///
+/// The files which differ between two Directories, by path.
+///
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DirectoryDiff {
+  /// Files which are only on the right.
+  pub added: Vec<(PathBuf, Digest)>,
+  /// Files which are only on the left.
+  pub removed: Vec<(PathBuf, Digest)>,
+  /// Files which are on both sides with different contents: their left and right digests.
+  pub changed: Vec<(PathBuf, Digest, Digest)>,
+  /// Subtrees whose Directory protos are not in the Store, so which could not be compared.
+  pub unknown: Vec<(PathBuf, Digest)>,
+}
+
+impl DirectoryDiff {
+  pub fn is_empty(&self) -> bool {
+    self.added.is_empty()
+      && self.removed.is_empty()
+      && self.changed.is_empty()
+      && self.unknown.is_empty()
+  }
+
+  pub fn len(&self) -> usize {
+    self.added.len() + self.removed.len() + self.changed.len() + self.unknown.len()
+  }
+
+  fn entries(&self) -> impl Iterator<Item = String> + '_ {
+    let added = self
+      .added
+      .iter()
+      .map(|(path, digest)| format!("added {} ({})", path.display(), render(digest)));
+    let removed = self
+      .removed
+      .iter()
+      .map(|(path, digest)| format!("removed {} ({})", path.display(), render(digest)));
+    let changed = self.changed.iter().map(|(path, left, right)| {
+      format!(
+        "changed {} ({} -> {})",
+        path.display(),
+        render(left),
+        render(right)
+      )
+    });
+    let unknown = self
+      .unknown
+      .iter()
+      .map(|(path, digest)| format!("unknown {}/ ({})", path.display(), render(digest)));
+    added.chain(removed).chain(changed).chain(unknown)
+  }
+}
+
+impl fmt::Display for DirectoryDiff {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if self.is_empty() {
+      return write!(f, "no differences");
+    }
+    let mut entries = self
+      .entries()
+      .take(MAX_REPORTED_ENTRIES)
+      .collect::<Vec<_>>();
+    if self.len() > MAX_REPORTED_ENTRIES {
+      entries.push(format!(
+        "… and {} more",
+        self.len() - MAX_REPORTED_ENTRIES
+      ));
+    }
+    write!(f, "{}", entries.join("; "))
+  }
+}
+
+fn render(digest: &Digest) -> String {
+  format!("{}, {} bytes", digest.0, digest.1)
+}
+
+///
+/// A subtree, at the same path on both sides, which is yet to be compared. A side is None if it
+/// does not have a directory at that path.
+///
+struct Pending {
+  path: PathBuf,
+  left: Option<Digest>,
+  right: Option<Digest>,
+}
+
+///
+/// Compares the Directories `left` and `right` without materializing them.
+///
+/// Subtrees whose digests are the same on both sides are not loaded. The trees are compared a
+/// level at a time, loading at most MAX_CONCURRENT_LOADS Directory protos at once. The entries of
+/// the returned diff are sorted by path.
+///
+pub fn compare_directory_digests(
+  store: &Store,
+  left: Digest,
+  right: Digest,
+  workunit_store: WorkUnitStore,
+) -> BoxFuture<DirectoryDiff, String> {
+  let store = store.clone();
+  let initial = vec![Pending {
+    path: PathBuf::new(),
+    left: Some(left),
+    right: Some(right),
+  }];
+  future::loop_fn(
+    (DirectoryDiff::default(), initial),
+    move |(mut diff, level)| {
+      let store = store.clone();
+      let workunit_store = workunit_store.clone();
+      stream::iter_ok(level)
+        .map(move |pending| load(&store, pending, workunit_store.clone()))
+        .buffer_unordered(MAX_CONCURRENT_LOADS)
+        .collect()
+        .and_then(move |loaded: Vec<_>| -> Result<_, String> {
+          let mut next_level = vec![];
+          for (pending, left, right) in loaded {
+            compare(&mut diff, &mut next_level, pending, left, right)?;
+          }
+          if next_level.is_empty() {
+            diff.added.sort();
+            diff.removed.sort();
+            diff.changed.sort();
+            diff.unknown.sort();
+            Ok(Loop::Break(diff))
+          } else {
+            Ok(Loop::Continue((diff, next_level)))
+          }
+        })
+    },
+  )
+  .to_boxed()
+}
+
+///
+/// Loads each side of the pending subtree. A side which is present but not in the Store is loaded
+/// as None.
+///
+fn load(
+  store: &Store,
+  pending: Pending,
+  workunit_store: WorkUnitStore,
+) -> BoxFuture<(Pending, Option<Directory>, Option<Directory>), String> {
+  let load_side = |digest: Option<Digest>| match digest {
+    None => future::ok(Some(Directory::new())).to_boxed(),
+    Some(digest) if digest == EMPTY_DIGEST => future::ok(Some(Directory::new())).to_boxed(),
+    Some(digest) => store
+      .load_directory(digest, workunit_store.clone())
+      .map(|maybe_directory| maybe_directory.map(|(directory, _metadata)| directory))
+      .to_boxed(),
+  };
+  let left = load_side(pending.left);
+  let right = load_side(pending.right);
+  left
+    .join(right)
+    .map(move |(left, right)| (pending, left, right))
+    .to_boxed()
+}
+
+fn compare(
+  diff: &mut DirectoryDiff,
+  next_level: &mut Vec<Pending>,
+  pending: Pending,
+  left: Option<Directory>,
+  right: Option<Directory>,
+) -> Result<(), String> {
+  let (left, right) = match (left, right) {
+    (Some(left), Some(right)) => (left, right),
+    (left, right) => {
+      for (side, directory) in &[(pending.left, left), (pending.right, right)] {
+        if let (Some(digest), None) = (side, directory) {
+          diff.unknown.push((pending.path.clone(), *digest));
+        }
+      }
+      return Ok(());
+    }
+  };
+
+  let left_files = files(&left)?;
+  let right_files = files(&right)?;
+  for (name, left_digest) in &left_files {
+    let path = pending.path.join(name);
+    match right_files.get(name) {
+      Some(right_digest) if right_digest == left_digest => {}
+      Some(right_digest) => diff.changed.push((path, *left_digest, *right_digest)),
+      None => diff.removed.push((path, *left_digest)),
+    }
+  }
+  for (name, right_digest) in &right_files {
+    if !left_files.contains_key(name) {
+      diff.added.push((pending.path.join(name), *right_digest));
+    }
+  }
+
+  let left_directories = directories(&left)?;
+  let mut right_directories = directories(&right)?;
+  for (name, left_digest) in left_directories {
+    let right_digest = right_directories.remove(name);
+    if right_digest != Some(left_digest) {
+      next_level.push(Pending {
+        path: pending.path.join(name),
+        left: Some(left_digest),
+        right: right_digest,
+      });
+    }
+  }
+  for (name, right_digest) in right_directories {
+    next_level.push(Pending {
+      path: pending.path.join(name),
+      left: None,
+      right: Some(right_digest),
+    });
+  }
+  Ok(())
+}
+
+fn files(directory: &Directory) -> Result<BTreeMap<&str, Digest>, String> {
+  directory
+    .get_files()
+    .iter()
+    .map(|file| {
+      let digest: Result<Digest, String> = file.get_digest().into();
+      digest.map(|digest| (file.get_name(), digest))
+    })
+    .collect()
+}
+
+fn directories(directory: &Directory) -> Result<BTreeMap<&str, Digest>, String> {
+  directory
+    .get_directories()
+    .iter()
+    .map(|subdirectory| {
+      let digest: Result<Digest, String> = subdirectory.get_digest().into();
+      digest.map(|digest| (subdirectory.get_name(), digest))
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{compare_directory_digests, DirectoryDiff, MAX_REPORTED_ENTRIES};
+  use futures::Future;
+  use hashing::EMPTY_DIGEST;
+  use std::path::PathBuf;
+  use std::time::Duration;
+  use store::Store;
+  use tempfile::TempDir;
+  use testutil::data::{TestData, TestDirectory};
+  use workunit_store::WorkUnitStore;
+
+  ///
+  /// A Store whose local store is empty, so that every Directory which is loaded is read from the
+  /// given CAS.
+  ///
+  fn remote_store(cas: &mock::StubCAS) -> (Store, TempDir) {
+    let store_dir = TempDir::new().unwrap();
+    let store = Store::with_remote(
+      task_executor::Executor::new(),
+      store_dir.path(),
+      vec![cas.address()],
+      None,
+      None,
+      None,
+      None,
+      1,
+      10 * 1024 * 1024,
+      Duration::from_secs(1),
+      store::BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10)).unwrap(),
+      1,
+      1,
+    )
+    .unwrap();
+    (store, store_dir)
+  }
+
+  fn cas() -> mock::StubCAS {
+    let mut builder = mock::StubCAS::builder();
+    for directory in &[
+      TestDirectory::containing_roland(),
+      TestDirectory::containing_robin(),
+      TestDirectory::containing_wrong_roland(),
+      TestDirectory::containing_roland_and_treats(),
+      TestDirectory::containing_falcons_dir(),
+      TestDirectory::nested(),
+      TestDirectory::nested_dir_and_file(),
+      TestDirectory::recursive(),
+      TestDirectory::recursive_with_robin(),
+    ] {
+      builder = builder.directory(directory);
+    }
+    builder.build()
+  }
+
+  fn compare(store: &Store, left: &TestDirectory, right: &TestDirectory) -> DirectoryDiff {
+    compare_directory_digests(store, left.digest(), right.digest(), WorkUnitStore::new())
+      .wait()
+      .unwrap()
+  }
+
+  fn path(path: &str) -> PathBuf {
+    PathBuf::from(path)
+  }
+
+  #[test]
+  fn identical_directories_are_not_loaded() {
+    let cas = cas();
+    let (store, _store_dir) = remote_store(&cas);
+    let diff = compare(
+      &store,
+      &TestDirectory::recursive(),
+      &TestDirectory::recursive(),
+    );
+    assert!(diff.is_empty());
+    assert_eq!(format!("{}", diff), "no differences");
+    assert_eq!(cas.read_request_count(), 0);
+  }
+
+  #[test]
+  fn files_and_directories_are_compared_by_path() {
+    let cas = cas();
+    let (store, _store_dir) = remote_store(&cas);
+    let diff = compare(
+      &store,
+      &TestDirectory::containing_roland(),
+      &TestDirectory::recursive(),
+    );
+    assert_eq!(
+      diff,
+      DirectoryDiff {
+        added: vec![
+          (path("cats/roland"), TestData::roland().digest()),
+          (path("treats"), TestData::catnip().digest()),
+        ],
+        removed: vec![(path("roland"), TestData::roland().digest())],
+        changed: vec![],
+        unknown: vec![],
+      }
+    );
+  }
+
+  #[test]
+  fn changed_files_have_both_digests() {
+    let cas = cas();
+    let (store, _store_dir) = remote_store(&cas);
+    let diff = compare(
+      &store,
+      &TestDirectory::containing_roland(),
+      &TestDirectory::containing_wrong_roland(),
+    );
+    assert_eq!(
+      diff.changed,
+      vec![(
+        path("roland"),
+        TestData::roland().digest(),
+        TestData::catnip().digest()
+      )]
+    );
+    assert_eq!(
+      format!("{}", diff),
+      format!(
+        "changed roland ({}, {} bytes -> {}, {} bytes)",
+        TestData::roland().fingerprint(),
+        TestData::roland().len(),
+        TestData::catnip().fingerprint(),
+        TestData::catnip().len(),
+      )
+    );
+
+    let diff = compare(
+      &store,
+      &TestDirectory::recursive(),
+      &TestDirectory::recursive_with_robin(),
+    );
+    assert_eq!(
+      diff.added,
+      vec![(path("cats/robin"), TestData::robin().digest())]
+    );
+    assert_eq!(
+      diff.removed,
+      vec![(path("cats/roland"), TestData::roland().digest())]
+    );
+  }
+
+  #[test]
+  fn identical_subtrees_are_not_loaded() {
+    let cas = cas();
+    let (store, _store_dir) = remote_store(&cas);
+    // Both sides have cats/ (containing_roland), so only the roots and birds/ are loaded: the
+    // empty falcons/ is known without a load. Empty directories are not reported.
+    let diff = compare(
+      &store,
+      &TestDirectory::nested_dir_and_file(),
+      &TestDirectory::nested(),
+    );
+    assert!(diff.is_empty());
+    assert_eq!(cas.read_request_count(), 3);
+  }
+
+  #[test]
+  fn missing_subtrees_are_unknown() {
+    let cas = mock::StubCAS::builder()
+      .directory(&TestDirectory::recursive())
+      .directory(&TestDirectory::containing_roland_and_treats())
+      .build();
+    let (store, _store_dir) = remote_store(&cas);
+    let diff = compare(
+      &store,
+      &TestDirectory::recursive(),
+      &TestDirectory::containing_roland_and_treats(),
+    );
+    assert_eq!(
+      diff,
+      DirectoryDiff {
+        added: vec![(path("roland"), TestData::roland().digest())],
+        removed: vec![],
+        changed: vec![],
+        unknown: vec![(path("cats"), TestDirectory::containing_roland().digest())],
+      }
+    );
+
+    let missing = TestDirectory::containing_robin().digest();
+    let diff = compare_directory_digests(&store, EMPTY_DIGEST, missing, WorkUnitStore::new())
+      .wait()
+      .unwrap();
+    assert_eq!(diff.unknown, vec![(path(""), missing)]);
+  }
+
+  #[test]
+  fn display_caps_the_number_of_entries() {
+    let diff = DirectoryDiff {
+      added: (0..MAX_REPORTED_ENTRIES + 3)
+        .map(|i| (path(&format!("file{}", i)), TestData::roland().digest()))
+        .collect(),
+      ..DirectoryDiff::default()
+    };
+    let rendered = format!("{}", diff);
+    assert!(rendered.starts_with("added file0 ("));
+    assert_eq!(rendered.matches("added ").count(), MAX_REPORTED_ENTRIES);
+    assert!(rendered.ends_with("; … and 3 more"));
+  }
+}
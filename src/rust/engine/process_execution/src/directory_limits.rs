@@ -0,0 +1,423 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! Sanity limits for Directory trees which were provided by a server, so that a pathological tree
+//! (absurdly deep, absurdly large, or containing a Directory which references one of its own
+//! ancestors) fails with an error which names the offending chain of digests, rather than looping
+//! or exhausting the stack of the recursive walks which later process it.
+//!
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use bazel_protos::remote_execution::Directory;
+use boxfuture::{BoxFuture, Boxable};
+use futures::Future;
+use hashing::Digest;
+use store::Store;
+use workunit_store::WorkUnitStore;
+
+use crate::input_tree_stats::walk_levels;
+
+pub const DEFAULT_MAX_DIRECTORY_DEPTH: usize = 1024;
+
+pub const DEFAULT_MAX_DIRECTORY_COUNT: usize = 1_000_000;
+
+// The most digests of a chain which are rendered in an error: the rest are elided.
+const MAX_RENDERED_CHAIN_LENGTH: usize = 8;
+
+///
+/// The limits which a Directory tree must be within.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DirectoryLimits {
+  /// The deepest that a Directory may be nested below the root, which is at depth 0.
+  pub max_depth: usize,
+  /// The most Directories which the tree may contain, counting each position in the tree (so an
+  /// identical subtree which appears at several depths is counted at each of them).
+  pub max_directories: usize,
+}
+
+impl Default for DirectoryLimits {
+  fn default() -> DirectoryLimits {
+    DirectoryLimits {
+      max_depth: DEFAULT_MAX_DIRECTORY_DEPTH,
+      max_directories: DEFAULT_MAX_DIRECTORY_COUNT,
+    }
+  }
+}
+
+///
+/// Loads the Directory with the given digest, or None if it is unknown.
+///
+pub type LoadDirectory = Arc<dyn Fn(Digest) -> BoxFuture<Option<Directory>, String> + Send + Sync>;
+
+///
+/// Fails if the Directory tree with the given root, as loaded from the Store, is cyclic or is not
+/// within the limits.
+///
+pub fn check_directory_limits(
+  store: &Store,
+  root: Digest,
+  limits: DirectoryLimits,
+  workunit_store: WorkUnitStore,
+) -> BoxFuture<(), String> {
+  let store = store.clone();
+  check_directory_limits_with(
+    root,
+    limits,
+    Arc::new(move |digest| {
+      store
+        .load_directory(digest, workunit_store.clone())
+        .map(|maybe_directory| maybe_directory.map(|(directory, _metadata)| directory))
+        .to_boxed()
+    }),
+  )
+}
+
+///
+/// Like check_directory_limits, but loads Directories with the given function, so that trees which
+/// are not (yet) in a Store, such as those of Tree protos, can be checked before they are expanded.
+///
+/// The tree is walked a level at a time by input_tree_stats::walk_levels. A Directory which cannot
+/// be loaded is not checked: a consumer which needs it will fail to load it in turn.
+///
+pub fn check_directory_limits_with(
+  root: Digest,
+  limits: DirectoryLimits,
+  load: LoadDirectory,
+) -> BoxFuture<(), String> {
+  let visited = vec![Visited {
+    digest: root,
+    parent: None,
+  }];
+  let mut deepest = HashMap::new();
+  deepest.insert(root, 0);
+  walk_levels(
+    vec![(0, root)],
+    (visited, deepest, 0),
+    load,
+    move |(visited, deepest, depth), loaded| {
+      let child_depth = *depth + 1;
+      let mut next_level = vec![];
+      for (index, _digest, directory) in loaded {
+        let directory: Directory = match directory {
+          Some(directory) => directory,
+          None => continue,
+        };
+        for child in directory.get_directories() {
+          let digest: Result<Digest, String> = child.get_digest().into();
+          let digest = digest?;
+          if let Some(&previous_depth) = deepest.get(&digest) {
+            // Only a digest which was already seen can be one of our ancestors.
+            if is_ancestor(visited, index, digest) {
+              return Err(format!(
+                "Directory tree is cyclic: {}",
+                render_chain(visited, index, digest)
+              ));
+            }
+            if previous_depth == child_depth {
+              // An identical subtree at the same depth, which is already being checked.
+              continue;
+            }
+          }
+          if child_depth > limits.max_depth {
+            return Err(format!(
+              "Directory tree is nested more than {} deep: {}",
+              limits.max_depth,
+              render_chain(visited, index, digest)
+            ));
+          }
+          if visited.len() >= limits.max_directories {
+            return Err(format!(
+              "Directory tree contains more than {} directories, including: {}",
+              limits.max_directories,
+              render_chain(visited, index, digest)
+            ));
+          }
+          deepest.insert(digest, child_depth);
+          visited.push(Visited {
+            digest,
+            parent: Some(index),
+          });
+          next_level.push((visited.len() - 1, digest));
+        }
+      }
+      *depth = child_depth;
+      Ok(next_level)
+    },
+  )
+  .map(|_| ())
+  .to_boxed()
+}
+
+///
+/// A position in the tree: the digest of a Directory, and the index of the position of its parent.
+///
+struct Visited {
+  digest: Digest,
+  parent: Option<usize>,
+}
+
+fn ancestors(visited: &[Visited], index: usize) -> impl Iterator<Item = &Visited> {
+  let mut next = Some(index);
+  std::iter::from_fn(move || {
+    let current = &visited[next?];
+    next = current.parent;
+    Some(current)
+  })
+}
+
+fn is_ancestor(visited: &[Visited], index: usize, digest: Digest) -> bool {
+  ancestors(visited, index).any(|ancestor| ancestor.digest == digest)
+}
+
+///
+/// Renders the digests from the root to the position at index, followed by the given digest.
+///
+fn render_chain(visited: &[Visited], index: usize, digest: Digest) -> String {
+  let mut chain = ancestors(visited, index)
+    .map(|ancestor| ancestor.digest)
+    .collect::<Vec<_>>();
+  chain.reverse();
+  chain.push(digest);
+  let render = |digests: &[Digest]| {
+    digests
+      .iter()
+      .map(|digest| format!("{}/{}", digest.0, digest.1))
+      .collect::<Vec<_>>()
+      .join(" -> ")
+  };
+  if chain.len() <= MAX_RENDERED_CHAIN_LENGTH {
+    return render(&chain);
+  }
+  let half = MAX_RENDERED_CHAIN_LENGTH / 2;
+  format!(
+    "{} -> … ({} more) … -> {}",
+    render(&chain[..half]),
+    chain.len() - 2 * half,
+    render(&chain[chain.len() - half..])
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{
+    check_directory_limits, check_directory_limits_with, DirectoryLimits,
+    DEFAULT_MAX_DIRECTORY_DEPTH,
+  };
+  use bazel_protos::remote_execution::{Directory, DirectoryNode};
+  use boxfuture::Boxable;
+  use futures::future::{self, Future};
+  use hashing::{Digest, Fingerprint};
+  use protobuf::Message;
+  use std::collections::HashMap;
+  use std::sync::Arc;
+  use std::time::Duration;
+  use store::{BackoffConfig, Store};
+  use tempfile::TempDir;
+  use testutil::data::TestDirectory;
+  use workunit_store::WorkUnitStore;
+
+  fn containing(name: &str, digest: Digest) -> Directory {
+    let mut directory = Directory::new();
+    directory.mut_directories().push({
+      let mut node = DirectoryNode::new();
+      node.set_name(name.to_owned());
+      node.set_digest((&digest).into());
+      node
+    });
+    directory
+  }
+
+  fn fake_digest(byte: u8, directory: &Directory) -> Digest {
+    Digest(
+      Fingerprint([byte; 32]),
+      directory.write_to_bytes().unwrap().len(),
+    )
+  }
+
+  ///
+  /// Two Directories which contain one another, under digests which do not match their contents
+  /// (as no real digests could). All such Directories have the same size, because the sizes of
+  /// their children are encoded in a single byte.
+  ///
+  fn cyclic_pair() -> (Digest, Directory, Digest, Directory) {
+    let size = containing("x", Digest(Fingerprint([0; 32]), 1))
+      .write_to_bytes()
+      .unwrap()
+      .len();
+    let a_digest = Digest(Fingerprint([0xaa; 32]), size);
+    let b_digest = Digest(Fingerprint([0xbb; 32]), size);
+    let a = containing("b", b_digest);
+    let b = containing("a", a_digest);
+    (a_digest, a, b_digest, b)
+  }
+
+  fn check_in_memory(
+    directories: HashMap<Digest, Directory>,
+    root: Digest,
+    limits: DirectoryLimits,
+  ) -> Result<(), String> {
+    let directories = Arc::new(directories);
+    check_directory_limits_with(
+      root,
+      limits,
+      Arc::new(move |digest| future::ok(directories.get(&digest).cloned()).to_boxed()),
+    )
+    .wait()
+  }
+
+  #[test]
+  fn cycles_are_reported_with_their_chain() {
+    let (a_digest, a, b_digest, b) = cyclic_pair();
+    let mut directories = HashMap::new();
+    directories.insert(a_digest, a);
+    directories.insert(b_digest, b);
+
+    let error = check_in_memory(directories, a_digest, DirectoryLimits::default()).unwrap_err();
+    assert_eq!(
+      error,
+      format!(
+        "Directory tree is cyclic: {0}/{1} -> {2}/{3} -> {0}/{1}",
+        a_digest.0, a_digest.1, b_digest.0, b_digest.1
+      )
+    );
+  }
+
+  #[test]
+  fn identical_subtrees_are_not_cycles() {
+    let leaf = TestDirectory::containing_roland();
+    let mut both = Directory::new();
+    for name in &["cats", "dogs"] {
+      both.mut_directories().push({
+        let mut node = DirectoryNode::new();
+        node.set_name((*name).to_owned());
+        node.set_digest((&leaf.digest()).into());
+        node
+      });
+    }
+    let both_digest = fake_digest(0x01, &both);
+    let mut directories = HashMap::new();
+    directories.insert(both_digest, both);
+    directories.insert(leaf.digest(), leaf.directory());
+
+    assert_eq!(
+      check_in_memory(directories, both_digest, DirectoryLimits::default()),
+      Ok(())
+    );
+  }
+
+  #[test]
+  fn directory_count_is_limited() {
+    let nested = TestDirectory::nested();
+    let mut directories = HashMap::new();
+    directories.insert(nested.digest(), nested.directory());
+    let limits = DirectoryLimits {
+      max_directories: 1,
+      ..DirectoryLimits::default()
+    };
+
+    let error = check_in_memory(directories, nested.digest(), limits).unwrap_err();
+    assert!(
+      error.starts_with("Directory tree contains more than 1 directories, including: "),
+      "{}",
+      error
+    );
+  }
+
+  #[test]
+  fn deep_chains_fail_gracefully() {
+    let store_dir = TempDir::new().unwrap();
+    let executor = task_executor::Executor::new();
+    let store = Store::local_only(executor.clone(), store_dir.path()).unwrap();
+    let mut digests = vec![store
+      .record_directory(&Directory::new(), false)
+      .wait()
+      .unwrap()];
+    for _ in 0..10_000 {
+      let parent = containing("d", *digests.last().unwrap());
+      digests.push(store.record_directory(&parent, false).wait().unwrap());
+    }
+    let root = *digests.last().unwrap();
+
+    let error = executor
+      .block_on(check_directory_limits(
+        &store,
+        root,
+        DirectoryLimits::default(),
+        WorkUnitStore::new(),
+      ))
+      .unwrap_err();
+    assert!(
+      error.starts_with(&format!(
+        "Directory tree is nested more than {} deep: {}/{} -> ",
+        DEFAULT_MAX_DIRECTORY_DEPTH, root.0, root.1
+      )),
+      "{}",
+      error
+    );
+    assert!(
+      error.contains(&format!(
+        "… ({} more) …",
+        DEFAULT_MAX_DIRECTORY_DEPTH + 2 - 8
+      )),
+      "{}",
+      error
+    );
+
+    // Within a deep enough limit, the chain is fine.
+    let limits = DirectoryLimits {
+      max_depth: 10_000,
+      ..DirectoryLimits::default()
+    };
+    assert_eq!(
+      executor.block_on(check_directory_limits(
+        &store,
+        root,
+        limits,
+        WorkUnitStore::new()
+      )),
+      Ok(())
+    );
+  }
+
+  #[test]
+  fn cyclic_directories_from_a_server_fail_gracefully() {
+    let (a_digest, a, b_digest, b) = cyclic_pair();
+    let cas = mock::StubCAS::builder()
+      .unverified_content(a_digest.0, a.write_to_bytes().unwrap().into())
+      .unverified_content(b_digest.0, b.write_to_bytes().unwrap().into())
+      .build();
+    let store_dir = TempDir::new().unwrap();
+    let executor = task_executor::Executor::new();
+    let store = Store::with_remote(
+      executor.clone(),
+      store_dir.path(),
+      vec![cas.address()],
+      None,
+      None,
+      None,
+      None,
+      1,
+      10 * 1024 * 1024,
+      Duration::from_secs(1),
+      BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10)).unwrap(),
+      1,
+      1,
+    )
+    .unwrap();
+
+    // The Store refuses the Directories, because their digests do not match their contents.
+    let error = executor
+      .block_on(check_directory_limits(
+        &store,
+        a_digest,
+        DirectoryLimits::default(),
+        WorkUnitStore::new(),
+      ))
+      .unwrap_err();
+    assert!(error.contains("CAS gave wrong digest"), "{}", error);
+  }
+}
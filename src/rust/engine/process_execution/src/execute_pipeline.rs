@@ -0,0 +1,236 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! A small pool of long-lived pipelined Execute streams (see `bazel_protos::pipelining`), each of
+//! which carries any number of concurrent ExecuteRequests.
+//!
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use bazel_protos::operations::Operation;
+use bazel_protos::pipelining::{self, PIPELINED_EXECUTE};
+use bazel_protos::remote_execution::ExecuteRequest;
+use boxfuture::{try_future, BoxFuture, Boxable};
+use futures::sync::{mpsc, oneshot};
+use futures::{future, Future, Sink, Stream};
+use grpcio::WriteFlags;
+
+// The maximum number of streams which are open at once.
+const MAX_STREAMS: usize = 4;
+// The number of in-flight requests on every open stream at which another stream is opened.
+const MAX_IN_FLIGHT_PER_STREAM: usize = 64;
+
+#[derive(Clone, Debug)]
+pub enum PipelinedOutcome {
+  // The first Operation which the server sent for the request.
+  Operation(Operation),
+  // The server does not support pipelining, so the request should be sent on its own stream.
+  Unsupported,
+}
+
+// Completed with an Err if the stream ends before the server responds to the request.
+type Pending = oneshot::Sender<Result<PipelinedOutcome, String>>;
+
+///
+/// Cheap to clone: all clones share a pool of streams.
+///
+#[derive(Clone)]
+pub struct ExecutePipeline {
+  client: grpcio::Client,
+  executor: task_executor::Executor,
+  next_tag: Arc<AtomicUsize>,
+  streams: Arc<Mutex<Vec<PipelineStream>>>,
+  // Set once a stream has ended in a way which shows that the server does not support
+  // pipelining, after which no more streams are opened.
+  unsupported: Arc<AtomicBool>,
+  unsupported_noted: Arc<AtomicBool>,
+}
+
+#[derive(Clone)]
+struct PipelineStream {
+  requests: mpsc::UnboundedSender<ExecuteRequest>,
+  state: Arc<Mutex<StreamState>>,
+}
+
+struct StreamState {
+  open: bool,
+  // The requests which have been sent on the stream, but not yet responded to, by tag.
+  pending: HashMap<String, Pending>,
+}
+
+enum StreamEnd {
+  Failed(grpcio::Error),
+  Untagged,
+}
+
+impl ExecutePipeline {
+  ///
+  /// Streams are opened lazily on the given channel, and driven by tasks spawned on the given
+  /// Executor.
+  ///
+  pub fn new(channel: grpcio::Channel, executor: task_executor::Executor) -> ExecutePipeline {
+    ExecutePipeline {
+      client: grpcio::Client::new(channel),
+      executor,
+      next_tag: Arc::new(AtomicUsize::new(0)),
+      streams: Arc::new(Mutex::new(vec![])),
+      unsupported: Arc::new(AtomicBool::new(false)),
+      unsupported_noted: Arc::new(AtomicBool::new(false)),
+    }
+  }
+
+  ///
+  /// Returns true the first time that it is called after the server has been found not to
+  /// support pipelining, so that the fallback can be reported once.
+  ///
+  pub fn note_unsupported(&self) -> bool {
+    self.unsupported.load(Ordering::SeqCst) && !self.unsupported_noted.swap(true, Ordering::SeqCst)
+  }
+
+  ///
+  /// Sends the request on the least loaded open stream (opening one if need be), and waits for
+  /// the server's first Operation for it. Fails (without affecting the requests on other streams)
+  /// if the stream ends before the server responds.
+  ///
+  pub fn execute(
+    &self,
+    execute_request: &ExecuteRequest,
+    call_option: grpcio::CallOption,
+  ) -> BoxFuture<PipelinedOutcome, String> {
+    if self.unsupported.load(Ordering::SeqCst) {
+      return future::ok(PipelinedOutcome::Unsupported).to_boxed();
+    }
+    let stream = try_future!(self.stream(call_option));
+
+    let tag = self.next_tag.fetch_add(1, Ordering::SeqCst).to_string();
+    let (sender, receiver) = oneshot::channel();
+    {
+      let mut state = stream.state.lock().unwrap();
+      if !state.open {
+        // The stream ended since it was chosen.
+        return if self.unsupported.load(Ordering::SeqCst) {
+          future::ok(PipelinedOutcome::Unsupported).to_boxed()
+        } else {
+          future::err("The pipelined Execute stream was closed".to_owned()).to_boxed()
+        };
+      }
+      state.pending.insert(tag.clone(), sender);
+    }
+
+    let mut request = execute_request.clone();
+    pipelining::set_tag(&mut request, &tag);
+    if stream.requests.unbounded_send(request).is_err() {
+      stream.state.lock().unwrap().pending.remove(&tag);
+      return future::err("The pipelined Execute stream was closed".to_owned()).to_boxed();
+    }
+
+    receiver
+      .then(|result| match result {
+        Ok(outcome) => outcome,
+        Err(oneshot::Canceled) => Err("The pipelined Execute stream was dropped".to_owned()),
+      })
+      .to_boxed()
+  }
+
+  fn stream(&self, call_option: grpcio::CallOption) -> Result<PipelineStream, String> {
+    let mut streams = self.streams.lock().unwrap();
+    streams.retain(|stream| stream.state.lock().unwrap().open);
+    let least_loaded = streams
+      .iter()
+      .map(|stream| (stream.in_flight(), stream))
+      .min_by_key(|&(in_flight, _)| in_flight)
+      .map(|(in_flight, stream)| (in_flight, stream.clone()));
+    match least_loaded {
+      Some((in_flight, stream))
+        if in_flight < MAX_IN_FLIGHT_PER_STREAM || streams.len() >= MAX_STREAMS =>
+      {
+        Ok(stream)
+      }
+      _ => {
+        let stream = self.open(call_option)?;
+        streams.push(stream.clone());
+        Ok(stream)
+      }
+    }
+  }
+
+  fn open(&self, call_option: grpcio::CallOption) -> Result<PipelineStream, String> {
+    let (sink, receiver) = self
+      .client
+      .duplex_streaming(&PIPELINED_EXECUTE, call_option)
+      .map_err(|e| format!("Error opening pipelined Execute stream: {:?}", e))?;
+    let (requests, queued) = mpsc::unbounded();
+    let state = Arc::new(Mutex::new(StreamState {
+      open: true,
+      pending: HashMap::new(),
+    }));
+
+    // The stream is half-closed once every clone of its sender has been dropped. Failures to send
+    // are observed (and reported) by the receiving task.
+    self.executor.spawn_and_ignore(
+      sink
+        .send_all(
+          queued
+            .map(|request| (request, WriteFlags::default()))
+            .map_err(|()| grpcio::Error::RemoteStopped),
+        )
+        .then(|_| Ok(())),
+    );
+
+    let routing_state = state.clone();
+    let closing_state = state.clone();
+    let unsupported = self.unsupported.clone();
+    self.executor.spawn_and_ignore(
+      receiver
+        .map_err(StreamEnd::Failed)
+        .for_each(move |operation| -> Result<(), StreamEnd> {
+          let tag = pipelining::tag(&operation).ok_or(StreamEnd::Untagged)?;
+          let pending = routing_state.lock().unwrap().pending.remove(&tag);
+          if let Some(pending) = pending {
+            // The request may have been dropped in the meantime.
+            let _ = pending.send(Ok(PipelinedOutcome::Operation(pipelining::without_tag(
+              &operation,
+            ))));
+          }
+          Ok(())
+        })
+        .then(move |result| {
+          let outcome = match result {
+            Err(StreamEnd::Failed(grpcio::Error::RpcFailure(ref status)))
+              if status.status == grpcio::RpcStatusCode::Unimplemented =>
+            {
+              Ok(PipelinedOutcome::Unsupported)
+            }
+            Err(StreamEnd::Untagged) => Ok(PipelinedOutcome::Unsupported),
+            Err(StreamEnd::Failed(e)) => {
+              Err(format!("The pipelined Execute stream failed: {:?}", e))
+            }
+            Ok(()) => Err("The server closed the pipelined Execute stream".to_owned()),
+          };
+          if let Ok(PipelinedOutcome::Unsupported) = outcome {
+            unsupported.store(true, Ordering::SeqCst);
+          }
+          let pending = {
+            let mut state = closing_state.lock().unwrap();
+            state.open = false;
+            std::mem::replace(&mut state.pending, HashMap::new())
+          };
+          for (_, pending) in pending {
+            let _ = pending.send(outcome.clone());
+          }
+          Ok(())
+        }),
+    );
+
+    Ok(PipelineStream { requests, state })
+  }
+}
+
+impl PipelineStream {
+  fn in_flight(&self) -> usize {
+    self.state.lock().unwrap().pending.len()
+  }
+}
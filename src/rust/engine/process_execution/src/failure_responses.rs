@@ -0,0 +1,230 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! A bounded index of the raw ExecuteResponses of recently failed remote actions, for debugging
+//! flaky actions after the fact: the responses themselves (including any server metadata and
+//! logs) are stored in the local Store, and the index records which action each belongs to.
+//!
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use hashing::{Digest, Fingerprint};
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::report::write_replacing;
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct RetainedFailure {
+  pub action_digest: Digest,
+  // The digest of the serialized ExecuteResponse, as stored in the local Store.
+  pub response_digest: Digest,
+  pub recorded_at: SystemTime,
+}
+
+// Digest only implements Serialize, so the index is read back via these mirrors of its format.
+#[derive(Deserialize)]
+struct PersistedFailure {
+  action_digest: PersistedDigest,
+  response_digest: PersistedDigest,
+  recorded_at: SystemTime,
+}
+
+#[derive(Deserialize)]
+struct PersistedDigest {
+  fingerprint: String,
+  size_bytes: usize,
+}
+
+impl PersistedDigest {
+  fn into_digest(self) -> Result<Digest, String> {
+    Ok(Digest(
+      Fingerprint::from_hex_string(&self.fingerprint)?,
+      self.size_bytes,
+    ))
+  }
+}
+
+///
+/// Cheap to clone: all clones share an index.
+///
+#[derive(Clone)]
+pub struct FailureResponseIndex {
+  capacity: usize,
+  // None if the index is not persisted.
+  path: Option<PathBuf>,
+  // Lazily loaded from `path`, oldest first, with the number of failures recorded since then.
+  entries: Arc<Mutex<(Option<Vec<RetainedFailure>>, usize)>>,
+  // Held while the index is written (outside of the lock of the entries, so that writing does not
+  // block recording), with the number of recorded failures that the index on disk includes, so
+  // that a write which was overtaken by a later one does not replace it.
+  written: Arc<Mutex<usize>>,
+}
+
+impl FailureResponseIndex {
+  ///
+  /// An index of the most recent `capacity` failures, persisted as JSON at `path`.
+  ///
+  pub fn new(capacity: usize, path: PathBuf) -> FailureResponseIndex {
+    FailureResponseIndex {
+      capacity,
+      path: Some(path),
+      entries: Arc::new(Mutex::new((None, 0))),
+      written: Arc::new(Mutex::new(0)),
+    }
+  }
+
+  ///
+  /// An index of the most recent `capacity` failures, which is not persisted: for Stores which are
+  /// themselves kept in memory.
+  ///
+  pub fn in_memory(capacity: usize) -> FailureResponseIndex {
+    FailureResponseIndex {
+      capacity,
+      path: None,
+      entries: Arc::new(Mutex::new((Some(vec![]), 0))),
+      written: Arc::new(Mutex::new(0)),
+    }
+  }
+
+  pub fn record(&self, action_digest: Digest, response_digest: Digest) -> Result<(), String> {
+    let (entries, recorded) = {
+      let mut guard = self.entries.lock().unwrap();
+      let (ref mut entries, ref mut recorded) = *guard;
+      let entries = entries.get_or_insert_with(|| self.load());
+      entries.push(RetainedFailure {
+        action_digest,
+        response_digest,
+        recorded_at: SystemTime::now(),
+      });
+      if entries.len() > self.capacity {
+        let excess = entries.len() - self.capacity;
+        entries.drain(..excess);
+      }
+      *recorded += 1;
+      match self.path {
+        Some(_) => (entries.clone(), *recorded),
+        None => return Ok(()),
+      }
+    };
+
+    let mut written = self.written.lock().unwrap();
+    if *written > recorded {
+      return Ok(());
+    }
+    Self::persist(self.path.as_ref().unwrap(), &entries)?;
+    *written = recorded;
+    Ok(())
+  }
+
+  ///
+  /// The retained failures, oldest first.
+  ///
+  pub fn entries(&self) -> Vec<RetainedFailure> {
+    let mut guard = self.entries.lock().unwrap();
+    guard.0.get_or_insert_with(|| self.load()).clone()
+  }
+
+  ///
+  /// Loads a previously persisted index. A missing index is empty, and an unreadable one is
+  /// discarded, because it only exists to aid debugging.
+  ///
+  fn load(&self) -> Vec<RetainedFailure> {
+    let path = match self.path {
+      Some(ref path) => path,
+      None => return vec![],
+    };
+    let content = match fs::read_to_string(path) {
+      Ok(content) => content,
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound => return vec![],
+      Err(e) => {
+        warn!(
+          "Discarding unreadable failure index {}: {}",
+          path.display(),
+          e
+        );
+        return vec![];
+      }
+    };
+    let parsed = serde_json::from_str::<Vec<PersistedFailure>>(&content)
+      .map_err(|e| e.to_string())
+      .and_then(|persisted| {
+        persisted
+          .into_iter()
+          .map(|failure| {
+            Ok(RetainedFailure {
+              action_digest: failure.action_digest.into_digest()?,
+              response_digest: failure.response_digest.into_digest()?,
+              recorded_at: failure.recorded_at,
+            })
+          })
+          .collect::<Result<Vec<_>, String>>()
+      });
+    parsed.unwrap_or_else(|e| {
+      warn!("Discarding corrupt failure index {}: {}", path.display(), e);
+      vec![]
+    })
+  }
+
+  fn persist(path: &Path, entries: &[RetainedFailure]) -> Result<(), String> {
+    let json = serde_json::to_string(entries)
+      .map_err(|e| format!("Error serializing failure index: {}", e))?;
+    write_replacing(path, json.as_bytes(), "failure index")
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use hashing::{Digest, Fingerprint, EMPTY_DIGEST};
+  use tempfile::TempDir;
+
+  use super::FailureResponseIndex;
+
+  #[test]
+  fn keeps_most_recent_failures() {
+    let dir = TempDir::new().unwrap();
+    let index = FailureResponseIndex::new(2, dir.path().join("index.json"));
+    for i in 1..4 {
+      index.record(digest(i), EMPTY_DIGEST).unwrap();
+    }
+
+    let actions = index
+      .entries()
+      .into_iter()
+      .map(|failure| failure.action_digest)
+      .collect::<Vec<_>>();
+    assert_eq!(actions, vec![digest(2), digest(3)]);
+  }
+
+  #[test]
+  fn index_is_persisted() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("index.json");
+    let index = FailureResponseIndex::new(2, path.clone());
+    index.record(digest(1), digest(2)).unwrap();
+
+    let reloaded = FailureResponseIndex::new(2, path);
+    assert_eq!(reloaded.entries(), index.entries());
+  }
+
+  #[test]
+  fn corrupt_index_is_discarded() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("index.json");
+    std::fs::write(&path, "not json").unwrap();
+
+    let index = FailureResponseIndex::new(2, path);
+    assert_eq!(index.entries(), vec![]);
+    index.record(digest(1), digest(2)).unwrap();
+    assert_eq!(index.entries().len(), 1);
+  }
+
+  fn digest(byte: u8) -> Digest {
+    Digest(Fingerprint([byte; 32]), byte as usize)
+  }
+}
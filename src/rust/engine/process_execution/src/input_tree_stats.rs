@@ -0,0 +1,363 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! The total size, file count and directory count of input roots, which are computed by walking
+//! their Directory protos, and memoized by digest: requests often share an input root (or differ
+//! only in their argv), so a walk of the same tree for each of them would mostly be wasted.
+//!
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use bazel_protos::remote_execution::Directory;
+use boxfuture::{BoxFuture, Boxable};
+use futures::future::{self, Loop};
+use futures::{stream, Future, Stream};
+use hashing::{Digest, EMPTY_DIGEST};
+use serde_derive::Serialize;
+use store::Store;
+use workunit_store::WorkUnitStore;
+
+use crate::directory_limits::LoadDirectory;
+use crate::metrics;
+
+pub const DEFAULT_MAX_ENTRIES: usize = 1024;
+
+// The most Directory protos which are loaded at once while walking a tree.
+const MAX_CONCURRENT_LOADS: usize = 16;
+
+///
+/// The contents of a Directory tree, counting each position in the tree (so an identical subtree
+/// which appears in several places is counted at each of them).
+///
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct InputTreeStats {
+  // The total size of the files of the tree.
+  pub bytes: u64,
+  pub files: u64,
+  // The Directories of the tree, including its root.
+  pub dirs: u64,
+}
+
+///
+/// Cheap to clone: all clones share their memoized stats.
+///
+#[derive(Clone)]
+pub struct InputTreeStatsCache {
+  max_entries: usize,
+  state: Arc<Mutex<CacheState>>,
+}
+
+struct CacheState {
+  // By root digest, each with the tick at which it was last used.
+  stats: HashMap<Digest, (InputTreeStats, u64)>,
+  tick: u64,
+}
+
+impl InputTreeStatsCache {
+  ///
+  /// A cache of the stats of at most `max_entries` trees, which evicts the least recently used.
+  ///
+  pub fn new(max_entries: usize) -> InputTreeStatsCache {
+    InputTreeStatsCache {
+      max_entries,
+      state: Arc::new(Mutex::new(CacheState {
+        stats: HashMap::new(),
+        tick: 0,
+      })),
+    }
+  }
+
+  ///
+  /// The stats of the tree with the given root, as loaded from the Store. Fails if any Directory
+  /// of the tree is not in the Store.
+  ///
+  pub fn input_tree_stats(
+    &self,
+    store: &Store,
+    root: Digest,
+    workunit_store: WorkUnitStore,
+  ) -> BoxFuture<InputTreeStats, String> {
+    let store = store.clone();
+    let load_workunit_store = workunit_store.clone();
+    self.input_tree_stats_with(
+      root,
+      Arc::new(move |digest| {
+        store
+          .load_directory(digest, load_workunit_store.clone())
+          .map(|maybe_directory| maybe_directory.map(|(directory, _metadata)| directory))
+          .to_boxed()
+      }),
+      &workunit_store,
+    )
+  }
+
+  ///
+  /// Like input_tree_stats, but loads Directories with the given function.
+  ///
+  pub fn input_tree_stats_with(
+    &self,
+    root: Digest,
+    load: LoadDirectory,
+    workunit_store: &WorkUnitStore,
+  ) -> BoxFuture<InputTreeStats, String> {
+    if let Some(stats) = self.get(root) {
+      workunit_store.increment_counter(metrics::REMOTE_INPUT_TREE_STATS_HITS, 1);
+      return future::ok(stats).to_boxed();
+    }
+    workunit_store.increment_counter(metrics::REMOTE_INPUT_TREE_STATS_MISSES, 1);
+    let cache = self.clone();
+    walk(root, load)
+      .map(move |stats| {
+        cache.insert(root, stats);
+        stats
+      })
+      .to_boxed()
+  }
+
+  fn get(&self, root: Digest) -> Option<InputTreeStats> {
+    let mut state = self.state.lock().unwrap();
+    state.tick += 1;
+    let tick = state.tick;
+    state.stats.get_mut(&root).map(|(stats, last_used)| {
+      *last_used = tick;
+      *stats
+    })
+  }
+
+  fn insert(&self, root: Digest, stats: InputTreeStats) {
+    let mut state = self.state.lock().unwrap();
+    if self.max_entries == 0 || state.stats.contains_key(&root) {
+      return;
+    }
+    // Evict the least recently used stats until there is room for the new ones.
+    while state.stats.len() >= self.max_entries {
+      let oldest = state
+        .stats
+        .iter()
+        .min_by_key(|(_, (_, last_used))| *last_used)
+        .map(|(digest, _)| *digest);
+      match oldest {
+        Some(oldest) => {
+          state.stats.remove(&oldest);
+        }
+        None => return,
+      }
+    }
+    state.tick += 1;
+    let tick = state.tick;
+    state.stats.insert(root, (stats, tick));
+  }
+}
+
+///
+/// Walks the tree a level at a time, loading at most MAX_CONCURRENT_LOADS Directories at once, so
+/// its depth does not affect the depth of the stack.
+///
+/// Each level is a list of keyed digests, starting from the given one. `visit` is given the
+/// Directories of a level as they were loaded (None for those which are unknown), and returns the
+/// next level: the walk ends with the final state once a level is empty. This is shared by the
+/// walks which compute stats, and those which check the limits of trees from servers.
+///
+pub(crate) fn walk_levels<K, S, V>(
+  level: Vec<(K, Digest)>,
+  state: S,
+  load: LoadDirectory,
+  visit: V,
+) -> BoxFuture<S, String>
+where
+  K: Send + 'static,
+  S: Send + 'static,
+  V: FnMut(&mut S, Vec<(K, Digest, Option<Directory>)>) -> Result<Vec<(K, Digest)>, String>
+    + Send
+    + 'static,
+{
+  future::loop_fn(
+    (state, visit, level),
+    move |(mut state, mut visit, level)| {
+      let load = load.clone();
+      stream::iter_ok(level)
+        .map(move |(key, digest)| {
+          let directory = if digest == EMPTY_DIGEST {
+            future::ok(Some(Directory::new())).to_boxed()
+          } else {
+            load(digest)
+          };
+          directory.map(move |directory| (key, digest, directory))
+        })
+        .buffer_unordered(MAX_CONCURRENT_LOADS)
+        .collect()
+        .and_then(move |loaded| -> Result<_, String> {
+          let next_level = visit(&mut state, loaded)?;
+          if next_level.is_empty() {
+            Ok(Loop::Break(state))
+          } else {
+            Ok(Loop::Continue((state, visit, next_level)))
+          }
+        })
+    },
+  )
+  .to_boxed()
+}
+
+///
+/// Walks the tree to compute its stats. Each Directory of a level is loaded once, however many
+/// times it appears in the level.
+///
+fn walk(root: Digest, load: LoadDirectory) -> BoxFuture<InputTreeStats, String> {
+  walk_levels(
+    vec![(1, root)],
+    InputTreeStats::default(),
+    load,
+    |stats, loaded| {
+      let mut next_level = HashMap::new();
+      for (count, digest, directory) in loaded {
+        let directory = directory.ok_or_else(|| {
+          format!(
+            "Directory {:?} of the input tree is not in the Store",
+            digest
+          )
+        })?;
+        stats.dirs += count;
+        for file in directory.get_files() {
+          stats.files += count;
+          stats.bytes += count * file.get_digest().get_size_bytes() as u64;
+        }
+        for child in directory.get_directories() {
+          let digest: Result<Digest, String> = child.get_digest().into();
+          *next_level.entry(digest?).or_insert(0) += count;
+        }
+      }
+      Ok(
+        next_level
+          .into_iter()
+          .map(|(digest, count)| (count, digest))
+          .collect(),
+      )
+    },
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::sync::Arc;
+
+  use bazel_protos::remote_execution::Directory;
+  use boxfuture::Boxable;
+  use futures::{future, Future};
+  use hashing::Digest;
+  use testutil::data::{TestData, TestDirectory};
+  use workunit_store::WorkUnitStore;
+
+  use super::{InputTreeStats, InputTreeStatsCache};
+  use crate::directory_limits::LoadDirectory;
+  use crate::metrics;
+
+  ///
+  /// Loads the given Directories, counting the loads.
+  ///
+  fn counting_loader(directories: &[TestDirectory], loads: &Arc<AtomicUsize>) -> LoadDirectory {
+    let directories: HashMap<Digest, Directory> = directories
+      .iter()
+      .map(|directory| (directory.digest(), directory.directory()))
+      .collect();
+    let loads = loads.clone();
+    Arc::new(move |digest| {
+      loads.fetch_add(1, Ordering::SeqCst);
+      future::ok(directories.get(&digest).cloned()).to_boxed()
+    })
+  }
+
+  #[test]
+  fn stats_of_a_recursive_tree_are_memoized() {
+    let cache = InputTreeStatsCache::new(8);
+    let loads = Arc::new(AtomicUsize::new(0));
+    let workunit_store = WorkUnitStore::new();
+    let load = counting_loader(
+      &[
+        TestDirectory::recursive(),
+        TestDirectory::containing_roland(),
+      ],
+      &loads,
+    );
+    let root = TestDirectory::recursive().digest();
+
+    let want = InputTreeStats {
+      bytes: (TestData::roland().len() + TestData::catnip().len()) as u64,
+      files: 2,
+      dirs: 2,
+    };
+    assert_eq!(
+      cache
+        .input_tree_stats_with(root, load.clone(), &workunit_store)
+        .wait(),
+      Ok(want)
+    );
+    assert_eq!(loads.load(Ordering::SeqCst), 2);
+
+    // Clones share the memoized stats, so the second request does not load the tree again.
+    assert_eq!(
+      cache
+        .clone()
+        .input_tree_stats_with(root, load, &workunit_store)
+        .wait(),
+      Ok(want)
+    );
+    assert_eq!(loads.load(Ordering::SeqCst), 2);
+
+    let counters = workunit_store.get_counters();
+    assert_eq!(counters[metrics::REMOTE_INPUT_TREE_STATS_HITS], 1);
+    assert_eq!(counters[metrics::REMOTE_INPUT_TREE_STATS_MISSES], 1);
+  }
+
+  #[test]
+  fn least_recently_used_stats_are_evicted() {
+    let cache = InputTreeStatsCache::new(1);
+    let loads = Arc::new(AtomicUsize::new(0));
+    let workunit_store = WorkUnitStore::new();
+    let load = counting_loader(
+      &[
+        TestDirectory::containing_roland(),
+        TestDirectory::containing_robin(),
+      ],
+      &loads,
+    );
+    let stats = |directory: TestDirectory| {
+      cache
+        .input_tree_stats_with(directory.digest(), load.clone(), &workunit_store)
+        .wait()
+        .unwrap()
+    };
+
+    stats(TestDirectory::containing_roland());
+    stats(TestDirectory::containing_robin());
+    stats(TestDirectory::containing_roland());
+    assert_eq!(loads.load(Ordering::SeqCst), 3);
+  }
+
+  #[test]
+  fn missing_directories_fail() {
+    let cache = InputTreeStatsCache::new(8);
+    let loads = Arc::new(AtomicUsize::new(0));
+    let load = counting_loader(&[TestDirectory::recursive()], &loads);
+    let err = cache
+      .input_tree_stats_with(
+        TestDirectory::recursive().digest(),
+        load,
+        &WorkUnitStore::new(),
+      )
+      .wait()
+      .unwrap_err();
+    assert!(
+      err.contains(&format!(
+        "{:?}",
+        TestDirectory::containing_roland().digest()
+      )),
+      "Error should name the missing Directory: {}",
+      err
+    );
+  }
+}
@@ -29,26 +29,95 @@
 #[macro_use]
 extern crate derivative;
 
-use boxfuture::BoxFuture;
+use boxfuture::{BoxFuture, Boxable};
 use bytes::Bytes;
-use std::collections::{BTreeMap, BTreeSet};
+use futures::{future, Future, Stream};
+use regex::Regex;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::convert::TryFrom;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
 use std::ops::AddAssign;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use store::UploadSummary;
+use store::{Store, UploadSummary};
 use workunit_store::WorkUnitStore;
 
 use async_semaphore::AsyncSemaphore;
 
+pub mod action;
+pub mod argfile;
+pub mod blob_cache;
+#[cfg(feature = "remote-execution")]
 pub mod cache;
+pub mod capabilities;
+pub mod directory_diff;
+pub mod directory_limits;
+#[cfg(feature = "remote-execution")]
+mod execute_pipeline;
+pub mod failure_responses;
+pub mod input_tree_stats;
 pub mod local;
+pub mod local_conformance;
+pub mod metrics;
+pub mod operation_name;
+pub mod output_size;
+pub mod polling_throttle;
+#[macro_use]
+pub mod profiling;
+#[cfg(test)]
+mod proptests;
+#[cfg(feature = "remote-execution")]
+pub mod rejections;
+#[cfg(feature = "remote-execution")]
 pub mod remote;
+#[cfg(feature = "remote_conformance")]
+pub mod remote_conformance;
+#[cfg(all(feature = "remote-execution", any(test, feature = "test_environment")))]
+pub mod remote_test_environment;
+pub mod report;
+pub mod retry_budget;
+pub mod routing;
+#[cfg(feature = "runner_service")]
+pub mod runner_service;
+pub mod scheduling_hints;
+pub mod shadow;
 pub mod speculate;
+pub mod time_budget;
+#[cfg(feature = "remote-execution")]
+pub mod trace;
+pub mod upload_coalescing;
+pub mod upload_gate;
+pub mod verify;
+#[cfg(feature = "remote-execution")]
+pub mod violations;
+
+use crate::output_size::{ExpectedOutputSize, OutputSizeAnomaly};
+use crate::scheduling_hints::SchedulingHints;
 
 extern crate uname;
 
+///
+/// Fails unless this build supports remote execution. The remote CommandRunner (and the modules
+/// which only it uses) are only built with the `remote-execution` feature, so embedders which
+/// decide at runtime whether to execute remotely should call this first, to fail with an
+/// explanation rather than needing the module to exist.
+///
+pub fn check_remote_execution_supported() -> Result<(), String> {
+  if cfg!(feature = "remote-execution") {
+    Ok(())
+  } else {
+    Err(
+      "Remote execution was configured, but this build of the engine does not support it: it was \
+       built without the `remote-execution` feature of the process_execution crate."
+        .to_owned(),
+    )
+  }
+}
+
 #[derive(PartialOrd, Ord, Clone, Copy, Debug, Eq, PartialEq, Hash)]
 pub enum Platform {
   Darwin,
@@ -75,7 +144,8 @@ impl TryFrom<&String> for Platform {
   ///
   fn try_from(variant_candidate: &String) -> Result<Self, Self::Error> {
     match variant_candidate.as_ref() {
-      "darwin" => Ok(Platform::Darwin),
+      // `String::from(Platform)` renders Darwin as "osx".
+      "darwin" | "osx" => Ok(Platform::Darwin),
       "linux" => Ok(Platform::Linux),
       "none" => Ok(Platform::None),
       other => Err(format!(
@@ -96,11 +166,97 @@ impl From<Platform> for String {
   }
 }
 
+///
+/// Where a process may be executed. A process which must run locally (e.g. because it uses local
+/// hardware, or licensed tools) is cached under the same key as it would be if it could run
+/// anywhere, because its locality does not affect its Action digest. But it only shares its
+/// results through the local process cache (see the cache module): it is never sent to the
+/// server, and there is no client of the server's ActionCache which would read or write results
+/// for it there.
+///
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum ExecutionLocality {
+  Any,
+  LocalOnly,
+  RemoteOnly,
+}
+
+impl ExecutionLocality {
+  pub fn allows_local(self) -> bool {
+    self != ExecutionLocality::RemoteOnly
+  }
+
+  pub fn allows_remote(self) -> bool {
+    self != ExecutionLocality::LocalOnly
+  }
+
+  ///
+  /// Checks whether a process with this locality may be run by a CommandRunner which runs processes
+  /// remotely (or, if `remote` is false, locally). Runners do not report a request which their
+  /// locality violates as compatible, so that runners which choose between others (such as
+  /// SpeculatingCommandRunner) choose one which honors it, and never fall back to one which does
+  /// not: a runner is only asked to run such a request directly. In strict mode that is an error,
+  /// and otherwise the returned warning describes it, and the process runs anyway.
+  ///
+  pub fn check(
+    self,
+    description: &str,
+    remote: bool,
+    strict: bool,
+  ) -> Result<Option<String>, String> {
+    let allowed = if remote {
+      self.allows_remote()
+    } else {
+      self.allows_local()
+    };
+    if allowed {
+      return Ok(None);
+    }
+    let (wanted, actual) = if remote {
+      ("locally", "remotely")
+    } else {
+      ("remotely", "locally")
+    };
+    if strict {
+      return Err(format!(
+        "{} may only run {} ({:?}), but no CommandRunner which runs processes {} is \
+         configured, and execution locality is strict.",
+        description, wanted, self, wanted
+      ));
+    }
+    Ok(Some(format!(
+      "{} may only run {} ({:?}), but no CommandRunner which runs processes {} is configured: \
+       running it {}.",
+      description, wanted, self, wanted, actual
+    )))
+  }
+}
+
+impl TryFrom<&String> for ExecutionLocality {
+  type Error = String;
+  ///
+  /// Converts the execution_locality of a python ExecuteProcessRequest into an ExecutionLocality.
+  ///
+  fn try_from(variant_candidate: &String) -> Result<Self, Self::Error> {
+    match variant_candidate.as_ref() {
+      "any" => Ok(ExecutionLocality::Any),
+      "local_only" => Ok(ExecutionLocality::LocalOnly),
+      "remote_only" => Ok(ExecutionLocality::RemoteOnly),
+      other => Err(format!(
+        "Unknown execution locality {:?} encountered in parsing",
+        other
+      )),
+    }
+  }
+}
+
 ///
 /// A process to be executed.
 ///
-#[derive(Derivative, Clone, Debug, Eq)]
-#[derivative(PartialEq, Hash)]
+/// Requests are equal (and hash and order alike) if they differ only in their descriptions or in
+/// the order of their ephemeral_input_digests.
+///
+#[derive(Clone, Debug)]
 pub struct ExecuteProcessRequest {
   ///
   /// The arguments to execute.
@@ -125,9 +281,12 @@ pub struct ExecuteProcessRequest {
 
   pub output_directories: BTreeSet<PathBuf>,
 
+  ///
+  /// How long a remote execution may take before it is cancelled. A zero timeout means that no
+  /// client-side timeout is applied, although the server may still apply its own.
+  ///
   pub timeout: std::time::Duration,
 
-  #[derivative(PartialEq = "ignore", Hash = "ignore")]
   pub description: String,
 
   ///
@@ -140,6 +299,181 @@ pub struct ExecuteProcessRequest {
   ///
   pub jdk_home: Option<PathBuf>,
   pub target_platform: Platform,
+
+  ///
+  /// If true, any cached result for this process will be ignored, and it will be re-executed.
+  ///
+  /// This does not affect the Action digest of the process (and so the result of a forced
+  /// re-execution will replace any previously cached result), which makes it useful for debugging
+  /// flaky or non-deterministic processes without needing to change their inputs.
+  ///
+  pub force_rerun: bool,
+
+  ///
+  /// If present, the digest that the process's output directory is expected to have: if the
+  /// outputs differ, the process fails. Like force_rerun, this does not affect the Action digest.
+  ///
+  pub expected_output_digest: Option<hashing::Digest>,
+
+  ///
+  /// If true, a mismatch with the expected_output_digest is reported along with the paths of the
+  /// files which were added, removed or changed.
+  ///
+  pub diff_outputs: bool,
+
+  ///
+  /// Digests (contained in the input_files) which are only used by this run, like generated
+  /// options files. When executing remotely, they are uploaded with short leases, so that they
+  /// don't consume server storage for as long as other inputs. This does not affect the Action
+  /// digest.
+  ///
+  pub ephemeral_input_digests: Vec<hashing::Digest>,
+
+  ///
+  /// Estimates of the resources the process will use, which are sent to remote execution servers
+  /// that accept them as a hint for scheduling. Like force_rerun, this does not affect the Action
+  /// digest.
+  ///
+  pub scheduling_hints: Option<SchedulingHints>,
+
+  ///
+  /// If present, how often a remote execution of the process should be polled for completion:
+  /// it replaces the default increment of the polling backoff, although a CommandRunner may
+  /// enforce a minimum (see `remote::CommandRunner::with_poll_interval_floor`). Useful for
+  /// latency-sensitive processes which are expected to complete quickly. Like force_rerun, this
+  /// does not affect the Action digest.
+  ///
+  pub poll_interval_hint: Option<Duration>,
+
+  ///
+  /// The names of the cache scopes (see `ExecuteProcessRequestMetadata::cache_scopes`) which the
+  /// process belongs to. A remote execution's Action digest includes the epochs of only these
+  /// scopes, so bumping a scope's epoch invalidates the cached results of only its processes.
+  ///
+  pub cache_scope_names: BTreeSet<String>,
+
+  ///
+  /// If present along with an argfile_flag_template, the size (in bytes, counting a terminator for
+  /// each argument) above which the trailing arguments of the argv are moved into an "argfile" in
+  /// the input files: see `argfile::with_argfile`.
+  ///
+  pub argfile_threshold: Option<usize>,
+
+  ///
+  /// The argument which replaces the arguments moved into an argfile, in which `{path}` is
+  /// replaced with the path of the argfile, e.g. `@{path}`.
+  ///
+  pub argfile_flag_template: Option<String>,
+
+  ///
+  /// A human readable explanation of why the process is running, like the rule and the target
+  /// which requested it. It is sent to remote execution servers alongside the process (see
+  /// `remote::PROVENANCE_HEADER`) so that operators can attribute its executions, and is included
+  /// in logs and reports. Like the description, it does not affect the identity of the request or
+  /// its Action digest, so processes which differ only in their provenance share their results.
+  ///
+  pub provenance: Option<String>,
+
+  ///
+  /// The name of the execution environment (see `ExecuteProcessRequestMetadata::environments`)
+  /// which the process runs in when executed remotely. The Action digest includes the properties of
+  /// the environment, but not its name, so a process has the same digest as one which specifies
+  /// those properties directly.
+  ///
+  pub environment: Option<String>,
+
+  ///
+  /// If true, the cache_key_gen_version of the metadata (if any) is not set in the environment of
+  /// the process when it is executed remotely. This means that its Action digest does not change
+  /// when the cache_key_gen_version is bumped, so its cached results are shared across versions:
+  /// only set it for processes whose results do not depend on the version of pants (like builds of
+  /// toolchains), and which can tolerate results cached by other versions.
+  ///
+  pub omit_cache_key_gen_version: bool,
+
+  ///
+  /// If present, roughly how large the outputs of the process are expected to be in total. When it
+  /// is executed remotely, outputs which are larger than expected (beyond its tolerance) are warned
+  /// about along with their largest files, and noted in the output_size_anomaly of the result; or,
+  /// if the expectation is strict, fail the process. Like force_rerun, this does not affect the
+  /// Action digest.
+  ///
+  pub expected_output_size: Option<ExpectedOutputSize>,
+
+  ///
+  /// Whether the process must be executed locally or remotely, or may be executed either way. The
+  /// CommandRunners which are configured honor it (see `ExecutionLocality::check`), while the
+  /// caches look the process up (and store its result) under its Action digest wherever it runs.
+  /// This does not affect the Action digest.
+  ///
+  pub execution_locality: ExecutionLocality,
+}
+
+impl ExecuteProcessRequest {
+  ///
+  /// The fields which identify the request: all but the description and provenance, with the
+  /// ephemeral_input_digests in a canonical order.
+  ///
+  fn identity(&self) -> impl Ord + Hash + '_ {
+    let mut ephemeral_input_digests = self.ephemeral_input_digests.iter().collect::<Vec<_>>();
+    ephemeral_input_digests.sort();
+    ephemeral_input_digests.dedup();
+    (
+      (
+        &self.argv,
+        &self.env,
+        &self.input_files,
+        &self.output_files,
+        &self.output_directories,
+        &self.timeout,
+      ),
+      (
+        &self.jdk_home,
+        &self.target_platform,
+        &self.force_rerun,
+        &self.expected_output_digest,
+        &self.diff_outputs,
+        ephemeral_input_digests,
+        &self.scheduling_hints,
+        &self.poll_interval_hint,
+        &self.cache_scope_names,
+      ),
+      (
+        &self.argfile_threshold,
+        &self.argfile_flag_template,
+        &self.environment,
+        &self.omit_cache_key_gen_version,
+        &self.expected_output_size,
+        &self.execution_locality,
+      ),
+    )
+  }
+}
+
+impl PartialEq for ExecuteProcessRequest {
+  fn eq(&self, other: &ExecuteProcessRequest) -> bool {
+    self.identity() == other.identity()
+  }
+}
+
+impl Eq for ExecuteProcessRequest {}
+
+impl Hash for ExecuteProcessRequest {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    self.identity().hash(state)
+  }
+}
+
+impl PartialOrd for ExecuteProcessRequest {
+  fn partial_cmp(&self, other: &ExecuteProcessRequest) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl Ord for ExecuteProcessRequest {
+  fn cmp(&self, other: &ExecuteProcessRequest) -> Ordering {
+    self.identity().cmp(&other.identity())
+  }
 }
 
 impl TryFrom<MultiPlatformExecuteProcessRequest> for ExecuteProcessRequest {
@@ -147,7 +481,7 @@ impl TryFrom<MultiPlatformExecuteProcessRequest> for ExecuteProcessRequest {
 
   fn try_from(req: MultiPlatformExecuteProcessRequest) -> Result<Self, Self::Error> {
     match req.0.get(&(Platform::None, Platform::None)) {
-      Some(crossplatform_req) => Ok(crossplatform_req.clone()),
+      Some(crossplatform_req) => Ok((**crossplatform_req).clone()),
       None => Err(String::from(
         "Cannot coerce to a simple ExecuteProcessRequest, no cross platform request exists.",
       )),
@@ -158,21 +492,232 @@ impl TryFrom<MultiPlatformExecuteProcessRequest> for ExecuteProcessRequest {
 ///
 /// A container of platform constrained processes.
 ///
-#[derive(Derivative, Clone, Debug, Eq, PartialEq, Hash)]
+/// Requests are equal if they have equal processes for the same constraints, regardless of the
+/// order in which they were built.
+///
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct MultiPlatformExecuteProcessRequest(
-  pub BTreeMap<(Platform, Platform), ExecuteProcessRequest>,
+  pub BTreeMap<(Platform, Platform), Arc<ExecuteProcessRequest>>,
 );
 
+impl MultiPlatformExecuteProcessRequest {
+  ///
+  /// A digest which identifies the request exactly as Eq does, but which (unlike Hash) is stable
+  /// across processes and releases: it is the stable form of the key by which the engine memoizes
+  /// MultiPlatformExecuteProcess nodes, so it must change whenever the request changes in a way
+  /// which could affect its result.
+  ///
+  /// Each process contributes its constraints, the digest of the Action that it is executed as
+  /// (see `action::make_execute_request`), and the fields which the Action does not cover.
+  ///
+  pub fn fingerprint(&self) -> Result<hashing::Digest, String> {
+    let mut hasher = hashing::WriterHasher::new(io::sink());
+    let mut write = |bytes: &[u8]| {
+      hasher
+        .write_all(&(bytes.len() as u64).to_le_bytes())
+        .and_then(|()| hasher.write_all(bytes))
+        .expect("Writing to a sink cannot fail.")
+    };
+    let write_digest = |write: &mut dyn FnMut(&[u8]), digest: &hashing::Digest| {
+      write(digest.0.as_bytes());
+      write(&(digest.1 as u64).to_le_bytes());
+    };
+
+    write(&(self.0.len() as u64).to_le_bytes());
+    for (&(constraint, target), req) in &self.0 {
+      write(String::from(constraint).as_bytes());
+      write(String::from(target).as_bytes());
+
+      // Like the epochs of cache scopes, the properties of environments depend on how the
+      // CommandRunner is configured, so the Action is computed without them, and only the name of
+      // the environment contributes.
+      let (_, _, execute_request) = if req.environment.is_some() {
+        let req = ExecuteProcessRequest {
+          environment: None,
+          ..(**req).clone()
+        };
+        action::make_execute_request(&req, fingerprint_metadata())?
+      } else {
+        action::make_execute_request(req, fingerprint_metadata())?
+      };
+      write(execute_request.get_action_digest().get_hash().as_bytes());
+      write(&(execute_request.get_action_digest().get_size_bytes() as u64).to_le_bytes());
+
+      write(&req.timeout.as_secs().to_le_bytes());
+      write(&req.timeout.subsec_nanos().to_le_bytes());
+      // Optional fields are preceded by whether they are present.
+      write(&[req.jdk_home.is_some() as u8]);
+      if let Some(ref jdk_home) = req.jdk_home {
+        write(jdk_home.to_string_lossy().as_bytes());
+      }
+      write(&[req.force_rerun as u8, req.diff_outputs as u8]);
+      write(&[req.expected_output_digest.is_some() as u8]);
+      if let Some(ref expected_output_digest) = req.expected_output_digest {
+        write_digest(&mut write, expected_output_digest);
+      }
+      let mut ephemeral_input_digests = req.ephemeral_input_digests.clone();
+      ephemeral_input_digests.sort();
+      ephemeral_input_digests.dedup();
+      write(&(ephemeral_input_digests.len() as u64).to_le_bytes());
+      for digest in &ephemeral_input_digests {
+        write_digest(&mut write, digest);
+      }
+      write(&[req.scheduling_hints.is_some() as u8]);
+      if let Some(scheduling_hints) = req.scheduling_hints {
+        write(&scheduling_hints.cpu_millis.to_le_bytes());
+        write(&scheduling_hints.ram_bytes.to_le_bytes());
+        write(&scheduling_hints.disk_bytes.to_le_bytes());
+      }
+      // Fields which were added after fingerprints were first pinned are only written if they are
+      // set (preceded by their names), so that the fingerprints of requests which do not set them
+      // are unchanged.
+      if let Some(poll_interval_hint) = req.poll_interval_hint {
+        write(b"poll_interval_hint");
+        write(&poll_interval_hint.as_secs().to_le_bytes());
+        write(&poll_interval_hint.subsec_nanos().to_le_bytes());
+      }
+      // The epochs of the scopes depend on how the CommandRunner is configured, but their names
+      // do not.
+      if !req.cache_scope_names.is_empty() {
+        write(b"cache_scope_names");
+        write(&(req.cache_scope_names.len() as u64).to_le_bytes());
+        for cache_scope_name in &req.cache_scope_names {
+          write(cache_scope_name.as_bytes());
+        }
+      }
+      // The Action is computed from the argv before any of it is moved into an argfile.
+      if let Some(argfile_threshold) = req.argfile_threshold {
+        write(b"argfile_threshold");
+        write(&(argfile_threshold as u64).to_le_bytes());
+      }
+      if let Some(ref argfile_flag_template) = req.argfile_flag_template {
+        write(b"argfile_flag_template");
+        write(argfile_flag_template.as_bytes());
+      }
+      if let Some(ref environment) = req.environment {
+        write(b"environment");
+        write(environment.as_bytes());
+      }
+      // The cache_key_gen_version is not part of the fingerprint_metadata, so the Action does not
+      // reflect whether it is omitted.
+      if req.omit_cache_key_gen_version {
+        write(b"omit_cache_key_gen_version");
+        write(&[req.omit_cache_key_gen_version as u8]);
+      }
+      if let Some(expected_output_size) = req.expected_output_size {
+        write(b"expected_output_size");
+        write(&expected_output_size.bytes.to_le_bytes());
+        write(&[expected_output_size.tolerance_percent.is_some() as u8]);
+        if let Some(tolerance_percent) = expected_output_size.tolerance_percent {
+          write(&tolerance_percent.to_le_bytes());
+        }
+        write(&[expected_output_size.strict as u8]);
+      }
+      if req.execution_locality != ExecutionLocality::Any {
+        write(b"execution_locality");
+        write(&[match req.execution_locality {
+          ExecutionLocality::Any => 0,
+          ExecutionLocality::LocalOnly => 1,
+          ExecutionLocality::RemoteOnly => 2,
+        }]);
+      }
+    }
+    Ok(hasher.finish().0)
+  }
+}
+
+///
+/// The metadata with which fingerprints compute Action digests: these do not depend on how the
+/// CommandRunner is configured, and use the original canonical form, so that output paths which
+/// are distinct in a request remain distinct in its fingerprint.
+///
+fn fingerprint_metadata() -> ExecuteProcessRequestMetadata {
+  ExecuteProcessRequestMetadata {
+    instance_name: None,
+    cache_key_gen_version: None,
+    cache_scopes: BTreeMap::new(),
+    platform_properties: vec![],
+    timeout_excludes_queue: false,
+    argv_error_patterns: vec![],
+    argv_warning_bytes: None,
+    allow_lossy_env: false,
+    canonical_form_version: 0,
+    environments: BTreeMap::new(),
+    record_env_in_report: EnvRecording::Off,
+  }
+}
+
 impl From<ExecuteProcessRequest> for MultiPlatformExecuteProcessRequest {
   fn from(req: ExecuteProcessRequest) -> Self {
     MultiPlatformExecuteProcessRequest(
-      vec![((Platform::None, Platform::None), req)]
+      vec![((Platform::None, Platform::None), Arc::new(req))]
         .into_iter()
         .collect(),
     )
   }
 }
 
+///
+/// Memoizes which of the constraints of a MultiPlatformExecuteProcessRequest a CommandRunner
+/// chooses. Selections are keyed by the set of constraints present in a request, rather than by
+/// the request itself, so requests of the same shape share a selection.
+///
+#[allow(clippy::type_complexity)]
+#[derive(Clone, Default)]
+pub struct CompatibleConstraintCache {
+  selections: Arc<Mutex<HashMap<Vec<(Platform, Platform)>, Option<(Platform, Platform)>>>>,
+}
+
+impl CompatibleConstraintCache {
+  pub fn new() -> CompatibleConstraintCache {
+    CompatibleConstraintCache::default()
+  }
+
+  ///
+  /// Returns the request for the first of the given compatible constraints (in order of
+  /// preference) which is present in the given request. The preferences are only computed when
+  /// a request of this shape has not been seen before.
+  ///
+  pub fn extract<F: FnOnce() -> Vec<(Platform, Platform)>>(
+    &self,
+    req: &MultiPlatformExecuteProcessRequest,
+    compatible_constraints: F,
+  ) -> Option<Arc<ExecuteProcessRequest>> {
+    let constraints = req.0.keys().cloned().collect::<Vec<_>>();
+    let selection = *self
+      .selections
+      .lock()
+      .unwrap()
+      .entry(constraints)
+      .or_insert_with(|| {
+        compatible_constraints()
+          .into_iter()
+          .find(|constraint| req.0.contains_key(constraint))
+      });
+    selection.and_then(|constraint| req.0.get(&constraint).cloned())
+  }
+
+  ///
+  /// Returns the variant of the request which a runner for the given platform would run,
+  /// regardless of its locality: in order of preference, the variant for any platform, the one
+  /// which runs on the given platform for any target, and the one which targets the current
+  /// platform.
+  ///
+  pub fn extract_for_platform(
+    &self,
+    req: &MultiPlatformExecuteProcessRequest,
+    platform: Platform,
+  ) -> Option<Arc<ExecuteProcessRequest>> {
+    self.extract(req, || {
+      vec![
+        (Platform::None, Platform::None),
+        (platform, Platform::None),
+        (platform, Platform::current_platform().unwrap()),
+      ]
+    })
+  }
+}
+
 ///
 /// Metadata surrounding an ExecuteProcessRequest which factors into its cache key when cached
 /// externally from the engine graph (e.g. when using remote execution or an external process
@@ -182,7 +727,288 @@ impl From<ExecuteProcessRequest> for MultiPlatformExecuteProcessRequest {
 pub struct ExecuteProcessRequestMetadata {
   pub instance_name: Option<String>,
   pub cache_key_gen_version: Option<String>,
+  ///
+  /// Epochs by the names of cache scopes, which are classes of processes whose cached results can
+  /// be invalidated together (e.g. by bumping the epoch of a scope for processes which ran on
+  /// workers with a bad toolchain) without changing the processes themselves. The epoch of each
+  /// scope that a request belongs to is set on its Command as the env var
+  /// `PANTS_CACHE_SCOPE_<NAME>`, so it affects the cache keys of only those requests.
+  ///
+  pub cache_scopes: BTreeMap<String, String>,
   pub platform_properties: Vec<(String, String)>,
+  ///
+  /// If true, a remote request's timeout is measured from when the server reports that the action
+  /// started executing, rather than from when it was submitted. Does not factor into the cache key.
+  ///
+  pub timeout_excludes_queue: bool,
+  ///
+  /// Regexes matched against the messages of InvalidArgument and OutOfRange errors from remote
+  /// execution, which identify errors caused by a request's argv being too long for the worker's
+  /// OS. Such errors are reported along with a summary of the argv. Does not factor into the cache
+  /// key.
+  ///
+  pub argv_error_patterns: Vec<Regex>,
+  ///
+  /// If set, a warning is logged for any remote request whose argv is larger than this many bytes.
+  /// Does not factor into the cache key.
+  ///
+  pub argv_warning_bytes: Option<usize>,
+  ///
+  /// By default, a request fails if any of its argv or env is invalid UTF-8 as it crosses the FFI
+  /// boundary (see `action::checked_request_string`). If set, invalid sequences in env names and
+  /// values are instead replaced with U+FFFD (and a warning is logged). Because this changes the
+  /// env that is sent, it affects the cache key.
+  ///
+  pub allow_lossy_env: bool,
+  ///
+  /// The version of the canonical form in which the request is serialized. Normalizations which
+  /// would change the cache keys of existing requests only apply from the version which introduced
+  /// them: version 0 is the original form, version 1 normalizes output paths, and version 2 treats
+  /// an empty cache key gen version or cache scope epoch as absent (see
+  /// `CANONICAL_FORM_COMPATIBILITY_NOTE`). Affects the cache key.
+  ///
+  pub canonical_form_version: u32,
+  ///
+  /// Execution environments by name, which requests select via their `environment`, so that rules
+  /// name the environment that they need rather than assembling its properties. Only the
+  /// properties of the environments which requests select affect their cache keys.
+  ///
+  pub environments: BTreeMap<String, ExecutionEnvironment>,
+  ///
+  /// How much of the env of each remote request's Command is recorded in its report record (see
+  /// `remote::CommandRunner::with_report`), for auditing. Does not factor into the cache key.
+  ///
+  pub record_env_in_report: EnvRecording,
+}
+
+///
+/// How much of the env of a Command is recorded, as it was placed into the Command: so including
+/// the env vars which pants injects, such as the cache key gen version.
+///
+#[derive(Clone, Debug)]
+pub enum EnvRecording {
+  Off,
+  NamesOnly,
+  // Names and values, except that values which match any of the patterns are replaced with
+  // `report::REDACTED_ENV_VALUE`.
+  Full { redact_patterns: Vec<Regex> },
+}
+
+impl EnvRecording {
+  ///
+  /// Parses a recording mode (one of `off`, `names_only` and `full`), and the patterns which
+  /// redact values in `full` mode. An invalid pattern is an error, rather than being skipped,
+  /// because it might have been meant to redact any of the values.
+  ///
+  pub fn parse(mode: &str, redact_patterns: &[String]) -> Result<EnvRecording, String> {
+    match mode {
+      "off" => Ok(EnvRecording::Off),
+      "names_only" => Ok(EnvRecording::NamesOnly),
+      "full" => redact_patterns
+        .iter()
+        .map(|pattern| {
+          Regex::new(pattern)
+            .map_err(|err| format!("Invalid env redaction pattern {:?}: {}", pattern, err))
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(|redact_patterns| EnvRecording::Full { redact_patterns }),
+      other => Err(format!("Unknown env recording mode {:?}", other)),
+    }
+  }
+}
+
+///
+/// A bundle of the properties of an environment in which remote processes execute (see
+/// `ExecuteProcessRequestMetadata::environments`).
+///
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ExecutionEnvironment {
+  ///
+  /// Platform properties, which replace any of the metadata's platform properties with the same
+  /// names. The properties which a request sets itself (e.g. target_platform) take precedence.
+  ///
+  pub platform_properties: Vec<(String, String)>,
+  ///
+  /// If set, the image of the container to execute in, which is sent as the `container-image`
+  /// platform property.
+  ///
+  pub container_image: Option<String>,
+  ///
+  /// If set, the instance name to execute with, instead of the metadata's instance_name.
+  ///
+  pub instance_name: Option<String>,
+  ///
+  /// If set, the priority to execute with, instead of any priority that the CommandRunner is
+  /// configured with (see `remote::CommandRunner::with_execution_priority`). Does not factor into
+  /// the cache key.
+  ///
+  pub priority: Option<i32>,
+}
+
+impl ExecutionEnvironment {
+  ///
+  /// Parses named environments from a JSON object which maps each name to an object with any of
+  /// the keys `platform_properties` (an object of string values), `container_image`,
+  /// `instance_name` and `priority`.
+  ///
+  pub fn parse_all(json: &str) -> Result<BTreeMap<String, ExecutionEnvironment>, String> {
+    #[derive(serde_derive::Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Serialized {
+      #[serde(default)]
+      platform_properties: BTreeMap<String, String>,
+      #[serde(default)]
+      container_image: Option<String>,
+      #[serde(default)]
+      instance_name: Option<String>,
+      #[serde(default)]
+      priority: Option<i32>,
+    }
+
+    let environments: BTreeMap<String, Serialized> = serde_json::from_str(json)
+      .map_err(|err| format!("Invalid remote execution environments: {}", err))?;
+    Ok(
+      environments
+        .into_iter()
+        .map(|(name, environment)| {
+          (
+            name,
+            ExecutionEnvironment {
+              platform_properties: environment.platform_properties.into_iter().collect(),
+              container_image: environment.container_image,
+              instance_name: environment.instance_name,
+              priority: environment.priority,
+            },
+          )
+        })
+        .collect(),
+    )
+  }
+}
+
+///
+/// The latest version of the canonical form (see `canonical_form_version`).
+///
+pub const CANONICAL_FORM_VERSION: u32 = 2;
+
+///
+/// How the "empty or absent" ambiguities of the Command and Action are resolved in the latest
+/// canonical form, for the cache key versioning of clients which must produce the same digests.
+///
+pub const CANONICAL_FORM_COMPATIBILITY_NOTE: &str = "\
+  The Command's platform field is omitted when there are no platform properties, and is never \
+  sent as an empty Platform message (every request currently has a target_platform property, so \
+  it is always present). Empty environment_variables, output_files and output_directories lists, \
+  and the working_directory, are omitted, which proto3 serializes identically to empty values. A \
+  request's own env vars are sent even if their values are empty, but from canonical form version \
+  2 an empty cache key gen version or cache scope epoch is treated as absent, and adds no env var. \
+  Output paths are sorted, and from canonical form version 1 normalized and deduplicated.";
+
+///
+/// The stdout or stderr of a process. Outputs which are larger than a CommandRunner's spill
+/// threshold (see `remote::CommandRunner::with_output_spill_threshold`) are kept only as the
+/// digest of their bytes, which are in the Store, so that the results which are held by the graph
+/// do not keep them in memory.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ProcessOutput {
+  Inline(Bytes),
+  Stored(hashing::Digest),
+  // Output which is held only in memory: its digest was computed, but its bytes were not recorded
+  // in the Store (see `remote::CommandRunner::without_persisting_inline_output`).
+  Unpersisted(Bytes, hashing::Digest),
+}
+
+impl ProcessOutput {
+  pub fn len(&self) -> usize {
+    match self {
+      ProcessOutput::Inline(bytes) | ProcessOutput::Unpersisted(bytes, _) => bytes.len(),
+      ProcessOutput::Stored(digest) => digest.1,
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  ///
+  /// The bytes of the output, which are loaded from the given Store if they were spilled to it.
+  ///
+  pub fn load(&self, store: &Store, workunit_store: WorkUnitStore) -> BoxFuture<Bytes, String> {
+    match *self {
+      ProcessOutput::Inline(ref bytes) | ProcessOutput::Unpersisted(ref bytes, _) => {
+        future::ok(bytes.clone()).to_boxed()
+      }
+      ProcessOutput::Stored(digest) => store
+        .load_file_bytes_with(digest, |bytes| bytes, workunit_store)
+        .and_then(move |maybe_bytes| {
+          maybe_bytes.map(|(bytes, _metadata)| bytes).ok_or_else(|| {
+            format!(
+              "Spilled process output {:?} is missing from the Store",
+              digest
+            )
+          })
+        })
+        .to_boxed(),
+    }
+  }
+
+  ///
+  /// Records the output in the given Store (unless it was spilled to it), and returns its digest.
+  ///
+  /// Unpersisted outputs are recorded too: a caller which refers to an output by its digest (e.g.
+  /// the local process cache) needs the bytes to be loadable.
+  ///
+  pub fn store(&self, store: &Store) -> BoxFuture<hashing::Digest, String> {
+    match *self {
+      ProcessOutput::Inline(ref bytes) | ProcessOutput::Unpersisted(ref bytes, _) => {
+        store.store_file_bytes(bytes.clone(), true)
+      }
+      ProcessOutput::Stored(digest) => future::ok(digest).to_boxed(),
+    }
+  }
+}
+
+impl From<Bytes> for ProcessOutput {
+  fn from(bytes: Bytes) -> ProcessOutput {
+    ProcessOutput::Inline(bytes)
+  }
+}
+
+///
+/// Spilled outputs are never equal to bytes, because comparing them would require loading them.
+///
+impl PartialEq<Bytes> for ProcessOutput {
+  fn eq(&self, other: &Bytes) -> bool {
+    match self {
+      ProcessOutput::Inline(bytes) | ProcessOutput::Unpersisted(bytes, _) => bytes == other,
+      ProcessOutput::Stored(_) => false,
+    }
+  }
+}
+
+impl PartialEq<[u8]> for ProcessOutput {
+  fn eq(&self, other: &[u8]) -> bool {
+    match self {
+      ProcessOutput::Inline(bytes) | ProcessOutput::Unpersisted(bytes, _) => bytes == other,
+      ProcessOutput::Stored(_) => false,
+    }
+  }
+}
+
+///
+/// Renders inline outputs as (lossily decoded) text, and spilled outputs as their length and
+/// digest.
+///
+impl fmt::Display for ProcessOutput {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ProcessOutput::Inline(bytes) | ProcessOutput::Unpersisted(bytes, _) => {
+        write!(f, "{}", String::from_utf8_lossy(bytes))
+      }
+      ProcessOutput::Stored(digest) => write!(f, "<{} bytes, stored as {}>", digest.1, digest.0),
+    }
+  }
 }
 
 ///
@@ -190,8 +1016,8 @@ pub struct ExecuteProcessRequestMetadata {
 ///
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct FallibleExecuteProcessResult {
-  pub stdout: Bytes,
-  pub stderr: Bytes,
+  pub stdout: ProcessOutput,
+  pub stderr: ProcessOutput,
   pub exit_code: i32,
 
   // It's unclear whether this should be a Snapshot or a digest of a Directory. A Directory digest
@@ -199,6 +1025,190 @@ pub struct FallibleExecuteProcessResult {
   pub output_directory: hashing::Digest,
 
   pub execution_attempts: Vec<ExecutionStats>,
+
+  pub source: ProcessResultSource,
+
+  // The name of the signal that killed the process, if its exit code indicates that it was killed
+  // by one. The exit_code is unchanged.
+  pub termination_signal: Option<String>,
+
+  // A message which a remote execution server attached to the result for the user (e.g. a
+  // deprecation notice for a worker pool), sanitized and length-capped.
+  pub server_message: Option<String>,
+
+  // Set if the process was timed out by a client-side timeout on its remote execution.
+  pub timeout_details: Option<TimeoutDetails>,
+
+  // Set if the server reported (in ExecuteOperationMetadata) that it executed an Action with a
+  // different digest than the one which was sent, i.e. that it rewrote the Action.
+  pub rewritten_action_digest: Option<hashing::Digest>,
+
+  // Set if the outputs of a remote execution were larger than the process expected (see
+  // `ExecuteProcessRequest::expected_output_size`).
+  pub output_size_anomaly: Option<OutputSizeAnomaly>,
+
+  // The platform which the process ran on, which a MultiPlatformExecuteProcessRequest does not
+  // imply: local processes run on the current platform, and remote ones on the platform of their
+  // CommandRunner. Caching CommandRunners preserve it.
+  pub platform: Platform,
+}
+
+///
+/// Why a remote execution timed out, judged by the last stage that the server reported for it
+/// via ExecuteOperationMetadata.
+///
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum TimeoutCategory {
+  // The action was still queued (or being checked against the cache): a capacity problem.
+  QueuedTimeout,
+  // The action was executing: the process is slow.
+  ExecutionTimeout,
+  // The server never reported a stage.
+  UnknownTimeout,
+}
+
+///
+/// Where the time went in a remote execution which timed out.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TimeoutDetails {
+  pub category: TimeoutCategory,
+  // The name of the last stage that the server reported (e.g. "QUEUED"), if any.
+  pub last_stage: Option<String>,
+  // The time spent in each stage, in the order in which they were first reported. Time before the
+  // server reported any stage is attributed to "UNKNOWN".
+  pub stage_durations: Vec<(String, Duration)>,
+}
+
+impl TimeoutCategory {
+  pub fn metric(self) -> &'static str {
+    match self {
+      TimeoutCategory::QueuedTimeout => metrics::REMOTE_TIMEOUTS_QUEUED,
+      TimeoutCategory::ExecutionTimeout => metrics::REMOTE_TIMEOUTS_EXECUTION,
+      TimeoutCategory::UnknownTimeout => metrics::REMOTE_TIMEOUTS_UNKNOWN,
+    }
+  }
+}
+
+///
+/// Where a FallibleExecuteProcessResult came from: set by whichever CommandRunner produced it.
+///
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ProcessResultSource {
+  RanLocally,
+  RanRemotely,
+  HitRemoteCache,
+  HitLocalCache,
+}
+
+impl FallibleExecuteProcessResult {
+  ///
+  /// The bytes of stdout, loading them from the given Store if they were spilled (see
+  /// ProcessOutput).
+  ///
+  pub fn stdout_bytes(
+    &self,
+    store: &Store,
+    workunit_store: WorkUnitStore,
+  ) -> BoxFuture<Bytes, String> {
+    self.stdout.load(store, workunit_store)
+  }
+
+  pub fn stderr_bytes(
+    &self,
+    store: &Store,
+    workunit_store: WorkUnitStore,
+  ) -> BoxFuture<Bytes, String> {
+    self.stderr.load(store, workunit_store)
+  }
+
+  ///
+  /// Renders a summary of this (presumably failed) result for use in an error message: its exit
+  /// code, a preview of its stderr (see render_output_preview), and any message from the server.
+  /// It is only computed when called, so successful results pay nothing for it. A spilled stderr
+  /// is not loaded, so only its length and digest are rendered.
+  ///
+  pub fn error_summary(&self) -> String {
+    let summary = self.exit_summary();
+    match self.server_message {
+      Some(ref server_message) => {
+        format!("{}\nMessage from the server: {}", summary, server_message)
+      }
+      None => summary,
+    }
+  }
+
+  fn exit_summary(&self) -> String {
+    let signal = match self.termination_signal {
+      Some(ref signal) => format!(" (killed by {})", signal),
+      None => "".to_owned(),
+    };
+    if self.stderr.is_empty() {
+      format!(
+        "Exited with code {}{} and no stderr.",
+        self.exit_code, signal
+      )
+    } else {
+      let stderr = match self.stderr {
+        ProcessOutput::Inline(ref stderr) | ProcessOutput::Unpersisted(ref stderr, _) => {
+          render_output_preview(stderr)
+        }
+        ProcessOutput::Stored(_) => self.stderr.to_string(),
+      };
+      format!(
+        "Exited with code {}{}. stderr:\n{}",
+        self.exit_code, signal, stderr
+      )
+    }
+  }
+}
+
+// The number of lines at each of the start and the end of some output which are preserved by
+// render_output_preview.
+pub const OUTPUT_PREVIEW_LINES: usize = 10;
+pub const MAX_OUTPUT_PREVIEW_LEN: usize = 4096;
+
+///
+/// Renders a preview of the output of a process for use in an error message: the first and last
+/// OUTPUT_PREVIEW_LINES lines, capped at MAX_OUTPUT_PREVIEW_LEN bytes. Output which does not look
+/// like text is replaced by a marker.
+///
+pub fn render_output_preview(output: &[u8]) -> String {
+  let text = match std::str::from_utf8(output) {
+    Ok(text) if !text.contains('\0') => text,
+    _ => return format!("<binary, {} bytes>", output.len()),
+  };
+
+  let lines = text.lines().collect::<Vec<_>>();
+  let preview = if lines.len() <= 2 * OUTPUT_PREVIEW_LINES {
+    lines.join("\n")
+  } else {
+    format!(
+      "{}\n... {} lines elided ...\n{}",
+      lines[..OUTPUT_PREVIEW_LINES].join("\n"),
+      lines.len() - 2 * OUTPUT_PREVIEW_LINES,
+      lines[lines.len() - OUTPUT_PREVIEW_LINES..].join("\n")
+    )
+  };
+  if preview.len() <= MAX_OUTPUT_PREVIEW_LEN {
+    return preview;
+  }
+
+  // Very long lines: keep the start and the end, leaving room for the elision marker.
+  let half = (MAX_OUTPUT_PREVIEW_LEN - 32) / 2;
+  let head_end = (0..=half)
+    .rev()
+    .find(|i| preview.is_char_boundary(*i))
+    .unwrap_or(0);
+  let tail_start = (preview.len() - half..preview.len())
+    .find(|i| preview.is_char_boundary(*i))
+    .unwrap_or_else(|| preview.len());
+  format!(
+    "{}\n... {} bytes elided ...\n{}",
+    &preview[..head_end],
+    tail_start - head_end,
+    &preview[tail_start..]
+  )
 }
 
 #[cfg(test)]
@@ -209,24 +1219,264 @@ impl FallibleExecuteProcessResult {
   }
 }
 
+///
+/// The status of a process, as reported to a ProcessProgress handle.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ProcessStatus {
+  // The process has been submitted, but is not yet known to be running.
+  Pending,
+  // The process is running (or, for a remote process, the server has accepted it).
+  Running,
+  // The process completed, with the given result.
+  Finished(FallibleExecuteProcessResult),
+  // The process could not be run, for the given reason.
+  Failed(String),
+}
+
+impl ProcessStatus {
+  pub fn is_done(&self) -> bool {
+    match self {
+      ProcessStatus::Finished(_) | ProcessStatus::Failed(_) => true,
+      ProcessStatus::Pending | ProcessStatus::Running => false,
+    }
+  }
+}
+
+///
+/// A handle to which a CommandRunner reports the status of a process, and any stdout that it has
+/// written so far, while the process runs. Clones of a handle share their state.
+///
+#[derive(Clone)]
+pub struct ProcessProgress {
+  state: Arc<Mutex<(ProcessStatus, Vec<u8>)>>,
+}
+
+impl ProcessProgress {
+  pub fn new() -> ProcessProgress {
+    ProcessProgress {
+      state: Arc::new(Mutex::new((ProcessStatus::Pending, vec![]))),
+    }
+  }
+
+  pub fn status(&self) -> ProcessStatus {
+    self.state.lock().unwrap().0.clone()
+  }
+
+  ///
+  /// The stdout that the process has written so far. Once the process has finished, this is
+  /// the stdout of its result.
+  ///
+  pub fn partial_stdout(&self) -> Bytes {
+    Bytes::from(self.state.lock().unwrap().1.as_slice())
+  }
+
+  pub fn mark_running(&self) {
+    let mut state = self.state.lock().unwrap();
+    if state.0 == ProcessStatus::Pending {
+      state.0 = ProcessStatus::Running;
+    }
+  }
+
+  pub fn append_stdout(&self, chunk: &[u8]) {
+    let mut state = self.state.lock().unwrap();
+    if !state.0.is_done() {
+      state.1.extend_from_slice(chunk);
+    }
+  }
+
+  ///
+  /// Records the outcome of the process. Runners which do not stream stdout only report it here.
+  ///
+  pub fn finish(&self, result: &Result<FallibleExecuteProcessResult, String>) {
+    let mut state = self.state.lock().unwrap();
+    match result {
+      Ok(result) => {
+        state.0 = ProcessStatus::Finished(result.clone());
+        // A spilled stdout is not copied here: it can be loaded from the result.
+        if let ProcessOutput::Inline(ref stdout) | ProcessOutput::Unpersisted(ref stdout, _) =
+          result.stdout
+        {
+          state.1 = stdout.to_vec();
+        }
+      }
+      Err(err) => state.0 = ProcessStatus::Failed(err.clone()),
+    }
+  }
+}
+
 #[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
 pub struct ExecutionStats {
   uploaded_bytes: usize,
   uploaded_file_count: usize,
   upload: Duration,
+  // The time which the uploaded blobs waited for the upload gate, summed over the blobs.
+  upload_queue: Option<Duration>,
+  // The serialized sizes of the Action and Command of a remote execution.
+  action_bytes: Option<usize>,
+  command_bytes: Option<usize>,
+  // The time spent waiting for a cancelled operation for the same action to be acknowledged.
+  cancellation_wait: Option<Duration>,
+  // The time between submitting the ExecuteRequest and the server responding with an operation.
+  operation_wait: Option<Duration>,
+  // The time between the server responding with an operation and it completing (or timing out).
+  polling: Option<Duration>,
+  // The time spent fetching and storing the outputs of a completed operation.
+  download: Option<Duration>,
+  // The number of GetOperation requests with which the operation was polled.
+  polls: Option<usize>,
   remote_queue: Option<Duration>,
   remote_input_fetch: Option<Duration>,
   remote_execution: Option<Duration>,
   remote_output_store: Option<Duration>,
+  // The time between the worker starting and completing the action which was not spent fetching
+  // inputs, executing or uploading outputs.
+  remote_worker_overhead: Option<Duration>,
   was_cache_hit: bool,
 }
 
+///
+/// The durations of an ExecutionStats which are measured by remote workers.
+///
+#[cfg(feature = "remote-execution")]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum RemoteTiming {
+  Queue,
+  InputFetch,
+  Execution,
+  OutputStore,
+  WorkerOverhead,
+}
+
+#[cfg(feature = "remote-execution")]
+impl ExecutionStats {
+  pub(crate) fn remote_timing(&self, timing: RemoteTiming) -> Option<Duration> {
+    match timing {
+      RemoteTiming::Queue => self.remote_queue,
+      RemoteTiming::InputFetch => self.remote_input_fetch,
+      RemoteTiming::Execution => self.remote_execution,
+      RemoteTiming::OutputStore => self.remote_output_store,
+      RemoteTiming::WorkerOverhead => self.remote_worker_overhead,
+    }
+  }
+
+  pub(crate) fn set_remote_timing(&mut self, timing: RemoteTiming, duration: Duration) {
+    let slot = match timing {
+      RemoteTiming::Queue => &mut self.remote_queue,
+      RemoteTiming::InputFetch => &mut self.remote_input_fetch,
+      RemoteTiming::Execution => &mut self.remote_execution,
+      RemoteTiming::OutputStore => &mut self.remote_output_store,
+      RemoteTiming::WorkerOverhead => &mut self.remote_worker_overhead,
+    };
+    *slot = Some(duration);
+  }
+}
+
 impl AddAssign<UploadSummary> for ExecutionStats {
   fn add_assign(&mut self, summary: UploadSummary) {
     self.uploaded_file_count += summary.uploaded_file_count;
     self.uploaded_bytes += summary.uploaded_file_bytes;
     self.upload += summary.upload_wall_time;
+    if summary.upload_queue_time > Duration::default() {
+      self.upload_queue = Some(self.upload_queue.unwrap_or_default() + summary.upload_queue_time);
+    }
+  }
+}
+
+///
+/// Renders as a single line of key=value pairs, with durations in milliseconds. Fields which were
+/// not measured are omitted.
+///
+impl fmt::Display for ExecutionStats {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "uploaded_bytes={} uploaded_file_count={} upload={}ms",
+      self.uploaded_bytes,
+      self.uploaded_file_count,
+      self.upload.as_millis()
+    )?;
+    let optional_sizes = [
+      ("action_bytes", self.action_bytes),
+      ("command_bytes", self.command_bytes),
+      ("polls", self.polls),
+    ];
+    for (name, size) in optional_sizes.iter() {
+      if let Some(size) = size {
+        write!(f, " {}={}", name, size)?;
+      }
+    }
+    let optional_durations = [
+      ("upload_queue", self.upload_queue),
+      ("cancellation_wait", self.cancellation_wait),
+      ("operation_wait", self.operation_wait),
+      ("polling", self.polling),
+      ("download", self.download),
+      ("remote_queue", self.remote_queue),
+      ("remote_input_fetch", self.remote_input_fetch),
+      ("remote_execution", self.remote_execution),
+      ("remote_output_store", self.remote_output_store),
+      ("remote_worker_overhead", self.remote_worker_overhead),
+    ];
+    for (name, duration) in optional_durations.iter() {
+      if let Some(duration) = duration {
+        write!(f, " {}={}ms", name, duration.as_millis())?;
+      }
+    }
+    write!(f, " was_cache_hit={}", self.was_cache_hit)
+  }
+}
+
+///
+/// The maximum length of the rendering of a list of attempts by `render_execution_attempts`.
+///
+pub const MAX_RENDERED_ATTEMPTS_LEN: usize = 2048;
+
+///
+/// Renders the given attempts one per line, collapsing runs of identical attempts into a single
+/// line suffixed with "xN". If the rendering would be longer than MAX_RENDERED_ATTEMPTS_LEN, lines
+/// are elided from the middle: the last attempt is always rendered, because it is usually the
+/// most interesting.
+///
+pub fn render_execution_attempts(attempts: &[ExecutionStats]) -> String {
+  let mut lines = vec![];
+  let mut i = 0;
+  while i < attempts.len() {
+    let run = attempts[i..]
+      .iter()
+      .take_while(|attempt| **attempt == attempts[i])
+      .count();
+    lines.push(if run == 1 {
+      format!("Attempt {}: {}", i, attempts[i])
+    } else {
+      format!("Attempts {}-{}: {} x{}", i, i + run - 1, attempts[i], run)
+    });
+    i += run;
+  }
+
+  let total_len = lines.iter().map(|line| line.len() + 1).sum::<usize>();
+  if total_len <= MAX_RENDERED_ATTEMPTS_LEN || lines.len() < 2 {
+    return lines.join("\n");
+  }
+  let last = lines.pop().unwrap();
+  let mut len = last.len();
+  let mut kept = vec![];
+  for line in &lines {
+    // Leave room for the elision line.
+    if len + line.len() + 64 > MAX_RENDERED_ATTEMPTS_LEN {
+      break;
+    }
+    len += line.len() + 1;
+    kept.push(line.clone());
   }
+  let elided = lines.len() - kept.len();
+  kept.push(format!(
+    "... {} line{} of attempts elided ...",
+    elided,
+    if elided == 1 { "" } else { "s" }
+  ));
+  kept.push(last);
+  kept.join("\n")
 }
 
 pub trait CommandRunner: Send + Sync {
@@ -240,6 +1490,22 @@ pub trait CommandRunner: Send + Sync {
     workunit_store: WorkUnitStore,
   ) -> BoxFuture<FallibleExecuteProcessResult, String>;
 
+  ///
+  /// Like `run`, but additionally reports the status of the process (and, for runners which can
+  /// stream it, its stdout) to the given ProcessProgress while it runs.
+  ///
+  /// Callers are responsible for calling `ProcessProgress::finish` with the result. By default,
+  /// nothing is reported before then.
+  ///
+  fn run_with_progress(
+    &self,
+    req: MultiPlatformExecuteProcessRequest,
+    _progress: ProcessProgress,
+    workunit_store: WorkUnitStore,
+  ) -> BoxFuture<FallibleExecuteProcessResult, String> {
+    self.run(req, workunit_store)
+  }
+
   ///
   /// Given a multi platform request which may have some platform
   /// constraints determine if any of the requests contained within are compatible
@@ -249,7 +1515,56 @@ pub trait CommandRunner: Send + Sync {
   fn extract_compatible_request(
     &self,
     req: &MultiPlatformExecuteProcessRequest,
-  ) -> Option<ExecuteProcessRequest>;
+  ) -> Option<Arc<ExecuteProcessRequest>>;
+
+  ///
+  /// A snapshot of the named counters of this runner, including those of any runners that it
+  /// wraps. By default, a runner has none.
+  ///
+  fn metrics(&self) -> HashMap<&'static str, i64> {
+    HashMap::new()
+  }
+}
+
+///
+/// Sums snapshots of metrics by name, for runners which wrap more than one runner.
+///
+pub fn sum_metrics<I: IntoIterator<Item = HashMap<&'static str, i64>>>(
+  snapshots: I,
+) -> HashMap<&'static str, i64> {
+  let mut sum = HashMap::new();
+  for snapshot in snapshots {
+    for (name, value) in snapshot {
+      *sum.entry(name).or_insert(0) += value;
+    }
+  }
+  sum
+}
+
+///
+/// Whether the given runner would run some variant of the request if its locality allowed it to
+/// run anywhere. Runners do not report requests which their locality violates as compatible (see
+/// `ExecutionLocality::check`), so callers which only need to know whether a request can run at
+/// all ask this instead, and leave the runner to fail or warn about the violation when it runs.
+///
+pub fn compatible_without_locality(
+  command_runner: &dyn CommandRunner,
+  req: &MultiPlatformExecuteProcessRequest,
+) -> bool {
+  let req = MultiPlatformExecuteProcessRequest(
+    req
+      .0
+      .iter()
+      .map(|(constraints, request)| {
+        let request = ExecuteProcessRequest {
+          execution_locality: ExecutionLocality::Any,
+          ..(**request).clone()
+        };
+        (*constraints, Arc::new(request))
+      })
+      .collect(),
+  );
+  command_runner.extract_compatible_request(&req).is_some()
 }
 
 ///
@@ -281,12 +1596,29 @@ impl CommandRunner for BoundedCommandRunner {
       .with_acquired(move || inner.0.run(req, workunit_store))
   }
 
+  fn run_with_progress(
+    &self,
+    req: MultiPlatformExecuteProcessRequest,
+    progress: ProcessProgress,
+    workunit_store: WorkUnitStore,
+  ) -> BoxFuture<FallibleExecuteProcessResult, String> {
+    let inner = self.inner.clone();
+    self
+      .inner
+      .1
+      .with_acquired(move || inner.0.run_with_progress(req, progress, workunit_store))
+  }
+
   fn extract_compatible_request(
     &self,
     req: &MultiPlatformExecuteProcessRequest,
-  ) -> Option<ExecuteProcessRequest> {
+  ) -> Option<Arc<ExecuteProcessRequest>> {
     self.inner.0.extract_compatible_request(&req)
   }
+
+  fn metrics(&self) -> HashMap<&'static str, i64> {
+    self.inner.0.metrics()
+  }
 }
 
 impl From<Box<BoundedCommandRunner>> for Arc<dyn CommandRunner> {
@@ -295,13 +1627,320 @@ impl From<Box<BoundedCommandRunner>> for Arc<dyn CommandRunner> {
   }
 }
 
+///
+/// Runs the given request n times in sequence, forcing each run to re-execute rather than being
+/// served from a cache. Useful for checking whether a process is deterministic.
+///
+pub fn run_n_times(
+  command_runner: Arc<dyn CommandRunner>,
+  req: ExecuteProcessRequest,
+  n: usize,
+  workunit_store: WorkUnitStore,
+) -> BoxFuture<Vec<FallibleExecuteProcessResult>, String> {
+  let req = ExecuteProcessRequest {
+    force_rerun: true,
+    ..req
+  };
+  futures::stream::iter_ok(0..n)
+    .and_then(move |_| command_runner.run(req.clone().into(), workunit_store.clone()))
+    .collect()
+    .to_boxed()
+}
+
 #[cfg(test)]
 mod tests {
-  use super::{ExecuteProcessRequest, Platform};
+  use super::{
+    check_remote_execution_supported, render_execution_attempts, render_output_preview,
+    CompatibleConstraintCache, ExecuteProcessRequest, ExecutionEnvironment, ExecutionLocality,
+    ExecutionStats, FallibleExecuteProcessResult, MultiPlatformExecuteProcessRequest, Platform,
+    ProcessOutput, ProcessResultSource, MAX_OUTPUT_PREVIEW_LEN, MAX_RENDERED_ATTEMPTS_LEN,
+  };
+  use crate::output_size::ExpectedOutputSize;
+  use crate::scheduling_hints::SchedulingHints;
+  use hashing::{Digest, Fingerprint};
+  use std::cmp::Ordering;
   use std::collections::hash_map::DefaultHasher;
   use std::collections::{BTreeMap, BTreeSet};
+  use std::convert::TryFrom;
   use std::hash::{Hash, Hasher};
+  use std::path::PathBuf;
+  use std::sync::Arc;
   use std::time::Duration;
+  use testutil::data::{TestData, TestDirectory};
+
+  pub(crate) fn execute_process_request(description: &str) -> ExecuteProcessRequest {
+    ExecuteProcessRequest {
+      argv: vec![description.to_owned()],
+      env: BTreeMap::new(),
+      input_files: hashing::EMPTY_DIGEST,
+      output_files: BTreeSet::new(),
+      output_directories: BTreeSet::new(),
+      timeout: Duration::new(0, 0),
+      description: description.to_owned(),
+      jdk_home: None,
+      target_platform: Platform::None,
+      force_rerun: false,
+      expected_output_digest: None,
+      diff_outputs: false,
+      ephemeral_input_digests: vec![],
+      scheduling_hints: None,
+      poll_interval_hint: None,
+      cache_scope_names: BTreeSet::new(),
+      argfile_threshold: None,
+      argfile_flag_template: None,
+      provenance: None,
+      environment: None,
+      omit_cache_key_gen_version: false,
+      expected_output_size: None,
+      execution_locality: ExecutionLocality::Any,
+    }
+  }
+
+  fn multi_platform_request(
+    constraints: &[(Platform, Platform)],
+    description: &str,
+  ) -> MultiPlatformExecuteProcessRequest {
+    MultiPlatformExecuteProcessRequest(
+      constraints
+        .iter()
+        .map(|constraint| (*constraint, Arc::new(execute_process_request(description))))
+        .collect(),
+    )
+  }
+
+  fn populated_execution_stats() -> ExecutionStats {
+    ExecutionStats {
+      uploaded_bytes: 1024,
+      uploaded_file_count: 3,
+      upload: Duration::from_millis(120),
+      upload_queue: None,
+      action_bytes: Some(138),
+      command_bytes: Some(2048),
+      cancellation_wait: None,
+      operation_wait: None,
+      polling: None,
+      download: None,
+      polls: None,
+      remote_queue: Some(Duration::from_millis(5)),
+      remote_input_fetch: None,
+      remote_execution: Some(Duration::from_secs(2)),
+      remote_output_store: None,
+      remote_worker_overhead: None,
+      was_cache_hit: false,
+    }
+  }
+
+  #[test]
+  fn execution_stats_display_is_compact() {
+    assert_eq!(
+      format!("{}", populated_execution_stats()),
+      "uploaded_bytes=1024 uploaded_file_count=3 upload=120ms action_bytes=138 command_bytes=2048 \
+       remote_queue=5ms remote_execution=2000ms was_cache_hit=false"
+    );
+  }
+
+  #[test]
+  fn render_execution_attempts_collapses_identical_runs() {
+    let populated = populated_execution_stats();
+    let empty = ExecutionStats::default();
+    assert_eq!(
+      render_execution_attempts(&[empty, empty, empty, populated, empty]),
+      vec![
+        format!("Attempts 0-2: {} x3", empty),
+        format!("Attempt 3: {}", populated),
+        format!("Attempt 4: {}", empty),
+      ]
+      .join("\n")
+    );
+  }
+
+  #[test]
+  fn render_execution_attempts_is_capped() {
+    // Alternate attempts so that none of them are collapsed.
+    let attempts = (0..200)
+      .map(|i| ExecutionStats {
+        uploaded_bytes: i % 2,
+        ..populated_execution_stats()
+      })
+      .collect::<Vec<_>>();
+    let rendered = render_execution_attempts(&attempts);
+    assert!(rendered.len() <= MAX_RENDERED_ATTEMPTS_LEN, "{}", rendered);
+    assert!(rendered.starts_with("Attempt 0: "), "{}", rendered);
+    assert!(rendered.contains("attempts elided"), "{}", rendered);
+    assert!(
+      rendered.ends_with(&format!("Attempt 199: {}", attempts[199])),
+      "{}",
+      rendered
+    );
+  }
+
+  fn failed_result(stderr: &[u8]) -> FallibleExecuteProcessResult {
+    FallibleExecuteProcessResult {
+      stdout: bytes::Bytes::new().into(),
+      stderr: bytes::Bytes::from(stderr).into(),
+      exit_code: 1,
+      output_directory: hashing::EMPTY_DIGEST,
+      execution_attempts: vec![],
+      source: ProcessResultSource::RanLocally,
+      termination_signal: None,
+      server_message: None,
+      timeout_details: None,
+      rewritten_action_digest: None,
+      output_size_anomaly: None,
+      platform: Platform::None,
+    }
+  }
+
+  #[test]
+  fn error_summary_keeps_head_and_tail_of_stderr() {
+    let stderr = (0..100)
+      .map(|i| format!("line {}\n", i))
+      .collect::<String>();
+
+    let summary = failed_result(stderr.as_bytes()).error_summary();
+    let expected_head = (0..10)
+      .map(|i| format!("line {}", i))
+      .collect::<Vec<_>>()
+      .join("\n");
+    let expected_tail = (90..100)
+      .map(|i| format!("line {}", i))
+      .collect::<Vec<_>>()
+      .join("\n");
+    assert_eq!(
+      summary,
+      format!(
+        "Exited with code 1. stderr:\n{}\n... 80 lines elided ...\n{}",
+        expected_head, expected_tail
+      )
+    );
+  }
+
+  #[test]
+  fn error_summary_without_stderr() {
+    assert_eq!(
+      failed_result(b"").error_summary(),
+      "Exited with code 1 and no stderr."
+    );
+  }
+
+  #[test]
+  fn error_summary_of_spilled_stderr() {
+    let digest = Digest(
+      Fingerprint::from_hex_string(
+        "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef",
+      )
+      .unwrap(),
+      4096,
+    );
+    let result = FallibleExecuteProcessResult {
+      stderr: ProcessOutput::Stored(digest),
+      ..failed_result(b"")
+    };
+    assert_eq!(result.stderr.len(), 4096);
+    assert_eq!(
+      result.error_summary(),
+      "Exited with code 1. stderr:\n<4096 bytes, stored as \
+       0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef>"
+    );
+  }
+
+  #[test]
+  fn error_summary_includes_server_message() {
+    let result = FallibleExecuteProcessResult {
+      server_message: Some("Worker pool old-pool is deprecated".to_owned()),
+      ..failed_result(b"")
+    };
+    assert_eq!(
+      result.error_summary(),
+      "Exited with code 1 and no stderr.\nMessage from the server: Worker pool old-pool is \
+       deprecated"
+    );
+  }
+
+  #[test]
+  fn render_output_preview_replaces_binary() {
+    assert_eq!(
+      render_output_preview(b"ELF\0\0\x01"),
+      "<binary, 6 bytes>".to_owned()
+    );
+    assert_eq!(
+      render_output_preview(&[0xff, 0xfe, 0x41]),
+      "<binary, 3 bytes>".to_owned()
+    );
+  }
+
+  #[test]
+  fn render_output_preview_caps_long_lines() {
+    let output = format!("{}{}", "a".repeat(10000), "b".repeat(10000));
+
+    let preview = render_output_preview(output.as_bytes());
+    assert!(preview.len() <= MAX_OUTPUT_PREVIEW_LEN, "{}", preview.len());
+    assert!(preview.starts_with("aaaa"));
+    assert!(preview.ends_with("bbbb"));
+    assert!(preview.contains("bytes elided"));
+  }
+
+  #[test]
+  fn compatible_constraint_cache_shares_request() {
+    let cache = CompatibleConstraintCache::new();
+    let req = multi_platform_request(
+      &[
+        (Platform::None, Platform::None),
+        (Platform::Darwin, Platform::None),
+      ],
+      "one",
+    );
+
+    let extracted = cache
+      .extract(&req, || {
+        vec![
+          (Platform::Darwin, Platform::None),
+          (Platform::None, Platform::None),
+        ]
+      })
+      .unwrap();
+    assert!(Arc::ptr_eq(
+      &extracted,
+      &req.0[&(Platform::Darwin, Platform::None)]
+    ));
+  }
+
+  #[test]
+  fn compatible_constraint_cache_memoizes_selection_by_shape() {
+    let cache = CompatibleConstraintCache::new();
+    let constraints = [
+      (Platform::None, Platform::None),
+      (Platform::Linux, Platform::None),
+    ];
+    let first = multi_platform_request(&constraints, "first");
+    let second = multi_platform_request(&constraints, "second");
+
+    let selected = cache.extract(&first, || vec![(Platform::Linux, Platform::None)]);
+    assert!(Arc::ptr_eq(
+      &selected.unwrap(),
+      &first.0[&(Platform::Linux, Platform::None)]
+    ));
+
+    // A request of the same shape uses the memoized selection, without computing preferences.
+    let selected = cache.extract(&second, || panic!("Selection should have been memoized."));
+    assert!(Arc::ptr_eq(
+      &selected.unwrap(),
+      &second.0[&(Platform::Linux, Platform::None)]
+    ));
+
+    // As does a request of a shape which no constraint matched.
+    let unmatched = multi_platform_request(&[(Platform::Darwin, Platform::Darwin)], "unmatched");
+    assert_eq!(
+      cache.extract(&unmatched, || vec![(Platform::None, Platform::None)]),
+      None
+    );
+    assert_eq!(
+      cache.extract(&unmatched, || panic!(
+        "Selection should have been memoized."
+      )),
+      None
+    );
+  }
 
   #[test]
   fn execute_process_request_equality() {
@@ -316,14 +1955,22 @@ mod tests {
         description,
         jdk_home: None,
         target_platform: Platform::None,
+        force_rerun: false,
+        expected_output_digest: None,
+        diff_outputs: false,
+        ephemeral_input_digests: vec![],
+        scheduling_hints: None,
+        poll_interval_hint: None,
+        cache_scope_names: BTreeSet::new(),
+        argfile_threshold: None,
+        argfile_flag_template: None,
+        provenance: None,
+        environment: None,
+        omit_cache_key_gen_version: false,
+        expected_output_size: None,
+        execution_locality: ExecutionLocality::Any,
       };
 
-    fn hash<Hashable: Hash>(hashable: &Hashable) -> u64 {
-      let mut hasher = DefaultHasher::new();
-      hashable.hash(&mut hasher);
-      hasher.finish()
-    }
-
     let a = execute_process_request_generator("One thing".to_string(), Duration::new(0, 0));
     let b = execute_process_request_generator("Another".to_string(), Duration::new(0, 0));
     let c = execute_process_request_generator("One thing".to_string(), Duration::new(5, 0));
@@ -336,4 +1983,307 @@ mod tests {
     assert!(a != c);
     assert!(hash(&a) != hash(&c));
   }
+
+  fn hash<Hashable: Hash>(hashable: &Hashable) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hashable.hash(&mut hasher);
+    hasher.finish()
+  }
+
+  fn fingerprinted_request() -> ExecuteProcessRequest {
+    ExecuteProcessRequest {
+      argv: vec!["/bin/echo".to_owned(), "yo".to_owned()],
+      env: vec![("SOME".to_owned(), "value".to_owned())]
+        .into_iter()
+        .collect(),
+      input_files: TestDirectory::containing_roland().digest(),
+      output_files: vec!["path/to/file", "other/file"]
+        .into_iter()
+        .map(PathBuf::from)
+        .collect(),
+      output_directories: vec!["directory/name"]
+        .into_iter()
+        .map(PathBuf::from)
+        .collect(),
+      timeout: Duration::from_millis(1000),
+      description: "some description".to_owned(),
+      ..execute_process_request("")
+    }
+  }
+
+  #[test]
+  fn multi_platform_request_identity_ignores_construction_order() {
+    let roland = TestData::roland().digest();
+    let catnip = TestData::catnip().digest();
+    let linux = (Platform::Linux, Platform::Linux);
+    let darwin = (Platform::Darwin, Platform::Darwin);
+    let request = |ephemeral_input_digests: Vec<Digest>| {
+      Arc::new(ExecuteProcessRequest {
+        ephemeral_input_digests,
+        ..fingerprinted_request()
+      })
+    };
+
+    let mut a = BTreeMap::new();
+    a.insert(linux, request(vec![roland, catnip]));
+    a.insert(darwin, request(vec![catnip]));
+    let mut b = BTreeMap::new();
+    b.insert(darwin, request(vec![catnip]));
+    b.insert(linux, request(vec![catnip, roland]));
+    let a = MultiPlatformExecuteProcessRequest(a);
+    let b = MultiPlatformExecuteProcessRequest(b);
+
+    assert_eq!(a, b);
+    assert_eq!(hash(&a), hash(&b));
+    assert_eq!(a.cmp(&b), Ordering::Equal);
+    assert_eq!(a.fingerprint(), b.fingerprint());
+  }
+
+  #[test]
+  fn fingerprint_is_stable() {
+    // If this changes, every cached process result is invalidated.
+    assert_eq!(
+      MultiPlatformExecuteProcessRequest::from(fingerprinted_request()).fingerprint(),
+      Ok(Digest(
+        Fingerprint::from_hex_string(
+          "3310ca42bda693961d9a9cee42491af61decd904d5cf47949c0a2b1bc255a819"
+        )
+        .unwrap(),
+        209
+      ))
+    );
+  }
+
+  #[test]
+  fn fingerprint_is_sensitive_to_semantic_changes() {
+    let variants: Vec<ExecuteProcessRequest> = vec![
+      fingerprinted_request(),
+      ExecuteProcessRequest {
+        argv: vec!["/bin/echo".to_owned(), "yo!".to_owned()],
+        ..fingerprinted_request()
+      },
+      ExecuteProcessRequest {
+        env: BTreeMap::new(),
+        ..fingerprinted_request()
+      },
+      ExecuteProcessRequest {
+        input_files: hashing::EMPTY_DIGEST,
+        ..fingerprinted_request()
+      },
+      ExecuteProcessRequest {
+        output_files: BTreeSet::new(),
+        ..fingerprinted_request()
+      },
+      ExecuteProcessRequest {
+        output_directories: BTreeSet::new(),
+        ..fingerprinted_request()
+      },
+      ExecuteProcessRequest {
+        timeout: Duration::from_millis(1001),
+        ..fingerprinted_request()
+      },
+      ExecuteProcessRequest {
+        jdk_home: Some(PathBuf::from("/usr/lib/jvm")),
+        ..fingerprinted_request()
+      },
+      ExecuteProcessRequest {
+        target_platform: Platform::Linux,
+        ..fingerprinted_request()
+      },
+      ExecuteProcessRequest {
+        force_rerun: true,
+        ..fingerprinted_request()
+      },
+      ExecuteProcessRequest {
+        expected_output_digest: Some(hashing::EMPTY_DIGEST),
+        ..fingerprinted_request()
+      },
+      ExecuteProcessRequest {
+        diff_outputs: true,
+        ..fingerprinted_request()
+      },
+      ExecuteProcessRequest {
+        ephemeral_input_digests: vec![TestData::roland().digest()],
+        ..fingerprinted_request()
+      },
+      ExecuteProcessRequest {
+        scheduling_hints: Some(SchedulingHints {
+          cpu_millis: 1000,
+          ..SchedulingHints::default()
+        }),
+        ..fingerprinted_request()
+      },
+      ExecuteProcessRequest {
+        poll_interval_hint: Some(Duration::from_millis(10)),
+        ..fingerprinted_request()
+      },
+      ExecuteProcessRequest {
+        cache_scope_names: vec!["glibc".to_owned()].into_iter().collect(),
+        ..fingerprinted_request()
+      },
+      ExecuteProcessRequest {
+        argfile_threshold: Some(1024),
+        ..fingerprinted_request()
+      },
+      ExecuteProcessRequest {
+        argfile_flag_template: Some("@{path}".to_owned()),
+        ..fingerprinted_request()
+      },
+      ExecuteProcessRequest {
+        environment: Some("linux-gpu".to_owned()),
+        ..fingerprinted_request()
+      },
+      ExecuteProcessRequest {
+        omit_cache_key_gen_version: true,
+        ..fingerprinted_request()
+      },
+      ExecuteProcessRequest {
+        expected_output_size: Some(ExpectedOutputSize {
+          bytes: 1024,
+          tolerance_percent: None,
+          strict: false,
+        }),
+        ..fingerprinted_request()
+      },
+      ExecuteProcessRequest {
+        execution_locality: ExecutionLocality::LocalOnly,
+        ..fingerprinted_request()
+      },
+      ExecuteProcessRequest {
+        execution_locality: ExecutionLocality::RemoteOnly,
+        ..fingerprinted_request()
+      },
+    ];
+    let mut fingerprints = variants
+      .into_iter()
+      .map(|req| {
+        MultiPlatformExecuteProcessRequest::from(req)
+          .fingerprint()
+          .unwrap()
+      })
+      .collect::<Vec<_>>();
+    let constrained = MultiPlatformExecuteProcessRequest(
+      vec![(
+        (Platform::Linux, Platform::None),
+        Arc::new(fingerprinted_request()),
+      )]
+      .into_iter()
+      .collect(),
+    );
+    fingerprints.push(constrained.fingerprint().unwrap());
+
+    let distinct = fingerprints.iter().collect::<BTreeSet<_>>();
+    assert_eq!(distinct.len(), fingerprints.len());
+
+    // The description is not semantic.
+    let redescribed = ExecuteProcessRequest {
+      description: "another description".to_owned(),
+      ..fingerprinted_request()
+    };
+    assert_eq!(
+      MultiPlatformExecuteProcessRequest::from(redescribed).fingerprint(),
+      Ok(fingerprints[0])
+    );
+  }
+
+  #[test]
+  fn provenance_does_not_affect_identity_or_digests() {
+    let attributed = ExecuteProcessRequest {
+      provenance: Some("scalac() for goal `compile`".to_owned()),
+      ..fingerprinted_request()
+    };
+
+    assert_eq!(attributed, fingerprinted_request());
+    assert_eq!(hash(&attributed), hash(&fingerprinted_request()));
+    assert_eq!(
+      MultiPlatformExecuteProcessRequest::from(attributed.clone()).fingerprint(),
+      MultiPlatformExecuteProcessRequest::from(fingerprinted_request()).fingerprint()
+    );
+    let action_digest = |req: &ExecuteProcessRequest| {
+      crate::action::make_execute_request(req, super::fingerprint_metadata())
+        .unwrap()
+        .2
+        .take_action_digest()
+    };
+    assert_eq!(
+      action_digest(&attributed),
+      action_digest(&fingerprinted_request())
+    );
+  }
+
+  #[test]
+  fn remote_execution_is_supported_only_with_the_feature() {
+    if cfg!(feature = "remote-execution") {
+      assert_eq!(check_remote_execution_supported(), Ok(()));
+    } else {
+      let err = check_remote_execution_supported().unwrap_err();
+      assert!(err.contains("`remote-execution` feature"), "{}", err);
+    }
+  }
+
+  #[test]
+  fn execution_locality_violations_only_fail_when_strict() {
+    assert_eq!(ExecutionLocality::Any.check("echo", true, true), Ok(None));
+    assert_eq!(
+      ExecutionLocality::LocalOnly.check("echo", false, true),
+      Ok(None)
+    );
+    assert_eq!(
+      ExecutionLocality::RemoteOnly.check("echo", true, true),
+      Ok(None)
+    );
+
+    let warning = ExecutionLocality::LocalOnly
+      .check("echo", true, false)
+      .unwrap()
+      .unwrap();
+    assert!(warning.contains("running it remotely"), "{}", warning);
+    let err = ExecutionLocality::RemoteOnly
+      .check("echo", false, true)
+      .unwrap_err();
+    assert!(err.contains("may only run remotely"), "{}", err);
+  }
+
+  #[test]
+  fn execution_localities_are_parsed_from_their_python_names() {
+    for &(name, locality) in &[
+      ("any", ExecutionLocality::Any),
+      ("local_only", ExecutionLocality::LocalOnly),
+      ("remote_only", ExecutionLocality::RemoteOnly),
+    ] {
+      assert_eq!(ExecutionLocality::try_from(&name.to_owned()), Ok(locality));
+    }
+    let err = ExecutionLocality::try_from(&"nowhere".to_owned()).unwrap_err();
+    assert!(err.contains("\"nowhere\""), "{}", err);
+  }
+
+  #[test]
+  fn execution_environments_are_parsed_from_json() {
+    let environments = ExecutionEnvironment::parse_all(
+      r#"{
+        "gpu": {"platform_properties": {"pool": "gpu", "OSFamily": "linux"}, "priority": 3},
+        "bare": {}
+      }"#,
+    )
+    .unwrap();
+    assert_eq!(
+      environments.get("gpu"),
+      Some(&ExecutionEnvironment {
+        platform_properties: vec![
+          ("OSFamily".to_owned(), "linux".to_owned()),
+          ("pool".to_owned(), "gpu".to_owned()),
+        ],
+        container_image: None,
+        instance_name: None,
+        priority: Some(3),
+      })
+    );
+    assert_eq!(
+      environments.get("bare"),
+      Some(&ExecutionEnvironment::default())
+    );
+
+    let err = ExecutionEnvironment::parse_all(r#"{"gpu": {"image": "x"}}"#).unwrap_err();
+    assert!(err.contains("image"), "{}", err);
+  }
 }
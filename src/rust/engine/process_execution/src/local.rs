@@ -4,13 +4,14 @@ use tempfile;
 use boxfuture::{try_future, BoxFuture, Boxable};
 use fs::{self, GlobExpansionConjunction, GlobMatching, PathGlobs, StrictGlobMatching};
 use futures::{future, Future, Stream};
-use log::info;
-use std::collections::{BTreeSet, HashSet};
+use hashing::Digest;
+use log::{info, warn};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::ffi::OsStr;
 use std::fs::create_dir_all;
 use std::ops::Neg;
 use std::os::unix::{fs::symlink, process::ExitStatusExt};
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::process::{Command, Stdio};
 use std::sync::Arc;
 use store::{OneOffStoreFileByDigest, Snapshot, Store};
@@ -19,18 +20,43 @@ use tokio_codec::{BytesCodec, FramedRead};
 use tokio_process::CommandExt;
 
 use super::{
-  ExecuteProcessRequest, FallibleExecuteProcessResult, MultiPlatformExecuteProcessRequest, Platform,
+  CompatibleConstraintCache, ExecuteProcessRequest, FallibleExecuteProcessResult,
+  MultiPlatformExecuteProcessRequest, Platform, ProcessProgress, ProcessResultSource,
 };
 
 use bytes::{Bytes, BytesMut};
 use workunit_store::WorkUnitStore;
 
+///
+/// How a process is run: derived either from an ExecuteProcessRequest, or (to reproduce the
+/// semantics of a remote execution exactly) from the Action and Command that a request is sent to
+/// servers as.
+///
+struct Invocation {
+  argv: Vec<String>,
+  env: BTreeMap<String, String>,
+  // Relative to the input root.
+  working_directory: PathBuf,
+  input_files: Digest,
+  // Relative to the working directory.
+  output_files: BTreeSet<PathBuf>,
+  output_directories: BTreeSet<PathBuf>,
+  jdk_home: Option<PathBuf>,
+  description: String,
+  // If set, PATH is set to the empty string unless the env sets it, so that the binary is never
+  // looked up on the PATH of pants itself.
+  empty_path: bool,
+}
+
 pub struct CommandRunner {
   store: Store,
   executor: task_executor::Executor,
   work_dir: PathBuf,
   cleanup_local_dirs: bool,
   platform: Platform,
+  compatible_constraints: CompatibleConstraintCache,
+  // Whether requests which may only run remotely fail, rather than being warned about and run.
+  strict_execution_locality: bool,
 }
 
 impl CommandRunner {
@@ -46,9 +72,20 @@ impl CommandRunner {
       work_dir,
       cleanup_local_dirs,
       platform: Platform::current_platform().unwrap(),
+      compatible_constraints: CompatibleConstraintCache::new(),
+      strict_execution_locality: false,
     }
   }
 
+  ///
+  /// Fails requests which may only run remotely (see `ExecutionLocality::check`), rather than
+  /// warning about them and running them locally.
+  ///
+  pub fn with_strict_execution_locality(mut self, strict: bool) -> CommandRunner {
+    self.strict_execution_locality = strict;
+    self
+  }
+
   fn construct_output_snapshot(
     store: Store,
     posix_fs: Arc<fs::PosixFS>,
@@ -90,6 +127,232 @@ impl CommandRunner {
       })
       .to_boxed()
   }
+
+  ///
+  /// Snapshots the given output files and directories of a process which ran in `root`, which
+  /// they are relative to.
+  ///
+  fn collect_outputs(
+    store: Store,
+    executor: task_executor::Executor,
+    root: PathBuf,
+    output_file_paths: BTreeSet<PathBuf>,
+    output_dir_paths: BTreeSet<PathBuf>,
+  ) -> BoxFuture<Snapshot, String> {
+    if output_file_paths.is_empty() && output_dir_paths.is_empty() {
+      return future::ok(store::Snapshot::empty()).to_boxed();
+    }
+    // Use no ignore patterns, because we are looking for explicitly listed paths.
+    future::done(fs::PosixFS::new(root, &[], executor))
+      .map_err(|err| {
+        format!(
+          "Error making posix_fs to fetch local process execution output files: {}",
+          err
+        )
+      })
+      .map(Arc::new)
+      .and_then(|posix_fs| {
+        CommandRunner::construct_output_snapshot(
+          store,
+          posix_fs,
+          output_file_paths,
+          output_dir_paths,
+        )
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Runs the given Command of the given Action with exactly the semantics that a remote execution
+  /// server gives it: with only the Command's env, in its working_directory, and with the outputs
+  /// that it declares collected relative to that directory. Unlike `run`, no PATH is set unless
+  /// the Command sets one (or names its binary without a path), and no JDK is symlinked.
+  ///
+  /// This allows a local run and a remote run of the same request to be compared (see
+  /// `local_conformance`).
+  ///
+  pub fn run_from_command_proto(
+    &self,
+    action: &bazel_protos::remote_execution::Action,
+    command: &bazel_protos::remote_execution::Command,
+    workunit_store: WorkUnitStore,
+  ) -> BoxFuture<FallibleExecuteProcessResult, String> {
+    let input_files: Result<Digest, String> = action.get_input_root_digest().into();
+    let argv = command.get_arguments().to_vec();
+    if argv.is_empty() {
+      return future::err("Cannot run a Command without arguments".to_owned()).to_boxed();
+    }
+    let env = command
+      .get_environment_variables()
+      .iter()
+      .map(|env| (env.get_name().to_owned(), env.get_value().to_owned()))
+      .collect::<BTreeMap<_, _>>();
+    // As remotely, the working_directory must be within the input root.
+    let working_directory = PathBuf::from(command.get_working_directory());
+    let within_input_root = working_directory
+      .components()
+      .all(|component| match component {
+        Component::Normal(_) | Component::CurDir => true,
+        Component::Prefix(_) | Component::RootDir | Component::ParentDir => false,
+      });
+    if !within_input_root {
+      return future::err(format!(
+        "Command working_directory must be a relative path within the input root, but was {:?}",
+        command.get_working_directory()
+      ))
+      .to_boxed();
+    }
+    let empty_path = !env.contains_key("PATH") && !argv[0].contains('/');
+    let invocation = Invocation {
+      description: format!("Command {:?}", argv),
+      argv,
+      env,
+      working_directory,
+      input_files: try_future!(input_files),
+      output_files: command
+        .get_output_files()
+        .iter()
+        .map(PathBuf::from)
+        .collect(),
+      output_directories: command
+        .get_output_directories()
+        .iter()
+        .map(PathBuf::from)
+        .collect(),
+      jdk_home: None,
+      empty_path,
+    };
+    self.run_invocation(invocation, ProcessProgress::new(), workunit_store)
+  }
+
+  fn run_invocation(
+    &self,
+    invocation: Invocation,
+    progress: ProcessProgress,
+    workunit_store: WorkUnitStore,
+  ) -> BoxFuture<FallibleExecuteProcessResult, String> {
+    let workdir = try_future!(tempfile::Builder::new()
+      .prefix("process-execution")
+      .tempdir_in(&self.work_dir)
+      .map_err(|err| format!(
+        "Error making tempdir for local process execution: {:?}",
+        err
+      )));
+    let Invocation {
+      argv,
+      env,
+      working_directory,
+      input_files,
+      output_files: output_file_paths,
+      output_directories: output_dir_paths,
+      jdk_home: maybe_jdk_home,
+      description: req_description,
+      empty_path,
+    } = invocation;
+    let input_root_path = workdir.path().to_owned();
+    let workdir_path = input_root_path.join(working_directory);
+    let workdir_path2 = workdir_path.clone();
+    let workdir_path3 = workdir_path.clone();
+    let store = self.store.clone();
+    let executor = self.executor.clone();
+
+    let output_file_paths2 = output_file_paths.clone();
+    let output_dir_paths2 = output_dir_paths.clone();
+    let cleanup_local_dirs = self.cleanup_local_dirs;
+    let platform = self.platform;
+    self
+      .store
+      .materialize_directory(input_root_path.clone(), input_files, workunit_store)
+      .and_then(move |_metadata| {
+        maybe_jdk_home.map_or(Ok(()), |jdk_home| {
+          symlink(jdk_home, input_root_path.join(".jdk"))
+            .map_err(|err| format!("Error making symlink for local execution: {:?}", err))
+        })?;
+        // The bazel remote execution API specifies that the parent directories for output files and
+        // output directories should be created before execution completes: see
+        //   https://github.com/pantsbuild/pants/issues/7084.
+        let parent_paths_to_create: HashSet<_> = output_file_paths2
+          .iter()
+          .chain(output_dir_paths2.iter())
+          .filter_map(|rel_path| rel_path.parent())
+          .map(|parent_relpath| workdir_path3.join(parent_relpath))
+          .collect();
+        // TODO: we use a HashSet to deduplicate directory paths to create, but it would probably be
+        // even more efficient to only retain the directories at greatest nesting depth, as
+        // create_dir_all() will ensure all parents are created. At that point, we might consider
+        // explicitly enumerating all the directories to be created and just using create_dir(),
+        // unless there is some optimization in create_dir_all() that makes that less efficient.
+        for path in parent_paths_to_create {
+          create_dir_all(path.clone()).map_err(|err| {
+            format!(
+              "Error making parent directory {:?} for local execution: {:?}",
+              path, err
+            )
+          })?;
+        }
+        Ok(())
+      })
+      .and_then(move |()| {
+        let mut command = StreamedHermeticCommand::new(&argv[0]);
+        if !empty_path {
+          command.without_empty_path();
+        }
+        command
+          .args(&argv[1..])
+          .current_dir(&workdir_path)
+          .envs(env)
+          .stream()
+      })
+      // NB: We fully buffer up the `Stream` above into final `ChildResults` below, but also pass
+      // stdout incrementally to the ProcessProgress. The idea going forward is to stream process
+      // results to console logs, etc. as tracked by:
+      //   https://github.com/pantsbuild/pants/issues/6089
+      .and_then(move |stream| {
+        progress.mark_running();
+        ChildResults::collect_from(stream.inspect(move |child_output| {
+          if let ChildOutput::Stdout(bytes) = child_output {
+            progress.append_stdout(bytes);
+          }
+        }))
+      })
+      .and_then(move |child_results| {
+        CommandRunner::collect_outputs(
+          store,
+          executor,
+          workdir_path2,
+          output_file_paths,
+          output_dir_paths,
+        )
+        .map(move |snapshot| FallibleExecuteProcessResult {
+          stdout: child_results.stdout.into(),
+          stderr: child_results.stderr.into(),
+          exit_code: child_results.exit_code,
+          output_directory: snapshot.digest,
+          execution_attempts: vec![],
+          source: ProcessResultSource::RanLocally,
+          termination_signal: None,
+          server_message: None,
+          timeout_details: None,
+          rewritten_action_digest: None,
+          output_size_anomaly: None,
+          platform,
+        })
+      })
+      .then(move |result| {
+        // Force workdir not to get dropped until after we've ingested the outputs
+        if !cleanup_local_dirs {
+          // This consumes the `TempDir` without deleting directory on the filesystem, meaning
+          // that the temporary directory will no longer be automatically deleted when dropped.
+          let preserved_path = workdir.into_path();
+          info!(
+            "preserved local process execution dir `{:?}` for {:?}",
+            preserved_path, req_description
+          );
+        } // Else, workdir gets dropped here
+        result
+      })
+      .to_boxed()
+  }
 }
 
 struct StreamedHermeticCommand {
@@ -120,6 +383,14 @@ impl StreamedHermeticCommand {
     StreamedHermeticCommand { inner }
   }
 
+  ///
+  /// Unsets PATH (unless it is later set via `envs`), for a binary which is named by its path.
+  ///
+  fn without_empty_path(&mut self) -> &mut StreamedHermeticCommand {
+    self.inner.env_remove("PATH");
+    self
+  }
+
   fn args<I, S>(&mut self, args: I) -> &mut StreamedHermeticCommand
   where
     I: IntoIterator<Item = S>,
@@ -218,147 +489,59 @@ impl super::CommandRunner for CommandRunner {
   fn extract_compatible_request(
     &self,
     req: &MultiPlatformExecuteProcessRequest,
-  ) -> Option<ExecuteProcessRequest> {
-    for compatible_constraint in vec![
-      &(Platform::None, Platform::None),
-      &(self.platform, Platform::None),
-      &(self.platform, Platform::current_platform().unwrap()),
-    ]
-    .iter()
-    {
-      if let Some(compatible_req) = req.0.get(compatible_constraint) {
-        return Some(compatible_req.clone());
-      }
-    }
-    None
+  ) -> Option<Arc<ExecuteProcessRequest>> {
+    self
+      .compatible_constraints
+      .extract_for_platform(req, self.platform)
+      .filter(|req| req.execution_locality.allows_local())
   }
 
   ///
   /// Runs a command on this machine in the passed working directory.
   ///
-  /// TODO: start to create workunits for local process execution
-  ///
   fn run(
     &self,
     req: MultiPlatformExecuteProcessRequest,
     workunit_store: WorkUnitStore,
   ) -> BoxFuture<FallibleExecuteProcessResult, String> {
-    let workdir = try_future!(tempfile::Builder::new()
-      .prefix("process-execution")
-      .tempdir_in(&self.work_dir)
-      .map_err(|err| format!(
-        "Error making tempdir for local process execution: {:?}",
-        err
-      )));
-    let req = self.extract_compatible_request(&req).unwrap();
-    let workdir_path = workdir.path().to_owned();
-    let workdir_path2 = workdir_path.clone();
-    let workdir_path3 = workdir_path.clone();
-    let store = self.store.clone();
-    let executor = self.executor.clone();
-
-    let env = req.env;
-    let output_file_paths = req.output_files;
-    let output_file_paths2 = output_file_paths.clone();
-    let output_dir_paths = req.output_directories;
-    let output_dir_paths2 = output_dir_paths.clone();
-    let cleanup_local_dirs = self.cleanup_local_dirs;
-    let argv = req.argv;
-    let req_description = req.description;
-    let maybe_jdk_home = req.jdk_home;
-    self
-      .store
-      .materialize_directory(workdir_path.clone(), req.input_files, workunit_store)
-      .and_then(move |_metadata| {
-        maybe_jdk_home.map_or(Ok(()), |jdk_home| {
-          symlink(jdk_home, workdir_path3.clone().join(".jdk"))
-            .map_err(|err| format!("Error making symlink for local execution: {:?}", err))
-        })?;
-        // The bazel remote execution API specifies that the parent directories for output files and
-        // output directories should be created before execution completes: see
-        //   https://github.com/pantsbuild/pants/issues/7084.
-        let parent_paths_to_create: HashSet<_> = output_file_paths2
-          .iter()
-          .chain(output_dir_paths2.iter())
-          .filter_map(|rel_path| rel_path.parent())
-          .map(|parent_relpath| workdir_path3.join(parent_relpath))
-          .collect();
-        // TODO: we use a HashSet to deduplicate directory paths to create, but it would probably be
-        // even more efficient to only retain the directories at greatest nesting depth, as
-        // create_dir_all() will ensure all parents are created. At that point, we might consider
-        // explicitly enumerating all the directories to be created and just using create_dir(),
-        // unless there is some optimization in create_dir_all() that makes that less efficient.
-        for path in parent_paths_to_create {
-          create_dir_all(path.clone()).map_err(|err| {
-            format!(
-              "Error making parent directory {:?} for local execution: {:?}",
-              path, err
-            )
-          })?;
-        }
-        Ok(())
-      })
-      .and_then(move |()| {
-        StreamedHermeticCommand::new(&argv[0])
-          .args(&argv[1..])
-          .current_dir(&workdir_path)
-          .envs(env)
-          .stream()
-      })
-      // NB: We fully buffer up the `Stream` above into final `ChildResults` below and so could
-      // instead be using `CommandExt::output_async` above to avoid the `ChildResults::collect_from`
-      // code. The idea going forward though is we eventually want to pass incremental results on
-      // down the line for streaming process results to console logs, etc. as tracked by:
-      //   https://github.com/pantsbuild/pants/issues/6089
-      .and_then(ChildResults::collect_from)
-      .and_then(move |child_results| {
-        let output_snapshot = if output_file_paths.is_empty() && output_dir_paths.is_empty() {
-          future::ok(store::Snapshot::empty()).to_boxed()
-        } else {
-          // Use no ignore patterns, because we are looking for explicitly listed paths.
-          future::done(fs::PosixFS::new(workdir_path2, &[], executor))
-            .map_err(|err| {
-              format!(
-                "Error making posix_fs to fetch local process execution output files: {}",
-                err
-              )
-            })
-            .map(Arc::new)
-            .and_then(|posix_fs| {
-              CommandRunner::construct_output_snapshot(
-                store,
-                posix_fs,
-                output_file_paths,
-                output_dir_paths,
-              )
-            })
-            .to_boxed()
-        };
+    self.run_with_progress(req, ProcessProgress::new(), workunit_store)
+  }
 
-        output_snapshot
-          .map(move |snapshot| FallibleExecuteProcessResult {
-            stdout: child_results.stdout,
-            stderr: child_results.stderr,
-            exit_code: child_results.exit_code,
-            output_directory: snapshot.digest,
-            execution_attempts: vec![],
-          })
-          .to_boxed()
-      })
-      .then(move |result| {
-        // Force workdir not to get dropped until after we've ingested the outputs
-        if !cleanup_local_dirs {
-          // This consumes the `TempDir` without deleting directory on the filesystem, meaning
-          // that the temporary directory will no longer be automatically deleted when dropped.
-          let preserved_path = workdir.into_path();
-          info!(
-            "preserved local process execution dir `{:?}` for {:?}",
-            preserved_path, req_description
-          );
-        } // Else, workdir gets dropped here
-        result
-      })
-      .to_boxed()
+  ///
+  /// Runs a command on this machine in the passed working directory, reporting its stdout to the
+  /// given ProcessProgress as it is written.
+  ///
+  /// TODO: start to create workunits for local process execution
+  ///
+  fn run_with_progress(
+    &self,
+    req: MultiPlatformExecuteProcessRequest,
+    progress: ProcessProgress,
+    workunit_store: WorkUnitStore,
+  ) -> BoxFuture<FallibleExecuteProcessResult, String> {
+    let req = self
+      .compatible_constraints
+      .extract_for_platform(&req, self.platform)
+      .unwrap();
+    if let Some(warning) = try_future!(req.execution_locality.check(
+      &req.description,
+      false,
+      self.strict_execution_locality
+    )) {
+      warn!("{}", warning);
+    }
+    let invocation = Invocation {
+      argv: req.argv.clone(),
+      env: req.env.clone(),
+      working_directory: PathBuf::new(),
+      input_files: req.input_files,
+      output_files: req.output_files.clone(),
+      output_directories: req.output_directories.clone(),
+      jdk_home: req.jdk_home.clone(),
+      description: req.description.clone(),
+      empty_path: true,
+    };
+    self.run_invocation(invocation, progress, workunit_store)
   }
 }
 
@@ -368,13 +551,16 @@ mod tests {
   use testutil;
 
   use super::super::CommandRunner as CommandRunnerTrait;
-  use super::{ExecuteProcessRequest, FallibleExecuteProcessResult};
-  use crate::Platform;
+  use super::{ExecuteProcessRequest, FallibleExecuteProcessResult, ProcessResultSource};
+  use crate::{
+    compatible_without_locality, ExecutionLocality, MultiPlatformExecuteProcessRequest, Platform,
+    ProcessProgress, ProcessStatus,
+  };
   use hashing::EMPTY_DIGEST;
   use std;
   use std::collections::{BTreeMap, BTreeSet};
   use std::path::PathBuf;
-  use std::time::Duration;
+  use std::time::{Duration, Instant};
   use store::Store;
   use tempfile::TempDir;
   use testutil::data::{TestData, TestDirectory};
@@ -395,20 +581,92 @@ mod tests {
       description: "echo foo".to_string(),
       jdk_home: None,
       target_platform: Platform::None,
+      force_rerun: false,
+      expected_output_digest: None,
+      diff_outputs: false,
+      ephemeral_input_digests: vec![],
+      scheduling_hints: None,
+      poll_interval_hint: None,
+      cache_scope_names: BTreeSet::new(),
+      argfile_threshold: None,
+      argfile_flag_template: None,
+      provenance: None,
+      environment: None,
+      omit_cache_key_gen_version: false,
+      expected_output_size: None,
+      execution_locality: ExecutionLocality::Any,
     });
 
     assert_eq!(
       result.unwrap(),
       FallibleExecuteProcessResult {
-        stdout: as_bytes("foo"),
-        stderr: as_bytes(""),
+        stdout: as_bytes("foo").into(),
+        stderr: as_bytes("").into(),
         exit_code: 0,
         output_directory: EMPTY_DIGEST,
         execution_attempts: vec![],
+        source: ProcessResultSource::RanLocally,
+        termination_signal: None,
+        server_message: None,
+        timeout_details: None,
+        rewritten_action_digest: None,
+        output_size_anomaly: None,
+        platform: Platform::current_platform().unwrap(),
       }
     )
   }
 
+  #[test]
+  #[cfg(unix)]
+  fn remote_only_requests_run_locally_unless_locality_is_strict() {
+    let store_dir = TempDir::new().unwrap();
+    let work_dir = TempDir::new().unwrap();
+    let executor = task_executor::Executor::new();
+    let store = Store::local_only(executor.clone(), store_dir.path()).unwrap();
+    let runner =
+      super::CommandRunner::new(store, executor.clone(), work_dir.path().to_owned(), true);
+    let req: MultiPlatformExecuteProcessRequest = ExecuteProcessRequest {
+      argv: owned_string_vec(&["/bin/echo", "-n", "foo"]),
+      env: BTreeMap::new(),
+      input_files: EMPTY_DIGEST,
+      output_files: BTreeSet::new(),
+      output_directories: BTreeSet::new(),
+      timeout: Duration::from_millis(1000),
+      description: "echo foo".to_string(),
+      jdk_home: None,
+      target_platform: Platform::None,
+      force_rerun: false,
+      expected_output_digest: None,
+      diff_outputs: false,
+      ephemeral_input_digests: vec![],
+      scheduling_hints: None,
+      poll_interval_hint: None,
+      cache_scope_names: BTreeSet::new(),
+      argfile_threshold: None,
+      argfile_flag_template: None,
+      provenance: None,
+      environment: None,
+      omit_cache_key_gen_version: false,
+      expected_output_size: None,
+      execution_locality: ExecutionLocality::RemoteOnly,
+    }
+    .into();
+
+    assert!(runner.extract_compatible_request(&req).is_none());
+    assert!(compatible_without_locality(&runner, &req));
+
+    let result = executor
+      .block_on(runner.run(req.clone(), WorkUnitStore::new()))
+      .unwrap();
+    assert_eq!(result.stdout, as_bytes("foo"));
+
+    let runner = runner.with_strict_execution_locality(true);
+    let err = executor
+      .block_on(runner.run(req, WorkUnitStore::new()))
+      .unwrap_err();
+    assert!(err.contains("may only run remotely"), "{}", err);
+  }
+
   #[test]
   #[cfg(unix)]
   fn stdout_and_stderr_and_exit_code() {
@@ -422,16 +680,37 @@ mod tests {
       description: "echo foo and fail".to_string(),
       jdk_home: None,
       target_platform: Platform::None,
+      force_rerun: false,
+      expected_output_digest: None,
+      diff_outputs: false,
+      ephemeral_input_digests: vec![],
+      scheduling_hints: None,
+      poll_interval_hint: None,
+      cache_scope_names: BTreeSet::new(),
+      argfile_threshold: None,
+      argfile_flag_template: None,
+      provenance: None,
+      environment: None,
+      omit_cache_key_gen_version: false,
+      expected_output_size: None,
+      execution_locality: ExecutionLocality::Any,
     });
 
     assert_eq!(
       result.unwrap(),
       FallibleExecuteProcessResult {
-        stdout: as_bytes("foo"),
-        stderr: as_bytes("bar"),
+        stdout: as_bytes("foo").into(),
+        stderr: as_bytes("bar").into(),
         exit_code: 1,
         output_directory: EMPTY_DIGEST,
         execution_attempts: vec![],
+        source: ProcessResultSource::RanLocally,
+        termination_signal: None,
+        server_message: None,
+        timeout_details: None,
+        rewritten_action_digest: None,
+        output_size_anomaly: None,
+        platform: Platform::current_platform().unwrap(),
       }
     )
   }
@@ -450,16 +729,37 @@ mod tests {
       description: "kill self".to_string(),
       jdk_home: None,
       target_platform: Platform::None,
+      force_rerun: false,
+      expected_output_digest: None,
+      diff_outputs: false,
+      ephemeral_input_digests: vec![],
+      scheduling_hints: None,
+      poll_interval_hint: None,
+      cache_scope_names: BTreeSet::new(),
+      argfile_threshold: None,
+      argfile_flag_template: None,
+      provenance: None,
+      environment: None,
+      omit_cache_key_gen_version: false,
+      expected_output_size: None,
+      execution_locality: ExecutionLocality::Any,
     });
 
     assert_eq!(
       result.unwrap(),
       FallibleExecuteProcessResult {
-        stdout: as_bytes(""),
-        stderr: as_bytes(""),
+        stdout: as_bytes("").into(),
+        stderr: as_bytes("").into(),
         exit_code: -15,
         output_directory: EMPTY_DIGEST,
         execution_attempts: vec![],
+        source: ProcessResultSource::RanLocally,
+        termination_signal: None,
+        server_message: None,
+        timeout_details: None,
+        rewritten_action_digest: None,
+        output_size_anomaly: None,
+        platform: Platform::current_platform().unwrap(),
       }
     )
   }
@@ -481,9 +781,23 @@ mod tests {
       description: "run env".to_string(),
       jdk_home: None,
       target_platform: Platform::None,
+      force_rerun: false,
+      expected_output_digest: None,
+      diff_outputs: false,
+      ephemeral_input_digests: vec![],
+      scheduling_hints: None,
+      poll_interval_hint: None,
+      cache_scope_names: BTreeSet::new(),
+      argfile_threshold: None,
+      argfile_flag_template: None,
+      provenance: None,
+      environment: None,
+      omit_cache_key_gen_version: false,
+      expected_output_size: None,
+      execution_locality: ExecutionLocality::Any,
     });
 
-    let stdout = String::from_utf8(result.unwrap().stdout.to_vec()).unwrap();
+    let stdout = result.unwrap().stdout.to_string();
     let got_env: BTreeMap<String, String> = stdout
       .split("\n")
       .filter(|line| !line.is_empty())
@@ -518,6 +832,20 @@ mod tests {
         description: "run env".to_string(),
         jdk_home: None,
         target_platform: Platform::None,
+        force_rerun: false,
+        expected_output_digest: None,
+        diff_outputs: false,
+        ephemeral_input_digests: vec![],
+        scheduling_hints: None,
+        poll_interval_hint: None,
+        cache_scope_names: BTreeSet::new(),
+        argfile_threshold: None,
+        argfile_flag_template: None,
+        provenance: None,
+        environment: None,
+        omit_cache_key_gen_version: false,
+        expected_output_size: None,
+        execution_locality: ExecutionLocality::Any,
       }
     }
 
@@ -539,10 +867,84 @@ mod tests {
       description: "echo foo".to_string(),
       jdk_home: None,
       target_platform: Platform::None,
+      force_rerun: false,
+      expected_output_digest: None,
+      diff_outputs: false,
+      ephemeral_input_digests: vec![],
+      scheduling_hints: None,
+      poll_interval_hint: None,
+      cache_scope_names: BTreeSet::new(),
+      argfile_threshold: None,
+      argfile_flag_template: None,
+      provenance: None,
+      environment: None,
+      omit_cache_key_gen_version: false,
+      expected_output_size: None,
+      execution_locality: ExecutionLocality::Any,
     })
     .expect_err("Want Err");
   }
 
+  #[test]
+  #[cfg(unix)]
+  fn stdout_reported_to_progress_while_running() {
+    let req = ExecuteProcessRequest {
+      argv: owned_string_vec(&[&find_bash(), "-c", "echo -n foo; sleep 1; echo -n bar"]),
+      env: BTreeMap::new(),
+      input_files: EMPTY_DIGEST,
+      output_files: BTreeSet::new(),
+      output_directories: BTreeSet::new(),
+      timeout: Duration::from_millis(5000),
+      description: "echo foo then bar".to_string(),
+      jdk_home: None,
+      target_platform: Platform::None,
+      force_rerun: false,
+      expected_output_digest: None,
+      diff_outputs: false,
+      ephemeral_input_digests: vec![],
+      scheduling_hints: None,
+      poll_interval_hint: None,
+      cache_scope_names: BTreeSet::new(),
+      argfile_threshold: None,
+      argfile_flag_template: None,
+      provenance: None,
+      environment: None,
+      omit_cache_key_gen_version: false,
+      expected_output_size: None,
+      execution_locality: ExecutionLocality::Any,
+    };
+
+    let progress = ProcessProgress::new();
+    let handle = {
+      let progress = progress.clone();
+      std::thread::spawn(move || {
+        let store_dir = TempDir::new().unwrap();
+        let work_dir = TempDir::new().unwrap();
+        let executor = task_executor::Executor::new();
+        let store = Store::local_only(executor.clone(), store_dir.path()).unwrap();
+        let runner = super::CommandRunner {
+          store: store,
+          executor: executor.clone(),
+          work_dir: work_dir.path().to_owned(),
+          cleanup_local_dirs: true,
+          platform: Platform::current_platform().unwrap(),
+          compatible_constraints: crate::CompatibleConstraintCache::new(),
+        };
+        executor.block_on(runner.run_with_progress(req.into(), progress, WorkUnitStore::new()))
+      })
+    };
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while progress.partial_stdout().is_empty() && Instant::now() < deadline {
+      std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(progress.status(), ProcessStatus::Running);
+    assert_eq!(progress.partial_stdout(), as_bytes("foo"));
+
+    let result = handle.join().unwrap().unwrap();
+    assert_eq!(result.stdout, as_bytes("foobar"));
+  }
+
   #[test]
   fn output_files_none() {
     let result = run_command_locally(ExecuteProcessRequest {
@@ -555,15 +957,36 @@ mod tests {
       description: "bash".to_string(),
       jdk_home: None,
       target_platform: Platform::None,
+      force_rerun: false,
+      expected_output_digest: None,
+      diff_outputs: false,
+      ephemeral_input_digests: vec![],
+      scheduling_hints: None,
+      poll_interval_hint: None,
+      cache_scope_names: BTreeSet::new(),
+      argfile_threshold: None,
+      argfile_flag_template: None,
+      provenance: None,
+      environment: None,
+      omit_cache_key_gen_version: false,
+      expected_output_size: None,
+      execution_locality: ExecutionLocality::Any,
     });
     assert_eq!(
       result.unwrap(),
       FallibleExecuteProcessResult {
-        stdout: as_bytes(""),
-        stderr: as_bytes(""),
+        stdout: as_bytes("").into(),
+        stderr: as_bytes("").into(),
         exit_code: 0,
         output_directory: EMPTY_DIGEST,
         execution_attempts: vec![],
+        source: ProcessResultSource::RanLocally,
+        termination_signal: None,
+        server_message: None,
+        timeout_details: None,
+        rewritten_action_digest: None,
+        output_size_anomaly: None,
+        platform: Platform::current_platform().unwrap(),
       }
     )
   }
@@ -584,16 +1007,37 @@ mod tests {
       description: "bash".to_string(),
       jdk_home: None,
       target_platform: Platform::None,
+      force_rerun: false,
+      expected_output_digest: None,
+      diff_outputs: false,
+      ephemeral_input_digests: vec![],
+      scheduling_hints: None,
+      poll_interval_hint: None,
+      cache_scope_names: BTreeSet::new(),
+      argfile_threshold: None,
+      argfile_flag_template: None,
+      provenance: None,
+      environment: None,
+      omit_cache_key_gen_version: false,
+      expected_output_size: None,
+      execution_locality: ExecutionLocality::Any,
     });
 
     assert_eq!(
       result.unwrap(),
       FallibleExecuteProcessResult {
-        stdout: as_bytes(""),
-        stderr: as_bytes(""),
+        stdout: as_bytes("").into(),
+        stderr: as_bytes("").into(),
         exit_code: 0,
         output_directory: TestDirectory::containing_roland().digest(),
         execution_attempts: vec![],
+        source: ProcessResultSource::RanLocally,
+        termination_signal: None,
+        server_message: None,
+        timeout_details: None,
+        rewritten_action_digest: None,
+        output_size_anomaly: None,
+        platform: Platform::current_platform().unwrap(),
       }
     )
   }
@@ -619,16 +1063,37 @@ mod tests {
       description: "bash".to_string(),
       jdk_home: None,
       target_platform: Platform::None,
+      force_rerun: false,
+      expected_output_digest: None,
+      diff_outputs: false,
+      ephemeral_input_digests: vec![],
+      scheduling_hints: None,
+      poll_interval_hint: None,
+      cache_scope_names: BTreeSet::new(),
+      argfile_threshold: None,
+      argfile_flag_template: None,
+      provenance: None,
+      environment: None,
+      omit_cache_key_gen_version: false,
+      expected_output_size: None,
+      execution_locality: ExecutionLocality::Any,
     });
 
     assert_eq!(
       result.unwrap(),
       FallibleExecuteProcessResult {
-        stdout: as_bytes(""),
-        stderr: as_bytes(""),
+        stdout: as_bytes("").into(),
+        stderr: as_bytes("").into(),
         exit_code: 0,
         output_directory: TestDirectory::recursive().digest(),
         execution_attempts: vec![],
+        source: ProcessResultSource::RanLocally,
+        termination_signal: None,
+        server_message: None,
+        timeout_details: None,
+        rewritten_action_digest: None,
+        output_size_anomaly: None,
+        platform: Platform::current_platform().unwrap(),
       }
     )
   }
@@ -655,16 +1120,37 @@ mod tests {
       description: "treats-roland".to_string(),
       jdk_home: None,
       target_platform: Platform::None,
+      force_rerun: false,
+      expected_output_digest: None,
+      diff_outputs: false,
+      ephemeral_input_digests: vec![],
+      scheduling_hints: None,
+      poll_interval_hint: None,
+      cache_scope_names: BTreeSet::new(),
+      argfile_threshold: None,
+      argfile_flag_template: None,
+      provenance: None,
+      environment: None,
+      omit_cache_key_gen_version: false,
+      expected_output_size: None,
+      execution_locality: ExecutionLocality::Any,
     });
 
     assert_eq!(
       result.unwrap(),
       FallibleExecuteProcessResult {
-        stdout: as_bytes(""),
-        stderr: as_bytes(""),
+        stdout: as_bytes("").into(),
+        stderr: as_bytes("").into(),
         exit_code: 0,
         output_directory: TestDirectory::recursive().digest(),
         execution_attempts: vec![],
+        source: ProcessResultSource::RanLocally,
+        termination_signal: None,
+        server_message: None,
+        timeout_details: None,
+        rewritten_action_digest: None,
+        output_size_anomaly: None,
+        platform: Platform::current_platform().unwrap(),
       }
     )
   }
@@ -689,16 +1175,37 @@ mod tests {
       description: "echo foo".to_string(),
       jdk_home: None,
       target_platform: Platform::None,
+      force_rerun: false,
+      expected_output_digest: None,
+      diff_outputs: false,
+      ephemeral_input_digests: vec![],
+      scheduling_hints: None,
+      poll_interval_hint: None,
+      cache_scope_names: BTreeSet::new(),
+      argfile_threshold: None,
+      argfile_flag_template: None,
+      provenance: None,
+      environment: None,
+      omit_cache_key_gen_version: false,
+      expected_output_size: None,
+      execution_locality: ExecutionLocality::Any,
     });
 
     assert_eq!(
       result.unwrap(),
       FallibleExecuteProcessResult {
-        stdout: as_bytes(""),
-        stderr: as_bytes(""),
+        stdout: as_bytes("").into(),
+        stderr: as_bytes("").into(),
         exit_code: 1,
         output_directory: TestDirectory::containing_roland().digest(),
         execution_attempts: vec![],
+        source: ProcessResultSource::RanLocally,
+        termination_signal: None,
+        server_message: None,
+        timeout_details: None,
+        rewritten_action_digest: None,
+        output_size_anomaly: None,
+        platform: Platform::current_platform().unwrap(),
       }
     )
   }
@@ -721,16 +1228,37 @@ mod tests {
       description: "echo-roland".to_string(),
       jdk_home: None,
       target_platform: Platform::None,
+      force_rerun: false,
+      expected_output_digest: None,
+      diff_outputs: false,
+      ephemeral_input_digests: vec![],
+      scheduling_hints: None,
+      poll_interval_hint: None,
+      cache_scope_names: BTreeSet::new(),
+      argfile_threshold: None,
+      argfile_flag_template: None,
+      provenance: None,
+      environment: None,
+      omit_cache_key_gen_version: false,
+      expected_output_size: None,
+      execution_locality: ExecutionLocality::Any,
     });
 
     assert_eq!(
       result.unwrap(),
       FallibleExecuteProcessResult {
-        stdout: as_bytes(""),
-        stderr: as_bytes(""),
+        stdout: as_bytes("").into(),
+        stderr: as_bytes("").into(),
         exit_code: 0,
         output_directory: TestDirectory::containing_roland().digest(),
         execution_attempts: vec![],
+        source: ProcessResultSource::RanLocally,
+        termination_signal: None,
+        server_message: None,
+        timeout_details: None,
+        rewritten_action_digest: None,
+        output_size_anomaly: None,
+        platform: Platform::current_platform().unwrap(),
       }
     )
   }
@@ -751,16 +1279,37 @@ mod tests {
       description: "bash".to_string(),
       jdk_home: None,
       target_platform: Platform::None,
+      force_rerun: false,
+      expected_output_digest: None,
+      diff_outputs: false,
+      ephemeral_input_digests: vec![],
+      scheduling_hints: None,
+      poll_interval_hint: None,
+      cache_scope_names: BTreeSet::new(),
+      argfile_threshold: None,
+      argfile_flag_template: None,
+      provenance: None,
+      environment: None,
+      omit_cache_key_gen_version: false,
+      expected_output_size: None,
+      execution_locality: ExecutionLocality::Any,
     });
 
     assert_eq!(
       result.unwrap(),
       FallibleExecuteProcessResult {
-        stdout: as_bytes(""),
-        stderr: as_bytes(""),
+        stdout: as_bytes("").into(),
+        stderr: as_bytes("").into(),
         exit_code: 0,
         output_directory: TestDirectory::nested().digest(),
         execution_attempts: vec![],
+        source: ProcessResultSource::RanLocally,
+        termination_signal: None,
+        server_message: None,
+        timeout_details: None,
+        rewritten_action_digest: None,
+        output_size_anomaly: None,
+        platform: Platform::current_platform().unwrap(),
       }
     )
   }
@@ -781,15 +1330,36 @@ mod tests {
       description: "cat roland".to_string(),
       jdk_home: Some(preserved_work_tmpdir.path().to_path_buf()),
       target_platform: Platform::None,
+      force_rerun: false,
+      expected_output_digest: None,
+      diff_outputs: false,
+      ephemeral_input_digests: vec![],
+      scheduling_hints: None,
+      poll_interval_hint: None,
+      cache_scope_names: BTreeSet::new(),
+      argfile_threshold: None,
+      argfile_flag_template: None,
+      provenance: None,
+      environment: None,
+      omit_cache_key_gen_version: false,
+      expected_output_size: None,
+      execution_locality: ExecutionLocality::Any,
     });
     assert_eq!(
       result,
       Ok(FallibleExecuteProcessResult {
-        stdout: roland,
-        stderr: as_bytes(""),
+        stdout: roland.into(),
+        stderr: as_bytes("").into(),
         exit_code: 0,
         output_directory: EMPTY_DIGEST,
         execution_attempts: vec![],
+        source: ProcessResultSource::RanLocally,
+        termination_signal: None,
+        server_message: None,
+        timeout_details: None,
+        rewritten_action_digest: None,
+        output_size_anomaly: None,
+        platform: Platform::current_platform().unwrap(),
       })
     )
   }
@@ -814,6 +1384,20 @@ mod tests {
         description: "bash".to_string(),
         jdk_home: None,
         target_platform: Platform::None,
+        force_rerun: false,
+        expected_output_digest: None,
+        diff_outputs: false,
+        ephemeral_input_digests: vec![],
+        scheduling_hints: None,
+        poll_interval_hint: None,
+        cache_scope_names: BTreeSet::new(),
+        argfile_threshold: None,
+        argfile_flag_template: None,
+        provenance: None,
+        environment: None,
+        omit_cache_key_gen_version: false,
+        expected_output_size: None,
+        execution_locality: ExecutionLocality::Any,
       },
       preserved_work_root.clone(),
       false,
@@ -850,6 +1434,20 @@ mod tests {
         description: "failing execution".to_string(),
         jdk_home: None,
         target_platform: Platform::None,
+        force_rerun: false,
+        expected_output_digest: None,
+        diff_outputs: false,
+        ephemeral_input_digests: vec![],
+        scheduling_hints: None,
+        poll_interval_hint: None,
+        cache_scope_names: BTreeSet::new(),
+        argfile_threshold: None,
+        argfile_flag_template: None,
+        provenance: None,
+        environment: None,
+        omit_cache_key_gen_version: false,
+        expected_output_size: None,
+        execution_locality: ExecutionLocality::Any,
       },
       preserved_work_root.clone(),
       false,
@@ -883,16 +1481,37 @@ mod tests {
       description: "create nonoverlapping directories and file".to_string(),
       jdk_home: None,
       target_platform: Platform::None,
+      force_rerun: false,
+      expected_output_digest: None,
+      diff_outputs: false,
+      ephemeral_input_digests: vec![],
+      scheduling_hints: None,
+      poll_interval_hint: None,
+      cache_scope_names: BTreeSet::new(),
+      argfile_threshold: None,
+      argfile_flag_template: None,
+      provenance: None,
+      environment: None,
+      omit_cache_key_gen_version: false,
+      expected_output_size: None,
+      execution_locality: ExecutionLocality::Any,
     });
 
     assert_eq!(
       result.unwrap(),
       FallibleExecuteProcessResult {
-        stdout: as_bytes(""),
-        stderr: as_bytes(""),
+        stdout: as_bytes("").into(),
+        stderr: as_bytes("").into(),
         exit_code: 0,
         output_directory: TestDirectory::nested_dir_and_file().digest(),
         execution_attempts: vec![],
+        source: ProcessResultSource::RanLocally,
+        termination_signal: None,
+        server_message: None,
+        timeout_details: None,
+        rewritten_action_digest: None,
+        output_size_anomaly: None,
+        platform: Platform::current_platform().unwrap(),
       }
     )
   }
@@ -913,16 +1532,37 @@ mod tests {
       description: "bash".to_string(),
       jdk_home: None,
       target_platform: Platform::None,
+      force_rerun: false,
+      expected_output_digest: None,
+      diff_outputs: false,
+      ephemeral_input_digests: vec![],
+      scheduling_hints: None,
+      poll_interval_hint: None,
+      cache_scope_names: BTreeSet::new(),
+      argfile_threshold: None,
+      argfile_flag_template: None,
+      provenance: None,
+      environment: None,
+      omit_cache_key_gen_version: false,
+      expected_output_size: None,
+      execution_locality: ExecutionLocality::Any,
     });
 
     assert_eq!(
       result.unwrap(),
       FallibleExecuteProcessResult {
-        stdout: as_bytes(""),
-        stderr: as_bytes(""),
+        stdout: as_bytes("").into(),
+        stderr: as_bytes("").into(),
         exit_code: 0,
         output_directory: TestDirectory::containing_falcons_dir().digest(),
         execution_attempts: vec![],
+        source: ProcessResultSource::RanLocally,
+        termination_signal: None,
+        server_message: None,
+        timeout_details: None,
+        rewritten_action_digest: None,
+        output_size_anomaly: None,
+        platform: Platform::current_platform().unwrap(),
       }
     )
   }
@@ -955,6 +1595,8 @@ mod tests {
       work_dir: dir,
       cleanup_local_dirs: cleanup,
       platform: Platform::current_platform().unwrap(),
+      compatible_constraints: crate::CompatibleConstraintCache::new(),
+      strict_execution_locality: false,
     };
     executor.block_on(runner.run(req.into(), WorkUnitStore::new()))
   }
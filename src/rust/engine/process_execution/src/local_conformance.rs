@@ -0,0 +1,427 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! Runs requests locally with exactly the semantics that they have remotely, by driving the local
+//! CommandRunner with the Action and Command which `action::make_execute_request` produces for
+//! them, so that local and remote runs of a request can be compared dimension by dimension.
+//!
+
+use std::fmt;
+
+use boxfuture::{try_future, BoxFuture, Boxable};
+use workunit_store::WorkUnitStore;
+
+use crate::{action, local, ExecuteProcessRequest, ExecuteProcessRequestMetadata};
+use crate::{FallibleExecuteProcessResult, ProcessOutput};
+
+///
+/// A way in which two results of the same request can differ.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Dimension {
+  Stdout,
+  Stderr,
+  ExitCode,
+  OutputDirectory,
+}
+
+impl fmt::Display for Dimension {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let name = match self {
+      Dimension::Stdout => "stdout",
+      Dimension::Stderr => "stderr",
+      Dimension::ExitCode => "exit code",
+      Dimension::OutputDirectory => "output directory",
+    };
+    write!(f, "{}", name)
+  }
+}
+
+///
+/// Runs the request via `local::CommandRunner::run_from_command_proto`, with the Command that it
+/// would be sent to a server as.
+///
+pub fn run_as_remote(
+  runner: &local::CommandRunner,
+  req: &ExecuteProcessRequest,
+  metadata: ExecuteProcessRequestMetadata,
+  workunit_store: WorkUnitStore,
+) -> BoxFuture<FallibleExecuteProcessResult, String> {
+  let (action, command, _) = try_future!(action::make_execute_request(req, metadata));
+  runner.run_from_command_proto(&action, &command, workunit_store)
+}
+
+///
+/// The dimensions in which two results differ. How the results were produced (their source and
+/// execution attempts) is not compared.
+///
+pub fn divergences(
+  expected: &FallibleExecuteProcessResult,
+  actual: &FallibleExecuteProcessResult,
+) -> Vec<Dimension> {
+  let mut divergences = vec![];
+  if expected.stdout != actual.stdout {
+    divergences.push(Dimension::Stdout);
+  }
+  if expected.stderr != actual.stderr {
+    divergences.push(Dimension::Stderr);
+  }
+  if expected.exit_code != actual.exit_code {
+    divergences.push(Dimension::ExitCode);
+  }
+  if expected.output_directory != actual.output_directory {
+    divergences.push(Dimension::OutputDirectory);
+  }
+  divergences
+}
+
+///
+/// Fails with a description of each dimension in which the results differ.
+///
+pub fn check_conforms(
+  description: &str,
+  expected: &FallibleExecuteProcessResult,
+  actual: &FallibleExecuteProcessResult,
+) -> Result<(), String> {
+  let divergences = divergences(expected, actual);
+  if divergences.is_empty() {
+    return Ok(());
+  }
+  let details = divergences
+    .iter()
+    .map(|dimension| {
+      let (expected, actual) = match dimension {
+        Dimension::Stdout => (render(&expected.stdout), render(&actual.stdout)),
+        Dimension::Stderr => (render(&expected.stderr), render(&actual.stderr)),
+        Dimension::ExitCode => (expected.exit_code.to_string(), actual.exit_code.to_string()),
+        Dimension::OutputDirectory => (
+          format!("{:?}", expected.output_directory),
+          format!("{:?}", actual.output_directory),
+        ),
+      };
+      format!(
+        "{}: expected {:?} but got {:?}",
+        dimension, expected, actual
+      )
+    })
+    .collect::<Vec<_>>()
+    .join("; ");
+  Err(format!("{} diverged in {}", description, details))
+}
+
+fn render(output: &ProcessOutput) -> String {
+  match output {
+    ProcessOutput::Inline(bytes) | ProcessOutput::Unpersisted(bytes, _) => {
+      String::from_utf8_lossy(bytes).into_owned()
+    }
+    ProcessOutput::Stored(digest) => format!("{:?}", digest),
+  }
+}
+
+#[cfg(all(test, feature = "remote-execution"))]
+mod tests {
+  use super::{check_conforms, divergences, run_as_remote, Dimension};
+  use crate::remote::tests::empty_request_metadata;
+  use crate::{
+    local, remote, CommandRunner as CommandRunnerTrait, ExecuteProcessRequest,
+    ExecuteProcessRequestMetadata, FallibleExecuteProcessResult, Platform, ProcessOutput,
+  };
+  use bytes::Bytes;
+  use futures::Future;
+  use hashing::{Digest, EMPTY_DIGEST};
+  use maplit::btreemap;
+  use std::collections::BTreeMap;
+  use std::path::PathBuf;
+  use std::time::Duration;
+  use store::Store;
+  use tempfile::TempDir;
+  use testutil::data::{TestData, TestDirectory};
+  use testutil::owned_string_vec;
+  use workunit_store::WorkUnitStore;
+
+  struct Runner {
+    runner: local::CommandRunner,
+    executor: task_executor::Executor,
+    _store_dir: TempDir,
+    _work_dir: TempDir,
+  }
+
+  ///
+  /// Stores the inputs of the matrix locally: remote runs upload them as they need them.
+  ///
+  fn seed(store: &Store) {
+    for directory in &[TestDirectory::containing_roland(), TestDirectory::nested()] {
+      store
+        .record_directory(&directory.directory(), false)
+        .wait()
+        .unwrap();
+    }
+    store
+      .store_file_bytes(TestData::roland().bytes(), false)
+      .wait()
+      .unwrap();
+  }
+
+  fn runner() -> Runner {
+    let store_dir = TempDir::new().unwrap();
+    let work_dir = TempDir::new().unwrap();
+    let executor = task_executor::Executor::new();
+    let store = Store::local_only(executor.clone(), store_dir.path()).unwrap();
+    seed(&store);
+    Runner {
+      runner: local::CommandRunner::new(store, executor.clone(), work_dir.path().to_owned(), true),
+      executor,
+      _store_dir: store_dir,
+      _work_dir: work_dir,
+    }
+  }
+
+  fn request(
+    argv: &[&str],
+    env: BTreeMap<String, String>,
+    input_files: Digest,
+    output_directories: &[&str],
+  ) -> ExecuteProcessRequest {
+    ExecuteProcessRequest {
+      argv: owned_string_vec(argv),
+      env,
+      input_files,
+      output_directories: output_directories.iter().map(PathBuf::from).collect(),
+      ..crate::tests::execute_process_request(&argv.join(" "))
+    }
+  }
+
+  // Each request is run locally, locally with the semantics of its Command, and remotely (when a
+  // server is configured via REMOTE_SERVER_ENV_VAR).
+  fn matrix() -> Vec<ExecuteProcessRequest> {
+    vec![
+      request(
+        &["/bin/echo", "-n", "foo"],
+        BTreeMap::new(),
+        EMPTY_DIGEST,
+        &[],
+      ),
+      request(
+        &["/bin/sh", "-c", "printf \"$FOO\""],
+        btreemap! {"FOO".to_owned() => "bar".to_owned()},
+        EMPTY_DIGEST,
+        &[],
+      ),
+      request(
+        &["/bin/sh", "-c", "printf err >&2; exit 3"],
+        BTreeMap::new(),
+        EMPTY_DIGEST,
+        &[],
+      ),
+      request(
+        &["/bin/cat", "roland"],
+        BTreeMap::new(),
+        TestDirectory::containing_roland().digest(),
+        &[],
+      ),
+      request(
+        &[
+          "/bin/sh",
+          "-c",
+          "/bin/mkdir cats && printf 'European Burmese' > cats/roland",
+        ],
+        BTreeMap::new(),
+        EMPTY_DIGEST,
+        &["cats"],
+      ),
+    ]
+  }
+
+  fn inline(output: &ProcessOutput) -> Bytes {
+    match output {
+      ProcessOutput::Inline(bytes) | ProcessOutput::Unpersisted(bytes, _) => bytes.clone(),
+      ProcessOutput::Stored(digest) => panic!("Want output in memory, got {:?}", digest),
+    }
+  }
+
+  fn run_locally(runner: &Runner, req: &ExecuteProcessRequest) -> FallibleExecuteProcessResult {
+    runner
+      .executor
+      .block_on(runner.runner.run(req.clone().into(), WorkUnitStore::new()))
+      .unwrap()
+  }
+
+  fn run_locally_as_remote(
+    runner: &Runner,
+    req: &ExecuteProcessRequest,
+  ) -> FallibleExecuteProcessResult {
+    runner
+      .executor
+      .block_on(run_as_remote(
+        &runner.runner,
+        req,
+        empty_request_metadata(),
+        WorkUnitStore::new(),
+      ))
+      .unwrap()
+  }
+
+  // The address of a remote execution server (which must also serve the CAS) to run the matrix
+  // against. The crate's own mock server can only echo back whatever result it is handed, so there
+  // is nothing to compare with unless a real server is configured: the remote leg is otherwise
+  // skipped.
+  const REMOTE_SERVER_ENV_VAR: &str = "PANTS_LOCAL_CONFORMANCE_REMOTE_SERVER";
+  const REMOTE_INSTANCE_NAME_ENV_VAR: &str = "PANTS_LOCAL_CONFORMANCE_REMOTE_INSTANCE_NAME";
+
+  ///
+  /// Runs the request via a remote CommandRunner, against the server at the given address.
+  ///
+  fn run_via_remote_server(
+    address: &str,
+    req: &ExecuteProcessRequest,
+  ) -> FallibleExecuteProcessResult {
+    let instance_name = std::env::var(REMOTE_INSTANCE_NAME_ENV_VAR).ok();
+    let executor = task_executor::Executor::new();
+    let store_dir = TempDir::new().unwrap();
+    let store = Store::with_remote(
+      executor.clone(),
+      store_dir.path(),
+      vec![address.to_owned()],
+      instance_name.clone(),
+      None,
+      None,
+      None,
+      1,
+      10 * 1024 * 1024,
+      Duration::from_secs(30),
+      store::BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10)).unwrap(),
+      3,
+      1,
+    )
+    .expect("Failed to make store");
+    seed(&store);
+
+    let command_runner = remote::CommandRunner::new(
+      address,
+      ExecuteProcessRequestMetadata {
+        instance_name,
+        ..empty_request_metadata()
+      },
+      None,
+      None,
+      None,
+      store,
+      Platform::Linux,
+      executor,
+    )
+    .unwrap();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime
+      .block_on(command_runner.run(req.clone().into(), WorkUnitStore::new()))
+      .unwrap()
+  }
+
+  #[test]
+  fn local_runs_conform_to_remote_semantics() {
+    let runner = runner();
+    let server = std::env::var(REMOTE_SERVER_ENV_VAR).ok();
+    if server.is_none() {
+      println!(
+        "Skipping the remote leg: set {} to the address of a server to run it.",
+        REMOTE_SERVER_ENV_VAR
+      );
+    }
+    for req in matrix() {
+      let as_remote = run_locally_as_remote(&runner, &req);
+      let local = run_locally(&runner, &req);
+
+      let description = &req.description;
+      check_conforms(&format!("Local run of {}", description), &as_remote, &local).unwrap();
+      if let Some(ref server) = server {
+        let remote = run_via_remote_server(server, &req);
+        check_conforms(
+          &format!("Remote run of {}", description),
+          &as_remote,
+          &remote,
+        )
+        .unwrap();
+      }
+    }
+  }
+
+  #[test]
+  fn outputs_are_collected_like_remote_outputs() {
+    let runner = runner();
+    let req = matrix().pop().unwrap();
+    assert_eq!(
+      run_locally_as_remote(&runner, &req).output_directory,
+      TestDirectory::nested().digest()
+    );
+  }
+
+  #[test]
+  fn commands_run_in_their_working_directory() {
+    let runner = runner();
+    let req = request(
+      &["/bin/cat", "roland"],
+      BTreeMap::new(),
+      TestDirectory::nested().digest(),
+      &[],
+    );
+    let (action, mut command, _) =
+      remote::make_execute_request(&req, empty_request_metadata()).unwrap();
+    command.set_working_directory("cats".to_owned());
+
+    let result = runner
+      .executor
+      .block_on(
+        runner
+          .runner
+          .run_from_command_proto(&action, &command, WorkUnitStore::new()),
+      )
+      .unwrap();
+    assert_eq!(inline(&result.stdout), Bytes::from("European Burmese"));
+    assert_eq!(result.exit_code, 0);
+  }
+
+  #[test]
+  fn working_directories_outside_of_the_input_root_are_rejected() {
+    let runner = runner();
+    let req = request(
+      &["/bin/cat", "roland"],
+      BTreeMap::new(),
+      TestDirectory::nested().digest(),
+      &[],
+    );
+    let (action, mut command, _) =
+      remote::make_execute_request(&req, empty_request_metadata()).unwrap();
+
+    for working_directory in &["/tmp", "..", "cats/../.."] {
+      command.set_working_directory((*working_directory).to_owned());
+      let error = runner
+        .executor
+        .block_on(
+          runner
+            .runner
+            .run_from_command_proto(&action, &command, WorkUnitStore::new()),
+        )
+        .expect_err(&format!("Want {:?} to be rejected", working_directory));
+      assert!(
+        error.contains("must be a relative path within the input root"),
+        "{}",
+        error
+      );
+    }
+  }
+
+  #[test]
+  fn divergences_name_the_differing_dimension() {
+    // Local runs set an empty PATH, which servers do not.
+    let runner = runner();
+    let req = request(&["/usr/bin/env"], BTreeMap::new(), EMPTY_DIGEST, &[]);
+    let as_remote = run_locally_as_remote(&runner, &req);
+    let local = run_locally(&runner, &req);
+
+    assert_eq!(divergences(&as_remote, &local), vec![Dimension::Stdout]);
+    assert_eq!(
+      check_conforms("env", &as_remote, &local),
+      Err("env diverged in stdout: expected \"\" but got \"PATH=\\n\"".to_owned())
+    );
+  }
+}
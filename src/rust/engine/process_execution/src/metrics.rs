@@ -0,0 +1,99 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! The names of the counters which CommandRunners increment in the WorkUnitStore of each request,
+//! and which are reported alongside the engine's other metrics, or (for counters which outlive
+//! requests) via CommandRunner::metrics. These names are a public contract: rename them only with
+//! a deprecation.
+//!
+
+// Remote requests which completed or failed (including remote cache hits).
+pub const REMOTE_EXECUTION_REQUESTS: &str = "remote_execution_requests";
+// Remote requests which were served from the remote cache.
+pub const REMOTE_CACHE_HITS: &str = "remote_cache_hits";
+// Remote requests which failed without a result (as opposed to processes which exited non-zero).
+pub const REMOTE_EXECUTION_ERRORS: &str = "remote_execution_errors";
+// Bytes of inputs uploaded to the remote CAS.
+pub const REMOTE_UPLOAD_BYTES: &str = "remote_upload_bytes";
+// Bytes of the output blobs of results (e.g. stdout and stderr) which were fetched from a remote
+// CAS, rather than loaded from the local Store or the blob cache.
+pub const REMOTE_DOWNLOAD_BYTES: &str = "remote_download_bytes";
+// Milliseconds which actions spent in the remote queue, as reported by servers.
+pub const REMOTE_QUEUE_MILLIS_TOTAL: &str = "remote_queue_millis_total";
+// Loads of small result blobs which were served from the in-memory blob cache.
+pub const REMOTE_BLOB_CACHE_HITS: &str = "remote_blob_cache_hits";
+// Loads of small result blobs which were not in the in-memory blob cache.
+pub const REMOTE_BLOB_CACHE_MISSES: &str = "remote_blob_cache_misses";
+// Requests for the stats of input roots which were (or were not) already memoized (see
+// input_tree_stats).
+pub const REMOTE_INPUT_TREE_STATS_HITS: &str = "remote_input_tree_stats_hits";
+pub const REMOTE_INPUT_TREE_STATS_MISSES: &str = "remote_input_tree_stats_misses";
+// Remote requests which the server rejected rather than running (see rejections::RemoteRejection),
+// by the reason for the rejection.
+pub const REMOTE_REJECTIONS_INSTANCE: &str = "remote_rejections_instance";
+pub const REMOTE_REJECTIONS_QUOTA: &str = "remote_rejections_quota";
+pub const REMOTE_REJECTIONS_POLICY: &str = "remote_rejections_policy";
+pub const REMOTE_REJECTIONS_PERMISSION: &str = "remote_rejections_permission";
+pub const REMOTE_REJECTIONS_OTHER: &str = "remote_rejections_other";
+// Remote requests which the server would not run because their inputs were too large for its
+// workers (see violations::InputsTooLarge).
+pub const REMOTE_INPUTS_TOO_LARGE: &str = "remote_inputs_too_large";
+// Remote requests whose outputs were larger than expected (see output_size::OutputSizeAnomaly).
+pub const REMOTE_OUTPUT_SIZE_ANOMALIES: &str = "remote_output_size_anomalies";
+// If output files are fetched eagerly: the BatchReadBlobs requests which were sent, the blobs which
+// they fetched, and the blobs which were read individually (because they were too large to batch,
+// or their batch failed).
+pub const REMOTE_BATCHED_READ_REQUESTS: &str = "remote_batched_read_requests";
+pub const REMOTE_BATCHED_READ_BLOBS: &str = "remote_batched_read_blobs";
+pub const REMOTE_INDIVIDUAL_READS: &str = "remote_individual_reads";
+// Remote requests which exceeded their client-side timeout, by the TimeoutCategory of the timeout.
+pub const REMOTE_TIMEOUTS_QUEUED: &str = "remote_timeouts_queued";
+pub const REMOTE_TIMEOUTS_EXECUTION: &str = "remote_timeouts_execution";
+pub const REMOTE_TIMEOUTS_UNKNOWN: &str = "remote_timeouts_unknown";
+// Digests which a server reported missing, but which were not uploaded again because another
+// request was uploading or had recently uploaded them (see upload_coalescing).
+pub const REMOTE_COALESCED_UPLOADS: &str = "remote_coalesced_uploads";
+// Cached results which referenced blobs that the CAS no longer had, so were re-executed (see
+// remote::CommandRunner::with_validate_cache_hit_blobs).
+pub const REMOTE_STALE_CACHE_HITS: &str = "remote_stale_cache_hits";
+
+// Reported via CommandRunner::metrics by the remote CommandRunner, if it has a RetryBudget (see
+// retry_budget::RetryCategory): by category, the tokens which remain in the budget, and the
+// retries which were not made because it was exhausted.
+pub const REMOTE_RETRY_BUDGET_REMAINING_MISSING_DIGESTS: &str =
+  "remote_retry_budget_remaining_missing_digests";
+pub const REMOTE_RETRY_BUDGET_REMAINING_CANCELLED_POLL: &str =
+  "remote_retry_budget_remaining_cancelled_poll";
+pub const REMOTE_RETRY_BUDGET_REMAINING_OUTPUT_FETCH: &str =
+  "remote_retry_budget_remaining_output_fetch";
+pub const REMOTE_RETRY_BUDGET_EXHAUSTED_MISSING_DIGESTS: &str =
+  "remote_retry_budget_exhausted_missing_digests";
+pub const REMOTE_RETRY_BUDGET_EXHAUSTED_CANCELLED_POLL: &str =
+  "remote_retry_budget_exhausted_cancelled_poll";
+pub const REMOTE_RETRY_BUDGET_EXHAUSTED_OUTPUT_FETCH: &str =
+  "remote_retry_budget_exhausted_output_fetch";
+
+// Reported via CommandRunner::metrics by the remote CommandRunner, if capability detection is
+// enabled: the features which the server has been found to lack (see capabilities::Feature).
+pub const REMOTE_MISSING_CAPABILITIES: &str = "remote_missing_capabilities";
+
+// Reported via CommandRunner::metrics by the RemoteTimeBudgetCommandRunner (see time_budget): the
+// milliseconds spent waiting on remote operations, whether (1) or not (0) that exceeds the budget,
+// and the requests which were run locally rather than remotely because it did.
+pub const REMOTE_TIME_BUDGET_SPENT_MILLIS: &str = "remote_time_budget_spent_millis";
+pub const REMOTE_TIME_BUDGET_EXCEEDED: &str = "remote_time_budget_exceeded";
+pub const REMOTE_TIME_BUDGET_REDIRECTED_REQUESTS: &str = "remote_time_budget_redirected_requests";
+
+// Reported via CommandRunner::metrics by the ShadowingCommandRunner:
+// Shadow runs which completed or failed.
+pub const SHADOW_RUNS: &str = "shadow_runs";
+// Shadow runs whose results differed from those of the primary.
+pub const SHADOW_DIVERGENCES: &str = "shadow_divergences";
+// Shadow runs which failed or timed out.
+pub const SHADOW_FAILURES: &str = "shadow_failures";
+// Sampled requests which were not shadowed, because too many shadow runs were in flight.
+pub const SHADOW_SKIPPED: &str = "shadow_skipped";
+// The counters of the shadow runner (and of its runs) are reported with this prefix, apart from
+// those of the primary, e.g. as `shadow_remote_execution_requests`.
+pub const SHADOW_COUNTER_PREFIX: &str = "shadow_";
@@ -0,0 +1,131 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! The names of remote operations. A name is only meaningful to the endpoint (server and instance)
+//! which returned it, so an OperationName carries its endpoint, and requests which refer to an
+//! operation check that they are sent to that endpoint.
+//!
+
+use std::fmt;
+use std::sync::Arc;
+
+///
+/// A remote execution server, and the instance on it, to which operations are submitted.
+///
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Endpoint {
+  address: String,
+  instance_name: Option<String>,
+  // Whether the names of operations render their endpoint, which is only worth the noise when
+  // several endpoints are configured.
+  named_in_operations: bool,
+}
+
+impl Endpoint {
+  pub fn new(address: String, instance_name: Option<String>) -> Endpoint {
+    Endpoint {
+      address,
+      instance_name,
+      named_in_operations: false,
+    }
+  }
+
+  pub fn named_in_operations(self) -> Endpoint {
+    Endpoint {
+      named_in_operations: true,
+      ..self
+    }
+  }
+}
+
+impl fmt::Display for Endpoint {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self.instance_name {
+      Some(ref instance_name) => write!(f, "{}/{}", self.address, instance_name),
+      None => write!(f, "{}", self.address),
+    }
+  }
+}
+
+///
+/// The name of an operation, as given to the server in GetOperation and CancelOperation requests,
+/// and the endpoint which it belongs to.
+///
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct OperationName {
+  name: String,
+  endpoint: Arc<Endpoint>,
+}
+
+impl OperationName {
+  pub fn new(name: String, endpoint: Arc<Endpoint>) -> Result<OperationName, String> {
+    if name.is_empty() {
+      return Err(format!(
+        "Server {} returned an empty operation name",
+        endpoint
+      ));
+    }
+    Ok(OperationName { name, endpoint })
+  }
+
+  ///
+  /// The name as the server knows it.
+  ///
+  pub fn as_str(&self) -> &str {
+    &self.name
+  }
+
+  pub fn endpoint(&self) -> &Endpoint {
+    &self.endpoint
+  }
+
+  ///
+  /// True if the operation belongs to the given endpoint, so may be referred to in requests to it.
+  ///
+  pub fn belongs_to(&self, endpoint: &Endpoint) -> bool {
+    *self.endpoint == *endpoint
+  }
+}
+
+impl fmt::Display for OperationName {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if self.endpoint.named_in_operations {
+      write!(f, "{} (on {})", self.name, self.endpoint)
+    } else {
+      write!(f, "{}", self.name)
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Endpoint, OperationName};
+  use std::sync::Arc;
+
+  #[test]
+  fn names_must_not_be_empty() {
+    let endpoint = Arc::new(Endpoint::new("localhost:1234".to_owned(), None));
+    assert_eq!(
+      OperationName::new(String::new(), endpoint),
+      Err("Server localhost:1234 returned an empty operation name".to_owned())
+    );
+  }
+
+  #[test]
+  fn display_names_the_endpoint_only_when_asked_to() {
+    let endpoint = Endpoint::new("localhost:1234".to_owned(), Some("main".to_owned()));
+    let name = OperationName::new("op".to_owned(), Arc::new(endpoint.clone())).unwrap();
+    assert_eq!(format!("{}", name), "op");
+
+    let named = OperationName::new(
+      "op".to_owned(),
+      Arc::new(endpoint.clone().named_in_operations()),
+    )
+    .unwrap();
+    assert_eq!(format!("{}", named), "op (on localhost:1234/main)");
+    assert_eq!(named.as_str(), "op");
+    assert!(name.belongs_to(&endpoint));
+    assert!(!name.belongs_to(&Endpoint::new("localhost:4321".to_owned(), None)));
+  }
+}
@@ -0,0 +1,159 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! Expectations of the total size of the outputs of processes. The owners of a process usually
+//! know roughly how large its outputs (e.g. a bundle or a jar) should be, and outputs which are far
+//! larger than that usually mean that a glob captures more than it should, which would otherwise
+//! only be noticed once the outputs had filled up the CAS.
+//!
+
+use std::fmt;
+use std::path::PathBuf;
+
+use hashing::Digest;
+
+// The percentage by which outputs may exceed their expected size, if a request does not set one.
+pub const DEFAULT_TOLERANCE_PERCENT: u64 = 100;
+
+// The most files which are listed in the description of an anomaly.
+const MAX_LARGEST_FILES: usize = 5;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct ExpectedOutputSize {
+  pub bytes: u64,
+  // The percentage by which the outputs may exceed `bytes` before they are anomalous: by default,
+  // DEFAULT_TOLERANCE_PERCENT.
+  pub tolerance_percent: Option<u64>,
+  // If true, a process whose outputs are anomalous fails, rather than only being warned about.
+  pub strict: bool,
+}
+
+impl ExpectedOutputSize {
+  ///
+  /// The largest total size of outputs which is not anomalous.
+  ///
+  pub fn limit(&self) -> u64 {
+    let tolerance_percent = self.tolerance_percent.unwrap_or(DEFAULT_TOLERANCE_PERCENT);
+    self
+      .bytes
+      .saturating_add(self.bytes.saturating_mul(tolerance_percent) / 100)
+  }
+}
+
+///
+/// Outputs which are larger than expected, along with the largest of their files (largest first).
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OutputSizeAnomaly {
+  pub expected: ExpectedOutputSize,
+  pub actual_bytes: u64,
+  pub largest_files: Vec<(PathBuf, u64)>,
+}
+
+impl OutputSizeAnomaly {
+  ///
+  /// An anomaly, if the total size of the given output files exceeds the limit of the expectation.
+  ///
+  pub fn check<I: IntoIterator<Item = (PathBuf, Digest)>>(
+    expected: ExpectedOutputSize,
+    files: I,
+  ) -> Option<OutputSizeAnomaly> {
+    let mut files = files
+      .into_iter()
+      .map(|(path, digest)| (path, digest.1 as u64))
+      .collect::<Vec<_>>();
+    let actual_bytes = files.iter().map(|&(_, size)| size).sum();
+    if actual_bytes <= expected.limit() {
+      return None;
+    }
+    files.sort_by(|(path1, size1), (path2, size2)| size2.cmp(size1).then(path1.cmp(path2)));
+    files.truncate(MAX_LARGEST_FILES);
+    Some(OutputSizeAnomaly {
+      expected,
+      actual_bytes,
+      largest_files: files,
+    })
+  }
+}
+
+impl fmt::Display for OutputSizeAnomaly {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "they total {} bytes, but were expected to total about {} bytes (and at most {}). The \
+       largest are: ",
+      self.actual_bytes,
+      self.expected.bytes,
+      self.expected.limit()
+    )?;
+    for (i, (path, size)) in self.largest_files.iter().enumerate() {
+      if i > 0 {
+        write!(f, ", ")?;
+      }
+      write!(f, "{} ({} bytes)", path.display(), size)?;
+    }
+    write!(
+      f,
+      ". Check that the process's output_files and output_directories do not capture more files \
+       than it produces."
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::PathBuf;
+
+  use hashing::{Digest, Fingerprint};
+
+  use super::{ExpectedOutputSize, OutputSizeAnomaly};
+
+  fn file(path: &str, size: usize) -> (PathBuf, Digest) {
+    (
+      PathBuf::from(path),
+      Digest(Fingerprint::from_bytes_unsafe(&[0; 32]), size),
+    )
+  }
+
+  #[test]
+  fn outputs_within_the_tolerance_are_not_anomalous() {
+    let expected = ExpectedOutputSize {
+      bytes: 100,
+      tolerance_percent: Some(50),
+      strict: false,
+    };
+    assert_eq!(expected.limit(), 150);
+    assert_eq!(
+      OutputSizeAnomaly::check(expected, vec![file("a.jar", 100), file("b.jar", 50)]),
+      None
+    );
+  }
+
+  #[test]
+  fn anomalies_list_the_largest_files_first() {
+    let expected = ExpectedOutputSize {
+      bytes: 10,
+      tolerance_percent: None,
+      strict: false,
+    };
+    let files = (0..7)
+      .map(|i| file(&format!("{}.class", i), i * 10))
+      .collect::<Vec<_>>();
+    let anomaly = OutputSizeAnomaly::check(expected, files).unwrap();
+    assert_eq!(anomaly.actual_bytes, 210);
+    assert_eq!(
+      anomaly.largest_files,
+      vec![
+        (PathBuf::from("6.class"), 60),
+        (PathBuf::from("5.class"), 50),
+        (PathBuf::from("4.class"), 40),
+        (PathBuf::from("3.class"), 30),
+        (PathBuf::from("2.class"), 20),
+      ]
+    );
+    assert!(anomaly
+      .to_string()
+      .contains("expected to total about 10 bytes (and at most 20)"));
+  }
+}
@@ -0,0 +1,202 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! A shared handle which slows down or pauses the polling of remote operations, without
+//! cancelling them: for example, while the session which is consuming their results has detached.
+//!
+
+use std::cmp::{max, min};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use boxfuture::{BoxFuture, Boxable};
+use futures::sync::oneshot;
+use futures::{future, Future};
+use tokio_timer::Delay;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PollingMode {
+  // Poll as soon as each backoff period elapses.
+  Active,
+  // Wait for at least the given interval before each poll, even if the backoff period is shorter.
+  Background(Duration),
+  // Do not poll until the mode changes, unless an operation's timeout is reached.
+  Paused,
+}
+
+///
+/// Cheap to clone: all clones share a mode. Changes of mode take effect immediately, including
+/// for polls which are already waiting.
+///
+#[derive(Clone)]
+pub struct PollingThrottle {
+  state: Arc<Mutex<ThrottleState>>,
+}
+
+struct ThrottleState {
+  mode: PollingMode,
+  // Notified (and then dropped) when the mode changes.
+  waiters: Vec<oneshot::Sender<()>>,
+}
+
+impl PollingThrottle {
+  pub fn new() -> PollingThrottle {
+    PollingThrottle {
+      state: Arc::new(Mutex::new(ThrottleState {
+        mode: PollingMode::Active,
+        waiters: vec![],
+      })),
+    }
+  }
+
+  pub fn mode(&self) -> PollingMode {
+    self.state.lock().unwrap().mode
+  }
+
+  pub fn set_mode(&self, mode: PollingMode) {
+    let waiters = {
+      let mut state = self.state.lock().unwrap();
+      state.mode = mode;
+      std::mem::replace(&mut state.waiters, vec![])
+    };
+    for waiter in waiters {
+      // The waiter may have completed in the meantime, in which case there is nothing to wake.
+      let _ = waiter.send(());
+    }
+  }
+
+  ///
+  /// Waits until a poll whose backoff period starts now should be made under the current mode,
+  /// re-evaluating whenever the mode changes. Regardless of the mode, the wait ends no later than
+  /// the deadline (usually an operation's timeout), if there is one.
+  ///
+  pub fn wait(&self, backoff: Duration, deadline: Option<Instant>) -> BoxFuture<(), String> {
+    let throttle = self.clone();
+    let started = Instant::now();
+    future::loop_fn((), move |()| {
+      let (mode, changed) = throttle.register();
+      let poll_at = match mode {
+        PollingMode::Active => Some(started + backoff),
+        PollingMode::Background(slow_interval) => Some(started + max(backoff, slow_interval)),
+        PollingMode::Paused => None,
+      };
+      let poll_at = match (poll_at, deadline) {
+        (Some(poll_at), Some(deadline)) => Some(min(poll_at, deadline)),
+        (poll_at, None) => poll_at,
+        (None, deadline) => deadline,
+      };
+      let elapsed = match poll_at {
+        Some(poll_at) => Delay::new(poll_at).map_err(|e| e.to_string()).to_boxed(),
+        None => future::empty().to_boxed(),
+      };
+      elapsed
+        .map(|()| future::Loop::Break(()))
+        .select(
+          // A dropped sender can only mean that the mode changed.
+          changed.then(|_| Ok(future::Loop::Continue(()))),
+        )
+        .map(|(next, _)| next)
+        .map_err(|(err, _)| err)
+    })
+    .to_boxed()
+  }
+
+  ///
+  /// Returns the current mode, and a receiver which is notified when it changes.
+  ///
+  fn register(&self) -> (PollingMode, oneshot::Receiver<()>) {
+    let (sender, receiver) = oneshot::channel();
+    let mut state = self.state.lock().unwrap();
+    // Drop the senders of waits which have already completed.
+    state.waiters.retain(|waiter| !waiter.is_canceled());
+    state.waiters.push(sender);
+    (state.mode, receiver)
+  }
+}
+
+impl Default for PollingThrottle {
+  fn default() -> PollingThrottle {
+    PollingThrottle::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::thread;
+  use std::time::{Duration, Instant};
+
+  use super::{PollingMode, PollingThrottle};
+
+  #[test]
+  fn active_waits_for_backoff() {
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let throttle = PollingThrottle::new();
+
+    let start = Instant::now();
+    runtime
+      .block_on(throttle.wait(Duration::from_millis(100), None))
+      .unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(100));
+  }
+
+  #[test]
+  fn background_waits_for_slow_interval() {
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let throttle = PollingThrottle::new();
+    throttle.set_mode(PollingMode::Background(Duration::from_millis(300)));
+
+    let start = Instant::now();
+    runtime
+      .block_on(throttle.wait(Duration::from_millis(10), None))
+      .unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(300));
+  }
+
+  #[test]
+  fn paused_wait_ends_as_soon_as_mode_changes() {
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let throttle = PollingThrottle::new();
+    throttle.set_mode(PollingMode::Paused);
+
+    let unpauser = {
+      let throttle = throttle.clone();
+      thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        throttle.set_mode(PollingMode::Active);
+      })
+    };
+    let start = Instant::now();
+    runtime
+      .block_on(throttle.wait(Duration::from_millis(10), None))
+      .unwrap();
+    unpauser.join().unwrap();
+
+    let elapsed = start.elapsed();
+    assert!(
+      elapsed >= Duration::from_millis(200),
+      "Waited {:?}",
+      elapsed
+    );
+    assert!(elapsed < Duration::from_secs(2), "Waited {:?}", elapsed);
+    // Completed waits do not leak their notifiers.
+    throttle.register();
+    assert_eq!(throttle.state.lock().unwrap().waiters.len(), 1);
+  }
+
+  #[test]
+  fn paused_wait_ends_at_deadline() {
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let throttle = PollingThrottle::new();
+    throttle.set_mode(PollingMode::Paused);
+
+    let start = Instant::now();
+    runtime
+      .block_on(throttle.wait(
+        Duration::from_millis(10),
+        Some(start + Duration::from_millis(200)),
+      ))
+      .unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(200));
+  }
+}
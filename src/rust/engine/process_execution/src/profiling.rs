@@ -0,0 +1,315 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! Timing of the phases of remote executions (building requests, uploading, executing, polling
+//! and extracting results), for finding out where the time goes for small actions without
+//! attaching a profiler to pantsd.
+//!
+//! The `profiled!` and `profiled_future!` macros wrap the expressions (or futures) which make up a
+//! phase. With the exec_profiling feature, each evaluation (or completion) of them is recorded in
+//! a histogram of durations for the phase, which is reported via CommandRunner::metrics and can be
+//! rendered as a table. Without the feature, the macros expand to the expressions themselves, and
+//! nothing else in this module is compiled.
+//!
+
+///
+/// Evaluates the given expression, recording how long it took as the given Phase in the given
+/// Profiler, if the exec_profiling feature is enabled.
+///
+#[cfg(feature = "exec_profiling")]
+macro_rules! profiled {
+  ($profiler:expr, $phase:expr, $e:expr) => {{
+    let start = std::time::Instant::now();
+    let result = $e;
+    $profiler.record($phase, start.elapsed());
+    result
+  }};
+}
+
+#[cfg(not(feature = "exec_profiling"))]
+macro_rules! profiled {
+  ($profiler:expr, $phase:expr, $e:expr) => {
+    $e
+  };
+}
+
+///
+/// Wraps the given future, recording how long it took from now until it completed (or failed) as
+/// the given Phase in the given Profiler, if the exec_profiling feature is enabled.
+///
+#[cfg(feature = "exec_profiling")]
+macro_rules! profiled_future {
+  ($profiler:expr, $phase:expr, $f:expr) => {
+    $profiler.time($phase, $f)
+  };
+}
+
+#[cfg(not(feature = "exec_profiling"))]
+macro_rules! profiled_future {
+  ($profiler:expr, $phase:expr, $f:expr) => {
+    $f
+  };
+}
+
+#[cfg(feature = "exec_profiling")]
+pub use self::profiler::{Phase, Profiler};
+
+#[cfg(feature = "exec_profiling")]
+mod profiler {
+  use std::collections::HashMap;
+  use std::sync::atomic::{AtomicU64, Ordering};
+  use std::sync::Arc;
+  use std::time::{Duration, Instant};
+
+  use boxfuture::{BoxFuture, Boxable};
+  use futures::Future;
+
+  // Durations are bucketed by powers of two microseconds: bucket 0 holds durations under 1us, and
+  // bucket i durations of at least 2^(i-1)us, up to the last bucket (about 4s and longer).
+  const BUCKETS: usize = 24;
+
+  #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+  pub enum Phase {
+    // action::make_execute_request: building and digesting the Action and Command.
+    MakeExecuteRequest,
+    // Serializing and storing the Action and Command in the local Store.
+    StoreProtoLocally,
+    // Ensuring that the remote CAS has the inputs (Store::ensure_remote_has_recursive).
+    Upload,
+    // Submitting the ExecuteRequest, up to the first response.
+    Execute,
+    // Each GetOperation poll.
+    Poll,
+    // Extracting the result from a response: including fetching and storing its outputs.
+    ExtractResult,
+  }
+
+  impl Phase {
+    pub const ALL: [Phase; 6] = [
+      Phase::MakeExecuteRequest,
+      Phase::StoreProtoLocally,
+      Phase::Upload,
+      Phase::Execute,
+      Phase::Poll,
+      Phase::ExtractResult,
+    ];
+
+    fn index(self) -> usize {
+      match self {
+        Phase::MakeExecuteRequest => 0,
+        Phase::StoreProtoLocally => 1,
+        Phase::Upload => 2,
+        Phase::Execute => 3,
+        Phase::Poll => 4,
+        Phase::ExtractResult => 5,
+      }
+    }
+
+    pub fn name(self) -> &'static str {
+      match self {
+        Phase::MakeExecuteRequest => "make_execute_request",
+        Phase::StoreProtoLocally => "store_proto_locally",
+        Phase::Upload => "upload",
+        Phase::Execute => "execute",
+        Phase::Poll => "poll",
+        Phase::ExtractResult => "extract_result",
+      }
+    }
+
+    // The metrics of the samples, total microseconds, and median and 99th percentile microseconds
+    // of this phase.
+    fn metrics(self) -> [&'static str; 4] {
+      macro_rules! phase_metrics {
+        ($name:expr) => {
+          [
+            concat!("remote_profile_", $name, "_samples"),
+            concat!("remote_profile_", $name, "_micros_total"),
+            concat!("remote_profile_", $name, "_micros_p50"),
+            concat!("remote_profile_", $name, "_micros_p99"),
+          ]
+        };
+      }
+      match self {
+        Phase::MakeExecuteRequest => phase_metrics!("make_execute_request"),
+        Phase::StoreProtoLocally => phase_metrics!("store_proto_locally"),
+        Phase::Upload => phase_metrics!("upload"),
+        Phase::Execute => phase_metrics!("execute"),
+        Phase::Poll => phase_metrics!("poll"),
+        Phase::ExtractResult => phase_metrics!("extract_result"),
+      }
+    }
+  }
+
+  #[derive(Default)]
+  struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+    samples: AtomicU64,
+    total_micros: AtomicU64,
+    max_micros: AtomicU64,
+  }
+
+  impl Histogram {
+    fn record(&self, duration: Duration) {
+      let micros = duration.as_micros() as u64;
+      let bucket = ((64 - micros.leading_zeros()) as usize).min(BUCKETS - 1);
+      self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+      self.samples.fetch_add(1, Ordering::Relaxed);
+      self.total_micros.fetch_add(micros, Ordering::Relaxed);
+      let mut max = self.max_micros.load(Ordering::Relaxed);
+      while micros > max {
+        match self.max_micros.compare_exchange_weak(
+          max,
+          micros,
+          Ordering::Relaxed,
+          Ordering::Relaxed,
+        ) {
+          Ok(_) => break,
+          Err(actual) => max = actual,
+        }
+      }
+    }
+
+    ///
+    /// An upper bound on the given percentile of the recorded durations, in microseconds: the
+    /// upper bound of the bucket which it falls in, or the longest duration for the last bucket.
+    ///
+    fn percentile_micros(&self, percentile: u64) -> u64 {
+      let samples = self.samples.load(Ordering::Relaxed);
+      if samples == 0 {
+        return 0;
+      }
+      let rank = (samples * percentile + 99) / 100;
+      let mut seen = 0;
+      for (bucket, count) in self.buckets.iter().enumerate() {
+        seen += count.load(Ordering::Relaxed);
+        if seen >= rank && bucket < BUCKETS - 1 {
+          return (1u64 << bucket).min(self.max_micros.load(Ordering::Relaxed));
+        }
+      }
+      self.max_micros.load(Ordering::Relaxed)
+    }
+  }
+
+  ///
+  /// Histograms of the durations of each Phase. Cheap to clone: all clones record into the same
+  /// histograms.
+  ///
+  #[derive(Clone, Default)]
+  pub struct Profiler {
+    histograms: Arc<[Histogram; 6]>,
+  }
+
+  impl Profiler {
+    pub fn record(&self, phase: Phase, duration: Duration) {
+      self.histograms[phase.index()].record(duration);
+    }
+
+    ///
+    /// Records how long the given future takes, from now until it completes or fails.
+    ///
+    pub fn time<F>(&self, phase: Phase, future: F) -> BoxFuture<F::Item, F::Error>
+    where
+      F: Future + Send + 'static,
+      F::Item: Send + 'static,
+      F::Error: Send + 'static,
+    {
+      let profiler = self.clone();
+      let start = Instant::now();
+      future
+        .then(move |result| {
+          profiler.record(phase, start.elapsed());
+          result
+        })
+        .to_boxed()
+    }
+
+    pub fn samples(&self, phase: Phase) -> u64 {
+      self.histograms[phase.index()]
+        .samples
+        .load(Ordering::Relaxed)
+    }
+
+    ///
+    /// Adds the samples, total, median and 99th percentile durations of each phase to a metrics
+    /// snapshot.
+    ///
+    pub fn record_metrics(&self, snapshot: &mut HashMap<&'static str, i64>) {
+      for phase in &Phase::ALL {
+        let histogram = &self.histograms[phase.index()];
+        let [samples, total, p50, p99] = phase.metrics();
+        snapshot.insert(samples, histogram.samples.load(Ordering::Relaxed) as i64);
+        snapshot.insert(total, histogram.total_micros.load(Ordering::Relaxed) as i64);
+        snapshot.insert(p50, histogram.percentile_micros(50) as i64);
+        snapshot.insert(p99, histogram.percentile_micros(99) as i64);
+      }
+    }
+
+    ///
+    /// Renders a table of the samples and durations of each phase.
+    ///
+    pub fn render_summary(&self) -> String {
+      let mut lines = vec![format!(
+        "{:<22}{:>10}{:>14}{:>12}{:>12}{:>12}{:>12}",
+        "phase", "samples", "total", "mean", "p50", "p99", "max"
+      )];
+      for phase in &Phase::ALL {
+        let histogram = &self.histograms[phase.index()];
+        let samples = histogram.samples.load(Ordering::Relaxed);
+        let total = histogram.total_micros.load(Ordering::Relaxed);
+        let micros = |micros: u64| format!("{:?}", Duration::from_micros(micros));
+        lines.push(format!(
+          "{:<22}{:>10}{:>14}{:>12}{:>12}{:>12}{:>12}",
+          phase.name(),
+          samples,
+          micros(total),
+          micros(if samples == 0 { 0 } else { total / samples }),
+          micros(histogram.percentile_micros(50)),
+          micros(histogram.percentile_micros(99)),
+          micros(histogram.max_micros.load(Ordering::Relaxed)),
+        ));
+      }
+      lines.join("\n")
+    }
+  }
+
+  #[cfg(test)]
+  mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use futures::{future, Future};
+
+    use super::{Phase, Profiler};
+
+    #[test]
+    fn percentiles_are_bucket_upper_bounds() {
+      let profiler = Profiler::default();
+      for micros in &[3, 3, 3, 100, 5000] {
+        profiler.record(Phase::Poll, Duration::from_micros(*micros));
+      }
+      let mut snapshot = HashMap::new();
+      profiler.record_metrics(&mut snapshot);
+      assert_eq!(snapshot.get("remote_profile_poll_samples"), Some(&5));
+      assert_eq!(
+        snapshot.get("remote_profile_poll_micros_total"),
+        Some(&5109)
+      );
+      assert_eq!(snapshot.get("remote_profile_poll_micros_p50"), Some(&4));
+      // The largest duration bounds any percentile.
+      assert_eq!(snapshot.get("remote_profile_poll_micros_p99"), Some(&5000));
+      assert_eq!(snapshot.get("remote_profile_execute_samples"), Some(&0));
+      assert_eq!(snapshot.get("remote_profile_execute_micros_p99"), Some(&0));
+    }
+
+    #[test]
+    fn futures_are_timed_when_they_complete() {
+      let profiler = Profiler::default();
+      let timed = profiler.time(Phase::Execute, future::err::<(), _>("Boom"));
+      assert_eq!(profiler.samples(Phase::Execute), 0);
+      assert_eq!(timed.wait(), Err("Boom"));
+      assert_eq!(profiler.samples(Phase::Execute), 1);
+      assert_eq!(profiler.clone().samples(Phase::Execute), 1);
+    }
+  }
+}
@@ -0,0 +1,291 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! Property tests for the canonicalization of ExecuteProcessRequests into REAPI Actions by
+//! `action::make_execute_request`, whose digests are used as cache keys.
+//!
+//! NB: Platform properties are deliberately not permuted: their order is preserved in the
+//! Command (see `make_execute_request_with_jdk_and_extra_platform_properties`), and so is
+//! significant.
+//!
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use hashing::{Digest, Fingerprint, EMPTY_DIGEST};
+use proptest::collection::{btree_map, btree_set, vec};
+use proptest::prelude::*;
+
+use crate::action::make_execute_request;
+use crate::{
+  EnvRecording, ExecuteProcessRequest, ExecuteProcessRequestMetadata, ExecutionLocality, Platform,
+};
+
+///
+/// An ExecuteProcessRequest whose Debug output (which proptest prints for failing, shrunk inputs)
+/// does not include the values of environment variables, which may be secrets in real requests.
+///
+#[derive(Clone)]
+struct ArbitraryRequest(ExecuteProcessRequest);
+
+impl fmt::Debug for ArbitraryRequest {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let req = &self.0;
+    f.debug_struct("ExecuteProcessRequest")
+      .field("argv", &req.argv)
+      .field(
+        "env",
+        &req
+          .env
+          .keys()
+          .map(|name| (name, "<redacted>"))
+          .collect::<BTreeMap<_, _>>(),
+      )
+      .field("input_files", &req.input_files)
+      .field("output_files", &req.output_files)
+      .field("output_directories", &req.output_directories)
+      .field("jdk_home", &req.jdk_home)
+      .field("target_platform", &req.target_platform)
+      .finish()
+  }
+}
+
+impl Arbitrary for Platform {
+  type Parameters = ();
+  type Strategy = BoxedStrategy<Platform>;
+
+  fn arbitrary_with(_: ()) -> Self::Strategy {
+    prop_oneof![
+      Just(Platform::Darwin),
+      Just(Platform::Linux),
+      Just(Platform::None),
+    ]
+    .boxed()
+  }
+}
+
+impl Arbitrary for ArbitraryRequest {
+  type Parameters = ();
+  type Strategy = BoxedStrategy<ArbitraryRequest>;
+
+  fn arbitrary_with(_: ()) -> Self::Strategy {
+    (
+      vec("[a-z/._-]{1,8}", 1..5),
+      btree_map("[A-Z_]{1,6}", "\\PC{0,8}", 0..5),
+      prop_oneof![Just(EMPTY_DIGEST), Just(a_digest())],
+      btree_set(path(), 0..4),
+      btree_set(path(), 0..4),
+      proptest::option::of(path()),
+      any::<Platform>(),
+    )
+      .prop_map(
+        |(argv, env, input_files, output_files, output_directories, jdk_home, target_platform)| {
+          ArbitraryRequest(ExecuteProcessRequest {
+            argv,
+            env,
+            input_files,
+            output_files,
+            output_directories,
+            timeout: Duration::from_secs(1),
+            description: "proptest".to_owned(),
+            jdk_home,
+            target_platform,
+            force_rerun: false,
+            expected_output_digest: None,
+            diff_outputs: false,
+            ephemeral_input_digests: vec![],
+            scheduling_hints: None,
+            poll_interval_hint: None,
+            cache_scope_names: BTreeSet::new(),
+            argfile_threshold: None,
+            argfile_flag_template: None,
+            provenance: None,
+            environment: None,
+            omit_cache_key_gen_version: false,
+            expected_output_size: None,
+            execution_locality: ExecutionLocality::Any,
+          })
+        },
+      )
+      .boxed()
+  }
+}
+
+fn a_digest() -> Digest {
+  Digest(
+    Fingerprint::from_hex_string(
+      "693d8db7b05e99c6b7a7c0616456039d89c555029026936248085193559a0b5d",
+    )
+    .unwrap(),
+    16,
+  )
+}
+
+fn path() -> impl Strategy<Value = PathBuf> {
+  "[a-z]{1,4}(/[a-z]{1,4}){0,2}".prop_map(PathBuf::from)
+}
+
+///
+/// Path components which are unlikely to be seen in practice, but which should not cause a panic.
+///
+fn adversarial_path() -> impl Strategy<Value = PathBuf> {
+  prop_oneof![
+    any::<String>(),
+    "[a-z]{0,4}\u{0}[a-z]{0,4}",
+    "[a-z]{256,1024}",
+    "\\PC{1,16}(/\\PC{1,16}){0,4}",
+    "(\\.\\./){1,4}[a-z]{0,4}",
+  ]
+  .prop_map(PathBuf::from)
+}
+
+fn action_digest(req: &ExecuteProcessRequest) -> bazel_protos::remote_execution::Digest {
+  let (_, _, execute_request) = make_execute_request(req, metadata()).unwrap();
+  execute_request.get_action_digest().clone()
+}
+
+fn metadata() -> ExecuteProcessRequestMetadata {
+  ExecuteProcessRequestMetadata {
+    instance_name: None,
+    cache_key_gen_version: None,
+    cache_scopes: BTreeMap::new(),
+    platform_properties: vec![],
+    timeout_excludes_queue: false,
+    argv_error_patterns: vec![],
+    argv_warning_bytes: None,
+    allow_lossy_env: false,
+    canonical_form_version: crate::CANONICAL_FORM_VERSION,
+    environments: BTreeMap::new(),
+    record_env_in_report: EnvRecording::Off,
+  }
+}
+
+fn is_strictly_sorted<T: Ord, I: IntoIterator<Item = T>>(items: I) -> bool {
+  let items = items.into_iter().collect::<Vec<_>>();
+  items.windows(2).all(|pair| pair[0] < pair[1])
+}
+
+///
+/// Whether two requests are equal in all of the fields which are expected to affect their Action
+/// digests.
+///
+fn semantically_equal(left: &ExecuteProcessRequest, right: &ExecuteProcessRequest) -> bool {
+  left.argv == right.argv
+    && left.env == right.env
+    && left.input_files == right.input_files
+    && left.output_files == right.output_files
+    && left.output_directories == right.output_directories
+    // Only the presence of a jdk_home is sent to the server.
+    && left.jdk_home.is_some() == right.jdk_home.is_some()
+    && left.target_platform == right.target_platform
+}
+
+proptest! {
+  #[test]
+  fn commands_are_canonical(req in any::<ArbitraryRequest>()) {
+    let ArbitraryRequest(req) = req;
+    let (action, command, execute_request) = make_execute_request(&req, metadata()).unwrap();
+
+    // The REAPI requires that environment variables are sorted by name, and output paths sorted,
+    // so that equivalent Commands serialize (and so digest) identically.
+    let env = command
+      .get_environment_variables()
+      .iter()
+      .map(|env| (env.get_name().to_owned(), env.get_value().to_owned()))
+      .collect::<Vec<_>>();
+    prop_assert!(is_strictly_sorted(env.iter().map(|(name, _)| name)), "{:?}", env);
+    prop_assert_eq!(env, req.env.clone().into_iter().collect::<Vec<_>>());
+    prop_assert!(is_strictly_sorted(command.get_output_files()));
+    prop_assert!(is_strictly_sorted(command.get_output_directories()));
+    prop_assert_eq!(command.get_arguments(), &req.argv[..]);
+
+    // And the digests which identify the request are those of the messages which are uploaded.
+    prop_assert_eq!(
+      Result::<Digest, String>::from(action.get_command_digest()),
+      crate::action::digest(&command)
+    );
+    prop_assert_eq!(
+      Result::<Digest, String>::from(execute_request.get_action_digest()),
+      crate::action::digest(&action)
+    );
+  }
+
+  #[test]
+  fn adding_then_removing_fields_restores_digest(
+    req in any::<ArbitraryRequest>(),
+    env_name in "[a-z]{1,6}",
+    output in path(),
+  ) {
+    let ArbitraryRequest(req) = req;
+    let original = action_digest(&req);
+
+    let mut modified = req.clone();
+    let env_inserted = modified.env.insert(env_name.clone(), "value".to_owned()).is_none();
+    let file_inserted = modified.output_files.insert(output.clone());
+    let dir_inserted = modified.output_directories.insert(output.clone());
+    let jdk_inserted = modified.jdk_home.is_none();
+    if jdk_inserted {
+      modified.jdk_home = Some(PathBuf::from("/jdk"));
+    }
+    if env_inserted || file_inserted || dir_inserted || jdk_inserted {
+      prop_assert_ne!(action_digest(&modified), original);
+    }
+
+    if env_inserted {
+      modified.env.remove(&env_name);
+    }
+    if file_inserted {
+      modified.output_files.remove(&output);
+    }
+    if dir_inserted {
+      modified.output_directories.remove(&output);
+    }
+    if jdk_inserted {
+      modified.jdk_home = None;
+    }
+    prop_assert_eq!(action_digest(&modified), original);
+  }
+
+  #[test]
+  fn digests_do_not_collide(reqs in vec(any::<ArbitraryRequest>(), 2..16)) {
+    let digested = reqs
+      .iter()
+      .map(|ArbitraryRequest(req)| (req, action_digest(req)))
+      .collect::<Vec<_>>();
+    for (i, (left, left_digest)) in digested.iter().enumerate() {
+      for (right, right_digest) in &digested[i + 1..] {
+        if !semantically_equal(left, right) {
+          prop_assert_ne!(
+            left_digest,
+            right_digest,
+            "{:?} and {:?} had the same digest",
+            ArbitraryRequest((*left).clone()),
+            ArbitraryRequest((*right).clone())
+          );
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn adversarial_paths_do_not_panic(
+    req in any::<ArbitraryRequest>(),
+    outputs in vec(adversarial_path(), 0..4),
+    jdk_home in proptest::option::of(adversarial_path()),
+    argv in vec(any::<String>(), 0..4),
+  ) {
+    let ArbitraryRequest(req) = req;
+    let req = ExecuteProcessRequest {
+      argv,
+      output_files: outputs.iter().cloned().collect(),
+      output_directories: outputs.into_iter().collect(),
+      jdk_home,
+      ..req
+    };
+    // Errors are acceptable, but panics are not.
+    let _ = make_execute_request(&req, metadata());
+  }
+}
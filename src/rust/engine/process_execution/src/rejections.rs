@@ -0,0 +1,366 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! Classifies the statuses with which remote execution servers reject requests for reasons other
+//! than missing blobs (such as an unknown instance, an exhausted quota, or a policy which bans the
+//! action), so that they can be reported with hints about how to resolve them, rather than as
+//! generic errors.
+//!
+//! Servers may explain a rejection with a google.rpc.ErrorInfo in the details of its status. The
+//! vendored error_details.proto predates ErrorInfo, which is equivalent to:
+//!
+//! ```protobuf
+//! package google.rpc;
+//!
+//! message ErrorInfo {
+//!   string reason = 1;
+//!   string domain = 2;
+//!   map<string, string> metadata = 3;
+//! }
+//! ```
+//!
+//! and is simple enough to decode by hand, as scheduling hints are encoded.
+//!
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use bazel_protos;
+use grpcio;
+use protobuf::well_known_types::Any;
+use protobuf::wire_format::WireType;
+use protobuf::{CodedInputStream, CodedOutputStream, ProtobufResult};
+
+use crate::metrics;
+
+pub const ERROR_INFO_TYPE_URL: &str = "type.googleapis.com/google.rpc.ErrorInfo";
+
+const ERROR_INFO_FULL_NAME: &str = "google.rpc.ErrorInfo";
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ErrorInfo {
+  pub reason: String,
+  pub domain: String,
+  pub metadata: BTreeMap<String, String>,
+}
+
+impl ErrorInfo {
+  ///
+  /// Decodes the given Any, if it contains an ErrorInfo. As with other Anys, the prefix of its type
+  /// URL is not significant.
+  ///
+  pub fn from_any(any: &Any) -> Option<Result<ErrorInfo, String>> {
+    if any.get_type_url().rsplit('/').next() != Some(ERROR_INFO_FULL_NAME) {
+      return None;
+    }
+    Some(
+      ErrorInfo::decode(any.get_value())
+        .map_err(|e| format!("Error decoding {}: {}", ERROR_INFO_FULL_NAME, e)),
+    )
+  }
+
+  fn decode(bytes: &[u8]) -> Result<ErrorInfo, String> {
+    let mut error_info = ErrorInfo::default();
+    read_fields(
+      &mut CodedInputStream::from_bytes(bytes),
+      |field_number, is| {
+        match field_number {
+          1 => error_info.reason = is.read_string()?,
+          2 => error_info.domain = is.read_string()?,
+          3 => {
+            // Map entries are messages of a key and a value.
+            let entry = is.read_bytes()?;
+            let (mut key, mut value) = (String::new(), String::new());
+            read_fields(
+              &mut CodedInputStream::from_bytes(&entry),
+              |field_number, is| {
+                match field_number {
+                  1 => key = is.read_string()?,
+                  2 => value = is.read_string()?,
+                  _ => return Ok(false),
+                }
+                Ok(true)
+              },
+            )?;
+            error_info.metadata.insert(key, value);
+          }
+          _ => return Ok(false),
+        }
+        Ok(true)
+      },
+    )
+    .map_err(|e| format!("{:?}", e))?;
+    Ok(error_info)
+  }
+
+  pub fn to_any(&self) -> Any {
+    let value = encode(|os| {
+      // As in generated code, fields with default values are omitted.
+      if !self.reason.is_empty() {
+        os.write_string(1, &self.reason)?;
+      }
+      if !self.domain.is_empty() {
+        os.write_string(2, &self.domain)?;
+      }
+      for (key, value) in &self.metadata {
+        let entry = encode(|os| {
+          os.write_string(1, key)?;
+          os.write_string(2, value)
+        });
+        os.write_bytes(3, &entry)?;
+      }
+      Ok(())
+    });
+    let mut any = Any::new();
+    any.set_type_url(ERROR_INFO_TYPE_URL.to_owned());
+    any.set_value(value);
+    any
+  }
+}
+
+///
+/// Calls `read_field` with the number of each length-delimited field of a message, and the stream
+/// positioned at its value. Fields for which it returns false are skipped, as are fields of other
+/// wire types.
+///
+fn read_fields<F>(is: &mut CodedInputStream, mut read_field: F) -> ProtobufResult<()>
+where
+  F: FnMut(u32, &mut CodedInputStream) -> ProtobufResult<bool>,
+{
+  while !is.eof()? {
+    let (field_number, wire_type) = is.read_tag_unpack()?;
+    if !(wire_type == WireType::WireTypeLengthDelimited && read_field(field_number, is)?) {
+      is.read_unknown(wire_type)?;
+    }
+  }
+  Ok(())
+}
+
+fn encode<F>(write_fields: F) -> Vec<u8>
+where
+  F: FnOnce(&mut CodedOutputStream) -> ProtobufResult<()>,
+{
+  let mut bytes = Vec::new();
+  {
+    let mut os = CodedOutputStream::vec(&mut bytes);
+    write_fields(&mut os)
+      .and_then(|()| os.flush())
+      .expect("Writing to a Vec cannot fail.");
+  }
+  bytes
+}
+
+///
+/// Why a server rejected a request. These are coarse, so that the rejections of different servers
+/// (which use different ErrorInfo reasons) can be counted and explained alike.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RejectionReason {
+  // The instance name is unknown to the server, or is not enabled.
+  Instance,
+  // A quota of the caller (or of the instance) is exhausted.
+  Quota,
+  // A policy of the server bans the action.
+  Policy,
+  // The caller is not authenticated, or is not allowed to use the instance.
+  Permission,
+  Other,
+}
+
+impl RejectionReason {
+  ///
+  /// Classifies the reason of an ErrorInfo, which is conventionally an UPPER_SNAKE_CASE
+  /// identifier such as INSTANCE_NOT_FOUND or RESOURCE_QUOTA_EXCEEDED.
+  ///
+  fn from_error_info_reason(reason: &str) -> Option<RejectionReason> {
+    let reason = reason.to_uppercase();
+    let is = |words: &[&str]| words.iter().any(|word| reason.contains(word));
+    if is(&["INSTANCE"]) {
+      Some(RejectionReason::Instance)
+    } else if is(&["QUOTA", "RATE_LIMIT"]) {
+      Some(RejectionReason::Quota)
+    } else if is(&["POLICY", "BANNED", "BLOCKED"]) {
+      Some(RejectionReason::Policy)
+    } else if is(&["AUTH", "CREDENTIAL", "PERMISSION"]) {
+      Some(RejectionReason::Permission)
+    } else {
+      None
+    }
+  }
+
+  fn from_code(code: grpcio::RpcStatusCode) -> RejectionReason {
+    match code {
+      grpcio::RpcStatusCode::ResourceExhausted => RejectionReason::Quota,
+      grpcio::RpcStatusCode::PermissionDenied | grpcio::RpcStatusCode::Unauthenticated => {
+        RejectionReason::Permission
+      }
+      _ => RejectionReason::Other,
+    }
+  }
+
+  pub fn metric(self) -> &'static str {
+    match self {
+      RejectionReason::Instance => metrics::REMOTE_REJECTIONS_INSTANCE,
+      RejectionReason::Quota => metrics::REMOTE_REJECTIONS_QUOTA,
+      RejectionReason::Policy => metrics::REMOTE_REJECTIONS_POLICY,
+      RejectionReason::Permission => metrics::REMOTE_REJECTIONS_PERMISSION,
+      RejectionReason::Other => metrics::REMOTE_REJECTIONS_OTHER,
+    }
+  }
+
+  fn remediation(self) -> &'static str {
+    match self {
+      RejectionReason::Instance => {
+        "Check that the remote instance name is correct, and that the instance is enabled."
+      }
+      RejectionReason::Quota => {
+        "Request more quota from the administrators of the server, or run fewer processes \
+         remotely at once."
+      }
+      RejectionReason::Policy => {
+        "A policy of the server does not allow this process: contact its administrators."
+      }
+      RejectionReason::Permission => {
+        "Check that your remote execution credentials are valid, and that they allow access to \
+         the instance."
+      }
+      RejectionReason::Other => "Contact the administrators of the server.",
+    }
+  }
+}
+
+///
+/// A request which the server rejected for a reason other than missing blobs, and which is not
+/// worth retrying as is.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct RemoteRejection {
+  pub code: grpcio::RpcStatusCode,
+  pub message: String,
+  pub reason: RejectionReason,
+  pub error_info: Option<ErrorInfo>,
+}
+
+impl RemoteRejection {
+  ///
+  /// Classifies the given status as a rejection, if it is one: PermissionDenied, Unauthenticated
+  /// and ResourceExhausted statuses always are, while FailedPrecondition statuses are if they
+  /// explain themselves with an ErrorInfo. Callers must first rule out FailedPrecondition
+  /// statuses which report missing blobs, which are resolved by uploading them.
+  ///
+  pub fn classify(status: &bazel_protos::status::Status) -> Option<RemoteRejection> {
+    let code = grpcio::RpcStatusCode::from(status.get_code());
+    // Undecodable ErrorInfos are ignored, so that the server's message is still reported.
+    let error_info = status
+      .get_details()
+      .iter()
+      .filter_map(ErrorInfo::from_any)
+      .filter_map(Result::ok)
+      .next();
+    match code {
+      grpcio::RpcStatusCode::PermissionDenied
+      | grpcio::RpcStatusCode::Unauthenticated
+      | grpcio::RpcStatusCode::ResourceExhausted => {}
+      grpcio::RpcStatusCode::FailedPrecondition if error_info.is_some() => {}
+      _ => return None,
+    }
+    let reason = error_info
+      .as_ref()
+      .and_then(|error_info| RejectionReason::from_error_info_reason(&error_info.reason))
+      .unwrap_or_else(|| RejectionReason::from_code(code));
+    Some(RemoteRejection {
+      code,
+      message: status.get_message().to_owned(),
+      reason,
+      error_info,
+    })
+  }
+}
+
+impl fmt::Display for RemoteRejection {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "Remote execution rejected the request: {:?}: {:?}",
+      self.code, self.message
+    )?;
+    if let Some(ref error_info) = self.error_info {
+      write!(f, " (reason {}", error_info.reason)?;
+      if !error_info.domain.is_empty() {
+        write!(f, " from {}", error_info.domain)?;
+      }
+      for (i, (key, value)) in error_info.metadata.iter().enumerate() {
+        write!(f, "{}{}={}", if i == 0 { ": " } else { ", " }, key, value)?;
+      }
+      write!(f, ")")?;
+    }
+    write!(f, ". {}", self.reason.remediation())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{ErrorInfo, RejectionReason, RemoteRejection};
+  use bazel_protos;
+  use grpcio;
+  use maplit::btreemap;
+
+  fn status(
+    code: grpcio::RpcStatusCode,
+    error_info: Option<&ErrorInfo>,
+  ) -> bazel_protos::status::Status {
+    let mut status = bazel_protos::status::Status::new();
+    status.set_code(code as i32);
+    status.set_message("Request rejected".to_owned());
+    if let Some(error_info) = error_info {
+      status.mut_details().push(error_info.to_any());
+    }
+    status
+  }
+
+  #[test]
+  fn error_info_round_trips() {
+    let error_info = ErrorInfo {
+      reason: "RESOURCE_QUOTA_EXCEEDED".to_owned(),
+      domain: "remotebuildexecution.googleapis.com".to_owned(),
+      metadata: btreemap! {
+        "quota".to_owned() => "concurrent_actions".to_owned(),
+        "limit".to_owned() => "500".to_owned(),
+      },
+    };
+    assert_eq!(
+      ErrorInfo::from_any(&error_info.to_any()),
+      Some(Ok(error_info))
+    );
+  }
+
+  #[test]
+  fn statuses_are_classified() {
+    let banned = ErrorInfo {
+      reason: "ACTION_BANNED_BY_POLICY".to_owned(),
+      ..ErrorInfo::default()
+    };
+    let rejection = RemoteRejection::classify(&status(
+      grpcio::RpcStatusCode::FailedPrecondition,
+      Some(&banned),
+    ))
+    .unwrap();
+    assert_eq!(rejection.reason, RejectionReason::Policy);
+    assert_eq!(rejection.error_info, Some(banned));
+
+    let unauthenticated =
+      RemoteRejection::classify(&status(grpcio::RpcStatusCode::Unauthenticated, None)).unwrap();
+    assert_eq!(unauthenticated.reason, RejectionReason::Permission);
+
+    // FailedPreconditions without an ErrorInfo are missing blobs.
+    assert_eq!(
+      RemoteRejection::classify(&status(grpcio::RpcStatusCode::FailedPrecondition, None)),
+      None
+    );
+    assert_eq!(
+      RemoteRejection::classify(&status(grpcio::RpcStatusCode::Internal, None)),
+      None
+    );
+  }
+}
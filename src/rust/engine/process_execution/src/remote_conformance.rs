@@ -0,0 +1,705 @@
+//!
+//! A battery of scripted executions which exercise a remote execution server through a real
+//! remote::CommandRunner, so that incompatibilities between server implementations can be
+//! diagnosed without running a full build.
+//!
+//! Every request made by the battery includes a caller-provided nonce in its environment, so that
+//! results are never served from the server's action cache.
+//!
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use bazel_protos;
+use boxfuture::{BoxFuture, Boxable};
+use bytes::Bytes;
+use futures::{future, Future, Stream};
+use hashing::Digest;
+use libc;
+use store::Store;
+use tokio_timer::Delay;
+use workunit_store::WorkUnitStore;
+
+use super::{
+  remote, CommandRunner, ExecuteProcessRequest, ExecutionLocality, FallibleExecuteProcessResult,
+  Platform, ProcessOutput,
+};
+
+// The environment variable via which the nonce is passed to each request.
+pub const NONCE_ENV_VAR_NAME: &str = "PANTS_REMOTE_CONFORMANCE_NONCE";
+
+// The number of bytes written to stdout by Check::LargeStdoutViaDigest. This is large enough that
+// servers should not inline it in the ActionResult.
+pub const LARGE_STDOUT_BYTES: usize = 1024 * 1024;
+
+// The name and content of the nested output file created by Check::OutputDirectoryNesting.
+pub const NESTED_OUTPUT_DIRECTORY: &str = "cats";
+pub const NESTED_OUTPUT_FILE: &str = "roland";
+pub const NESTED_OUTPUT_CONTENT: &str = "European Burmese";
+
+// The name of the input file read by Check::MissingBlobRecovery.
+pub const MISSING_BLOB_INPUT_FILE: &str = "nonce.txt";
+
+// How long Check::Cancellation waits before dropping its request.
+pub const CANCELLATION_DELAY: Duration = Duration::from_secs(2);
+
+// The timeout of the request made by Check::Timeout.
+pub const TIMEOUT_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+// The maximum number of bytes of stdout/stderr attached to a CheckReport.
+const MAX_ATTACHED_OUTPUT_BYTES: usize = 1024;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Check {
+  // Runs an echo, and expects its stdout back.
+  EchoRoundTrip,
+  // Writes enough to stdout that the server should return it by digest rather than inline.
+  LargeStdoutViaDigest,
+  // Writes a file into a nested output directory, and expects to find it in the output tree.
+  OutputDirectoryNesting,
+  // Provides an input root which the server has never seen, and expects the client to recover
+  // from the FailedPrecondition that the server reports for it.
+  MissingBlobRecovery,
+  // Drops a long-running request, and expects that to neither error nor hang.
+  Cancellation,
+  // Runs a long-running request with a short timeout, and expects the timeout to be reported.
+  Timeout,
+}
+
+impl Check {
+  pub fn all() -> Vec<Check> {
+    vec![
+      Check::EchoRoundTrip,
+      Check::LargeStdoutViaDigest,
+      Check::OutputDirectoryNesting,
+      Check::MissingBlobRecovery,
+      Check::Cancellation,
+      Check::Timeout,
+    ]
+  }
+
+  pub fn name(self) -> &'static str {
+    match self {
+      Check::EchoRoundTrip => "echo_round_trip",
+      Check::LargeStdoutViaDigest => "large_stdout_via_digest",
+      Check::OutputDirectoryNesting => "output_directory_nesting",
+      Check::MissingBlobRecovery => "missing_blob_recovery",
+      Check::Cancellation => "cancellation",
+      Check::Timeout => "timeout",
+    }
+  }
+
+  pub fn from_name(name: &str) -> Result<Check, String> {
+    Check::all()
+      .into_iter()
+      .find(|check| check.name() == name)
+      .ok_or_else(|| {
+        format!(
+          "Unknown check {:?}: options are {}",
+          name,
+          Check::all()
+            .into_iter()
+            .map(Check::name)
+            .collect::<Vec<_>>()
+            .join(", ")
+        )
+      })
+  }
+
+  ///
+  /// Stores any inputs needed by this Check in the local Store, and returns the request that it
+  /// will run.
+  ///
+  pub fn prepare(self, store: &Store, nonce: &str) -> BoxFuture<ExecuteProcessRequest, String> {
+    let mut env = BTreeMap::new();
+    env.insert(NONCE_ENV_VAR_NAME.to_owned(), nonce.to_owned());
+    let request = ExecuteProcessRequest {
+      argv: vec![],
+      env,
+      input_files: hashing::EMPTY_DIGEST,
+      output_files: BTreeSet::new(),
+      output_directories: BTreeSet::new(),
+      timeout: Duration::from_secs(60),
+      description: format!("remote conformance check {}", self.name()),
+      jdk_home: None,
+      target_platform: Platform::None,
+      force_rerun: false,
+      expected_output_digest: None,
+      diff_outputs: false,
+      ephemeral_input_digests: vec![],
+      scheduling_hints: None,
+      poll_interval_hint: None,
+      cache_scope_names: BTreeSet::new(),
+      argfile_threshold: None,
+      argfile_flag_template: None,
+      provenance: None,
+      environment: None,
+      omit_cache_key_gen_version: false,
+      expected_output_size: None,
+      execution_locality: ExecutionLocality::Any,
+    };
+
+    match self {
+      Check::EchoRoundTrip => future::ok(ExecuteProcessRequest {
+        argv: argv(&["/bin/echo", "-n", nonce]),
+        ..request
+      })
+      .to_boxed(),
+      Check::LargeStdoutViaDigest => future::ok(ExecuteProcessRequest {
+        argv: argv(&[
+          "/bin/sh",
+          "-c",
+          &format!("head -c {} /dev/zero | tr '\\0' x", LARGE_STDOUT_BYTES),
+        ]),
+        ..request
+      })
+      .to_boxed(),
+      Check::OutputDirectoryNesting => future::ok(ExecuteProcessRequest {
+        argv: argv(&[
+          "/bin/sh",
+          "-c",
+          &format!(
+            "mkdir -p {dir} && /bin/echo -n '{content}' > {dir}/{file}",
+            dir = NESTED_OUTPUT_DIRECTORY,
+            file = NESTED_OUTPUT_FILE,
+            content = NESTED_OUTPUT_CONTENT,
+          ),
+        ]),
+        output_directories: vec![PathBuf::from(NESTED_OUTPUT_DIRECTORY)]
+          .into_iter()
+          .collect(),
+        ..request
+      })
+      .to_boxed(),
+      Check::MissingBlobRecovery => {
+        let store = store.clone();
+        store
+          .store_file_bytes(Bytes::from(nonce.as_bytes()), true)
+          .and_then(move |file_digest| {
+            let mut directory = bazel_protos::remote_execution::Directory::new();
+            directory.mut_files().push({
+              let mut file = bazel_protos::remote_execution::FileNode::new();
+              file.set_name(MISSING_BLOB_INPUT_FILE.to_owned());
+              file.set_digest((&file_digest).into());
+              file
+            });
+            store.record_directory(&directory, true)
+          })
+          .map(move |input_files| ExecuteProcessRequest {
+            argv: argv(&["/bin/cat", MISSING_BLOB_INPUT_FILE]),
+            input_files,
+            ..request
+          })
+          .to_boxed()
+      }
+      Check::Cancellation => future::ok(ExecuteProcessRequest {
+        argv: argv(&["/bin/sleep", "60"]),
+        timeout: Duration::from_secs(120),
+        ..request
+      })
+      .to_boxed(),
+      Check::Timeout => future::ok(ExecuteProcessRequest {
+        argv: argv(&["/bin/sleep", "60"]),
+        timeout: TIMEOUT_CHECK_TIMEOUT,
+        ..request
+      })
+      .to_boxed(),
+    }
+  }
+
+  ///
+  /// Runs this Check via the given CommandRunner, which should use the given Store.
+  ///
+  /// Never fails: any failure is recorded in the returned CheckReport.
+  ///
+  pub fn run(
+    self,
+    command_runner: remote::CommandRunner,
+    store: Store,
+    nonce: String,
+  ) -> BoxFuture<CheckReport, ()> {
+    let start = Instant::now();
+    self
+      .prepare(&store, &nonce)
+      .and_then(move |request| match self {
+        Check::Cancellation => {
+          let run = command_runner
+            .run(request.into(), WorkUnitStore::new())
+            .map(Some);
+          let delay = Delay::new(Instant::now() + CANCELLATION_DELAY)
+            .map(|_| None)
+            .map_err(|e| format!("Timer failed: {}", e));
+          run
+            .select(delay)
+            .map(|(maybe_result, _outstanding)| maybe_result)
+            .map_err(|(err, _outstanding)| err)
+            .map(|maybe_result| match maybe_result {
+              Some(result) => (
+                Err(format!(
+                  "Request completed within {:?}, so could not be cancelled.",
+                  CANCELLATION_DELAY
+                )),
+                describe_result(&result),
+              ),
+              None => (
+                Ok(()),
+                format!("Request was still running after {:?}.", CANCELLATION_DELAY),
+              ),
+            })
+            .to_boxed()
+        }
+        check => command_runner
+          .run(request.into(), WorkUnitStore::new())
+          .and_then(move |result| {
+            let response = describe_result(&result);
+            check
+              .verify(result, store, nonce)
+              .then(move |outcome| Ok((outcome, response)))
+          })
+          .to_boxed(),
+      })
+      .then(move |res| {
+        let (outcome, response) = match res {
+          Ok((outcome, response)) => (outcome, response),
+          Err(err) => (Err(err.clone()), format!("Error: {}", err)),
+        };
+        Ok::<_, ()>(CheckReport {
+          check: self,
+          outcome,
+          response,
+          elapsed: start.elapsed(),
+        })
+      })
+      .to_boxed()
+  }
+
+  fn verify(
+    self,
+    result: FallibleExecuteProcessResult,
+    store: Store,
+    nonce: String,
+  ) -> BoxFuture<(), String> {
+    match self {
+      Check::Timeout => {
+        if result.exit_code == -libc::SIGTERM {
+          future::ok(()).to_boxed()
+        } else {
+          future::err(format!(
+            "Expected the request to time out with exit code {}, but got exit code {}",
+            -libc::SIGTERM,
+            result.exit_code
+          ))
+          .to_boxed()
+        }
+      }
+      _ if result.exit_code != 0 => future::err(format!(
+        "Expected exit code 0, but got {}",
+        result.exit_code
+      ))
+      .to_boxed(),
+      Check::EchoRoundTrip | Check::MissingBlobRecovery => {
+        future::done(expect_stdout(&result, nonce.as_bytes())).to_boxed()
+      }
+      Check::LargeStdoutViaDigest => future::done(expect_stdout(
+        &result,
+        "x".repeat(LARGE_STDOUT_BYTES).as_bytes(),
+      ))
+      .to_boxed(),
+      Check::OutputDirectoryNesting => verify_nested_output(store, result.output_directory),
+      Check::Cancellation => unreachable!("Cancellation is not verified by result."),
+    }
+  }
+}
+
+///
+/// Runs the given Checks sequentially, and reports on each of them.
+///
+pub fn run_checks(
+  checks: Vec<Check>,
+  command_runner: remote::CommandRunner,
+  store: Store,
+  nonce: String,
+) -> BoxFuture<ConformanceReport, ()> {
+  futures::stream::iter_ok::<_, ()>(checks)
+    .and_then(move |check| check.run(command_runner.clone(), store.clone(), nonce.clone()))
+    .collect()
+    .map(|checks| ConformanceReport { checks })
+    .to_boxed()
+}
+
+#[derive(Clone, Debug)]
+pub struct CheckReport {
+  pub check: Check,
+  pub outcome: Result<(), String>,
+  // A description of what the server responded with, for diagnosing failures.
+  pub response: String,
+  pub elapsed: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct ConformanceReport {
+  pub checks: Vec<CheckReport>,
+}
+
+impl ConformanceReport {
+  pub fn passed(&self) -> bool {
+    self.checks.iter().all(|check| check.outcome.is_ok())
+  }
+}
+
+impl fmt::Display for ConformanceReport {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    for report in &self.checks {
+      match report.outcome {
+        Ok(()) => writeln!(f, "PASS {} ({:?})", report.check.name(), report.elapsed)?,
+        Err(ref err) => writeln!(
+          f,
+          "FAIL {} ({:?}): {}",
+          report.check.name(),
+          report.elapsed,
+          err
+        )?,
+      }
+      for line in report.response.lines() {
+        writeln!(f, "    {}", line)?;
+      }
+    }
+    let passed = self.checks.iter().filter(|c| c.outcome.is_ok()).count();
+    write!(f, "{} of {} checks passed", passed, self.checks.len())
+  }
+}
+
+fn argv(args: &[&str]) -> Vec<String> {
+  args.iter().map(|s| (*s).to_owned()).collect()
+}
+
+fn truncated(bytes: &Bytes) -> String {
+  if bytes.len() > MAX_ATTACHED_OUTPUT_BYTES {
+    format!(
+      "{}... ({} bytes total)",
+      String::from_utf8_lossy(&bytes[..MAX_ATTACHED_OUTPUT_BYTES]),
+      bytes.len()
+    )
+  } else {
+    String::from_utf8_lossy(bytes).into_owned()
+  }
+}
+
+fn truncated_output(output: &ProcessOutput) -> String {
+  match output {
+    ProcessOutput::Inline(bytes) | ProcessOutput::Unpersisted(bytes, _) => truncated(bytes),
+    ProcessOutput::Stored(_) => output.to_string(),
+  }
+}
+
+fn describe_result(result: &FallibleExecuteProcessResult) -> String {
+  let mut description = format!(
+    "exit_code: {}\nstdout: {}\nstderr: {}\noutput_directory: {:?}",
+    result.exit_code,
+    truncated_output(&result.stdout),
+    truncated_output(&result.stderr),
+    result.output_directory,
+  );
+  for (i, attempt) in result.execution_attempts.iter().enumerate() {
+    description += &format!("\nattempt {}: {:?}", i, attempt);
+  }
+  description
+}
+
+fn expect_stdout(result: &FallibleExecuteProcessResult, expected: &[u8]) -> Result<(), String> {
+  if result.stdout == *expected {
+    Ok(())
+  } else {
+    Err(format!(
+      "Expected {} bytes of stdout, but got {} different bytes",
+      expected.len(),
+      result.stdout.len()
+    ))
+  }
+}
+
+fn verify_nested_output(store: Store, output_directory: Digest) -> BoxFuture<(), String> {
+  let store2 = store.clone();
+  let store3 = store.clone();
+  load_child(
+    store,
+    output_directory,
+    NESTED_OUTPUT_DIRECTORY,
+    |directory| {
+      directory
+        .get_directories()
+        .iter()
+        .find(|node| node.get_name() == NESTED_OUTPUT_DIRECTORY)
+        .map(|node| node.get_digest().into())
+    },
+  )
+  .and_then(move |nested_digest| {
+    load_child(store2, nested_digest, NESTED_OUTPUT_FILE, |directory| {
+      directory
+        .get_files()
+        .iter()
+        .find(|node| node.get_name() == NESTED_OUTPUT_FILE)
+        .map(|node| node.get_digest().into())
+    })
+  })
+  .and_then(move |file_digest| {
+    store3
+      .load_file_bytes_with(file_digest, |bytes| bytes, WorkUnitStore::new())
+      .and_then(move |maybe_bytes| match maybe_bytes {
+        Some((ref bytes, _)) if bytes == NESTED_OUTPUT_CONTENT.as_bytes() => Ok(()),
+        Some((bytes, _)) => Err(format!(
+          "Nested output file had unexpected content: {}",
+          truncated(&bytes)
+        )),
+        None => Err(format!(
+          "Nested output file {:?} was not found",
+          file_digest
+        )),
+      })
+  })
+  .to_boxed()
+}
+
+///
+/// Loads the given Directory, and returns the Digest of the named child that `find` locates in it.
+///
+fn load_child<F>(
+  store: Store,
+  digest: Digest,
+  name: &'static str,
+  find: F,
+) -> BoxFuture<Digest, String>
+where
+  F: Fn(&bazel_protos::remote_execution::Directory) -> Option<Result<Digest, String>>
+    + Send
+    + 'static,
+{
+  store
+    .load_directory(digest, WorkUnitStore::new())
+    .and_then(move |maybe_directory| match maybe_directory {
+      Some((directory, _)) => find(&directory).unwrap_or_else(|| {
+        Err(format!(
+          "Output directory {:?} did not contain {}: {:?}",
+          digest, name, directory
+        ))
+      }),
+      None => Err(format!("Output directory {:?} was not found", digest)),
+    })
+    .to_boxed()
+}
+
+#[cfg(test)]
+mod tests {
+  use bazel_protos;
+  use futures::Future;
+  use mock;
+  use mock::execution_server::MockOperation;
+  use protobuf::{self, Message};
+  use std::time::Duration;
+  use store::Store;
+  use tempfile::TempDir;
+  use testutil::data::{TestData, TestDirectory};
+
+  use super::{
+    Check, CheckReport, LARGE_STDOUT_BYTES, NESTED_OUTPUT_CONTENT, NESTED_OUTPUT_DIRECTORY,
+  };
+  use crate::remote;
+  use crate::remote::tests::{
+    empty_request_metadata, make_delayed_incomplete_operation, make_incomplete_operation,
+    make_precondition_failure_operation, make_successful_operation,
+    missing_preconditionfailure_violation, StderrType, StdoutType,
+  };
+  use crate::Platform;
+
+  const NONCE: &str = "abc123";
+
+  #[test]
+  fn echo_round_trip() {
+    let report = run_check(Check::EchoRoundTrip, None, |_| {
+      vec![make_successful_operation(
+        "echo",
+        StdoutType::Raw(NONCE.to_owned()),
+        StderrType::Raw("".to_owned()),
+        0,
+      )]
+    });
+    assert_eq!(report.outcome, Ok(()));
+  }
+
+  #[test]
+  fn echo_round_trip_reports_wrong_stdout() {
+    let report = run_check(Check::EchoRoundTrip, None, |_| {
+      vec![make_successful_operation(
+        "echo",
+        StdoutType::Raw("something else".to_owned()),
+        StderrType::Raw("".to_owned()),
+        0,
+      )]
+    });
+    assert!(report.outcome.is_err());
+    assert!(report.response.contains("something else"));
+  }
+
+  #[test]
+  fn large_stdout_via_digest() {
+    let stdout = TestData::new(&"x".repeat(LARGE_STDOUT_BYTES));
+    let report = run_check(Check::LargeStdoutViaDigest, Some(&stdout), |_| {
+      vec![make_successful_operation(
+        "large",
+        StdoutType::Digest(stdout.digest()),
+        StderrType::Raw("".to_owned()),
+        0,
+      )]
+    });
+    assert_eq!(report.outcome, Ok(()));
+  }
+
+  #[test]
+  fn output_directory_nesting() {
+    assert_eq!(NESTED_OUTPUT_CONTENT, TestData::roland().string());
+    let report = run_check(Check::OutputDirectoryNesting, None, |_| {
+      let mut op = bazel_protos::operations::Operation::new();
+      op.set_name("nested".to_owned());
+      op.set_done(true);
+      op.set_response({
+        let mut response = bazel_protos::remote_execution::ExecuteResponse::new();
+        response.mut_result().mut_output_directories().push({
+          let mut dir = bazel_protos::remote_execution::OutputDirectory::new();
+          dir.set_path(NESTED_OUTPUT_DIRECTORY.to_owned());
+          dir.set_tree_digest((&TestDirectory::containing_roland().digest()).into());
+          dir
+        });
+        let mut any = protobuf::well_known_types::Any::new();
+        any.set_type_url(format!(
+          "type.googleapis.com/{}",
+          response.descriptor().full_name()
+        ));
+        any.set_value(response.write_to_bytes().unwrap());
+        any
+      });
+      vec![MockOperation::new(op)]
+    });
+    assert_eq!(report.outcome, Ok(()));
+  }
+
+  #[test]
+  fn missing_blob_recovery() {
+    let report = run_check(Check::MissingBlobRecovery, None, |request| {
+      vec![
+        make_precondition_failure_operation(vec![missing_preconditionfailure_violation(
+          &request.input_files,
+        )]),
+        make_successful_operation(
+          "cat",
+          StdoutType::Raw(NONCE.to_owned()),
+          StderrType::Raw("".to_owned()),
+          0,
+        ),
+      ]
+    });
+    assert_eq!(report.outcome, Ok(()));
+    assert!(report.response.contains("attempt 1"));
+  }
+
+  #[test]
+  fn cancellation() {
+    let report = run_check(Check::Cancellation, None, |_| {
+      vec![
+        make_incomplete_operation("sleep"),
+        make_delayed_incomplete_operation("sleep", Duration::from_secs(5)),
+      ]
+    });
+    assert_eq!(report.outcome, Ok(()));
+  }
+
+  #[test]
+  fn timeout() {
+    let report = run_check(Check::Timeout, None, |_| {
+      vec![
+        make_incomplete_operation("sleep"),
+        make_delayed_incomplete_operation("sleep", Duration::from_secs(3)),
+      ]
+    });
+    assert_eq!(report.outcome, Ok(()));
+  }
+
+  #[test]
+  fn check_names_round_trip() {
+    for check in Check::all() {
+      assert_eq!(Check::from_name(check.name()), Ok(check));
+    }
+    assert!(Check::from_name("not_a_check").is_err());
+  }
+
+  ///
+  /// Runs the given Check against a mock server which responds with the operations returned by
+  /// `responses` for the Check's request, backed by a StubCAS which additionally contains `file`.
+  ///
+  fn run_check<F>(check: Check, file: Option<&TestData>, responses: F) -> CheckReport
+  where
+    F: FnOnce(&crate::ExecuteProcessRequest) -> Vec<MockOperation>,
+  {
+    let mut cas_builder = mock::StubCAS::builder()
+      .file(&TestData::roland())
+      .directory(&TestDirectory::containing_roland());
+    if let Some(file) = file {
+      cas_builder = cas_builder.file(file);
+    }
+    let cas = cas_builder.build();
+
+    let executor = task_executor::Executor::new();
+    let store_dir = TempDir::new().unwrap();
+    let store = Store::with_remote(
+      executor.clone(),
+      store_dir.path(),
+      vec![cas.address()],
+      None,
+      None,
+      None,
+      None,
+      1,
+      10 * 1024 * 1024,
+      Duration::from_secs(1),
+      store::BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10)).unwrap(),
+      1,
+      1,
+    )
+    .expect("Failed to make store");
+
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let request = runtime
+      .block_on(check.prepare(&store, NONCE))
+      .expect("Failed to prepare check");
+
+    let mock_server = mock::execution_server::TestServer::new(
+      mock::execution_server::MockExecution::new(
+        "conformance".to_owned(),
+        remote::make_execute_request(&request, empty_request_metadata())
+          .unwrap()
+          .2,
+        responses(&request),
+      ),
+      None,
+    );
+
+    let command_runner = remote::CommandRunner::new(
+      &mock_server.address(),
+      empty_request_metadata(),
+      None,
+      None,
+      None,
+      store.clone(),
+      Platform::Linux,
+      executor,
+    )
+    .unwrap();
+
+    let report = runtime
+      .block_on(check.run(command_runner, store, NONCE.to_owned()))
+      .unwrap();
+    runtime.shutdown_on_idle().wait().unwrap();
+    report
+  }
+}
@@ -0,0 +1,487 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! A remote execution environment for tests: a StubCAS, a mock execution server, a local Store
+//! which uses the StubCAS as its remote, and a remote CommandRunner which uses all of them.
+//!
+//! This lives in process_execution rather than in testutil, because testutil cannot depend on the
+//! CommandRunner which it would construct. Other crates can use it by enabling the
+//! test_environment feature in their dev-dependencies.
+//!
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::time::Duration;
+
+use bazel_protos::remote_execution::ServerCapabilities;
+use bytes::Bytes;
+use futures::Future;
+use hashing::Fingerprint;
+use mock::execution_server::{ExpectedRpc, MockExecution, MockOperation, TestServer};
+use mock::StubCAS;
+use store::{BackoffConfig, Store};
+use tempfile::TempDir;
+use testutil::data::{TestData, TestDirectory};
+use workunit_store::WorkUnitStore;
+
+use crate::remote::{self, CommandRunner};
+use crate::{
+  CommandRunner as CommandRunnerTrait, EnvRecording, ExecuteProcessRequest,
+  ExecuteProcessRequestMetadata, FallibleExecuteProcessResult, MultiPlatformExecuteProcessRequest,
+  Platform,
+};
+
+// The responses of the mock execution server, which depend on the metadata of the runner, so are
+// only computed when the environment is built.
+enum Responses {
+  Operations {
+    name: String,
+    request: Box<ExecuteProcessRequest>,
+    operations: Vec<MockOperation>,
+  },
+  Scripted {
+    name: String,
+    expected_rpcs: Vec<ExpectedRpc>,
+  },
+}
+
+///
+/// Builds a TestRemoteEnvironment. By default: the StubCAS is empty, the execution server expects
+/// no requests, the local Store is kept in memory, and the CommandRunner has empty metadata and
+/// runs processes on Platform::Linux.
+///
+pub struct TestRemoteEnvironmentBuilder {
+  blobs: Vec<(Fingerprint, Bytes)>,
+  responses: Option<Responses>,
+  metadata: ExecuteProcessRequestMetadata,
+  platform: Platform,
+  lmdb_store: bool,
+  capabilities: Option<ServerCapabilities>,
+  configure: Option<Box<dyn FnOnce(CommandRunner) -> CommandRunner>>,
+}
+
+impl TestRemoteEnvironmentBuilder {
+  ///
+  /// Seeds the StubCAS with the given file.
+  ///
+  pub fn file(mut self, file: &TestData) -> TestRemoteEnvironmentBuilder {
+    self.blobs.push((file.fingerprint(), file.bytes()));
+    self
+  }
+
+  ///
+  /// Seeds the StubCAS with the given directory (but not its contents).
+  ///
+  pub fn directory(mut self, directory: &TestDirectory) -> TestRemoteEnvironmentBuilder {
+    self
+      .blobs
+      .push((directory.fingerprint(), directory.bytes()));
+    self
+  }
+
+  ///
+  /// Responds to the given request (with the operation name `name`) with the given operations, in
+  /// order, like `MockExecution::new`.
+  ///
+  pub fn operations(
+    mut self,
+    name: &str,
+    request: MultiPlatformExecuteProcessRequest,
+    operations: Vec<MockOperation>,
+  ) -> TestRemoteEnvironmentBuilder {
+    let request = request
+      .0
+      .into_iter()
+      .next()
+      .map(|(_, request)| (*request).clone())
+      .expect("A MultiPlatformExecuteProcessRequest must contain a request.");
+    self.responses = Some(Responses::Operations {
+      name: name.to_owned(),
+      request: Box::new(request),
+      operations,
+    });
+    self
+  }
+
+  ///
+  /// Expects exactly the given RPCs, in order, like `MockExecution::scripted`.
+  ///
+  pub fn scripted(
+    mut self,
+    name: &str,
+    expected_rpcs: Vec<ExpectedRpc>,
+  ) -> TestRemoteEnvironmentBuilder {
+    self.responses = Some(Responses::Scripted {
+      name: name.to_owned(),
+      expected_rpcs,
+    });
+    self
+  }
+
+  pub fn metadata(
+    mut self,
+    metadata: ExecuteProcessRequestMetadata,
+  ) -> TestRemoteEnvironmentBuilder {
+    self.metadata = metadata;
+    self
+  }
+
+  pub fn platform(mut self, platform: Platform) -> TestRemoteEnvironmentBuilder {
+    self.platform = platform;
+    self
+  }
+
+  ///
+  /// Keeps the local Store in LMDB, in a temporary directory, rather than in memory.
+  ///
+  pub fn lmdb_store(mut self) -> TestRemoteEnvironmentBuilder {
+    self.lmdb_store = true;
+    self
+  }
+
+  ///
+  /// Responds to GetCapabilities requests with the given capabilities, like
+  /// `MockExecution::with_capabilities`.
+  ///
+  pub fn capabilities(mut self, capabilities: ServerCapabilities) -> TestRemoteEnvironmentBuilder {
+    self.capabilities = Some(capabilities);
+    self
+  }
+
+  ///
+  /// Configures the CommandRunner once it has been constructed, e.g. with its `with_*` methods.
+  ///
+  pub fn configure<F: FnOnce(CommandRunner) -> CommandRunner + 'static>(
+    mut self,
+    configure: F,
+  ) -> TestRemoteEnvironmentBuilder {
+    self.configure = Some(Box::new(configure));
+    self
+  }
+
+  pub fn build(self) -> TestRemoteEnvironment {
+    let cas = self
+      .blobs
+      .into_iter()
+      .fold(StubCAS::builder(), |cas, (fingerprint, bytes)| {
+        cas.unverified_content(fingerprint, bytes)
+      })
+      .build();
+
+    let mock_execution = match self.responses {
+      Some(Responses::Operations {
+        name,
+        request,
+        operations,
+      }) => {
+        let (_, _, execute_request) = remote::make_execute_request(&request, self.metadata.clone())
+          .expect("Failed to make the ExecuteRequest to respond to");
+        MockExecution::new(name, execute_request, operations)
+      }
+      Some(Responses::Scripted {
+        name,
+        expected_rpcs,
+      }) => MockExecution::scripted(name, expected_rpcs),
+      None => MockExecution::scripted("unused".to_owned(), vec![]),
+    };
+    let mock_execution = match self.capabilities {
+      Some(capabilities) => mock_execution.with_capabilities(capabilities),
+      None => mock_execution,
+    };
+    let execution_server = TestServer::new(mock_execution, None);
+
+    let executor = task_executor::Executor::new();
+    let backoff_config =
+      BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10)).unwrap();
+    let store_dir = if self.lmdb_store {
+      Some(TempDir::new().unwrap())
+    } else {
+      None
+    };
+    let store = match store_dir {
+      Some(ref store_dir) => Store::with_remote(
+        executor.clone(),
+        store_dir.path(),
+        vec![cas.address()],
+        None,
+        None,
+        None,
+        None,
+        1,
+        10 * 1024 * 1024,
+        Duration::from_secs(1),
+        backoff_config,
+        1,
+        1,
+      ),
+      None => Store::in_memory_with_remote(
+        vec![cas.address()],
+        None,
+        None,
+        None,
+        None,
+        1,
+        10 * 1024 * 1024,
+        Duration::from_secs(1),
+        backoff_config,
+        1,
+        1,
+      ),
+    }
+    .expect("Failed to make store");
+
+    let command_runner = CommandRunner::new(
+      &execution_server.address(),
+      self.metadata,
+      None,
+      None,
+      None,
+      store.clone(),
+      self.platform,
+      executor,
+    )
+    .expect("Failed to make command runner");
+    let command_runner = match self.configure {
+      Some(configure) => configure(command_runner),
+      None => command_runner,
+    };
+
+    TestRemoteEnvironment {
+      runtime: Some(tokio::runtime::Runtime::new().unwrap()),
+      command_runner: Some(command_runner),
+      store: Some(store),
+      execution_server: Some(execution_server),
+      cas: Some(cas),
+      store_dir,
+    }
+  }
+}
+
+///
+/// A StubCAS, a mock execution server, a local Store and a CommandRunner. When it is dropped, the
+/// runner is torn down first, then the Store, the servers, and finally the Store's directory. Like
+/// the TestServer, dropping it fails the test if the execution server expected more requests.
+///
+pub struct TestRemoteEnvironment {
+  // These are only None while the environment is being dropped.
+  runtime: Option<tokio::runtime::Runtime>,
+  command_runner: Option<CommandRunner>,
+  store: Option<Store>,
+  execution_server: Option<TestServer>,
+  cas: Option<StubCAS>,
+  // Set if the Store is kept in LMDB.
+  store_dir: Option<TempDir>,
+}
+
+impl TestRemoteEnvironment {
+  pub fn builder() -> TestRemoteEnvironmentBuilder {
+    TestRemoteEnvironmentBuilder {
+      blobs: vec![],
+      responses: None,
+      metadata: ExecuteProcessRequestMetadata {
+        instance_name: None,
+        cache_key_gen_version: None,
+        cache_scopes: BTreeMap::new(),
+        platform_properties: vec![],
+        timeout_excludes_queue: false,
+        argv_error_patterns: vec![],
+        argv_warning_bytes: None,
+        allow_lossy_env: false,
+        canonical_form_version: 0,
+        environments: BTreeMap::new(),
+        record_env_in_report: EnvRecording::Off,
+      },
+      platform: Platform::Linux,
+      lmdb_store: false,
+      capabilities: None,
+      configure: None,
+    }
+  }
+
+  pub fn command_runner(&self) -> &CommandRunner {
+    self.command_runner.as_ref().unwrap()
+  }
+
+  pub fn store(&self) -> &Store {
+    self.store.as_ref().unwrap()
+  }
+
+  pub fn cas(&self) -> &StubCAS {
+    self.cas.as_ref().unwrap()
+  }
+
+  pub fn execution_server(&self) -> &TestServer {
+    self.execution_server.as_ref().unwrap()
+  }
+
+  ///
+  /// The directory of the local Store, if it is kept in LMDB.
+  ///
+  pub fn store_dir(&self) -> Option<&Path> {
+    self.store_dir.as_ref().map(TempDir::path)
+  }
+
+  ///
+  /// Runs the given request with the CommandRunner, blocking until it completes.
+  ///
+  pub fn run(
+    &mut self,
+    request: MultiPlatformExecuteProcessRequest,
+  ) -> Result<FallibleExecuteProcessResult, String> {
+    let run = self.command_runner().run(request, WorkUnitStore::new());
+    self.block_on(run)
+  }
+
+  ///
+  /// Runs the given future (e.g. of the Store) on the environment's runtime, blocking until it
+  /// completes.
+  ///
+  pub fn block_on<F>(&mut self, future: F) -> Result<F::Item, F::Error>
+  where
+    F: Future + Send + 'static,
+    F::Item: Send + 'static,
+    F::Error: Send + 'static,
+  {
+    self.runtime.as_mut().unwrap().block_on(future)
+  }
+
+  ///
+  /// The types of the messages received by the execution server, in order (e.g.
+  /// "ExecuteRequest" and "GetOperationRequest").
+  ///
+  pub fn received_message_types(&self) -> Vec<String> {
+    self
+      .execution_server()
+      .mock_responder
+      .received_messages
+      .lock()
+      .iter()
+      .map(|received| received.message_type.clone())
+      .collect()
+  }
+
+  ///
+  /// The ExecuteRequests received by the execution server, in order.
+  ///
+  pub fn received_execute_requests(&self) -> Vec<bazel_protos::remote_execution::ExecuteRequest> {
+    self
+      .execution_server()
+      .mock_responder
+      .received_messages
+      .lock()
+      .iter()
+      .filter_map(|received| {
+        received
+          .message
+          .as_any()
+          .downcast_ref::<bazel_protos::remote_execution::ExecuteRequest>()
+          .cloned()
+      })
+      .collect()
+  }
+
+  ///
+  /// The names of the operations which the execution server was asked to cancel, in order.
+  ///
+  pub fn cancelled_operations(&self) -> Vec<String> {
+    self
+      .execution_server()
+      .mock_responder
+      .cancelation_requests
+      .lock()
+      .iter()
+      .map(|request| request.get_name().to_owned())
+      .collect()
+  }
+
+  ///
+  /// The blobs in the StubCAS, including those it was seeded with.
+  ///
+  pub fn cas_blobs(&self) -> HashMap<Fingerprint, Bytes> {
+    self.cas().blobs.lock().clone()
+  }
+
+  ///
+  /// The blobs written to the StubCAS, in the order in which the writes completed.
+  ///
+  pub fn uploaded_blobs(&self) -> Vec<Fingerprint> {
+    self.cas().write_log.lock().clone()
+  }
+}
+
+impl Drop for TestRemoteEnvironment {
+  fn drop(&mut self) {
+    // The runtime is shut down first, so that no work of the runner outlives the servers.
+    if let Some(runtime) = self.runtime.take() {
+      let _ = runtime.shutdown_now().wait();
+    }
+    self.command_runner.take();
+    self.store.take();
+    self.execution_server.take();
+    self.cas.take();
+    self.store_dir.take();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use mock::execution_server::{ExpectedRpc, MockOperation};
+  use testutil::data::{TestData, TestDirectory};
+  use workunit_store::WorkUnitStore;
+
+  use super::TestRemoteEnvironment;
+
+  #[test]
+  fn seeded_blobs_are_loaded_from_the_cas() {
+    let roland = TestData::roland();
+    let directory = TestDirectory::containing_roland();
+    let mut env = TestRemoteEnvironment::builder()
+      .file(&roland)
+      .directory(&directory)
+      .build();
+
+    let blobs = env.cas_blobs();
+    assert_eq!(blobs.get(&roland.fingerprint()), Some(&roland.bytes()));
+    assert_eq!(
+      blobs.get(&directory.fingerprint()),
+      Some(&directory.bytes())
+    );
+
+    let load =
+      env
+        .store()
+        .load_file_bytes_with(roland.digest(), |bytes| bytes, WorkUnitStore::new());
+    let loaded = env.block_on(load).unwrap().map(|(bytes, _)| bytes);
+    assert_eq!(loaded, Some(roland.bytes()));
+    assert_eq!(env.cas().read_request_count(), 1);
+    assert_eq!(env.uploaded_blobs(), vec![]);
+  }
+
+  #[test]
+  fn dropping_removes_the_store_directory() {
+    let env = TestRemoteEnvironment::builder().lmdb_store().build();
+    let store_dir = env.store_dir().unwrap().to_owned();
+    assert!(store_dir.exists());
+    drop(env);
+    assert!(!store_dir.exists());
+
+    let env = TestRemoteEnvironment::builder().build();
+    assert_eq!(env.store_dir(), None);
+  }
+
+  #[test]
+  #[should_panic(expected = "Expected 1 more requests")]
+  fn dropping_fails_if_the_server_expected_more_requests() {
+    let env = TestRemoteEnvironment::builder()
+      .scripted(
+        "gimme-foo",
+        vec![ExpectedRpc::get_operation(
+          "gimme-foo",
+          MockOperation::incomplete("gimme-foo"),
+        )],
+      )
+      .build();
+    drop(env);
+  }
+}
@@ -0,0 +1,548 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! An accumulator for a report of the remote executions of a run, which can be written out as JSON
+//! for offline analysis (e.g. by CI), without scraping logs.
+//!
+//! CommandRunners append a record to a shared RemoteExecutionReport for each request that they
+//! complete, and a note for each way in which they degraded (e.g. clamping a timeout). The records
+//! of the session clones of a CommandRunner are tagged with their build ids, and are also totalled
+//! per build.
+//!
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bazel_protos::remote_execution::Command;
+use hashing::Digest;
+use serde_derive::Serialize;
+
+use crate::input_tree_stats::InputTreeStats;
+use crate::{EnvRecording, ExecutionStats, Platform, ProcessResultSource};
+
+// Replaces the values of recorded env vars which match a redaction pattern.
+pub const REDACTED_ENV_VALUE: &str = "<redacted>";
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ActionRecord {
+  pub description: String,
+  pub action_digest: Digest,
+  // None if the request failed before a result was produced.
+  pub source: Option<String>,
+  pub exit_code: Option<i32>,
+  // The platform which the process ran on, named as the python Platform enum names it.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub platform: Option<String>,
+  pub error: Option<String>,
+  pub duration_ms: u64,
+  pub attempts: Vec<AttemptRecord>,
+  // Set for the records of a session: see `remote::CommandRunner::with_session_metadata`.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub build_id: Option<String>,
+  // The provenance of the request: see `ExecuteProcessRequest::provenance`.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub provenance: Option<String>,
+  // The execution environment of the request: see `ExecuteProcessRequest::environment`.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub environment: Option<String>,
+  // The size of the input root of the request, if it could be computed.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub input_tree: Option<InputTreeStats>,
+  // The env of the Command, if the runner records it: see `EnvRecording`.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub env: Option<Vec<EnvVarRecord>>,
+}
+
+impl ActionRecord {
+  pub fn completed(
+    description: String,
+    action_digest: Digest,
+    source: ProcessResultSource,
+    exit_code: i32,
+    platform: Platform,
+    duration: Duration,
+    attempts: &[ExecutionStats],
+  ) -> ActionRecord {
+    ActionRecord {
+      description,
+      action_digest,
+      source: Some(format!("{:?}", source)),
+      exit_code: Some(exit_code),
+      platform: Some(String::from(platform)),
+      error: None,
+      duration_ms: duration.as_millis() as u64,
+      attempts: attempts.iter().map(AttemptRecord::from).collect(),
+      build_id: None,
+      provenance: None,
+      environment: None,
+      input_tree: None,
+      env: None,
+    }
+  }
+
+  pub fn failed(
+    description: String,
+    action_digest: Digest,
+    error: String,
+    duration: Duration,
+  ) -> ActionRecord {
+    ActionRecord {
+      description,
+      action_digest,
+      source: None,
+      exit_code: None,
+      platform: None,
+      error: Some(error),
+      duration_ms: duration.as_millis() as u64,
+      attempts: vec![],
+      build_id: None,
+      provenance: None,
+      environment: None,
+      input_tree: None,
+      env: None,
+    }
+  }
+
+  pub fn with_build_id(mut self, build_id: Option<String>) -> ActionRecord {
+    self.build_id = build_id;
+    self
+  }
+
+  pub fn with_provenance(mut self, provenance: Option<String>) -> ActionRecord {
+    self.provenance = provenance;
+    self
+  }
+
+  pub fn with_environment(mut self, environment: Option<String>) -> ActionRecord {
+    self.environment = environment;
+    self
+  }
+
+  pub fn with_input_tree(mut self, input_tree: Option<InputTreeStats>) -> ActionRecord {
+    self.input_tree = input_tree;
+    self
+  }
+
+  pub fn with_env(mut self, env: Option<Vec<EnvVarRecord>>) -> ActionRecord {
+    self.env = env;
+    self
+  }
+
+  ///
+  /// Whether this record is always retained: requests which errored or exited non-zero.
+  ///
+  fn is_failure(&self) -> bool {
+    self.error.is_some() || self.exit_code != Some(0)
+  }
+}
+
+///
+/// An env var of a Command, as it is recorded in an ActionRecord.
+///
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct EnvVarRecord {
+  pub name: String,
+  // Unset if only names are recorded.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub value: Option<String>,
+}
+
+impl EnvVarRecord {
+  ///
+  /// The env vars of the Command, as the given EnvRecording records them: None if it is Off.
+  ///
+  pub fn record_all(recording: &EnvRecording, command: &Command) -> Option<Vec<EnvVarRecord>> {
+    let redact_patterns = match recording {
+      EnvRecording::Off => return None,
+      EnvRecording::NamesOnly => None,
+      EnvRecording::Full { redact_patterns } => Some(redact_patterns),
+    };
+    let records = command
+      .get_environment_variables()
+      .iter()
+      .map(|env| {
+        let value = redact_patterns.map(|redact_patterns| {
+          let redacted = redact_patterns
+            .iter()
+            .any(|pattern| pattern.is_match(env.get_value()));
+          if redacted {
+            REDACTED_ENV_VALUE.to_owned()
+          } else {
+            env.get_value().to_owned()
+          }
+        });
+        EnvVarRecord {
+          name: env.get_name().to_owned(),
+          value,
+        }
+      })
+      .collect();
+    Some(records)
+  }
+}
+
+///
+/// The ExecutionStats of one attempt of an action, with durations in milliseconds.
+///
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AttemptRecord {
+  pub uploaded_bytes: usize,
+  pub uploaded_file_count: usize,
+  pub upload_ms: u64,
+  pub upload_queue_ms: Option<u64>,
+  pub action_bytes: Option<usize>,
+  pub command_bytes: Option<usize>,
+  pub cancellation_wait_ms: Option<u64>,
+  pub operation_wait_ms: Option<u64>,
+  pub polling_ms: Option<u64>,
+  pub download_ms: Option<u64>,
+  pub remote_queue_ms: Option<u64>,
+  pub remote_input_fetch_ms: Option<u64>,
+  pub remote_execution_ms: Option<u64>,
+  pub remote_output_store_ms: Option<u64>,
+  pub remote_worker_overhead_ms: Option<u64>,
+  pub was_cache_hit: bool,
+}
+
+impl<'a> From<&'a ExecutionStats> for AttemptRecord {
+  fn from(stats: &'a ExecutionStats) -> AttemptRecord {
+    let millis = |duration: Option<Duration>| duration.map(|d| d.as_millis() as u64);
+    AttemptRecord {
+      uploaded_bytes: stats.uploaded_bytes,
+      uploaded_file_count: stats.uploaded_file_count,
+      upload_ms: stats.upload.as_millis() as u64,
+      upload_queue_ms: millis(stats.upload_queue),
+      action_bytes: stats.action_bytes,
+      command_bytes: stats.command_bytes,
+      cancellation_wait_ms: millis(stats.cancellation_wait),
+      operation_wait_ms: millis(stats.operation_wait),
+      polling_ms: millis(stats.polling),
+      download_ms: millis(stats.download),
+      remote_queue_ms: millis(stats.remote_queue),
+      remote_input_fetch_ms: millis(stats.remote_input_fetch),
+      remote_execution_ms: millis(stats.remote_execution),
+      remote_output_store_ms: millis(stats.remote_output_store),
+      remote_worker_overhead_ms: millis(stats.remote_worker_overhead),
+      was_cache_hit: stats.was_cache_hit,
+    }
+  }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ReportTotals {
+  pub requests: usize,
+  pub failures: usize,
+  // The number of successful ActionRecords which were dropped to stay under the cap.
+  pub dropped_action_records: usize,
+  // The most recent snapshot of CommandRunner::metrics.
+  pub metrics: BTreeMap<String, i64>,
+}
+
+///
+/// The requests and failures of one build, which are also counted in the ReportTotals.
+///
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct BuildTotals {
+  pub requests: usize,
+  pub failures: usize,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+struct ReportState {
+  totals: ReportTotals,
+  // By build id.
+  builds: BTreeMap<String, BuildTotals>,
+  actions: Vec<ActionRecord>,
+  degradations: Vec<String>,
+}
+
+///
+/// Cheap to clone: all clones append to the same report.
+///
+#[derive(Clone)]
+pub struct RemoteExecutionReport {
+  max_action_records: usize,
+  state: Arc<Mutex<ReportState>>,
+}
+
+impl RemoteExecutionReport {
+  ///
+  /// Once more than max_action_records actions have been recorded, the fastest successful actions
+  /// are dropped, so that the report holds the slowest successful actions plus all failures.
+  ///
+  pub fn new(max_action_records: usize) -> RemoteExecutionReport {
+    RemoteExecutionReport {
+      max_action_records,
+      state: Arc::new(Mutex::new(ReportState::default())),
+    }
+  }
+
+  pub fn record_action(&self, record: ActionRecord) {
+    let mut state = self.state.lock().unwrap();
+    state.totals.requests += 1;
+    if record.is_failure() {
+      state.totals.failures += 1;
+    }
+    if let Some(ref build_id) = record.build_id {
+      let build = state
+        .builds
+        .entry(build_id.clone())
+        .or_insert_with(BuildTotals::default);
+      build.requests += 1;
+      if record.is_failure() {
+        build.failures += 1;
+      }
+    }
+    state.actions.push(record);
+
+    if state.actions.len() > self.max_action_records {
+      let fastest_success = state
+        .actions
+        .iter()
+        .enumerate()
+        .filter(|(_, record)| !record.is_failure())
+        .min_by_key(|(_, record)| record.duration_ms)
+        .map(|(index, _)| index);
+      if let Some(index) = fastest_success {
+        state.actions.remove(index);
+        state.totals.dropped_action_records += 1;
+      }
+    }
+  }
+
+  pub fn build_totals(&self, build_id: &str) -> Option<BuildTotals> {
+    self.state.lock().unwrap().builds.get(build_id).cloned()
+  }
+
+  pub fn record_degradation(&self, message: String) {
+    self.state.lock().unwrap().degradations.push(message);
+  }
+
+  pub fn record_metrics(&self, metrics: HashMap<&'static str, i64>) {
+    self.state.lock().unwrap().totals.metrics = metrics
+      .into_iter()
+      .map(|(name, value)| (name.to_owned(), value))
+      .collect();
+  }
+
+  pub fn to_json(&self) -> Result<String, String> {
+    serde_json::to_string_pretty(&*self.state.lock().unwrap())
+      .map_err(|e| format!("Error serializing remote execution report: {}", e))
+  }
+
+  ///
+  /// Writes the report as JSON to a temporary file alongside the given path, and then renames it
+  /// into place, so that readers never observe a partially written report.
+  ///
+  pub fn write_to(&self, path: &Path) -> Result<(), String> {
+    let json = self.to_json()?;
+    write_replacing(path, json.as_bytes(), "remote execution report")
+  }
+}
+
+///
+/// Writes the contents to a temporary file alongside the given path, and then renames it into
+/// place. The `what` names the file in errors.
+///
+pub(crate) fn write_replacing(path: &Path, contents: &[u8], what: &str) -> Result<(), String> {
+  let file_name = path
+    .file_name()
+    .ok_or_else(|| format!("Path {} for the {} has no file name", path.display(), what))?;
+  let mut temp_name = file_name.to_owned();
+  temp_name.push(".tmp");
+  let temp_path = path.with_file_name(temp_name);
+
+  File::create(&temp_path)
+    .and_then(|mut file| {
+      file.write_all(contents)?;
+      file.sync_all()
+    })
+    .and_then(|()| fs::rename(&temp_path, path))
+    .map_err(|e| format!("Error writing {} to {}: {}", what, path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use hashing::EMPTY_DIGEST;
+  use maplit::hashmap;
+  use tempfile::TempDir;
+
+  use super::{ActionRecord, BuildTotals, EnvVarRecord, RemoteExecutionReport, REDACTED_ENV_VALUE};
+  use crate::{EnvRecording, Platform, ProcessResultSource};
+
+  #[test]
+  fn keeps_slowest_successes_and_all_failures() {
+    let report = RemoteExecutionReport::new(2);
+    report.record_action(success("fast", 10));
+    report.record_action(failure("failed", 1));
+    report.record_action(success("slow", 50));
+    report.record_action(success("medium", 20));
+
+    let state = report.state.lock().unwrap();
+    let descriptions = state
+      .actions
+      .iter()
+      .map(|record| record.description.as_str())
+      .collect::<Vec<_>>();
+    assert_eq!(descriptions, vec!["failed", "slow"]);
+    assert_eq!(state.totals.requests, 4);
+    assert_eq!(state.totals.failures, 1);
+    assert_eq!(state.totals.dropped_action_records, 2);
+  }
+
+  #[test]
+  fn failures_are_kept_even_over_the_cap() {
+    let report = RemoteExecutionReport::new(1);
+    report.record_action(failure("one", 1));
+    report.record_action(failure("two", 1));
+
+    assert_eq!(report.state.lock().unwrap().actions.len(), 2);
+  }
+
+  #[test]
+  fn builds_are_totalled_separately() {
+    let report = RemoteExecutionReport::new(10);
+    report.record_action(success("one", 10).with_build_id(Some("a".to_owned())));
+    report.record_action(failure("two", 10).with_build_id(Some("a".to_owned())));
+    report.record_action(success("three", 10).with_build_id(Some("b".to_owned())));
+    report.record_action(success("four", 10));
+
+    assert_eq!(
+      report.build_totals("a"),
+      Some(BuildTotals {
+        requests: 2,
+        failures: 1,
+      })
+    );
+    assert_eq!(
+      report.build_totals("b"),
+      Some(BuildTotals {
+        requests: 1,
+        failures: 0,
+      })
+    );
+    assert_eq!(report.build_totals("c"), None);
+    assert_eq!(report.state.lock().unwrap().totals.requests, 4);
+  }
+
+  #[test]
+  fn write_to_replaces_existing_report() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("report.json");
+    std::fs::write(&path, "stale").unwrap();
+
+    let report = RemoteExecutionReport::new(10);
+    report.record_action(success("echo", 10));
+    report.record_action(
+      failure("false", 10)
+        .with_provenance(Some("lint() for goal `lint`".to_owned()))
+        .with_environment(Some("linux-default".to_owned())),
+    );
+    report.record_degradation("Clamped a timeout".to_owned());
+    report.record_metrics(hashmap! {"executions" => 1});
+    report.write_to(&path).unwrap();
+
+    let json: serde_json::Value =
+      serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(json["totals"]["requests"], 2);
+    assert_eq!(json["totals"]["metrics"]["executions"], 1);
+    assert_eq!(json["actions"][0]["description"], "echo");
+    assert_eq!(json["actions"][0]["source"], "RanRemotely");
+    assert_eq!(json["actions"][0]["platform"], "linux");
+    assert!(json["actions"][1].get("platform").is_none());
+    // Only records with a provenance include it.
+    assert!(json["actions"][0].get("provenance").is_none());
+    assert_eq!(json["actions"][1]["provenance"], "lint() for goal `lint`");
+    assert!(json["actions"][0].get("environment").is_none());
+    assert_eq!(json["actions"][1]["environment"], "linux-default");
+    assert_eq!(json["degradations"][0], "Clamped a timeout");
+    // Only the report itself is left behind.
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+  }
+
+  fn command_with_env(env: &[(&str, &str)]) -> bazel_protos::remote_execution::Command {
+    let mut command = bazel_protos::remote_execution::Command::new();
+    for (name, value) in env {
+      let mut env = bazel_protos::remote_execution::Command_EnvironmentVariable::new();
+      env.set_name((*name).to_owned());
+      env.set_value((*value).to_owned());
+      command.mut_environment_variables().push(env);
+    }
+    command
+  }
+
+  fn env_var(name: &str, value: Option<&str>) -> EnvVarRecord {
+    EnvVarRecord {
+      name: name.to_owned(),
+      value: value.map(str::to_owned),
+    }
+  }
+
+  #[test]
+  fn env_records_only_names_unless_full() {
+    let command = command_with_env(&[("PATH", "/bin"), ("PANTS_CACHE_KEY_GEN_VERSION", "7")]);
+    assert_eq!(EnvVarRecord::record_all(&EnvRecording::Off, &command), None);
+    assert_eq!(
+      EnvVarRecord::record_all(&EnvRecording::NamesOnly, &command),
+      Some(vec![
+        env_var("PATH", None),
+        env_var("PANTS_CACHE_KEY_GEN_VERSION", None)
+      ])
+    );
+    let json = serde_json::to_value(
+      success("echo", 1).with_env(EnvVarRecord::record_all(&EnvRecording::NamesOnly, &command)),
+    )
+    .unwrap();
+    assert_eq!(json["env"][0], serde_json::json!({"name": "PATH"}));
+  }
+
+  #[test]
+  fn env_values_which_match_a_pattern_are_redacted() {
+    let command = command_with_env(&[("PATH", "/bin"), ("TOKEN", "secret-abc123")]);
+    let full = |redact_patterns: &[&str]| {
+      let redact_patterns = redact_patterns
+        .iter()
+        .map(|p| (*p).to_owned())
+        .collect::<Vec<_>>();
+      EnvRecording::parse("full", &redact_patterns)
+    };
+    assert_eq!(
+      EnvVarRecord::record_all(&full(&["^secret-"]).unwrap(), &command),
+      Some(vec![
+        env_var("PATH", Some("/bin")),
+        env_var("TOKEN", Some(REDACTED_ENV_VALUE))
+      ])
+    );
+    // An invalid pattern might have been meant to match any of the values.
+    let err = full(&["(unclosed"]).unwrap_err();
+    assert!(err.contains("\"(unclosed\""), "{}", err);
+  }
+
+  fn success(description: &str, duration_ms: u64) -> ActionRecord {
+    ActionRecord::completed(
+      description.to_owned(),
+      EMPTY_DIGEST,
+      ProcessResultSource::RanRemotely,
+      0,
+      Platform::Linux,
+      Duration::from_millis(duration_ms),
+      &[],
+    )
+  }
+
+  fn failure(description: &str, duration_ms: u64) -> ActionRecord {
+    ActionRecord::failed(
+      description.to_owned(),
+      EMPTY_DIGEST,
+      "Boom".to_owned(),
+      Duration::from_millis(duration_ms),
+    )
+  }
+}
@@ -0,0 +1,255 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! A budget for the retries which CommandRunners make, which is shared across a whole build.
+//!
+//! Per-request retry limits do not help when the remote service is partly broken: if thousands of
+//! requests each retry a few times, the retries multiply the load on an already unhealthy service.
+//! Instead, each retry takes a token from a bucket for its RetryCategory, which is refilled at a
+//! fixed rate, and once a bucket is empty, failures of that category are not retried until it has
+//! refilled.
+//!
+//! Each bucket is a single atomic: rather than the number of tokens, it holds the time at which
+//! the bucket will be full again (the "theoretical arrival time" of the generic cell rate
+//! algorithm), from which the number of tokens follows.
+//!
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::metrics;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RetryCategory {
+  // Re-executing a request after uploading the digests which the server reported missing.
+  MissingDigests,
+  // Polling an operation again after a poll was cancelled (e.g. by a proxy).
+  CancelledPoll,
+  // Fetching an output blob of a result again after a fetch of it failed.
+  OutputFetch,
+}
+
+impl RetryCategory {
+  const ALL: [RetryCategory; 3] = [
+    RetryCategory::MissingDigests,
+    RetryCategory::CancelledPoll,
+    RetryCategory::OutputFetch,
+  ];
+
+  fn index(self) -> usize {
+    match self {
+      RetryCategory::MissingDigests => 0,
+      RetryCategory::CancelledPoll => 1,
+      RetryCategory::OutputFetch => 2,
+    }
+  }
+
+  // The metric of the tokens which remain in this category's bucket.
+  fn remaining_metric(self) -> &'static str {
+    match self {
+      RetryCategory::MissingDigests => metrics::REMOTE_RETRY_BUDGET_REMAINING_MISSING_DIGESTS,
+      RetryCategory::CancelledPoll => metrics::REMOTE_RETRY_BUDGET_REMAINING_CANCELLED_POLL,
+      RetryCategory::OutputFetch => metrics::REMOTE_RETRY_BUDGET_REMAINING_OUTPUT_FETCH,
+    }
+  }
+
+  // The metric of the retries of this category which were not made because its bucket was empty.
+  fn exhausted_metric(self) -> &'static str {
+    match self {
+      RetryCategory::MissingDigests => metrics::REMOTE_RETRY_BUDGET_EXHAUSTED_MISSING_DIGESTS,
+      RetryCategory::CancelledPoll => metrics::REMOTE_RETRY_BUDGET_EXHAUSTED_CANCELLED_POLL,
+      RetryCategory::OutputFetch => metrics::REMOTE_RETRY_BUDGET_EXHAUSTED_OUTPUT_FETCH,
+    }
+  }
+}
+
+impl fmt::Display for RetryCategory {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let name = match self {
+      RetryCategory::MissingDigests => "re-executions after missing digests",
+      RetryCategory::CancelledPoll => "polls after cancellations",
+      RetryCategory::OutputFetch => "fetches of outputs",
+    };
+    write!(f, "{}", name)
+  }
+}
+
+#[derive(Default)]
+struct Bucket {
+  // Microseconds since the epoch of the budget at which the bucket will be full again: the bucket
+  // is full whenever this is in the past.
+  full_at: AtomicU64,
+  // Retries which were not made because the bucket was empty.
+  exhausted: AtomicU64,
+}
+
+///
+/// Cheap to clone: all clones share their buckets, so that a budget which is given to each of a
+/// build's CommandRunners bounds the retries of the whole build.
+///
+#[derive(Clone)]
+pub struct RetryBudget {
+  capacity: u64,
+  refill_interval_micros: u64,
+  epoch: Instant,
+  buckets: Arc<[Bucket; 3]>,
+}
+
+impl RetryBudget {
+  ///
+  /// Each category's bucket holds up to `capacity` tokens, and starts full. One token is added to
+  /// it per `refill_interval`: a zero interval makes the budget unlimited.
+  ///
+  pub fn new(capacity: u64, refill_interval: Duration) -> RetryBudget {
+    RetryBudget {
+      capacity,
+      refill_interval_micros: refill_interval.as_micros() as u64,
+      epoch: Instant::now(),
+      buckets: Arc::new([Bucket::default(), Bucket::default(), Bucket::default()]),
+    }
+  }
+
+  fn now_micros(&self) -> u64 {
+    self.epoch.elapsed().as_micros() as u64
+  }
+
+  ///
+  /// Takes a token for a retry of the given category, returning whether there was one: if not,
+  /// the failure should not be retried.
+  ///
+  pub fn try_acquire(&self, category: RetryCategory) -> bool {
+    let bucket = &self.buckets[category.index()];
+    let now = self.now_micros();
+    let limit = now.saturating_add(self.capacity.saturating_mul(self.refill_interval_micros));
+    let mut full_at = bucket.full_at.load(Ordering::SeqCst);
+    loop {
+      let next_full_at = full_at.max(now).saturating_add(self.refill_interval_micros);
+      if next_full_at > limit {
+        bucket.exhausted.fetch_add(1, Ordering::SeqCst);
+        return false;
+      }
+      match bucket.full_at.compare_exchange(
+        full_at,
+        next_full_at,
+        Ordering::SeqCst,
+        Ordering::SeqCst,
+      ) {
+        Ok(_) => return true,
+        Err(actual) => full_at = actual,
+      }
+    }
+  }
+
+  ///
+  /// The tokens which remain in the bucket of the given category.
+  ///
+  pub fn remaining(&self, category: RetryCategory) -> u64 {
+    if self.refill_interval_micros == 0 {
+      return self.capacity;
+    }
+    let now = self.now_micros();
+    let full_at = self.buckets[category.index()]
+      .full_at
+      .load(Ordering::SeqCst);
+    let missing =
+      (full_at.saturating_sub(now) + self.refill_interval_micros - 1) / self.refill_interval_micros;
+    self.capacity.saturating_sub(missing)
+  }
+
+  ///
+  /// The number of retries of the given category which were not made, because its bucket was
+  /// empty.
+  ///
+  pub fn exhausted(&self, category: RetryCategory) -> u64 {
+    self.buckets[category.index()]
+      .exhausted
+      .load(Ordering::SeqCst)
+  }
+
+  ///
+  /// Adds the remaining tokens and exhausted retries of each category to a metrics snapshot.
+  ///
+  pub fn record_metrics(&self, snapshot: &mut HashMap<&'static str, i64>) {
+    for category in &RetryCategory::ALL {
+      snapshot.insert(
+        category.remaining_metric(),
+        self.remaining(*category) as i64,
+      );
+      snapshot.insert(
+        category.exhausted_metric(),
+        self.exhausted(*category) as i64,
+      );
+    }
+  }
+}
+
+///
+/// The error for a failure which was not retried because the budget for its category was
+/// exhausted.
+///
+pub fn exhausted_error(category: RetryCategory, error: &str) -> String {
+  format!(
+    "{} (not retried, because the build's retry budget for {} is exhausted: the remote \
+     execution service may be unhealthy)",
+    error, category
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+  use std::time::Duration;
+
+  use super::{RetryBudget, RetryCategory};
+  use crate::metrics;
+
+  #[test]
+  fn budget_is_exhausted_per_category() {
+    let budget = RetryBudget::new(2, Duration::from_secs(3600));
+    assert_eq!(budget.remaining(RetryCategory::MissingDigests), 2);
+    assert!(budget.try_acquire(RetryCategory::MissingDigests));
+    assert!(budget.clone().try_acquire(RetryCategory::MissingDigests));
+    assert!(!budget.try_acquire(RetryCategory::MissingDigests));
+    assert_eq!(budget.remaining(RetryCategory::MissingDigests), 0);
+    assert_eq!(budget.exhausted(RetryCategory::MissingDigests), 1);
+
+    // Other categories have their own buckets.
+    assert!(budget.try_acquire(RetryCategory::OutputFetch));
+    assert_eq!(budget.remaining(RetryCategory::OutputFetch), 1);
+
+    let mut snapshot = HashMap::new();
+    budget.record_metrics(&mut snapshot);
+    assert_eq!(
+      snapshot.get(metrics::REMOTE_RETRY_BUDGET_EXHAUSTED_MISSING_DIGESTS),
+      Some(&1)
+    );
+    assert_eq!(
+      snapshot.get(metrics::REMOTE_RETRY_BUDGET_REMAINING_CANCELLED_POLL),
+      Some(&2)
+    );
+  }
+
+  #[test]
+  fn budget_refills() {
+    let budget = RetryBudget::new(1, Duration::from_millis(50));
+    assert!(budget.try_acquire(RetryCategory::CancelledPoll));
+    assert!(!budget.try_acquire(RetryCategory::CancelledPoll));
+    std::thread::sleep(Duration::from_millis(100));
+    // The bucket only refills up to its capacity.
+    assert_eq!(budget.remaining(RetryCategory::CancelledPoll), 1);
+    assert!(budget.try_acquire(RetryCategory::CancelledPoll));
+  }
+
+  #[test]
+  fn zero_refill_interval_is_unlimited() {
+    let budget = RetryBudget::new(1, Duration::from_millis(0));
+    for _ in 0..10 {
+      assert!(budget.try_acquire(RetryCategory::OutputFetch));
+    }
+    assert_eq!(budget.exhausted(RetryCategory::OutputFetch), 0);
+  }
+}
@@ -0,0 +1,286 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! A CommandRunner which dispatches each request to one of several named pools of runners (for
+//! example, a pool of Linux workers and a pool of Mac workers), based on the platform variants
+//! that the request contains.
+//!
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use boxfuture::{BoxFuture, Boxable};
+use futures::future;
+use workunit_store::WorkUnitStore;
+
+use crate::{
+  sum_metrics, CommandRunner, ExecuteProcessRequest, FallibleExecuteProcessResult,
+  MultiPlatformExecuteProcessRequest, ProcessProgress,
+};
+
+#[derive(Clone)]
+pub struct RoutingCommandRunner {
+  pools: Arc<Vec<(String, Box<dyn CommandRunner>)>>,
+}
+
+impl RoutingCommandRunner {
+  ///
+  /// The pools are in priority order: a request is dispatched to the first pool which has a
+  /// compatible variant.
+  ///
+  pub fn new(pools: Vec<(String, Box<dyn CommandRunner>)>) -> RoutingCommandRunner {
+    RoutingCommandRunner {
+      pools: Arc::new(pools),
+    }
+  }
+
+  fn select(&self, req: &MultiPlatformExecuteProcessRequest) -> Option<&dyn CommandRunner> {
+    self
+      .pools
+      .iter()
+      .find(|(_, runner)| runner.extract_compatible_request(req).is_some())
+      .map(|(_, runner)| &**runner)
+  }
+
+  fn no_compatible_pool(&self, req: &MultiPlatformExecuteProcessRequest) -> String {
+    format!(
+      "No configured pool is compatible with any of the platforms {:?} of {}. Available pools: {}",
+      req.0.keys().collect::<Vec<_>>(),
+      req
+        .0
+        .values()
+        .next()
+        .map_or("an empty request", |req| req.description.as_str()),
+      self
+        .pools
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+    )
+  }
+}
+
+impl CommandRunner for RoutingCommandRunner {
+  fn run(
+    &self,
+    req: MultiPlatformExecuteProcessRequest,
+    workunit_store: WorkUnitStore,
+  ) -> BoxFuture<FallibleExecuteProcessResult, String> {
+    match self.select(&req) {
+      Some(runner) => runner.run(req, workunit_store),
+      None => future::err(self.no_compatible_pool(&req)).to_boxed(),
+    }
+  }
+
+  fn run_with_progress(
+    &self,
+    req: MultiPlatformExecuteProcessRequest,
+    progress: ProcessProgress,
+    workunit_store: WorkUnitStore,
+  ) -> BoxFuture<FallibleExecuteProcessResult, String> {
+    match self.select(&req) {
+      Some(runner) => runner.run_with_progress(req, progress, workunit_store),
+      None => future::err(self.no_compatible_pool(&req)).to_boxed(),
+    }
+  }
+
+  fn extract_compatible_request(
+    &self,
+    req: &MultiPlatformExecuteProcessRequest,
+  ) -> Option<Arc<ExecuteProcessRequest>> {
+    self
+      .pools
+      .iter()
+      .filter_map(|(_, runner)| runner.extract_compatible_request(req))
+      .next()
+  }
+
+  fn metrics(&self) -> HashMap<&'static str, i64> {
+    sum_metrics(self.pools.iter().map(|(_, runner)| runner.metrics()))
+  }
+}
+
+#[cfg(all(test, feature = "remote-execution"))]
+mod tests {
+  use std::collections::HashMap;
+  use std::convert::TryInto;
+  use std::sync::Arc;
+
+  use boxfuture::{BoxFuture, Boxable};
+  use maplit::hashmap;
+  use testutil::data::{TestData, TestDirectory};
+  use testutil::{as_bytes, owned_string_vec};
+  use workunit_store::WorkUnitStore;
+
+  use super::RoutingCommandRunner;
+  use crate::remote::tests::{
+    create_command_runner_for_platform, echo_foo_request, empty_request_metadata,
+    make_successful_operation, StderrType, StdoutType,
+  };
+  use crate::{
+    CommandRunner, ExecuteProcessRequest, FallibleExecuteProcessResult,
+    MultiPlatformExecuteProcessRequest, Platform,
+  };
+
+  #[test]
+  fn requests_are_dispatched_to_the_pool_for_their_platform() {
+    let linux_request = request_for(Platform::Linux, "linux");
+    let darwin_request = request_for(Platform::Darwin, "darwin");
+    let linux_server = mock_server_for(&linux_request, "linux");
+    let darwin_server = mock_server_for(&darwin_request, "darwin");
+
+    let cas = mock::StubCAS::builder()
+      .file(&TestData::roland())
+      .directory(&TestDirectory::containing_roland())
+      .build();
+    let runner = RoutingCommandRunner::new(vec![
+      pool(
+        "linux",
+        create_command_runner_for_platform(linux_server.address(), &cas, Platform::Linux),
+      ),
+      pool(
+        "mac",
+        create_command_runner_for_platform(darwin_server.address(), &cas, Platform::Darwin),
+      ),
+    ]);
+
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let darwin_result = runtime
+      .block_on(runner.run(darwin_request, WorkUnitStore::new()))
+      .unwrap();
+    let linux_result = runtime
+      .block_on(runner.run(linux_request, WorkUnitStore::new()))
+      .unwrap();
+
+    // Each server only knows how to respond to its own request, and echoes its pool's name.
+    assert_eq!(darwin_result.stdout, as_bytes("darwin"));
+    assert_eq!(linux_result.stdout, as_bytes("linux"));
+    assert_eq!(darwin_result.platform, Platform::Darwin);
+    assert_eq!(linux_result.platform, Platform::Linux);
+  }
+
+  #[test]
+  fn requests_without_a_compatible_pool_fail() {
+    let runner = RoutingCommandRunner::new(vec![
+      pool(
+        "linux",
+        StubCommandRunner::new(Platform::Linux, hashmap! {}),
+      ),
+      pool("mac", StubCommandRunner::new(Platform::Darwin, hashmap! {})),
+    ]);
+    // The request only has a variant for a platform which no pool supports.
+    let mut request = echo_foo_request();
+    let echo_foo = request.0.remove(&(Platform::None, Platform::None)).unwrap();
+    request
+      .0
+      .insert((Platform::None, Platform::Linux), echo_foo);
+
+    assert_eq!(runner.extract_compatible_request(&request), None);
+    let err = tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(runner.run(request, WorkUnitStore::new()))
+      .unwrap_err();
+    assert_eq!(
+      err,
+      "No configured pool is compatible with any of the platforms [(None, Linux)] of echo a foo. \
+       Available pools: linux, mac"
+    );
+  }
+
+  #[test]
+  fn metrics_are_summed_across_pools() {
+    let runner = RoutingCommandRunner::new(vec![
+      pool(
+        "linux",
+        StubCommandRunner::new(
+          Platform::Linux,
+          hashmap! {"executions" => 2, "cache_hits" => 1},
+        ),
+      ),
+      pool(
+        "mac",
+        StubCommandRunner::new(Platform::Darwin, hashmap! {"executions" => 3}),
+      ),
+    ]);
+
+    assert_eq!(
+      runner.metrics(),
+      hashmap! {"executions" => 5, "cache_hits" => 1}
+    );
+  }
+
+  fn pool<R: CommandRunner + 'static>(name: &str, runner: R) -> (String, Box<dyn CommandRunner>) {
+    (name.to_owned(), Box::new(runner))
+  }
+
+  fn request_for(platform: Platform, output: &str) -> MultiPlatformExecuteProcessRequest {
+    let req: ExecuteProcessRequest = echo_foo_request().try_into().unwrap();
+    let req = ExecuteProcessRequest {
+      argv: owned_string_vec(&["/bin/echo", "-n", output]),
+      target_platform: platform,
+      ..req
+    };
+    MultiPlatformExecuteProcessRequest(
+      vec![((platform, Platform::None), Arc::new(req))]
+        .into_iter()
+        .collect(),
+    )
+  }
+
+  fn mock_server_for(
+    request: &MultiPlatformExecuteProcessRequest,
+    output: &str,
+  ) -> mock::execution_server::TestServer {
+    let op_name = format!("{}-op", output);
+    let req = request.0.values().next().unwrap();
+    mock::execution_server::TestServer::new(
+      mock::execution_server::MockExecution::new(
+        op_name.clone(),
+        crate::remote::make_execute_request(req, empty_request_metadata())
+          .unwrap()
+          .2,
+        vec![make_successful_operation(
+          &op_name,
+          StdoutType::Raw(output.to_owned()),
+          StderrType::Raw("".to_owned()),
+          0,
+        )],
+      ),
+      None,
+    )
+  }
+
+  struct StubCommandRunner {
+    platform: Platform,
+    metrics: HashMap<&'static str, i64>,
+  }
+
+  impl StubCommandRunner {
+    fn new(platform: Platform, metrics: HashMap<&'static str, i64>) -> StubCommandRunner {
+      StubCommandRunner { platform, metrics }
+    }
+  }
+
+  impl CommandRunner for StubCommandRunner {
+    fn run(
+      &self,
+      _req: MultiPlatformExecuteProcessRequest,
+      _workunit_store: WorkUnitStore,
+    ) -> BoxFuture<FallibleExecuteProcessResult, String> {
+      futures::future::err("Stubs do not run requests.".to_owned()).to_boxed()
+    }
+
+    fn extract_compatible_request(
+      &self,
+      req: &MultiPlatformExecuteProcessRequest,
+    ) -> Option<Arc<ExecuteProcessRequest>> {
+      req.0.get(&(self.platform, Platform::None)).cloned()
+    }
+
+    fn metrics(&self) -> HashMap<&'static str, i64> {
+      self.metrics.clone()
+    }
+  }
+}
@@ -0,0 +1,532 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! Serves a CommandRunner on a unix socket, so that tools which run outside of pants can run
+//! processes with its configuration (e.g. remote execution credentials, instance and store)
+//! rather than reimplementing it. The service is the ProcessRunner of
+//! `bazel_protos/protos/pants/process_runner/v1/process_runner.proto`.
+//!
+//! Every call must carry the content of a token file which is shared with the caller, as an
+//! `authorization: Bearer <token>` header.
+//!
+
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bazel_protos::process_runner::{RunProcessRequest, RunProcessResponse};
+use futures::Future;
+use hashing::{Digest, EMPTY_DIGEST};
+use log::{debug, warn};
+use store::Store;
+use task_executor::Executor;
+use workunit_store::WorkUnitStore;
+
+use crate::{
+  BoundedCommandRunner, CommandRunner, ExecuteProcessRequest, ExecutionLocality,
+  FallibleExecuteProcessResult, Platform,
+};
+
+pub const AUTHORIZATION_HEADER: &str = "authorization";
+
+pub struct ServiceOptions {
+  // The path of the unix socket to serve on, which must not exist.
+  pub socket_path: PathBuf,
+  // A file containing the token which callers must present.
+  pub token_file: PathBuf,
+  // The maximum number of processes which are run at once: further calls wait for a slot.
+  pub max_concurrency: usize,
+}
+
+///
+/// A started service, which serves until it is stopped (or dropped).
+///
+pub struct RunningService {
+  server: grpcio::Server,
+  socket_path: PathBuf,
+}
+
+impl RunningService {
+  ///
+  /// Serves the runner on `options.socket_path`. Processes are run (and their outputs loaded from
+  /// the Store) on the Executor.
+  ///
+  pub fn start(
+    runner: Box<dyn CommandRunner>,
+    store: Store,
+    executor: Executor,
+    options: ServiceOptions,
+  ) -> Result<RunningService, String> {
+    let token = read_token(&options.token_file)?;
+    let handler = Handler {
+      runner: Arc::new(BoundedCommandRunner::new(runner, options.max_concurrency)),
+      store,
+      executor,
+      token: Arc::new(token),
+    };
+    let mut server = bind_unix_socket(
+      grpcio::ServerBuilder::new(Arc::new(grpcio::Environment::new(1))).register_service(
+        bazel_protos::process_runner_grpc::create_process_runner(handler),
+      ),
+      &options.socket_path,
+    )?;
+    server.start();
+    debug!(
+      "Serving process runner on {}",
+      options.socket_path.display()
+    );
+    Ok(RunningService {
+      server,
+      socket_path: options.socket_path,
+    })
+  }
+
+  pub fn socket_path(&self) -> &Path {
+    &self.socket_path
+  }
+
+  ///
+  /// Stops accepting calls, and waits for those which are in flight to be cancelled.
+  ///
+  pub fn stop(mut self) -> Result<(), String> {
+    self
+      .server
+      .shutdown()
+      .wait()
+      .map_err(|err| format!("Error stopping process runner service: {:?}", err))
+  }
+}
+
+impl Drop for RunningService {
+  fn drop(&mut self) {
+    if let Err(err) = std::fs::remove_file(&self.socket_path) {
+      debug!(
+        "Could not remove process runner socket {}: {}",
+        self.socket_path.display(),
+        err
+      );
+    }
+  }
+}
+
+fn read_token(token_file: &Path) -> Result<String, String> {
+  let token = std::fs::read_to_string(token_file)
+    .map_err(|err| format!("Error reading token file {}: {}", token_file.display(), err))?;
+  let token = token.trim();
+  if token.is_empty() {
+    return Err(format!("Token file {} is empty", token_file.display()));
+  }
+  Ok(token.to_owned())
+}
+
+///
+/// grpcio only binds servers to a host and port, which it joins with a colon, and grpc takes the
+/// whole of a `unix:` address after its scheme as the path of the socket (replacing any socket
+/// which is already there). So the socket is bound in a fresh directory next to the path which
+/// was asked for, and then hard linked to that path: which fails, atomically, if it exists.
+///
+fn bind_unix_socket(
+  builder: grpcio::ServerBuilder,
+  socket_path: &Path,
+) -> Result<grpcio::Server, String> {
+  let parent = match socket_path.parent() {
+    Some(parent) if !parent.as_os_str().is_empty() => parent,
+    _ => Path::new("."),
+  };
+  // Removed (with the path the socket was bound at) once the socket is linked.
+  let bind_dir = tempfile::Builder::new()
+    .prefix(".sock")
+    .tempdir_in(parent)
+    .map_err(|err| {
+      format!(
+        "Error creating a directory in {}: {}",
+        parent.display(),
+        err
+      )
+    })?;
+  let bind_path = bind_dir.path().join("s");
+  let port = 0;
+  let server = builder
+    .bind(format!("unix:{}", bind_path.display()), port)
+    .build()
+    .map_err(|err| format!("Error binding {}: {:?}", socket_path.display(), err))?;
+  let bound_path = PathBuf::from(format!("{}:{}", bind_path.display(), port));
+  std::fs::hard_link(&bound_path, socket_path).map_err(|err| {
+    if err.kind() == std::io::ErrorKind::AlreadyExists {
+      format!(
+        "Cannot serve on {}: it already exists",
+        socket_path.display()
+      )
+    } else {
+      format!(
+        "Error linking socket {} to {}: {}",
+        bound_path.display(),
+        socket_path.display(),
+        err
+      )
+    }
+  })?;
+  Ok(server)
+}
+
+#[derive(Clone)]
+struct Handler {
+  runner: Arc<dyn CommandRunner>,
+  store: Store,
+  executor: Executor,
+  token: Arc<String>,
+}
+
+impl Handler {
+  fn authenticate(&self, ctx: &grpcio::RpcContext<'_>) -> Result<(), grpcio::RpcStatus> {
+    let expected = format!("Bearer {}", self.token);
+    let authorized = ctx.request_headers().iter().any(|(key, value)| {
+      key == AUTHORIZATION_HEADER && constant_time_eq(value, expected.as_bytes())
+    });
+    if authorized {
+      Ok(())
+    } else {
+      Err(grpcio::RpcStatus::new(
+        grpcio::RpcStatusCode::Unauthenticated,
+        Some(format!(
+          "Calls must have an {} header with the service's token",
+          AUTHORIZATION_HEADER
+        )),
+      ))
+    }
+  }
+}
+
+impl bazel_protos::process_runner_grpc::ProcessRunner for Handler {
+  fn run(
+    &self,
+    ctx: grpcio::RpcContext<'_>,
+    req: RunProcessRequest,
+    sink: grpcio::UnarySink<RunProcessResponse>,
+  ) {
+    let request = self.authenticate(&ctx).and_then(|()| {
+      request_from_proto(&req)
+        .map_err(|err| grpcio::RpcStatus::new(grpcio::RpcStatusCode::InvalidArgument, Some(err)))
+    });
+    let request = match request {
+      Ok(request) => request,
+      Err(status) => {
+        ctx.spawn(sink.fail(status).map_err(|err| {
+          warn!("Error failing process runner call: {:?}", err);
+        }));
+        return;
+      }
+    };
+
+    let store = self.store.clone();
+    let response = self.executor.spawn_oneshot(
+      self
+        .runner
+        .run(request.into(), WorkUnitStore::new())
+        .and_then(move |result| response_from_result(&store, result)),
+    );
+    ctx.spawn(
+      response
+        .then(move |response| match response {
+          Ok(response) => sink.success(response),
+          Err(err) => sink.fail(grpcio::RpcStatus::new(
+            grpcio::RpcStatusCode::Internal,
+            Some(err),
+          )),
+        })
+        .map_err(|err| warn!("Error responding to process runner call: {:?}", err)),
+    );
+  }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn request_from_proto(req: &RunProcessRequest) -> Result<ExecuteProcessRequest, String> {
+  let input_files = if req.has_input_files() {
+    let input_files: Result<Digest, String> = req.get_input_files().into();
+    input_files.map_err(|err| format!("Invalid input_files: {}", err))?
+  } else {
+    EMPTY_DIGEST
+  };
+  let timeout = req.get_timeout();
+  if timeout.get_seconds() < 0 || timeout.get_nanos() < 0 {
+    return Err(format!("Invalid negative timeout: {:?}", timeout));
+  }
+  let target_platform = if req.get_target_platform().is_empty() {
+    Platform::None
+  } else {
+    Platform::try_from(&req.get_target_platform().to_owned())?
+  };
+  Ok(ExecuteProcessRequest {
+    argv: req.get_argv().to_vec(),
+    env: req
+      .get_env()
+      .iter()
+      .map(|(key, value)| (key.clone(), value.clone()))
+      .collect(),
+    input_files,
+    output_files: req.get_output_files().iter().map(PathBuf::from).collect(),
+    output_directories: req
+      .get_output_directories()
+      .iter()
+      .map(PathBuf::from)
+      .collect(),
+    timeout: Duration::new(timeout.get_seconds() as u64, timeout.get_nanos() as u32),
+    description: req.get_description().to_owned(),
+    jdk_home: if req.get_jdk_home().is_empty() {
+      None
+    } else {
+      Some(PathBuf::from(req.get_jdk_home()))
+    },
+    target_platform,
+    force_rerun: false,
+    expected_output_digest: None,
+    diff_outputs: false,
+    ephemeral_input_digests: vec![],
+    scheduling_hints: None,
+    poll_interval_hint: None,
+    cache_scope_names: BTreeSet::new(),
+    argfile_threshold: None,
+    argfile_flag_template: None,
+    provenance: None,
+    environment: None,
+    omit_cache_key_gen_version: false,
+    expected_output_size: None,
+    execution_locality: ExecutionLocality::Any,
+  })
+}
+
+fn response_from_result(
+  store: &Store,
+  result: FallibleExecuteProcessResult,
+) -> impl Future<Item = RunProcessResponse, Error = String> {
+  let workunit_store = WorkUnitStore::new();
+  result
+    .stdout_bytes(store, workunit_store.clone())
+    .join(result.stderr_bytes(store, workunit_store))
+    .map(move |(stdout, stderr)| {
+      let mut response = RunProcessResponse::new();
+      response.set_stdout(stdout);
+      response.set_stderr(stderr);
+      response.set_exit_code(result.exit_code);
+      response.set_output_directory((&result.output_directory).into());
+      response
+    })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{RunningService, ServiceOptions, AUTHORIZATION_HEADER};
+  use crate::remote::tests::{
+    create_command_runner_for_platform, empty_request_metadata, make_successful_operation,
+    StderrType, StdoutType,
+  };
+  use crate::{remote, CommandRunner, ExecuteProcessRequest, Platform};
+  use bazel_protos::process_runner::RunProcessRequest;
+  use bazel_protos::process_runner_grpc::ProcessRunnerClient;
+  use bytes::Bytes;
+  use maplit::btreemap;
+  use std::collections::HashMap;
+  use std::sync::Arc;
+  use std::time::Duration;
+  use store::Store;
+  use tempfile::TempDir;
+  use testutil::owned_string_vec;
+  use workunit_store::WorkUnitStore;
+
+  const TOKEN: &str = "let-me-in";
+
+  fn request() -> ExecuteProcessRequest {
+    ExecuteProcessRequest {
+      argv: owned_string_vec(&["/bin/echo", "-n", "foo"]),
+      env: btreemap! {"FOO".to_owned() => "bar".to_owned()},
+      timeout: Duration::from_secs(5),
+      ..crate::tests::execute_process_request("echo a foo")
+    }
+  }
+
+  fn request_proto() -> RunProcessRequest {
+    let mut req = RunProcessRequest::new();
+    req.set_argv(protobuf::RepeatedField::from_vec(owned_string_vec(&[
+      "/bin/echo",
+      "-n",
+      "foo",
+    ])));
+    let mut env = HashMap::new();
+    env.insert("FOO".to_owned(), "bar".to_owned());
+    req.set_env(env);
+    req.mut_timeout().set_seconds(5);
+    req.set_description("echo a foo".to_owned());
+    req
+  }
+
+  struct Service {
+    service: RunningService,
+    _mock_server: mock::execution_server::TestServer,
+    runner: remote::CommandRunner,
+    executor: task_executor::Executor,
+    _dir: TempDir,
+  }
+
+  // The mock server expects the request to be run the given number of times.
+  fn start_service(runs: usize) -> Service {
+    let op_name = "echo-foo".to_owned();
+    let operations = (0..runs)
+      .map(|_| {
+        make_successful_operation(
+          &op_name,
+          StdoutType::Raw("foo".to_owned()),
+          StderrType::Raw("".to_owned()),
+          0,
+        )
+      })
+      .collect();
+    let mock_server = mock::execution_server::TestServer::new(
+      mock::execution_server::MockExecution::new(
+        op_name.clone(),
+        remote::make_execute_request(&request(), empty_request_metadata())
+          .unwrap()
+          .2,
+        operations,
+      ),
+      None,
+    );
+    let cas = mock::StubCAS::empty();
+    let runner = create_command_runner_for_platform(mock_server.address(), &cas, Platform::Linux);
+
+    let dir = TempDir::new().unwrap();
+    let token_file = dir.path().join("token");
+    std::fs::write(&token_file, format!("{}\n", TOKEN)).unwrap();
+    let executor = task_executor::Executor::new();
+    // Outputs are returned inline, so are never loaded from the Store.
+    let store = Store::local_only(executor.clone(), dir.path().join("store")).unwrap();
+    let service = RunningService::start(
+      Box::new(runner.clone()),
+      store,
+      executor.clone(),
+      ServiceOptions {
+        socket_path: dir.path().join("runner.sock"),
+        token_file,
+        max_concurrency: 2,
+      },
+    )
+    .unwrap();
+    Service {
+      service,
+      _mock_server: mock_server,
+      runner,
+      executor,
+      _dir: dir,
+    }
+  }
+
+  fn client(service: &Service) -> ProcessRunnerClient {
+    let env = Arc::new(grpcio::Environment::new(1));
+    let channel = grpcio::ChannelBuilder::new(env)
+      .connect(&format!("unix:{}", service.service.socket_path().display()));
+    ProcessRunnerClient::new(channel)
+  }
+
+  fn call_option(token: &str) -> grpcio::CallOption {
+    let mut metadata = grpcio::MetadataBuilder::with_capacity(1);
+    metadata
+      .add_str(AUTHORIZATION_HEADER, &format!("Bearer {}", token))
+      .unwrap();
+    grpcio::CallOption::default().headers(metadata.build())
+  }
+
+  #[test]
+  fn calls_via_the_service_match_calls_to_the_runner() {
+    // Once via the service, and once directly.
+    let service = start_service(2);
+    let response = client(&service)
+      .run_opt(&request_proto(), call_option(TOKEN))
+      .unwrap();
+
+    let direct = service
+      .executor
+      .block_on(service.runner.run(request().into(), WorkUnitStore::new()))
+      .unwrap();
+    assert_eq!(direct.stdout, Bytes::from("foo"));
+    assert_eq!(direct.stdout, response.get_stdout().clone());
+    assert_eq!(direct.stderr, response.get_stderr().clone());
+    assert_eq!(direct.exit_code, response.get_exit_code());
+    let output_directory: Result<hashing::Digest, String> = response.get_output_directory().into();
+    assert_eq!(output_directory, Ok(direct.output_directory));
+
+    service.service.stop().unwrap();
+  }
+
+  #[test]
+  fn calls_without_the_token_are_rejected() {
+    let service = start_service(0);
+    let client = client(&service);
+    for option in vec![
+      grpcio::CallOption::default(),
+      call_option("let-me-in-please"),
+    ] {
+      match client.run_opt(&request_proto(), option) {
+        Err(grpcio::Error::RpcFailure(status)) => {
+          assert_eq!(status.status, grpcio::RpcStatusCode::Unauthenticated)
+        }
+        other => panic!("Want Unauthenticated, got {:?}", other),
+      }
+    }
+  }
+
+  #[test]
+  fn invalid_requests_are_rejected() {
+    let service = start_service(0);
+    let client = client(&service);
+    let mut req = request_proto();
+    req.set_target_platform("beos".to_owned());
+    match client.run_opt(&req, call_option(TOKEN)) {
+      Err(grpcio::Error::RpcFailure(status)) => {
+        assert_eq!(status.status, grpcio::RpcStatusCode::InvalidArgument)
+      }
+      other => panic!("Want InvalidArgument, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn services_are_not_started_on_existing_paths() {
+    let service = start_service(0);
+    let socket_path = service.service.socket_path().to_owned();
+    let dir = TempDir::new().unwrap();
+    let token_file = dir.path().join("token");
+    std::fs::write(&token_file, TOKEN).unwrap();
+    let executor = task_executor::Executor::new();
+    let store = Store::local_only(executor.clone(), dir.path().join("store")).unwrap();
+
+    let result = RunningService::start(
+      Box::new(service.runner.clone()),
+      store,
+      executor,
+      ServiceOptions {
+        socket_path: socket_path.clone(),
+        token_file,
+        max_concurrency: 1,
+      },
+    );
+    assert_eq!(
+      result.err(),
+      Some(format!(
+        "Cannot serve on {}: it already exists",
+        socket_path.display()
+      ))
+    );
+
+    // The existing service still serves on the path.
+    match client(&service).run_opt(&request_proto(), grpcio::CallOption::default()) {
+      Err(grpcio::Error::RpcFailure(status)) => {
+        assert_eq!(status.status, grpcio::RpcStatusCode::Unauthenticated)
+      }
+      other => panic!("Want Unauthenticated, got {:?}", other),
+    }
+  }
+}
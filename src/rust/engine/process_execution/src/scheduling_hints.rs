@@ -0,0 +1,178 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! Estimates of the resources that a process will use, which some remote execution schedulers
+//! accept as a hint when placing an action on a worker.
+//!
+//! Scheduling hints are not part of the REAPI, so they are sent as an Any in a field of the
+//! ExecuteRequest which the REAPI does not use (and never in the Action, so they do not affect
+//! any digests). The Any contains a message equivalent to:
+//!
+//! ```protobuf
+//! package pants.remote_execution;
+//!
+//! message SchedulingHints {
+//!   uint64 cpu_millis = 1;
+//!   uint64 ram_bytes = 2;
+//!   uint64 disk_bytes = 3;
+//! }
+//! ```
+//!
+//! which is simple enough to encode by hand, rather than generating it in bazel_protos.
+//!
+
+use bazel_protos;
+#[cfg(feature = "remote-execution")]
+use grpcio;
+use protobuf::well_known_types::Any;
+use protobuf::{self, CodedOutputStream, Message};
+
+pub const SCHEDULING_HINTS_TYPE_URL: &str =
+  "type.googleapis.com/pants.remote_execution.SchedulingHints";
+
+// The field of the ExecuteRequest in which the Any is sent.
+pub const SCHEDULING_HINTS_FIELD_NUMBER: u32 = 1000;
+
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct SchedulingHints {
+  pub cpu_millis: u64,
+  pub ram_bytes: u64,
+  pub disk_bytes: u64,
+}
+
+impl SchedulingHints {
+  pub fn to_any(&self) -> Any {
+    let mut value = Vec::new();
+    {
+      let mut os = CodedOutputStream::vec(&mut value);
+      // As in generated code, fields with default values are omitted.
+      for &(field_number, field) in &[
+        (1, self.cpu_millis),
+        (2, self.ram_bytes),
+        (3, self.disk_bytes),
+      ] {
+        if field != 0 {
+          os.write_uint64(field_number, field)
+            .expect("Writing to a Vec cannot fail.");
+        }
+      }
+      os.flush().expect("Writing to a Vec cannot fail.");
+    }
+    let mut any = Any::new();
+    any.set_type_url(SCHEDULING_HINTS_TYPE_URL.to_owned());
+    any.set_value(value);
+    any
+  }
+}
+
+pub fn attach(
+  execute_request: &mut bazel_protos::remote_execution::ExecuteRequest,
+  hints: &SchedulingHints,
+) {
+  let any_bytes = hints
+    .to_any()
+    .write_to_bytes()
+    .expect("Serializing an Any cannot fail.");
+  execute_request
+    .mut_unknown_fields()
+    .add_length_delimited(SCHEDULING_HINTS_FIELD_NUMBER, any_bytes);
+}
+
+///
+/// Returns the Any attached to the given ExecuteRequest, if any.
+///
+pub fn attached(execute_request: &bazel_protos::remote_execution::ExecuteRequest) -> Option<Any> {
+  execute_request
+    .get_unknown_fields()
+    .get(SCHEDULING_HINTS_FIELD_NUMBER)
+    .and_then(|values| values.length_delimited.first())
+    .and_then(|any_bytes| protobuf::parse_from_bytes::<Any>(any_bytes).ok())
+}
+
+pub fn without(
+  execute_request: &bazel_protos::remote_execution::ExecuteRequest,
+) -> bazel_protos::remote_execution::ExecuteRequest {
+  let mut execute_request = execute_request.clone();
+  let unknown_fields = execute_request.mut_unknown_fields();
+  let now_empty = match unknown_fields.fields {
+    Some(ref mut fields) => {
+      fields.remove(&SCHEDULING_HINTS_FIELD_NUMBER);
+      fields.is_empty()
+    }
+    None => false,
+  };
+  // So that a request which had only the hints is equal to one which never had them.
+  if now_empty {
+    unknown_fields.fields = None;
+  }
+  execute_request
+}
+
+///
+/// Whether an error from an Execute RPC indicates that the server rejected the scheduling hints.
+/// Depending on the server, the error is either a Status, or only a gRPC status code and message.
+///
+#[cfg(feature = "remote-execution")]
+pub fn is_rejection_status(status: &bazel_protos::status::Status) -> bool {
+  grpcio::RpcStatusCode::from(status.get_code()) == grpcio::RpcStatusCode::InvalidArgument
+    && status.get_message().contains(SCHEDULING_HINTS_TYPE_URL)
+}
+
+#[cfg(feature = "remote-execution")]
+pub fn is_rejection_message(message: &str) -> bool {
+  message.contains(&format!("{:?}", grpcio::RpcStatusCode::InvalidArgument))
+    && message.contains(SCHEDULING_HINTS_TYPE_URL)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{attach, attached, without, SchedulingHints, SCHEDULING_HINTS_TYPE_URL};
+  use bazel_protos;
+  use protobuf::{self, CodedInputStream};
+
+  #[test]
+  fn encodes_non_default_fields() {
+    let any = SchedulingHints {
+      cpu_millis: 1500,
+      ram_bytes: 0,
+      disk_bytes: 3,
+    }
+    .to_any();
+    assert_eq!(any.get_type_url(), SCHEDULING_HINTS_TYPE_URL);
+
+    let mut is = CodedInputStream::from_bytes(any.get_value());
+    let mut fields = vec![];
+    while !is.eof().unwrap() {
+      let (field_number, _) = is.read_tag_unpack().unwrap();
+      fields.push((field_number, is.read_uint64().unwrap()));
+    }
+    assert_eq!(fields, vec![(1, 1500), (3, 3)]);
+  }
+
+  #[test]
+  fn attach_then_without_round_trips() {
+    let mut execute_request = bazel_protos::remote_execution::ExecuteRequest::new();
+    execute_request.set_instance_name("dark-tower".to_owned());
+    let original = execute_request.clone();
+    let hints = SchedulingHints {
+      cpu_millis: 1000,
+      ram_bytes: 1 << 30,
+      disk_bytes: 0,
+    };
+
+    attach(&mut execute_request, &hints);
+    assert_ne!(execute_request, original);
+    assert_eq!(attached(&execute_request), Some(hints.to_any()));
+
+    // The hints survive serialization, as they must to be sent to the server.
+    let reparsed: bazel_protos::remote_execution::ExecuteRequest =
+      protobuf::parse_from_bytes(&protobuf::Message::write_to_bytes(&execute_request).unwrap())
+        .unwrap();
+    assert_eq!(attached(&reparsed), Some(hints.to_any()));
+
+    let stripped = without(&execute_request);
+    assert_eq!(attached(&stripped), None);
+    assert_eq!(stripped, original);
+  }
+}
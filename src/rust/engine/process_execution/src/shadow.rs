@@ -0,0 +1,551 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! A CommandRunner which returns the results of a primary runner, and in the background re-runs a
+//! sample of requests against a shadow runner (for example, a remote execution service which is
+//! being migrated to), reporting any differences between the results. The shadow runs never
+//! affect the results which are returned.
+//!
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use boxfuture::{BoxFuture, Boxable};
+use futures::Future;
+use hashing::Digest;
+use log::{debug, warn};
+use tokio_timer::Delay;
+use workunit_store::WorkUnitStore;
+
+use crate::metrics;
+use crate::verify::{self, FieldMismatch};
+use crate::{
+  sum_metrics, CommandRunner, ExecuteProcessRequest, ExecuteProcessRequestMetadata,
+  FallibleExecuteProcessResult, MultiPlatformExecuteProcessRequest, ProcessProgress,
+};
+
+// The default maximum number of shadow runs in flight at once.
+const DEFAULT_MAX_CONCURRENT_SHADOWS: usize = 16;
+
+///
+/// The differences between the primary and shadow results of a sampled request. In each field,
+/// `remote` is the primary's value and `local` is the shadow's.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ShadowDivergence {
+  pub action_digest: Digest,
+  pub description: String,
+  pub fields: Vec<FieldMismatch>,
+}
+
+impl fmt::Display for ShadowDivergence {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "Primary and shadow results of {} (action {}) differ:",
+      self.description, self.action_digest.0
+    )?;
+    for mismatch in &self.fields {
+      write!(
+        f,
+        "\n  {}:\n    primary: {}\n    shadow: {}",
+        mismatch.field, mismatch.remote, mismatch.local
+      )?;
+    }
+    Ok(())
+  }
+}
+
+pub type DivergenceReporter = Arc<dyn Fn(&ShadowDivergence) + Send + Sync>;
+
+#[derive(Clone)]
+pub struct ShadowingCommandRunner {
+  primary: Arc<dyn CommandRunner>,
+  shadow: Arc<dyn CommandRunner>,
+  metadata: ExecuteProcessRequestMetadata,
+  sample_rate: f64,
+  executor: task_executor::Executor,
+  reporter: DivergenceReporter,
+  max_concurrent_shadows: usize,
+  shadow_timeout: Option<Duration>,
+  in_flight: Arc<AtomicUsize>,
+  counters: Arc<Mutex<HashMap<&'static str, i64>>>,
+  // The names under which the counters of the shadow are reported, by their own names.
+  shadow_counter_names: Arc<Mutex<HashMap<&'static str, &'static str>>>,
+}
+
+impl ShadowingCommandRunner {
+  ///
+  /// A sample_rate of 1.0 (or more) shadows every request, and 0.0 (or less) shadows none. The
+  /// metadata should be that of the primary runner, so that the action digests which decide the
+  /// sample (and which are reported) are those that the primary runner executes. Shadow runs are
+  /// spawned on the given Executor.
+  ///
+  pub fn new(
+    primary: Box<dyn CommandRunner>,
+    shadow: Box<dyn CommandRunner>,
+    metadata: ExecuteProcessRequestMetadata,
+    sample_rate: f64,
+    executor: task_executor::Executor,
+    reporter: DivergenceReporter,
+  ) -> ShadowingCommandRunner {
+    ShadowingCommandRunner {
+      primary: primary.into(),
+      shadow: shadow.into(),
+      metadata,
+      sample_rate,
+      executor,
+      reporter,
+      max_concurrent_shadows: DEFAULT_MAX_CONCURRENT_SHADOWS,
+      shadow_timeout: None,
+      in_flight: Arc::new(AtomicUsize::new(0)),
+      counters: Arc::new(Mutex::new(HashMap::new())),
+      shadow_counter_names: Arc::new(Mutex::new(HashMap::new())),
+    }
+  }
+
+  ///
+  /// Sampled requests which complete while this many shadow runs are already in flight are not
+  /// shadowed (and are counted as skipped).
+  ///
+  pub fn with_max_concurrent_shadows(mut self, max_concurrent_shadows: usize) -> Self {
+    self.max_concurrent_shadows = max_concurrent_shadows;
+    self
+  }
+
+  ///
+  /// Abandons (and counts as failed) any shadow run which takes longer than the given timeout. By
+  /// default, shadow runs are only bounded by the timeouts of their requests.
+  ///
+  pub fn with_shadow_timeout(mut self, shadow_timeout: Duration) -> Self {
+    self.shadow_timeout = Some(shadow_timeout);
+    self
+  }
+
+  ///
+  /// Returns the action digest of the request if it should be shadowed, or None if it should only
+  /// run on the primary.
+  ///
+  fn sample(&self, req: &MultiPlatformExecuteProcessRequest) -> Option<(Digest, String)> {
+    let primary_req = self.primary.extract_compatible_request(req)?;
+    self.shadow.extract_compatible_request(req)?;
+    let action_digest = verify::action_digest(&primary_req, self.metadata.clone())
+      .map_err(|err| debug!("Not shadowing {}: {}", primary_req.description, err))
+      .ok()?;
+    if verify::is_sampled(self.sample_rate, &action_digest) {
+      Some((action_digest, primary_req.description.clone()))
+    } else {
+      None
+    }
+  }
+
+  fn increment_counter(&self, name: &'static str) {
+    *self.counters.lock().unwrap().entry(name).or_insert(0) += 1;
+  }
+
+  ///
+  /// The name under which a counter of the shadow is reported (see
+  /// `metrics::SHADOW_COUNTER_PREFIX`). Each name is only allocated (and leaked) once, and there
+  /// are only as many names as there are counters.
+  ///
+  fn shadow_counter_name(&self, name: &'static str) -> &'static str {
+    self
+      .shadow_counter_names
+      .lock()
+      .unwrap()
+      .entry(name)
+      .or_insert_with(|| {
+        Box::leak(format!("{}{}", metrics::SHADOW_COUNTER_PREFIX, name).into_boxed_str())
+      })
+  }
+
+  fn shadow_counters(&self, counters: HashMap<&'static str, i64>) -> HashMap<&'static str, i64> {
+    counters
+      .into_iter()
+      .map(|(name, value)| (self.shadow_counter_name(name), value))
+      .collect()
+  }
+
+  ///
+  /// Runs the request on the shadow in the background (skipping any cache lookup), and reports
+  /// any differences from the primary's result. Failures of the shadow are only counted.
+  ///
+  /// Shadow runs have their own WorkUnitStore, so that their workunits and counters are not
+  /// reported as those of the build: their counters are instead reported by `metrics`, under the
+  /// names of `shadow_counter_name`.
+  ///
+  fn spawn_shadow(
+    &self,
+    req: MultiPlatformExecuteProcessRequest,
+    primary_result: FallibleExecuteProcessResult,
+    action_digest: Digest,
+    description: String,
+  ) {
+    if self.in_flight.fetch_add(1, Ordering::SeqCst) >= self.max_concurrent_shadows {
+      self.in_flight.fetch_sub(1, Ordering::SeqCst);
+      self.increment_counter(metrics::SHADOW_SKIPPED);
+      debug!(
+        "Not shadowing {}: {} shadow runs are already in flight",
+        description, self.max_concurrent_shadows
+      );
+      return;
+    }
+
+    let req = MultiPlatformExecuteProcessRequest(
+      req
+        .0
+        .into_iter()
+        .map(|(platforms, req)| {
+          let req = ExecuteProcessRequest {
+            force_rerun: true,
+            ..(*req).clone()
+          };
+          (platforms, Arc::new(req))
+        })
+        .collect(),
+    );
+    let shadow_workunit_store = WorkUnitStore::new();
+    let shadow_run = self.shadow.run(req, shadow_workunit_store.clone());
+    let shadow_run = match self.shadow_timeout {
+      Some(shadow_timeout) => shadow_run
+        .select(Delay::new(Instant::now() + shadow_timeout).then(move |_| {
+          Err::<FallibleExecuteProcessResult, _>(format!("Timed out after {:?}", shadow_timeout))
+        }))
+        .map(|(result, _)| result)
+        .map_err(|(err, _)| err)
+        .to_boxed(),
+      None => shadow_run,
+    };
+
+    let runner = self.clone();
+    self
+      .executor
+      .spawn_and_ignore(shadow_run.then(move |shadow_result| {
+        runner.in_flight.fetch_sub(1, Ordering::SeqCst);
+        let shadow_counters = runner.shadow_counters(shadow_workunit_store.get_counters());
+        {
+          let mut counters = runner.counters.lock().unwrap();
+          for (name, value) in shadow_counters {
+            *counters.entry(name).or_insert(0) += value;
+          }
+        }
+        runner.increment_counter(metrics::SHADOW_RUNS);
+        match shadow_result {
+          Ok(shadow_result) => {
+            let fields = verify::compare(&primary_result, &shadow_result);
+            if !fields.is_empty() {
+              runner.increment_counter(metrics::SHADOW_DIVERGENCES);
+              let divergence = ShadowDivergence {
+                action_digest,
+                description,
+                fields,
+              };
+              warn!("{}", divergence);
+              (runner.reporter)(&divergence);
+            }
+          }
+          Err(err) => {
+            runner.increment_counter(metrics::SHADOW_FAILURES);
+            debug!("Shadow run of {} failed: {}", description, err);
+          }
+        }
+        Ok(())
+      }));
+  }
+}
+
+impl CommandRunner for ShadowingCommandRunner {
+  fn extract_compatible_request(
+    &self,
+    req: &MultiPlatformExecuteProcessRequest,
+  ) -> Option<Arc<ExecuteProcessRequest>> {
+    self.primary.extract_compatible_request(req)
+  }
+
+  fn metrics(&self) -> HashMap<&'static str, i64> {
+    sum_metrics(vec![
+      self.primary.metrics(),
+      self.shadow_counters(self.shadow.metrics()),
+      self.counters.lock().unwrap().clone(),
+    ])
+  }
+
+  fn run(
+    &self,
+    req: MultiPlatformExecuteProcessRequest,
+    workunit_store: WorkUnitStore,
+  ) -> BoxFuture<FallibleExecuteProcessResult, String> {
+    self.run_with_progress(req, ProcessProgress::new(), workunit_store)
+  }
+
+  fn run_with_progress(
+    &self,
+    req: MultiPlatformExecuteProcessRequest,
+    progress: ProcessProgress,
+    workunit_store: WorkUnitStore,
+  ) -> BoxFuture<FallibleExecuteProcessResult, String> {
+    let primary_run = self
+      .primary
+      .run_with_progress(req.clone(), progress, workunit_store);
+    match self.sample(&req) {
+      Some((action_digest, description)) => {
+        let runner = self.clone();
+        primary_run
+          .map(move |result| {
+            runner.spawn_shadow(req, result.clone(), action_digest, description);
+            result
+          })
+          .to_boxed()
+      }
+      None => primary_run,
+    }
+  }
+}
+
+#[cfg(all(test, feature = "remote-execution"))]
+mod tests {
+  use std::collections::BTreeMap;
+  use std::sync::mpsc;
+  use std::sync::{Arc, Mutex};
+  use std::time::Duration;
+
+  use boxfuture::{BoxFuture, Boxable};
+  use futures::future;
+  use hashing::EMPTY_DIGEST;
+  use maplit::hashmap;
+  use testutil::as_bytes;
+  use workunit_store::WorkUnitStore;
+
+  use super::{ShadowDivergence, ShadowingCommandRunner};
+  use crate::metrics;
+  use crate::remote::tests::echo_foo_request;
+  use crate::verify::FieldMismatch;
+  use crate::{
+    CommandRunner, EnvRecording, ExecuteProcessRequest, ExecuteProcessRequestMetadata,
+    FallibleExecuteProcessResult, MultiPlatformExecuteProcessRequest, Platform,
+    ProcessResultSource,
+  };
+
+  #[test]
+  fn divergence_is_reported_and_primary_result_returned() {
+    let shadow = StubCommandRunner::new(Ok(FallibleExecuteProcessResult {
+      exit_code: 1,
+      ..result()
+    }));
+    let shadow_requests = shadow.requests.clone();
+    let (runner, divergences) = shadowing_runner(shadow);
+
+    assert_eq!(run(&runner), Ok(result()));
+
+    let divergence = divergences
+      .recv_timeout(Duration::from_secs(5))
+      .expect("Want a divergence");
+    assert_eq!(
+      divergence.fields,
+      vec![FieldMismatch {
+        field: "exit_code",
+        remote: "0".to_owned(),
+        local: "1".to_owned(),
+      }]
+    );
+    assert_eq!(divergence.description, "echo a foo");
+    assert!(divergences
+      .recv_timeout(Duration::from_millis(100))
+      .is_err());
+
+    // The shadow skipped the cache.
+    let shadow_requests = shadow_requests.lock().unwrap();
+    assert_eq!(shadow_requests.len(), 1);
+    assert!(shadow_requests[0].force_rerun);
+    let metrics = runner.metrics();
+    assert_eq!(metrics[metrics::SHADOW_RUNS], 1);
+    assert_eq!(metrics[metrics::SHADOW_DIVERGENCES], 1);
+  }
+
+  #[test]
+  fn shadow_counters_are_kept_apart_from_those_of_the_primary() {
+    let (runner, divergences) =
+      shadowing_runner(StubCommandRunner::new(Ok(FallibleExecuteProcessResult {
+        exit_code: 1,
+        ..result()
+      })));
+
+    let workunit_store = WorkUnitStore::new();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    assert_eq!(
+      runtime.block_on(runner.run(echo_foo_request(), workunit_store.clone())),
+      Ok(result())
+    );
+    divergences
+      .recv_timeout(Duration::from_secs(5))
+      .expect("Want a divergence");
+
+    // Only the primary's run is counted in the request's WorkUnitStore.
+    assert_eq!(
+      workunit_store.get_counters(),
+      hashmap! {metrics::REMOTE_EXECUTION_REQUESTS => 1}
+    );
+    let metrics = runner.metrics();
+    assert_eq!(metrics.get(metrics::REMOTE_EXECUTION_REQUESTS), None);
+    assert_eq!(metrics["shadow_remote_execution_requests"], 1);
+  }
+
+  #[test]
+  fn shadow_failures_are_only_counted() {
+    let (runner, divergences) =
+      shadowing_runner(StubCommandRunner::new(Err("Shadow fell over".to_owned())));
+
+    assert_eq!(run(&runner), Ok(result()));
+
+    assert!(divergences
+      .recv_timeout(Duration::from_millis(500))
+      .is_err());
+    assert_eq!(runner.metrics()[metrics::SHADOW_FAILURES], 1);
+  }
+
+  #[test]
+  fn shadow_timeouts_are_only_counted() {
+    let (runner, divergences) = shadowing_runner(StubCommandRunner::hanging());
+    let runner = runner.with_shadow_timeout(Duration::from_millis(100));
+
+    assert_eq!(run(&runner), Ok(result()));
+
+    assert!(divergences
+      .recv_timeout(Duration::from_millis(500))
+      .is_err());
+    assert_eq!(runner.metrics()[metrics::SHADOW_FAILURES], 1);
+  }
+
+  #[test]
+  fn shadows_beyond_the_concurrency_bound_are_skipped() {
+    let (runner, _divergences) = shadowing_runner(StubCommandRunner::hanging());
+    let runner = runner.with_max_concurrent_shadows(1);
+
+    assert_eq!(run(&runner), Ok(result()));
+    assert_eq!(run(&runner), Ok(result()));
+
+    assert_eq!(runner.metrics()[metrics::SHADOW_SKIPPED], 1);
+  }
+
+  #[test]
+  fn divergence_display_labels_primary_and_shadow() {
+    let divergence = ShadowDivergence {
+      action_digest: EMPTY_DIGEST,
+      description: "echo a foo".to_owned(),
+      fields: vec![FieldMismatch {
+        field: "exit_code",
+        remote: "0".to_owned(),
+        local: "1".to_owned(),
+      }],
+    };
+    assert_eq!(
+      divergence.to_string(),
+      format!(
+        "Primary and shadow results of echo a foo (action {}) differ:\n  exit_code:\n{}",
+        EMPTY_DIGEST.0, "    primary: 0\n    shadow: 1"
+      )
+    );
+  }
+
+  fn result() -> FallibleExecuteProcessResult {
+    FallibleExecuteProcessResult {
+      stdout: as_bytes("foo").into(),
+      stderr: as_bytes("").into(),
+      exit_code: 0,
+      output_directory: EMPTY_DIGEST,
+      execution_attempts: vec![],
+      source: ProcessResultSource::RanRemotely,
+      termination_signal: None,
+      server_message: None,
+      timeout_details: None,
+      rewritten_action_digest: None,
+      output_size_anomaly: None,
+      platform: Platform::None,
+    }
+  }
+
+  fn run(runner: &ShadowingCommandRunner) -> Result<FallibleExecuteProcessResult, String> {
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(runner.run(echo_foo_request(), WorkUnitStore::new()))
+  }
+
+  fn shadowing_runner(
+    shadow: StubCommandRunner,
+  ) -> (ShadowingCommandRunner, mpsc::Receiver<ShadowDivergence>) {
+    let (sender, receiver) = mpsc::channel();
+    let sender = Mutex::new(sender);
+    let runner = ShadowingCommandRunner::new(
+      Box::new(StubCommandRunner::new(Ok(result()))),
+      Box::new(shadow),
+      ExecuteProcessRequestMetadata {
+        instance_name: None,
+        cache_key_gen_version: None,
+        cache_scopes: BTreeMap::new(),
+        platform_properties: vec![],
+        timeout_excludes_queue: false,
+        argv_error_patterns: vec![],
+        argv_warning_bytes: None,
+        allow_lossy_env: false,
+        canonical_form_version: 0,
+        environments: BTreeMap::new(),
+        record_env_in_report: EnvRecording::Off,
+      },
+      1.0,
+      task_executor::Executor::new(),
+      Arc::new(move |divergence: &ShadowDivergence| {
+        sender.lock().unwrap().send(divergence.clone()).unwrap()
+      }),
+    );
+    (runner, receiver)
+  }
+
+  #[derive(Clone)]
+  struct StubCommandRunner {
+    // None if runs never complete.
+    result: Option<Result<FallibleExecuteProcessResult, String>>,
+    requests: Arc<Mutex<Vec<ExecuteProcessRequest>>>,
+  }
+
+  impl StubCommandRunner {
+    fn new(result: Result<FallibleExecuteProcessResult, String>) -> StubCommandRunner {
+      StubCommandRunner {
+        result: Some(result),
+        requests: Arc::new(Mutex::new(vec![])),
+      }
+    }
+
+    fn hanging() -> StubCommandRunner {
+      StubCommandRunner {
+        result: None,
+        requests: Arc::new(Mutex::new(vec![])),
+      }
+    }
+  }
+
+  impl CommandRunner for StubCommandRunner {
+    fn run(
+      &self,
+      req: MultiPlatformExecuteProcessRequest,
+      workunit_store: WorkUnitStore,
+    ) -> BoxFuture<FallibleExecuteProcessResult, String> {
+      workunit_store.increment_counter(metrics::REMOTE_EXECUTION_REQUESTS, 1);
+      let req = self.extract_compatible_request(&req).unwrap();
+      self.requests.lock().unwrap().push((*req).clone());
+      match self.result {
+        Some(ref result) => future::result(result.clone()).to_boxed(),
+        None => future::empty().to_boxed(),
+      }
+    }
+
+    fn extract_compatible_request(
+      &self,
+      req: &MultiPlatformExecuteProcessRequest,
+    ) -> Option<Arc<ExecuteProcessRequest>> {
+      req.0.get(&(Platform::None, Platform::None)).cloned()
+    }
+  }
+}
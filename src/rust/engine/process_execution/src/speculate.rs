@@ -1,9 +1,10 @@
 use super::{
-  CommandRunner, ExecuteProcessRequest, FallibleExecuteProcessResult,
-  MultiPlatformExecuteProcessRequest,
+  sum_metrics, CommandRunner, ExecuteProcessRequest, FallibleExecuteProcessResult,
+  MultiPlatformExecuteProcessRequest, ProcessProgress,
 };
 use boxfuture::{BoxFuture, Boxable};
 use futures::future::{err, ok, Future};
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio_timer::Delay;
@@ -58,17 +59,21 @@ impl CommandRunner for SpeculatingCommandRunner {
   fn extract_compatible_request(
     &self,
     req: &MultiPlatformExecuteProcessRequest,
-  ) -> Option<ExecuteProcessRequest> {
+  ) -> Option<Arc<ExecuteProcessRequest>> {
     match (
       self.primary.extract_compatible_request(req),
       self.secondary.extract_compatible_request(req),
     ) {
-      (Some(req), _) => Some(req.clone()),
-      (_, Some(req)) => Some(req.clone()),
+      (Some(req), _) => Some(req),
+      (_, Some(req)) => Some(req),
       _ => None,
     }
   }
 
+  fn metrics(&self) -> HashMap<&'static str, i64> {
+    sum_metrics(vec![self.primary.metrics(), self.secondary.metrics()])
+  }
+
   fn run(
     &self,
     req: MultiPlatformExecuteProcessRequest,
@@ -88,9 +93,32 @@ impl CommandRunner for SpeculatingCommandRunner {
       .to_boxed(),
     }
   }
+
+  fn run_with_progress(
+    &self,
+    req: MultiPlatformExecuteProcessRequest,
+    progress: ProcessProgress,
+    workunit_store: WorkUnitStore,
+  ) -> BoxFuture<FallibleExecuteProcessResult, String> {
+    match (
+      self.primary.extract_compatible_request(&req),
+      self.secondary.extract_compatible_request(&req),
+    ) {
+      // NB: When speculating, the progress of the two runners would be interleaved, so neither
+      // is reported.
+      (Some(_), Some(_)) => self.speculate(req, workunit_store),
+      (Some(_), None) => self
+        .primary
+        .run_with_progress(req, progress, workunit_store),
+      (None, Some(_)) => self
+        .secondary
+        .run_with_progress(req, progress, workunit_store),
+      (None, None) => self.run(req, workunit_store),
+    }
+  }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "remote-execution"))]
 mod tests {
   use crate::remote::tests::echo_foo_request;
   use boxfuture::{BoxFuture, Boxable};
@@ -107,7 +135,7 @@ mod tests {
     CommandRunner, ExecuteProcessRequest, FallibleExecuteProcessResult,
     MultiPlatformExecuteProcessRequest, SpeculatingCommandRunner,
   };
-  use crate::Platform;
+  use crate::{Platform, ProcessResultSource};
 
   #[test]
   fn test_no_speculation() {
@@ -248,11 +276,18 @@ mod tests {
       Err(msg.into())
     } else {
       Ok(FallibleExecuteProcessResult {
-        stdout: msg.into(),
-        stderr: "".into(),
+        stdout: Bytes::from(msg).into(),
+        stderr: Bytes::new().into(),
         exit_code: 0,
         output_directory: EMPTY_DIGEST,
         execution_attempts: vec![],
+        source: ProcessResultSource::RanLocally,
+        termination_signal: None,
+        server_message: None,
+        timeout_details: None,
+        rewritten_action_digest: None,
+        output_size_anomaly: None,
+        platform: Platform::None,
       })
     };
     DelayedCommandRunner::new(
@@ -324,7 +359,7 @@ mod tests {
     fn extract_compatible_request(
       &self,
       req: &MultiPlatformExecuteProcessRequest,
-    ) -> Option<ExecuteProcessRequest> {
+    ) -> Option<Arc<ExecuteProcessRequest>> {
       if self.is_compatible {
         Some(
           req
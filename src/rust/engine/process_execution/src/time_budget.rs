@@ -0,0 +1,519 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! A ceiling on the wall time which a build spends waiting on remote operations, after which its
+//! requests run locally instead.
+//!
+//! CI jobs usually have a time budget of their own: when the remote execution service degrades
+//! part of the way through a build, finishing the build locally is better than blowing the job's
+//! timeout. Each request which RemoteTimeBudgetCommandRunner runs remotely adds the time that it
+//! waited on its operation (from submitting its ExecuteRequest until the operation completed, but
+//! not the upload of its inputs) to a shared RemoteTimeBudget. Requests which fail do not report
+//! their attempts, so all of the time that they spent in the remote runner is added instead: a
+//! degraded service is exactly when the budget should be used up. Once the budget is exceeded, new
+//! requests are run locally, while requests which are already running remotely finish there.
+//!
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use boxfuture::{BoxFuture, Boxable};
+use futures::Future;
+use log::warn;
+use workunit_store::WorkUnitStore;
+
+use crate::metrics;
+use crate::report::RemoteExecutionReport;
+use crate::{
+  sum_metrics, CommandRunner, ExecuteProcessRequest, ExecutionStats, FallibleExecuteProcessResult,
+  MultiPlatformExecuteProcessRequest, ProcessProgress,
+};
+
+///
+/// Cheap to clone: all clones share their accumulated time, so a budget which is created for a
+/// session (i.e. a build) bounds the remote time of the whole build.
+///
+#[derive(Clone)]
+pub struct RemoteTimeBudget {
+  budget: Duration,
+  state: Arc<BudgetState>,
+}
+
+#[derive(Default)]
+struct BudgetState {
+  spent_micros: AtomicU64,
+  // Requests which were run locally because the budget was exceeded.
+  redirected: AtomicU64,
+  // Whether the cutover to local execution has been warned about.
+  warned: AtomicBool,
+}
+
+impl RemoteTimeBudget {
+  pub fn new(budget: Duration) -> RemoteTimeBudget {
+    RemoteTimeBudget {
+      budget,
+      state: Arc::new(BudgetState::default()),
+    }
+  }
+
+  ///
+  /// Adds the time which the attempts of a remote request waited on their operations.
+  ///
+  fn record(&self, attempts: &[ExecutionStats]) {
+    self.spend(
+      attempts
+        .iter()
+        .flat_map(|attempt| attempt.operation_wait.into_iter().chain(attempt.polling))
+        .sum(),
+    );
+  }
+
+  fn spend(&self, waited: Duration) {
+    self
+      .state
+      .spent_micros
+      .fetch_add(waited.as_micros() as u64, Ordering::SeqCst);
+  }
+
+  ///
+  /// The wall time spent waiting on remote operations so far.
+  ///
+  pub fn spent(&self) -> Duration {
+    Duration::from_micros(self.state.spent_micros.load(Ordering::SeqCst))
+  }
+
+  pub fn is_exceeded(&self) -> bool {
+    self.spent() > self.budget
+  }
+
+  ///
+  /// The number of requests which were run locally, because the budget was exceeded.
+  ///
+  pub fn redirected(&self) -> u64 {
+    self.state.redirected.load(Ordering::SeqCst)
+  }
+
+  ///
+  /// Adds the spent time, whether the budget is exceeded, and the redirected requests to a
+  /// metrics snapshot.
+  ///
+  pub fn record_metrics(&self, snapshot: &mut HashMap<&'static str, i64>) {
+    snapshot.insert(
+      metrics::REMOTE_TIME_BUDGET_SPENT_MILLIS,
+      self.spent().as_millis() as i64,
+    );
+    snapshot.insert(
+      metrics::REMOTE_TIME_BUDGET_EXCEEDED,
+      self.is_exceeded() as i64,
+    );
+    snapshot.insert(
+      metrics::REMOTE_TIME_BUDGET_REDIRECTED_REQUESTS,
+      self.redirected() as i64,
+    );
+  }
+}
+
+#[derive(Clone)]
+pub struct RemoteTimeBudgetCommandRunner {
+  remote: Arc<dyn CommandRunner>,
+  local: Arc<dyn CommandRunner>,
+  budget: RemoteTimeBudget,
+  report: Option<RemoteExecutionReport>,
+}
+
+impl RemoteTimeBudgetCommandRunner {
+  pub fn new(
+    remote: Box<dyn CommandRunner>,
+    local: Box<dyn CommandRunner>,
+    budget: RemoteTimeBudget,
+  ) -> RemoteTimeBudgetCommandRunner {
+    RemoteTimeBudgetCommandRunner {
+      remote: remote.into(),
+      local: local.into(),
+      budget,
+      report: None,
+    }
+  }
+
+  ///
+  /// Notes the cutover to local execution (once) as a degradation in the given report.
+  ///
+  pub fn with_report(mut self, report: RemoteExecutionReport) -> RemoteTimeBudgetCommandRunner {
+    self.report = Some(report);
+    self
+  }
+
+  ///
+  /// Whether the request should run locally: if the budget is exceeded, and the local runner can
+  /// run it. Requests which only the remote runner can run are still run remotely.
+  ///
+  fn redirect(&self, req: &MultiPlatformExecuteProcessRequest) -> bool {
+    if !self.budget.is_exceeded() || self.local.extract_compatible_request(req).is_none() {
+      return false;
+    }
+    self.budget.state.redirected.fetch_add(1, Ordering::SeqCst);
+    if !self.budget.state.warned.swap(true, Ordering::SeqCst) {
+      let message = format!(
+        "The remote execution time budget of {:?} for this build is exhausted, after {:?} spent \
+         waiting on remote operations: requests which are already running remotely will finish, \
+         but this and all later requests will run locally. The number of redirected requests is \
+         reported as the {} metric.",
+        self.budget.budget,
+        self.budget.spent(),
+        metrics::REMOTE_TIME_BUDGET_REDIRECTED_REQUESTS
+      );
+      warn!("{}", message);
+      if let Some(ref report) = self.report {
+        report.record_degradation(message);
+      }
+    }
+    true
+  }
+
+  ///
+  /// Whether the request runs locally: either because only the local runner can run it (e.g.
+  /// because it is `ExecutionLocality::LocalOnly`), or because it is redirected.
+  ///
+  fn runs_locally(&self, req: &MultiPlatformExecuteProcessRequest) -> bool {
+    if self.remote.extract_compatible_request(req).is_none() {
+      self.local.extract_compatible_request(req).is_some()
+    } else {
+      self.redirect(req)
+    }
+  }
+
+  fn run_remotely(
+    &self,
+    req: MultiPlatformExecuteProcessRequest,
+    progress: Option<ProcessProgress>,
+    workunit_store: WorkUnitStore,
+  ) -> BoxFuture<FallibleExecuteProcessResult, String> {
+    let budget = self.budget.clone();
+    let start = Instant::now();
+    let run = match progress {
+      Some(progress) => self.remote.run_with_progress(req, progress, workunit_store),
+      None => self.remote.run(req, workunit_store),
+    };
+    run
+      .then(move |result| {
+        match result {
+          Ok(ref result) => budget.record(&result.execution_attempts),
+          Err(_) => budget.spend(start.elapsed()),
+        }
+        result
+      })
+      .to_boxed()
+  }
+}
+
+impl CommandRunner for RemoteTimeBudgetCommandRunner {
+  fn run(
+    &self,
+    req: MultiPlatformExecuteProcessRequest,
+    workunit_store: WorkUnitStore,
+  ) -> BoxFuture<FallibleExecuteProcessResult, String> {
+    if self.runs_locally(&req) {
+      self.local.run(req, workunit_store)
+    } else {
+      self.run_remotely(req, None, workunit_store)
+    }
+  }
+
+  fn run_with_progress(
+    &self,
+    req: MultiPlatformExecuteProcessRequest,
+    progress: ProcessProgress,
+    workunit_store: WorkUnitStore,
+  ) -> BoxFuture<FallibleExecuteProcessResult, String> {
+    if self.runs_locally(&req) {
+      self.local.run_with_progress(req, progress, workunit_store)
+    } else {
+      self.run_remotely(req, Some(progress), workunit_store)
+    }
+  }
+
+  fn extract_compatible_request(
+    &self,
+    req: &MultiPlatformExecuteProcessRequest,
+  ) -> Option<Arc<ExecuteProcessRequest>> {
+    self
+      .remote
+      .extract_compatible_request(req)
+      .or_else(|| self.local.extract_compatible_request(req))
+  }
+
+  fn metrics(&self) -> HashMap<&'static str, i64> {
+    let mut snapshot = sum_metrics(vec![self.remote.metrics(), self.local.metrics()]);
+    self.budget.record_metrics(&mut snapshot);
+    snapshot
+  }
+}
+
+#[cfg(all(test, feature = "remote-execution"))]
+mod tests {
+  use std::sync::{Arc, Mutex};
+  use std::time::{Duration, Instant};
+
+  use boxfuture::{BoxFuture, Boxable};
+  use futures::{future, Future};
+  use hashing::EMPTY_DIGEST;
+  use testutil::as_bytes;
+  use testutil::data::{TestData, TestDirectory};
+  use tokio_timer::Delay;
+  use workunit_store::WorkUnitStore;
+
+  use super::{RemoteTimeBudget, RemoteTimeBudgetCommandRunner};
+  use crate::metrics;
+  use crate::remote::tests::{
+    create_command_runner_for_platform, echo_foo_request, empty_request_metadata,
+    make_delayed_incomplete_operation, make_successful_operation, StderrType, StdoutType,
+  };
+  use crate::report::RemoteExecutionReport;
+  use crate::{
+    CommandRunner, ExecuteProcessRequest, ExecutionLocality, ExecutionStats,
+    FallibleExecuteProcessResult, MultiPlatformExecuteProcessRequest, Platform,
+    ProcessResultSource,
+  };
+
+  #[test]
+  fn requests_run_locally_once_the_budget_is_exceeded() {
+    let request = echo_foo_request();
+    let execute_request = crate::remote::make_execute_request(
+      request.0.values().next().unwrap(),
+      empty_request_metadata(),
+    )
+    .unwrap()
+    .2;
+    // The execution waits on its operation for longer than the whole budget.
+    let mock_server = mock::execution_server::TestServer::new(
+      mock::execution_server::MockExecution::new(
+        "gimme-foo".to_owned(),
+        execute_request,
+        vec![
+          make_delayed_incomplete_operation("gimme-foo", Duration::from_millis(50)),
+          make_successful_operation(
+            "gimme-foo",
+            StdoutType::Raw("remote".to_owned()),
+            StderrType::Raw("".to_owned()),
+            0,
+          ),
+        ],
+      ),
+      None,
+    );
+    let cas = mock::StubCAS::builder()
+      .file(&TestData::roland())
+      .directory(&TestDirectory::containing_roland())
+      .build();
+    let local = StubCommandRunner::new();
+    let budget = RemoteTimeBudget::new(Duration::from_millis(10));
+    let report = RemoteExecutionReport::new(10);
+    let runner = RemoteTimeBudgetCommandRunner::new(
+      Box::new(create_command_runner_for_platform(
+        mock_server.address(),
+        &cas,
+        Platform::Linux,
+      )),
+      Box::new(local.clone()),
+      budget.clone(),
+    )
+    .with_report(report.clone());
+
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let first = runtime
+      .block_on(runner.run(request.clone(), WorkUnitStore::new()))
+      .unwrap();
+    assert_eq!(first.stdout, as_bytes("remote"));
+    assert!(budget.is_exceeded());
+
+    // The second request starts after the budget was exceeded, so runs locally.
+    let second = runtime
+      .block_on(runner.run(request, WorkUnitStore::new()))
+      .unwrap();
+    assert_eq!(second.stdout, as_bytes("local"));
+    assert_eq!(local.requests.lock().unwrap().len(), 1);
+
+    let metrics = runner.metrics();
+    assert_eq!(metrics[metrics::REMOTE_TIME_BUDGET_EXCEEDED], 1);
+    assert_eq!(metrics[metrics::REMOTE_TIME_BUDGET_REDIRECTED_REQUESTS], 1);
+    assert!(metrics[metrics::REMOTE_TIME_BUDGET_SPENT_MILLIS] >= 50);
+    let json: serde_json::Value = serde_json::from_str(&report.to_json().unwrap()).unwrap();
+    assert_eq!(json["degradations"].as_array().unwrap().len(), 1);
+  }
+
+  #[test]
+  fn remote_only_requests_are_never_redirected() {
+    let request: MultiPlatformExecuteProcessRequest = ExecuteProcessRequest {
+      execution_locality: ExecutionLocality::RemoteOnly,
+      ..(*echo_foo_request().0[&(Platform::None, Platform::None)]).clone()
+    }
+    .into();
+    // Nothing listens on the port once the listener has been dropped.
+    let dead_address = std::net::TcpListener::bind("127.0.0.1:0")
+      .unwrap()
+      .local_addr()
+      .unwrap()
+      .to_string();
+    let local = StubCommandRunner::new();
+    let budget = RemoteTimeBudget::new(Duration::from_millis(10));
+    budget.record(&[ExecutionStats {
+      operation_wait: Some(Duration::from_millis(50)),
+      ..ExecutionStats::default()
+    }]);
+    assert!(budget.is_exceeded());
+    let runner = RemoteTimeBudgetCommandRunner::new(
+      Box::new(create_command_runner_for_platform(
+        dead_address,
+        &mock::StubCAS::empty(),
+        Platform::Linux,
+      )),
+      Box::new(local.clone()),
+      budget,
+    );
+
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime
+      .block_on(runner.run(request, WorkUnitStore::new()))
+      .expect_err("A request which may only run remotely should fail with the remote.");
+    assert!(local.requests.lock().unwrap().is_empty());
+    assert_eq!(
+      runner.metrics()[metrics::REMOTE_TIME_BUDGET_REDIRECTED_REQUESTS],
+      0
+    );
+  }
+
+  #[test]
+  fn failed_requests_use_up_the_budget() {
+    let local = StubCommandRunner::new();
+    let budget = RemoteTimeBudget::new(Duration::from_millis(10));
+    let runner = RemoteTimeBudgetCommandRunner::new(
+      Box::new(FailingCommandRunner {
+        delay: Duration::from_millis(50),
+      }),
+      Box::new(local.clone()),
+      budget.clone(),
+    );
+
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime
+      .block_on(runner.run(echo_foo_request(), WorkUnitStore::new()))
+      .expect_err("Want the error of the remote runner");
+    // The failed request reported no attempts, but the time that it took is counted.
+    assert!(budget.spent() >= Duration::from_millis(50));
+    assert!(budget.is_exceeded());
+
+    let result = runtime
+      .block_on(runner.run(echo_foo_request(), WorkUnitStore::new()))
+      .unwrap();
+    assert_eq!(result.stdout, as_bytes("local"));
+    assert_eq!(local.requests.lock().unwrap().len(), 1);
+  }
+
+  #[test]
+  fn budget_accumulates_operation_waits_and_polling() {
+    let budget = RemoteTimeBudget::new(Duration::from_millis(100));
+    let result = FallibleExecuteProcessResult {
+      execution_attempts: vec![
+        ExecutionStats {
+          upload: Duration::from_secs(10),
+          operation_wait: Some(Duration::from_millis(30)),
+          polling: Some(Duration::from_millis(40)),
+          ..ExecutionStats::default()
+        },
+        ExecutionStats {
+          operation_wait: Some(Duration::from_millis(20)),
+          ..ExecutionStats::default()
+        },
+      ],
+      ..StubCommandRunner::result()
+    };
+    budget.record(&result.execution_attempts);
+    // Uploads are not counted.
+    assert_eq!(budget.spent(), Duration::from_millis(90));
+    assert!(!budget.is_exceeded());
+    budget.clone().record(&result.execution_attempts);
+    assert!(budget.is_exceeded());
+  }
+
+  ///
+  /// Fails every request, after the given delay.
+  ///
+  struct FailingCommandRunner {
+    delay: Duration,
+  }
+
+  impl CommandRunner for FailingCommandRunner {
+    fn run(
+      &self,
+      _req: MultiPlatformExecuteProcessRequest,
+      _workunit_store: WorkUnitStore,
+    ) -> BoxFuture<FallibleExecuteProcessResult, String> {
+      Delay::new(Instant::now() + self.delay)
+        .then(|_| Err("The remote execution service is degraded".to_owned()))
+        .to_boxed()
+    }
+
+    fn extract_compatible_request(
+      &self,
+      req: &MultiPlatformExecuteProcessRequest,
+    ) -> Option<Arc<ExecuteProcessRequest>> {
+      req.0.values().next().cloned()
+    }
+  }
+
+  #[derive(Clone)]
+  struct StubCommandRunner {
+    requests: Arc<Mutex<Vec<ExecuteProcessRequest>>>,
+  }
+
+  impl StubCommandRunner {
+    fn new() -> StubCommandRunner {
+      StubCommandRunner {
+        requests: Arc::new(Mutex::new(vec![])),
+      }
+    }
+
+    fn result() -> FallibleExecuteProcessResult {
+      FallibleExecuteProcessResult {
+        stdout: as_bytes("local").into(),
+        stderr: as_bytes("").into(),
+        exit_code: 0,
+        output_directory: EMPTY_DIGEST,
+        execution_attempts: vec![],
+        source: ProcessResultSource::RanLocally,
+        termination_signal: None,
+        server_message: None,
+        timeout_details: None,
+        rewritten_action_digest: None,
+        output_size_anomaly: None,
+        platform: Platform::None,
+      }
+    }
+  }
+
+  impl CommandRunner for StubCommandRunner {
+    fn run(
+      &self,
+      req: MultiPlatformExecuteProcessRequest,
+      _workunit_store: WorkUnitStore,
+    ) -> BoxFuture<FallibleExecuteProcessResult, String> {
+      let req = self.extract_compatible_request(&req).unwrap();
+      self.requests.lock().unwrap().push((*req).clone());
+      future::ok(StubCommandRunner::result()).to_boxed()
+    }
+
+    fn extract_compatible_request(
+      &self,
+      req: &MultiPlatformExecuteProcessRequest,
+    ) -> Option<Arc<ExecuteProcessRequest>> {
+      req
+        .0
+        .get(&(Platform::None, Platform::None))
+        .filter(|req| req.execution_locality.allows_local())
+        .cloned()
+    }
+  }
+}
@@ -0,0 +1,529 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! An accumulator for a trace of the remote executions of a run, which can be written out in the
+//! JSON array format of Chrome's trace events (as loaded by chrome://tracing or Perfetto), to see
+//! where the time of each action went, both on the client and on the server.
+//!
+//! Each endpoint is a process of the trace, and each worker which ran actions for it is a thread
+//! (actions which did not name a worker are spread over "slot" threads, as are actions which
+//! overlap on one worker). The timestamps which a server reports are by its own clock, so they are
+//! shifted by an estimate of how far that clock is skewed from the client's (see estimate_skew).
+//!
+
+use std::cmp::{self, Reverse};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bazel_protos::remote_execution::ExecutedActionMetadata;
+use protobuf::well_known_types::Timestamp;
+use serde_derive::Serialize;
+use serde_json::Value;
+
+use crate::remote::REMOTE_TIME_SPANS;
+use crate::report::write_replacing;
+use crate::ExecutionStats;
+
+///
+/// The timings of one remote execution, which completed or failed.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct ActionTrace {
+  pub description: String,
+  // The endpoint which the action was sent to, which is the process of its slices.
+  pub endpoint: String,
+  // When the client started the request, by the client's clock.
+  pub start: SystemTime,
+  pub duration: Duration,
+  pub attempts: Vec<ExecutionStats>,
+  // The metadata which the server reported for the last attempt, by the server's clock.
+  pub metadata: Option<ExecutedActionMetadata>,
+  // Why the execution failed, if it did. The attempts of a failed execution are those which were
+  // retried before the one which failed.
+  pub error: Option<String>,
+}
+
+// A slice of an action, in microseconds since the epoch by the client's clock.
+struct Slice {
+  name: String,
+  start: i64,
+  end: i64,
+  args: BTreeMap<&'static str, Value>,
+}
+
+#[derive(Debug, Serialize)]
+struct TraceEvent {
+  name: String,
+  ph: &'static str,
+  // In microseconds since the start of the earliest action: only set for complete events.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  ts: Option<i64>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  dur: Option<i64>,
+  pid: usize,
+  tid: usize,
+  args: BTreeMap<&'static str, Value>,
+}
+
+impl TraceEvent {
+  fn metadata(
+    name: &str,
+    pid: usize,
+    tid: usize,
+    args: BTreeMap<&'static str, Value>,
+  ) -> TraceEvent {
+    TraceEvent {
+      name: name.to_owned(),
+      ph: "M",
+      ts: None,
+      dur: None,
+      pid,
+      tid,
+      args,
+    }
+  }
+}
+
+///
+/// Cheap to clone: all clones append to the same trace.
+///
+#[derive(Clone, Default)]
+pub struct TraceCollector {
+  actions: Arc<Mutex<Vec<ActionTrace>>>,
+}
+
+impl TraceCollector {
+  pub fn new() -> TraceCollector {
+    TraceCollector::default()
+  }
+
+  pub fn record_action(&self, action: ActionTrace) {
+    self.actions.lock().unwrap().push(action);
+  }
+
+  ///
+  /// Moves the actions recorded so far into a new TraceCollector, so that (for example) each run
+  /// of a long-lived process can be written as a trace of its own.
+  ///
+  pub fn take(&self) -> TraceCollector {
+    let actions = std::mem::replace(&mut *self.actions.lock().unwrap(), vec![]);
+    TraceCollector {
+      actions: Arc::new(Mutex::new(actions)),
+    }
+  }
+
+  pub fn to_json(&self) -> Result<String, String> {
+    let actions = self.actions.lock().unwrap().clone();
+    serde_json::to_string_pretty(&trace_events(&actions))
+      .map_err(|e| format!("Error serializing Chrome trace: {}", e))
+  }
+
+  ///
+  /// Writes the trace to the given path, replacing it atomically (like
+  /// `RemoteExecutionReport::write_to`).
+  ///
+  pub fn write_chrome_trace(&self, path: &Path) -> Result<(), String> {
+    let json = self.to_json()?;
+    write_replacing(path, json.as_bytes(), "Chrome trace")
+  }
+}
+
+fn trace_events(actions: &[ActionTrace]) -> Vec<TraceEvent> {
+  let mut actions = actions.iter().collect::<Vec<_>>();
+  actions.sort_by_key(|action| action.start);
+  let origin = actions
+    .first()
+    .map_or(0, |action| micros_since_epoch(action.start));
+
+  let mut pids: BTreeMap<&str, usize> = BTreeMap::new();
+  for action in &actions {
+    let next_pid = pids.len() + 1;
+    pids.entry(action.endpoint.as_str()).or_insert(next_pid);
+  }
+  let skews = pids
+    .keys()
+    .map(|&endpoint| {
+      let skew = estimate_skew(
+        actions
+          .iter()
+          .filter(|action| action.endpoint == endpoint)
+          .cloned(),
+      );
+      (endpoint, skew)
+    })
+    .collect::<HashMap<_, _>>();
+
+  let mut events = vec![];
+  for (&endpoint, &pid) in &pids {
+    let mut args = BTreeMap::new();
+    args.insert("name", Value::from(endpoint));
+    args.insert("clock_skew_us", Value::from(skews[endpoint]));
+    events.push(TraceEvent::metadata("process_name", pid, 0, args));
+  }
+
+  // The lanes of each endpoint: their names, and when the last action in each of them ends.
+  let mut lanes: HashMap<&str, Vec<(String, i64)>> = HashMap::new();
+  let mut slices = vec![];
+  for action in &actions {
+    let (mut action_slices, execute_window) = client_slices(action);
+    let top = (action_slices[0].start, action_slices[0].end);
+    if let Some(ref metadata) = action.metadata {
+      let (start, end) = execute_window.unwrap_or(top);
+      action_slices.extend(server_slices(
+        metadata,
+        skews[&*action.endpoint],
+        start,
+        end,
+      ));
+    }
+
+    let worker = action
+      .metadata
+      .as_ref()
+      .map(ExecutedActionMetadata::get_worker)
+      .filter(|worker| !worker.is_empty());
+    let endpoint_lanes = lanes
+      .entry(action.endpoint.as_str())
+      .or_insert_with(Vec::new);
+    let tid = assign_lane(endpoint_lanes, worker, top);
+    let pid = pids[&*action.endpoint];
+    slices.extend(action_slices.into_iter().map(|slice| (pid, tid, slice)));
+  }
+
+  let mut endpoint_lanes = lanes.into_iter().collect::<Vec<_>>();
+  endpoint_lanes.sort_by_key(|&(endpoint, _)| pids[endpoint]);
+  for (endpoint, lanes) in endpoint_lanes {
+    for (index, (name, _)) in lanes.into_iter().enumerate() {
+      let mut args = BTreeMap::new();
+      args.insert("name", Value::from(name));
+      events.push(TraceEvent::metadata(
+        "thread_name",
+        pids[endpoint],
+        index + 1,
+        args,
+      ));
+    }
+  }
+
+  // Slices which start together are ordered outermost first, as viewers expect. The sort is
+  // stable, so otherwise they stay in the order in which each action produced them.
+  slices.sort_by_key(|(_, _, slice)| (slice.start, Reverse(slice.end - slice.start)));
+  events.extend(slices.into_iter().map(|(pid, tid, slice)| TraceEvent {
+    name: slice.name,
+    ph: "X",
+    ts: Some(slice.start - origin),
+    dur: Some(slice.end - slice.start),
+    pid,
+    tid,
+    args: slice.args,
+  }));
+  events
+}
+
+///
+/// The slices of the client's time for an action: one for the whole action, containing those of
+/// each phase of each attempt in turn, with the window in which the client waited for the server
+/// to execute the last attempt (if it did).
+///
+fn client_slices(action: &ActionTrace) -> (Vec<Slice>, Option<(i64, i64)>) {
+  let start = micros_since_epoch(action.start);
+  let end = start + action.duration.as_micros() as i64;
+  let mut args = BTreeMap::new();
+  if let Some(ref error) = action.error {
+    args.insert("error", Value::from(error.as_str()));
+  }
+  let mut slices = vec![Slice {
+    name: action.description.clone(),
+    start,
+    end,
+    args,
+  }];
+  let mut execute_window = None;
+  let mut at = start;
+  for (index, attempt) in action.attempts.iter().enumerate() {
+    let execute = match (attempt.operation_wait, attempt.polling) {
+      (None, None) => None,
+      (operation_wait, polling) => {
+        Some(operation_wait.unwrap_or_default() + polling.unwrap_or_default())
+      }
+    };
+    execute_window = None;
+    for &(name, duration) in &[
+      ("upload", Some(attempt.upload)),
+      ("execute", execute),
+      ("download", attempt.download),
+    ] {
+      let duration = match duration {
+        Some(duration) => duration,
+        None => continue,
+      };
+      // Phases are clamped to the action, in case they were measured to outlast it.
+      let phase_start = cmp::min(at, end);
+      let phase_end = cmp::min(at + duration.as_micros() as i64, end);
+      let mut args = BTreeMap::new();
+      args.insert("attempt", Value::from(index + 1));
+      if name == "execute" {
+        execute_window = Some((phase_start, phase_end));
+      }
+      slices.push(Slice {
+        name: name.to_owned(),
+        start: phase_start,
+        end: phase_end,
+        args,
+      });
+      at = phase_end;
+    }
+  }
+  (slices, execute_window)
+}
+
+///
+/// The slices of the server's time for an action, shifted by the skew of its clock, and clamped to
+/// the window in which the client waited for them.
+///
+fn server_slices(
+  metadata: &ExecutedActionMetadata,
+  skew: i64,
+  window_start: i64,
+  window_end: i64,
+) -> Vec<Slice> {
+  let clamp = |micros: i64| cmp::max(window_start, cmp::min(micros + skew, window_end));
+  let mut slices = vec![];
+  for span in REMOTE_TIME_SPANS {
+    let (start, end) = match (
+      timestamp_micros((span.start)(metadata)),
+      timestamp_micros((span.end)(metadata)),
+    ) {
+      (Some(start), Some(end)) if start <= end => (start, end),
+      _ => continue,
+    };
+    let mut args = BTreeMap::new();
+    args.insert("worker", Value::from(metadata.get_worker()));
+    slices.push(Slice {
+      name: span.description.to_owned(),
+      start: clamp(start),
+      end: clamp(end),
+      args,
+    });
+  }
+  slices
+}
+
+///
+/// Estimates the number of microseconds to add to an endpoint's timestamps to convert them to the
+/// client's clock, from the actions which ran on it.
+///
+/// Each action bounds the skew: the whole of the server's time for it must fall within the window
+/// in which the client waited for it. The skew is the smallest (in magnitude) which is within the
+/// bounds of every action; if there is none (because the windows of the actions include network
+/// latency which varies between them), it is the median of the skews which would centre the
+/// server's time for each action in its window.
+///
+fn estimate_skew<'a, I: Iterator<Item = &'a ActionTrace>>(actions: I) -> i64 {
+  let bounds = actions
+    .filter_map(|action| {
+      let (_, window) = client_slices(action);
+      let (window_start, window_end) = window?;
+      let (server_start, server_end) = server_span(action.metadata.as_ref()?)?;
+      Some((window_start - server_start, window_end - server_end))
+    })
+    .collect::<Vec<_>>();
+  if bounds.is_empty() {
+    return 0;
+  }
+  let lowest = bounds.iter().map(|&(lowest, _)| lowest).max().unwrap();
+  let highest = bounds.iter().map(|&(_, highest)| highest).min().unwrap();
+  if lowest <= highest {
+    return cmp::max(lowest, cmp::min(0, highest));
+  }
+  let mut centres = bounds
+    .iter()
+    .map(|&(lowest, highest)| lowest + (highest - lowest) / 2)
+    .collect::<Vec<_>>();
+  centres.sort();
+  centres[centres.len() / 2]
+}
+
+///
+/// The earliest start and latest end of the spans of the given metadata, if any are set.
+///
+fn server_span(metadata: &ExecutedActionMetadata) -> Option<(i64, i64)> {
+  let starts = REMOTE_TIME_SPANS
+    .iter()
+    .filter_map(|span| timestamp_micros((span.start)(metadata)));
+  let ends = REMOTE_TIME_SPANS
+    .iter()
+    .filter_map(|span| timestamp_micros((span.end)(metadata)));
+  Some((starts.min()?, ends.max()?))
+}
+
+///
+/// Finds the lowest lane of the given worker (or the lowest slot, if there is no worker) which is
+/// free for the whole of the given span, or adds one, and returns its tid.
+///
+fn assign_lane(lanes: &mut Vec<(String, i64)>, worker: Option<&str>, span: (i64, i64)) -> usize {
+  let (start, end) = span;
+  let names = (1..).map(|n| match (worker, n) {
+    (Some(worker), 1) => worker.to_owned(),
+    (Some(worker), n) => format!("{} ({})", worker, n),
+    (None, n) => format!("slot {}", n),
+  });
+  for name in names {
+    match lanes.iter().position(|(lane, _)| *lane == name) {
+      Some(index) => {
+        if lanes[index].1 <= start {
+          lanes[index].1 = end;
+          return index + 1;
+        }
+      }
+      None => {
+        lanes.push((name, end));
+        return lanes.len();
+      }
+    }
+  }
+  unreachable!()
+}
+
+fn micros_since_epoch(time: SystemTime) -> i64 {
+  match time.duration_since(UNIX_EPOCH) {
+    Ok(duration) => duration.as_micros() as i64,
+    Err(e) => -(e.duration().as_micros() as i64),
+  }
+}
+
+// Unset Timestamps are omitted by servers which do not measure their span.
+fn timestamp_micros(timestamp: &Timestamp) -> Option<i64> {
+  if timestamp.get_seconds() == 0 && timestamp.get_nanos() == 0 {
+    None
+  } else {
+    Some(timestamp.get_seconds() * 1_000_000 + i64::from(timestamp.get_nanos()) / 1000)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::{Duration, UNIX_EPOCH};
+
+  use bazel_protos::remote_execution::ExecutedActionMetadata;
+  use protobuf::well_known_types::Timestamp;
+
+  use super::{estimate_skew, trace_events, ActionTrace, TraceEvent};
+  use crate::ExecutionStats;
+
+  fn timestamp(micros: i64) -> Timestamp {
+    let mut timestamp = Timestamp::new();
+    timestamp.set_seconds(micros / 1_000_000);
+    timestamp.set_nanos((micros % 1_000_000) as i32 * 1000);
+    timestamp
+  }
+
+  ///
+  /// An action which starts at the given second, uploads for 1ms, waits 8ms for the server and
+  /// downloads for 1ms; and which the server (by its clock, `skew` behind the client's) queued
+  /// and executed for 2ms each within that wait.
+  ///
+  fn action(description: &str, start_secs: u64, worker: &str, skew: i64) -> ActionTrace {
+    let execute_start = (start_secs * 1_000_000) as i64 + 1000 - skew;
+    let mut metadata = ExecutedActionMetadata::new();
+    metadata.set_worker(worker.to_owned());
+    metadata.set_queued_timestamp(timestamp(execute_start + 1000));
+    metadata.set_worker_start_timestamp(timestamp(execute_start + 3000));
+    metadata.set_execution_start_timestamp(timestamp(execute_start + 3000));
+    metadata.set_execution_completed_timestamp(timestamp(execute_start + 5000));
+    ActionTrace {
+      description: description.to_owned(),
+      endpoint: "grpc://example.com".to_owned(),
+      start: UNIX_EPOCH + Duration::from_secs(start_secs),
+      duration: Duration::from_millis(10),
+      attempts: vec![ExecutionStats {
+        upload: Duration::from_millis(1),
+        operation_wait: Some(Duration::from_millis(2)),
+        polling: Some(Duration::from_millis(6)),
+        download: Some(Duration::from_millis(1)),
+        ..ExecutionStats::default()
+      }],
+      metadata: Some(metadata),
+      error: None,
+    }
+  }
+
+  fn names_of_thread(events: &[TraceEvent], tid: usize) -> Vec<&str> {
+    events
+      .iter()
+      .filter(|event| event.ph == "X" && event.tid == tid)
+      .map(|event| event.name.as_str())
+      .collect()
+  }
+
+  #[test]
+  fn skew_is_the_smallest_which_fits_every_action() {
+    let actions = vec![
+      action("one", 100, "w", 500_000),
+      action("two", 200, "w", 500_000),
+    ];
+    // The server's time for each action may move up to 1ms within the 8ms wait.
+    assert_eq!(estimate_skew(actions.iter()), 500_000 - 1000);
+    assert_eq!(estimate_skew(vec![action("one", 100, "w", 0)].iter()), 0);
+  }
+
+  #[test]
+  fn skew_is_the_median_if_no_skew_fits_every_action() {
+    let actions = vec![
+      action("one", 100, "w", 100_000),
+      action("two", 200, "w", 200_000),
+      action("three", 300, "w", 300_000),
+    ];
+    // Centring the server's time for an action in its wait needs 1ms more than its skew.
+    assert_eq!(estimate_skew(actions.iter()), 200_000 + 1000);
+  }
+
+  #[test]
+  fn overlapping_actions_on_a_worker_are_separate_threads() {
+    let mut overlapping = action("two", 100, "w", 0);
+    overlapping.start += Duration::from_millis(5);
+    let mut unnamed = action("three", 100, "", 0);
+    unnamed.start += Duration::from_millis(20);
+    unnamed.metadata = None;
+    let events = trace_events(&[action("one", 100, "w", 0), overlapping, unnamed]);
+
+    let threads = events
+      .iter()
+      .filter(|event| event.name == "thread_name")
+      .map(|event| (event.tid, event.args["name"].as_str().unwrap()))
+      .collect::<Vec<_>>();
+    assert_eq!(threads, vec![(1, "w"), (2, "w (2)"), (3, "slot 1")]);
+    assert_eq!(
+      names_of_thread(&events, 1),
+      vec![
+        "one",
+        "upload",
+        "execute",
+        "remote queue",
+        "remote execution",
+        "download"
+      ]
+    );
+    assert_eq!(
+      names_of_thread(&events, 3),
+      vec!["three", "upload", "execute", "download"]
+    );
+  }
+
+  #[test]
+  fn failed_actions_are_traced_with_their_error() {
+    let mut failed = action("one", 100, "w", 0);
+    failed.error = Some("Connection reset".to_owned());
+    let events = trace_events(&[failed, action("two", 200, "w", 0)]);
+
+    let errors = events
+      .iter()
+      .filter(|event| event.ph == "X" && event.args.contains_key("error"))
+      .map(|event| (event.name.as_str(), event.args["error"].as_str().unwrap()))
+      .collect::<Vec<_>>();
+    assert_eq!(errors, vec![("one", "Connection reset")]);
+  }
+}
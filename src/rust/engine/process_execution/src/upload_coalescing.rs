@@ -0,0 +1,377 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! Coalescing of the uploads which servers trigger by reporting that the inputs of an Action are
+//! missing.
+//!
+//! A server which is sharded across CAS replicas may report the same blobs missing from several
+//! Actions within milliseconds of each other, and each of their runs would otherwise upload them
+//! again. Instead, the digests which are uploaded for this reason are remembered for a short
+//! window: a run which is told that they are missing waits for an upload of them which is already
+//! in flight, or skips uploading them if they were uploaded within the window.
+//!
+//! A server which garbage collects aggressively may really have lost blobs which were uploaded
+//! moments ago, so each run only skips an upload of a digest once: if the server reports it missing
+//! again, the run uploads it regardless.
+//!
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use boxfuture::{BoxFuture, Boxable};
+use futures::future::{self, Shared};
+use futures::sync::oneshot;
+use futures::Future;
+use hashing::Digest;
+use store::UploadSummary;
+use tokio_timer::Delay;
+
+///
+/// Cheap to clone: all clones share the uploads which they have made.
+///
+#[derive(Clone)]
+pub struct UploadCoalescer {
+  window: Duration,
+  settle_delay: Duration,
+  uploads: Arc<Mutex<HashMap<Digest, Upload>>>,
+}
+
+enum Upload {
+  // Completed (or failed) when the upload does.
+  InFlight(Shared<oneshot::Receiver<()>>),
+  Completed(Instant),
+}
+
+///
+/// The digests which a run registered as InFlight. Unless the run completes their upload, they are
+/// removed when it is dropped: otherwise a run which was dropped mid-upload would leave them
+/// InFlight forever.
+///
+struct Registration {
+  uploads: Arc<Mutex<HashMap<Digest, Upload>>>,
+  digests: Vec<Digest>,
+}
+
+impl Registration {
+  fn complete(mut self, succeeded: bool) {
+    let digests = std::mem::replace(&mut self.digests, vec![]);
+    let mut uploads = self.uploads.lock().unwrap();
+    for digest in digests {
+      if succeeded {
+        uploads.insert(digest, Upload::Completed(Instant::now()));
+      } else {
+        uploads.remove(&digest);
+      }
+    }
+  }
+}
+
+impl Drop for Registration {
+  fn drop(&mut self) {
+    if self.digests.is_empty() {
+      return;
+    }
+    let mut uploads = self.uploads.lock().unwrap();
+    for digest in &self.digests {
+      uploads.remove(digest);
+    }
+  }
+}
+
+///
+/// The result of `UploadCoalescer::upload`.
+///
+#[derive(Debug)]
+pub struct CoalescedUpload {
+  pub summary: UploadSummary,
+  // The number of digests which were not uploaded, because they were being or had recently been
+  // uploaded by another run.
+  pub coalesced: usize,
+}
+
+impl UploadCoalescer {
+  ///
+  /// Uploads are remembered for the given window after they complete. A run whose missing digests
+  /// were all uploaded within the window waits for the settle_delay before it re-executes, to give
+  /// the server's replicas a chance to see the blobs.
+  ///
+  pub fn new(window: Duration, settle_delay: Duration) -> UploadCoalescer {
+    UploadCoalescer {
+      window,
+      settle_delay,
+      uploads: Arc::new(Mutex::new(HashMap::new())),
+    }
+  }
+
+  ///
+  /// Uploads (via `upload`) the given digests which a server reported missing from one run, other
+  /// than those which another run is uploading or has recently uploaded. `coalesced` holds the
+  /// digests which this run has already skipped uploading, which are uploaded regardless.
+  ///
+  pub fn upload<F>(
+    &self,
+    digests: Vec<Digest>,
+    coalesced: &Mutex<HashSet<Digest>>,
+    upload: F,
+  ) -> BoxFuture<CoalescedUpload, String>
+  where
+    F: FnOnce(Vec<Digest>) -> BoxFuture<UploadSummary, String>,
+  {
+    let now = Instant::now();
+    let (sender, receiver) = oneshot::channel();
+    let receiver = receiver.shared();
+    let mut to_upload = vec![];
+    let mut registered = vec![];
+    let mut waits = vec![];
+    let mut skipped = 0;
+    {
+      let mut uploads = self.uploads.lock().unwrap();
+      let mut coalesced = coalesced.lock().unwrap();
+      let window = self.window;
+      uploads.retain(|_, upload| match upload {
+        Upload::InFlight(_) => true,
+        Upload::Completed(at) => now.duration_since(*at) < window,
+      });
+      for digest in digests {
+        match uploads.get(&digest) {
+          Some(_) if coalesced.contains(&digest) => to_upload.push(digest),
+          Some(Upload::InFlight(upload)) => {
+            coalesced.insert(digest);
+            waits.push(upload.clone());
+            skipped += 1;
+          }
+          Some(Upload::Completed(_)) => {
+            coalesced.insert(digest);
+            skipped += 1;
+          }
+          None => {
+            uploads.insert(digest, Upload::InFlight(receiver.clone()));
+            registered.push(digest);
+            to_upload.push(digest);
+          }
+        }
+      }
+    }
+
+    let all_recently_uploaded = to_upload.is_empty() && waits.is_empty();
+    let uploaded = if to_upload.is_empty() {
+      future::ok(UploadSummary {
+        ingested_file_count: 0,
+        ingested_file_bytes: 0,
+        uploaded_file_count: 0,
+        uploaded_file_bytes: 0,
+        upload_wall_time: Duration::default(),
+        upload_queue_time: Duration::default(),
+      })
+      .to_boxed()
+    } else {
+      let registration = Registration {
+        uploads: self.uploads.clone(),
+        digests: registered,
+      };
+      upload(to_upload)
+        .then(move |result| {
+          registration.complete(result.is_ok());
+          // Runs which are waiting for the upload proceed whether it succeeded or failed: if it
+          // failed, the server will report the digests missing again.
+          let _ = sender.send(());
+          result
+        })
+        .to_boxed()
+    };
+    // A wait ends when the upload completes, or when its run is dropped while it is in flight.
+    let waited = future::join_all(
+      waits
+        .into_iter()
+        .map(|wait| wait.then(|_| Ok::<(), String>(()))),
+    );
+    let settled = if all_recently_uploaded {
+      Delay::new(now + self.settle_delay)
+        .map_err(|e| format!("Future-Delay errored waiting for coalesced uploads: {}", e))
+        .to_boxed()
+    } else {
+      future::ok(()).to_boxed()
+    };
+    uploaded
+      .join3(waited, settled)
+      .map(move |(summary, _, ())| CoalescedUpload {
+        summary,
+        coalesced: skipped,
+      })
+      .to_boxed()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashSet;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::sync::{Arc, Mutex};
+  use std::time::{Duration, Instant};
+
+  use boxfuture::{BoxFuture, Boxable};
+  use futures::sync::oneshot;
+  use futures::{future, Future};
+  use hashing::Digest;
+  use store::UploadSummary;
+  use testutil::data::TestData;
+
+  use super::UploadCoalescer;
+
+  fn summary(count: usize) -> UploadSummary {
+    UploadSummary {
+      ingested_file_count: count,
+      ingested_file_bytes: 0,
+      uploaded_file_count: count,
+      uploaded_file_bytes: 0,
+      upload_wall_time: Duration::default(),
+      upload_queue_time: Duration::default(),
+    }
+  }
+
+  fn counting_upload(
+    uploaded: &Arc<Mutex<Vec<Digest>>>,
+  ) -> impl FnOnce(Vec<Digest>) -> BoxFuture<UploadSummary, String> {
+    let uploaded = uploaded.clone();
+    move |digests| {
+      let count = digests.len();
+      uploaded.lock().unwrap().extend(digests);
+      future::ok(summary(count)).to_boxed()
+    }
+  }
+
+  #[test]
+  fn concurrent_uploads_of_a_digest_are_coalesced() {
+    let coalescer = UploadCoalescer::new(Duration::from_secs(5), Duration::from_millis(0));
+    let roland = TestData::roland().digest();
+    let catnip = TestData::catnip().digest();
+    let (release, released) = oneshot::channel::<()>();
+    let uploads = Arc::new(AtomicUsize::new(0));
+
+    let first = coalescer.upload(vec![roland], &Mutex::new(HashSet::new()), {
+      let uploads = uploads.clone();
+      move |digests| {
+        uploads.fetch_add(digests.len(), Ordering::SeqCst);
+        released
+          .map(|()| summary(1))
+          .map_err(|e| format!("{:?}", e))
+          .to_boxed()
+      }
+    });
+    let coalesced = Mutex::new(HashSet::new());
+    let second = coalescer.upload(vec![roland, catnip], &coalesced, {
+      let uploads = uploads.clone();
+      move |digests| {
+        assert_eq!(digests, vec![catnip]);
+        uploads.fetch_add(digests.len(), Ordering::SeqCst);
+        future::ok(summary(1)).to_boxed()
+      }
+    });
+
+    // The second upload of roland waits for the first, which is still in flight.
+    release.send(()).unwrap();
+    let (first, second) = first.join(second).wait().unwrap();
+    assert_eq!(first.coalesced, 0);
+    assert_eq!(second.coalesced, 1);
+    assert_eq!(second.summary, summary(1));
+    assert_eq!(uploads.load(Ordering::SeqCst), 2);
+    assert_eq!(
+      *coalesced.lock().unwrap(),
+      vec![roland].into_iter().collect::<HashSet<_>>()
+    );
+  }
+
+  #[test]
+  fn dropped_uploads_are_not_waited_for() {
+    let coalescer = UploadCoalescer::new(Duration::from_secs(5), Duration::from_millis(0));
+    let roland = TestData::roland().digest();
+    let (_release, released) = oneshot::channel::<()>();
+
+    let dropped = coalescer.upload(vec![roland], &Mutex::new(HashSet::new()), move |_| {
+      released
+        .map(|()| summary(1))
+        .map_err(|e| format!("{:?}", e))
+        .to_boxed()
+    });
+    std::mem::drop(dropped);
+
+    // Had the dropped run left roland InFlight, this run would skip uploading it.
+    let uploaded = Arc::new(Mutex::new(vec![]));
+    let upload = coalescer
+      .upload(
+        vec![roland],
+        &Mutex::new(HashSet::new()),
+        counting_upload(&uploaded),
+      )
+      .wait()
+      .unwrap();
+    assert_eq!(upload.coalesced, 0);
+    assert_eq!(*uploaded.lock().unwrap(), vec![roland]);
+  }
+
+  #[test]
+  fn recent_uploads_are_skipped_once_per_run() {
+    let coalescer = UploadCoalescer::new(Duration::from_secs(5), Duration::from_millis(100));
+    let roland = TestData::roland().digest();
+    let uploaded = Arc::new(Mutex::new(vec![]));
+    coalescer
+      .upload(
+        vec![roland],
+        &Mutex::new(HashSet::new()),
+        counting_upload(&uploaded),
+      )
+      .wait()
+      .unwrap();
+
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let coalesced = Mutex::new(HashSet::new());
+    let start = Instant::now();
+    let skipped = runtime
+      .block_on(coalescer.upload(vec![roland], &coalesced, counting_upload(&uploaded)))
+      .unwrap();
+    assert_eq!(skipped.coalesced, 1);
+    assert!(start.elapsed() >= Duration::from_millis(100));
+    assert_eq!(*uploaded.lock().unwrap(), vec![roland]);
+
+    // Reported missing again by the same run: the server may really have lost it.
+    let retried = runtime
+      .block_on(coalescer.upload(vec![roland], &coalesced, counting_upload(&uploaded)))
+      .unwrap();
+    assert_eq!(retried.coalesced, 0);
+    assert_eq!(*uploaded.lock().unwrap(), vec![roland, roland]);
+  }
+
+  #[test]
+  fn uploads_are_forgotten_after_the_window_or_on_failure() {
+    let coalescer = UploadCoalescer::new(Duration::from_millis(50), Duration::from_millis(0));
+    let roland = TestData::roland().digest();
+    let uploaded = Arc::new(Mutex::new(vec![]));
+    let failed = coalescer
+      .upload(vec![roland], &Mutex::new(HashSet::new()), |_| {
+        future::err("Unavailable".to_owned()).to_boxed()
+      })
+      .wait();
+    assert_eq!(failed.map(|_| ()), Err("Unavailable".to_owned()));
+
+    coalescer
+      .upload(
+        vec![roland],
+        &Mutex::new(HashSet::new()),
+        counting_upload(&uploaded),
+      )
+      .wait()
+      .unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+    coalescer
+      .upload(
+        vec![roland],
+        &Mutex::new(HashSet::new()),
+        counting_upload(&uploaded),
+      )
+      .wait()
+      .unwrap();
+    assert_eq!(*uploaded.lock().unwrap(), vec![roland, roland]);
+  }
+}
@@ -0,0 +1,54 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! Bounds on the blobs which are uploaded to each remote CAS at once.
+//!
+//! Each remote execution uploads its inputs with the Store's own parallelism, so when many actions
+//! upload at the same time, the connections which they open to the CAS add up, until they exhaust
+//! the client's file descriptors or the server's connection limits. An upload gate is a semaphore
+//! which the remote CommandRunners for one CAS endpoint share: their Stores take one of its
+//! permits for each blob which they upload (see `Store::with_upload_limiter`).
+//!
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use async_semaphore::AsyncSemaphore;
+
+///
+/// The upload gate of each CAS endpoint, which all have the same number of permits. Cheap to
+/// clone: all clones share their gates.
+///
+#[derive(Clone)]
+pub struct UploadGates {
+  permits: usize,
+  gates: Arc<Mutex<HashMap<String, AsyncSemaphore>>>,
+}
+
+impl UploadGates {
+  ///
+  /// Each gate allows the given number of blobs (at least one) to be uploaded at once.
+  ///
+  pub fn new(permits: usize) -> UploadGates {
+    UploadGates {
+      permits: permits.max(1),
+      gates: Arc::new(Mutex::new(HashMap::new())),
+    }
+  }
+
+  ///
+  /// The gate of the CAS at the given address, which is shared by every CommandRunner which is
+  /// given the gate of that address (see `remote::CommandRunner::with_upload_gate`).
+  ///
+  pub fn for_endpoint(&self, address: &str) -> AsyncSemaphore {
+    let permits = self.permits;
+    self
+      .gates
+      .lock()
+      .unwrap()
+      .entry(address.to_owned())
+      .or_insert_with(|| AsyncSemaphore::new(permits))
+      .clone()
+  }
+}
@@ -0,0 +1,492 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! A CommandRunner which re-runs a sample of remotely executed requests locally, and reports any
+//! differences between the results, to detect divergence between remote workers and the local
+//! toolchain.
+//!
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use boxfuture::{BoxFuture, Boxable};
+use futures::{future, Future};
+use hashing::Digest;
+use log::{debug, warn};
+use workunit_store::WorkUnitStore;
+
+use crate::{
+  render_output_preview, sum_metrics, CommandRunner, ExecuteProcessRequest,
+  ExecuteProcessRequestMetadata, FallibleExecuteProcessResult, MultiPlatformExecuteProcessRequest,
+  ProcessOutput, ProcessProgress,
+};
+
+///
+/// A field of a FallibleExecuteProcessResult which differed between the remote and local runs of
+/// a request.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FieldMismatch {
+  pub field: &'static str,
+  pub remote: String,
+  pub local: String,
+}
+
+///
+/// The differences between the remote and local results of a sampled request.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ResultMismatch {
+  pub action_digest: Digest,
+  pub description: String,
+  pub fields: Vec<FieldMismatch>,
+}
+
+impl fmt::Display for ResultMismatch {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "Remote and local results of {} (action {}) differ:",
+      self.description, self.action_digest.0
+    )?;
+    for mismatch in &self.fields {
+      write!(
+        f,
+        "\n  {}:\n    remote: {}\n    local: {}",
+        mismatch.field, mismatch.remote, mismatch.local
+      )?;
+    }
+    Ok(())
+  }
+}
+
+pub type MismatchReporter = Arc<dyn Fn(&ResultMismatch) + Send + Sync>;
+
+#[derive(Clone)]
+pub struct VerifyingCommandRunner {
+  remote: Arc<dyn CommandRunner>,
+  local: Arc<dyn CommandRunner>,
+  metadata: ExecuteProcessRequestMetadata,
+  sample_rate: f64,
+  reporter: MismatchReporter,
+}
+
+impl VerifyingCommandRunner {
+  ///
+  /// A sample_rate of 1.0 (or more) verifies every request, and 0.0 (or less) verifies none. The
+  /// metadata should be that of the remote runner, so that the action digests which decide the
+  /// sample (and which are reported) are those that the remote runner executes.
+  ///
+  pub fn new(
+    remote: Box<dyn CommandRunner>,
+    local: Box<dyn CommandRunner>,
+    metadata: ExecuteProcessRequestMetadata,
+    sample_rate: f64,
+    reporter: MismatchReporter,
+  ) -> VerifyingCommandRunner {
+    VerifyingCommandRunner {
+      remote: remote.into(),
+      local: local.into(),
+      metadata,
+      sample_rate,
+      reporter,
+    }
+  }
+
+  ///
+  /// Whether the request with the given action digest should be verified. The decision depends
+  /// only on the digest, so reruns of an action are consistently either verified or not.
+  ///
+  fn is_sampled(&self, action_digest: &Digest) -> bool {
+    is_sampled(self.sample_rate, action_digest)
+  }
+
+  ///
+  /// Returns the action digest of the request if it should be verified, or None if it should only
+  /// run remotely.
+  ///
+  fn sample(&self, req: &MultiPlatformExecuteProcessRequest) -> Option<(Digest, String)> {
+    let remote_req = self.remote.extract_compatible_request(req)?;
+    self.local.extract_compatible_request(req)?;
+    let action_digest = action_digest(&remote_req, self.metadata.clone())
+      .map_err(|err| debug!("Not verifying {}: {}", remote_req.description, err))
+      .ok()?;
+    if self.is_sampled(&action_digest) {
+      Some((action_digest, remote_req.description.clone()))
+    } else {
+      None
+    }
+  }
+
+  fn verify(
+    &self,
+    req: MultiPlatformExecuteProcessRequest,
+    progress: ProcessProgress,
+    workunit_store: WorkUnitStore,
+    action_digest: Digest,
+    description: String,
+  ) -> BoxFuture<FallibleExecuteProcessResult, String> {
+    let reporter = self.reporter.clone();
+    // NB: The remote result is only returned once the local run has also completed, so a sampled
+    // request takes as long as the slower of its two runs.
+    self
+      .remote
+      .run_with_progress(req.clone(), progress, workunit_store.clone())
+      .then(future::ok::<_, String>)
+      .join(self.local.run(req, workunit_store).then(future::ok))
+      .and_then(move |(remote_result, local_result)| {
+        match (&remote_result, local_result) {
+          (Ok(remote), Ok(local)) => {
+            let fields = compare(remote, &local);
+            if !fields.is_empty() {
+              let mismatch = ResultMismatch {
+                action_digest,
+                description,
+                fields,
+              };
+              warn!("{}", mismatch);
+              reporter(&mismatch);
+            }
+          }
+          (Ok(_), Err(err)) => debug!("Could not verify {} locally: {}", description, err),
+          // The remote failure is returned as is: there is nothing to verify.
+          (Err(_), _) => {}
+        }
+        remote_result
+      })
+      .to_boxed()
+  }
+}
+
+impl CommandRunner for VerifyingCommandRunner {
+  fn extract_compatible_request(
+    &self,
+    req: &MultiPlatformExecuteProcessRequest,
+  ) -> Option<Arc<ExecuteProcessRequest>> {
+    self.remote.extract_compatible_request(req)
+  }
+
+  fn metrics(&self) -> HashMap<&'static str, i64> {
+    sum_metrics(vec![self.remote.metrics(), self.local.metrics()])
+  }
+
+  fn run(
+    &self,
+    req: MultiPlatformExecuteProcessRequest,
+    workunit_store: WorkUnitStore,
+  ) -> BoxFuture<FallibleExecuteProcessResult, String> {
+    self.run_with_progress(req, ProcessProgress::new(), workunit_store)
+  }
+
+  fn run_with_progress(
+    &self,
+    req: MultiPlatformExecuteProcessRequest,
+    progress: ProcessProgress,
+    workunit_store: WorkUnitStore,
+  ) -> BoxFuture<FallibleExecuteProcessResult, String> {
+    match self.sample(&req) {
+      Some((action_digest, description)) => {
+        self.verify(req, progress, workunit_store, action_digest, description)
+      }
+      None => self.remote.run_with_progress(req, progress, workunit_store),
+    }
+  }
+}
+
+///
+/// Whether an action is in a sample of the given rate. A sample_rate of 1.0 (or more) includes
+/// every action, and 0.0 (or less) includes none.
+///
+pub(crate) fn is_sampled(sample_rate: f64, action_digest: &Digest) -> bool {
+  if sample_rate >= 1.0 {
+    return true;
+  }
+  if sample_rate <= 0.0 {
+    return false;
+  }
+  let mut prefix = [0; 8];
+  prefix.copy_from_slice(&action_digest.0.as_bytes()[..8]);
+  (u64::from_be_bytes(prefix) as f64) < sample_rate * (std::u64::MAX as f64)
+}
+
+pub(crate) fn action_digest(
+  req: &ExecuteProcessRequest,
+  metadata: ExecuteProcessRequestMetadata,
+) -> Result<Digest, String> {
+  let (_, _, execute_request) = crate::action::make_execute_request(req, metadata)?;
+  execute_request.get_action_digest().into()
+}
+
+///
+/// Compares the fields of two results which are expected to be equal for a deterministic process,
+/// regardless of where it ran.
+///
+pub(crate) fn compare(
+  remote: &FallibleExecuteProcessResult,
+  local: &FallibleExecuteProcessResult,
+) -> Vec<FieldMismatch> {
+  let mut fields = vec![];
+  let mut compare_field = |field, remote: String, local: String| {
+    if remote != local {
+      fields.push(FieldMismatch {
+        field,
+        remote,
+        local,
+      });
+    }
+  };
+  compare_field(
+    "exit_code",
+    remote.exit_code.to_string(),
+    local.exit_code.to_string(),
+  );
+  compare_field(
+    "termination_signal",
+    format!("{:?}", remote.termination_signal),
+    format!("{:?}", local.termination_signal),
+  );
+  compare_field(
+    "output_directory",
+    format!("{:?}", remote.output_directory),
+    format!("{:?}", local.output_directory),
+  );
+  compare_field(
+    "stdout",
+    render_output(&remote.stdout),
+    render_output(&local.stdout),
+  );
+  compare_field(
+    "stderr",
+    render_output(&remote.stderr),
+    render_output(&local.stderr),
+  );
+  fields
+}
+
+///
+/// Spilled outputs are rendered (and so compared) by their digest, without loading them.
+///
+fn render_output(output: &ProcessOutput) -> String {
+  match output {
+    ProcessOutput::Inline(bytes) | ProcessOutput::Unpersisted(bytes, _) => {
+      format!("{} bytes: {:?}", bytes.len(), render_output_preview(bytes))
+    }
+    ProcessOutput::Stored(_) => output.to_string(),
+  }
+}
+
+#[cfg(all(test, feature = "remote-execution"))]
+mod tests {
+  use crate::remote::tests::echo_foo_request;
+  use boxfuture::{BoxFuture, Boxable};
+  use hashing::{Digest, Fingerprint, EMPTY_DIGEST};
+  use std::collections::BTreeMap;
+  use std::sync::{Arc, Mutex};
+  use testutil::as_bytes;
+  use workunit_store::WorkUnitStore;
+
+  use super::{FieldMismatch, ResultMismatch, VerifyingCommandRunner};
+  use crate::{
+    CommandRunner, EnvRecording, ExecuteProcessRequest, ExecuteProcessRequestMetadata,
+    FallibleExecuteProcessResult, MultiPlatformExecuteProcessRequest, Platform,
+    ProcessResultSource,
+  };
+
+  #[test]
+  fn disagreement_is_reported() {
+    let local_result = FallibleExecuteProcessResult {
+      exit_code: 1,
+      stderr: as_bytes("gcc: not found").into(),
+      source: ProcessResultSource::RanLocally,
+      ..result()
+    };
+    let (remote_runs, local_runs, mismatches, runner) = verifying_runner(1.0, local_result);
+
+    let result = run(&runner).unwrap();
+
+    assert_eq!(result, self::result());
+    assert_eq!(*remote_runs.lock().unwrap(), 1);
+    assert_eq!(*local_runs.lock().unwrap(), 1);
+    let mismatches = mismatches.lock().unwrap();
+    assert_eq!(mismatches.len(), 1);
+    assert_eq!(
+      mismatches[0].fields,
+      vec![
+        FieldMismatch {
+          field: "exit_code",
+          remote: "0".to_owned(),
+          local: "1".to_owned(),
+        },
+        FieldMismatch {
+          field: "stderr",
+          remote: "0 bytes: \"\"".to_owned(),
+          local: "14 bytes: \"gcc: not found\"".to_owned(),
+        },
+      ]
+    );
+    assert_eq!(mismatches[0].description, "echo a foo");
+  }
+
+  #[test]
+  fn agreement_is_not_reported() {
+    // Only fields which are expected to differ between environments do so.
+    let local_result = FallibleExecuteProcessResult {
+      source: ProcessResultSource::RanLocally,
+      ..result()
+    };
+    let (remote_runs, local_runs, mismatches, runner) = verifying_runner(1.0, local_result);
+
+    assert_eq!(run(&runner).unwrap(), result());
+    assert_eq!(*remote_runs.lock().unwrap(), 1);
+    assert_eq!(*local_runs.lock().unwrap(), 1);
+    assert!(mismatches.lock().unwrap().is_empty());
+  }
+
+  #[test]
+  fn unsampled_requests_only_run_remotely() {
+    let (remote_runs, local_runs, mismatches, runner) = verifying_runner(0.0, result());
+
+    assert_eq!(run(&runner).unwrap(), result());
+    assert_eq!(*remote_runs.lock().unwrap(), 1);
+    assert_eq!(*local_runs.lock().unwrap(), 0);
+    assert!(mismatches.lock().unwrap().is_empty());
+  }
+
+  #[test]
+  fn sampling_is_deterministic_per_action_digest() {
+    let (_, _, _, runner) = verifying_runner(0.5, result());
+    let digests = (0..=255_u8)
+      .map(|byte| Digest(Fingerprint([byte; 32]), 1))
+      .collect::<Vec<_>>();
+
+    let sampled = digests
+      .iter()
+      .map(|digest| runner.is_sampled(digest))
+      .collect::<Vec<_>>();
+
+    assert_eq!(
+      sampled,
+      digests
+        .iter()
+        .map(|digest| runner.is_sampled(digest))
+        .collect::<Vec<_>>()
+    );
+    assert_eq!(sampled.iter().filter(|sampled| **sampled).count(), 128);
+  }
+
+  #[test]
+  fn mismatch_display_includes_digest_and_fields() {
+    let mismatch = ResultMismatch {
+      action_digest: EMPTY_DIGEST,
+      description: "echo a foo".to_owned(),
+      fields: vec![FieldMismatch {
+        field: "exit_code",
+        remote: "0".to_owned(),
+        local: "1".to_owned(),
+      }],
+    };
+    assert_eq!(
+      mismatch.to_string(),
+      format!(
+        "Remote and local results of echo a foo (action {}) differ:\n  exit_code:\n{}",
+        EMPTY_DIGEST.0, "    remote: 0\n    local: 1"
+      )
+    );
+  }
+
+  fn result() -> FallibleExecuteProcessResult {
+    FallibleExecuteProcessResult {
+      stdout: as_bytes("foo").into(),
+      stderr: as_bytes("").into(),
+      exit_code: 0,
+      output_directory: EMPTY_DIGEST,
+      execution_attempts: vec![],
+      source: ProcessResultSource::RanRemotely,
+      termination_signal: None,
+      server_message: None,
+      timeout_details: None,
+      rewritten_action_digest: None,
+      output_size_anomaly: None,
+      platform: Platform::None,
+    }
+  }
+
+  fn run(runner: &VerifyingCommandRunner) -> Result<FallibleExecuteProcessResult, String> {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on_all(runner.run(echo_foo_request(), WorkUnitStore::new()))
+  }
+
+  #[allow(clippy::type_complexity)]
+  fn verifying_runner(
+    sample_rate: f64,
+    local_result: FallibleExecuteProcessResult,
+  ) -> (
+    Arc<Mutex<usize>>,
+    Arc<Mutex<usize>>,
+    Arc<Mutex<Vec<ResultMismatch>>>,
+    VerifyingCommandRunner,
+  ) {
+    let remote = StubCommandRunner::new(result());
+    let local = StubCommandRunner::new(local_result);
+    let (remote_runs, local_runs) = (remote.runs.clone(), local.runs.clone());
+    let mismatches = Arc::new(Mutex::new(vec![]));
+    let runner = VerifyingCommandRunner::new(
+      Box::new(remote),
+      Box::new(local),
+      ExecuteProcessRequestMetadata {
+        instance_name: None,
+        cache_key_gen_version: None,
+        cache_scopes: BTreeMap::new(),
+        platform_properties: vec![],
+        timeout_excludes_queue: false,
+        argv_error_patterns: vec![],
+        argv_warning_bytes: None,
+        allow_lossy_env: false,
+        canonical_form_version: 0,
+        environments: BTreeMap::new(),
+        record_env_in_report: EnvRecording::Off,
+      },
+      sample_rate,
+      Arc::new({
+        let mismatches = mismatches.clone();
+        move |mismatch: &ResultMismatch| mismatches.lock().unwrap().push(mismatch.clone())
+      }),
+    );
+    (remote_runs, local_runs, mismatches, runner)
+  }
+
+  #[derive(Clone)]
+  struct StubCommandRunner {
+    result: FallibleExecuteProcessResult,
+    runs: Arc<Mutex<usize>>,
+  }
+
+  impl StubCommandRunner {
+    fn new(result: FallibleExecuteProcessResult) -> StubCommandRunner {
+      StubCommandRunner {
+        result,
+        runs: Arc::new(Mutex::new(0)),
+      }
+    }
+  }
+
+  impl CommandRunner for StubCommandRunner {
+    fn run(
+      &self,
+      _req: MultiPlatformExecuteProcessRequest,
+      _workunit_store: WorkUnitStore,
+    ) -> BoxFuture<FallibleExecuteProcessResult, String> {
+      *self.runs.lock().unwrap() += 1;
+      futures::future::ok(self.result.clone()).to_boxed()
+    }
+
+    fn extract_compatible_request(
+      &self,
+      req: &MultiPlatformExecuteProcessRequest,
+    ) -> Option<Arc<ExecuteProcessRequest>> {
+      req.0.get(&(Platform::None, Platform::None)).cloned()
+    }
+  }
+}
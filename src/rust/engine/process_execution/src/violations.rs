@@ -0,0 +1,222 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! Classifies the violations of the PreconditionFailures with which remote execution servers fail
+//! requests. The API only specifies the MISSING type (of blobs which the CAS does not have), but
+//! servers report other preconditions with their own types: Buildbarn, for example, fails actions
+//! whose input root is too large for the disks of its workers with INPUT_TOO_LARGE violations
+//! (in a FailedPrecondition or a ResourceExhausted status).
+//!
+//! The types which a CommandRunner understands are configured as a mapping of violation types to
+//! ViolationCategories (see `remote::CommandRunner::with_violation_categories`), which may be
+//! extended by --remote-execution-violation-categories.
+//!
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use bazel_protos;
+use bazel_protos::error_details::PreconditionFailure_Violation;
+use grpcio;
+use hashing::Digest;
+use protobuf::{self, Message};
+
+use crate::input_tree_stats::InputTreeStats;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, serde_derive::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ViolationCategory {
+  // A blob which the CAS does not have, whose subject is of the form blobs/<hash>/<size>.
+  MissingBlob,
+  // The inputs of the action are larger than the workers can accept.
+  InputsTooLarge,
+}
+
+///
+/// The violation types which are understood without configuration.
+///
+pub fn default_violation_categories() -> BTreeMap<String, ViolationCategory> {
+  let mut categories = BTreeMap::new();
+  categories.insert("MISSING".to_owned(), ViolationCategory::MissingBlob);
+  categories.insert(
+    "INPUT_TOO_LARGE".to_owned(),
+    ViolationCategory::InputsTooLarge,
+  );
+  categories
+}
+
+///
+/// Parses a JSON object which maps violation types to the names of their categories
+/// (`missing_blob` or `inputs_too_large`).
+///
+pub fn parse_violation_categories(
+  json: &str,
+) -> Result<BTreeMap<String, ViolationCategory>, String> {
+  serde_json::from_str(json)
+    .map_err(|err| format!("Invalid remote execution violation categories: {}", err))
+}
+
+///
+/// An action which the server would not run because its inputs are too large for its workers. The
+/// limit and actual size of the inputs are reported if they could be parsed from the violation,
+/// and the stats of the input root if the CommandRunner could compute them.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct InputsTooLarge {
+  pub limit: Option<u64>,
+  pub actual: Option<u64>,
+  pub input_root: Option<Digest>,
+  pub input_tree: Option<InputTreeStats>,
+  pub violation: PreconditionFailure_Violation,
+}
+
+impl InputsTooLarge {
+  ///
+  /// Finds the first violation of the given status which is categorized as InputsTooLarge, if it
+  /// is a FailedPrecondition or ResourceExhausted status with PreconditionFailure details.
+  ///
+  pub fn classify(
+    status: &bazel_protos::status::Status,
+    categories: &BTreeMap<String, ViolationCategory>,
+    input_root: Option<Digest>,
+  ) -> Option<InputsTooLarge> {
+    match grpcio::RpcStatusCode::from(status.get_code()) {
+      grpcio::RpcStatusCode::FailedPrecondition | grpcio::RpcStatusCode::ResourceExhausted => {}
+      _ => return None,
+    }
+    let full_name = bazel_protos::error_details::PreconditionFailure::new()
+      .descriptor()
+      .full_name();
+    // Undecodable details are left to be reported as they otherwise would be.
+    let violation = status
+      .get_details()
+      .iter()
+      .filter(|any| any.get_type_url().rsplit('/').next() == Some(full_name))
+      .filter_map(|any| {
+        protobuf::parse_from_bytes::<bazel_protos::error_details::PreconditionFailure>(
+          any.get_value(),
+        )
+        .ok()
+      })
+      .flat_map(|precondition_failure| precondition_failure.get_violations().to_vec())
+      .find(|violation| {
+        categories.get(violation.get_field_type()) == Some(&ViolationCategory::InputsTooLarge)
+      })?;
+    let (limit, actual) = parse_sizes(&format!(
+      "{} {}",
+      violation.get_subject(),
+      violation.get_description()
+    ));
+    Some(InputsTooLarge {
+      limit,
+      actual,
+      input_root,
+      input_tree: None,
+      violation,
+    })
+  }
+}
+
+impl fmt::Display for InputsTooLarge {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Remote execution rejected the request: its inputs")?;
+    if let Some(input_root) = self.input_root {
+      write!(f, " (input root {:?})", input_root)?;
+    }
+    write!(f, " are too large for the workers of the server")?;
+    match (self.actual, self.limit) {
+      (Some(actual), Some(limit)) => write!(f, ": {} bytes, of at most {}", actual, limit)?,
+      (Some(actual), None) => write!(f, ": {} bytes", actual)?,
+      (None, Some(limit)) => write!(f, ": they may be at most {} bytes", limit)?,
+      (None, None) => {}
+    }
+    if let Some(input_tree) = self.input_tree {
+      write!(
+        f,
+        " (locally, the input root holds {} bytes in {} files and {} directories)",
+        input_tree.bytes, input_tree.files, input_tree.dirs
+      )?;
+    }
+    write!(
+      f,
+      " ({} violation of {:?}: {:?}). Check that the process's inputs and output_files globs do \
+       not capture more files than it needs.",
+      self.violation.get_field_type(),
+      self.violation.get_subject(),
+      self.violation.get_description()
+    )
+  }
+}
+
+///
+/// Parses the limit and actual size from the text of a violation, such as "size=1200 limit=1000"
+/// or "12345 bytes exceeds the limit of 10000 bytes": numbers which follow a word like "limit" or
+/// "max" are limits, and the first other number is the actual size.
+///
+fn parse_sizes(text: &str) -> (Option<u64>, Option<u64>) {
+  let (mut limit, mut actual) = (None, None);
+  let mut follows_limit = false;
+  for word in text
+    .split(|c: char| !c.is_ascii_alphanumeric())
+    .filter(|word| !word.is_empty())
+  {
+    match word.parse::<u64>() {
+      Ok(number) => {
+        if follows_limit {
+          limit = limit.or(Some(number));
+        } else {
+          actual = actual.or(Some(number));
+        }
+        follows_limit = false;
+      }
+      Err(_) => match word.to_lowercase().as_str() {
+        "limit" | "max" | "maximum" | "allowed" => follows_limit = true,
+        "actual" | "size" | "total" => follows_limit = false,
+        _ => {}
+      },
+    }
+  }
+  (limit, actual)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{parse_sizes, parse_violation_categories, ViolationCategory};
+  use maplit::btreemap;
+
+  #[test]
+  fn sizes_are_parsed() {
+    assert_eq!(
+      parse_sizes("size=1200 limit=1000"),
+      (Some(1000), Some(1200))
+    );
+    assert_eq!(
+      parse_sizes("Input root is 12345 bytes, which exceeds the limit of 10000 bytes"),
+      (Some(10000), Some(12345))
+    );
+    assert_eq!(
+      parse_sizes("max_input_bytes: 10, actual: 20"),
+      (Some(10), Some(20))
+    );
+    assert_eq!(parse_sizes("inputs"), (None, None));
+  }
+
+  #[test]
+  fn violation_categories_are_parsed() {
+    assert_eq!(
+      parse_violation_categories(r#"{"DISK_FULL": "inputs_too_large", "GONE": "missing_blob"}"#),
+      Ok(btreemap! {
+        "DISK_FULL".to_owned() => ViolationCategory::InputsTooLarge,
+        "GONE".to_owned() => ViolationCategory::MissingBlob,
+      })
+    );
+    assert_eq!(parse_violation_categories("{}"), Ok(btreemap! {}));
+    let err = parse_violation_categories(r#"{"DISK_FULL": "full"}"#).unwrap_err();
+    assert!(
+      err.contains("Invalid remote execution violation categories"),
+      "{}",
+      err
+    );
+  }
+}
@@ -0,0 +1,199 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+#![deny(warnings)]
+// Enable all clippy lints except for many of the pedantic ones. It's a shame this needs to be copied and pasted across crates, but there doesn't appear to be a way to include inner attributes from a common source.
+#![deny(
+  clippy::all,
+  clippy::default_trait_access,
+  clippy::expl_impl_clone_on_copy,
+  clippy::if_not_else,
+  clippy::needless_continue,
+  clippy::single_match_else,
+  clippy::unseparated_literal_suffix,
+  clippy::used_underscore_binding
+)]
+// It is often more clear to show that nothing is being moved.
+#![allow(clippy::match_ref_pats)]
+// Subjective style.
+#![allow(
+  clippy::len_without_is_empty,
+  clippy::redundant_field_names,
+  clippy::too_many_arguments
+)]
+// Default isn't as big a deal as people seem to think it is.
+#![allow(clippy::new_without_default, clippy::new_ret_no_self)]
+// Arc<Mutex> can be more clear than needing to grok Orderings:
+#![allow(clippy::mutex_atomic)]
+
+use clap;
+use env_logger;
+
+use process_execution;
+
+use clap::{value_t, App, Arg};
+use process_execution::remote_conformance::{run_checks, Check};
+use process_execution::{EnvRecording, ExecuteProcessRequestMetadata, Platform};
+use std::collections::BTreeMap;
+use std::process::exit;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use store::{BackoffConfig, Store};
+use tokio::runtime::Runtime;
+
+/// A binary which runs a battery of conformance checks against a remote execution server and its
+/// CAS, and prints a report of which checks passed:
+///  remote_conformance --server=localhost:8980 --cas-server=localhost:8981
+///
+/// Exits non-zero if any check failed.
+fn main() {
+  env_logger::init();
+
+  let args = App::new("remote_conformance")
+    .arg(
+      Arg::with_name("server")
+        .long("server")
+        .takes_value(true)
+        .required(true)
+        .help("The host:port of the gRPC execution server to check."),
+    )
+    .arg(
+      Arg::with_name("cas-server")
+        .long("cas-server")
+        .takes_value(true)
+        .required(true)
+        .help("The host:port of the gRPC CAS server used by the execution server."),
+    )
+    .arg(
+      Arg::with_name("root-ca-cert-file")
+        .long("root-ca-cert-file")
+        .takes_value(true)
+        .required(false)
+        .help("Path to file containing root certificate authority certificates. If not set, TLS will not be used."),
+    )
+    .arg(
+      Arg::with_name("oauth-bearer-token-path")
+        .long("oauth-bearer-token-path")
+        .takes_value(true)
+        .required(false)
+        .help("Path to file containing oauth bearer token. If not set, no authorization will be provided to remote servers."),
+    )
+    .arg(
+      Arg::with_name("remote-instance-name")
+        .long("remote-instance-name")
+        .takes_value(true)
+        .required(false),
+    )
+    .arg(
+      Arg::with_name("local-store-path")
+        .long("local-store-path")
+        .takes_value(true)
+        .help("Path to lmdb directory used for local file storage"),
+    )
+    .arg(
+      Arg::with_name("nonce")
+        .long("nonce")
+        .takes_value(true)
+        .required(false)
+        .help("A value included in every request to avoid action cache hits. Defaults to the current time."),
+    )
+    .arg(
+      Arg::with_name("check")
+        .long("check")
+        .takes_value(true)
+        .multiple(true)
+        .required(false)
+        .help("The names of the checks to run. Defaults to all checks."),
+    )
+    .arg(
+      Arg::with_name("store-connection-limit")
+        .help("Number of concurrent servers to allow connections to.")
+        .takes_value(true)
+        .long("store-connection-limit")
+        .required(false)
+        .default_value("3"),
+    )
+    .get_matches();
+
+  let checks = match args.values_of("check") {
+    Some(names) => names
+      .map(Check::from_name)
+      .collect::<Result<Vec<_>, _>>()
+      .unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(2)
+      }),
+    None => Check::all(),
+  };
+  let nonce = args
+    .value_of("nonce")
+    .map(str::to_owned)
+    .unwrap_or_else(|| {
+      let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time is before the epoch");
+      format!("{}.{}", since_epoch.as_secs(), since_epoch.subsec_nanos())
+    });
+  let remote_instance_name = args.value_of("remote-instance-name").map(str::to_owned);
+  let root_ca_certs = args
+    .value_of("root-ca-cert-file")
+    .map(|path| std::fs::read(path).expect("Error reading root CA certs file"));
+  let oauth_bearer_token = args
+    .value_of("oauth-bearer-token-path")
+    .map(|path| std::fs::read_to_string(path).expect("Error reading oauth bearer token file"));
+  let local_store_path = args
+    .value_of("local-store-path")
+    .map(std::path::PathBuf::from)
+    .unwrap_or_else(Store::default_path);
+
+  let executor = task_executor::Executor::new();
+
+  let store = Store::with_remote(
+    executor.clone(),
+    local_store_path,
+    vec![args.value_of("cas-server").unwrap().to_owned()],
+    remote_instance_name.clone(),
+    root_ca_certs.clone(),
+    oauth_bearer_token.clone(),
+    None,
+    1,
+    3 * 1024 * 1024,
+    Duration::from_secs(30),
+    BackoffConfig::new(Duration::from_secs(1), 1.2, Duration::from_secs(20)).unwrap(),
+    3,
+    value_t!(args.value_of("store-connection-limit"), usize)
+      .expect("Bad store-connection-limit flag"),
+  )
+  .expect("Error making store");
+
+  let command_runner = process_execution::remote::CommandRunner::new(
+    args.value_of("server").unwrap(),
+    ExecuteProcessRequestMetadata {
+      instance_name: remote_instance_name,
+      cache_key_gen_version: None,
+      cache_scopes: BTreeMap::new(),
+      platform_properties: vec![],
+      timeout_excludes_queue: false,
+      argv_error_patterns: vec![],
+      argv_warning_bytes: None,
+      allow_lossy_env: false,
+      canonical_form_version: process_execution::CANONICAL_FORM_VERSION,
+      environments: BTreeMap::new(),
+      record_env_in_report: EnvRecording::Off,
+    },
+    root_ca_certs,
+    oauth_bearer_token,
+    None,
+    store.clone(),
+    Platform::Linux,
+    executor,
+  )
+  .expect("Error making remote command runner");
+
+  let mut runtime = Runtime::new().unwrap();
+  let report = runtime
+    .block_on(run_checks(checks, command_runner, store, nonce))
+    .expect("Conformance checks do not fail");
+
+  println!("{}", report);
+  exit(if report.passed() { 0 } else { 1 });
+}
@@ -33,14 +33,14 @@ use process_execution;
 
 use clap::{value_t, App, AppSettings, Arg};
 use hashing::{Digest, Fingerprint};
-use process_execution::{ExecuteProcessRequestMetadata, Platform};
+use process_execution::{EnvRecording, ExecuteProcessRequestMetadata, ExecutionLocality, Platform};
 use std::collections::{BTreeMap, BTreeSet};
 use std::convert::TryFrom;
 use std::iter::{FromIterator, Iterator};
 use std::path::PathBuf;
 use std::process::exit;
 use std::time::Duration;
-use store::{BackoffConfig, Store};
+use store::{BackoffConfig, ProxyConfig, Store};
 use tokio::runtime::Runtime;
 use workunit_store::WorkUnitStore;
 
@@ -124,14 +124,38 @@ fn main() {
             .long("cas-oauth-bearer-token-path")
             .required(false)
       )
+      .arg(Arg::with_name("proxy")
+          .help("URL of an HTTP CONNECT proxy (http://[username:password@]host:port) via which to connect to the execution and CAS servers.")
+          .takes_value(true)
+          .long("proxy")
+          .required(false))
       .arg(Arg::with_name("remote-instance-name")
           .takes_value(true)
           .long("remote-instance-name")
           .required(false))
+      .arg(Arg::with_name("operation-name-prefix")
+          .help("Prefix to add to operation names in GetOperation and CancelOperation requests, for servers which require qualified names (e.g. with the instance name).")
+          .takes_value(true)
+          .long("operation-name-prefix")
+          .required(false))
       .arg(Arg::with_name("cache-key-gen-version")
           .takes_value(true)
           .long("cache-key-gen-version")
           .required(false))
+      .arg(
+        Arg::with_name("cache-scope")
+            .help("The epochs of cache scopes, as name=epoch: the cache key of a request includes the epochs of the scopes which it belongs to.")
+            .takes_value(true)
+            .multiple(true)
+            .long("cache-scope")
+            .required(false))
+      .arg(
+        Arg::with_name("cache-scope-name")
+            .help("The names of the cache scopes which the request belongs to.")
+            .takes_value(true)
+            .multiple(true)
+            .long("cache-scope-name")
+            .required(false))
       .arg(
         Arg::with_name("upload-chunk-bytes")
             .help("Number of bytes to include per-chunk when uploading bytes. grpc imposes a hard message-size limit of around 4MB.")
@@ -147,6 +171,21 @@ fn main() {
         .multiple(true)
         .help("Extra platform properties to set on the execution request."),
     )
+    .arg(
+      Arg::with_name("timeout-excludes-queue")
+        .long("timeout-excludes-queue")
+        .takes_value(false)
+        .help(
+          "Measure the remote execution timeout from when the server reports the action started \
+           executing, rather than from when it was submitted.",
+        ),
+    )
+    .arg(
+      Arg::with_name("force-rerun")
+        .long("force-rerun")
+        .takes_value(false)
+        .help("Skip any cached result for the process, and re-execute it."),
+    )
     .arg(
       Arg::with_name("env")
         .long("env")
@@ -244,6 +283,10 @@ fn main() {
 
   let executor = task_executor::Executor::new();
 
+  let proxy = args
+    .value_of("proxy")
+    .map(|url| ProxyConfig::parse(url).expect("Bad proxy flag"));
+
   let store = match (server_arg, args.value_of("cas-server")) {
     (Some(_server), Some(cas_server)) => {
       let chunk_size =
@@ -268,6 +311,7 @@ fn main() {
         remote_instance_arg.clone(),
         root_ca_certs,
         oauth_bearer_token,
+        proxy.clone(),
         1,
         chunk_size,
         Duration::from_secs(30),
@@ -305,6 +349,23 @@ fn main() {
     jdk_home: args.value_of("jdk").map(PathBuf::from),
     target_platform: Platform::try_from(&args.value_of("target-platform").unwrap().to_string())
       .expect("invalid value for `target-platform"),
+    force_rerun: args.is_present("force-rerun"),
+    expected_output_digest: None,
+    diff_outputs: false,
+    ephemeral_input_digests: vec![],
+    scheduling_hints: None,
+    poll_interval_hint: None,
+    cache_scope_names: args
+      .values_of("cache-scope-name")
+      .map(|names| names.map(str::to_owned).collect())
+      .unwrap_or_default(),
+    argfile_threshold: None,
+    argfile_flag_template: None,
+    provenance: None,
+    environment: None,
+    omit_cache_key_gen_version: false,
+    expected_output_size: None,
+    execution_locality: ExecutionLocality::Any,
   };
 
   let runner: Box<dyn process_execution::CommandRunner> = match server_arg {
@@ -322,19 +383,37 @@ fn main() {
           None
         };
 
-      Box::new(process_execution::remote::CommandRunner::new(
+      let command_runner = process_execution::remote::CommandRunner::new(
         address,
         ExecuteProcessRequestMetadata {
           instance_name: remote_instance_arg,
           cache_key_gen_version: args.value_of("cache-key-gen-version").map(str::to_owned),
+          cache_scopes: args
+            .values_of("cache-scope")
+            .map(collection_from_keyvalues::<_, BTreeMap<_, _>>)
+            .unwrap_or_default(),
           platform_properties,
+          timeout_excludes_queue: args.is_present("timeout-excludes-queue"),
+          argv_error_patterns: process_execution::remote::default_argv_error_patterns(),
+          argv_warning_bytes: Some(process_execution::remote::DEFAULT_ARGV_WARNING_BYTES),
+          allow_lossy_env: false,
+          canonical_form_version: process_execution::CANONICAL_FORM_VERSION,
+          environments: BTreeMap::new(),
+          record_env_in_report: EnvRecording::Off,
         },
         root_ca_certs,
         oauth_bearer_token,
+        proxy,
         store.clone(),
         Platform::Linux,
         executor.clone(),
-      )) as Box<dyn process_execution::CommandRunner>
+      )
+      .expect("Error making remote command runner");
+      let command_runner = match args.value_of("operation-name-prefix") {
+        Some(prefix) => command_runner.with_operation_name_prefix(prefix.to_owned()),
+        None => command_runner,
+      };
+      Box::new(command_runner) as Box<dyn process_execution::CommandRunner>
     }
     None => Box::new(process_execution::local::CommandRunner::new(
       store.clone(),
@@ -356,8 +435,14 @@ fn main() {
       .unwrap();
   }
 
-  print!("{}", String::from_utf8(result.stdout.to_vec()).unwrap());
-  eprint!("{}", String::from_utf8(result.stderr.to_vec()).unwrap());
+  let stdout = runtime
+    .block_on(result.stdout_bytes(&store, WorkUnitStore::new()))
+    .unwrap();
+  let stderr = runtime
+    .block_on(result.stderr_bytes(&store, WorkUnitStore::new()))
+    .unwrap();
+  print!("{}", String::from_utf8(stdout.to_vec()).unwrap());
+  eprint!("{}", String::from_utf8(stderr.to_vec()).unwrap());
   exit(result.exit_code);
 }
 
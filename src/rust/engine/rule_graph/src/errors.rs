@@ -0,0 +1,154 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::collections::HashMap;
+
+use serde_derive::Serialize;
+
+///
+/// A dependency of a rule which could not be satisfied, along with the parameters that were
+/// available when it was attempted.
+///
+/// All of the fields are rendered strings, so that the structure can be serialized without
+/// knowledge of the concrete Rule type.
+///
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct DependencyKeyProvenance {
+  pub dependency_key: String,
+  pub product: String,
+  pub params: Vec<String>,
+  // The params rendered together, as they appear in error messages.
+  pub params_display: String,
+  // Explanations of why the dependency could not be satisfied, such as providers that were
+  // disabled.
+  pub notes: Vec<String>,
+}
+
+///
+/// A machine readable error from RuleGraph validation. `RuleGraph::validate` renders a list of
+/// these via `render_errors`.
+///
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(tag = "kind")]
+pub enum RuleGraphError {
+  // No rule (or param) could compute one or more of the dependencies of a rule.
+  UnsatisfiableRule {
+    rule_display: String,
+    missing: Vec<DependencyKeyProvenance>,
+  },
+  // More than one rule could compute a dependency of a rule with the same params.
+  AmbiguousProvider {
+    rule_display: String,
+    dependency: DependencyKeyProvenance,
+    candidates: Vec<String>,
+  },
+  // A rule was not used by any other rule, or by any root.
+  UnreachableRule {
+    rule_display: String,
+  },
+}
+
+impl RuleGraphError {
+  pub fn rule_display(&self) -> &str {
+    match self {
+      RuleGraphError::UnsatisfiableRule {
+        ref rule_display, ..
+      }
+      | RuleGraphError::AmbiguousProvider {
+        ref rule_display, ..
+      }
+      | RuleGraphError::UnreachableRule { ref rule_display } => rule_display,
+    }
+  }
+
+  ///
+  /// The reasons for this error, each with a (possibly empty) list of details.
+  ///
+  fn reasons(&self) -> Vec<(String, Vec<String>)> {
+    match self {
+      RuleGraphError::UnsatisfiableRule { ref missing, .. } => missing
+        .iter()
+        .map(|m| {
+          let reason = if m.params.is_empty() && m.notes.is_empty() {
+            format!(
+              "No rule was available to compute {}. Maybe declare it as a RootRule({})?",
+              m.dependency_key, m.product,
+            )
+          } else if m.params.is_empty() {
+            format!("No rule was available to compute {}", m.dependency_key)
+          } else {
+            format!(
+              "No rule was available to compute {} with parameter type{} {}",
+              m.dependency_key,
+              if m.params.len() > 1 { "s" } else { "" },
+              m.params_display,
+            )
+          };
+          if m.notes.is_empty() {
+            (reason, vec![])
+          } else {
+            (format!("{}: {}", reason, m.notes.join("; ")), vec![])
+          }
+        })
+        .collect(),
+      RuleGraphError::AmbiguousProvider {
+        ref dependency,
+        ref candidates,
+        ..
+      } => {
+        let params_clause = match dependency.params.len() {
+          0 => "",
+          1 => " with parameter type ",
+          _ => " with parameter types ",
+        };
+        vec![(
+          format!(
+            "Ambiguous rules to compute {}{}{}",
+            dependency.dependency_key, params_clause, dependency.params_display,
+          ),
+          candidates.clone(),
+        )]
+      }
+      RuleGraphError::UnreachableRule { .. } => {
+        vec![("Was not usable by any other @rule.".to_string(), vec![])]
+      }
+    }
+  }
+}
+
+///
+/// Renders RuleGraphErrors as human readable text, collated by rule.
+///
+pub fn render_errors(errors: &[RuleGraphError]) -> String {
+  let mut collated: HashMap<&str, Vec<(String, Vec<String>)>> = HashMap::new();
+  for error in errors {
+    collated
+      .entry(error.rule_display())
+      .or_insert_with(Vec::new)
+      .extend(error.reasons());
+  }
+
+  let mut msgs: Vec<String> = collated
+    .into_iter()
+    .map(|(rule, mut reasons)| {
+      reasons.sort_by(|l, r| l.0.cmp(&r.0));
+      reasons.dedup_by(|l, r| l.0 == r.0);
+      let errors = reasons
+        .into_iter()
+        .map(|(reason, mut details)| {
+          if details.is_empty() {
+            reason
+          } else {
+            details.sort();
+            format!("{}:\n      {}", reason, details.join("\n      "))
+          }
+        })
+        .collect::<Vec<_>>()
+        .join("\n    ");
+      format!("{}:\n    {}", rule, errors)
+    })
+    .collect();
+  msgs.sort();
+
+  format!("Rules with errors: {}\n  {}", msgs.len(), msgs.join("\n  "))
+}
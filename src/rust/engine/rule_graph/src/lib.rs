@@ -26,11 +26,15 @@
 // Arc<Mutex> can be more clear than needing to grok Orderings:
 #![allow(clippy::mutex_atomic)]
 
+mod errors;
+mod plan;
 mod rules;
 
 use std::collections::{hash_map, BTreeSet, HashMap, HashSet};
 use std::io;
 
+pub use crate::errors::{render_errors, DependencyKeyProvenance, RuleGraphError};
+pub use crate::plan::PlanNode;
 pub use crate::rules::{DependencyKey, Rule, TypeId};
 
 // TODO: Consider switching to HashSet and dropping the Ord bound from TypeId.
@@ -48,8 +52,7 @@ impl<R: Rule> UnreachableError<R> {
       rule,
       diagnostic: Diagnostic {
         params: ParamTypes::default(),
-        reason: "Was not usable by any other @rule.".to_string(),
-        details: vec![],
+        kind: DiagnosticKind::Unreachable,
       },
     }
   }
@@ -148,8 +151,26 @@ type ChosenDependency<'a, R> = (&'a <R as Rule>::DependencyKey, &'a Entry<R>);
 #[derive(Eq, Hash, PartialEq, Clone, Debug)]
 pub struct Diagnostic<T: TypeId> {
   params: ParamTypes<T>,
-  reason: String,
-  details: Vec<String>,
+  kind: DiagnosticKind,
+}
+
+// The rendered DependencyKeys and types that a Diagnostic refers to, which are converted into a
+// RuleGraphError during validation.
+#[derive(Eq, Hash, PartialEq, Clone, Debug)]
+enum DiagnosticKind {
+  // No rule was available to compute the dependency.
+  Missing {
+    dependency_key: String,
+    product: String,
+  },
+  // More than one rule was available to compute the dependency.
+  Ambiguous {
+    dependency_key: String,
+    product: String,
+    candidates: Vec<String>,
+  },
+  // The rule was not used by any other rule.
+  Unreachable,
 }
 
 enum ConstructGraphResult<R: Rule> {
@@ -226,6 +247,7 @@ impl<'t, R: Rule> GraphMaker<'t, R> {
       rule_dependency_edges: dependency_edges,
       unfulfillable_rules,
       unreachable_rules,
+      disabled_providers: vec![],
     }
   }
 
@@ -439,20 +461,10 @@ impl<'t, R: Rule> GraphMaker<'t, R> {
         // If no candidates were fulfillable, this rule is not fulfillable.
         unfulfillable_diagnostics.push(Diagnostic {
           params: params.clone(),
-          reason: if params.is_empty() {
-            format!(
-              "No rule was available to compute {}. Maybe declare it as a RootRule({})?",
-              dependency_key, product,
-            )
-          } else {
-            format!(
-              "No rule was available to compute {} with parameter type{} {}",
-              dependency_key,
-              if params.len() > 1 { "s" } else { "" },
-              params_str(&params),
-            )
+          kind: DiagnosticKind::Missing {
+            dependency_key: dependency_key.to_string(),
+            product: product.to_string(),
           },
-          details: vec![],
         });
       }
     }
@@ -630,21 +642,13 @@ impl<'t, R: Rule> GraphMaker<'t, R> {
           combination.push((key, chosen_entries[0]));
         }
         _ => {
-          let params_clause = match available_params.len() {
-            0 => "",
-            1 => " with parameter type ",
-            _ => " with parameter types ",
-          };
-
           return Err(Diagnostic {
             params: available_params.clone(),
-            reason: format!(
-              "Ambiguous rules to compute {}{}{}",
-              key,
-              params_clause,
-              params_str(&available_params),
-            ),
-            details: chosen_entries.into_iter().map(entry_str).collect(),
+            kind: DiagnosticKind::Ambiguous {
+              dependency_key: key.to_string(),
+              product: key.product().to_string(),
+              candidates: chosen_entries.into_iter().map(entry_str).collect(),
+            },
           });
         }
       }
@@ -761,12 +765,28 @@ impl<'t, R: Rule> GraphMaker<'t, R> {
 ///   The collections of dependencies are contained by RuleEdges objects.
 /// `unfulfillable_rules` A map of rule entries to collections of Diagnostics
 ///   containing the reasons why they were eliminated from the graph.
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct RuleGraph<R: Rule> {
   root_param_types: ParamTypes<R::TypeId>,
   rule_dependency_edges: RuleDependencyEdges<R>,
   unfulfillable_rules: UnfulfillableRuleMap<R>,
   unreachable_rules: Vec<UnreachableError<R>>,
+  disabled_providers: Vec<DisabledProvider<R::TypeId>>,
+}
+
+///
+/// A provider of a product from an input which was deliberately not installed (for example,
+/// because it was disabled by configuration). Errors for the dependencies which it would have
+/// satisfied name it.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DisabledProvider<T: TypeId> {
+  pub product: T,
+  pub input: T,
+  // What kind of provider this was, e.g. "intrinsic".
+  pub provider: String,
+  // What disabled the provider, e.g. the name of a flag.
+  pub disabled_by: String,
 }
 
 // TODO: We can't derive this due to https://github.com/rust-lang/rust/issues/26925, which
@@ -778,6 +798,7 @@ impl<R: Rule> Default for RuleGraph<R> {
       rule_dependency_edges: RuleDependencyEdges::default(),
       unfulfillable_rules: UnfulfillableRuleMap::default(),
       unreachable_rules: Vec::default(),
+      disabled_providers: Vec::default(),
     }
   }
 }
@@ -921,7 +942,32 @@ impl<R: Rule> RuleGraph<R> {
     }
   }
 
+  ///
+  /// Records providers which were deliberately not installed, so that errors for the dependencies
+  /// which they would have satisfied can explain why no rule was available.
+  ///
+  pub fn with_disabled_providers(
+    mut self,
+    disabled_providers: Vec<DisabledProvider<R::TypeId>>,
+  ) -> RuleGraph<R> {
+    self.disabled_providers = disabled_providers;
+    self
+  }
+
   pub fn validate(&self) -> Result<(), String> {
+    let errors = self.errors();
+    if errors.is_empty() {
+      Ok(())
+    } else {
+      Err(render_errors(&errors))
+    }
+  }
+
+  ///
+  /// Returns the errors that make this graph invalid, in a stable order. An empty result means
+  /// that the graph is valid.
+  ///
+  pub fn errors(&self) -> Vec<RuleGraphError> {
     let mut collated_errors: HashMap<R, Vec<Diagnostic<_>>> = HashMap::new();
 
     let used_rules: HashSet<_> = self
@@ -959,33 +1005,77 @@ impl<R: Rule> RuleGraph<R> {
       }
     }
 
-    if collated_errors.is_empty() {
-      return Ok(());
+    let mut errors = Vec::new();
+    for (rule, diagnostics) in collated_errors {
+      let rule_display = rule.to_string();
+      let mut missing = Vec::new();
+      for Diagnostic { params, kind } in diagnostics {
+        let provenance = |dependency_key, product, notes| DependencyKeyProvenance {
+          dependency_key,
+          product,
+          params: params.iter().map(ToString::to_string).collect(),
+          params_display: params_str(&params),
+          notes,
+        };
+        match kind {
+          DiagnosticKind::Missing {
+            dependency_key,
+            product,
+          } => {
+            let notes = self.disabled_provider_notes(&product, &params);
+            missing.push(provenance(dependency_key, product, notes))
+          }
+          DiagnosticKind::Ambiguous {
+            dependency_key,
+            product,
+            mut candidates,
+          } => {
+            candidates.sort();
+            errors.push(RuleGraphError::AmbiguousProvider {
+              rule_display: rule_display.clone(),
+              dependency: provenance(dependency_key, product, vec![]),
+              candidates,
+            })
+          }
+          DiagnosticKind::Unreachable => errors.push(RuleGraphError::UnreachableRule {
+            rule_display: rule_display.clone(),
+          }),
+        }
+      }
+      if !missing.is_empty() {
+        missing.sort();
+        missing.dedup();
+        errors.push(RuleGraphError::UnsatisfiableRule {
+          rule_display,
+          missing,
+        });
+      }
     }
+    errors.sort();
+    errors.dedup();
+    errors
+  }
 
-    let mut msgs: Vec<String> = collated_errors
-      .into_iter()
-      .map(|(rule, mut diagnostics)| {
-        diagnostics.sort_by(|l, r| l.reason.cmp(&r.reason));
-        diagnostics.dedup_by(|l, r| l.reason == r.reason);
-        let errors = diagnostics
-          .into_iter()
-          .map(|mut d| {
-            if d.details.is_empty() {
-              d.reason.clone()
-            } else {
-              d.details.sort();
-              format!("{}:\n      {}", d.reason, d.details.join("\n      "))
-            }
-          })
-          .collect::<Vec<_>>()
-          .join("\n    ");
-        format!("{}:\n    {}", rule, errors)
+  ///
+  /// Explains which disabled providers would have computed a missing product from the available
+  /// params.
+  ///
+  fn disabled_provider_notes(&self, product: &str, params: &ParamTypes<R::TypeId>) -> Vec<String> {
+    let mut notes = self
+      .disabled_providers
+      .iter()
+      .filter(|disabled| {
+        disabled.product.to_string() == product && params.contains(&disabled.input)
       })
-      .collect();
-    msgs.sort();
-
-    Err(format!("Rules with errors: {}\n  {}", msgs.len(), msgs.join("\n  ")).to_string())
+      .map(|disabled| {
+        format!(
+          "the {} providing {} from {} was disabled by {}",
+          disabled.provider, product, disabled.input, disabled.disabled_by,
+        )
+      })
+      .collect::<Vec<_>>();
+    notes.sort();
+    notes
   }
 
   pub fn visualize(&self, f: &mut dyn io::Write) -> io::Result<()> {
@@ -1092,7 +1182,8 @@ impl<R: Rule> Default for RuleEdges<R> {
 
 #[cfg(test)]
 mod tests {
-  use super::RuleGraph;
+  use super::{DependencyKeyProvenance, DisabledProvider, RuleGraph, RuleGraphError};
+  use serde_json;
   use std::fmt;
 
   #[test]
@@ -1121,6 +1212,155 @@ mod tests {
       .contains("No rule was available to compute DependencyKey(\"b\", None)."));
   }
 
+  #[test]
+  fn errors_unsatisfiable() {
+    let a_from_b = Rule("a_from_b", vec![DependencyKey("b", None)]);
+    let rules = vec![("a", vec![a_from_b.clone()])].into_iter().collect();
+    let graph = RuleGraph::new(&rules, vec![]);
+
+    let errors = graph.errors();
+    assert_eq!(
+      errors,
+      vec![RuleGraphError::UnsatisfiableRule {
+        rule_display: a_from_b.to_string(),
+        missing: vec![DependencyKeyProvenance {
+          dependency_key: "DependencyKey(\"b\", None)".to_string(),
+          product: "b".to_string(),
+          params: vec![],
+          params_display: "".to_string(),
+          notes: vec![],
+        }],
+      }]
+    );
+
+    let json = serde_json::to_value(&errors).unwrap();
+    assert_eq!(json[0]["kind"], "UnsatisfiableRule");
+    assert_eq!(json[0]["missing"][0]["product"], "b");
+  }
+
+  #[test]
+  fn errors_name_disabled_providers() {
+    let a_from_get_b = Rule("a_from_get_b", vec![DependencyKey("b", Some("url"))]);
+    let b_from_url = Rule("b_from_url", vec![DependencyKey("url", None)]);
+    let disabled = || {
+      vec![DisabledProvider {
+        product: "b",
+        input: "url",
+        provider: "intrinsic".to_owned(),
+        disabled_by: "url_fetch".to_owned(),
+      }]
+    };
+
+    let rules = vec![("a", vec![a_from_get_b.clone()])]
+      .into_iter()
+      .collect();
+    let error = RuleGraph::new(&rules, vec![])
+      .with_disabled_providers(disabled())
+      .validate()
+      .unwrap_err();
+    assert!(error.contains(
+      "No rule was available to compute DependencyKey(\"b\", Some(\"url\")) with parameter type \
+       url: the intrinsic providing b from url was disabled by url_fetch"
+    ));
+
+    let rules = vec![("a", vec![a_from_get_b]), ("b", vec![b_from_url])]
+      .into_iter()
+      .collect();
+    RuleGraph::new(&rules, vec![]).validate().unwrap();
+  }
+
+  #[test]
+  fn errors_ambiguous_and_unreachable() {
+    let a_from_b_1 = Rule("a_from_b_1", vec![DependencyKey("b", None)]);
+    let a_from_b_2 = Rule("a_from_b_2", vec![DependencyKey("b", None)]);
+    let c_from_a = Rule("c_from_a", vec![DependencyKey("a", None)]);
+    let rules = vec![
+      ("a", vec![a_from_b_1.clone(), a_from_b_2.clone()]),
+      ("c", vec![c_from_a.clone()]),
+    ]
+    .into_iter()
+    .collect();
+    let graph = RuleGraph::new(&rules, vec!["b"]);
+
+    let errors = graph.errors();
+    assert_eq!(errors.len(), 3);
+    for unreachable in &[&a_from_b_1, &a_from_b_2] {
+      assert!(errors.contains(&RuleGraphError::UnreachableRule {
+        rule_display: unreachable.to_string(),
+      }));
+    }
+    match errors
+      .iter()
+      .find(|e| e.rule_display() == c_from_a.to_string())
+    {
+      Some(RuleGraphError::AmbiguousProvider {
+        dependency,
+        candidates,
+        ..
+      }) => {
+        assert_eq!(dependency.params, vec!["b".to_string()]);
+        assert_eq!(candidates.len(), 2);
+      }
+      e => panic!("Expected an AmbiguousProvider error, got: {:?}", e),
+    }
+
+    let rendered = graph.validate().err().unwrap();
+    assert!(rendered.starts_with("Rules with errors: 3"));
+    assert!(rendered.contains("Was not usable by any other @rule."));
+    assert!(rendered
+      .contains("Ambiguous rules to compute DependencyKey(\"a\", None) with parameter type b:"));
+  }
+
+  #[test]
+  fn plan_chooses_provider_by_params() {
+    let rules = vec![(
+      "a",
+      vec![
+        Rule("a_from_b", vec![DependencyKey("b", None)]),
+        Rule("a_from_c", vec![DependencyKey("c", None)]),
+      ],
+    )]
+    .into_iter()
+    .collect();
+    let graph = RuleGraph::new(&rules, vec!["b", "c"]);
+
+    let plan = graph.plan(vec!["c"], "a").unwrap();
+    assert_eq!(plan.dependencies.len(), 1);
+    assert!(plan.dependencies[0].entry.contains("a_from_c"));
+    assert_eq!(
+      plan.dependencies[0].dependencies[0].entry,
+      "Param(c)".to_string()
+    );
+
+    assert_eq!(
+      plan.render_text(None),
+      vec![
+        "Select(DependencyKey(\"a\", None)) for c",
+        "  DependencyKey(\"a\", None) <- Rule(\"a_from_c\", [DependencyKey(\"c\", None)]) for c",
+        "    DependencyKey(\"c\", None) <- Param(c)",
+      ]
+      .join("\n")
+    );
+    assert_eq!(plan.render_text(Some(1)).lines().last(), Some("    ..."));
+
+    let json: serde_json::Value = serde_json::from_str(&plan.to_json()).unwrap();
+    assert_eq!(json["dependency_key"], serde_json::Value::Null);
+    assert_eq!(
+      json["dependencies"][0]["dependencies"][0]["entry"],
+      "Param(c)"
+    );
+  }
+
+  #[test]
+  fn plan_for_uncomputable_product() {
+    let rules = vec![("a", vec![Rule("a_from_b", vec![DependencyKey("b", None)])])]
+      .into_iter()
+      .collect();
+    let graph = RuleGraph::new(&rules, vec!["b"]);
+
+    assert!(graph.plan(vec!["b"], "z").is_err());
+  }
+
   impl super::TypeId for &'static str {
     fn display<I>(type_ids: I) -> String
     where
@@ -0,0 +1,128 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use serde_derive::Serialize;
+use serde_json;
+
+use crate::{
+  entry_str, entry_with_deps_str, DependencyKey, Entry, EntryWithDeps, ParamTypes, RootEntry, Rule,
+  RuleEdges, RuleGraph,
+};
+
+///
+/// One step in the plan of rule invocations that would be used to compute a product: the
+/// dependency that it satisfies, and the Entry (a rule, or a Param) that satisfies it.
+///
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct PlanNode {
+  // The DependencyKey that this node satisfies, or None for the root of the plan.
+  pub dependency_key: Option<String>,
+  pub entry: String,
+  // True if this entry is already being expanded higher in the plan, and so was not expanded here.
+  pub cycle: bool,
+  pub dependencies: Vec<PlanNode>,
+}
+
+impl PlanNode {
+  ///
+  /// Renders the plan as indented text. If a max_depth is given, dependencies below that depth are
+  /// elided.
+  ///
+  pub fn render_text(&self, max_depth: Option<usize>) -> String {
+    let mut lines = Vec::new();
+    self.render_lines(0, max_depth, &mut lines);
+    lines.join("\n")
+  }
+
+  fn render_lines(&self, depth: usize, max_depth: Option<usize>, lines: &mut Vec<String>) {
+    let indent = "  ".repeat(depth);
+    let mut line = match self.dependency_key {
+      Some(ref dependency_key) => format!("{}{} <- {}", indent, dependency_key, self.entry),
+      None => format!("{}{}", indent, self.entry),
+    };
+    if self.cycle {
+      line.push_str(" (cycle)");
+    }
+    lines.push(line);
+
+    if self.dependencies.is_empty() {
+      return;
+    }
+    if max_depth.map_or(false, |max_depth| depth >= max_depth) {
+      lines.push(format!("{}  ...", indent));
+      return;
+    }
+    for dependency in &self.dependencies {
+      dependency.render_lines(depth + 1, max_depth, lines);
+    }
+  }
+
+  pub fn to_json(&self) -> String {
+    serde_json::to_string_pretty(self).expect("A PlanNode is always serializable.")
+  }
+}
+
+impl<R: Rule> RuleGraph<R> {
+  ///
+  /// Returns the plan of rule invocations that would be used to compute the given product for the
+  /// given params, without running anything.
+  ///
+  pub fn plan<I: IntoIterator<Item = R::TypeId>>(
+    &self,
+    param_inputs: I,
+    product: R::TypeId,
+  ) -> Result<PlanNode, String> {
+    let params: ParamTypes<_> = param_inputs.into_iter().collect();
+    let edges = self.find_root_edges(params.iter().cloned(), product)?;
+    let root = EntryWithDeps::Root(RootEntry {
+      params,
+      dependency_key: R::DependencyKey::new_root(product),
+    });
+    let mut path = vec![];
+    Ok(PlanNode {
+      dependency_key: None,
+      entry: entry_with_deps_str(&root),
+      cycle: false,
+      dependencies: self.plan_dependencies(&edges, &mut path),
+    })
+  }
+
+  fn plan_dependencies(
+    &self,
+    edges: &RuleEdges<R>,
+    path: &mut Vec<EntryWithDeps<R>>,
+  ) -> Vec<PlanNode> {
+    let mut nodes = edges
+      .dependencies
+      .iter()
+      .filter_map(|(key, entries)| entries.first().map(|entry| (key, entry)))
+      .map(|(key, entry)| self.plan_entry(key, entry, path))
+      .collect::<Vec<_>>();
+    nodes.sort_by(|l, r| l.dependency_key.cmp(&r.dependency_key));
+    nodes
+  }
+
+  fn plan_entry(
+    &self,
+    dependency_key: &R::DependencyKey,
+    entry: &Entry<R>,
+    path: &mut Vec<EntryWithDeps<R>>,
+  ) -> PlanNode {
+    let mut node = PlanNode {
+      dependency_key: Some(dependency_key.to_string()),
+      entry: entry_str(entry),
+      cycle: false,
+      dependencies: vec![],
+    };
+    if let Entry::WithDeps(ref entry) = entry {
+      if path.contains(entry) {
+        node.cycle = true;
+      } else if let Some(edges) = self.rule_dependency_edges.get(entry) {
+        path.push(entry.clone());
+        node.dependencies = self.plan_dependencies(edges, path);
+        path.pop();
+      }
+    }
+    node
+  }
+}
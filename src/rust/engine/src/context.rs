@@ -2,10 +2,12 @@
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 
 use std;
+use std::collections::BTreeMap;
 use std::convert::{Into, TryInto};
 use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use futures::Future;
 
@@ -19,18 +21,33 @@ use boxfuture::{BoxFuture, Boxable};
 use core::clone::Clone;
 use fs::{safe_create_dir_all_ioerror, PosixFS};
 use graph::{EntryId, Graph, NodeContext};
+use log::debug;
 use process_execution::{
-  self, speculate::SpeculatingCommandRunner, BoundedCommandRunner, ExecuteProcessRequestMetadata,
-  Platform,
+  self,
+  remote::CancellationSender,
+  report::RemoteExecutionReport,
+  retry_budget::RetryBudget,
+  speculate::SpeculatingCommandRunner,
+  time_budget::{RemoteTimeBudget, RemoteTimeBudgetCommandRunner},
+  trace::TraceCollector,
+  upload_gate::UploadGates,
+  violations, BoundedCommandRunner, EnvRecording, ExecuteProcessRequestMetadata,
+  ExecutionEnvironment, Platform,
 };
 use rand::seq::SliceRandom;
 use reqwest;
 use rule_graph::RuleGraph;
 use sharded_lmdb::ShardedLmdb;
-use store::Store;
+use store::{ProxyConfig, Store};
 
 const GIGABYTES: usize = 1024 * 1024 * 1024;
 
+// How long shutdown waits for the cancellations of dropped remote executions to be sent.
+const CANCELLATION_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How many actions the remote execution report holds before it drops the fastest successful ones.
+const MAX_REPORTED_ACTIONS: usize = 1000;
+
 ///
 /// The core context shared (via Arc) between the Scheduler and the Context objects of
 /// all running Nodes.
@@ -46,16 +63,33 @@ pub struct Core {
   pub types: Types,
   pub executor: task_executor::Executor,
   store: Store,
+  // The Store in which the outputs of process results (including spilled stdout and stderr) are
+  // recorded: see `Core::result_store`.
+  result_store: Store,
   pub command_runner: Box<dyn process_execution::CommandRunner>,
+  // If remote execution is enabled, the sender of the cancellations of its dropped requests.
+  cancellation_sender: Option<CancellationSender>,
+  // The count of process executions which were cancelled because no session was waiting for them.
+  pub orphaned_executions_cancelled: AtomicUsize,
+  // If remote executions are traced, the trace and the path which it is written to.
+  remote_execution_trace: Option<(TraceCollector, PathBuf)>,
+  // If remote executions are reported, the report and the path which it is written to.
+  remote_execution_report: Option<(RemoteExecutionReport, PathBuf)>,
   pub http_client: reqwest::r#async::Client,
   pub vfs: PosixFS,
   pub build_root: PathBuf,
+  // Whether invalid UTF-8 in the env of process requests is replaced when they are lifted from
+  // Python (see `ExecuteProcessRequestMetadata::allow_lossy_env`).
+  pub allow_lossy_env: bool,
+  // Shared by the Store and the remote CommandRunner. Fields are dropped in declaration order, so
+  // this is dropped after them.
+  _grpc_environment: Option<Arc<store::GrpcEnvironment>>,
 }
 
 impl Core {
   pub fn new(
     root_subject_types: Vec<TypeId>,
-    tasks: Tasks,
+    mut tasks: Tasks,
     types: Types,
     build_root: PathBuf,
     ignore_patterns: &[String],
@@ -67,23 +101,52 @@ impl Core {
     remote_instance_name: Option<String>,
     remote_root_ca_certs_path: Option<PathBuf>,
     remote_oauth_bearer_token_path: Option<PathBuf>,
+    remote_proxy: Option<String>,
     remote_store_thread_count: usize,
     remote_store_chunk_bytes: usize,
     remote_store_chunk_upload_timeout: Duration,
     remote_store_rpc_retries: usize,
     remote_store_connection_limit: usize,
+    remote_store_max_concurrent_uploads: usize,
     remote_execution_extra_platform_properties: Vec<(String, String)>,
+    remote_execution_timeout_excludes_queue: bool,
+    remote_execution_max_queue_wait: Duration,
+    remote_execution_environments: String,
+    remote_execution_output_spill_threshold: Option<usize>,
     process_execution_local_parallelism: usize,
     process_execution_remote_parallelism: usize,
     process_execution_cleanup_local_dirs: bool,
     process_execution_speculation_delay: Duration,
     process_execution_speculation_strategy: String,
     process_execution_use_local_cache: bool,
+    remote_execution_persist_inline_output: bool,
+    remote_execution_retry_budget: u64,
+    remote_execution_retry_budget_refill_interval: Duration,
+    remote_execution_validate_cache_hit_blobs: bool,
+    remote_execution_deterministic_span_ids: bool,
+    remote_execution_violation_categories: String,
+    remote_execution_time_budget: Option<Duration>,
+    remote_execution_chrome_trace_path: Option<PathBuf>,
+    process_execution_strict_locality: bool,
+    remote_execution_report_path: Option<PathBuf>,
+    remote_execution_record_env_in_report: String,
+    remote_execution_record_env_redact_patterns: Vec<String>,
   ) -> Result<Core, String> {
     // Randomize CAS address order to avoid thundering herds from common config.
     let mut remote_store_servers = remote_store_servers;
     remote_store_servers.shuffle(&mut rand::thread_rng());
 
+    // Unless remote_store_max_concurrent_uploads is zero, it bounds the blobs which process
+    // executions upload to the remote store at once.
+    let upload_gate = if remote_store_max_concurrent_uploads > 0 {
+      Some(
+        UploadGates::new(remote_store_max_concurrent_uploads)
+          .for_endpoint(&remote_store_servers.join(",")),
+      )
+    } else {
+      None
+    };
+
     let executor = task_executor::Executor::new();
     // We re-use these certs for both the execution and store service; they're generally tied together.
     let root_ca_certs = if let Some(path) = remote_root_ca_certs_path {
@@ -105,29 +168,55 @@ impl Core {
       None
     };
 
+    // We re-use the proxy for both the execution and store service, as for the certs and token.
+    let proxy = match remote_proxy {
+      Some(url) => {
+        Some(ProxyConfig::parse(&url).map_err(|err| format!("Invalid --remote-proxy: {}", err))?)
+      }
+      None => None,
+    };
+
+    if remote_execution {
+      process_execution::check_remote_execution_supported()?;
+    }
+
+    // The Store and the remote CommandRunner connect in one grpc Environment, with
+    // remote_store_thread_count threads (or the default count, if it is 0).
+    let grpc_environment = if remote_execution {
+      Some(store::grpc_environment(if remote_store_thread_count == 0 {
+        store::default_grpc_thread_count()
+      } else {
+        remote_store_thread_count
+      }))
+    } else {
+      None
+    };
+
     let local_store_dir2 = local_store_dir.clone();
     let store = safe_create_dir_all_ioerror(&local_store_dir)
       .map_err(|e| format!("Error making directory {:?}: {:?}", local_store_dir, e))
       .and_then(|()| {
-        if !remote_execution || remote_store_servers.is_empty() {
-          Store::local_only(executor.clone(), local_store_dir)
-        } else {
-          Store::with_remote(
-            executor.clone(),
-            local_store_dir,
-            remote_store_servers,
-            remote_instance_name.clone(),
-            root_ca_certs.clone(),
-            oauth_bearer_token.clone(),
-            remote_store_thread_count,
-            remote_store_chunk_bytes,
-            remote_store_chunk_upload_timeout,
-            // TODO: Take a parameter
-            store::BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10))
-              .unwrap(),
-            remote_store_rpc_retries,
-            remote_store_connection_limit,
-          )
+        match grpc_environment {
+          Some(ref grpc_environment) if !remote_store_servers.is_empty() => {
+            Store::with_remote_in_environment(
+              executor.clone(),
+              local_store_dir,
+              remote_store_servers,
+              remote_instance_name.clone(),
+              root_ca_certs.clone(),
+              oauth_bearer_token.clone(),
+              proxy.clone(),
+              grpc_environment.clone(),
+              remote_store_chunk_bytes,
+              remote_store_chunk_upload_timeout,
+              // TODO: Take a parameter
+              store::BackoffConfig::new(Duration::from_millis(10), 1.0, Duration::from_millis(10))
+                .unwrap(),
+              remote_store_rpc_retries,
+              remote_store_connection_limit,
+            )
+          }
+          _ => Store::local_only(executor.clone(), local_store_dir),
         }
       })
       .map_err(|e| format!("Could not initialize Store: {:?}", e))?;
@@ -135,38 +224,110 @@ impl Core {
     let process_execution_metadata = ExecuteProcessRequestMetadata {
       instance_name: remote_instance_name.clone(),
       cache_key_gen_version: remote_execution_process_cache_namespace.clone(),
+      cache_scopes: BTreeMap::new(),
       platform_properties: remote_execution_extra_platform_properties.clone(),
+      timeout_excludes_queue: remote_execution_timeout_excludes_queue,
+      argv_error_patterns: process_execution::remote::default_argv_error_patterns(),
+      argv_warning_bytes: Some(process_execution::remote::DEFAULT_ARGV_WARNING_BYTES),
+      allow_lossy_env: false,
+      canonical_form_version: process_execution::CANONICAL_FORM_VERSION,
+      environments: ExecutionEnvironment::parse_all(&remote_execution_environments)?,
+      record_env_in_report: EnvRecording::parse(
+        &remote_execution_record_env_in_report,
+        &remote_execution_record_env_redact_patterns,
+      )?,
     };
+    let allow_lossy_env = process_execution_metadata.allow_lossy_env;
 
-    let mut command_runner: Box<dyn process_execution::CommandRunner> =
-      Box::new(BoundedCommandRunner::new(
-        Box::new(process_execution::local::CommandRunner::new(
+    // Results are recorded in the Store of inputs: the remote CommandRunner is not given a separate
+    // result Store (see `remote::CommandRunner::with_result_store`).
+    let result_store = store.clone();
+
+    // Clones share the bound on local parallelism.
+    let local_command_runner = BoundedCommandRunner::new(
+      Box::new(
+        process_execution::local::CommandRunner::new(
           store.clone(),
           executor.clone(),
           std::env::temp_dir(),
           process_execution_cleanup_local_dirs,
-        )),
-        process_execution_local_parallelism,
-      ));
+        )
+        .with_strict_execution_locality(process_execution_strict_locality),
+      ),
+      process_execution_local_parallelism,
+    );
+    let mut command_runner: Box<dyn process_execution::CommandRunner> =
+      Box::new(local_command_runner.clone());
 
-    if remote_execution {
-      let remote_command_runner: Box<dyn process_execution::CommandRunner> =
+    let mut cancellation_sender = None;
+    let mut remote_execution_trace = None;
+    let mut remote_execution_report = None;
+    if let Some(ref grpc_environment) = grpc_environment {
+      let mut remote_command_runner = process_execution::remote::CommandRunner::new_in_environment(
+        // No problem unwrapping here because the global options validation
+        // requires the remote_execution_server be present when remote_execution is set.
+        &remote_execution_server.unwrap(),
+        process_execution_metadata.clone(),
+        root_ca_certs.clone(),
+        oauth_bearer_token.clone(),
+        proxy.clone(),
+        store.clone(),
+        // TODO if we ever want to configure the remote platform to be something else we
+        // need to take an option all the way down here and into the remote::CommandRunner struct.
+        Platform::Linux,
+        executor.clone(),
+        grpc_environment.clone(),
+      )?;
+      remote_command_runner =
+        remote_command_runner.with_max_queue_wait(remote_execution_max_queue_wait);
+      if let Some(threshold_bytes) = remote_execution_output_spill_threshold {
+        remote_command_runner = remote_command_runner.with_output_spill_threshold(threshold_bytes);
+      }
+      if !remote_execution_persist_inline_output {
+        remote_command_runner = remote_command_runner.without_persisting_inline_output();
+      }
+      remote_command_runner = remote_command_runner
+        .with_validate_cache_hit_blobs(remote_execution_validate_cache_hit_blobs)
+        .with_deterministic_span_ids(remote_execution_deterministic_span_ids)
+        .with_strict_execution_locality(process_execution_strict_locality)
+        .with_violation_categories(violations::parse_violation_categories(
+          &remote_execution_violation_categories,
+        )?);
+      if remote_execution_retry_budget > 0 {
+        // One budget bounds the retries of every process of the build.
+        remote_command_runner = remote_command_runner.with_retry_budget(RetryBudget::new(
+          remote_execution_retry_budget,
+          remote_execution_retry_budget_refill_interval,
+        ));
+      }
+      if let Some(upload_gate) = upload_gate {
+        remote_command_runner = remote_command_runner.with_upload_gate(upload_gate);
+      }
+      if let Some(path) = remote_execution_chrome_trace_path {
+        let trace = TraceCollector::new();
+        remote_command_runner = remote_command_runner.with_trace_collector(trace.clone());
+        remote_execution_trace = Some((trace, path));
+      }
+      if let Some(path) = remote_execution_report_path {
+        let report = RemoteExecutionReport::new(MAX_REPORTED_ACTIONS);
+        remote_command_runner = remote_command_runner.with_report(report.clone());
+        remote_execution_report = Some((report, path));
+      }
+      cancellation_sender = Some(remote_command_runner.cancellation_sender());
+      let mut remote_command_runner: Box<dyn process_execution::CommandRunner> =
         Box::new(BoundedCommandRunner::new(
-          Box::new(process_execution::remote::CommandRunner::new(
-            // No problem unwrapping here because the global options validation
-            // requires the remote_execution_server be present when remote_execution is set.
-            &remote_execution_server.unwrap(),
-            process_execution_metadata.clone(),
-            root_ca_certs.clone(),
-            oauth_bearer_token.clone(),
-            store.clone(),
-            // TODO if we ever want to configure the remote platform to be something else we
-            // need to take an option all the way down here and into the remote::CommandRunner struct.
-            Platform::Linux,
-            executor.clone(),
-          )),
+          Box::new(remote_command_runner),
           process_execution_remote_parallelism,
         ));
+      if let Some(remote_execution_time_budget) = remote_execution_time_budget {
+        // One budget bounds the remote time of every process of the build, after which processes
+        // run locally instead.
+        remote_command_runner = Box::new(RemoteTimeBudgetCommandRunner::new(
+          remote_command_runner,
+          Box::new(local_command_runner),
+          RemoteTimeBudget::new(remote_execution_time_budget),
+        ));
+      }
       command_runner = match process_execution_speculation_strategy.as_ref() {
         "local_first" => Box::new(SpeculatingCommandRunner::new(
           command_runner,
@@ -199,7 +360,7 @@ impl Core {
     }
 
     let http_client = reqwest::r#async::Client::new();
-    let rule_graph = RuleGraph::new(tasks.as_map(), root_subject_types);
+    let rule_graph = tasks.rule_graph(root_subject_types);
 
     Ok(Core {
       graph: Graph::new(),
@@ -208,19 +369,74 @@ impl Core {
       types: types,
       executor: executor.clone(),
       store,
+      result_store,
       command_runner,
+      cancellation_sender,
+      orphaned_executions_cancelled: AtomicUsize::new(0),
+      remote_execution_trace,
+      remote_execution_report,
       http_client,
       // TODO: Errors in initialization should definitely be exposed as python
       // exceptions, rather than as panics.
       vfs: PosixFS::new(&build_root, &ignore_patterns, executor)
         .map_err(|e| format!("Could not initialize VFS: {:?}", e))?,
       build_root: build_root,
+      allow_lossy_env,
+      _grpc_environment: grpc_environment,
     })
   }
 
   pub fn store(&self) -> Store {
     self.store.clone()
   }
+
+  ///
+  /// The Store in which the outputs of process results are recorded, and so from which stdout and
+  /// stderr which were spilled (see `process_execution::ProcessOutput`) must be loaded.
+  ///
+  pub fn result_store(&self) -> Store {
+    self.result_store.clone()
+  }
+
+  ///
+  /// Writes the remote executions which were traced since the last call (if they are traced, see
+  /// --remote-execution-chrome-trace-path) as a Chrome trace, replacing the previous one.
+  ///
+  pub fn write_remote_execution_trace(&self) -> Result<(), String> {
+    match self.remote_execution_trace {
+      Some((ref trace, ref path)) => trace.take().write_chrome_trace(path),
+      None => Ok(()),
+    }
+  }
+
+  ///
+  /// Writes the report of the remote executions so far (if they are reported, see
+  /// --remote-execution-report-path), with the current metrics of the CommandRunner, replacing the
+  /// previous one.
+  ///
+  pub fn write_remote_execution_report(&self) -> Result<(), String> {
+    match self.remote_execution_report {
+      Some((ref report, ref path)) => {
+        report.record_metrics(self.command_runner.metrics());
+        report.write_to(path)
+      }
+      None => Ok(()),
+    }
+  }
+
+  ///
+  /// Waits (for at most CANCELLATION_FLUSH_TIMEOUT) for the cancellations of any remote executions
+  /// which were dropped to be sent, which would otherwise be lost when the Executor is dropped.
+  ///
+  pub fn flush_cancellations(&self) {
+    if let Some(ref cancellation_sender) = self.cancellation_sender {
+      let sent = cancellation_sender.flush(Instant::now() + CANCELLATION_FLUSH_TIMEOUT);
+      debug!(
+        "Sent {} remote execution cancellations before shutdown",
+        sent
+      );
+    }
+  }
 }
 
 #[derive(Clone)]
@@ -11,6 +11,7 @@ use crate::externs;
 use crate::handles::Handle;
 
 use rule_graph;
+use serde_derive::{Deserialize, Serialize};
 use smallvec::{smallvec, SmallVec};
 
 pub type FNV = hash::BuildHasherDefault<FnvHasher>;
@@ -118,7 +119,7 @@ pub type Id = u64;
 // The type of a python object (which itself has a type, but which is not represented
 // by a Key, because that would result in a infinitely recursive structure.)
 #[repr(C)]
-#[derive(Clone, Copy, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[derive(Clone, Copy, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
 pub struct TypeId(pub Id);
 
 impl TypeId {
@@ -160,7 +161,7 @@ pub const ANY_TYPE: TypeId = TypeId(0);
 
 // An identifier for a python function.
 #[repr(C)]
-#[derive(Clone, Copy, Eq, Hash, PartialEq)]
+#[derive(Clone, Copy, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub struct Function(pub Key);
 
 impl Function {
@@ -187,7 +188,7 @@ impl fmt::Debug for Function {
 /// Wraps a type id for use as a key in HashMaps and sets.
 ///
 #[repr(C)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Deserialize, Serialize)]
 pub struct Key {
   id: Id,
   type_id: TypeId,
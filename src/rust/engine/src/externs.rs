@@ -180,6 +180,17 @@ pub fn project_multi_strs(item: &Value, field: &str) -> Vec<String> {
     .collect()
 }
 
+///
+/// The raw bytes of the strs of the given field, which (unlike `project_multi_strs`) are not
+/// required to be valid UTF-8, so that callers can validate them.
+///
+pub fn project_multi_str_bytes(item: &Value, field: &str) -> Vec<Vec<u8>> {
+  project_multi(item, field)
+    .iter()
+    .map(|v| with_externs(|e| (e.val_to_str)(e.context, v as &Handle).to_bytes()))
+    .collect()
+}
+
 pub fn project_str(value: &Value, field: &str) -> String {
   let name_val = with_externs(|e| {
     (e.project_ignoring_type)(
@@ -39,11 +39,15 @@ pub mod nodes;
 mod scheduler;
 mod selectors;
 mod tasks;
+mod type_index;
 mod types;
 
 pub use crate::context::Core;
 pub use crate::core::{Function, Key, Params, TypeId, Value};
 pub use crate::handles::Handle;
 pub use crate::scheduler::{ExecutionRequest, RootResult, Scheduler, Session};
-pub use crate::tasks::{Rule, Tasks};
+pub use crate::tasks::{
+  InternedNames, IntrinsicFlag, IntrinsicFlags, Rule, RuleCost, SnapshotKey, Tasks,
+};
+pub use crate::type_index::TypeIndex;
 pub use crate::types::Types;
@@ -1,11 +1,13 @@
 // Copyright 2017 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::convert::TryFrom;
 use std::fmt::Display;
+use std::hash::{Hash, Hasher};
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use std::{self, fmt};
@@ -16,8 +18,9 @@ use futures::Stream;
 use url::Url;
 
 use crate::context::{Context, Core};
-use crate::core::{throw, Failure, Key, Params, TypeId, Value};
+use crate::core::{throw, Failure, Function, Key, Params, TypeId, Value};
 use crate::externs;
+use crate::scheduler::Session;
 use crate::selectors;
 use crate::tasks::{self, Intrinsic, Rule};
 use boxfuture::{try_future, BoxFuture, Boxable};
@@ -27,12 +30,14 @@ use fs::{
   PathGlobs, PathStat, StrictGlobMatching, VFS,
 };
 use hashing;
+use process_execution::action::checked_request_string;
 use process_execution::{
-  self, ExecuteProcessRequest, MultiPlatformExecuteProcessRequest, Platform,
+  self, ExecuteProcessRequest, ExecutionLocality, MultiPlatformExecuteProcessRequest, Platform,
 };
 use rule_graph;
 
 use graph::{Entry, Node, NodeError, NodeTracer, NodeVisualizer};
+use parking_lot::Mutex;
 use store::{self, StoreFileByDigest};
 use workunit_store::{generate_random_64bit_string, set_parent_id, WorkUnit, WorkUnitStore};
 
@@ -96,11 +101,51 @@ pub trait WrappedNode: Into<NodeKey> {
 /// The 'params' represent a series of type-keyed parameters that will be used by Nodes in the
 /// subgraph below this Select.
 ///
-#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+/// The rule which requested a Select is not part of its identity: it is only used to attribute the
+/// processes which the Select runs.
+///
+#[derive(Clone, Debug)]
 pub struct Select {
   pub params: Params,
   pub product: TypeId,
   entry: rule_graph::Entry<Rule>,
+  requester: Option<Requester>,
+}
+
+impl PartialEq for Select {
+  fn eq(&self, other: &Select) -> bool {
+    self.params == other.params && self.product == other.product && self.entry == other.entry
+  }
+}
+
+impl Eq for Select {}
+
+impl Hash for Select {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    self.params.hash(state);
+    self.product.hash(state);
+    self.entry.hash(state);
+  }
+}
+
+///
+/// The rule (and its params) which requested a Select, from which the provenance of any processes
+/// that the Select runs is rendered: see `ExecuteProcessRequest::provenance`.
+///
+#[derive(Clone, Debug)]
+struct Requester {
+  func: Function,
+  goal: Option<String>,
+  params: Params,
+}
+
+impl Requester {
+  fn provenance(&self) -> String {
+    match self.goal {
+      Some(ref goal) => format!("{} for goal `{}`", self.func, goal),
+      None => format!("{} for {}", self.func, self.params),
+    }
+  }
 }
 
 impl Select {
@@ -113,9 +158,15 @@ impl Select {
       params,
       product,
       entry,
+      requester: None,
     }
   }
 
+  fn requested_by(mut self, requester: Option<Requester>) -> Select {
+    self.requester = requester;
+    self
+  }
+
   pub fn new_from_edges(
     params: Params,
     product: TypeId,
@@ -147,7 +198,9 @@ impl Select {
         ))
       });
     let context = context.clone();
-    Select::new_from_edges(self.params.clone(), product, &try_future!(edges)).run(context.clone())
+    Select::new_from_edges(self.params.clone(), product, &try_future!(edges))
+      .requested_by(self.requester.clone())
+      .run(context.clone())
   }
 }
 
@@ -168,8 +221,9 @@ impl WrappedNode for Select {
           product: self.product,
           task: task.clone(),
           entry: Arc::new(self.entry.clone()),
+          session_scoped: task.involves(types.running_process),
         }),
-        &Rule::Intrinsic(Intrinsic { product, input })
+        &Rule::Intrinsic(Intrinsic { product, input, .. })
           if product == types.directory_digest && input == types.input_files_content =>
         {
           let new_context = context.clone();
@@ -206,7 +260,7 @@ impl WrappedNode for Select {
             })
             .to_boxed()
         }
-        &Rule::Intrinsic(Intrinsic { product, input })
+        &Rule::Intrinsic(Intrinsic { product, input, .. })
           if product == types.snapshot && input == types.path_globs =>
         {
           let context = context.clone();
@@ -217,7 +271,7 @@ impl WrappedNode for Select {
             .map(move |snapshot| Snapshot::store_snapshot(&core, &snapshot))
             .to_boxed()
         }
-        &Rule::Intrinsic(Intrinsic { product, input })
+        &Rule::Intrinsic(Intrinsic { product, input, .. })
           if product == types.snapshot && input == types.url_to_fetch =>
         {
           let context = context.clone();
@@ -228,7 +282,7 @@ impl WrappedNode for Select {
             .map(move |snapshot| Snapshot::store_snapshot(&core, &snapshot))
             .to_boxed()
         }
-        &Rule::Intrinsic(Intrinsic { product, input })
+        &Rule::Intrinsic(Intrinsic { product, input, .. })
           if product == types.directory_digest && input == types.directories_to_merge =>
         {
           let request = self.select_product(&context, types.directories_to_merge, "intrinsic");
@@ -247,7 +301,7 @@ impl WrappedNode for Select {
             })
             .to_boxed()
         }
-        &Rule::Intrinsic(Intrinsic { product, input })
+        &Rule::Intrinsic(Intrinsic { product, input, .. })
           if product == types.snapshot && input == types.directory_digest =>
         {
           let core = context.core.clone();
@@ -264,7 +318,7 @@ impl WrappedNode for Select {
             .to_boxed()
         }
 
-        &Rule::Intrinsic(Intrinsic { product, input })
+        &Rule::Intrinsic(Intrinsic { product, input, .. })
           if product == types.directory_digest && input == types.directory_with_prefix_to_strip =>
         {
           let request =
@@ -292,7 +346,7 @@ impl WrappedNode for Select {
             })
             .to_boxed()
         }
-        &Rule::Intrinsic(Intrinsic { product, input })
+        &Rule::Intrinsic(Intrinsic { product, input, .. })
           if product == types.files_content && input == types.directory_digest =>
         {
           let context = context.clone();
@@ -311,31 +365,56 @@ impl WrappedNode for Select {
             })
             .to_boxed()
         }
-        &Rule::Intrinsic(Intrinsic { product, input })
+        &Rule::Intrinsic(Intrinsic { product, input, .. })
           if product == types.process_result && input == types.multi_platform_process_request =>
         {
           let context = context.clone();
           let core = context.core.clone();
+          let requester = self.requester.clone();
+          let allow_lossy_env = core.allow_lossy_env;
           self
             .select_product(&context, types.multi_platform_process_request, "intrinsic")
-            .and_then(|request| {
-              MultiPlatformExecuteProcess::lift(&request).map_err(|str| {
+            .and_then(move |request| {
+              MultiPlatformExecuteProcess::lift(&request, allow_lossy_env).map_err(|str| {
                 throw(&format!(
                   "Error lifting MultiPlatformExecuteProcess: {}",
                   str
                 ))
               })
             })
-            .and_then(move |process_request| context.get(process_request))
-            .map(move |result| {
+            .map(move |process_request| process_request.requested_by(requester.as_ref()))
+            .and_then(move |process_request| {
+              let workunit_store = context.session.workunit_store();
+              context.get(process_request).and_then(move |result| {
+                ProcessResult::store_process_result(&core, &result.0, workunit_store)
+                  .map_err(|e| throw(&e))
+              })
+            })
+            .to_boxed()
+        }
+        &Rule::Intrinsic(Intrinsic { product, input, .. })
+          if product == types.running_process && input == types.multi_platform_process_request =>
+        {
+          let context = context.clone();
+          let core = context.core.clone();
+          let requester = self.requester.clone();
+          let allow_lossy_env = core.allow_lossy_env;
+          self
+            .select_product(&context, types.multi_platform_process_request, "intrinsic")
+            .and_then(move |request| {
+              MultiPlatformExecuteProcess::lift(&request, allow_lossy_env).map_err(|str| {
+                throw(&format!(
+                  "Error lifting MultiPlatformExecuteProcess: {}",
+                  str
+                ))
+              })
+            })
+            .map(move |process_request| process_request.requested_by(requester.as_ref()))
+            .and_then(move |process_request| process_request.start(&context))
+            .map(move |id| {
               externs::unsafe_call(
-                &core.types.construct_process_result,
-                &[
-                  externs::store_bytes(&result.0.stdout),
-                  externs::store_bytes(&result.0.stderr),
-                  externs::store_i64(result.0.exit_code.into()),
-                  Snapshot::store_directory(&core, &result.0.output_directory),
-                ],
+                &core.types.construct_running_process,
+                &[externs::store_u64(id)],
               )
             })
             .to_boxed()
@@ -379,24 +458,43 @@ pub fn lift_digest(digest: &Value) -> Result<hashing::Digest, String> {
 
 /// A Node that represents a set of processes to execute on specific platforms.
 ///
+/// Nodes are memoized by the Eq and Hash of their request, which ignore the order in which it was
+/// built. `MultiPlatformExecuteProcessRequest::fingerprint` is the equivalent key which is stable
+/// across processes.
+///
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
-pub struct MultiPlatformExecuteProcess(MultiPlatformExecuteProcessRequest);
+pub struct MultiPlatformExecuteProcess(pub(crate) MultiPlatformExecuteProcessRequest);
 
 impl MultiPlatformExecuteProcess {
   fn lift_execute_process(
     value: &Value,
     target_platform: Platform,
+    allow_lossy_env: bool,
   ) -> Result<ExecuteProcessRequest, String> {
+    let argv = externs::project_multi_str_bytes(&value, "argv")
+      .iter()
+      .enumerate()
+      .map(|(i, arg)| checked_request_string(&format!("argv[{}]", i), arg, false))
+      .collect::<Result<Vec<_>, _>>()?;
+
     let mut env: BTreeMap<String, String> = BTreeMap::new();
-    let env_var_parts = externs::project_multi_strs(&value, "env");
+    let env_var_parts = externs::project_multi_str_bytes(&value, "env");
     if env_var_parts.len() % 2 != 0 {
       return Err("Error parsing env: odd number of parts".to_owned());
     }
     for i in 0..(env_var_parts.len() / 2) {
-      env.insert(
-        env_var_parts[2 * i].clone(),
-        env_var_parts[2 * i + 1].clone(),
-      );
+      // The name can only be included in messages once it is known to be valid.
+      let name = checked_request_string(
+        &format!("The name of env var {}", i),
+        &env_var_parts[2 * i],
+        allow_lossy_env,
+      )?;
+      let value = checked_request_string(
+        &format!("The value of env var {}", name),
+        &env_var_parts[2 * i + 1],
+        allow_lossy_env,
+      )?;
+      env.insert(name, value);
     }
     let digest = lift_digest(&externs::project_ignoring_type(&value, "input_files"))
       .map_err(|err| format!("Error parsing digest {}", err))?;
@@ -431,8 +529,20 @@ impl MultiPlatformExecuteProcess {
       }
     };
 
+    let environment = {
+      let val = externs::project_str(&value, "environment");
+      if val.is_empty() {
+        None
+      } else {
+        Some(val)
+      }
+    };
+
+    let execution_locality =
+      ExecutionLocality::try_from(&externs::project_str(&value, "execution_locality"))?;
+
     Ok(process_execution::ExecuteProcessRequest {
-      argv: externs::project_multi_strs(&value, "argv"),
+      argv: argv,
       env: env,
       input_files: digest,
       output_files: output_files,
@@ -441,9 +551,23 @@ impl MultiPlatformExecuteProcess {
       description: description,
       jdk_home: jdk_home,
       target_platform: target_platform,
+      force_rerun: false,
+      expected_output_digest: None,
+      diff_outputs: false,
+      ephemeral_input_digests: vec![],
+      scheduling_hints: None,
+      poll_interval_hint: None,
+      cache_scope_names: BTreeSet::new(),
+      argfile_threshold: None,
+      argfile_flag_template: None,
+      provenance: None,
+      environment: environment,
+      omit_cache_key_gen_version: externs::project_bool(&value, "omit_cache_key_gen_version"),
+      expected_output_size: None,
+      execution_locality: execution_locality,
     })
   }
-  fn lift(value: &Value) -> Result<MultiPlatformExecuteProcess, String> {
+  fn lift(value: &Value, allow_lossy_env: bool) -> Result<MultiPlatformExecuteProcess, String> {
     let constraint_parts = externs::project_multi_strs(&value, "platform_constraints");
     if constraint_parts.len() % 2 != 0 {
       return Err("Error parsing platform_constraints: odd number of parts".to_owned());
@@ -466,17 +590,76 @@ impl MultiPlatformExecuteProcess {
       ));
     }
 
-    let mut request_by_constraint: BTreeMap<(Platform, Platform), ExecuteProcessRequest> =
+    let mut request_by_constraint: BTreeMap<(Platform, Platform), Arc<ExecuteProcessRequest>> =
       BTreeMap::new();
     for (constraint_key, execute_process) in constraint_key_pairs.iter().zip(requests.iter()) {
-      let underlying_req =
-        MultiPlatformExecuteProcess::lift_execute_process(execute_process, constraint_key.1)?;
-      request_by_constraint.insert(constraint_key.clone(), underlying_req.clone());
+      let underlying_req = MultiPlatformExecuteProcess::lift_execute_process(
+        execute_process,
+        constraint_key.1,
+        allow_lossy_env,
+      )?;
+      request_by_constraint.insert(constraint_key.clone(), Arc::new(underlying_req));
     }
     Ok(MultiPlatformExecuteProcess(
       MultiPlatformExecuteProcessRequest(request_by_constraint),
     ))
   }
+
+  ///
+  /// Sets the provenance of each of the processes to that of the rule which requested them, if
+  /// any. Provenance is not part of the identity of a request, so where several rules request the
+  /// same processes, they run with the provenance of whichever requested them first.
+  ///
+  fn requested_by(self, requester: Option<&Requester>) -> MultiPlatformExecuteProcess {
+    let provenance = match requester {
+      Some(requester) => requester.provenance(),
+      None => return self,
+    };
+    let MultiPlatformExecuteProcess(MultiPlatformExecuteProcessRequest(requests)) = self;
+    MultiPlatformExecuteProcess(MultiPlatformExecuteProcessRequest(
+      requests
+        .into_iter()
+        .map(|(constraints, request)| {
+          let request = ExecuteProcessRequest {
+            provenance: Some(provenance.clone()),
+            ..(*request).clone()
+          };
+          (constraints, Arc::new(request))
+        })
+        .collect(),
+    ))
+  }
+
+  ///
+  /// Starts running the process in the background (outside of the Graph, so it is not memoized as
+  /// a ProcessResult would be), and returns the id with which it can be polled via
+  /// `Session::running_processes`. The process is stopped if the Session is cancelled or dropped.
+  ///
+  fn start(self, context: &Context) -> NodeFuture<u64> {
+    let request = self.0;
+    if !process_execution::compatible_without_locality(&*context.core.command_runner, &request) {
+      return err(throw(&format!(
+        "No compatible platform found for request: {:?}",
+        request
+      )));
+    }
+    let progress = process_execution::ProcessProgress::new();
+    let id = context.session.running_processes().insert(progress.clone());
+    let run = context.core.command_runner.run_with_progress(
+      request,
+      progress.clone(),
+      context.session.workunit_store(),
+    );
+    // The run is also dropped if the Session is dropped, since nothing could poll it any more.
+    let run = context.session.unless_cancelled(run);
+    context.core.executor.spawn_and_ignore(run.then(move |res| {
+      let result =
+        res.and_then(|result| result.ok_or_else(|| "The session was cancelled.".to_owned()));
+      progress.finish(&result);
+      Ok(())
+    }));
+    ok(id)
+  }
 }
 
 impl From<MultiPlatformExecuteProcess> for NodeKey {
@@ -491,16 +674,21 @@ impl WrappedNode for MultiPlatformExecuteProcess {
   fn run(self, context: Context) -> NodeFuture<ProcessResult> {
     let request = self.0;
     let workunit_store = context.session.workunit_store();
-    if context
-      .core
-      .command_runner
-      .extract_compatible_request(&request)
-      .is_some()
-    {
+    // A request which is compatible only if its locality is violated still runs: the runner fails
+    // or warns about the violation, depending on whether execution locality is strict.
+    if process_execution::compatible_without_locality(&*context.core.command_runner, &request) {
+      let mut orphan_guard = OrphanedExecutionGuard {
+        core: context.core.clone(),
+        completed: false,
+      };
       context
         .core
         .command_runner
         .run(request, workunit_store)
+        .then(move |res| {
+          orphan_guard.completed = true;
+          res
+        })
         .map(ProcessResult)
         .map_err(|e| throw(&format!("Failed to execute process: {}", e)))
         .to_boxed()
@@ -513,9 +701,119 @@ impl WrappedNode for MultiPlatformExecuteProcess {
   }
 }
 
+///
+/// Counts a process execution in `Core::orphaned_executions_cancelled` if it is dropped before it
+/// completes, which happens when no session is waiting for it any more.
+///
+struct OrphanedExecutionGuard {
+  core: Arc<Core>,
+  completed: bool,
+}
+
+impl Drop for OrphanedExecutionGuard {
+  fn drop(&mut self) {
+    if !self.completed {
+      self
+        .core
+        .orphaned_executions_cancelled
+        .fetch_add(1, Ordering::SeqCst);
+    }
+  }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct ProcessResult(process_execution::FallibleExecuteProcessResult);
 
+impl ProcessResult {
+  ///
+  /// Constructs the Python ProcessResult, loading the stdout and stderr of the result from the
+  /// result Store if they were spilled to it.
+  ///
+  pub fn store_process_result(
+    core: &Arc<Core>,
+    result: &process_execution::FallibleExecuteProcessResult,
+    workunit_store: WorkUnitStore,
+  ) -> BoxFuture<Value, String> {
+    let core = core.clone();
+    let exit_code = result.exit_code;
+    let output_directory = result.output_directory;
+    result
+      .stdout_bytes(&core.result_store(), workunit_store.clone())
+      .join(result.stderr_bytes(&core.result_store(), workunit_store))
+      .map(move |(stdout, stderr)| {
+        externs::unsafe_call(
+          &core.types.construct_process_result,
+          &[
+            externs::store_bytes(&stdout),
+            externs::store_bytes(&stderr),
+            externs::store_i64(exit_code.into()),
+            Snapshot::store_directory(&core, &output_directory),
+          ],
+        )
+      })
+      .to_boxed()
+  }
+}
+
+// Ids are unique across Sessions, so that polling a process via a Session which did not start it
+// fails rather than observing another process.
+static NEXT_RUNNING_PROCESS_ID: AtomicU64 = AtomicU64::new(0);
+
+///
+/// The processes which were started by requesting a RunningProcess in a Session, by id.
+///
+/// Because the RunningProcess for a request may be consumed by more than one rule, processes
+/// remain pollable (including after they are done) until the Session which started them is
+/// dropped. Since they are not pollable after that, the Tasks which consume them are not memoized
+/// across Sessions.
+///
+#[derive(Default)]
+pub struct RunningProcesses {
+  processes: Mutex<HashMap<u64, process_execution::ProcessProgress>>,
+}
+
+impl RunningProcesses {
+  fn insert(&self, progress: process_execution::ProcessProgress) -> u64 {
+    let id = NEXT_RUNNING_PROCESS_ID.fetch_add(1, Ordering::SeqCst);
+    self.processes.lock().insert(id, progress);
+    id
+  }
+
+  ///
+  /// Returns the stdout that the given process has written so far, and its status.
+  ///
+  pub fn poll(&self, id: u64) -> Result<(bytes::Bytes, process_execution::ProcessStatus), String> {
+    match self.processes.lock().get(&id) {
+      Some(progress) => Ok((progress.partial_stdout(), progress.status())),
+      None => Err(format!(
+        "No running process with id {} was started in this session.",
+        id
+      )),
+    }
+  }
+}
+
+///
+/// Polls the given RunningProcess, returning a tuple of the stdout that it has written so far and
+/// its result (or None, if it is still running).
+///
+pub fn poll_running_process(core: &Arc<Core>, session: &Session, id: u64) -> Result<Value, String> {
+  let (stdout, status) = session.running_processes().poll(id)?;
+  let result = match status {
+    process_execution::ProcessStatus::Pending | process_execution::ProcessStatus::Running => {
+      externs::none().into()
+    }
+    process_execution::ProcessStatus::Finished(result) => core.executor.block_on(
+      ProcessResult::store_process_result(core, &result, WorkUnitStore::new()),
+    )?,
+    process_execution::ProcessStatus::Failed(err) => return Err(err),
+  };
+  Ok(externs::store_tuple(&[
+    externs::store_bytes(&stdout),
+    result,
+  ]))
+}
+
 ///
 /// A Node that represents reading the destination of a symlink (non-recursively).
 ///
@@ -902,6 +1200,10 @@ pub struct Task {
   product: TypeId,
   task: tasks::Task,
   entry: Arc<rule_graph::Entry<Rule>>,
+  // Whether the Task's result is only valid in the Session which computed it, and so must not be
+  // memoized across Sessions: a RunningProcess can only be polled via the Session which started
+  // it (see RunningProcesses).
+  session_scoped: bool,
 }
 
 impl Task {
@@ -909,6 +1211,7 @@ impl Task {
     context: &Context,
     params: &Params,
     entry: &Arc<rule_graph::Entry<Rule>>,
+    requester: &Requester,
     gets: Vec<externs::Get>,
   ) -> NodeFuture<Vec<Value>> {
     let get_futures = gets
@@ -917,6 +1220,7 @@ impl Task {
         let context = context.clone();
         let params = params.clone();
         let entry = entry.clone();
+        let requester = requester.clone();
         let dependency_key = selectors::DependencyKey::JustGet(selectors::Get {
           product: get.product,
           subject: *get.subject.type_id(),
@@ -951,8 +1255,11 @@ impl Task {
         // type.
         let mut params = params.clone();
         params.put(get.subject);
-        future::result(entry)
-          .and_then(move |entry| Select::new(params, get.product, entry).run(context.clone()))
+        future::result(entry).and_then(move |entry| {
+          Select::new(params, get.product, entry)
+            .requested_by(Some(requester))
+            .run(context.clone())
+        })
       })
       .collect::<Vec<_>>();
     future::join_all(get_futures).to_boxed()
@@ -966,21 +1273,23 @@ impl Task {
     context: Context,
     params: Params,
     entry: Arc<rule_graph::Entry<Rule>>,
+    requester: Requester,
     generator: Value,
   ) -> NodeFuture<Value> {
     future::loop_fn(Value::from(externs::none()), move |input| {
       let context = context.clone();
       let params = params.clone();
       let entry = entry.clone();
+      let requester = requester.clone();
       future::result(externs::generator_send(&generator, &input)).and_then(move |response| {
         match response {
           externs::GeneratorResponse::Get(get) => {
-            Self::gen_get(&context, &params, &entry, vec![get])
+            Self::gen_get(&context, &params, &entry, &requester, vec![get])
               .map(|vs| future::Loop::Continue(vs.into_iter().next().unwrap()))
               .to_boxed()
           }
           externs::GeneratorResponse::GetMulti(gets) => {
-            Self::gen_get(&context, &params, &entry, gets)
+            Self::gen_get(&context, &params, &entry, &requester, gets)
               .map(|vs| future::Loop::Continue(externs::store_tuple(&vs)))
               .to_boxed()
           }
@@ -1007,6 +1316,11 @@ impl WrappedNode for Task {
 
   fn run(self, context: Context) -> NodeFuture<Value> {
     let params = self.params;
+    let requester = Requester {
+      func: self.task.func,
+      goal: self.task.goal.clone(),
+      params: params.clone(),
+    };
     let deps = {
       let edges = &context
         .core
@@ -1018,7 +1332,11 @@ impl WrappedNode for Task {
           .task
           .clause
           .into_iter()
-          .map(|s| Select::new_from_edges(params.clone(), s.product, edges).run(context.clone()))
+          .map(|s| {
+            Select::new_from_edges(params.clone(), s.product, edges)
+              .requested_by(Some(requester.clone()))
+              .run(context.clone())
+          })
           .collect::<Vec<_>>(),
       )
     };
@@ -1033,7 +1351,9 @@ impl WrappedNode for Task {
       })
       .then(move |task_result| match task_result {
         Ok(val) => match externs::get_type_for(&val) {
-          t if t == context.core.types.generator => Self::generate(context, params, entry, val),
+          t if t == context.core.types.generator => {
+            Self::generate(context, params, entry, requester, val)
+          }
           t if t == product => ok(val),
           _ => err(throw(&format!(
             "{:?} returned a result value that did not satisfy its constraints: {:?}",
@@ -1220,12 +1540,21 @@ impl Node for NodeKey {
 
   fn cacheable(&self) -> bool {
     match self {
-      &NodeKey::Task(ref s) => s.task.cacheable,
+      &NodeKey::Task(ref s) => s.task.cacheable && !s.session_scoped,
       // TODO Select nodes are made uncacheable as a workaround to #6146. Will be worked on in #6598
       &NodeKey::Select(_) => false,
       _ => true,
     }
   }
+
+  fn cancellable(&self) -> bool {
+    // Process executions are cancelled when no session is waiting for them any more. Select and
+    // Task nodes are cancellable in order for that to propagate from the roots of a session.
+    match self {
+      &NodeKey::MultiPlatformExecuteProcess(_) | &NodeKey::Select(_) | &NodeKey::Task(_) => true,
+      _ => false,
+    }
+  }
 }
 
 impl Display for NodeKey {
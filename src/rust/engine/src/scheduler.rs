@@ -5,14 +5,16 @@ use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
 use std::io;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
 use std::sync::{mpsc, Arc};
 use std::time::Duration;
 
-use futures::future::{self, Future};
+use futures::future::{self, Either, Future, Shared};
+use futures::sync::oneshot;
 
 use crate::context::{Context, Core};
-use crate::core::{Failure, Params, TypeId, Value};
-use crate::nodes::{NodeKey, Select, Tracer, Visualizer};
+use crate::core::{throw, Failure, Params, TypeId, Value};
+use crate::nodes::{NodeKey, RunningProcesses, Select, Tracer, Visualizer};
 use graph::{EntryId, Graph, InvalidationResult, NodeContext};
 use indexmap::IndexMap;
 use log::{debug, info, warn};
@@ -39,6 +41,11 @@ struct InnerSession {
   should_record_zipkin_spans: bool,
   // A place to store info about workunits in rust part
   workunit_store: WorkUnitStore,
+  // Taken and sent on to cancel the Session, which completes `cancelled`.
+  cancellation: Mutex<Option<oneshot::Sender<()>>>,
+  cancelled: Shared<oneshot::Receiver<()>>,
+  // The processes which were started by requesting a RunningProcess in this session.
+  running_processes: RunningProcesses,
 }
 
 #[derive(Clone)]
@@ -51,6 +58,7 @@ impl Session {
     should_render_ui: bool,
     ui_worker_count: usize,
   ) -> Session {
+    let (cancellation, cancelled) = oneshot::channel();
     let inner_session = InnerSession {
       preceding_graph_size: scheduler.core.graph.len(),
       roots: Mutex::new(HashSet::new()),
@@ -58,10 +66,45 @@ impl Session {
         .map(|x| Arc::new(Mutex::new(x))),
       should_record_zipkin_spans: should_record_zipkin_spans,
       workunit_store: WorkUnitStore::new(),
+      cancellation: Mutex::new(Some(cancellation)),
+      cancelled: cancelled.shared(),
+      running_processes: RunningProcesses::default(),
     };
     Session(Arc::new(inner_session))
   }
 
+  ///
+  /// Cancels the running and future requests of this Session, which fail. Work which no other
+  /// Session is waiting for is dropped, including process executions.
+  ///
+  pub fn cancel(&self) {
+    if let Some(cancellation) = self.0.cancellation.lock().take() {
+      let _ = cancellation.send(());
+    }
+  }
+
+  ///
+  /// Waits for the given work unless this Session is cancelled (or dropped) first, in which case
+  /// the work is dropped, which cancels any of its Nodes that no other Session is waiting for, and
+  /// None is returned.
+  ///
+  pub(crate) fn unless_cancelled<F: Future>(
+    &self,
+    work: F,
+  ) -> impl Future<Item = Option<F::Item>, Error = F::Error> {
+    work
+      .select2(self.0.cancelled.clone())
+      .then(|res| match res {
+        Ok(Either::A((item, _))) => Ok(Some(item)),
+        Err(Either::A((err, _))) => Err(err),
+        Ok(Either::B(_)) | Err(Either::B(_)) => Ok(None),
+      })
+  }
+
+  pub fn running_processes(&self) -> &RunningProcesses {
+    &self.0.running_processes
+  }
+
   fn extend(&self, new_roots: &[Root]) {
     let mut roots = self.0.roots.lock();
     roots.extend(new_roots.iter().cloned());
@@ -207,6 +250,15 @@ impl Scheduler {
       session.preceding_graph_size() as i64,
     );
     m.insert("resulting_graph_size", self.core.graph.len() as i64);
+    m.insert(
+      "orphaned_executions_cancelled",
+      self
+        .core
+        .orphaned_executions_cancelled
+        .load(Ordering::SeqCst) as i64,
+    );
+    // Counters recorded during the session, such as those of remote execution.
+    m.extend(session.workunit_store().get_counters());
     m
   }
 
@@ -261,13 +313,23 @@ impl Scheduler {
     );
 
     // If the join failed (due to `Invalidated`, since that is the only error we propagate), retry
-    // the entire set of roots.
+    // the entire set of roots. If the Session is cancelled first, the roots are dropped.
+    let roots_res = context.session.unless_cancelled(roots_res);
     core.executor.spawn_and_ignore(roots_res.then(move |res| {
-      if let Ok(res) = res {
-        sender.send(res).map_err(|_| ())
-      } else {
-        Scheduler::execute_helper(context, sender, roots, count - 1);
-        Ok(())
+      match res {
+        Ok(Some(res)) => sender.send(res).map_err(|_| ()),
+        Err(_) => {
+          Scheduler::execute_helper(context, sender, roots, count - 1);
+          Ok(())
+        }
+        Ok(None) => sender
+          .send(
+            roots
+              .iter()
+              .map(|_| Err(throw("The session was cancelled.")))
+              .collect(),
+          )
+          .map_err(|_| ()),
       }
     }));
   }
@@ -388,6 +450,9 @@ impl Drop for Scheduler {
     // Because Nodes may hold references to the Core in their closure, this is intended to
     // break cycles between Nodes and the Core.
     self.core.graph.clear();
+    // Clearing the graph drops any running remote executions, whose cancellations must be sent
+    // before the Core (and so its Executor) is dropped.
+    self.core.flush_cancellations();
   }
 }
 
@@ -427,3 +492,247 @@ impl NodeContext for RootContext {
     self.core.executor.spawn_and_ignore(future);
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::{RootContext, Scheduler, Session};
+
+  use std::collections::{BTreeMap, BTreeSet};
+  use std::path::Path;
+  use std::sync::atomic::Ordering;
+  use std::thread;
+  use std::time::Duration;
+
+  use bazel_protos::remote_execution::ActionResult;
+  use bytes::Bytes;
+  use futures::future::Future;
+  use hashing::EMPTY_DIGEST;
+  use mock::execution_server::{ExpectedRpc, MockExecution, MockOperation, RpcKind, RpcMatcher};
+  use process_execution::{ExecuteProcessRequest, ExecutionLocality, Platform};
+  use tempfile::TempDir;
+
+  use crate::context::Core;
+  use crate::core::Failure;
+  use crate::nodes::{MultiPlatformExecuteProcess, NodeKey, NodeResult};
+  use crate::tasks::{self, Tasks};
+
+  const OP_NAME: &str = "gimme-foo";
+
+  fn scheduler(dir: &Path, execution_server: &str, cas: &mock::StubCAS) -> Scheduler {
+    let core = Core::new(
+      vec![],
+      Tasks::new(),
+      tasks::tests::types(),
+      dir.to_path_buf(),
+      &[],
+      dir.join("store"),
+      true,
+      vec![cas.address()],
+      Some(execution_server.to_owned()),
+      None,
+      None,
+      None,
+      None,
+      0,
+      1024 * 1024,
+      Duration::from_secs(30),
+      1,
+      1,
+      0,
+      vec![],
+      false,
+      Duration::from_secs(600),
+      "{}".to_owned(),
+      None,
+      1,
+      1,
+      false,
+      Duration::from_millis(0),
+      "none".to_owned(),
+      false,
+      true,
+      0,
+      Duration::from_secs(1),
+      false,
+      false,
+      "{}".to_owned(),
+      None,
+      None,
+      false,
+      None,
+      "off".to_owned(),
+      vec![],
+    )
+    .unwrap();
+    Scheduler::new(core)
+  }
+
+  fn echo_foo() -> NodeKey {
+    let request = ExecuteProcessRequest {
+      argv: vec!["/bin/echo".to_owned(), "-n".to_owned(), "foo".to_owned()],
+      env: BTreeMap::new(),
+      input_files: EMPTY_DIGEST,
+      output_files: BTreeSet::new(),
+      output_directories: BTreeSet::new(),
+      timeout: Duration::from_secs(10),
+      description: "echo-a-foo".to_owned(),
+      jdk_home: None,
+      target_platform: Platform::None,
+      force_rerun: false,
+      expected_output_digest: None,
+      diff_outputs: false,
+      ephemeral_input_digests: vec![],
+      scheduling_hints: None,
+      poll_interval_hint: None,
+      cache_scope_names: BTreeSet::new(),
+      argfile_threshold: None,
+      argfile_flag_template: None,
+      provenance: None,
+      environment: None,
+      omit_cache_key_gen_version: false,
+      expected_output_size: None,
+      execution_locality: ExecutionLocality::Any,
+    };
+    NodeKey::MultiPlatformExecuteProcess(Box::new(MultiPlatformExecuteProcess(request.into())))
+  }
+
+  ///
+  /// Requests the node in the given Session, unless it is cancelled first.
+  ///
+  fn request(
+    scheduler: &Scheduler,
+    session: &Session,
+    node: NodeKey,
+  ) -> impl Future<Item = Option<NodeResult>, Error = Failure> {
+    let context = RootContext {
+      core: scheduler.core.clone(),
+      session: session.clone(),
+    };
+    let work = session.unless_cancelled(scheduler.core.graph.create(node, &context));
+    scheduler.core.executor.spawn_oneshot(work)
+  }
+
+  fn received(mock_server: &mock::execution_server::TestServer, message_type: &str) -> bool {
+    mock_server
+      .mock_responder
+      .received_messages
+      .lock()
+      .iter()
+      .any(|m| m.message_type == message_type)
+  }
+
+  fn cancelled_operations(mock_server: &mock::execution_server::TestServer) -> Vec<String> {
+    mock_server
+      .mock_responder
+      .cancelation_requests
+      .lock()
+      .iter()
+      .map(|req| req.get_name().to_owned())
+      .collect()
+  }
+
+  ///
+  /// Polls the condition until it holds, or until a generous timeout has elapsed.
+  ///
+  fn eventually(condition: impl Fn() -> bool) -> bool {
+    for _ in 0..1000 {
+      if condition() {
+        return true;
+      }
+      thread::sleep(Duration::from_millis(10));
+    }
+    false
+  }
+
+  #[test]
+  fn cancelling_a_session_cancels_its_remote_executions() {
+    let mock_server = mock::execution_server::TestServer::new(
+      MockExecution::scripted(
+        OP_NAME.to_owned(),
+        vec![
+          ExpectedRpc::new(
+            RpcKind::Execute,
+            RpcMatcher::Any,
+            MockOperation::incomplete(OP_NAME),
+          ),
+          // Delayed so that the execution is still running when the session is cancelled.
+          ExpectedRpc::get_operation(OP_NAME, MockOperation::incomplete(OP_NAME))
+            .with_delay(Duration::from_secs(2)),
+          ExpectedRpc::cancel_operation(OP_NAME),
+        ],
+      ),
+      None,
+    );
+    let cas = mock::StubCAS::empty();
+    let dir = TempDir::new().unwrap();
+    let scheduler = scheduler(dir.path(), &mock_server.address(), &cas);
+    let session = Session::new(&scheduler, false, false, 0);
+
+    let result = request(&scheduler, &session, echo_foo());
+    assert!(eventually(|| received(&mock_server, "GetOperationRequest")));
+    session.cancel();
+
+    match result.wait() {
+      Ok(None) => (),
+      _ => panic!("Expected the request to be cancelled with the Session."),
+    }
+    assert!(eventually(|| !cancelled_operations(&mock_server).is_empty()));
+    assert_eq!(cancelled_operations(&mock_server), vec![OP_NAME.to_owned()]);
+    assert_eq!(
+      scheduler
+        .core
+        .orphaned_executions_cancelled
+        .load(Ordering::SeqCst),
+      1
+    );
+  }
+
+  #[test]
+  fn cancelling_a_session_does_not_cancel_executions_another_session_waits_for() {
+    let mut action_result = ActionResult::new();
+    action_result.set_stdout_raw(Bytes::from("foo"));
+    action_result.set_exit_code(0);
+    let mock_server = mock::execution_server::TestServer::new(
+      MockExecution::scripted(
+        OP_NAME.to_owned(),
+        vec![
+          ExpectedRpc::new(
+            RpcKind::Execute,
+            RpcMatcher::Any,
+            MockOperation::incomplete(OP_NAME),
+          ),
+          ExpectedRpc::get_operation(OP_NAME, MockOperation::successful(OP_NAME, action_result))
+            .with_delay(Duration::from_secs(1)),
+        ],
+      ),
+      None,
+    );
+    let cas = mock::StubCAS::empty();
+    let dir = TempDir::new().unwrap();
+    let scheduler = scheduler(dir.path(), &mock_server.address(), &cas);
+    let cancelled_session = Session::new(&scheduler, false, false, 0);
+    let session = Session::new(&scheduler, false, false, 0);
+
+    let cancelled_result = request(&scheduler, &cancelled_session, echo_foo());
+    let result = request(&scheduler, &session, echo_foo());
+    assert!(eventually(|| received(&mock_server, "GetOperationRequest")));
+    cancelled_session.cancel();
+
+    match cancelled_result.wait() {
+      Ok(None) => (),
+      _ => panic!("Expected the request to be cancelled with the Session."),
+    }
+    match result.wait() {
+      Ok(Some(NodeResult::ProcessResult(_))) => (),
+      _ => panic!("Expected the execution to complete for the Session which was not cancelled."),
+    }
+    assert_eq!(cancelled_operations(&mock_server), Vec::<String>::new());
+    assert_eq!(
+      scheduler
+        .core
+        .orphaned_executions_cancelled
+        .load(Ordering::SeqCst),
+      0
+    );
+  }
+}
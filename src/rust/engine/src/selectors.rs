@@ -6,8 +6,9 @@ use std::fmt;
 use crate::core::TypeId;
 
 use rule_graph;
+use serde_derive::{Deserialize, Serialize};
 
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub struct Get {
   pub product: TypeId,
   pub subject: TypeId,
@@ -19,7 +20,7 @@ impl fmt::Display for Get {
   }
 }
 
-#[derive(Clone, Copy, Eq, Hash, PartialEq)]
+#[derive(Clone, Copy, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub struct Select {
   pub product: TypeId,
 }
@@ -3,14 +3,23 @@
 
 use std::collections::HashMap;
 use std::fmt;
+use std::hash::{Hash, Hasher};
 
-use crate::core::{Function, TypeId};
+use crate::core::{Function, TypeId, ANY_TYPE};
 use crate::selectors::{DependencyKey, Get, Select};
+use crate::type_index::TypeIndex;
 use crate::types::Types;
 
-use rule_graph;
+use fnv::FnvHasher;
+use log::warn;
+use rule_graph::{self, DisabledProvider, RuleGraph};
+use serde_derive::{Deserialize, Serialize};
 
-#[derive(Eq, Hash, PartialEq, Clone, Debug)]
+// The version of the format of Tasks snapshots, which must be bumped whenever the serialized form
+// of Tasks or of its rules changes.
+const SNAPSHOT_VERSION: u32 = 3;
+
+#[derive(Eq, Hash, PartialEq, Clone, Debug, Deserialize, Serialize)]
 pub enum Rule {
   // Intrinsic rules are implemented in rust.
   Intrinsic(Intrinsic),
@@ -47,6 +56,51 @@ impl rule_graph::Rule for Rule {
   }
 }
 
+impl Rule {
+  pub fn estimated_cost(&self) -> Option<RuleCost> {
+    match self {
+      &Rule::Task(ref task) => task.estimated_cost,
+      &Rule::Intrinsic(ref intrinsic) => intrinsic.estimated_cost,
+    }
+  }
+
+  ///
+  /// The rule with each of the TypeIds and Functions which it refers to replaced by the given
+  /// functions.
+  ///
+  fn map_ids<T, F>(&self, type_id: &mut T, function: &mut F) -> Result<Rule, String>
+  where
+    T: FnMut(TypeId) -> Result<TypeId, String>,
+    F: FnMut(Function) -> Result<Function, String>,
+  {
+    match self {
+      &Rule::Task(ref task) => Ok(Rule::Task(Task {
+        product: type_id(task.product)?,
+        clause: task
+          .clause
+          .iter()
+          .map(|select| type_id(select.product).map(Select::new))
+          .collect::<Result<_, _>>()?,
+        gets: task
+          .gets
+          .iter()
+          .map(|get| {
+            Ok(Get {
+              product: type_id(get.product)?,
+              subject: type_id(get.subject)?,
+            })
+          })
+          .collect::<Result<_, String>>()?,
+        func: function(task.func)?,
+        cacheable: task.cacheable,
+        goal: task.goal.clone(),
+        estimated_cost: task.estimated_cost,
+      })),
+      &Rule::Intrinsic(ref intrinsic) => Ok(Rule::Intrinsic(intrinsic.map_ids(type_id)?)),
+    }
+  }
+}
+
 impl fmt::Display for Rule {
   fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
     match self {
@@ -71,28 +125,414 @@ impl fmt::Display for Rule {
           format!("[{}], ", get_portion)
         };
 
+        if let Some(ref goal) = task.goal {
+          write!(f, "goal `{}` ", goal)?;
+        }
         write!(
           f,
           "({}, {}, {}{})",
           product, clause_portion, get_portion, task.func,
-        )
+        )?;
       }
       &Rule::Intrinsic(ref intrinsic) => write!(
         f,
         "({}, [{}], <intrinsic>)",
         intrinsic.product, intrinsic.input,
-      ),
+      )?,
+    }
+    if let Some(cost) = self.estimated_cost() {
+      write!(f, " (estimated cost: {})", cost)?;
     }
+    Ok(())
+  }
+}
+
+///
+/// A coarse estimate of how expensive it is to run a rule, which the scheduler may use to start
+/// expensive work on the critical path ahead of cheap work. Ordered from cheapest to most
+/// expensive.
+///
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub enum RuleCost {
+  Cheap,
+  Moderate,
+  // For example, rules which run processes.
+  Expensive,
+}
+
+impl fmt::Display for RuleCost {
+  fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+    let name = match self {
+      RuleCost::Cheap => "cheap",
+      RuleCost::Moderate => "moderate",
+      RuleCost::Expensive => "expensive",
+    };
+    write!(f, "{}", name)
   }
 }
 
-#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Task {
   pub product: TypeId,
   pub clause: Vec<Select>,
   pub gets: Vec<Get>,
   pub func: Function,
   pub cacheable: bool,
+  // Set for goal rules: the name by which a user invokes the goal.
+  pub goal: Option<String>,
+  // A scheduling hint only: it is not part of the identity of the Task, so two Tasks which differ
+  // only in their estimated costs are duplicates.
+  pub estimated_cost: Option<RuleCost>,
+}
+
+impl Task {
+  ///
+  /// Whether the Task produces, selects or Gets the given type.
+  ///
+  pub fn involves(&self, type_id: TypeId) -> bool {
+    self.product == type_id
+      || self.clause.iter().any(|select| select.product == type_id)
+      || self.gets.iter().any(|get| get.product == type_id)
+  }
+}
+
+impl PartialEq for Task {
+  fn eq(&self, other: &Task) -> bool {
+    self.product == other.product
+      && self.clause == other.clause
+      && self.gets == other.gets
+      && self.func == other.func
+      && self.cacheable == other.cacheable
+      && self.goal == other.goal
+  }
+}
+
+impl Eq for Task {}
+
+impl Hash for Task {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    self.product.hash(state);
+    self.clause.hash(state);
+    self.gets.hash(state);
+    self.func.hash(state);
+    self.cacheable.hash(state);
+    self.goal.hash(state);
+  }
+}
+
+///
+/// An error encountered while registering a rule in Tasks.
+///
+/// Holds the relevant Functions rather than a rendered message, because rendering a Function
+/// requires the externs.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RegistrationError {
+  // Two goal rules were registered with the same goal name.
+  DuplicateGoal {
+    goal: String,
+    first: Function,
+    second: Function,
+  },
+  // An identical rule was registered twice for a product, under DuplicateRulePolicy::Error. The
+  // sequence numbers give the order in which the two registrations happened.
+  DuplicateRule {
+    product: TypeId,
+    rule: Rule,
+    first_sequence: usize,
+    second_sequence: usize,
+  },
+  // A rule was removed or replaced after a RuleGraph had been built from the Tasks.
+  ModifiedAfterGraphBuilt {
+    product: TypeId,
+  },
+  // replace_rule was called, but no registered rule matched.
+  NoRuleToReplace {
+    product: TypeId,
+  },
+}
+
+impl fmt::Display for RegistrationError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+    match self {
+      &RegistrationError::DuplicateGoal {
+        ref goal,
+        ref first,
+        ref second,
+      } => write!(
+        f,
+        "Goal `{}` was registered by both {} and {}: goal names must be unique.",
+        goal, first, second,
+      ),
+      &RegistrationError::DuplicateRule {
+        ref product,
+        ref rule,
+        first_sequence,
+        second_sequence,
+      } => write!(
+        f,
+        "{}",
+        render_double_registration(
+          rule,
+          product,
+          DuplicateRulePolicy::Error,
+          first_sequence,
+          second_sequence,
+        )
+      ),
+      &RegistrationError::ModifiedAfterGraphBuilt { ref product } => write!(
+        f,
+        "Cannot remove or replace rules for {} after a rule graph has been built.",
+        product,
+      ),
+      &RegistrationError::NoRuleToReplace { ref product } => write!(
+        f,
+        "No registered rule for {} matched for replacement.",
+        product
+      ),
+    }
+  }
+}
+
+///
+/// Renders the double registration of a rule for a product, and what the policy did about it.
+///
+/// Takes the rule and product as Display rather than as a Rule and TypeId, so that it can be tested
+/// without the externs.
+///
+fn render_double_registration(
+  rule: &dyn fmt::Display,
+  product: &dyn fmt::Display,
+  policy: DuplicateRulePolicy,
+  first_sequence: usize,
+  second_sequence: usize,
+) -> String {
+  let resolution = match policy {
+    DuplicateRulePolicy::Error => format!(
+      "first as registration #{}, and again as #{}.",
+      first_sequence, second_sequence
+    ),
+    DuplicateRulePolicy::KeepFirst => format!(
+      "keeping registration #{}, and ignoring #{}.",
+      first_sequence, second_sequence
+    ),
+    DuplicateRulePolicy::KeepLast => format!(
+      "replacing registration #{} with #{}.",
+      first_sequence, second_sequence
+    ),
+  };
+  format!(
+    "{} was double-registered for {}: {}",
+    rule, product, resolution
+  )
+}
+
+///
+/// What Tasks should do when an identical rule is registered twice for the same product.
+///
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum DuplicateRulePolicy {
+  // Fail the second registration.
+  Error,
+  // Ignore the second registration, with a warning.
+  KeepFirst,
+  // Replace the first registration with the second, with a warning.
+  KeepLast,
+}
+
+///
+/// Which of the optional intrinsics Tasks::intrinsics_set should register. All are enabled by
+/// default: disabling one causes the rules which depend on it to fail rule graph validation,
+/// rather than to run.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct IntrinsicFlags {
+  // Fetching URLs into Snapshots, which touches the network.
+  pub url_fetch: bool,
+  // Running processes, whether locally or remotely.
+  pub process_execution: bool,
+}
+
+impl Default for IntrinsicFlags {
+  fn default() -> IntrinsicFlags {
+    IntrinsicFlags {
+      url_fetch: true,
+      process_execution: true,
+    }
+  }
+}
+
+///
+/// A field of IntrinsicFlags, which the optional intrinsics are registered under.
+///
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum IntrinsicFlag {
+  UrlFetch,
+  ProcessExecution,
+}
+
+impl IntrinsicFlag {
+  pub fn name(self) -> &'static str {
+    match self {
+      IntrinsicFlag::UrlFetch => "url_fetch",
+      IntrinsicFlag::ProcessExecution => "process_execution",
+    }
+  }
+
+  fn is_enabled(self, flags: &IntrinsicFlags) -> bool {
+    match self {
+      IntrinsicFlag::UrlFetch => flags.url_fetch,
+      IntrinsicFlag::ProcessExecution => flags.process_execution,
+    }
+  }
+}
+
+///
+/// Identifies the registrations which a snapshot of Tasks is valid for: those of a particular set
+/// of plugins, against particular Types. Because Functions and TypeIds are ids which are only
+/// meaningful to the process which interned them, the Types are identified by the stable names of
+/// what they hold (the module-qualified names of their functions and types, in the order of the
+/// fields of Types) rather than by their ids.
+///
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SnapshotKey {
+  plugins: u64,
+  types: u64,
+}
+
+impl SnapshotKey {
+  pub fn new<'a, P, T>(plugins: P, type_names: T) -> SnapshotKey
+  where
+    P: IntoIterator<Item = &'a str>,
+    T: IntoIterator<Item = &'a str>,
+  {
+    // The order in which plugins are registered does not affect which rules they register.
+    let mut plugins = plugins.into_iter().collect::<Vec<_>>();
+    plugins.sort();
+    SnapshotKey {
+      plugins: fnv_hash(&plugins),
+      types: fnv_hash(&type_names.into_iter().collect::<Vec<_>>()),
+    }
+  }
+}
+
+fn fnv_hash<T: Hash + ?Sized>(value: &T) -> u64 {
+  let mut hasher = FnvHasher::default();
+  value.hash(&mut hasher);
+  hasher.finish()
+}
+
+///
+/// Stable names (such as the module-qualified names of python types and functions) for the
+/// TypeIds and Functions which a process has interned. A snapshot records the names of the ids
+/// which its rules refer to, so that another process, which will have interned the same types and
+/// functions as different ids, can restore it.
+///
+#[derive(Clone, Default)]
+pub struct InternedNames {
+  type_names: HashMap<TypeId, String>,
+  type_ids: HashMap<String, TypeId>,
+  function_names: HashMap<Function, String>,
+  functions: HashMap<String, Function>,
+}
+
+impl InternedNames {
+  pub fn new<T, F>(types: T, functions: F) -> InternedNames
+  where
+    T: IntoIterator<Item = (TypeId, String)>,
+    F: IntoIterator<Item = (Function, String)>,
+  {
+    let mut names = InternedNames::default();
+    for (type_id, name) in types {
+      names.type_ids.insert(name.clone(), type_id);
+      names.type_names.insert(type_id, name);
+    }
+    for (function, name) in functions {
+      names.functions.insert(name.clone(), function);
+      names.function_names.insert(function, name);
+    }
+    names
+  }
+
+  // NB: Rendering a TypeId or a Function requires the externs, so errors refer to their ids.
+
+  fn type_name(&self, type_id: TypeId) -> Result<String, String> {
+    self.type_names.get(&type_id).cloned().ok_or_else(|| {
+      format!(
+        "Cannot snapshot Tasks: no name was given for TypeId {}.",
+        type_id.0
+      )
+    })
+  }
+
+  fn function_name(&self, function: Function) -> Result<String, String> {
+    self.function_names.get(&function).cloned().ok_or_else(|| {
+      format!(
+        "Cannot snapshot Tasks: no name was given for Function {}.",
+        function.0.id()
+      )
+    })
+  }
+
+  fn type_id(&self, name: &str) -> Result<TypeId, String> {
+    self.type_ids.get(name).cloned().ok_or_else(|| {
+      format!(
+        "Tasks snapshot refers to the type `{}`, which was not given.",
+        name
+      )
+    })
+  }
+
+  fn function(&self, name: &str) -> Result<Function, String> {
+    self.functions.get(name).cloned().ok_or_else(|| {
+      format!(
+        "Tasks snapshot refers to the function `{}`, which was not given.",
+        name
+      )
+    })
+  }
+}
+
+///
+/// The serialized form of Tasks. The type index and whether a graph has been built are derived
+/// state, so are not included.
+///
+#[derive(Deserialize, Serialize)]
+struct TasksSnapshot {
+  // The names of the TypeIds and Functions which the rules refer to, by their ids in the process
+  // which took the snapshot.
+  type_names: Vec<(TypeId, String)>,
+  function_names: Vec<(Function, String)>,
+  rules: Vec<(TypeId, Vec<Rule>)>,
+  sequences: Vec<(Rule, usize)>,
+  next_sequence: usize,
+  duplicate_rule_policy: DuplicateRulePolicy,
+  disabled_intrinsics: Vec<(Intrinsic, IntrinsicFlag)>,
+}
+
+impl TasksSnapshot {
+  ///
+  /// Replaces each of the TypeIds and Functions which the rules refer to by the given functions.
+  ///
+  fn map_ids<T, F>(&mut self, type_id: &mut T, function: &mut F) -> Result<(), String>
+  where
+    T: FnMut(TypeId) -> Result<TypeId, String>,
+    F: FnMut(Function) -> Result<Function, String>,
+  {
+    for (product, rules) in &mut self.rules {
+      *product = type_id(*product)?;
+      for rule in rules.iter_mut() {
+        *rule = rule.map_ids(type_id, function)?;
+      }
+    }
+    for (rule, _) in &mut self.sequences {
+      *rule = rule.map_ids(type_id, function)?;
+    }
+    for (intrinsic, _) in &mut self.disabled_intrinsics {
+      *intrinsic = intrinsic.map_ids(type_id)?;
+    }
+    Ok(())
+  }
 }
 
 ///
@@ -104,15 +544,27 @@ pub struct Tasks {
   rules: HashMap<TypeId, Vec<Rule>>,
   // Used during the construction of the tasks map.
   preparing: Option<Task>,
+  // The sequence number of the registration which is currently providing each rule.
+  sequences: HashMap<Rule, usize>,
+  // The sequence number that will be assigned to the next registration.
+  next_sequence: usize,
+  duplicate_rule_policy: DuplicateRulePolicy,
+  // Computed lazily from the rules map, and cleared whenever it changes.
+  type_index: Option<TypeIndex>,
+  // Set once a RuleGraph has been built, after which rules may no longer be removed.
+  graph_built: bool,
+  // Intrinsics which were not registered, with the flag which disabled each.
+  disabled_intrinsics: Vec<(Intrinsic, IntrinsicFlag)>,
 }
 
 ///
 /// A collection of Rules (TODO: rename to Rules).
 ///
 /// Defines a stateful lifecycle for defining tasks via the C api. Call in order:
-///   1. task_begin() - once per task
-///   2. add_*() - zero or more times per task to add input clauses
-///   3. task_end() - once per task
+///   1. task_begin() (or goal_begin() for a goal rule) - once per task
+///   2. add_*() - zero or more times per task to add input clauses, and optionally
+///      set_estimated_cost()
+///   3. task_end() (or goal_end() for a goal rule) - once per task
 ///
 /// (This protocol was original defined in a Builder, but that complicated the C lifecycle.)
 ///
@@ -121,52 +573,383 @@ impl Tasks {
     Tasks {
       rules: HashMap::default(),
       preparing: None,
+      sequences: HashMap::default(),
+      next_sequence: 0,
+      duplicate_rule_policy: DuplicateRulePolicy::Error,
+      type_index: None,
+      graph_built: false,
+      disabled_intrinsics: Vec::new(),
     }
   }
 
+  ///
+  /// Sets the policy for identical rules registered twice for the same product. Defaults to
+  /// DuplicateRulePolicy::Error. Must be called before any rules are registered.
+  ///
+  pub fn set_duplicate_rule_policy(&mut self, policy: DuplicateRulePolicy) {
+    assert!(
+      self.next_sequence == 0 && self.preparing.is_none(),
+      "Must set the duplicate rule policy before registering any rules!"
+    );
+    self.duplicate_rule_policy = policy;
+  }
+
   pub fn as_map(&self) -> &HashMap<TypeId, Vec<Rule>> {
     &self.rules
   }
 
-  pub fn intrinsics_set(&mut self, types: &Types) {
-    let intrinsics = vec![
-      Intrinsic {
-        product: types.directory_digest,
-        input: types.input_files_content,
-      },
-      Intrinsic {
-        product: types.snapshot,
-        input: types.path_globs,
-      },
-      Intrinsic {
-        product: types.snapshot,
-        input: types.url_to_fetch,
-      },
-      Intrinsic {
-        product: types.snapshot,
-        input: types.directory_digest,
-      },
-      Intrinsic {
-        product: types.files_content,
-        input: types.directory_digest,
+  ///
+  /// Serializes the registered rules, so that a later process which would register the same rules
+  /// (as identified by the key) can restore them with from_snapshot instead. Every TypeId and
+  /// Function which the rules refer to must be named by `names`. Must not be called while a task
+  /// is being registered.
+  ///
+  pub fn snapshot(&self, key: &SnapshotKey, names: &InternedNames) -> Result<Vec<u8>, String> {
+    assert!(
+      self.preparing.is_none(),
+      "Must `end()` the current task creation before snapshotting!"
+    );
+    let mut body = TasksSnapshot {
+      type_names: vec![],
+      function_names: vec![],
+      rules: self
+        .rules
+        .iter()
+        .map(|(product, rules)| (*product, rules.clone()))
+        .collect(),
+      sequences: self
+        .sequences
+        .iter()
+        .map(|(rule, sequence)| (rule.clone(), *sequence))
+        .collect(),
+      next_sequence: self.next_sequence,
+      duplicate_rule_policy: self.duplicate_rule_policy,
+      disabled_intrinsics: self.disabled_intrinsics.clone(),
+    };
+    let mut type_names = HashMap::new();
+    let mut function_names = HashMap::new();
+    body.map_ids(
+      &mut |type_id: TypeId| {
+        if type_id != ANY_TYPE {
+          type_names.insert(type_id, names.type_name(type_id)?);
+        }
+        Ok(type_id)
       },
-      Intrinsic {
-        product: types.directory_digest,
-        input: types.directories_to_merge,
+      &mut |function: Function| {
+        function_names.insert(function, names.function_name(function)?);
+        Ok(function)
       },
-      Intrinsic {
-        product: types.directory_digest,
-        input: types.directory_with_prefix_to_strip,
+    )?;
+    body.type_names = type_names.into_iter().collect();
+    body.function_names = function_names.into_iter().collect();
+    // The version and key are serialized ahead of (and separately from) the body, so that they can
+    // be validated even if the body is in a format that this version cannot read.
+    let body = bincode::serialize(&body).expect("Failed to serialize Tasks");
+    Ok(bincode::serialize(&(SNAPSHOT_VERSION, key, body)).expect("Failed to serialize Tasks"))
+  }
+
+  ///
+  /// Restores Tasks from a snapshot, re-interning the TypeIds and Functions which its rules refer
+  /// to as those which `names` gives for their names. Fails if the snapshot was taken by a
+  /// different version of this format, or for a different key, or refers to a name which `names`
+  /// does not give, or is corrupt.
+  ///
+  pub fn from_snapshot(
+    bytes: &[u8],
+    key: &SnapshotKey,
+    names: &InternedNames,
+  ) -> Result<Tasks, String> {
+    let (version, snapshot_key, body): (u32, SnapshotKey, Vec<u8>) =
+      bincode::deserialize(bytes).map_err(|e| format!("Tasks snapshot was corrupt: {}", e))?;
+    if version != SNAPSHOT_VERSION {
+      return Err(format!(
+        "Tasks snapshot has version {}, but only version {} is supported.",
+        version, SNAPSHOT_VERSION
+      ));
+    }
+    if snapshot_key != *key {
+      return Err(format!(
+        "Tasks snapshot is stale: it was taken for {:?}, rather than {:?}.",
+        snapshot_key, key
+      ));
+    }
+    let mut body: TasksSnapshot =
+      bincode::deserialize(&body).map_err(|e| format!("Tasks snapshot was corrupt: {}", e))?;
+    let type_ids = body
+      .type_names
+      .iter()
+      .map(|(snapshotted, name)| Ok((*snapshotted, names.type_id(name)?)))
+      .collect::<Result<HashMap<_, _>, String>>()?;
+    let functions = body
+      .function_names
+      .iter()
+      .map(|(snapshotted, name)| Ok((*snapshotted, names.function(name)?)))
+      .collect::<Result<HashMap<_, _>, String>>()?;
+    body.map_ids(
+      &mut |type_id: TypeId| {
+        if type_id == ANY_TYPE {
+          return Ok(type_id);
+        }
+        type_ids.get(&type_id).cloned().ok_or_else(|| {
+          format!(
+            "Tasks snapshot was corrupt: TypeId {} was not named.",
+            type_id.0
+          )
+        })
       },
-      Intrinsic {
-        product: types.process_result,
-        input: types.multi_platform_process_request,
+      &mut |function: Function| {
+        functions.get(&function).cloned().ok_or_else(|| {
+          format!(
+            "Tasks snapshot was corrupt: Function {} was not named.",
+            function.0.id()
+          )
+        })
       },
+    )?;
+    Ok(Tasks {
+      rules: body.rules.into_iter().collect(),
+      preparing: None,
+      sequences: body.sequences.into_iter().collect(),
+      next_sequence: body.next_sequence,
+      duplicate_rule_policy: body.duplicate_rule_policy,
+      type_index: None,
+      graph_built: false,
+      disabled_intrinsics: body.disabled_intrinsics,
+    })
+  }
+
+  ///
+  /// Builds a RuleGraph from the registered rules. Once a graph has been built, rules may no longer
+  /// be removed or replaced, because the graph would not reflect the change.
+  ///
+  pub fn rule_graph(&mut self, root_param_types: Vec<TypeId>) -> RuleGraph<Rule> {
+    self.graph_built = true;
+    let disabled_providers = self
+      .disabled_intrinsics
+      .iter()
+      .map(|&(intrinsic, flag)| DisabledProvider {
+        product: intrinsic.product,
+        input: intrinsic.input,
+        provider: "intrinsic".to_owned(),
+        disabled_by: format!("IntrinsicFlags.{}", flag.name()),
+      })
+      .collect();
+    RuleGraph::new(&self.rules, root_param_types).with_disabled_providers(disabled_providers)
+  }
+
+  ///
+  /// Removes the rules for the given product which match the predicate, and returns how many were
+  /// removed. Intended for tests and plugins which need to substitute a rule before the graph is
+  /// built.
+  ///
+  pub fn remove_rule<P: Fn(&Rule) -> bool>(
+    &mut self,
+    product: TypeId,
+    predicate: P,
+  ) -> Result<usize, RegistrationError> {
+    if self.graph_built {
+      return Err(RegistrationError::ModifiedAfterGraphBuilt { product });
+    }
+    let removed = match self.rules.get_mut(&product) {
+      Some(rules) => {
+        let (removed, kept): (Vec<_>, Vec<_>) = rules.drain(..).partition(predicate);
+        *rules = kept;
+        removed
+      }
+      None => vec![],
+    };
+    if self.rules.get(&product).map_or(false, Vec::is_empty) {
+      self.rules.remove(&product);
+    }
+    for rule in &removed {
+      self.sequences.remove(rule);
+    }
+    if !removed.is_empty() {
+      self.type_index = None;
+    }
+    Ok(removed.len())
+  }
+
+  ///
+  /// Replaces the rules for the given product which match the predicate with the given rule, and
+  /// returns how many were replaced. Fails if no rule matched, in which case (as for any other
+  /// failure) the registered rules are left unchanged.
+  ///
+  pub fn replace_rule<P: Fn(&Rule) -> bool>(
+    &mut self,
+    product: TypeId,
+    predicate: P,
+    new_rule: Rule,
+  ) -> Result<usize, RegistrationError> {
+    if self.graph_built {
+      return Err(RegistrationError::ModifiedAfterGraphBuilt { product });
+    }
+    // Validate everything that could fail before removing anything, so that a failed replacement
+    // cannot leave the rules missing.
+    let existing = self.rules.get(&product).map_or(&[][..], Vec::as_slice);
+    if !existing.iter().any(|rule| predicate(rule)) {
+      return Err(RegistrationError::NoRuleToReplace { product });
+    }
+    if self.duplicate_rule_policy == DuplicateRulePolicy::Error {
+      if let Some(kept) = existing
+        .iter()
+        .find(|rule| !predicate(rule) && **rule == new_rule)
+      {
+        return Err(RegistrationError::DuplicateRule {
+          product,
+          first_sequence: self.sequences[kept],
+          second_sequence: self.next_sequence,
+          rule: new_rule,
+        });
+      }
+    }
+    let replaced = self.remove_rule(product, predicate)?;
+    self.insert_rule(product, new_rule)?;
+    Ok(replaced)
+  }
+
+  ///
+  /// An index of the registered rules by the types they produce and consume.
+  ///
+  pub fn type_index(&mut self) -> &TypeIndex {
+    let rules = &self.rules;
+    self.type_index.get_or_insert_with(|| TypeIndex::new(rules))
+  }
+
+  ///
+  /// Returns the name and product type of each registered goal rule, sorted by name.
+  ///
+  pub fn goals(&self) -> Vec<(String, TypeId)> {
+    let mut goals = self
+      .goal_tasks()
+      .map(|task| (task.goal.clone().unwrap(), task.product))
+      .collect::<Vec<_>>();
+    goals.sort_by(|a, b| a.0.cmp(&b.0));
+    goals
+  }
+
+  ///
+  /// The estimated cost of the registered rule which is equal to the given rule, if it has one.
+  ///
+  /// Because estimated costs are not part of the identity of rules, the given rule may have been
+  /// deduplicated against a registration with a different estimate: the registered one wins.
+  ///
+  pub fn estimated_cost(&self, rule: &Rule) -> Option<RuleCost> {
+    self
+      .rules
+      .values()
+      .flat_map(|rules| rules.iter())
+      .find(|registered| *registered == rule)
+      .and_then(Rule::estimated_cost)
+  }
+
+  fn goal_tasks(&self) -> impl Iterator<Item = &Task> {
+    self
+      .rules
+      .values()
+      .flat_map(|rules| rules.iter())
+      .filter_map(|rule| match rule {
+        &Rule::Task(ref task) if task.goal.is_some() => Some(task),
+        _ => None,
+      })
+  }
+
+  ///
+  /// Returns the intrinsics which intrinsics_set did not register, with the flag which disabled
+  /// each.
+  ///
+  pub fn disabled_intrinsics(&self) -> &[(Intrinsic, IntrinsicFlag)] {
+    &self.disabled_intrinsics
+  }
+
+  pub fn intrinsics_set(
+    &mut self,
+    types: &Types,
+    flags: &IntrinsicFlags,
+  ) -> Result<(), RegistrationError> {
+    // Each intrinsic, with the flag which enables it, if it is optional.
+    let intrinsics = vec![
+      (
+        Intrinsic {
+          product: types.directory_digest,
+          input: types.input_files_content,
+          estimated_cost: None,
+        },
+        None,
+      ),
+      (
+        Intrinsic {
+          product: types.snapshot,
+          input: types.path_globs,
+          estimated_cost: None,
+        },
+        None,
+      ),
+      (
+        Intrinsic {
+          product: types.snapshot,
+          input: types.url_to_fetch,
+          estimated_cost: None,
+        },
+        Some(IntrinsicFlag::UrlFetch),
+      ),
+      (
+        Intrinsic {
+          product: types.snapshot,
+          input: types.directory_digest,
+          estimated_cost: None,
+        },
+        None,
+      ),
+      (
+        Intrinsic {
+          product: types.files_content,
+          input: types.directory_digest,
+          estimated_cost: None,
+        },
+        None,
+      ),
+      (
+        Intrinsic {
+          product: types.directory_digest,
+          input: types.directories_to_merge,
+          estimated_cost: None,
+        },
+        None,
+      ),
+      (
+        Intrinsic {
+          product: types.directory_digest,
+          input: types.directory_with_prefix_to_strip,
+          estimated_cost: None,
+        },
+        None,
+      ),
+      (
+        Intrinsic {
+          product: types.process_result,
+          input: types.multi_platform_process_request,
+          estimated_cost: Some(RuleCost::Expensive),
+        },
+        Some(IntrinsicFlag::ProcessExecution),
+      ),
+      (
+        Intrinsic {
+          product: types.running_process,
+          input: types.multi_platform_process_request,
+          estimated_cost: Some(RuleCost::Expensive),
+        },
+        Some(IntrinsicFlag::ProcessExecution),
+      ),
     ];
 
-    for intrinsic in intrinsics {
-      self.insert_rule(intrinsic.product, Rule::Intrinsic(intrinsic))
+    for (intrinsic, flag) in intrinsics {
+      match flag {
+        Some(flag) if !flag.is_enabled(flags) => self.disabled_intrinsics.push((intrinsic, flag)),
+        _ => self.insert_rule(intrinsic.product, Rule::Intrinsic(intrinsic))?,
+      }
     }
+    Ok(())
   }
 
   ///
@@ -184,9 +967,20 @@ impl Tasks {
       clause: Vec::new(),
       gets: Vec::new(),
       func: func,
+      goal: None,
+      estimated_cost: None,
     });
   }
 
+  ///
+  /// Begins registration of a goal rule: a user-invokable rule, identified by the given goal name,
+  /// whose product is an exit code. Goal rules have side effects, and so are never cacheable.
+  ///
+  pub fn goal_begin(&mut self, func: Function, product: TypeId, goal: String) {
+    self.task_begin(func, product, false);
+    self.preparing.as_mut().unwrap().goal = Some(goal);
+  }
+
   pub fn add_get(&mut self, product: TypeId, subject: TypeId) {
     self
       .preparing
@@ -208,30 +1002,764 @@ impl Tasks {
       .push(Select::new(product));
   }
 
-  pub fn task_end(&mut self) {
+  ///
+  /// Sets the estimated cost of the task being registered. Tasks have no estimated cost by default.
+  ///
+  pub fn set_estimated_cost(&mut self, cost: RuleCost) {
+    self
+      .preparing
+      .as_mut()
+      .expect("Must `begin()` a task creation before setting its estimated cost!")
+      .estimated_cost = Some(cost);
+  }
+
+  pub fn task_end(&mut self) -> Result<(), RegistrationError> {
     // Move the task from `preparing` to the Rules map
     let task = self
       .preparing
       .take()
       .expect("Must `begin()` a task creation before ending it!");
+    assert!(
+      task.goal.is_none(),
+      "Must `goal_end()` a goal rule creation that was begun with `goal_begin()`!"
+    );
+    self.insert_rule(task.product, Rule::Task(task))
+  }
+
+  ///
+  /// Ends registration of a goal rule, failing if another goal rule already uses its goal name.
+  ///
+  pub fn goal_end(&mut self) -> Result<(), RegistrationError> {
+    let task = self
+      .preparing
+      .take()
+      .expect("Must `goal_begin()` a goal rule creation before ending it!");
+    let goal = task
+      .goal
+      .clone()
+      .expect("Must `task_end()` a task creation that was begun with `task_begin()`!");
+    if let Some(existing) = self
+      .goal_tasks()
+      .find(|existing| existing.goal.as_ref() == Some(&goal))
+    {
+      return Err(RegistrationError::DuplicateGoal {
+        goal,
+        first: existing.func,
+        second: task.func,
+      });
+    }
     self.insert_rule(task.product, Rule::Task(task))
   }
 
-  fn insert_rule(&mut self, product: TypeId, rule: Rule) {
+  fn insert_rule(&mut self, product: TypeId, rule: Rule) -> Result<(), RegistrationError> {
+    let sequence = self.next_sequence;
+    self.next_sequence += 1;
+    self.type_index = None;
+
     let rules = self.rules.entry(product).or_insert_with(Vec::new);
-    assert!(
-      !rules.contains(&rule),
-      "{:?} was double-registered for {:?}: {:?}",
-      rule,
-      product,
-      rules,
-    );
+    if let Some(position) = rules.iter().position(|existing| existing == &rule) {
+      let first_sequence = self.sequences[&rule];
+      match self.duplicate_rule_policy {
+        DuplicateRulePolicy::Error => {
+          return Err(RegistrationError::DuplicateRule {
+            product,
+            rule,
+            first_sequence,
+            second_sequence: sequence,
+          });
+        }
+        DuplicateRulePolicy::KeepFirst => {
+          warn!(
+            "{}",
+            render_double_registration(
+              &rule,
+              &product,
+              DuplicateRulePolicy::KeepFirst,
+              first_sequence,
+              sequence,
+            )
+          );
+          return Ok(());
+        }
+        DuplicateRulePolicy::KeepLast => {
+          warn!(
+            "{}",
+            render_double_registration(
+              &rule,
+              &product,
+              DuplicateRulePolicy::KeepLast,
+              first_sequence,
+              sequence,
+            )
+          );
+          rules.remove(position);
+        }
+      }
+    }
+    self.sequences.insert(rule.clone(), sequence);
     rules.push(rule);
+    Ok(())
   }
 }
 
-#[derive(Eq, Hash, PartialEq, Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
 pub struct Intrinsic {
   pub product: TypeId,
   pub input: TypeId,
+  // As for Task, not part of the identity of the Intrinsic.
+  pub estimated_cost: Option<RuleCost>,
+}
+
+impl Intrinsic {
+  fn map_ids<T>(&self, type_id: &mut T) -> Result<Intrinsic, String>
+  where
+    T: FnMut(TypeId) -> Result<TypeId, String>,
+  {
+    Ok(Intrinsic {
+      product: type_id(self.product)?,
+      input: type_id(self.input)?,
+      estimated_cost: self.estimated_cost,
+    })
+  }
+}
+
+impl PartialEq for Intrinsic {
+  fn eq(&self, other: &Intrinsic) -> bool {
+    self.product == other.product && self.input == other.input
+  }
+}
+
+impl Eq for Intrinsic {}
+
+impl Hash for Intrinsic {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    self.product.hash(state);
+    self.input.hash(state);
+  }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::{
+    render_double_registration, DuplicateRulePolicy, InternedNames, Intrinsic, IntrinsicFlag,
+    IntrinsicFlags, RegistrationError, Rule, RuleCost, SnapshotKey, Tasks, SNAPSHOT_VERSION,
+  };
+  use crate::core::{Function, Key, TypeId};
+  use crate::types::Types;
+  use rule_graph::{DisabledProvider, RuleGraph};
+
+  // NB: Rendering a TypeId or Function requires the externs, so these tests only compare them.
+  fn function(id: u64) -> Function {
+    Function(Key::new(id, TypeId(1)))
+  }
+
+  #[test]
+  fn goals() {
+    let mut tasks = Tasks::new();
+    tasks.goal_begin(function(1), TypeId(10), "list".to_owned());
+    tasks.goal_end().unwrap();
+    tasks.task_begin(function(2), TypeId(11), true);
+    tasks.add_select(TypeId(12));
+    tasks.task_end().unwrap();
+    tasks.goal_begin(function(3), TypeId(10), "fmt".to_owned());
+    tasks.add_select(TypeId(11));
+    tasks.goal_end().unwrap();
+
+    assert_eq!(
+      tasks.goals(),
+      vec![
+        ("fmt".to_owned(), TypeId(10)),
+        ("list".to_owned(), TypeId(10)),
+      ]
+    );
+  }
+
+  #[test]
+  fn tasks_involve_their_products_selects_and_gets() {
+    let mut tasks = Tasks::new();
+    tasks.task_begin(function(1), TypeId(10), true);
+    tasks.add_select(TypeId(11));
+    tasks.add_get(TypeId(12), TypeId(13));
+    tasks.task_end().unwrap();
+
+    let task = match &tasks.as_map()[&TypeId(10)][0] {
+      &Rule::Task(ref task) => task.clone(),
+      &Rule::Intrinsic(_) => panic!("Expected a Task to be registered."),
+    };
+    for &type_id in &[TypeId(10), TypeId(11), TypeId(12)] {
+      assert!(task.involves(type_id), "TypeId({})", type_id.0);
+    }
+    // The subject of a Get is only its input.
+    assert!(!task.involves(TypeId(13)));
+  }
+
+  #[test]
+  fn duplicate_goal_name() {
+    let mut tasks = Tasks::new();
+    tasks.goal_begin(function(1), TypeId(10), "list".to_owned());
+    tasks.goal_end().unwrap();
+    tasks.goal_begin(function(2), TypeId(11), "list".to_owned());
+
+    assert_eq!(
+      tasks.goal_end(),
+      Err(RegistrationError::DuplicateGoal {
+        goal: "list".to_owned(),
+        first: function(1),
+        second: function(2),
+      })
+    );
+    assert_eq!(tasks.goals(), vec![("list".to_owned(), TypeId(10))]);
+  }
+
+  fn register_twice(tasks: &mut Tasks) -> Result<(), RegistrationError> {
+    tasks.task_begin(function(1), TypeId(10), true);
+    tasks.add_select(TypeId(11));
+    tasks.task_end().unwrap();
+    tasks.task_begin(function(2), TypeId(10), true);
+    tasks.task_end().unwrap();
+    tasks.task_begin(function(1), TypeId(10), true);
+    tasks.add_select(TypeId(11));
+    tasks.task_end()
+  }
+
+  fn registered_funcs(tasks: &Tasks) -> Vec<Function> {
+    tasks.as_map()[&TypeId(10)]
+      .iter()
+      .map(|rule| match rule {
+        &Rule::Task(ref task) => task.func,
+        &Rule::Intrinsic(_) => panic!("Expected only Tasks to be registered."),
+      })
+      .collect()
+  }
+
+  #[test]
+  fn duplicate_rule_error() {
+    let mut tasks = Tasks::new();
+    match register_twice(&mut tasks) {
+      Err(RegistrationError::DuplicateRule {
+        product,
+        first_sequence,
+        second_sequence,
+        ..
+      }) => {
+        assert_eq!(product, TypeId(10));
+        assert_eq!((first_sequence, second_sequence), (0, 2));
+      }
+      _ => panic!("Expected a DuplicateRule error."),
+    }
+    assert_eq!(registered_funcs(&tasks), vec![function(1), function(2)]);
+  }
+
+  #[test]
+  fn duplicate_rule_keep_first() {
+    let mut tasks = Tasks::new();
+    tasks.set_duplicate_rule_policy(DuplicateRulePolicy::KeepFirst);
+    assert_eq!(register_twice(&mut tasks), Ok(()));
+    assert_eq!(registered_funcs(&tasks), vec![function(1), function(2)]);
+  }
+
+  #[test]
+  fn duplicate_rule_keep_last() {
+    let mut tasks = Tasks::new();
+    tasks.set_duplicate_rule_policy(DuplicateRulePolicy::KeepLast);
+    assert_eq!(register_twice(&mut tasks), Ok(()));
+    assert_eq!(registered_funcs(&tasks), vec![function(2), function(1)]);
+  }
+
+  #[test]
+  fn double_registrations_render_their_order_and_resolution() {
+    let render = |policy| render_double_registration(&"Task(list)", &"Targets", policy, 0, 2);
+    assert_eq!(
+      render(DuplicateRulePolicy::Error),
+      "Task(list) was double-registered for Targets: first as registration #0, and again as #2."
+    );
+    assert_eq!(
+      render(DuplicateRulePolicy::KeepFirst),
+      "Task(list) was double-registered for Targets: keeping registration #0, and ignoring #2."
+    );
+    assert_eq!(
+      render(DuplicateRulePolicy::KeepLast),
+      "Task(list) was double-registered for Targets: replacing registration #0 with #2."
+    );
+  }
+
+  #[test]
+  #[should_panic(expected = "before registering any rules")]
+  fn duplicate_rule_policy_after_registration() {
+    let mut tasks = Tasks::new();
+    tasks.task_begin(function(1), TypeId(10), true);
+    tasks.task_end().unwrap();
+    tasks.set_duplicate_rule_policy(DuplicateRulePolicy::KeepLast);
+  }
+
+  #[test]
+  fn remove_task_by_func() {
+    let mut tasks = Tasks::new();
+    register_twice(&mut tasks).unwrap_err();
+    let removed = tasks
+      .remove_rule(TypeId(10), |rule| match rule {
+        &Rule::Task(ref task) => task.func == function(1),
+        &Rule::Intrinsic(_) => false,
+      })
+      .unwrap();
+    assert_eq!(removed, 1);
+    assert_eq!(registered_funcs(&tasks), vec![function(2)]);
+    assert_eq!(tasks.remove_rule(TypeId(10), |_| false), Ok(0));
+  }
+
+  #[test]
+  fn replace_intrinsic() {
+    let mut tasks = Tasks::new();
+    let intrinsic = Intrinsic {
+      product: TypeId(10),
+      input: TypeId(11),
+      estimated_cost: None,
+    };
+    tasks
+      .insert_rule(TypeId(10), Rule::Intrinsic(intrinsic))
+      .unwrap();
+    tasks.task_begin(function(3), TypeId(10), true);
+    let fake = tasks.preparing.take().unwrap();
+
+    let replaced = tasks.replace_rule(
+      TypeId(10),
+      |rule| rule == &Rule::Intrinsic(intrinsic),
+      Rule::Task(fake),
+    );
+    assert_eq!(replaced, Ok(1));
+    assert_eq!(registered_funcs(&tasks), vec![function(3)]);
+    assert_eq!(
+      tasks.replace_rule(
+        TypeId(10),
+        |rule| rule == &Rule::Intrinsic(intrinsic),
+        Rule::Intrinsic(intrinsic),
+      ),
+      Err(RegistrationError::NoRuleToReplace {
+        product: TypeId(10)
+      })
+    );
+  }
+
+  #[test]
+  fn failed_replacement_leaves_rules_unchanged() {
+    let mut tasks = Tasks::new();
+    register_twice(&mut tasks).unwrap_err();
+    let first = tasks.as_map()[&TypeId(10)][0].clone();
+
+    // Replacing the second rule with a copy of the first would register the first twice.
+    let result = tasks.replace_rule(
+      TypeId(10),
+      |rule| match rule {
+        &Rule::Task(ref task) => task.func == function(2),
+        &Rule::Intrinsic(_) => false,
+      },
+      first.clone(),
+    );
+    match result {
+      Err(RegistrationError::DuplicateRule { rule, .. }) => assert_eq!(rule, first),
+      other => panic!("Expected a DuplicateRule error, but got {:?}", other),
+    }
+    assert_eq!(registered_funcs(&tasks), vec![function(1), function(2)]);
+  }
+
+  #[test]
+  fn remove_after_graph_built() {
+    let mut tasks = Tasks::new();
+    tasks.task_begin(function(1), TypeId(10), true);
+    tasks.add_select(TypeId(11));
+    tasks.task_end().unwrap();
+    tasks.rule_graph(vec![TypeId(11)]);
+
+    assert_eq!(
+      tasks.remove_rule(TypeId(10), |_| true),
+      Err(RegistrationError::ModifiedAfterGraphBuilt {
+        product: TypeId(10)
+      })
+    );
+    assert_eq!(registered_funcs(&tasks), vec![function(1)]);
+  }
+
+  fn registered_rule(tasks: &Tasks, product: TypeId) -> Rule {
+    tasks.as_map()[&product][0].clone()
+  }
+
+  #[test]
+  fn estimated_cost_defaults_to_none() {
+    let mut tasks = Tasks::new();
+    tasks.task_begin(function(1), TypeId(10), true);
+    tasks.task_end().unwrap();
+
+    let rule = registered_rule(&tasks, TypeId(10));
+    assert_eq!(rule.estimated_cost(), None);
+    assert_eq!(tasks.estimated_cost(&rule), None);
+  }
+
+  #[test]
+  fn estimated_cost_is_registered() {
+    let mut tasks = Tasks::new();
+    tasks.task_begin(function(1), TypeId(10), true);
+    tasks.set_estimated_cost(RuleCost::Moderate);
+    tasks.task_end().unwrap();
+
+    let rule = registered_rule(&tasks, TypeId(10));
+    assert_eq!(rule.estimated_cost(), Some(RuleCost::Moderate));
+    assert_eq!(tasks.estimated_cost(&rule), Some(RuleCost::Moderate));
+  }
+
+  #[test]
+  fn estimated_cost_does_not_affect_dedup() {
+    let mut tasks = Tasks::new();
+    tasks.set_duplicate_rule_policy(DuplicateRulePolicy::KeepFirst);
+    tasks.task_begin(function(1), TypeId(10), true);
+    tasks.set_estimated_cost(RuleCost::Cheap);
+    tasks.task_end().unwrap();
+    tasks.task_begin(function(1), TypeId(10), true);
+    tasks.set_estimated_cost(RuleCost::Expensive);
+    tasks.task_end().unwrap();
+
+    assert_eq!(tasks.as_map()[&TypeId(10)].len(), 1);
+    // An equal rule with a different estimate reports the registered estimate.
+    let mut other = registered_rule(&tasks, TypeId(10));
+    if let Rule::Task(ref mut task) = other {
+      task.estimated_cost = None;
+    }
+    assert_eq!(tasks.estimated_cost(&other), Some(RuleCost::Cheap));
+  }
+
+  // Distinct ids for each type.
+  pub(crate) fn types() -> Types {
+    Types {
+      construct_directory_digest: function(20),
+      construct_snapshot: function(21),
+      construct_file_content: function(22),
+      construct_files_content: function(23),
+      construct_process_result: function(24),
+      construct_running_process: function(25),
+      address: TypeId(26),
+      path_globs: TypeId(27),
+      directory_digest: TypeId(28),
+      snapshot: TypeId(29),
+      directories_to_merge: TypeId(30),
+      directory_with_prefix_to_strip: TypeId(31),
+      files_content: TypeId(32),
+      input_files_content: TypeId(33),
+      dir: TypeId(34),
+      file: TypeId(35),
+      link: TypeId(36),
+      multi_platform_process_request: TypeId(37),
+      process_result: TypeId(38),
+      running_process: TypeId(39),
+      generator: TypeId(40),
+      url_to_fetch: TypeId(41),
+      string: TypeId(42),
+      bytes: TypeId(43),
+    }
+  }
+
+  #[test]
+  fn process_intrinsics_are_expensive() {
+    let types = types();
+    let mut tasks = Tasks::new();
+    tasks
+      .intrinsics_set(&types, &IntrinsicFlags::default())
+      .unwrap();
+
+    for product in &[types.process_result, types.running_process] {
+      assert_eq!(
+        registered_rule(&tasks, *product).estimated_cost(),
+        Some(RuleCost::Expensive)
+      );
+    }
+    assert_eq!(
+      registered_rule(&tasks, types.files_content).estimated_cost(),
+      None
+    );
+  }
+
+  fn snapshot_inputs(tasks: &Tasks, types: &Types) -> Vec<TypeId> {
+    tasks.as_map()[&types.snapshot]
+      .iter()
+      .map(|rule| match rule {
+        &Rule::Intrinsic(ref intrinsic) => intrinsic.input,
+        &Rule::Task(_) => panic!("Expected only Intrinsics to be registered."),
+      })
+      .collect()
+  }
+
+  #[test]
+  fn intrinsics_are_enabled_by_default() {
+    let types = types();
+    let mut tasks = Tasks::new();
+    tasks
+      .intrinsics_set(&types, &IntrinsicFlags::default())
+      .unwrap();
+
+    assert!(snapshot_inputs(&tasks, &types).contains(&types.url_to_fetch));
+    assert!(tasks.as_map().contains_key(&types.process_result));
+    assert!(tasks.disabled_intrinsics().is_empty());
+  }
+
+  #[test]
+  fn disabled_intrinsics_are_not_registered() {
+    let types = types();
+    let mut tasks = Tasks::new();
+    let flags = IntrinsicFlags {
+      url_fetch: false,
+      ..IntrinsicFlags::default()
+    };
+    tasks.intrinsics_set(&types, &flags).unwrap();
+
+    assert_eq!(
+      snapshot_inputs(&tasks, &types),
+      vec![types.path_globs, types.directory_digest]
+    );
+    assert!(tasks.as_map().contains_key(&types.process_result));
+    assert_eq!(
+      tasks
+        .disabled_intrinsics()
+        .iter()
+        .map(|&(intrinsic, flag)| (intrinsic.product, intrinsic.input, flag))
+        .collect::<Vec<_>>(),
+      vec![(types.snapshot, types.url_to_fetch, IntrinsicFlag::UrlFetch)]
+    );
+  }
+
+  #[test]
+  fn rule_graphs_name_the_flags_of_disabled_intrinsics() {
+    let types = types();
+    let mut tasks = populated_tasks();
+    let flags = IntrinsicFlags {
+      url_fetch: false,
+      ..IntrinsicFlags::default()
+    };
+    tasks.intrinsics_set(&types, &flags).unwrap();
+    let without_disabled_providers = RuleGraph::new(tasks.as_map(), vec![TypeId(11)]);
+    let with_disabled_providers = RuleGraph::new(tasks.as_map(), vec![TypeId(11)])
+      .with_disabled_providers(vec![DisabledProvider {
+        product: types.snapshot,
+        input: types.url_to_fetch,
+        provider: "intrinsic".to_owned(),
+        disabled_by: "IntrinsicFlags.url_fetch".to_owned(),
+      }]);
+
+    // NB: Graphs are compared with `==`, because rendering them would require the externs.
+    let rule_graph = tasks.rule_graph(vec![TypeId(11)]);
+    assert!(rule_graph == with_disabled_providers);
+    assert!(rule_graph != without_disabled_providers);
+  }
+
+  const TYPE_NAMES: &[&str] = &["pants.engine.fs.Digest", "pants.engine.fs.Snapshot"];
+
+  fn snapshot_key() -> SnapshotKey {
+    SnapshotKey::new(
+      vec!["pants.backend.python", "pants.backend.jvm"],
+      TYPE_NAMES.iter().cloned(),
+    )
+  }
+
+  // Rules which are all satisfiable from a root of TypeId(11).
+  fn populated_tasks() -> Tasks {
+    populated_tasks_interned_at(0)
+  }
+
+  // The rules of populated_tasks, as registered by a process which interned each of their ids
+  // plus `offset`.
+  fn populated_tasks_interned_at(offset: u64) -> Tasks {
+    let type_id = |id: u64| TypeId(id + offset);
+    let mut tasks = Tasks::new();
+    tasks.set_duplicate_rule_policy(DuplicateRulePolicy::KeepFirst);
+    tasks.task_begin(Function(Key::new(1 + offset, TypeId(5))), type_id(10), true);
+    tasks.add_select(type_id(11));
+    tasks.add_get(type_id(12), type_id(11));
+    tasks.set_estimated_cost(RuleCost::Expensive);
+    tasks.task_end().unwrap();
+    tasks.task_begin(function(2 + offset), type_id(12), false);
+    tasks.add_select(type_id(11));
+    tasks.task_end().unwrap();
+    tasks.goal_begin(function(3 + offset), type_id(13), "list".to_owned());
+    tasks.add_select(type_id(10));
+    tasks.goal_end().unwrap();
+    // A duplicate, which consumes a sequence number.
+    tasks.task_begin(function(2 + offset), type_id(12), false);
+    tasks.add_select(type_id(11));
+    tasks.task_end().unwrap();
+    tasks
+  }
+
+  // Names for the ids of populated_tasks and of types(), as given by a process which interned each
+  // of them plus `offset`.
+  fn interned_names(offset: u64) -> InternedNames {
+    InternedNames::new(
+      (10..44).map(|id| (TypeId(id + offset), format!("type{}", id))),
+      (1..4).chain(20..26).map(|id| {
+        (
+          Function(Key::new(id + offset, TypeId(5))),
+          format!("function{}", id),
+        )
+      }),
+    )
+  }
+
+  #[test]
+  fn snapshot_round_trips() {
+    let mut tasks = populated_tasks();
+    let flags = IntrinsicFlags {
+      url_fetch: false,
+      ..IntrinsicFlags::default()
+    };
+    tasks.intrinsics_set(&types(), &flags).unwrap();
+    let key = snapshot_key();
+    let names = interned_names(0);
+
+    let restored =
+      Tasks::from_snapshot(&tasks.snapshot(&key, &names).unwrap(), &key, &names).unwrap();
+    assert_eq!(restored.as_map(), tasks.as_map());
+    assert_eq!(restored.sequences, tasks.sequences);
+    assert_eq!(restored.next_sequence, tasks.next_sequence);
+    assert_eq!(
+      restored.duplicate_rule_policy,
+      DuplicateRulePolicy::KeepFirst
+    );
+    assert_eq!(restored.disabled_intrinsics, tasks.disabled_intrinsics);
+    assert_eq!(restored.goals(), vec![("list".to_owned(), TypeId(13))]);
+
+    // Estimated costs, and the type ids of Functions, are not part of the identity of a rule, so
+    // are not covered by the comparison of the maps.
+    let rule = registered_rule(&restored, TypeId(10));
+    assert_eq!(rule.estimated_cost(), Some(RuleCost::Expensive));
+    match rule {
+      Rule::Task(task) => assert_eq!(*task.func.0.type_id(), TypeId(5)),
+      Rule::Intrinsic(_) => panic!("Expected a Task to be registered."),
+    }
+  }
+
+  #[test]
+  fn snapshot_builds_an_equal_rule_graph() {
+    let mut tasks = populated_tasks();
+    let key = snapshot_key();
+    let names = interned_names(0);
+    let mut restored =
+      Tasks::from_snapshot(&tasks.snapshot(&key, &names).unwrap(), &key, &names).unwrap();
+
+    assert!(tasks.rule_graph(vec![TypeId(11)]) == restored.rule_graph(vec![TypeId(11)]));
+    // Restored Tasks have not yet had a graph built from them.
+    let mut restored =
+      Tasks::from_snapshot(&tasks.snapshot(&key, &names).unwrap(), &key, &names).unwrap();
+    assert_eq!(restored.remove_rule(TypeId(13), |_| true), Ok(1));
+  }
+
+  #[test]
+  fn snapshot_for_another_key_is_rejected() {
+    let tasks = populated_tasks();
+    let names = interned_names(0);
+    let snapshot = tasks.snapshot(&snapshot_key(), &names).unwrap();
+
+    let other_plugins = SnapshotKey::new(vec!["pants.backend.python"], TYPE_NAMES.iter().cloned());
+    let error = Tasks::from_snapshot(&snapshot, &other_plugins, &names).unwrap_err();
+    assert!(error.contains("is stale"), "Bad error: {}", error);
+
+    // The order of the type names is that of the fields of Types, so is significant.
+    let error = Tasks::from_snapshot(
+      &snapshot,
+      &SnapshotKey::new(
+        vec!["pants.backend.jvm", "pants.backend.python"],
+        TYPE_NAMES.iter().rev().cloned(),
+      ),
+      &names,
+    )
+    .unwrap_err();
+    assert!(error.contains("is stale"), "Bad error: {}", error);
+
+    // But plugins may be given in any order.
+    Tasks::from_snapshot(
+      &snapshot,
+      &SnapshotKey::new(
+        vec!["pants.backend.jvm", "pants.backend.python"],
+        TYPE_NAMES.iter().cloned(),
+      ),
+      &names,
+    )
+    .unwrap();
+  }
+
+  #[test]
+  fn snapshot_is_restored_with_the_ids_of_the_restoring_process() {
+    let key = snapshot_key();
+    let snapshot = populated_tasks()
+      .snapshot(&key, &interned_names(0))
+      .unwrap();
+
+    let restored = Tasks::from_snapshot(&snapshot, &key, &interned_names(100)).unwrap();
+    let registered = populated_tasks_interned_at(100);
+    assert_eq!(restored.as_map(), registered.as_map());
+    assert_eq!(restored.sequences, registered.sequences);
+    assert_eq!(restored.goals(), vec![("list".to_owned(), TypeId(113))]);
+  }
+
+  #[test]
+  fn snapshot_of_unnamed_ids_fails() {
+    let names = InternedNames::new(
+      (10..14).map(|id| (TypeId(id), format!("type{}", id))),
+      (2..4).map(|id| (function(id), format!("function{}", id))),
+    );
+    assert_eq!(
+      populated_tasks().snapshot(&snapshot_key(), &names).err(),
+      Some("Cannot snapshot Tasks: no name was given for Function 1.".to_owned())
+    );
+  }
+
+  #[test]
+  fn snapshot_which_refers_to_names_which_are_not_given_is_rejected() {
+    let key = snapshot_key();
+    let snapshot = populated_tasks()
+      .snapshot(&key, &interned_names(0))
+      .unwrap();
+    let names = InternedNames::new(
+      (10..13).map(|id| (TypeId(id), format!("type{}", id))),
+      (1..4).map(|id| (function(id), format!("function{}", id))),
+    );
+    assert_eq!(
+      Tasks::from_snapshot(&snapshot, &key, &names).err(),
+      Some("Tasks snapshot refers to the type `type13`, which was not given.".to_owned())
+    );
+  }
+
+  #[test]
+  fn snapshot_with_another_version_is_rejected() {
+    let key = snapshot_key();
+    let names = interned_names(0);
+    let snapshot = bincode::serialize(&(SNAPSHOT_VERSION + 1, &key, vec![0_u8; 3])).unwrap();
+    assert_eq!(
+      Tasks::from_snapshot(&snapshot, &key, &names).err(),
+      Some(format!(
+        "Tasks snapshot has version {}, but only version {} is supported.",
+        SNAPSHOT_VERSION + 1,
+        SNAPSHOT_VERSION
+      ))
+    );
+  }
+
+  #[test]
+  fn corrupt_snapshot_is_rejected() {
+    let key = snapshot_key();
+    let names = interned_names(0);
+    let snapshot = populated_tasks().snapshot(&key, &names).unwrap();
+
+    let truncated = &snapshot[..snapshot.len() - 1];
+    let error = Tasks::from_snapshot(truncated, &key, &names).unwrap_err();
+    assert!(
+      error.starts_with("Tasks snapshot was corrupt"),
+      "Bad error: {}",
+      error
+    );
+
+    // A valid header, but a body which is not a Tasks.
+    let body_corrupted = bincode::serialize(&(SNAPSHOT_VERSION, &key, vec![0xff_u8; 8])).unwrap();
+    let error = Tasks::from_snapshot(&body_corrupted, &key, &names).unwrap_err();
+    assert!(
+      error.starts_with("Tasks snapshot was corrupt"),
+      "Bad error: {}",
+      error
+    );
+  }
 }
@@ -0,0 +1,222 @@
+// Copyright 2019 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::core::TypeId;
+use crate::tasks::{Intrinsic, Rule, Task};
+
+///
+/// An index of registered Rules by the types that they produce and consume, for answering simple
+/// questions about what the engine can compute without constructing a rule graph.
+///
+#[derive(Clone, Debug, Default)]
+pub struct TypeIndex {
+  // type -> rules which produce it
+  producers: HashMap<TypeId, Vec<Rule>>,
+  // type -> rules which consume it, either via a clause or a Get
+  consumers: HashMap<TypeId, Vec<Rule>>,
+}
+
+impl TypeIndex {
+  pub fn new(rules: &HashMap<TypeId, Vec<Rule>>) -> TypeIndex {
+    let mut index = TypeIndex::default();
+    for (product, rules) in rules {
+      for rule in rules {
+        index
+          .producers
+          .entry(*product)
+          .or_insert_with(Vec::new)
+          .push(rule.clone());
+        let mut consumed = Self::consumed_types(rule);
+        consumed.sort();
+        consumed.dedup();
+        for type_id in consumed {
+          index
+            .consumers
+            .entry(type_id)
+            .or_insert_with(Vec::new)
+            .push(rule.clone());
+        }
+      }
+    }
+    index
+  }
+
+  ///
+  /// The rules which produce the given type.
+  ///
+  pub fn producers_of(&self, type_id: TypeId) -> &[Rule] {
+    self
+      .producers
+      .get(&type_id)
+      .map(Vec::as_slice)
+      .unwrap_or(&[])
+  }
+
+  ///
+  /// The rules which consume the given type, via either a clause or a Get.
+  ///
+  pub fn consumers_of(&self, type_id: TypeId) -> &[Rule] {
+    self
+      .consumers
+      .get(&type_id)
+      .map(Vec::as_slice)
+      .unwrap_or(&[])
+  }
+
+  ///
+  /// The types which are produced by at least one rule.
+  ///
+  pub fn products(&self) -> HashSet<TypeId> {
+    self.producers.keys().cloned().collect()
+  }
+
+  ///
+  /// The types which are consumed by at least one rule.
+  ///
+  pub fn inputs(&self) -> HashSet<TypeId> {
+    self.consumers.keys().cloned().collect()
+  }
+
+  ///
+  /// Returns true if the product can be computed from the given params, by a breadth first search
+  /// forward from the params through rules whose clauses are entirely satisfied.
+  ///
+  /// This is an approximation of rule graph construction: Gets are assumed to be satisfiable, and
+  /// ambiguity between multiple providers of a type is not detected.
+  ///
+  pub fn can_compute(&self, product: TypeId, available_params: &[TypeId]) -> bool {
+    // Rules without clauses can run before any params are available.
+    let clauseless_products = self
+      .producers
+      .iter()
+      .filter(|(_, rules)| rules.iter().any(|rule| Self::clause_types(rule).is_empty()))
+      .map(|(&rule_product, _)| rule_product);
+    let mut available: HashSet<TypeId> = HashSet::new();
+    let mut queue: VecDeque<TypeId> = VecDeque::new();
+    for type_id in available_params.iter().cloned().chain(clauseless_products) {
+      if available.insert(type_id) {
+        queue.push_back(type_id);
+      }
+    }
+
+    while let Some(type_id) = queue.pop_front() {
+      if type_id == product {
+        return true;
+      }
+      for rule in self.consumers_of(type_id) {
+        let rule_product = Self::product(rule);
+        if !available.contains(&rule_product)
+          && Self::clause_types(rule)
+            .iter()
+            .all(|clause_type| available.contains(clause_type))
+        {
+          available.insert(rule_product);
+          queue.push_back(rule_product);
+        }
+      }
+    }
+    false
+  }
+
+  fn product(rule: &Rule) -> TypeId {
+    match rule {
+      &Rule::Task(Task { product, .. }) => product,
+      &Rule::Intrinsic(Intrinsic { product, .. }) => product,
+    }
+  }
+
+  fn clause_types(rule: &Rule) -> Vec<TypeId> {
+    match rule {
+      &Rule::Task(Task { ref clause, .. }) => clause.iter().map(|s| s.product).collect(),
+      &Rule::Intrinsic(Intrinsic { input, .. }) => vec![input],
+    }
+  }
+
+  fn consumed_types(rule: &Rule) -> Vec<TypeId> {
+    match rule {
+      &Rule::Task(Task {
+        ref clause,
+        ref gets,
+        ..
+      }) => clause
+        .iter()
+        .map(|s| s.product)
+        .chain(gets.iter().flat_map(|g| vec![g.product, g.subject]))
+        .collect(),
+      &Rule::Intrinsic(Intrinsic { input, .. }) => vec![input],
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::core::{Function, Key, TypeId};
+  use crate::tasks::Tasks;
+
+  // NB: Rendering a TypeId or Function requires the externs, so these tests only compare them.
+  fn function(id: u64) -> Function {
+    Function(Key::new(id, TypeId(1)))
+  }
+
+  const SNAPSHOT: TypeId = TypeId(10);
+  const SOURCES: TypeId = TypeId(11);
+  const COMPILED: TypeId = TypeId(12);
+  const PACKAGED: TypeId = TypeId(13);
+  const CONFIG: TypeId = TypeId(14);
+
+  // A chain of rules: Snapshot -> Sources -> Compiled -> Packaged, where packaging also requires
+  // a Config.
+  fn chain() -> Tasks {
+    let mut tasks = Tasks::new();
+    tasks.task_begin(function(1), SOURCES, true);
+    tasks.add_select(SNAPSHOT);
+    tasks.task_end().unwrap();
+    tasks.task_begin(function(2), COMPILED, true);
+    tasks.add_select(SOURCES);
+    tasks.task_end().unwrap();
+    tasks.task_begin(function(3), PACKAGED, true);
+    tasks.add_select(COMPILED);
+    tasks.add_select(CONFIG);
+    tasks.task_end().unwrap();
+    tasks
+  }
+
+  #[test]
+  fn lookups() {
+    let mut tasks = chain();
+    let index = tasks.type_index();
+    assert_eq!(index.producers_of(COMPILED).len(), 1);
+    assert_eq!(index.consumers_of(COMPILED).len(), 1);
+    assert_eq!(index.consumers_of(SNAPSHOT).len(), 1);
+    assert!(index.producers_of(SNAPSHOT).is_empty());
+    assert!(index.consumers_of(PACKAGED).is_empty());
+  }
+
+  #[test]
+  fn can_compute() {
+    let mut tasks = chain();
+    let index = tasks.type_index();
+    assert!(index.can_compute(COMPILED, &[SNAPSHOT]));
+    assert!(index.can_compute(PACKAGED, &[SNAPSHOT, CONFIG]));
+    assert!(index.can_compute(SNAPSHOT, &[SNAPSHOT]));
+  }
+
+  #[test]
+  fn cannot_compute() {
+    let mut tasks = chain();
+    let index = tasks.type_index();
+    assert!(!index.can_compute(PACKAGED, &[SNAPSHOT]));
+    assert!(!index.can_compute(SOURCES, &[CONFIG]));
+  }
+
+  #[test]
+  fn invalidated_on_registration() {
+    let mut tasks = chain();
+    assert!(!tasks.type_index().can_compute(CONFIG, &[SNAPSHOT]));
+    tasks.task_begin(function(4), CONFIG, true);
+    tasks.task_end().unwrap();
+    assert!(tasks.type_index().can_compute(PACKAGED, &[SNAPSHOT]));
+  }
+}
@@ -6,6 +6,7 @@ pub struct Types {
   pub construct_file_content: Function,
   pub construct_files_content: Function,
   pub construct_process_result: Function,
+  pub construct_running_process: Function,
   pub address: TypeId,
   pub path_globs: TypeId,
   pub directory_digest: TypeId,
@@ -19,6 +20,7 @@ pub struct Types {
   pub link: TypeId,
   pub multi_platform_process_request: TypeId,
   pub process_result: TypeId,
+  pub running_process: TypeId,
   pub generator: TypeId,
   pub url_to_fetch: TypeId,
   pub string: TypeId,
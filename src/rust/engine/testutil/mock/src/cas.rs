@@ -1,5 +1,7 @@
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::thread::sleep;
+use std::time::Duration;
 
 use bazel_protos;
 use futures;
@@ -18,28 +20,49 @@ use testutil::data::{TestData, TestDirectory};
 pub struct StubCAS {
   server_transport: grpcio::Server,
   read_request_count: Arc<Mutex<usize>>,
+  batch_read_request_count: Arc<Mutex<usize>>,
   pub write_message_sizes: Arc<Mutex<Vec<usize>>>,
+  // For each written blob, whether the client asked for it to be stored with a short lease.
+  pub write_short_leases: Arc<Mutex<HashMap<Fingerprint, bool>>>,
+  // Each blob written, in the order in which the writes completed.
+  pub write_log: Arc<Mutex<Vec<Fingerprint>>>,
+  // Each blob whose existence was checked by a FindMissingBlobs request.
+  pub find_missing_blobs_log: Arc<Mutex<Vec<Fingerprint>>>,
   pub blobs: Arc<Mutex<HashMap<Fingerprint, Bytes>>>,
+  write_concurrency: Arc<Mutex<WriteConcurrency>>,
+}
+
+// The writes which are in progress, and the most which have been in progress at once.
+#[derive(Debug, Default)]
+struct WriteConcurrency {
+  current: usize,
+  max: usize,
 }
 
 pub struct StubCASBuilder {
   always_errors: bool,
+  read_failures: usize,
   chunk_size_bytes: Option<usize>,
   content: HashMap<Fingerprint, Bytes>,
   port: Option<u16>,
   instance_name: Option<String>,
   required_auth_token: Option<String>,
+  write_delay: Option<Duration>,
+  max_batch_total_size_bytes: Option<usize>,
 }
 
 impl StubCASBuilder {
   pub fn new() -> Self {
     StubCASBuilder {
       always_errors: false,
+      read_failures: 0,
       chunk_size_bytes: None,
       content: HashMap::new(),
       port: None,
       instance_name: None,
       required_auth_token: None,
+      write_delay: None,
+      max_batch_total_size_bytes: None,
     }
   }
 }
@@ -83,6 +106,14 @@ impl StubCASBuilder {
     self
   }
 
+  ///
+  /// Fail the first `read_failures` read requests with Unavailable, and then serve reads normally.
+  ///
+  pub fn read_failures(mut self, read_failures: usize) -> Self {
+    self.read_failures = read_failures;
+    self
+  }
+
   pub fn instance_name(mut self, instance_name: String) -> Self {
     if self.instance_name.is_some() {
       panic!("Can't set instance_name twice");
@@ -99,14 +130,34 @@ impl StubCASBuilder {
     self
   }
 
+  ///
+  /// Delay each write request by the given duration before handling it, like a slow upload.
+  ///
+  pub fn write_delay(mut self, write_delay: Duration) -> Self {
+    self.write_delay = Some(write_delay);
+    self
+  }
+
+  ///
+  /// Fail BatchReadBlobs requests for more than the given total size of blobs with
+  /// InvalidArgument, like a server which advertises that max_batch_total_size_bytes.
+  ///
+  pub fn max_batch_total_size_bytes(mut self, max_batch_total_size_bytes: usize) -> Self {
+    self.max_batch_total_size_bytes = Some(max_batch_total_size_bytes);
+    self
+  }
+
   pub fn build(self) -> StubCAS {
     StubCAS::new(
       self.chunk_size_bytes.unwrap_or(1024),
       self.content,
       self.port.unwrap_or(0),
       self.always_errors,
+      self.read_failures,
       self.instance_name,
       self.required_auth_token,
+      self.write_delay,
+      self.max_batch_total_size_bytes,
     )
   }
 }
@@ -125,27 +176,48 @@ impl StubCAS {
   /// * `blobs`            - Known Fingerprints and their content responses. These are not checked
   ///                        for correctness.
   /// * `port`             - The port for the CAS to listen to.
+  /// * `read_failures`    - The number of initial read requests to fail with Unavailable.
+  /// * `write_delay`      - If set, how long to wait before handling each write request.
+  /// * `max_batch_total_size_bytes` - If set, the largest total size of blobs which a
+  ///                        BatchReadBlobs request may read.
   fn new(
     chunk_size_bytes: usize,
     blobs: HashMap<Fingerprint, Bytes>,
     port: u16,
     always_errors: bool,
+    read_failures: usize,
     instance_name: Option<String>,
     required_auth_token: Option<String>,
+    write_delay: Option<Duration>,
+    max_batch_total_size_bytes: Option<usize>,
   ) -> StubCAS {
     let env = Arc::new(grpcio::Environment::new(1));
     let read_request_count = Arc::new(Mutex::new(0));
+    let batch_read_request_count = Arc::new(Mutex::new(0));
     let write_message_sizes = Arc::new(Mutex::new(Vec::new()));
+    let write_short_leases = Arc::new(Mutex::new(HashMap::new()));
+    let write_log = Arc::new(Mutex::new(Vec::new()));
+    let find_missing_blobs_log = Arc::new(Mutex::new(Vec::new()));
     let blobs = Arc::new(Mutex::new(blobs));
+    let write_concurrency = Arc::new(Mutex::new(WriteConcurrency::default()));
     let responder = StubCASResponder {
       chunk_size_bytes: chunk_size_bytes,
       instance_name: instance_name,
       blobs: blobs.clone(),
       always_errors: always_errors,
+      remaining_read_failures: Arc::new(Mutex::new(read_failures)),
       read_request_count: read_request_count.clone(),
+      batch_read_request_count: batch_read_request_count.clone(),
+      max_batch_total_size_bytes,
       write_message_sizes: write_message_sizes.clone(),
+      write_short_leases: write_short_leases.clone(),
+      write_log: write_log.clone(),
+      find_missing_blobs_log: find_missing_blobs_log.clone(),
       required_auth_header: required_auth_token.map(|t| format!("Bearer {}", t)),
+      write_delay,
+      write_concurrency: write_concurrency.clone(),
     };
+    let batch_read_responder = responder.clone();
     let mut server_transport = grpcio::ServerBuilder::new(env)
       .register_service(bazel_protos::bytestream_grpc::create_byte_stream(
         responder.clone(),
@@ -153,6 +225,14 @@ impl StubCAS {
       .register_service(
         bazel_protos::remote_execution_grpc::create_content_addressable_storage(responder.clone()),
       )
+      .register_service(
+        grpcio::ServiceBuilder::new()
+          .add_unary_handler(
+            &bazel_protos::batch_read_blobs::BATCH_READ_BLOBS,
+            move |ctx, req, sink| batch_read_responder.batch_read_blobs(ctx, req, sink),
+          )
+          .build(),
+      )
       .bind("localhost", port)
       .build()
       .unwrap();
@@ -161,8 +241,13 @@ impl StubCAS {
     StubCAS {
       server_transport,
       read_request_count,
+      batch_read_request_count,
       write_message_sizes,
+      write_short_leases,
+      write_log,
+      find_missing_blobs_log,
       blobs,
+      write_concurrency,
     }
   }
 
@@ -185,6 +270,18 @@ impl StubCAS {
   pub fn read_request_count(&self) -> usize {
     *self.read_request_count.lock()
   }
+
+  pub fn batch_read_request_count(&self) -> usize {
+    *self.batch_read_request_count.lock()
+  }
+
+  ///
+  /// The most write requests which have been in progress at once (from when the server received
+  /// them until it responded).
+  ///
+  pub fn max_concurrent_writes(&self) -> usize {
+    self.write_concurrency.lock().max
+  }
 }
 
 #[derive(Clone, Debug)]
@@ -193,9 +290,17 @@ pub struct StubCASResponder {
   instance_name: Option<String>,
   blobs: Arc<Mutex<HashMap<Fingerprint, Bytes>>>,
   always_errors: bool,
+  remaining_read_failures: Arc<Mutex<usize>>,
   required_auth_header: Option<String>,
+  write_delay: Option<Duration>,
+  write_concurrency: Arc<Mutex<WriteConcurrency>>,
+  max_batch_total_size_bytes: Option<usize>,
   pub read_request_count: Arc<Mutex<usize>>,
+  pub batch_read_request_count: Arc<Mutex<usize>>,
   pub write_message_sizes: Arc<Mutex<Vec<usize>>>,
+  pub write_short_leases: Arc<Mutex<HashMap<Fingerprint, bool>>>,
+  pub write_log: Arc<Mutex<Vec<Fingerprint>>>,
+  pub find_missing_blobs_log: Arc<Mutex<Vec<Fingerprint>>>,
 }
 
 macro_rules! check_auth {
@@ -260,6 +365,16 @@ impl StubCASResponder {
         Some("StubCAS is configured to always fail".to_owned()),
       ));
     }
+    {
+      let mut remaining_read_failures = self.remaining_read_failures.lock();
+      if *remaining_read_failures > 0 {
+        *remaining_read_failures -= 1;
+        return Err(grpcio::RpcStatus::new(
+          grpcio::RpcStatusCode::Unavailable,
+          Some("StubCAS is configured to fail this read".to_owned()),
+        ));
+      }
+    }
     let blobs = self.blobs.lock();
     let maybe_bytes = blobs.get(&fingerprint);
     match maybe_bytes {
@@ -333,8 +448,26 @@ impl bazel_protos::bytestream_grpc::ByteStream for StubCASResponder {
   ) {
     check_auth!(self, ctx, sink);
 
+    {
+      let mut write_concurrency = self.write_concurrency.lock();
+      write_concurrency.current += 1;
+      write_concurrency.max = write_concurrency.max.max(write_concurrency.current);
+    }
+    let write_concurrency = self.write_concurrency.clone();
+
+    if let Some(write_delay) = self.write_delay {
+      sleep(write_delay);
+    }
+
+    let short_lease = ctx
+      .request_headers()
+      .iter()
+      .any(|(key, value)| &key.to_lowercase() == "x-pants-blob-lease" && value == &b"short"[..]);
+
     let always_errors = self.always_errors;
     let write_message_sizes = self.write_message_sizes.clone();
+    let write_short_leases = self.write_short_leases.clone();
+    let write_log = self.write_log.clone();
     let blobs = self.blobs.clone();
     let instance_name = self.instance_name();
     ctx.spawn(
@@ -442,6 +575,8 @@ impl bazel_protos::bytestream_grpc::ByteStream for StubCASResponder {
                 let mut blobs = blobs.lock();
                 blobs.insert(fingerprint, bytes);
               }
+              write_short_leases.lock().insert(fingerprint, short_lease);
+              write_log.lock().push(fingerprint);
 
               let mut response = bazel_protos::bytestream::WriteResponse::new();
               response.set_committed_size(size as i64);
@@ -453,7 +588,10 @@ impl bazel_protos::bytestream_grpc::ByteStream for StubCASResponder {
           Ok(resp) => sink.success(resp),
           Err(err) => sink.fail(err),
         })
-        .then(move |_| Ok(())),
+        .then(move |_| {
+          write_concurrency.lock().current -= 1;
+          Ok(())
+        }),
     );
   }
 
@@ -502,6 +640,7 @@ impl bazel_protos::remote_execution_grpc::ContentAddressableStorage for StubCASR
     for digest in req.get_blob_digests() {
       let hashing_digest_result: Result<Digest, String> = digest.into();
       let hashing_digest = hashing_digest_result.expect("Bad digest");
+      self.find_missing_blobs_log.lock().push(hashing_digest.0);
       if !blobs.contains_key(&hashing_digest.0) {
         response.mut_missing_blob_digests().push(digest.clone())
       }
@@ -520,6 +659,7 @@ impl bazel_protos::remote_execution_grpc::ContentAddressableStorage for StubCASR
       None,
     ));
   }
+
   fn get_tree(
     &self,
     _ctx: grpcio::RpcContext<'_>,
@@ -531,3 +671,75 @@ impl bazel_protos::remote_execution_grpc::ContentAddressableStorage for StubCASR
     unimplemented!()
   }
 }
+
+impl StubCASResponder {
+  ///
+  /// Serves bazel_protos::batch_read_blobs::BATCH_READ_BLOBS, which the generated
+  /// ContentAddressableStorage service does not have.
+  ///
+  fn batch_read_blobs(
+    &self,
+    ctx: grpcio::RpcContext<'_>,
+    req: bazel_protos::remote_apis_backports::BatchReadBlobsRequest,
+    sink: grpcio::UnarySink<bazel_protos::remote_apis_backports::BatchReadBlobsResponse>,
+  ) {
+    check_auth!(self, ctx, sink);
+
+    {
+      let mut batch_read_request_count = self.batch_read_request_count.lock();
+      *batch_read_request_count += 1;
+    }
+    if self.always_errors {
+      sink.fail(grpcio::RpcStatus::new(
+        grpcio::RpcStatusCode::Internal,
+        Some("StubCAS is configured to always fail".to_owned()),
+      ));
+      return;
+    }
+    if req.instance_name != self.instance_name() {
+      sink.fail(grpcio::RpcStatus::new(
+        grpcio::RpcStatusCode::NotFound,
+        Some(format!(
+          "Wrong instance_name; want {:?} got {:?}",
+          self.instance_name(),
+          req.instance_name
+        )),
+      ));
+      return;
+    }
+    let total_size_bytes: i64 = req
+      .get_digests()
+      .iter()
+      .map(|digest| digest.get_size_bytes())
+      .sum();
+    if let Some(max_batch_total_size_bytes) = self.max_batch_total_size_bytes {
+      if total_size_bytes as usize > max_batch_total_size_bytes {
+        sink.fail(grpcio::RpcStatus::new(
+          grpcio::RpcStatusCode::InvalidArgument,
+          Some(format!(
+            "Batch of {} bytes is larger than the limit of {} bytes",
+            total_size_bytes, max_batch_total_size_bytes
+          )),
+        ));
+        return;
+      }
+    }
+    let blobs = self.blobs.lock();
+    let mut response = bazel_protos::remote_apis_backports::BatchReadBlobsResponse::new();
+    for digest in req.get_digests() {
+      let hashing_digest_result: Result<Digest, String> = digest.into();
+      let hashing_digest = hashing_digest_result.expect("Bad digest");
+      let mut blob_response =
+        bazel_protos::remote_apis_backports::BatchReadBlobsResponse_Response::new();
+      blob_response.set_digest(digest.clone());
+      match blobs.get(&hashing_digest.0) {
+        Some(bytes) => blob_response.set_data(bytes.clone()),
+        None => blob_response
+          .mut_status()
+          .set_code(grpcio::RpcStatusCode::NotFound as i32),
+      }
+      response.mut_responses().push(blob_response);
+    }
+    sink.success(response);
+  }
+}
@@ -1,6 +1,5 @@
 use std::collections::VecDeque;
 use std::fmt::Debug;
-use std::iter::FromIterator;
 use std::ops::Deref;
 use std::sync::Arc;
 use std::thread::sleep;
@@ -8,7 +7,8 @@ use std::time::Duration;
 use std::time::Instant;
 
 use bazel_protos;
-use futures::{Future, Sink};
+use bazel_protos::operations::Operation;
+use futures::{Future, Sink, Stream};
 use grpcio;
 use parking_lot::Mutex;
 use protobuf;
@@ -33,6 +33,251 @@ impl MockOperation {
       duration: None,
     }
   }
+
+  ///
+  /// An Operation which is not yet done.
+  ///
+  pub fn incomplete(name: &str) -> MockOperation {
+    let mut op = Operation::new();
+    op.set_name(name.to_owned());
+    op.set_done(false);
+    MockOperation::new(op)
+  }
+
+  ///
+  /// A done Operation whose ExecuteResponse has the given ActionResult.
+  ///
+  pub fn successful(
+    name: &str,
+    action_result: bazel_protos::remote_execution::ActionResult,
+  ) -> MockOperation {
+    let mut response = bazel_protos::remote_execution::ExecuteResponse::new();
+    response.set_result(action_result);
+    let mut op = Operation::new();
+    op.set_name(name.to_owned());
+    op.set_done(true);
+    op.set_response(make_any_proto(&response));
+    MockOperation::new(op)
+  }
+
+  ///
+  /// Fails the RPC with the given status.
+  ///
+  pub fn failed(status: grpcio::RpcStatus) -> MockOperation {
+    MockOperation {
+      op: Err(status),
+      duration: None,
+    }
+  }
+
+  ///
+  /// Drops the channel, triggering cancelation on the client.
+  ///
+  pub fn canceled() -> MockOperation {
+    MockOperation {
+      op: Ok(None),
+      duration: None,
+    }
+  }
+
+  pub fn with_metadata(
+    mut self,
+    metadata: &bazel_protos::remote_execution::ExecuteOperationMetadata,
+  ) -> MockOperation {
+    if let Ok(Some(ref mut op)) = self.op {
+      op.set_metadata(make_any_proto(metadata));
+    }
+    self
+  }
+
+  pub fn with_duration(mut self, duration: Duration) -> MockOperation {
+    self.duration = Some(duration);
+    self
+  }
+}
+
+fn make_any_proto<T: protobuf::Message>(message: &T) -> protobuf::well_known_types::Any {
+  let mut any = protobuf::well_known_types::Any::new();
+  any.set_type_url(format!(
+    "type.googleapis.com/{}",
+    message.descriptor().full_name()
+  ));
+  any.set_value(message.write_to_bytes().unwrap());
+  any
+}
+
+///
+/// The RPCs which a scripted MockExecution can expect.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RpcKind {
+  Execute,
+  WaitExecution,
+  GetOperation,
+  CancelOperation,
+}
+
+///
+/// A condition on the request of an ExpectedRpc.
+///
+#[derive(Clone)]
+pub enum RpcMatcher {
+  Any,
+  // The request is equal to the given ExecuteRequest.
+  ExecuteRequest(bazel_protos::remote_execution::ExecuteRequest),
+  // The request is for the named operation.
+  OperationName(String),
+  // The request has a header with the given name and value.
+  Header(String, String),
+  // Returns an explanation of why the request does not match.
+  Custom(Arc<dyn Fn(&ReceivedMessage) -> Result<(), String> + Send + Sync>),
+}
+
+impl RpcMatcher {
+  fn check(&self, received: &ReceivedMessage) -> Result<(), String> {
+    match self {
+      RpcMatcher::Any => Ok(()),
+      RpcMatcher::ExecuteRequest(expected) => {
+        let actual = received
+          .message
+          .as_any()
+          .downcast_ref::<bazel_protos::remote_execution::ExecuteRequest>();
+        if actual == Some(expected) {
+          Ok(())
+        } else {
+          Err(format!("Expected ExecuteRequest {:?}", expected))
+        }
+      }
+      RpcMatcher::OperationName(expected) => {
+        let actual = operation_name(&*received.message);
+        if actual.as_ref() == Some(expected) {
+          Ok(())
+        } else {
+          Err(format!(
+            "Expected operation {:?} but got {:?}",
+            expected, actual
+          ))
+        }
+      }
+      RpcMatcher::Header(name, value) => {
+        if received
+          .headers
+          .iter()
+          .any(|(k, v)| k.eq_ignore_ascii_case(name) && v[..] == *value.as_bytes())
+        {
+          Ok(())
+        } else {
+          Err(format!(
+            "Expected header {}: {:?} but got headers {:?}",
+            name, value, received.headers
+          ))
+        }
+      }
+      RpcMatcher::Custom(check) => check(received),
+    }
+  }
+}
+
+impl Debug for RpcMatcher {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      RpcMatcher::Any => write!(f, "Any"),
+      RpcMatcher::ExecuteRequest(req) => write!(f, "ExecuteRequest({:?})", req),
+      RpcMatcher::OperationName(name) => write!(f, "OperationName({:?})", name),
+      RpcMatcher::Header(name, value) => write!(f, "Header({:?}, {:?})", name, value),
+      RpcMatcher::Custom(_) => write!(f, "Custom(..)"),
+    }
+  }
+}
+
+fn operation_name(message: &dyn protobuf::Message) -> Option<String> {
+  let message = message.as_any();
+  if let Some(req) = message.downcast_ref::<bazel_protos::operations::GetOperationRequest>() {
+    Some(req.get_name().to_owned())
+  } else if let Some(req) =
+    message.downcast_ref::<bazel_protos::remote_execution::WaitExecutionRequest>()
+  {
+    Some(req.get_name().to_owned())
+  } else if let Some(req) =
+    message.downcast_ref::<bazel_protos::operations::CancelOperationRequest>()
+  {
+    Some(req.get_name().to_owned())
+  } else {
+    None
+  }
+}
+
+///
+/// An RPC which a scripted MockExecution expects, and its response.
+///
+/// The response is the same as that of a MockOperation, except that for CancelOperation any
+/// Operation is a successful (empty) response. The delay is before either responding or canceling.
+///
+#[derive(Clone, Debug)]
+pub struct ExpectedRpc {
+  pub kind: RpcKind,
+  pub matcher: RpcMatcher,
+  pub response: Result<Option<Operation>, grpcio::RpcStatus>,
+  pub delay: Option<Duration>,
+}
+
+impl ExpectedRpc {
+  pub fn new(kind: RpcKind, matcher: RpcMatcher, response: MockOperation) -> ExpectedRpc {
+    ExpectedRpc {
+      kind,
+      matcher,
+      response: response.op,
+      delay: response.duration,
+    }
+  }
+
+  pub fn execute(
+    execute_request: bazel_protos::remote_execution::ExecuteRequest,
+    response: MockOperation,
+  ) -> ExpectedRpc {
+    ExpectedRpc::new(
+      RpcKind::Execute,
+      RpcMatcher::ExecuteRequest(execute_request),
+      response,
+    )
+  }
+
+  pub fn wait_execution(name: &str, response: MockOperation) -> ExpectedRpc {
+    ExpectedRpc::new(
+      RpcKind::WaitExecution,
+      RpcMatcher::OperationName(name.to_owned()),
+      response,
+    )
+  }
+
+  pub fn get_operation(name: &str, response: MockOperation) -> ExpectedRpc {
+    ExpectedRpc::new(
+      RpcKind::GetOperation,
+      RpcMatcher::OperationName(name.to_owned()),
+      response,
+    )
+  }
+
+  pub fn cancel_operation(name: &str) -> ExpectedRpc {
+    ExpectedRpc::new(
+      RpcKind::CancelOperation,
+      RpcMatcher::OperationName(name.to_owned()),
+      MockOperation::new(Operation::new()),
+    )
+  }
+
+  ///
+  /// Replaces the matcher, for example to also check the request's headers.
+  ///
+  pub fn matching(mut self, matcher: RpcMatcher) -> ExpectedRpc {
+    self.matcher = matcher;
+    self
+  }
+
+  pub fn with_delay(mut self, delay: Duration) -> ExpectedRpc {
+    self.delay = Some(delay);
+    self
+  }
 }
 
 #[derive(Clone, Debug)]
@@ -40,6 +285,14 @@ pub struct MockExecution {
   name: String,
   execute_request: bazel_protos::remote_execution::ExecuteRequest,
   operation_responses: Arc<Mutex<VecDeque<MockOperation>>>,
+  // If set, the RPCs which are expected, in order, instead of operation_responses.
+  expected_rpcs: Option<Arc<Mutex<VecDeque<ExpectedRpc>>>>,
+  rejects_unknown_fields: bool,
+  pipelining: bool,
+  // If set, the response to GetCapabilities (or the code with which it fails), which otherwise
+  // fails with Unimplemented.
+  capabilities:
+    Option<Result<bazel_protos::remote_execution::ServerCapabilities, grpcio::RpcStatusCode>>,
 }
 
 impl MockExecution {
@@ -60,13 +313,92 @@ impl MockExecution {
       name: name,
       execute_request: execute_request,
       operation_responses: Arc::new(Mutex::new(VecDeque::from(operation_responses))),
+      expected_rpcs: None,
+      rejects_unknown_fields: false,
+      pipelining: false,
+      capabilities: None,
     }
   }
+
+  ///
+  /// A MockExecution which expects exactly the given RPCs, in order. A request which is not of the
+  /// kind of the next ExpectedRpc, or which does not match it, fails with InvalidArgument, and does
+  /// not consume it.
+  ///
+  /// # Arguments:
+  ///  * `name` - The name of the operation, used for errors on pipelined Execute streams.
+  ///  * `expected_rpcs` - The RPCs to expect, with their responses.
+  ///
+  pub fn scripted(name: String, expected_rpcs: Vec<ExpectedRpc>) -> MockExecution {
+    MockExecution {
+      name: name,
+      execute_request: bazel_protos::remote_execution::ExecuteRequest::new(),
+      operation_responses: Arc::new(Mutex::new(VecDeque::new())),
+      expected_rpcs: Some(Arc::new(Mutex::new(VecDeque::from(expected_rpcs)))),
+      rejects_unknown_fields: false,
+      pipelining: false,
+      capabilities: None,
+    }
+  }
+
+  fn remaining_expectations(&self) -> Vec<String> {
+    let mut remaining: Vec<_> = self
+      .operation_responses
+      .lock()
+      .iter()
+      .map(|op| format!("{:?}", op))
+      .collect();
+    if let Some(ref expected_rpcs) = self.expected_rpcs {
+      remaining.extend(expected_rpcs.lock().iter().map(|rpc| format!("{:?}", rpc)));
+    }
+    remaining
+  }
+
+  ///
+  /// Like a server which strictly validates requests, fail any ExecuteRequest which has fields
+  /// that are not part of the REAPI with InvalidArgument, listing the type urls of any of them
+  /// which are Anys. Such requests do not consume an operation response.
+  ///
+  pub fn rejecting_unknown_fields(mut self) -> MockExecution {
+    self.rejects_unknown_fields = true;
+    self
+  }
+
+  ///
+  /// Also serve pipelined Execute streams (see `bazel_protos::pipelining`), responding to each
+  /// ExecuteRequest on them with the next operation response, tagged like the request. Operation
+  /// responses which are a status are sent as Operations with that error, and one which is None
+  /// fails the whole stream.
+  ///
+  pub fn pipelining(mut self) -> MockExecution {
+    self.pipelining = true;
+    self
+  }
+
+  ///
+  /// Respond to GetCapabilities requests with the given capabilities. They are not part of the
+  /// script of a scripted MockExecution, and do not consume an operation response.
+  ///
+  pub fn with_capabilities(
+    mut self,
+    capabilities: bazel_protos::remote_execution::ServerCapabilities,
+  ) -> MockExecution {
+    self.capabilities = Some(Ok(capabilities));
+    self
+  }
+
+  ///
+  /// Fail GetCapabilities requests with the given code.
+  ///
+  pub fn with_failing_capabilities(mut self, code: grpcio::RpcStatusCode) -> MockExecution {
+    self.capabilities = Some(Err(code));
+    self
+  }
 }
 
 ///
-/// A server which will answer ExecuteRequest and GetOperation gRPC requests with pre-canned
-/// responses.
+/// A server which will answer ExecuteRequest, WaitExecution, GetOperation, CancelOperation and
+/// GetCapabilities gRPC requests with pre-canned responses.
 ///
 pub struct TestServer {
   pub mock_responder: MockResponder,
@@ -84,17 +416,35 @@ impl TestServer {
   ///                      If a GetOperation request is received whose name is not equal to this
   ///                      MockExecution's name, or more requests are received than stub responses
   ///                      are available for, an error will be returned.
+  ///                      A scripted MockExecution instead responds to exactly the RPCs that it
+  ///                      expects.
   pub fn new(mock_execution: MockExecution, port: Option<u16>) -> TestServer {
+    let pipelining = mock_execution.pipelining;
     let mock_responder = MockResponder::new(mock_execution);
 
     let env = Arc::new(grpcio::Environment::new(1));
-    let mut server_transport = grpcio::ServerBuilder::new(env)
+    let mut server_builder = grpcio::ServerBuilder::new(env)
       .register_service(bazel_protos::remote_execution_grpc::create_execution(
         mock_responder.clone(),
       ))
       .register_service(bazel_protos::operations_grpc::create_operations(
         mock_responder.clone(),
       ))
+      .register_service(bazel_protos::remote_execution_grpc::create_capabilities(
+        mock_responder.clone(),
+      ));
+    if pipelining {
+      let pipelined_responder = mock_responder.clone();
+      server_builder = server_builder.register_service(
+        grpcio::ServiceBuilder::new()
+          .add_duplex_streaming_handler(
+            &bazel_protos::pipelining::PIPELINED_EXECUTE,
+            move |ctx, requests, sink| pipelined_responder.execute_pipelined(ctx, requests, sink),
+          )
+          .build(),
+      );
+    }
+    let mut server_transport = server_builder
       .bind("localhost", port.unwrap_or(0))
       .build()
       .unwrap();
@@ -117,24 +467,12 @@ impl TestServer {
 
 impl Drop for TestServer {
   fn drop(&mut self) {
-    let remaining_expected_responses = self
-      .mock_responder
-      .mock_execution
-      .operation_responses
-      .lock()
-      .len();
-    if remaining_expected_responses != 0 {
+    let remaining_expectations = self.mock_responder.mock_execution.remaining_expectations();
+    if !remaining_expectations.is_empty() {
       let message = format!(
         "Expected {} more requests. Remaining expected responses:\n{}\nReceived requests:\n{}",
-        remaining_expected_responses,
-        MockResponder::display_all(&Vec::from_iter(
-          self
-            .mock_responder
-            .mock_execution
-            .operation_responses
-            .lock()
-            .clone(),
-        )),
+        remaining_expectations.len(),
+        MockResponder::display_all(&remaining_expectations),
         MockResponder::display_all(&self.mock_responder.received_messages.deref().lock())
       );
       if std::thread::panicking() {
@@ -143,7 +481,7 @@ impl Drop for TestServer {
           message
         );
       } else {
-        assert_eq!(remaining_expected_responses, 0, "{}", message,);
+        assert_eq!(remaining_expectations.len(), 0, "{}", message,);
       }
     }
   }
@@ -153,14 +491,33 @@ impl Drop for TestServer {
 pub struct ReceivedMessage {
   pub message_type: String,
   pub message: Box<dyn protobuf::Message>,
+  // The request metadata of the call, in the order that it was sent.
+  pub headers: Vec<(String, Vec<u8>)>,
   pub received_at: Instant,
 }
 
+fn request_headers(ctx: &grpcio::RpcContext<'_>) -> Vec<(String, Vec<u8>)> {
+  ctx
+    .request_headers()
+    .iter()
+    .map(|(key, value)| (key.to_owned(), value.to_vec()))
+    .collect()
+}
+
+fn unexpected_request_status() -> grpcio::RpcStatus {
+  grpcio::RpcStatus::new(
+    grpcio::RpcStatusCode::InvalidArgument,
+    Some("Did not expect further requests from client.".to_string()),
+  )
+}
+
 #[derive(Clone, Debug)]
 pub struct MockResponder {
   mock_execution: MockExecution,
   pub received_messages: Arc<Mutex<Vec<ReceivedMessage>>>,
   pub cancelation_requests: Arc<Mutex<Vec<bazel_protos::operations::CancelOperationRequest>>>,
+  // The number of pipelined Execute streams which have been opened.
+  pub pipelined_streams: Arc<Mutex<usize>>,
 }
 
 impl MockResponder {
@@ -169,15 +526,63 @@ impl MockResponder {
       mock_execution: mock_execution,
       received_messages: Arc::new(Mutex::new(vec![])),
       cancelation_requests: Arc::new(Mutex::new(vec![])),
+      pipelined_streams: Arc::new(Mutex::new(0)),
     }
   }
 
-  fn log<T: protobuf::Message + Sized>(&self, message: T) {
-    self.received_messages.lock().push(ReceivedMessage {
+  fn received_message<T: protobuf::Message + Sized>(
+    headers: Vec<(String, Vec<u8>)>,
+    message: T,
+  ) -> ReceivedMessage {
+    ReceivedMessage {
       message_type: message.descriptor().name().to_string(),
       message: Box::new(message),
+      headers,
       received_at: Instant::now(),
-    });
+    }
+  }
+
+  fn log(&self, received: ReceivedMessage) {
+    self.received_messages.lock().push(received);
+  }
+
+  ///
+  /// Logs the request and, if the MockExecution is scripted, consumes the next ExpectedRpc if the
+  /// request matches it, or else explains why not.
+  ///
+  fn receive(
+    &self,
+    kind: RpcKind,
+    received: ReceivedMessage,
+  ) -> Option<Result<MockOperation, grpcio::RpcStatus>> {
+    let response = self
+      .mock_execution
+      .expected_rpcs
+      .as_ref()
+      .map(|expected_rpcs| {
+        let mut expected_rpcs = expected_rpcs.lock();
+        let mismatch = match expected_rpcs.front() {
+          None => Some("Did not expect further requests from client.".to_owned()),
+          Some(expected) if expected.kind != kind => Some(format!(
+            "Expected a {:?} request, but got a {:?} request.",
+            expected.kind, kind
+          )),
+          Some(expected) => expected.matcher.check(&received).err(),
+        };
+        if let Some(mismatch) = mismatch {
+          return Err(grpcio::RpcStatus::new(
+            grpcio::RpcStatusCode::InvalidArgument,
+            Some(format!("{} Got: {:?}", mismatch, received.message)),
+          ));
+        }
+        let expected = expected_rpcs.pop_front().unwrap();
+        Ok(MockOperation {
+          op: expected.response,
+          duration: expected.delay,
+        })
+      });
+    self.log(received);
+    response
   }
 
   fn display_all<D: Debug>(items: &[D]) -> String {
@@ -188,40 +593,47 @@ impl MockResponder {
       .concat()
   }
 
-  fn send_next_operation_unary(
-    &self,
+  fn next_operation_response(&self) -> Result<MockOperation, grpcio::RpcStatus> {
+    self
+      .mock_execution
+      .operation_responses
+      .lock()
+      .pop_front()
+      .ok_or_else(unexpected_request_status)
+  }
+
+  fn respond_unary(
     sink: grpcio::UnarySink<bazel_protos::operations::Operation>,
+    response: Result<MockOperation, grpcio::RpcStatus>,
   ) {
-    if let Some(MockOperation { op, duration }) =
-      self.mock_execution.operation_responses.lock().pop_front()
-    {
-      if let Some(d) = duration {
-        sleep(d);
+    match response {
+      Ok(MockOperation { op, duration }) => {
+        if let Some(d) = duration {
+          sleep(d);
+        }
+        if let Ok(Some(op)) = op {
+          // Complete the channel with the op.
+          sink.success(op.clone());
+        } else if let Err(status) = op {
+          sink.fail(status);
+        } else {
+          // Cancel the request by dropping the sink.
+          drop(sink);
+        }
       }
-      if let Ok(Some(op)) = op {
-        // Complete the channel with the op.
-        sink.success(op.clone());
-      } else if let Err(status) = op {
+      Err(status) => {
         sink.fail(status);
-      } else {
-        // Cancel the request by dropping the sink.
-        drop(sink);
       }
-    } else {
-      sink.fail(grpcio::RpcStatus::new(
-        grpcio::RpcStatusCode::InvalidArgument,
-        Some("Did not expect further requests from client.".to_string()),
-      ));
     }
   }
 
-  fn send_next_operation_stream(
-    &self,
+  fn respond_stream(
     ctx: &grpcio::RpcContext<'_>,
     sink: grpcio::ServerStreamingSink<bazel_protos::operations::Operation>,
+    response: Result<MockOperation, grpcio::RpcStatus>,
   ) {
-    match self.mock_execution.operation_responses.lock().pop_front() {
-      Some(MockOperation { op, duration }) => {
+    match response {
+      Ok(MockOperation { op, duration }) => {
         if let Some(d) = duration {
           sleep(d);
         }
@@ -240,19 +652,116 @@ impl MockResponder {
           drop(sink)
         }
       }
-      None => ctx.spawn(
-        sink
-          .fail(grpcio::RpcStatus::new(
-            grpcio::RpcStatusCode::InvalidArgument,
-            Some("Did not expect further requests from client.".to_string()),
-          ))
-          .map(|_| ())
-          .map_err(|_| ()),
-      ),
+      Err(status) => ctx.spawn(sink.fail(status).map(|_| ()).map_err(|_| ())),
+    }
+  }
+
+  ///
+  /// Like a server which strictly validates requests, describes the fields of the request which are
+  /// not part of the REAPI, listing the type urls of any of them which are Anys.
+  ///
+  fn unknown_fields_status(
+    req: &bazel_protos::remote_execution::ExecuteRequest,
+  ) -> Option<grpcio::RpcStatus> {
+    let unknown_fields = protobuf::Message::get_unknown_fields(req);
+    if unknown_fields.iter().next().is_none() {
+      return None;
+    }
+    let unknown_type_urls: Vec<_> = unknown_fields
+      .iter()
+      .flat_map(|(_, values)| values.length_delimited.iter())
+      .filter_map(|bytes| protobuf::parse_from_bytes::<protobuf::well_known_types::Any>(bytes).ok())
+      .map(|any| any.get_type_url().to_owned())
+      .collect();
+    Some(grpcio::RpcStatus::new(
+      grpcio::RpcStatusCode::InvalidArgument,
+      Some(format!(
+        "ExecuteRequest had unknown fields, with types: {:?}",
+        unknown_type_urls
+      )),
+    ))
+  }
+
+  fn check_execute_request(
+    &self,
+    req: &bazel_protos::remote_execution::ExecuteRequest,
+  ) -> Result<(), grpcio::RpcStatus> {
+    if self.mock_execution.execute_request == *req {
+      Ok(())
+    } else {
+      Err(grpcio::RpcStatus::new(
+        grpcio::RpcStatusCode::InvalidArgument,
+        Some(format!(
+          "Did not expect this request. Expected: {:?}, Got: {:?}",
+          self.mock_execution.execute_request, req
+        )),
+      ))
     }
   }
 }
 
+impl MockResponder {
+  fn execute_pipelined(
+    &self,
+    ctx: grpcio::RpcContext<'_>,
+    requests: grpcio::RequestStream<bazel_protos::remote_execution::ExecuteRequest>,
+    sink: grpcio::DuplexSink<bazel_protos::operations::Operation>,
+  ) {
+    *self.pipelined_streams.lock() += 1;
+    let headers = request_headers(&ctx);
+    let responder = self.clone();
+    let responses = requests.and_then(move |tagged_request| -> Result<_, grpcio::Error> {
+      let req = bazel_protos::pipelining::without_tag(&tagged_request);
+      let response = responder
+        .receive(
+          RpcKind::Execute,
+          Self::received_message(headers.clone(), req.clone()),
+        )
+        .unwrap_or_else(|| {
+          responder
+            .check_execute_request(&req)
+            .and_then(|()| responder.next_operation_response())
+        });
+      let mut op = responder.pipelined_operation(response)?;
+      if let Some(tag) = bazel_protos::pipelining::tag(&tagged_request) {
+        bazel_protos::pipelining::set_tag(&mut op, &tag);
+      }
+      Ok((op, grpcio::WriteFlags::default()))
+    });
+    ctx.spawn(sink.send_all(responses).map(|_| ()).map_err(|_| ()));
+  }
+
+  ///
+  /// Fails with an error (which ends the stream) if the operation response is None.
+  ///
+  fn pipelined_operation(
+    &self,
+    response: Result<MockOperation, grpcio::RpcStatus>,
+  ) -> Result<bazel_protos::operations::Operation, grpcio::Error> {
+    let op = response.and_then(|MockOperation { op, duration }| {
+      if let Some(d) = duration {
+        sleep(d);
+      }
+      op
+    });
+    let op = match op {
+      Ok(Some(op)) => Ok(op),
+      Ok(None) => return Err(grpcio::Error::RemoteStopped),
+      Err(status) => Err(status),
+    };
+    Ok(op.unwrap_or_else(|status| {
+      let mut error = bazel_protos::status::Status::new();
+      error.set_code(status.status as i32);
+      error.set_message(status.details.unwrap_or_default());
+      let mut op = bazel_protos::operations::Operation::new();
+      op.set_name(self.mock_execution.name.clone());
+      op.set_done(true);
+      op.set_error(error);
+      op
+    }))
+  }
+}
+
 impl bazel_protos::remote_execution_grpc::Execution for MockResponder {
   // We currently only support the one-shot "stream and disconnect" client behavior.
   // If we start supporting the "stream updates" variant, we will need to do so here.
@@ -262,46 +771,84 @@ impl bazel_protos::remote_execution_grpc::Execution for MockResponder {
     req: bazel_protos::remote_execution::ExecuteRequest,
     sink: grpcio::ServerStreamingSink<bazel_protos::operations::Operation>,
   ) {
-    self.log(req.clone());
+    let received = Self::received_message(request_headers(&ctx), req.clone());
 
-    if self.mock_execution.execute_request != req {
-      ctx.spawn(
-        sink
-          .fail(grpcio::RpcStatus::new(
-            grpcio::RpcStatusCode::InvalidArgument,
-            Some(format!(
-              "Did not expect this request. Expected: {:?}, Got: {:?}",
-              self.mock_execution.execute_request, req
-            )),
-          ))
-          .map_err(|_| ()),
-      );
-      return;
+    // Such requests do not consume a response.
+    if self.mock_execution.rejects_unknown_fields {
+      if let Some(status) = Self::unknown_fields_status(&req) {
+        self.log(received);
+        Self::respond_stream(&ctx, sink, Err(status));
+        return;
+      }
     }
 
-    self.send_next_operation_stream(&ctx, sink);
+    let response = self.receive(RpcKind::Execute, received).unwrap_or_else(|| {
+      self
+        .check_execute_request(&req)
+        .and_then(|()| self.next_operation_response())
+    });
+    Self::respond_stream(&ctx, sink, response);
   }
 
   fn wait_execution(
     &self,
-    _ctx: grpcio::RpcContext<'_>,
-    _req: bazel_protos::remote_execution::WaitExecutionRequest,
-    _sink: grpcio::ServerStreamingSink<bazel_protos::operations::Operation>,
+    ctx: grpcio::RpcContext<'_>,
+    req: bazel_protos::remote_execution::WaitExecutionRequest,
+    sink: grpcio::ServerStreamingSink<bazel_protos::operations::Operation>,
+  ) {
+    let received = Self::received_message(request_headers(&ctx), req);
+    let response = self
+      .receive(RpcKind::WaitExecution, received)
+      .unwrap_or_else(|| {
+        Err(grpcio::RpcStatus::new(
+          grpcio::RpcStatusCode::Unimplemented,
+          Some("Only a scripted MockExecution supports WaitExecution.".to_owned()),
+        ))
+      });
+    Self::respond_stream(&ctx, sink, response);
+  }
+}
+
+impl bazel_protos::remote_execution_grpc::Capabilities for MockResponder {
+  fn get_capabilities(
+    &self,
+    ctx: grpcio::RpcContext<'_>,
+    req: bazel_protos::remote_execution::GetCapabilitiesRequest,
+    sink: grpcio::UnarySink<bazel_protos::remote_execution::ServerCapabilities>,
   ) {
-    unimplemented!()
+    self.log(Self::received_message(request_headers(&ctx), req));
+    match self.mock_execution.capabilities {
+      Some(Ok(ref capabilities)) => {
+        sink.success(capabilities.clone());
+      }
+      Some(Err(code)) => {
+        sink.fail(grpcio::RpcStatus::new(
+          code,
+          Some("This MockExecution fails to fetch its capabilities.".to_owned()),
+        ));
+      }
+      None => {
+        sink.fail(grpcio::RpcStatus::new(
+          grpcio::RpcStatusCode::Unimplemented,
+          Some("This MockExecution has no capabilities.".to_owned()),
+        ));
+      }
+    }
   }
 }
 
 impl bazel_protos::operations_grpc::Operations for MockResponder {
   fn get_operation(
     &self,
-    _: grpcio::RpcContext<'_>,
+    ctx: grpcio::RpcContext<'_>,
     req: bazel_protos::operations::GetOperationRequest,
     sink: grpcio::UnarySink<bazel_protos::operations::Operation>,
   ) {
-    self.log(req.clone());
-
-    self.send_next_operation_unary(sink)
+    let received = Self::received_message(request_headers(&ctx), req);
+    let response = self
+      .receive(RpcKind::GetOperation, received)
+      .unwrap_or_else(|| self.next_operation_response());
+    Self::respond_unary(sink, response)
   }
 
   fn list_operations(
@@ -330,12 +877,218 @@ impl bazel_protos::operations_grpc::Operations for MockResponder {
 
   fn cancel_operation(
     &self,
-    _: grpcio::RpcContext<'_>,
+    ctx: grpcio::RpcContext<'_>,
     req: bazel_protos::operations::CancelOperationRequest,
     sink: grpcio::UnarySink<bazel_protos::empty::Empty>,
   ) {
-    self.log(req.clone());
-    self.cancelation_requests.lock().push(req);
-    sink.success(bazel_protos::empty::Empty::new());
+    self.cancelation_requests.lock().push(req.clone());
+    let received = Self::received_message(request_headers(&ctx), req);
+    match self.receive(RpcKind::CancelOperation, received) {
+      None => {
+        sink.success(bazel_protos::empty::Empty::new());
+      }
+      Some(Ok(MockOperation { op, duration })) => {
+        if let Some(d) = duration {
+          sleep(d);
+        }
+        match op {
+          Ok(Some(_)) => {
+            sink.success(bazel_protos::empty::Empty::new());
+          }
+          // Cancel the request by dropping the sink.
+          Ok(None) => drop(sink),
+          Err(status) => {
+            sink.fail(status);
+          }
+        }
+      }
+      Some(Err(status)) => {
+        sink.fail(status);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+  use std::time::Duration;
+
+  use bazel_protos::operations::{GetOperationRequest, Operation};
+  use bazel_protos::operations_grpc::OperationsClient;
+  use bazel_protos::remote_execution::{ActionResult, ExecuteRequest, WaitExecutionRequest};
+  use bazel_protos::remote_execution_grpc::ExecutionClient;
+  use futures::{Future, Stream};
+
+  use super::{ExpectedRpc, MockExecution, MockOperation, RpcMatcher, TestServer};
+
+  fn clients(server: &TestServer) -> (ExecutionClient, OperationsClient) {
+    let env = Arc::new(grpcio::Environment::new(1));
+    let channel = grpcio::ChannelBuilder::new(env).connect(&server.address());
+    (
+      ExecutionClient::new(channel.clone()),
+      OperationsClient::new(channel),
+    )
+  }
+
+  fn execute_request(instance_name: &str) -> ExecuteRequest {
+    let mut req = ExecuteRequest::new();
+    req.set_instance_name(instance_name.to_owned());
+    req
+  }
+
+  fn get_operation_request(name: &str) -> GetOperationRequest {
+    let mut req = GetOperationRequest::new();
+    req.set_name(name.to_owned());
+    req
+  }
+
+  fn execute(client: &ExecutionClient, req: &ExecuteRequest) -> Result<Vec<Operation>, String> {
+    client
+      .execute(req)
+      .unwrap()
+      .collect()
+      .wait()
+      .map_err(|e| format!("{:?}", e))
+  }
+
+  fn invalid_argument(result: Result<Operation, grpcio::Error>) -> bool {
+    match result {
+      Err(grpcio::Error::RpcFailure(status)) => {
+        status.status == grpcio::RpcStatusCode::InvalidArgument
+      }
+      _ => false,
+    }
+  }
+
+  #[test]
+  fn scripted_rpcs_are_consumed_in_order() {
+    let server = TestServer::new(
+      MockExecution::scripted(
+        "op".to_owned(),
+        vec![
+          ExpectedRpc::execute(execute_request("main"), MockOperation::incomplete("op")),
+          ExpectedRpc::get_operation("op", MockOperation::incomplete("op"))
+            .with_delay(Duration::from_millis(10)),
+          ExpectedRpc::wait_execution("op", MockOperation::successful("op", ActionResult::new())),
+          ExpectedRpc::cancel_operation("op"),
+        ],
+      ),
+      None,
+    );
+    let (execution_client, operations_client) = clients(&server);
+
+    // Requests which are out of order, or which do not match, do not consume the next RPC.
+    assert!(invalid_argument(
+      operations_client.get_operation(&get_operation_request("op"))
+    ));
+    assert!(execute(&execution_client, &execute_request("other")).is_err());
+
+    let ops = execute(&execution_client, &execute_request("main")).unwrap();
+    assert_eq!(ops.len(), 1);
+    assert!(!ops[0].get_done());
+
+    assert!(invalid_argument(
+      operations_client.get_operation(&get_operation_request("other-op"))
+    ));
+    let op = operations_client
+      .get_operation(&get_operation_request("op"))
+      .unwrap();
+    assert!(!op.get_done());
+
+    let mut wait_request = WaitExecutionRequest::new();
+    wait_request.set_name("op".to_owned());
+    let ops = execution_client
+      .wait_execution(&wait_request)
+      .unwrap()
+      .collect()
+      .wait()
+      .unwrap();
+    assert!(ops[0].get_done());
+
+    let mut cancel_request = bazel_protos::operations::CancelOperationRequest::new();
+    cancel_request.set_name("op".to_owned());
+    operations_client.cancel_operation(&cancel_request).unwrap();
+    assert_eq!(server.mock_responder.cancelation_requests.lock().len(), 1);
+
+    let message_types: Vec<_> = server
+      .mock_responder
+      .received_messages
+      .lock()
+      .iter()
+      .map(|m| m.message_type.clone())
+      .collect();
+    assert_eq!(
+      message_types,
+      vec![
+        "GetOperationRequest",
+        "ExecuteRequest",
+        "ExecuteRequest",
+        "GetOperationRequest",
+        "GetOperationRequest",
+        "WaitExecutionRequest",
+        "CancelOperationRequest",
+      ]
+    );
+  }
+
+  #[test]
+  fn request_headers_are_captured_and_matched() {
+    let server = TestServer::new(
+      MockExecution::scripted(
+        "op".to_owned(),
+        vec![
+          ExpectedRpc::get_operation("op", MockOperation::incomplete("op"))
+            .matching(RpcMatcher::Header("x-test".to_owned(), "yes".to_owned())),
+        ],
+      ),
+      None,
+    );
+    let (_, operations_client) = clients(&server);
+
+    assert!(invalid_argument(
+      operations_client.get_operation(&get_operation_request("op"))
+    ));
+
+    let mut headers = grpcio::MetadataBuilder::with_capacity(1);
+    headers.add_str("x-test", "yes").unwrap();
+    operations_client
+      .get_operation_opt(
+        &get_operation_request("op"),
+        grpcio::CallOption::default().headers(headers.build()),
+      )
+      .unwrap();
+
+    let received_messages = server.mock_responder.received_messages.lock();
+    assert_eq!(received_messages.len(), 2);
+    let has_header = |i: usize| {
+      received_messages[i]
+        .headers
+        .iter()
+        .any(|(key, value)| key == "x-test" && value == b"yes")
+    };
+    assert!(!has_header(0));
+    assert!(has_header(1));
+  }
+
+  #[test]
+  fn legacy_responses_answer_any_rpc() {
+    let server = TestServer::new(
+      MockExecution::new(
+        "op".to_owned(),
+        execute_request("main"),
+        vec![
+          MockOperation::incomplete("op"),
+          MockOperation::incomplete("op"),
+        ],
+      ),
+      None,
+    );
+    let (execution_client, operations_client) = clients(&server);
+
+    operations_client
+      .get_operation(&get_operation_request("op"))
+      .unwrap();
+    execute(&execution_client, &execute_request("main")).unwrap();
   }
 }
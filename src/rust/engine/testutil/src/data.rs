@@ -270,6 +270,28 @@ impl TestDirectory {
     TestDirectory { directory }
   }
 
+  // Directory structure:
+  //
+  // /cats/robin
+  // /treats
+  pub fn recursive_with_robin() -> TestDirectory {
+    let mut directory = bazel_protos::remote_execution::Directory::new();
+    directory.mut_directories().push({
+      let mut subdir = bazel_protos::remote_execution::DirectoryNode::new();
+      subdir.set_name("cats".to_string());
+      subdir.set_digest((&TestDirectory::containing_robin().digest()).into());
+      subdir
+    });
+    directory.mut_files().push({
+      let mut file = bazel_protos::remote_execution::FileNode::new();
+      file.set_name("treats".to_string());
+      file.set_digest((&TestData::catnip().digest()).into());
+      file.set_is_executable(false);
+      file
+    });
+    TestDirectory { directory }
+  }
+
   // Directory structure:
   //
   // /feed (executable)
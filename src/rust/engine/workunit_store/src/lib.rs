@@ -28,10 +28,11 @@
 
 use concrete_time::TimeSpan;
 use futures::task_local;
+use log::Level;
 use parking_lot::Mutex;
 use rand::thread_rng;
 use rand::Rng;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
@@ -45,12 +46,16 @@ pub struct WorkUnit {
 #[derive(Clone)]
 pub struct WorkUnitStore {
   workunits: Arc<Mutex<HashSet<WorkUnit>>>,
+  counters: Arc<Mutex<HashMap<&'static str, i64>>>,
+  logged_messages: Arc<Mutex<HashSet<(Level, String)>>>,
 }
 
 impl WorkUnitStore {
   pub fn new() -> WorkUnitStore {
     WorkUnitStore {
       workunits: Arc::new(Mutex::new(HashSet::new())),
+      counters: Arc::new(Mutex::new(HashMap::new())),
+      logged_messages: Arc::new(Mutex::new(HashSet::new())),
     }
   }
 
@@ -61,6 +66,33 @@ impl WorkUnitStore {
   pub fn add_workunit(&self, workunit: WorkUnit) {
     self.workunits.lock().insert(workunit);
   }
+
+  ///
+  /// Adds delta to the named counter, which starts at zero.
+  ///
+  pub fn increment_counter(&self, name: &'static str, delta: i64) {
+    *self.counters.lock().entry(name).or_insert(0) += delta;
+  }
+
+  ///
+  /// A snapshot of the counters which have been incremented.
+  ///
+  pub fn get_counters(&self) -> HashMap<&'static str, i64> {
+    self.counters.lock().clone()
+  }
+
+  ///
+  /// Records that the given message is about to be logged at the given level, returning false if
+  /// it already was at that level for this WorkUnitStore (and so in this session): messages which
+  /// many processes report can then be logged once. A message which was only logged at a level
+  /// which is not shown is still logged at a more severe one.
+  ///
+  pub fn record_logged_message(&self, level: Level, message: &str) -> bool {
+    self
+      .logged_messages
+      .lock()
+      .insert((level, message.to_owned()))
+  }
 }
 
 pub fn generate_random_64bit_string() -> String {
@@ -106,7 +138,9 @@ pub fn get_parent_id() -> Option<String> {
 
 #[cfg(test)]
 mod tests {
-  use crate::hex_16_digit_string;
+  use crate::{hex_16_digit_string, WorkUnitStore};
+  use log::Level;
+  use std::collections::HashMap;
 
   #[test]
   fn workunit_span_id_has_16_digits_len_hex_format() {
@@ -130,4 +164,29 @@ mod tests {
       "0123456789abcdef"
     );
   }
+
+  #[test]
+  fn counters_are_shared_between_clones() {
+    let workunit_store = WorkUnitStore::new();
+    workunit_store.increment_counter("requests", 1);
+    workunit_store.clone().increment_counter("requests", 2);
+    workunit_store.clone().increment_counter("bytes", 0);
+
+    let mut expected = HashMap::new();
+    expected.insert("requests", 3);
+    expected.insert("bytes", 0);
+    assert_eq!(workunit_store.get_counters(), expected);
+  }
+
+  #[test]
+  fn messages_are_logged_once_per_store() {
+    let workunit_store = WorkUnitStore::new();
+    assert!(workunit_store.record_logged_message(Level::Info, "deprecated"));
+    assert!(!workunit_store
+      .clone()
+      .record_logged_message(Level::Info, "deprecated"));
+    assert!(workunit_store.record_logged_message(Level::Info, "out of memory"));
+    assert!(workunit_store.record_logged_message(Level::Debug, "deprecated"));
+    assert!(WorkUnitStore::new().record_logged_message(Level::Info, "deprecated"));
+  }
 }
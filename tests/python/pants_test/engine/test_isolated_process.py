@@ -252,6 +252,23 @@ class ExecuteProcessRequestTest(unittest.TestCase):
     )
     self.assertEqual(req.env, ('VAR', 'VAL'))
 
+  def test_execution_locality(self):
+    self.assertEqual(self._default_args_execute_process_request().execution_locality, 'any')
+    req = ExecuteProcessRequest(
+      argv=('foo',),
+      description="Some process",
+      input_files=EMPTY_DIRECTORY_DIGEST,
+      execution_locality='local_only',
+    )
+    self.assertEqual(req.execution_locality, 'local_only')
+    with self.assertRaisesRegexp(TypeCheckError, "nowhere"):
+      ExecuteProcessRequest(
+        argv=('foo',),
+        description="Some process",
+        input_files=EMPTY_DIRECTORY_DIGEST,
+        execution_locality='nowhere',
+      )
+
 
 class TestInputFileCreation(TestBase):
   def test_input_file_creation(self):